@@ -0,0 +1,151 @@
+//! Measures how long building a tunnel with 3 and with 5 hops takes, with a peer provider which
+//! answers at once and with one which takes a while for every peer. The next random peer is drawn
+//! while the previous hop is being added, so the delay of the provider should be paid about once
+//! per build instead of once per hop.
+//!
+//! A build ends when the destination has been added, which is reported to a [`StateObserver`].
+//! The tunnel only becomes ready with the following round, which is not measured.
+//!
+//! Run with `cargo bench --bench build_latency`.
+use allium::stats::{BuildAttempt, BuildOutcome};
+use allium::{
+    OnionBuilder, OnionContext, OnionIncoming, Peer, PeerProvider, RsaPrivateKey, StateObserver,
+    TunnelId,
+};
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant, Sleep};
+use tokio_stream::{self as stream, Stream};
+
+const N_BUILDS: usize = 20;
+/// time the slow provider takes for every peer
+const PROVIDER_DELAY: Duration = Duration::from_millis(10);
+
+static PORT_COUNTER: AtomicU16 = AtomicU16::new(43700);
+
+/// Cycles through the given peers, waiting for `delay` before returning each of them.
+struct SlowPeers {
+    peers: Vec<Peer>,
+    next: usize,
+    delay: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Stream for SlowPeers {
+    type Item = Peer;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Peer>> {
+        let delay = self.delay;
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Box::pin(time::sleep(delay)));
+        if sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        self.sleep = None;
+        let peer = self.peers[self.next % self.peers.len()].clone();
+        self.next += 1;
+        Poll::Ready(Some(peer))
+    }
+}
+
+/// Reports when the destination, the hop after `n_hops` relays, was added to a tunnel.
+struct DestinationAdded {
+    n_hops: usize,
+    added_tx: mpsc::UnboundedSender<Instant>,
+}
+
+impl StateObserver for DestinationAdded {
+    fn on_build_attempt(&self, _tunnel_id: TunnelId, attempt: &BuildAttempt) {
+        if attempt.hop == self.n_hops && attempt.outcome == BuildOutcome::Ok {
+            let _ = self.added_tx.send(Instant::now());
+        }
+    }
+}
+
+fn start(
+    peer_provider: PeerProvider,
+    n_hops: usize,
+    added_tx: mpsc::UnboundedSender<Instant>,
+) -> (Peer, OnionContext, OnionIncoming) {
+    let port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    let hostkey = RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+    let peer = Peer::new(addr, hostkey.public_key());
+    let (ctx, incoming) = OnionBuilder::new(addr, hostkey, peer_provider)
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(n_hops)
+        .set_state_observer(Arc::new(DestinationAdded { n_hops, added_tx }))
+        .set_round_duration(Duration::from_secs(1))
+        .set_min_tunnel_lifetime(Duration::from_secs(3600))
+        .start()
+        .unwrap();
+    (peer, ctx, incoming)
+}
+
+/// Returns the median time taken to build a tunnel through `relays` to `dest`.
+async fn measure(relays: &[Peer], dest: &Peer, delay: Duration) -> Duration {
+    let peers = SlowPeers {
+        peers: relays.to_vec(),
+        next: 0,
+        delay,
+        sleep: None,
+    };
+    let (added_tx, mut added_rx) = mpsc::unbounded_channel();
+    let (_, ctx, _incoming) = start(PeerProvider::from_stream(peers), relays.len(), added_tx);
+    let mut durations = Vec::with_capacity(N_BUILDS);
+    for _ in 0..N_BUILDS {
+        let start = Instant::now();
+        // the next build starts while this tunnel waits for the next round to become ready.
+        // Tunnels still waiting when the onion router is shut down fail, which is ignored
+        let ctx = ctx.clone();
+        let dest = dest.clone();
+        tokio::spawn(async move { ctx.build_tunnel(dest).await });
+        let added = added_rx.recv().await.unwrap();
+        durations.push(added - start);
+    }
+    ctx.shutdown().await;
+    durations.sort();
+    durations[N_BUILDS / 2]
+}
+
+#[tokio::main]
+async fn main() {
+    let (unobserved, _) = mpsc::unbounded_channel();
+    let relays: Vec<_> = (0..5)
+        .map(|_| {
+            let provider = PeerProvider::from_stream(stream::empty());
+            start(provider, 0, unobserved.clone())
+        })
+        .collect();
+    let (dest, dest_ctx, _dest_incoming) =
+        start(PeerProvider::from_stream(stream::empty()), 0, unobserved);
+    let peers: Vec<_> = relays.iter().map(|(peer, _, _)| peer.clone()).collect();
+
+    // the first measurement pays for warming up the caches and the allocator
+    measure(&peers[..3], &dest, Duration::ZERO).await;
+    for &n_hops in &[3, 5] {
+        let instant = measure(&peers[..n_hops], &dest, Duration::ZERO).await;
+        let slow = measure(&peers[..n_hops], &dest, PROVIDER_DELAY).await;
+        println!(
+            "{} hops: {:?} per build, {:?} with a provider taking {:?} per peer \
+             (+{:?}, +{:?} if the peers were drawn one after another)",
+            n_hops,
+            instant,
+            slow,
+            PROVIDER_DELAY,
+            slow.saturating_sub(instant),
+            PROVIDER_DELAY * n_hops as u32
+        );
+    }
+
+    for (_, ctx, _) in &relays {
+        ctx.shutdown().await;
+    }
+    dest_ctx.shutdown().await;
+}
@@ -0,0 +1,128 @@
+//! Measures how much building many tunnels at once stalls the other tasks of the runtime, e.g.
+//! the tasks relaying data.
+//!
+//! The signatures of the circuit handshakes are signed and verified on the blocking thread pool,
+//! so a task ticking every millisecond on the same two worker threads should only be delayed by
+//! the scheduling of the handshakes, not by a RSA operation per handshake. This needs more cores
+//! than worker threads, otherwise the blocking threads compete with the workers for the CPU.
+//!
+//! A build ends when the destination has been added, which is reported to a [`StateObserver`].
+//!
+//! Run with `cargo bench --bench concurrent_builds`.
+use allium::stats::{BuildAttempt, BuildOutcome};
+use allium::{
+    OnionBuilder, OnionContext, OnionIncoming, Peer, PeerProvider, RsaPrivateKey, StateObserver,
+    TunnelId,
+};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+use tokio_stream as stream;
+
+const N_BUILDS: usize = 50;
+const N_HOPS: usize = 2;
+const TICK: Duration = Duration::from_millis(1);
+
+static PORT_COUNTER: AtomicU16 = AtomicU16::new(43900);
+
+/// Reports when the destination, the hop after the relays, was added to a tunnel.
+struct DestinationAdded {
+    added_tx: mpsc::UnboundedSender<()>,
+}
+
+impl StateObserver for DestinationAdded {
+    fn on_build_attempt(&self, _tunnel_id: TunnelId, attempt: &BuildAttempt) {
+        if attempt.hop == N_HOPS && attempt.outcome == BuildOutcome::Ok {
+            let _ = self.added_tx.send(());
+        }
+    }
+}
+
+fn start(
+    peer_provider: PeerProvider,
+    configure: impl FnOnce(OnionBuilder) -> OnionBuilder,
+) -> (Peer, OnionContext, OnionIncoming) {
+    let port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    let hostkey = RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+    let peer = Peer::new(addr, hostkey.public_key());
+    let builder = OnionBuilder::new(addr, hostkey, peer_provider)
+        .enable_cover_traffic(false)
+        // longer than all builds take, so no tunnel misses a round while it is being built
+        .set_round_duration(Duration::from_secs(5))
+        .set_min_tunnel_lifetime(Duration::from_secs(3600));
+    let (ctx, incoming) = configure(builder).start().unwrap();
+    (peer, ctx, incoming)
+}
+
+/// Returns the largest and the median delay of a task ticking every millisecond until `stop` is
+/// set.
+async fn tick(stop: Arc<AtomicBool>) -> (Duration, Duration) {
+    let mut delays = Vec::new();
+    while !stop.load(Ordering::Relaxed) {
+        let start = Instant::now();
+        time::sleep(TICK).await;
+        delays.push(start.elapsed().saturating_sub(TICK));
+    }
+    delays.sort();
+    (delays[delays.len() - 1], delays[delays.len() / 2])
+}
+
+async fn run() {
+    let relays: Vec<_> = (0..N_HOPS)
+        .map(|_| start(PeerProvider::from_stream(stream::empty()), |b| b))
+        .collect();
+    let (dest, dest_ctx, _dest_incoming) = start(PeerProvider::from_stream(stream::empty()), |b| b);
+    let peers: Vec<_> = relays.iter().map(|(peer, _, _)| peer.clone()).collect();
+    let (added_tx, mut added_rx) = mpsc::unbounded_channel();
+    let (_, ctx, _incoming) = start(
+        PeerProvider::from_stream(stream::iter(peers.into_iter().cycle())),
+        |b| {
+            b.set_hops_per_tunnel(N_HOPS)
+                .set_max_handshakes_per_peer(N_BUILDS)
+                .set_state_observer(Arc::new(DestinationAdded { added_tx }))
+        },
+    );
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let ticker = tokio::spawn(tick(stop.clone()));
+    let start = Instant::now();
+    let builds: Vec<_> = (0..N_BUILDS)
+        .map(|_| {
+            let ctx = ctx.clone();
+            let dest = dest.clone();
+            tokio::spawn(async move { ctx.build_tunnel(dest).await })
+        })
+        .collect();
+    for _ in 0..N_BUILDS {
+        added_rx.recv().await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    stop.store(true, Ordering::Relaxed);
+    let (max_delay, median_delay) = ticker.await.unwrap();
+    // the tunnels become ready with the next round
+    for build in builds {
+        build.await.unwrap().unwrap();
+    }
+    println!(
+        "{} concurrent builds of {} hops: {:?} in total, ticks delayed by up to {:?} (median {:?})",
+        N_BUILDS, N_HOPS, elapsed, max_delay, median_delay
+    );
+
+    ctx.shutdown().await;
+    for (_, ctx, _) in &relays {
+        ctx.shutdown().await;
+    }
+    dest_ctx.shutdown().await;
+}
+
+fn main() {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run());
+}
@@ -0,0 +1,141 @@
+//! Measures the latency of a tunnel built by an onion router which relays heavy traffic of other
+//! peers at the same time, with the relay traffic handled on the same or on a dedicated runtime.
+//!
+//! Run with `cargo bench --bench relay_isolation`.
+use allium::{OnionBuilder, OnionContext, OnionIncoming, Peer, PeerProvider, RsaPrivateKey};
+use bytes::Bytes;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU16, Ordering};
+use tokio::runtime::{self, Handle, Runtime};
+use tokio::time::{self, Duration, Instant};
+use tokio_stream as stream;
+
+const LOAD_TUNNELS: usize = 8;
+const LOAD_PAYLOAD: Bytes = Bytes::from_static(&[42; 1024]);
+/// number of queued messages up to which a load tunnel is refilled
+const LOAD_QUEUE: usize = 64;
+const PROBE_PAYLOAD: Bytes = Bytes::from_static(&[7; 64]);
+const SAMPLES: usize = 1000;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(2);
+const ROUND_DURATION: Duration = Duration::from_secs(1);
+
+static PORT_COUNTER: AtomicU16 = AtomicU16::new(43300);
+
+fn new_runtime() -> Runtime {
+    runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn new_peer() -> (Peer, RsaPrivateKey) {
+    let port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    let hostkey = RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+    (Peer::new(addr, hostkey.public_key()), hostkey)
+}
+
+/// Starts an onion router on the current runtime, which builds tunnels via `hops`.
+fn start(hops: Vec<Peer>, relay_runtime: Option<Handle>) -> (Peer, OnionContext, OnionIncoming) {
+    let (peer, hostkey) = new_peer();
+    let n_hops = hops.len();
+    let peer_provider = PeerProvider::from_stream(stream::iter(hops.into_iter().cycle()));
+    let mut builder = OnionBuilder::new(peer.address(), hostkey, peer_provider)
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(n_hops)
+        .set_round_duration(ROUND_DURATION)
+        // the measurement must not be disturbed by rotations
+        .set_min_tunnel_lifetime(Duration::from_secs(3600));
+    if let Some(handle) = relay_runtime {
+        builder = builder.set_relay_runtime(handle);
+    }
+    let (ctx, incoming) = builder.start().unwrap();
+    (peer, ctx, incoming)
+}
+
+/// Sends data from `LOAD_TUNNELS` tunnels through `relay` as fast as it is forwarded.
+async fn generate_load(relay: Peer) {
+    let (dest, _, mut dest_incoming) = start(vec![], None);
+    tokio::spawn(async move {
+        while let Some(mut tunnel) = dest_incoming.next().await {
+            tokio::spawn(async move { while tunnel.read().await.is_ok() {} });
+        }
+    });
+
+    for _ in 0..LOAD_TUNNELS {
+        let (_, ctx, _) = start(vec![relay.clone()], None);
+        let dest = dest.clone();
+        tokio::spawn(async move {
+            let tunnel = ctx.build_tunnel(dest).await.unwrap();
+            loop {
+                while tunnel.stats().queued_cells < LOAD_QUEUE {
+                    tunnel.write(LOAD_PAYLOAD).unwrap();
+                }
+                let _ = tokio::task::yield_now().await;
+            }
+        });
+    }
+}
+
+/// Returns the sorted latencies of messages sent through a direct tunnel of an onion router under
+/// relay load.
+fn measure(isolate: bool) -> Vec<Duration> {
+    let main_runtime = new_runtime();
+    let load_runtime = new_runtime();
+    let relay_runtime = new_runtime();
+
+    let (router, ctx, _incoming) = {
+        let _guard = main_runtime.enter();
+        let relay_handle = if isolate {
+            Some(relay_runtime.handle().clone())
+        } else {
+            None
+        };
+        start(vec![], relay_handle)
+    };
+    load_runtime.spawn({
+        let _guard = load_runtime.enter();
+        generate_load(router)
+    });
+
+    let mut latencies = main_runtime.block_on(async move {
+        let (dest, _, mut dest_incoming) = start(vec![], None);
+        // wait for the load tunnels to be built
+        time::sleep(3 * ROUND_DURATION).await;
+        let tunnel = ctx.build_tunnel(dest).await.unwrap();
+        let mut remote = dest_incoming.next().await.unwrap();
+
+        let mut latencies = Vec::with_capacity(SAMPLES);
+        for _ in 0..SAMPLES {
+            let start = Instant::now();
+            tunnel.write(PROBE_PAYLOAD).unwrap();
+            remote.read().await.unwrap();
+            latencies.push(start.elapsed());
+            time::sleep(SAMPLE_INTERVAL).await;
+        }
+        latencies
+    });
+
+    load_runtime.shutdown_background();
+    relay_runtime.shutdown_background();
+    main_runtime.shutdown_background();
+    latencies.sort();
+    latencies
+}
+
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    sorted[(sorted.len() - 1) * p / 100]
+}
+
+fn main() {
+    for &(isolate, label) in &[(false, "shared runtime"), (true, "relay runtime")] {
+        let latencies = measure(isolate);
+        println!(
+            "{}: p50 {:?}, p99 {:?}",
+            label,
+            percentile(&latencies, 50),
+            percentile(&latencies, 99)
+        );
+    }
+}
@@ -0,0 +1,100 @@
+//! Measures the cost of recording the forwarding latency of a relay, by comparing how many cells
+//! per second a relay forwards with and without the latency histogram. The difference should stay
+//! below 2 %.
+//!
+//! Run with `cargo bench --bench relay_latency`.
+use allium::{OnionBuilder, OnionContext, OnionIncoming, Peer, PeerProvider, RsaPrivateKey};
+use bytes::Bytes;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU16, Ordering};
+use tokio::time::{Duration, Instant};
+use tokio_stream as stream;
+
+const PAYLOAD: Bytes = Bytes::from_static(&[42; 64]);
+const N_MESSAGES: usize = 20_000;
+/// cells in flight at once, well below the backlog after which a relay sheds a circuit
+const WINDOW: usize = 64;
+/// measurements per setting, alternating between the settings to even out drift of the machine
+const ROUNDS: usize = 5;
+
+static PORT_COUNTER: AtomicU16 = AtomicU16::new(43500);
+
+fn start(hops: Vec<Peer>, latency_histogram: bool) -> (Peer, OnionContext, OnionIncoming) {
+    let port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    let hostkey = RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+    let peer = Peer::new(addr, hostkey.public_key());
+    let n_hops = hops.len();
+    let (ctx, incoming) = OnionBuilder::new(
+        addr,
+        hostkey,
+        PeerProvider::from_stream(stream::iter(hops.into_iter().cycle())),
+    )
+    .enable_cover_traffic(false)
+    .enable_latency_histogram(latency_histogram)
+    .set_hops_per_tunnel(n_hops)
+    // tunnels become ready with the next round
+    .set_round_duration(Duration::from_secs(1))
+    .set_min_tunnel_lifetime(Duration::from_secs(3600))
+    .start()
+    .unwrap();
+    (peer, ctx, incoming)
+}
+
+/// Returns the number of cells per second forwarded by a relay, and the onion routers taking part.
+async fn measure(latency_histogram: bool) -> (f64, [OnionContext; 3]) {
+    let (relay, relay_ctx, _) = start(vec![], latency_histogram);
+    let (_, ctx, _) = start(vec![relay], true);
+    let (dest, dest_ctx, mut incoming) = start(vec![], true);
+
+    let tunnel = ctx.build_tunnel(dest).await.unwrap();
+    let mut remote = incoming.next().await.unwrap();
+
+    let start = Instant::now();
+    for _ in 0..N_MESSAGES / WINDOW {
+        for _ in 0..WINDOW {
+            tunnel.write(PAYLOAD).unwrap();
+        }
+        for _ in 0..WINDOW {
+            remote.read().await.unwrap();
+        }
+    }
+    let rate = (N_MESSAGES / WINDOW * WINDOW) as f64 / start.elapsed().as_secs_f64();
+    (rate, [relay_ctx, ctx, dest_ctx])
+}
+
+#[tokio::main]
+async fn main() {
+    // the first measurement pays for warming up the caches and the allocator
+    let (_, contexts) = measure(true).await;
+    for ctx in &contexts {
+        ctx.shutdown().await;
+    }
+    let mut rates = [0.0, 0.0];
+    for _ in 0..ROUNDS {
+        for (i, &enabled) in [false, true].iter().enumerate() {
+            let (rate, [relay, ctx, dest]) = measure(enabled).await;
+            rates[i] += rate / ROUNDS as f64;
+            if enabled {
+                let latency = relay.relay_stats().unwrap().forwarding_latency;
+                println!(
+                    "relay latency of {} cells: p50 {:?}, p95 {:?}, p99 {:?}",
+                    latency.count(),
+                    latency.p50().unwrap(),
+                    latency.p95().unwrap(),
+                    latency.p99().unwrap()
+                );
+            }
+            for ctx in &[relay, ctx, dest] {
+                ctx.shutdown().await;
+            }
+        }
+    }
+    let [disabled, enabled] = rates;
+    println!(
+        "without histogram: {:.0} cells/s, with histogram: {:.0} cells/s, overhead {:.2} %",
+        disabled,
+        enabled,
+        (1.0 - enabled / disabled) * 100.0
+    );
+}
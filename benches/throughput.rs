@@ -0,0 +1,78 @@
+//! Measures how many small messages per second can be sent through a direct tunnel over loopback,
+//! once with every message written on its own and once with the default batching of queued
+//! messages.
+//!
+//! Run with `cargo bench --bench throughput`.
+use allium::{OnionBuilder, Peer, PeerProvider, RsaPrivateKey};
+use bytes::Bytes;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::time::{Duration, Instant};
+use tokio_stream as stream;
+
+const PAYLOAD: Bytes = Bytes::from_static(&[42; 64]);
+const N_MESSAGES: usize = 100_000;
+/// number of messages written before waiting for their arrival, tunnels have no flow control and
+/// a destination falling behind by more than the backlog of its connection tears the path down
+const WINDOW: usize = 500;
+
+/// Spawns a peer which writes at most `batch_size` messages at once, or the default number if
+/// `None`.
+fn spawn_peer(
+    port: u16,
+    batch_size: Option<usize>,
+) -> (Peer, allium::OnionContext, allium::OnionIncoming) {
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    let hostkey = RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+    let peer = Peer::new(addr, hostkey.public_key());
+    let mut builder = OnionBuilder::new(addr, hostkey, PeerProvider::from_stream(stream::empty()))
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(0)
+        // tunnels become ready with the next round
+        .set_round_duration(Duration::from_secs(1))
+        // the measurement stays on the first path, switchovers are not part of it
+        .set_min_tunnel_lifetime(Duration::from_secs(3600));
+    if let Some(n) = batch_size {
+        builder = builder.set_max_batch_size(n);
+    }
+    let (ctx, incoming) = builder.start().unwrap();
+    (peer, ctx, incoming)
+}
+
+/// Returns the number of cells per second sent between two peers listening on `port` and
+/// `port + 1`.
+async fn measure(port: u16, batch_size: Option<usize>) -> f64 {
+    let (_, ctx, _) = spawn_peer(port, batch_size);
+    let (dest, _, mut incoming) = spawn_peer(port + 1, batch_size);
+
+    let tunnel = ctx.build_tunnel(dest).await.unwrap();
+    let mut remote = incoming.next().await.unwrap();
+
+    let start = Instant::now();
+    for _ in 0..N_MESSAGES / WINDOW {
+        for _ in 0..WINDOW {
+            tunnel.write(PAYLOAD).unwrap();
+        }
+        for _ in 0..WINDOW {
+            remote.read().await.unwrap();
+        }
+    }
+    let elapsed = start.elapsed();
+    let rate = N_MESSAGES as f64 / elapsed.as_secs_f64();
+
+    println!(
+        "batch size {}: {} messages of {} bytes in {:?}: {:.0} cells/s",
+        batch_size.map_or("default".to_string(), |n| n.to_string()),
+        N_MESSAGES,
+        PAYLOAD.len(),
+        elapsed,
+        rate
+    );
+    rate
+}
+
+#[tokio::main]
+async fn main() {
+    let unbatched = measure(43100, Some(1)).await;
+    let batched = measure(43102, None).await;
+    println!("speedup of batching: {:.2}x", batched / unbatched);
+}
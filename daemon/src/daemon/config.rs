@@ -35,6 +35,8 @@ pub struct OnionConfig {
     pub hops: usize,
     /// Enable cover traffic
     pub cover_traffic: Option<bool>,
+    /// Allow other peers to terminate their tunnels at this peer.
+    pub relay_termination: Option<bool>,
     /// Duration of each round in seconds.
     /// After each round connections will seamlessly switchover to a new tunnel.
     pub round_duration: Option<u64>,
@@ -91,6 +93,7 @@ impl Config {
                     hostkey: required(sec, "hostkey")?,
                     hops: required(sec, "hops")?,
                     cover_traffic: optional(sec, "cover_traffic")?,
+                    relay_termination: optional(sec, "relay_termination")?,
                     round_duration: optional(sec, "round_duration")?,
                 })
             })?;
@@ -126,8 +129,9 @@ mod tests {
     use super::Config;
 
     #[test]
+    #[cfg_attr(not(feature = "rust-ini"), ignore = "requires the rust-ini feature")]
     fn test_read_config() {
-        let _ = Config::from_file("config.ini").unwrap();
+        let _ = Config::from_file("../config.ini").unwrap();
         // let _ = Config::from_file("config.toml").unwrap();
     }
 }
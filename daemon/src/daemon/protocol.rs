@@ -98,6 +98,7 @@ impl FromBytes for Result<OnionRequest> {
 }
 
 impl OnionRequest {
+    #[allow(dead_code)]
     pub fn id(&self) -> u16 {
         match self {
             OnionRequest::Build(_, _) => ONION_TUNNEL_BUILD,
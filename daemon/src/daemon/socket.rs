@@ -27,7 +27,7 @@ impl<S: AsyncRead + Unpin> DaemonSocket<S> {
         self.buf[0] = size_buf[0];
         self.buf[1] = size_buf[1];
         self.stream.read_exact(&mut self.buf[2..]).await?;
-        Ok(M::try_read_from(&mut self.buf)?)
+        M::try_read_from(&mut self.buf)
     }
 }
 
@@ -9,8 +9,10 @@ use log::{info, trace, warn};
 use std::collections::HashMap;
 use std::env;
 use std::net::SocketAddr;
+use std::process;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{broadcast, mpsc};
+use tokio::time::Duration;
 
 mod daemon;
 mod utils;
@@ -292,7 +294,9 @@ impl OnionModule {
                     let (client, _) = client?;
                     let mut api_handler = DaemonHandler::new(client, self.ctx.clone(), incoming.subscribe());
                     tokio::spawn(async move {
-                        api_handler.handle().await;
+                        if let Err(e) = api_handler.handle().await {
+                            warn!("{}", e);
+                        }
                     });
                 }
                 Some(tunnel) = self.onion_incoming.next() => {
@@ -319,9 +323,10 @@ async fn main() -> Result<()> {
     // setup logging
     pretty_env_logger::init();
     info!(
-        "{} version {}",
+        "{} version {}, built with {}",
         env!("CARGO_PKG_NAME"),
-        env!("CARGO_PKG_VERSION")
+        env!("CARGO_PKG_VERSION"),
+        allium::build_info()
     );
 
     // read config file
@@ -352,10 +357,22 @@ async fn main() -> Result<()> {
 
     // initialize onion, start listening on p2p port
     // events is a stream of events from the p2p protocol which should notify API clients
-    let (ctx, onion_incoming) = OnionBuilder::new(onion_addr, hostkey, peer_provider)
+    let mut builder = OnionBuilder::new(onion_addr, hostkey, peer_provider)
         .enable_cover_traffic(config.onion.cover_traffic.unwrap_or(true))
-        .set_hops_per_tunnel(config.onion.hops)
-        .start();
+        .enable_relay_termination(config.onion.relay_termination.unwrap_or(true))
+        .set_hops_per_tunnel(config.onion.hops);
+    if let Some(round_duration) = config.onion.round_duration {
+        builder = builder.set_round_duration(Duration::from_secs(round_duration));
+    }
+    let (ctx, onion_incoming) = match builder.start() {
+        Ok(started) => started,
+        Err(e) => {
+            for problem in e.problems() {
+                eprintln!("error: {}", problem);
+            }
+            process::exit(1);
+        }
+    };
 
     let mut onion = OnionModule::new(ctx, onion_incoming);
     onion.listen_api(config.onion.api_address).await?;
@@ -1,33 +1,65 @@
 use allium::{
-    OnionBuilder, OnionContext, Peer, PeerProvider, RsaPrivateKey, RsaPublicKey, Tunnel, TunnelId,
-    TunnelWriter,
+    BuildTimeouts, HostKey, HostPublicKey, OnionBuilder, OnionContext, Peer, PeerProvider, Tunnel,
+    TunnelId, TunnelWriter,
 };
 use std::collections::HashMap;
 use std::env;
+use std::net::SocketAddr;
+use std::process;
+use std::time::Duration;
 use tokio::io::{self, AsyncBufReadExt, BufReader};
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
 const DEFAULT_ADDR: &str = "127.0.0.1:4200";
+const DEFAULT_KEY: &str = "testkey.pem";
 
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
-    let onion_addr = env::args()
-        .nth(1)
-        .unwrap_or(DEFAULT_ADDR.to_string())
-        .parse()
-        .unwrap();
+    let onion_addr = match parse_addr(&env::args().nth(1).unwrap_or(DEFAULT_ADDR.to_string())) {
+        Some(addr) => addr,
+        None => process::exit(1),
+    };
     let cover_enabled = env::args().any(|arg| arg == "--cover");
-    let hostkey = RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+    let mut timeouts = BuildTimeouts::default();
+    if let Some(secs) = flag_value("--hop-timeout") {
+        timeouts.hop = Duration::from_secs(secs);
+    }
+    if let Some(secs) = flag_value("--build-timeout") {
+        timeouts.deadline = Duration::from_secs(secs);
+    }
+    // a RSA or Ed25519 key, the peers are expected to use the same one
+    let key_path = flag_arg("--key").unwrap_or_else(|| DEFAULT_KEY.to_string());
+    let hostkey = match HostKey::from_pem_file(&key_path) {
+        Ok(hostkey) => hostkey,
+        Err(e) => {
+            eprintln!("error: could not read the host key {}: {}", key_path, e);
+            process::exit(1);
+        }
+    };
     let public_key = hostkey.public_key();
     let (mut peer_tx, peer_rx) = mpsc::unbounded_channel();
     let peer_rx = UnboundedReceiverStream::new(peer_rx);
-    let (onion, mut incoming) =
-        OnionBuilder::new(onion_addr, hostkey, PeerProvider::from_stream(peer_rx))
-            .enable_cover_traffic(cover_enabled)
-            .set_hops_per_tunnel(0)
-            .start();
+    let started = OnionBuilder::new(onion_addr, hostkey, PeerProvider::from_stream(peer_rx))
+        .enable_cover_traffic(cover_enabled)
+        .set_hops_per_tunnel(0)
+        .set_build_timeouts(timeouts)
+        .start();
+    let (onion, mut incoming) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            for problem in e.problems() {
+                eprintln!("error: {}", problem);
+            }
+            process::exit(1);
+        }
+    };
+    let timeouts = onion.build_timeouts();
+    println!(
+        "Building tunnels with a timeout of {:?} per hop and {:?} in total",
+        timeouts.hop, timeouts.deadline
+    );
 
     let mut tunnels: HashMap<TunnelId, TunnelWriter> = HashMap::new();
 
@@ -51,13 +83,16 @@ async fn parse_command(
     cmd: String,
     onion: &OnionContext,
     tunnels: &mut HashMap<TunnelId, TunnelWriter>,
-    hostkey: &RsaPublicKey,
+    hostkey: &HostPublicKey,
     peers: &mut mpsc::UnboundedSender<Peer>,
 ) {
     let mut parts = cmd.split_whitespace();
     match parts.next() {
         Some("build") => {
-            let dest_addr = parts.next().unwrap_or(DEFAULT_ADDR).parse().unwrap();
+            let dest_addr = match parse_addr(parts.next().unwrap_or(DEFAULT_ADDR)) {
+                Some(addr) => addr,
+                None => return,
+            };
             let dest = Peer::new(dest_addr, hostkey.clone());
             let tunnel = onion.build_tunnel(dest).await.unwrap();
             println!("Built tunnel with ID {}", tunnel.id());
@@ -78,10 +113,52 @@ async fn parse_command(
                 .unwrap();
         }
         Some("peer") => {
-            let peer_addr = parts.next().unwrap().parse().unwrap();
+            let peer_addr = match parse_addr(parts.next().unwrap_or_default()) {
+                Some(addr) => addr,
+                None => return,
+            };
             let peer = Peer::new(peer_addr, hostkey.clone());
             let _ = peers.send(peer);
         }
+        Some("list") => {
+            for tunnel_id in tunnels.keys() {
+                if let Some(hops) = onion.path_info(*tunnel_id) {
+                    println!("Tunnel {} (outgoing):", tunnel_id);
+                    for (position, hop) in hops.iter().enumerate() {
+                        println!("  hop {}: {} ({})", position, hop.addr, hop.params);
+                    }
+                } else if let Some(info) = onion.tunnel_info(*tunnel_id) {
+                    println!(
+                        "Tunnel {} (incoming): from {} (cell size {:?}, cipher suite {:?})",
+                        tunnel_id, info.adjacent_peer, info.cell_size, info.cipher_suite
+                    );
+                } else {
+                    println!("Tunnel {} (closed)", tunnel_id);
+                }
+            }
+            for circuit in onion.relay_stats().unwrap().inbound_circuits {
+                println!("Circuit from {} ({})", circuit.peer_addr, circuit.params);
+            }
+        }
+        Some("status") => {
+            let tunnels = onion.list_tunnels();
+            println!("{} outgoing tunnels", tunnels.len());
+            for info in tunnels {
+                let since_switchover = match info.since_switchover {
+                    Some(since) => format!("{:.1?} ago", since),
+                    None => "never".to_string(),
+                };
+                println!(
+                    "Tunnel {}: {:?}, {} hops, {} bytes sent, {} rotations, switched over {}",
+                    info.tunnel_id,
+                    info.state,
+                    info.hops,
+                    info.stats.sent_bytes,
+                    info.stats.rotations,
+                    since_switchover
+                );
+            }
+        }
         Some("cover") => {
             let size = parts.next().unwrap().parse().unwrap();
             onion.send_cover(size).unwrap();
@@ -89,15 +166,43 @@ async fn parse_command(
         Some("help") => {
             println!("Available Commands:");
             println!("  build <dest_addr> <n_hops>");
+            println!("  peer <addr>");
             println!("  destroy <tunnel_id>");
             println!("  data <tunnel_id> data");
+            println!("  list");
+            println!("  status");
             println!("  cover <size>");
             println!("  help");
+            println!("Addresses are given as 127.0.0.1:4200 or [::1]:4200.");
         }
         _ => println!("Unknown command!"),
     }
 }
 
+/// Parses an IPv4 or IPv6 socket address, printing the expected format if `s` is none.
+fn parse_addr(s: &str) -> Option<SocketAddr> {
+    match s.parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            eprintln!(
+                "Invalid address {:?}, expected e.g. 127.0.0.1:4200 or [::1]:4200",
+                s
+            );
+            None
+        }
+    }
+}
+
+/// Returns the number following the flag `name` on the command line, if given.
+fn flag_value(name: &str) -> Option<u64> {
+    flag_arg(name).map(|value| value.parse().unwrap())
+}
+
+/// Returns the argument following the flag `name` on the command line, if given.
+fn flag_arg(name: &str) -> Option<String> {
+    env::args().skip_while(|arg| arg != name).nth(1)
+}
+
 fn handle_tunnel_data(mut tunnel: Tunnel) {
     tokio::spawn(async move {
         while let Ok(data) = tunnel.read().await {
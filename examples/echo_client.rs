@@ -0,0 +1,216 @@
+//! Builds a tunnel to an `echo_server`, sends numbered messages at a fixed rate and verifies
+//! their echoes.
+//!
+//! ```text
+//! cargo run --example echo_client -- [--listen <addr>] [--key <pem>] [--server <addr>]
+//!     [--peer-key <pem>] [--relay <addr>]... [--hops <n>] [--count <n>] [--rate <msgs/s>]
+//!     [--round <secs>] [--timeout <secs>]
+//! ```
+//!
+//! The intermediate hops are drawn from the given relays, e.g. further echo servers. Like the
+//! `cli` example, all peers are expected to use the host key in `--peer-key`. Prints the loss and
+//! round-trip latency of the messages and exits with status 1 if any echo is missing or wrong.
+use allium::{OnionBuilder, Peer, PeerProvider, RsaPrivateKey};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::convert::TryInto;
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use tokio::time::{self, Duration, Instant};
+use tokio_stream as stream;
+
+const MESSAGE_LEN: usize = 32;
+
+struct Args {
+    listen: SocketAddr,
+    key: String,
+    server: SocketAddr,
+    peer_key: String,
+    relays: Vec<SocketAddr>,
+    hops: usize,
+    count: u64,
+    rate: f64,
+    round: Duration,
+    timeout: Duration,
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("error: {}", error);
+    eprintln!(
+        "usage: echo_client [--listen <addr>] [--key <pem>] [--server <addr>] [--peer-key <pem>] \
+         [--relay <addr>]... [--hops <n>] [--count <n>] [--rate <msgs/s>] [--round <secs>] \
+         [--timeout <secs>]"
+    );
+    process::exit(2);
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| usage(&format!("invalid value of {}: {}", flag, value)))
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        listen: "127.0.0.1:4201".parse().unwrap(),
+        key: "testkey.pem".to_string(),
+        server: "127.0.0.1:4200".parse().unwrap(),
+        peer_key: "testkey.pem".to_string(),
+        relays: vec![],
+        hops: 0,
+        count: 100,
+        rate: 10.0,
+        round: Duration::from_secs(2),
+        timeout: Duration::from_secs(5),
+    };
+    let mut argv = env::args().skip(1);
+    while let Some(flag) = argv.next() {
+        let value = argv
+            .next()
+            .unwrap_or_else(|| usage(&format!("missing value of {}", flag)));
+        match flag.as_str() {
+            "--listen" => args.listen = parse(&flag, &value),
+            "--key" => args.key = value,
+            "--server" => args.server = parse(&flag, &value),
+            "--peer-key" => args.peer_key = value,
+            "--relay" => args.relays.push(parse(&flag, &value)),
+            "--hops" => args.hops = parse(&flag, &value),
+            "--count" => args.count = parse(&flag, &value),
+            "--rate" => args.rate = parse(&flag, &value),
+            "--round" => args.round = Duration::from_secs_f64(parse(&flag, &value)),
+            "--timeout" => args.timeout = Duration::from_secs_f64(parse(&flag, &value)),
+            _ => usage(&format!("unknown flag {}", flag)),
+        }
+    }
+    if args.rate <= 0.0 {
+        usage("the rate must be positive");
+    }
+    args
+}
+
+/// Returns message `seq`, which carries the time it was sent relative to `start`.
+fn message(seq: u64, start: Instant) -> Bytes {
+    let mut buf = BytesMut::with_capacity(MESSAGE_LEN);
+    buf.put_u64(seq);
+    buf.put_u64(start.elapsed().as_micros() as u64);
+    for i in 0..MESSAGE_LEN - 16 {
+        buf.put_u8((seq as u8).wrapping_add(i as u8));
+    }
+    buf.freeze()
+}
+
+/// Returns the number and send time of an echoed message, or `None` if it was altered.
+fn parse_echo(data: &[u8]) -> Option<(u64, Duration)> {
+    if data.len() != MESSAGE_LEN {
+        return None;
+    }
+    let seq = u64::from_be_bytes(data[0..8].try_into().unwrap());
+    let sent = u64::from_be_bytes(data[8..16].try_into().unwrap());
+    let intact = data[16..]
+        .iter()
+        .enumerate()
+        .all(|(i, &b)| b == (seq as u8).wrapping_add(i as u8));
+    intact.then(|| (seq, Duration::from_micros(sent)))
+}
+
+#[tokio::main]
+async fn main() {
+    pretty_env_logger::init();
+    let args = parse_args();
+    let hostkey = RsaPrivateKey::from_pem_file(&args.key).unwrap_or_else(|e| usage(&e.to_string()));
+    let peer_key = RsaPrivateKey::from_pem_file(&args.peer_key)
+        .unwrap_or_else(|e| usage(&e.to_string()))
+        .public_key();
+    let relays = args
+        .relays
+        .iter()
+        .map(|&addr| Peer::new(addr, peer_key.clone()))
+        .collect::<Vec<_>>();
+    let started = OnionBuilder::new(
+        args.listen,
+        hostkey,
+        PeerProvider::from_stream(stream::iter(relays.into_iter().cycle())),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(args.hops)
+    .set_round_duration(args.round)
+    .start();
+    let (onion, _incoming) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            for problem in e.problems() {
+                eprintln!("error: {}", problem);
+            }
+            process::exit(1);
+        }
+    };
+
+    let server = Peer::new(args.server, peer_key);
+    let mut tunnel = match onion.build_tunnel(server).await {
+        Ok(tunnel) => tunnel,
+        Err(e) => {
+            eprintln!("error: could not build tunnel: {:?}", e);
+            process::exit(1);
+        }
+    };
+    println!("Built tunnel with ID {}", tunnel.id());
+
+    let start = Instant::now();
+    let writer = tunnel.writer();
+    let (count, period) = (args.count, Duration::from_secs_f64(1.0 / args.rate));
+    tokio::spawn(async move {
+        let mut interval = time::interval(period);
+        for seq in 0..count {
+            interval.tick().await;
+            if writer.write(message(seq, start)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = start + period * args.count as u32 + args.timeout;
+    let mut echoed = vec![false; args.count as usize];
+    let mut latencies = vec![];
+    let mut corrupt = 0;
+    while latencies.len() < echoed.len() {
+        let data = match time::timeout_at(deadline, tunnel.read()).await {
+            Ok(Ok(data)) => data,
+            Ok(Err(e)) => {
+                eprintln!("error: tunnel closed: {:?}", e);
+                break;
+            }
+            Err(_) => break,
+        };
+        match parse_echo(&data) {
+            Some((seq, sent)) if seq < args.count && !echoed[seq as usize] => {
+                echoed[seq as usize] = true;
+                latencies.push(start.elapsed() - sent);
+            }
+            _ => corrupt += 1,
+        }
+    }
+
+    let lost = args.count - latencies.len() as u64;
+    println!(
+        "sent {}, echoed {}, lost {} ({:.1} %), corrupt {}",
+        args.count,
+        latencies.len(),
+        lost,
+        lost as f64 / args.count.max(1) as f64 * 100.0,
+        corrupt
+    );
+    latencies.sort();
+    if let Some(max) = latencies.last() {
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "round-trip latency: p50 {:?}, p99 {:?}, max {:?}",
+            percentile(50),
+            percentile(99),
+            max
+        );
+    }
+    onion.shutdown().await;
+    if lost > 0 || corrupt > 0 {
+        process::exit(1);
+    }
+}
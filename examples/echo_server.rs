@@ -0,0 +1,94 @@
+//! Accepts incoming tunnels and echoes every data cell back, see `echo_client.rs`.
+//!
+//! ```text
+//! cargo run --example echo_server -- [--listen <addr>] [--key <pem>] [--round <secs>]
+//! ```
+//!
+//! The server also relays the circuits of other peers, so it can be used as a hop of the client
+//! as well. Once it listens, `listening on <addr>` is printed.
+use allium::{OnionBuilder, PeerProvider, RsaPrivateKey, Tunnel};
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use std::time::Duration;
+use tokio_stream as stream;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4200";
+const DEFAULT_KEY: &str = "testkey.pem";
+
+struct Args {
+    listen: SocketAddr,
+    key: String,
+    round: Duration,
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("error: {}", error);
+    eprintln!("usage: echo_server [--listen <addr>] [--key <pem>] [--round <secs>]");
+    process::exit(2);
+}
+
+fn parse_args() -> Args {
+    let mut args = Args {
+        listen: DEFAULT_ADDR.parse().unwrap(),
+        key: DEFAULT_KEY.to_string(),
+        round: Duration::from_secs(2),
+    };
+    let mut argv = env::args().skip(1);
+    while let Some(flag) = argv.next() {
+        let value = argv
+            .next()
+            .unwrap_or_else(|| usage(&format!("missing value of {}", flag)));
+        match flag.as_str() {
+            "--listen" => args.listen = value.parse().unwrap_or_else(|_| usage("invalid address")),
+            "--key" => args.key = value,
+            "--round" => {
+                let secs = value.parse().unwrap_or_else(|_| usage("invalid round"));
+                args.round = Duration::from_secs_f64(secs);
+            }
+            _ => usage(&format!("unknown flag {}", flag)),
+        }
+    }
+    args
+}
+
+#[tokio::main]
+async fn main() {
+    pretty_env_logger::init();
+    let args = parse_args();
+    let hostkey = RsaPrivateKey::from_pem_file(&args.key).unwrap_or_else(|e| usage(&e.to_string()));
+    let started = OnionBuilder::new(
+        args.listen,
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_round_duration(args.round)
+    .start();
+    let (_onion, mut incoming) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            for problem in e.problems() {
+                eprintln!("error: {}", problem);
+            }
+            process::exit(1);
+        }
+    };
+    println!("listening on {}", args.listen);
+
+    while let Some(tunnel) = incoming.next().await {
+        println!("Incoming tunnel with ID {}", tunnel.id());
+        tokio::spawn(echo(tunnel));
+    }
+}
+
+async fn echo(mut tunnel: Tunnel) {
+    let mut echoed = 0u64;
+    while let Ok(data) = tunnel.read().await {
+        if tunnel.write(data).is_err() {
+            break;
+        }
+        echoed += 1;
+    }
+    println!("Tunnel {} closed after {} echoes", tunnel.id(), echoed);
+}
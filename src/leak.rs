@@ -0,0 +1,63 @@
+//! Accounting of tasks and long-lived objects, used to find leaks in long-running deployments.
+//!
+//! Spawned tasks, tunnels, circuits and socket buffers carry a [`Tracked`] guard, which counts
+//! the live instances per label. The counts are only kept with the `leak-check` feature enabled,
+//! otherwise the guard is a zero-sized no-op.
+
+#[cfg(feature = "leak-check")]
+use std::collections::BTreeMap;
+#[cfg(feature = "leak-check")]
+use std::sync::Mutex;
+
+#[cfg(feature = "leak-check")]
+static LIVE: Mutex<BTreeMap<&'static str, usize>> = Mutex::new(BTreeMap::new());
+
+/// Counts an object as live under its label until dropped.
+pub(crate) struct Tracked {
+    #[cfg(feature = "leak-check")]
+    label: &'static str,
+}
+
+impl Tracked {
+    #[cfg(feature = "leak-check")]
+    pub(crate) fn new(label: &'static str) -> Self {
+        *LIVE.lock().unwrap().entry(label).or_insert(0) += 1;
+        Tracked { label }
+    }
+
+    #[cfg(not(feature = "leak-check"))]
+    pub(crate) fn new(_label: &'static str) -> Self {
+        Tracked {}
+    }
+}
+
+#[cfg(feature = "leak-check")]
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(count) = LIVE.lock().unwrap().get_mut(self.label) {
+            *count -= 1;
+        }
+    }
+}
+
+/// Returns the number of live tasks and objects per label, omitting labels without any.
+///
+/// The counts are shared by all onion router instances in the process.
+#[cfg(feature = "leak-check")]
+pub fn live_objects() -> BTreeMap<&'static str, usize> {
+    LIVE.lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, &count)| count > 0)
+        .map(|(&label, &count)| (label, count))
+        .collect()
+}
+
+/// Returns a human readable listing of [`live_objects`].
+#[cfg(feature = "leak-check")]
+pub fn debug_dump() -> String {
+    live_objects()
+        .iter()
+        .map(|(label, count)| format!("{}: {}\n", label, count))
+        .collect()
+}
@@ -10,35 +10,50 @@
 //!
 //! ## Getting started
 //!
-//! Each peer in the onion network requires a RSA keypair to sign its messages.
+//! Each peer in the onion network requires a RSA or Ed25519 keypair to sign its messages.
 //! A suitable RSA keypair can be generated with OpenSSL:
 //! ```text
 //! $ genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:4096 -out hostkey.pkcs8.pem
 //! $ openssl rsa -in hostkey.pkcs8.pem -out hostkey.pem
 //! ```
-//! Use [`RsaPrivateKey::from_pem_file`] to load the created key.
+//! Ed25519 keys are smaller and make handshakes faster, but can only be verified by peers of this
+//! version on:
+//! ```text
+//! $ openssl genpkey -algorithm ed25519 -out hostkey.pem
+//! ```
+//! Use [`HostKey::from_pem_file`] to load the created key, whichever type it has.
 //!
 //! Furthermore, the public keys of other peers in the network must be supplied to verify their identities.
 //! A peer can export its public key like this:
 //! ```text
-//! $ openssl rsa -in hostkey.pem -outform DER -pubout -out hostkey_pub.der
+//! $ openssl pkey -in hostkey.pem -outform DER -pubout -out hostkey_pub.der
 //! ```
-//! A remote peer is represented by the [`Peer`] struct which stores the peers address, port and [`RsaPublicKey`].
+//! A remote peer is represented by the [`Peer`] struct which stores the peers address, port and [`HostPublicKey`].
 //!
 //! To continuously (re-) build tunnels, the onion router needs a stream of peers which can be used as intermediary nodes in a tunnel.
 //! This is requirement is met by the [`PeerProvider`] struct, which can be created from a asynchronous `Stream<Item = Peer>`.
 //! The [`PeerProvider`] is fully responsible for the peer sampling.
 //!
-//! With a [`RsaPrivateKey`] and a [`PeerProvider`] ready, the actual onion router can be constructed.
+//! With a [`HostKey`] and a [`PeerProvider`] ready, the actual onion router can be constructed.
 //! The onion router is split into two parts: a stream of incoming connections and a context
 //! allowing the building of new tunnels.
 //! Use the [`OnionBuilder`] type to configure the onion router and then call [`OnionBuilder::start`]
 //! to obtain a [`OnionIncoming`] stream and a [`OnionContext`].
+//! Applications which only build tunnels and must not listen on any port, e.g. clients behind a
+//! NAT, use [`OnionBuilder::client_only`] instead, which requires no host key.
 //!
 //! [`OnionContext`] implements [`Clone`], [`Send`] and [`Sync`] allowing to have multiple handles to the
 //! same onion router instance.
 //! The async method [`OnionContext::build_tunnel`] blocks until a [`Tunnel`] was successfully created and is ready for communication.
 //! A [`Tunnel`] can be used similar to a normal socket by calling the [`Tunnel::read`] and [`Tunnel::write`] methods.
+//! To run an ordinary protocol over a tunnel, wrap it in an [`OnionStream`], which implements
+//! Tokio's `AsyncRead` and `AsyncWrite`.
+//! Wrapping both ends in a [`StreamMux`] instead carries multiple independent streams over a single
+//! tunnel.
+//! Call [`OnionContext::events`] to be notified when tunnels become ready or are rotated.
+//!
+//! The types used to configure tunnels, inspect them and handle their errors are grouped in the
+//! [`config`], [`stats`] and [`error`](mod@error) modules, which are also re-exported at the root.
 //!
 //! ## Daemon
 //!
@@ -48,38 +63,83 @@
 //! information on how to use Allium as a daemon.
 //!
 
+use anyhow::anyhow;
+use log::error;
+use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
-use tokio::sync::{mpsc, oneshot};
+use std::ops::BitOr;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, Notify};
 use tokio_stream::{Stream, StreamExt};
 
+mod leak;
 mod onion;
+mod task;
 mod utils;
 
-pub use crate::onion::crypto::{RsaPrivateKey, RsaPublicKey};
+pub use crate::onion::crypto::{
+    Ed25519PrivateKey, Ed25519PublicKey, HostKey, HostPublicKey, RsaPrivateKey, RsaPublicKey,
+};
 pub use crate::onion::tunnel::TunnelId;
 pub use crate::onion::*;
+pub use crate::task::caught_panics;
+
+#[cfg(feature = "leak-check")]
+pub use crate::leak::{debug_dump, live_objects};
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 
+/// The SHA-256 digest of a peer's host key, identifying the peer.
+pub type Fingerprint = [u8; 32];
+
 /// A remote peer characterized by its address, the port on which it is listening for onion
 /// connections and its public key.
 ///
 /// The public key is needed to verify the authenticity of signed messages received from this peer.
+///
+/// A peer may optionally carry the set of [`Capabilities`] it advertises, e.g. as obtained from a
+/// directory service.
 #[derive(Clone)]
 pub struct Peer {
     addr: SocketAddr,
-    hostkey: RsaPublicKey,
+    hostkey: HostPublicKey,
+    capabilities: Option<Capabilities>,
 }
 
 impl Peer {
-    pub fn new(addr: SocketAddr, hostkey: RsaPublicKey) -> Self {
-        Peer { addr, hostkey }
+    /// Creates a peer listening on `addr`, which is identified by a RSA or Ed25519 public key.
+    pub fn new(addr: SocketAddr, hostkey: impl Into<HostPublicKey>) -> Self {
+        Peer {
+            addr,
+            hostkey: hostkey.into(),
+            capabilities: None,
+        }
+    }
+
+    /// Attaches the capabilities advertised by this peer.
+    ///
+    /// These are only used to skip peers which are known to be unsuitable for a tunnel and may be
+    /// outdated.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = Some(capabilities);
+        self
     }
 
     pub fn address(&self) -> SocketAddr {
         self.addr
     }
+
+    /// Returns the fingerprint of this peer's host key.
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.hostkey.fingerprint()
+    }
+
+    /// Returns the capabilities advertised by this peer, if known.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        self.capabilities
+    }
 }
 
 impl fmt::Debug for Peer {
@@ -88,32 +148,268 @@ impl fmt::Debug for Peer {
     }
 }
 
+/// A set of optional protocol features supported by a peer.
+///
+/// Each feature is represented by a single bit. The features implemented by this crate use the
+/// upper 16 bits, the lower 16 bits are free to be used by applications.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// The peer drops padding messages sent within tunnels, see
+    /// [`OnionBuilder::set_padding_interval`].
+    ///
+    /// Every onion router of this version supports padding, but it is only sent to peers which are
+    /// known to advertise this capability.
+    pub const PADDING: Capabilities = Capabilities(1 << 16);
+    /// The application of the peer accepts multiple streams on its incoming tunnels, see
+    /// [`StreamMux`].
+    ///
+    /// Whether a tunnel is multiplexed is up to the applications at its ends, so peers only
+    /// advertise this if their application wraps every incoming tunnel in a multiplexer.
+    pub const STREAMS: Capabilities = Capabilities(1 << 17);
+
+    /// Returns the empty set of capabilities.
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Capabilities(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns whether all capabilities in `other` are contained in `self`.
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+/// The most recently known [`Capabilities`] of peers, keyed on the fingerprint of their host key.
+///
+/// Allows remembering the capabilities of a peer even if it is later passed without them. A failed
+/// handshake with a peer expected to support the required capabilities overrides its advertisement
+/// until the peer is passed with different capabilities, see [`record_mismatch`].
+///
+/// [`record_mismatch`]: CapabilityCache::record_mismatch
+#[derive(Clone, Default)]
+pub(crate) struct CapabilityCache {
+    inner: Arc<Mutex<HashMap<Fingerprint, CachedCapabilities>>>,
+}
+
+#[derive(Copy, Clone)]
+struct CachedCapabilities {
+    /// the capabilities the peer was last passed with
+    advertised: Option<Capabilities>,
+    known: Capabilities,
+}
+
+impl CapabilityCache {
+    /// Returns whether `peer` is not known to lack any of the `required` capabilities.
+    ///
+    /// Capabilities advertised by `peer` itself are recorded in the cache.
+    pub(crate) fn may_support(&self, peer: &Peer, required: Capabilities) -> bool {
+        let capabilities = self.lookup(peer);
+        if required == Capabilities::empty() {
+            return true;
+        }
+        capabilities.is_none_or(|capabilities| capabilities.contains(required))
+    }
+
+    /// Returns whether `peer` is known to support all of the `required` capabilities.
+    ///
+    /// Capabilities advertised by `peer` itself are recorded in the cache.
+    pub(crate) fn supports(&self, peer: &Peer, required: Capabilities) -> bool {
+        self.lookup(peer)
+            .is_some_and(|capabilities| capabilities.contains(required))
+    }
+
+    /// Records that the handshake with `peer` failed although it was expected to support the
+    /// `required` capabilities. The cached capabilities are considered stale, so `peer` is no
+    /// longer expected to support `required`, unless it advertises new capabilities.
+    pub(crate) fn record_mismatch(&self, peer: &Peer, required: Capabilities) {
+        let mut cache = self.inner.lock().unwrap();
+        let entry = cache
+            .entry(peer.fingerprint())
+            .or_insert(CachedCapabilities {
+                advertised: peer.capabilities,
+                known: peer.capabilities.unwrap_or_default(),
+            });
+        entry.known = Capabilities(entry.known.0 & !required.0);
+    }
+
+    fn lookup(&self, peer: &Peer) -> Option<Capabilities> {
+        let fingerprint = peer.fingerprint();
+        let mut cache = self.inner.lock().unwrap();
+        if let Some(capabilities) = peer.capabilities {
+            let cached = cache.get(&fingerprint);
+            if cached.is_none_or(|cached| cached.advertised != Some(capabilities)) {
+                cache.insert(
+                    fingerprint,
+                    CachedCapabilities {
+                        advertised: Some(capabilities),
+                        known: capabilities,
+                    },
+                );
+            }
+        }
+        cache.get(&fingerprint).map(|cached| cached.known)
+    }
+}
+
+/// Returned if a tunnel requires a random peer, but the stream of the [`PeerProvider`] ended.
+///
+/// Use [`OnionContext::replace_peer_provider`] to attach a new stream.
+#[derive(Error, Debug, PartialEq)]
+#[error("the peer provider was closed")]
+pub struct ProviderClosed;
+
 /// A stream of [`Peer`]s used for constructing tunnels.
 ///
 /// It is up to the user to choose an appropriate peer sampling and caching strategy.
 #[derive(Clone)]
 pub struct PeerProvider {
-    inner: mpsc::Sender<oneshot::Sender<Peer>>,
+    inner: Arc<ProviderSlot>,
+}
+
+impl fmt::Debug for PeerProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerProvider")
+            .field("generation", &self.current().0)
+            .finish()
+    }
+}
+
+/// The request channel of the current stream, which is shared by all clones of a provider.
+struct ProviderSlot {
+    /// the channel and how often it was replaced
+    requests: Mutex<(usize, mpsc::Sender<oneshot::Sender<Peer>>)>,
+    replaced: Notify,
 }
 
 impl PeerProvider {
     /// Turns a given stream of [`Peer`]s into a [`PeerProvider`].
+    ///
+    /// Has to be called from within a tokio runtime, the stream is polled by a task spawned on it.
     pub fn from_stream<S>(mut stream: S) -> Self
     where
         S: Stream<Item = Peer> + Unpin + Send + Sync + 'static,
     {
         let (peer_tx, mut peer_rx) = mpsc::channel::<oneshot::Sender<Peer>>(100);
-        tokio::spawn(async move {
+        task::spawn("task.peer_provider", async move {
             while let Some(req) = peer_rx.recv().await {
-                let _ = req.send(stream.next().await.unwrap());
+                // a panicking stream only fails the current request
+                match task::catch_panic(stream.next()).await {
+                    Ok(Some(peer)) => {
+                        let _ = req.send(peer);
+                    }
+                    Ok(None) => {
+                        // closed before dropping the request, so it is failed as closed
+                        peer_rx.close();
+                        break;
+                    }
+                    Err(panic) => error!("Peer provider panicked: {}", panic),
+                }
             }
         });
-        PeerProvider { inner: peer_tx }
+        PeerProvider {
+            inner: Arc::new(ProviderSlot {
+                requests: Mutex::new((0, peer_tx)),
+                replaced: Notify::new(),
+            }),
+        }
+    }
+
+    /// Returns a provider drawing from the same stream, whose stream can be replaced without
+    /// affecting `self`.
+    pub(crate) fn isolate(&self) -> Self {
+        let (_, requests) = self.current();
+        PeerProvider {
+            inner: Arc::new(ProviderSlot {
+                requests: Mutex::new((0, requests)),
+                replaced: Notify::new(),
+            }),
+        }
+    }
+
+    /// Makes this provider and all of its clones draw from the stream of `provider`.
+    pub(crate) fn replace(&self, provider: &PeerProvider) {
+        let (_, requests) = provider.current();
+        let mut current = self.inner.requests.lock().unwrap();
+        *current = (current.0 + 1, requests);
+        self.inner.replaced.notify_waiters();
+    }
+
+    fn current(&self) -> (usize, mpsc::Sender<oneshot::Sender<Peer>>) {
+        self.inner.requests.lock().unwrap().clone()
+    }
+
+    /// Waits until the current stream ends and returns its generation, which is increased by
+    /// every call to [`replace`](PeerProvider::replace).
+    pub(crate) async fn closed(&self) -> usize {
+        loop {
+            // created before reading the channel, so a replacement in between is not missed
+            let replaced = self.inner.replaced.notified();
+            let (generation, requests) = self.current();
+            tokio::select! {
+                _ = requests.closed() => {
+                    if self.current().0 == generation {
+                        return generation;
+                    }
+                }
+                _ = replaced => {}
+            }
+        }
+    }
+
+    /// Waits until the stream of the given generation was replaced.
+    pub(crate) async fn replaced(&self, generation: usize) {
+        loop {
+            let replaced = self.inner.replaced.notified();
+            if self.current().0 != generation {
+                return;
+            }
+            replaced.await;
+        }
     }
 
     pub(crate) async fn random_peer(&mut self) -> Result<Peer> {
+        let (_, requests) = self.current();
         let (peer_tx, peer_rx) = oneshot::channel();
-        let _ = self.inner.send(peer_tx).await;
-        Ok(peer_rx.await?)
+        requests.send(peer_tx).await.map_err(|_| ProviderClosed)?;
+        match peer_rx.await {
+            Ok(peer) => Ok(peer),
+            Err(_) if requests.is_closed() => Err(ProviderClosed.into()),
+            Err(_) => Err(anyhow!("Peer provider failed to provide a peer")),
+        }
+    }
+}
+
+/// Peers made known to the onion router out of band, keyed on their fingerprint.
+///
+/// These may be used for constrained hop positions, see [`TunnelOptions::set_hop`].
+#[derive(Clone, Default)]
+pub(crate) struct KnownPeers {
+    inner: Arc<Mutex<HashMap<Fingerprint, Peer>>>,
+}
+
+impl KnownPeers {
+    pub(crate) fn insert(&self, peer: Peer) {
+        self.inner.lock().unwrap().insert(peer.fingerprint(), peer);
+    }
+
+    pub(crate) fn get(&self, fingerprint: &Fingerprint) -> Option<Peer> {
+        self.inner.lock().unwrap().get(fingerprint).cloned()
     }
 }
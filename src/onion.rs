@@ -1,35 +1,118 @@
-use crate::{Peer, PeerProvider, Result};
+use crate::leak::Tracked;
+use crate::task;
+use crate::{CapabilityCache, Fingerprint, KnownPeers, Peer, PeerProvider, Result};
 use anyhow::anyhow;
 use bytes::Bytes;
 use circuit::CircuitHandler;
-use crypto::RsaPrivateKey;
-use log::{debug, info, warn};
+use coalesce::{BuildKey, Pending, PendingBuilds};
+use config::ContextSettings;
+use connection::{Accepted, CircuitStream, ConnectionCache, SharedStream};
+use crypto::{CipherSuites, HostKey};
+use diagnosis::SuspectedPeers;
+use endpoint::Endpoints;
+use guards::EntryGuards;
+use handshakes::HandshakeLimiter;
+use log::{debug, error, info, warn};
+use observer::Observer;
+use retry::DestinationRetries;
+use rng::{RngFactory, SharedRng};
+use shutdown::{EventTally, Shutdown, Subscriber};
 use socket::OnionSocket;
-use std::collections::{hash_map, HashMap};
+use startup::StartCheck;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::{cmp, fmt};
+use std::{fmt, mem};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::runtime::Handle;
 use tokio::sync::{broadcast, mpsc, oneshot};
-use tokio::time::{self, Duration};
-use tunnel::{Target, TunnelBuilder, TunnelHandler, TunnelId};
+use tokio::time::{self, Duration, Instant};
+use tunnel::{KeyLimits, RotationPolicy, Target, TunnelBuilder, TunnelHandler, TunnelId};
 
+pub(crate) mod build_info;
 pub(crate) mod circuit;
+pub(crate) mod coalesce;
+pub mod config;
+pub(crate) mod connection;
 pub(crate) mod crypto;
+pub(crate) mod diagnosis;
+pub(crate) mod endpoint;
+pub mod error;
+pub(crate) mod feedback;
+pub(crate) mod guards;
+pub(crate) mod handshakes;
+pub(crate) mod lanes;
+pub(crate) mod latency;
+pub(crate) mod mux;
+pub(crate) mod observer;
+pub mod policy;
 pub(crate) mod protocol;
+#[cfg(feature = "research")]
+pub(crate) mod research;
+pub(crate) mod retry;
+pub(crate) mod rng;
+pub(crate) mod shutdown;
 pub(crate) mod socket;
+pub(crate) mod startup;
+pub(crate) mod state;
+pub mod stats;
+pub(crate) mod stream;
 pub(crate) mod tunnel;
 
+pub use build_info::build_info;
+pub use config::{
+    BuildTimeouts, CellSize, CipherSuite, RotationStrategy, SocketTimeouts, TunnelOptions,
+};
+pub use error::{
+    BuildTimedOut, DestinationUnreachable, Fallback, HopSelectionError, InvalidAddressRange,
+    NoAcceptablePeers, NotARelay, PathUnreachable, ShuttingDown, StartError, StartProblem,
+    StrictViolation, TunnelBroken,
+};
+pub use feedback::{MessageHandle, WriteFeedback, WrittenMessage};
+pub use mux::{MuxStream, StreamMux};
+pub use observer::{StateObserver, TunnelState};
+pub use policy::{AddressRange, ExtendPolicy};
+pub use protocol::EndReason;
+#[cfg(feature = "research")]
+pub use research::{CellDirection, CellInspector, CellKind, CellMeta};
+pub use rng::{OnionRng, SeededRandom, SystemRandom};
+pub use state::{DestinationBackoff, NodeState, SuspectedPeer};
+pub use stats::{
+    BuildAttempt, BuildInfo, BuildOutcome, BuildReport, CircuitParams, ConnectionQueueInfo,
+    HandshakeVersion, HopInfo, InboundCircuitInfo, IncomingTunnelInfo, LatencyHistogram,
+    OutgoingTunnelInfo, PeerHandshakes, RelayStats, RetryBackoff, ShutdownReport, Transport,
+    TunnelStats,
+};
+pub(crate) use stats::{InboundCircuit, RelayCounters, TunnelCounters};
+pub use stream::OnionStream;
+
 #[cfg(test)]
+#[allow(clippy::needless_range_loop)]
 mod tests;
 
 const DEFAULT_ROUND_DURATION: Duration = Duration::from_secs(30);
 const DEFAULT_HOPS: usize = 2;
+const DEFAULT_MIN_TUNNEL_LIFETIME: Duration = Duration::from_secs(2);
+const DEFAULT_STALL_THRESHOLD: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_PENDING_HANDSHAKES: usize = 128;
+const DEFAULT_RELAY_CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_DIAGNOSIS_BUDGET: Duration = Duration::from_secs(2);
+/// deadline for an incoming connection to complete the circuit handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// minimum time between two reports of rejected incoming connections
+const BACKLOG_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// time after which a shutdown gives up on tunnels which are not closed yet
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// two thirds of the time after which relays close idle circuits, see [`circuit::IDLE_TIMEOUT`]
+const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(80);
+/// random tunnel ids drawn before building a tunnel fails, see [`TunnelRegistry::allocate_id`]
+const MAX_TUNNEL_ID_ATTEMPTS: usize = 16;
 
 const DATA_BUFFER_SIZE: usize = 100;
 const INCOMING_BUFFER_SIZE: usize = 100;
+/// a power of two, which the broadcast channel keeps as its capacity
+const EVENT_BUFFER_SIZE: usize = 128;
 
 static TUNNEL_COUNT: AtomicUsize = AtomicUsize::new(0);
 
@@ -42,17 +125,25 @@ static TUNNEL_COUNT: AtomicUsize = AtomicUsize::new(0);
 /// tunnel is specific to the intermediate hops.
 /// As a user, you will only deal with persistent tunnels, which forward data to and from
 /// periodically rebuilt ephemeral tunnels.
+///
+/// The tunnel is closed once it and all of its [`TunnelWriter`]s are dropped. Closing takes
+/// priority over data which has not been sent yet, which is discarded.
 pub struct Tunnel {
     tunnel_id: TunnelId,
     data_tx: mpsc::UnboundedSender<Bytes>,
     data_rx: mpsc::Receiver<Bytes>,
     counted: bool,
+    cell_size: CellSize,
+    stats: Arc<TunnelCounters>,
+    incoming_info: Option<IncomingTunnelInfo>,
+    _tracked: Tracked,
 }
 
 impl Tunnel {
     pub(crate) fn new(
         tunnel_id: TunnelId,
         counted: bool,
+        cell_size: CellSize,
     ) -> (Self, mpsc::Sender<Bytes>, mpsc::UnboundedReceiver<Bytes>) {
         if counted {
             TUNNEL_COUNT.fetch_add(1, Ordering::Relaxed);
@@ -64,12 +155,21 @@ impl Tunnel {
             data_tx,
             data_rx,
             counted,
+            cell_size,
+            stats: Default::default(),
+            incoming_info: None,
+            _tracked: Tracked::new("tunnel"),
         };
         (tunnel, data_tx2, data_rx2)
     }
 
     /// Receive data from the remote peer.
     ///
+    /// Data is returned in chunks as it arrives, at most one `TUNNEL DATA` cell per chunk, so a
+    /// large write of the peer is never buffered as a whole. Message boundaries are not
+    /// preserved. Only a bounded number of chunks is buffered for the application, once they
+    /// are not read the circuit is no longer read either, which eventually stalls the sender.
+    ///
     /// Returns an error if the connection was closed.
     pub async fn read(&mut self) -> Result<Bytes> {
         self.data_rx
@@ -78,48 +178,125 @@ impl Tunnel {
             .ok_or(anyhow!("Connection closed."))
     }
 
+    /// Receive data from the remote peer like [`Tunnel::read`], blocking the current thread until
+    /// data arrives.
+    ///
+    /// Intended for threads without a tokio runtime.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within an asynchronous execution context.
+    pub fn read_blocking(&mut self) -> Result<Bytes> {
+        self.data_rx
+            .blocking_recv()
+            .ok_or(anyhow!("Connection closed."))
+    }
+
     /// Send data to the remote peer.
     ///
     /// The data may be split across multiple messages if it is too large to fit into a single one.
+    /// The data is only queued, so this never blocks and does not require a tokio runtime, i.e.
+    /// it can be called from any thread.
     ///
     /// Returns an error if the connection was closed.
-    pub fn write(&self, mut buf: Bytes) -> Result<()> {
-        while !buf.is_empty() {
-            let part = buf.split_to(cmp::min(protocol::MAX_DATA_SIZE, buf.len()));
-            self.data_tx
-                .send(part)
-                .map_err(|_| anyhow!("Connection closed."))?;
-        }
+    pub fn write(&self, buf: Bytes) -> Result<()> {
+        queue_message(&self.data_tx, self.cell_size, &self.stats, buf, false)?;
         Ok(())
     }
 
+    /// Send data to the remote peer like [`Tunnel::write`], returning a handle which identifies the
+    /// message in the [`WriteFeedback`] of this tunnel.
+    ///
+    /// Returns an error if the connection was closed or write feedback is not enabled, see
+    /// [`TunnelOptions::enable_write_feedback`].
+    pub fn write_tracked(&self, buf: Bytes) -> Result<MessageHandle> {
+        write_tracked(&self.data_tx, self.cell_size, &self.stats, buf)
+    }
+
     /// Returns the unique id of this tunnel.
     pub fn id(&self) -> TunnelId {
         self.tunnel_id
     }
 
+    /// Returns the cell size used by this tunnel.
+    pub fn cell_size(&self) -> CellSize {
+        self.cell_size
+    }
+
+    /// Returns information about the path on which this tunnel reached us.
+    ///
+    /// Only available for tunnels returned by [`OnionIncoming::next`]. The information describes
+    /// the first path of the tunnel, see [`OnionContext::tunnel_info`] for the current one.
+    pub fn incoming_info(&self) -> Option<&IncomingTunnelInfo> {
+        self.incoming_info.as_ref()
+    }
+
+    /// Returns a snapshot of the statistics collected for this tunnel.
+    pub fn stats(&self) -> TunnelStats {
+        self.stats.snapshot()
+    }
+
+    /// Returns the report of the most recent successful build of a path for this tunnel.
+    ///
+    /// This is either the current path or the path prepared to replace it. Only available for
+    /// tunnels built by this onion router with [`OnionBuilder::enable_build_reports`] enabled.
+    pub fn last_build_report(&self) -> Option<BuildReport> {
+        self.stats.build_report()
+    }
+
+    /// Returns the stream reporting when the messages written to this tunnel were sent.
+    ///
+    /// Only available if enabled with [`TunnelOptions::enable_write_feedback`], and only the first
+    /// time this method is called.
+    pub fn write_feedback(&self) -> Option<WriteFeedback> {
+        self.stats.write_feedback()
+    }
+
     /// Create an additional write handle to this tunnel.
     pub fn writer(&self) -> TunnelWriter {
         TunnelWriter {
             tunnel_id: self.tunnel_id,
             data_tx: self.data_tx.clone(),
+            cell_size: self.cell_size,
+            stats: self.stats.clone(),
         }
     }
+}
 
-    async fn forward_data(
-        mut self,
-        mut tunnel_rx: mpsc::Receiver<Tunnel>,
-        data_tx: mpsc::Sender<Bytes>,
-        mut data_rx: mpsc::UnboundedReceiver<Bytes>,
-    ) -> Option<()> {
-        loop {
-            tokio::select! {
-                t = tunnel_rx.recv() => self = t?,
-                d = self.read() => data_tx.send(d.ok()?).await.ok()?,
-                d = data_rx.recv() => self.write(d?).ok()?,
-            }
-        }
+/// Queues `buf` for sending, split into parts which fit into a `TUNNEL DATA` cell each.
+fn queue_message(
+    data_tx: &mpsc::UnboundedSender<Bytes>,
+    cell_size: CellSize,
+    stats: &TunnelCounters,
+    buf: Bytes,
+    tracked: bool,
+) -> Result<Option<MessageHandle>> {
+    stats
+        .queue_message(buf, cell_size.max_data_size(), tracked, |part| {
+            data_tx.send(part)
+        })
+        .map_err(|_| anyhow!("Connection closed."))
+}
+
+fn write_tracked(
+    data_tx: &mpsc::UnboundedSender<Bytes>,
+    cell_size: CellSize,
+    stats: &TunnelCounters,
+    buf: Bytes,
+) -> Result<MessageHandle> {
+    if !stats.has_write_feedback() {
+        return Err(anyhow!("Write feedback is not enabled for this tunnel"));
     }
+    let handle = queue_message(data_tx, cell_size, stats, buf, true)?;
+    // feedback can not be disabled later on
+    Ok(handle.unwrap())
+}
+
+/// Waits until `data_tx` has room for another message.
+///
+/// Returns `false` if the receiver has been closed.
+pub(crate) async fn has_room(data_tx: &mpsc::Sender<Bytes>) -> bool {
+    data_tx.reserve().await.is_ok()
 }
 
 impl fmt::Debug for Tunnel {
@@ -141,6 +318,453 @@ impl Drop for Tunnel {
     }
 }
 
+/// Notifications about the lifecycle of tunnels built by an onion router.
+///
+/// Use [`OnionContext::events`] to subscribe.
+///
+/// With the `serde` feature enabled, events implement `Serialize` and `Deserialize`, so they can be
+/// forwarded to other processes. Durations are represented by their seconds and nanoseconds.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub enum Event {
+    /// The tunnel with the given id is ready for communication.
+    Ready {
+        tunnel_id: TunnelId,
+        cause: ReadyCause,
+    },
+    /// The tunnel with the given id was switched over to a freshly built path.
+    Rotated {
+        tunnel_id: TunnelId,
+        /// How long the replaced path carried data for this tunnel.
+        old_path_age: Duration,
+    },
+    /// Building a replacement path for the tunnel with the given id failed repeatedly and was
+    /// given up. The tunnel keeps its current path, building a replacement is started over at the
+    /// next switchover.
+    RotationFailed { tunnel_id: TunnelId },
+    /// Data written to the tunnel with the given id has not been sent for at least the stall
+    /// threshold configured with [`OnionBuilder::set_stall_threshold`].
+    /// Emitted once per stall.
+    Stalled {
+        tunnel_id: TunnelId,
+        duration: Duration,
+    },
+    /// Incoming connections were closed because too many connections were performing the circuit
+    /// handshake, which may indicate a flooding attempt.
+    /// Emitted at most every 10 seconds, with the number of connections rejected since the last
+    /// report.
+    HandshakeBacklogFull { rejected: u64 },
+    /// The stream of the [`PeerProvider`] ended. Building tunnels which require random peers fails
+    /// with [`ProviderClosed`](crate::ProviderClosed) until a new stream is attached using
+    /// [`OnionContext::replace_peer_provider`].
+    PeerProviderClosed,
+    /// The tunnel with the given id was closed and can not be used anymore.
+    ///
+    /// If the tunnel was ended by its destination or its path was torn down by the first hop, the
+    /// reason sent by that peer is given as `end_reason`. Peers predating reason codes send
+    /// [`EndReason::Unspecified`].
+    Closed {
+        tunnel_id: TunnelId,
+        reason: CloseReason,
+        end_reason: Option<EndReason>,
+    },
+    /// The path of the tunnel with the given id failed and the hop at `position` is suspected to
+    /// have caused the failure, since every hop before it still answered. Position 0 is the first
+    /// hop, a lost connection to it is always attributed to it.
+    ///
+    /// Emitted before the path is replaced or the tunnel is closed. Paths torn down by a hop can
+    /// not be attributed. The suspected peer is avoided when random hops are chosen, see
+    /// [`OnionBuilder::set_diagnosis_budget`].
+    HopSuspected {
+        tunnel_id: TunnelId,
+        position: usize,
+        fingerprint: Fingerprint,
+    },
+    /// A peer was chosen as entry guard or a guard was demoted after repeated failures, see
+    /// [`OnionBuilder::set_entry_guards`]. Lists the fingerprints of the current guards.
+    GuardsChanged { guards: Vec<Fingerprint> },
+    /// A task working on the tunnel with the given id panicked.
+    /// Builds of replacement paths are retried, while a panic while forwarding data closes the
+    /// tunnel.
+    Error {
+        tunnel_id: TunnelId,
+        message: String,
+    },
+}
+
+/// The reason for an [`Event::Ready`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub enum ReadyCause {
+    /// The tunnel was built for the first time.
+    Initial,
+}
+
+/// The reason for an [`Event::Closed`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub enum CloseReason {
+    /// The first hop tore down the path because it timed out or ran out of resources, and no
+    /// replacement could be built.
+    TornDown,
+    /// The first hop tore down the path for any other reason, e.g. a protocol error, which a new
+    /// path would not avoid. The tunnel is closed without replacing the path.
+    Rejected,
+    /// The connection to the first hop failed or timed out and no replacement could be built.
+    ConnectionLost,
+    /// The tunnel failed due to any other error.
+    Failed,
+    /// The onion router was shut down using [`OnionContext::shutdown`].
+    Shutdown,
+    /// The handler of the tunnel ended unexpectedly, e.g. because it panicked, which is reported
+    /// by an [`Event::Error`] first.
+    Internal,
+    /// The destination ended the tunnel, e.g. because its application closed it.
+    Ended,
+    /// A session key of the path was used for the maximum number of cells and no path with fresh
+    /// keys could be built, see [`OnionBuilder::set_key_usage_limits`].
+    KeysExhausted,
+    /// Neither the application nor the destination sent data for the idle timeout of the tunnel,
+    /// see [`OnionBuilder::set_tunnel_idle_timeout`]. The tunnel was ended like one closed by the
+    /// application.
+    IdleTimeout,
+    /// The last hop of the path stopped answering keep-alives and no replacement could be built,
+    /// see [`OnionBuilder::set_keep_alive_interval`].
+    Unresponsive,
+}
+
+impl CloseReason {
+    /// Returns whether a tunnel whose path failed for this reason should immediately be moved to
+    /// a new path instead of being closed.
+    pub(crate) fn is_recoverable(self) -> bool {
+        match self {
+            CloseReason::TornDown | CloseReason::ConnectionLost | CloseReason::Unresponsive => true,
+            CloseReason::Rejected
+            | CloseReason::Failed
+            | CloseReason::Shutdown
+            | CloseReason::Internal
+            | CloseReason::Ended
+            | CloseReason::KeysExhausted
+            | CloseReason::IdleTimeout => false,
+        }
+    }
+
+    /// Returns the reason sent to the peers of a tunnel closed for this reason.
+    pub(crate) fn end_reason(self) -> EndReason {
+        match self {
+            CloseReason::Shutdown | CloseReason::Ended => EndReason::Normal,
+            CloseReason::Failed => EndReason::ProtocolError,
+            CloseReason::IdleTimeout | CloseReason::Unresponsive => EndReason::Timeout,
+            CloseReason::KeysExhausted => EndReason::ResourceLimit,
+            CloseReason::TornDown
+            | CloseReason::Rejected
+            | CloseReason::ConnectionLost
+            | CloseReason::Internal => EndReason::Unspecified,
+        }
+    }
+}
+
+/// A stream of [`Event`]s.
+///
+/// Events are never waited for and writing to a [`Tunnel`] never blocks, so the task consuming
+/// the stream may call back into the onion router, e.g. to write to a tunnel in response to an
+/// event. A consumer which falls behind misses events instead of holding up the tunnels.
+pub struct OnionEvents {
+    events: broadcast::Receiver<Event>,
+    subscriber: Arc<Subscriber>,
+    shutdown: Arc<Shutdown>,
+}
+
+impl OnionEvents {
+    /// Returns the next [`Event`].
+    ///
+    /// Events which were not consumed in time are skipped.
+    /// Returns `None` once the onion router was shut down and all remaining events were returned.
+    pub async fn next(&mut self) -> Option<Event> {
+        loop {
+            let evt = tokio::select! {
+                biased;
+                evt = self.events.recv() => evt,
+                _ = self.shutdown.finished() => return None,
+            };
+            match evt {
+                Ok(evt) => {
+                    self.subscriber.record(0);
+                    return Some(evt);
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Skipped {} onion events", n);
+                    self.subscriber.record(n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Limits the number of incoming connections performing the circuit handshake at the same time.
+#[derive(Clone)]
+pub(crate) struct HandshakeBacklog {
+    max_pending: usize,
+    counters: Arc<RelayCounters>,
+    notify: broadcast::Sender<Event>,
+    last_report: Option<Instant>,
+    unreported: u64,
+}
+
+impl HandshakeBacklog {
+    fn new(
+        max_pending: usize,
+        counters: Arc<RelayCounters>,
+        notify: broadcast::Sender<Event>,
+    ) -> Self {
+        HandshakeBacklog {
+            max_pending,
+            counters,
+            notify,
+            last_report: None,
+            unreported: 0,
+        }
+    }
+
+    /// Admits a new connection to the handshake phase, unless the backlog is full.
+    fn try_admit(&mut self) -> bool {
+        let pending = &self.counters.pending_handshakes;
+        if pending.load(Ordering::Relaxed) < self.max_pending {
+            pending.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+
+        self.counters
+            .rejected_handshakes
+            .fetch_add(1, Ordering::Relaxed);
+        self.unreported += 1;
+        let report_due = match self.last_report {
+            Some(t) => t.elapsed() >= BACKLOG_REPORT_INTERVAL,
+            None => true,
+        };
+        if report_due {
+            warn!(
+                "Handshake backlog full, rejected {} incoming connections",
+                self.unreported
+            );
+            let _ = self.notify.send(Event::HandshakeBacklogFull {
+                rejected: self.unreported,
+            });
+            self.last_report = Some(Instant::now());
+            self.unreported = 0;
+        }
+        false
+    }
+
+    /// Removes an admitted connection from the backlog once its handshake is done.
+    fn finish(&self) {
+        self.counters
+            .pending_handshakes
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for HandshakeBacklog {
+    fn default() -> Self {
+        let (notify, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+        HandshakeBacklog::new(DEFAULT_MAX_PENDING_HANDSHAKES, Default::default(), notify)
+    }
+}
+
+/// The [`IncomingTunnelInfo`] of all open incoming tunnels and the state and hops of all
+/// outgoing tunnels, updated whenever a tunnel changes its state or is rebuilt.
+///
+/// Also tracks the ids and destinations of outgoing tunnels and the failures of the destinations,
+/// which delay automatic rebuilds towards them.
+#[derive(Clone, Default)]
+pub(crate) struct TunnelRegistry {
+    incoming: Arc<std::sync::Mutex<HashMap<TunnelId, IncomingTunnelInfo>>>,
+    /// the outgoing tunnels which are built or whose handlers have not finished
+    outgoing: Arc<std::sync::Mutex<HashMap<TunnelId, OutgoingTunnel>>>,
+    pub(crate) retries: DestinationRetries,
+    /// the generator the ids of outgoing tunnels are drawn from
+    rng: SharedRng,
+}
+
+/// An outgoing tunnel in the [`TunnelRegistry`].
+struct OutgoingTunnel {
+    state: TunnelState,
+    /// the hops of the current path and the time it was switched to, once the tunnel is ready
+    path: Option<(Vec<HopInfo>, Instant)>,
+    destination: Option<Fingerprint>,
+    stats: Arc<TunnelCounters>,
+    /// passes [`OnionContext::ping_tunnel`] and [`OnionContext::rekey_tunnel`] to the handler of
+    /// the tunnel
+    requests: Option<mpsc::UnboundedSender<tunnel::HandlerRequest>>,
+}
+
+impl Default for OutgoingTunnel {
+    fn default() -> Self {
+        OutgoingTunnel {
+            state: TunnelState::Building,
+            path: None,
+            destination: None,
+            stats: Default::default(),
+            requests: None,
+        }
+    }
+}
+
+impl OutgoingTunnel {
+    fn info(&self, tunnel_id: TunnelId) -> OutgoingTunnelInfo {
+        OutgoingTunnelInfo {
+            tunnel_id,
+            state: self.state,
+            hops: self.path.as_ref().map_or(0, |(hops, _)| hops.len()),
+            destination: self.destination,
+            stats: self.stats.snapshot(),
+            since_switchover: self
+                .path
+                .as_ref()
+                .map(|(_, switched_at)| switched_at.elapsed()),
+        }
+    }
+}
+
+impl TunnelRegistry {
+    /// Allocates a random id for a new outgoing tunnel, see [`allocate_id_with`].
+    ///
+    /// [`allocate_id_with`]: TunnelRegistry::allocate_id_with
+    pub(crate) fn allocate_id(&self) -> Option<TunnelId> {
+        let mut rng = self.rng.lock();
+        self.allocate_id_with(|| tunnel::random_id(&mut **rng))
+    }
+
+    /// Allocates an id drawn from `random_id`, re-rolling on collision with the id of another
+    /// outgoing tunnel, so the events of two tunnels can not be confused. Returns `None` if no
+    /// free id has been drawn after a few attempts.
+    ///
+    /// The id is in use until it is [released](TunnelRegistry::release).
+    pub(crate) fn allocate_id_with<F>(&self, mut random_id: F) -> Option<TunnelId>
+    where
+        F: FnMut() -> TunnelId,
+    {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        let id = (0..MAX_TUNNEL_ID_ATTEMPTS)
+            .map(|_| random_id())
+            .find(|id| !outgoing.contains_key(id))?;
+        outgoing.insert(id, Default::default());
+        Some(id)
+    }
+
+    /// Removes the id, state, path and destination of an outgoing tunnel whose handler has
+    /// finished or which could not be built.
+    pub(crate) fn release(&self, tunnel_id: TunnelId) {
+        self.outgoing.lock().unwrap().remove(&tunnel_id);
+    }
+
+    /// Updates an outgoing tunnel, which is added unless it has been released.
+    fn update<F: FnOnce(&mut OutgoingTunnel)>(&self, tunnel_id: TunnelId, f: F) {
+        f(self.outgoing.lock().unwrap().entry(tunnel_id).or_default())
+    }
+
+    /// Makes the statistics of an outgoing tunnel part of its [`OutgoingTunnelInfo`].
+    pub(crate) fn set_stats(&self, tunnel_id: TunnelId, stats: Arc<TunnelCounters>) {
+        self.update(tunnel_id, |tunnel| tunnel.stats = stats);
+    }
+
+    /// Records the state of an outgoing tunnel. A released tunnel is not added again.
+    pub(crate) fn set_state(&self, tunnel_id: TunnelId, state: TunnelState) {
+        if let Some(tunnel) = self.outgoing.lock().unwrap().get_mut(&tunnel_id) {
+            tunnel.state = state;
+        }
+    }
+
+    /// Records the path an outgoing tunnel has just been switched to.
+    pub(crate) fn set_path(&self, tunnel_id: TunnelId, hops: Vec<HopInfo>) {
+        self.update(tunnel_id, |tunnel| {
+            tunnel.path = Some((hops, Instant::now()))
+        });
+    }
+
+    /// Makes the handler of an outgoing tunnel receive the requests of
+    /// [`OnionContext::ping_tunnel`] and [`OnionContext::rekey_tunnel`].
+    pub(crate) fn set_requests(
+        &self,
+        tunnel_id: TunnelId,
+        requests: mpsc::UnboundedSender<tunnel::HandlerRequest>,
+    ) {
+        self.update(tunnel_id, |tunnel| tunnel.requests = Some(requests));
+    }
+
+    /// Passes `request` to the handler of an outgoing tunnel.
+    fn send_request(&self, tunnel_id: TunnelId, request: tunnel::HandlerRequest) -> Result<()> {
+        let requests = self
+            .outgoing
+            .lock()
+            .unwrap()
+            .get(&tunnel_id)
+            .and_then(|tunnel| tunnel.requests.clone())
+            .ok_or_else(|| anyhow!("No outgoing tunnel with id {}", tunnel_id))?;
+        requests
+            .send(request)
+            .map_err(|_| anyhow!("Tunnel {} is closed", tunnel_id))
+    }
+
+    fn path(&self, tunnel_id: TunnelId) -> Option<Vec<HopInfo>> {
+        let outgoing = self.outgoing.lock().unwrap();
+        Some(outgoing.get(&tunnel_id)?.path.as_ref()?.0.clone())
+    }
+
+    pub(crate) fn set_destination(&self, tunnel_id: TunnelId, fingerprint: Fingerprint) {
+        self.update(tunnel_id, |tunnel| tunnel.destination = Some(fingerprint));
+    }
+
+    fn retry_backoff(&self, tunnel_id: TunnelId) -> Option<RetryBackoff> {
+        let fingerprint = self.outgoing.lock().unwrap().get(&tunnel_id)?.destination?;
+        self.retries.backoff(&fingerprint)
+    }
+
+    fn outgoing_info(&self, tunnel_id: TunnelId) -> Option<OutgoingTunnelInfo> {
+        let outgoing = self.outgoing.lock().unwrap();
+        Some(outgoing.get(&tunnel_id)?.info(tunnel_id))
+    }
+
+    /// Returns the [`OutgoingTunnelInfo`] of all outgoing tunnels, ordered by id.
+    fn list_outgoing(&self) -> Vec<OutgoingTunnelInfo> {
+        let outgoing = self.outgoing.lock().unwrap();
+        let mut infos: Vec<_> = outgoing
+            .iter()
+            .map(|(&id, tunnel)| tunnel.info(id))
+            .collect();
+        infos.sort_by_key(|info| info.tunnel_id);
+        infos
+    }
+
+    fn insert_incoming(&self, tunnel_id: TunnelId, info: IncomingTunnelInfo) {
+        self.incoming.lock().unwrap().insert(tunnel_id, info);
+    }
+
+    fn remove_incoming(&self, tunnel_id: TunnelId) {
+        self.incoming.lock().unwrap().remove(&tunnel_id);
+    }
+
+    fn incoming(&self, tunnel_id: TunnelId) -> Option<IncomingTunnelInfo> {
+        self.incoming.lock().unwrap().get(&tunnel_id).cloned()
+    }
+}
+
 /// A write handle to a [`Tunnel`].
 ///
 /// Each tunnel may have arbitrarily many [`TunnelWriter`]s.
@@ -150,19 +774,22 @@ impl Drop for Tunnel {
 pub struct TunnelWriter {
     tunnel_id: TunnelId,
     data_tx: mpsc::UnboundedSender<Bytes>,
+    cell_size: CellSize,
+    stats: Arc<TunnelCounters>,
 }
 
 impl TunnelWriter {
-    pub fn write(&self, mut buf: Bytes) -> Result<()> {
-        while !buf.is_empty() {
-            let part = buf.split_to(cmp::min(protocol::MAX_DATA_SIZE, buf.len()));
-            self.data_tx
-                .send(part)
-                .map_err(|_| anyhow!("Connection closed."))?;
-        }
+    /// Send data to the remote peer, see [`Tunnel::write`].
+    pub fn write(&self, buf: Bytes) -> Result<()> {
+        queue_message(&self.data_tx, self.cell_size, &self.stats, buf, false)?;
         Ok(())
     }
 
+    /// Send data to the remote peer, see [`Tunnel::write_tracked`].
+    pub fn write_tracked(&self, buf: Bytes) -> Result<MessageHandle> {
+        write_tracked(&self.data_tx, self.cell_size, &self.stats, buf)
+    }
+
     pub fn id(&self) -> TunnelId {
         self.tunnel_id
     }
@@ -176,35 +803,169 @@ impl fmt::Debug for TunnelWriter {
     }
 }
 
+/// The result of [`OnionContext::build_tunnel_coalesced`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CoalescedTunnel {
+    /// No equal build was in flight, so a tunnel was built for this call.
+    Built(Tunnel),
+    /// An equal build was in flight and its tunnel is shared. Data written to the writer is sent
+    /// on that tunnel, while data received on it is only delivered to the [`Tunnel`] returned to
+    /// the first caller.
+    Shared(TunnelWriter),
+}
+
+impl CoalescedTunnel {
+    /// Returns the id of the tunnel, which is the same for all callers sharing it.
+    pub fn id(&self) -> TunnelId {
+        match self {
+            CoalescedTunnel::Built(tunnel) => tunnel.id(),
+            CoalescedTunnel::Shared(writer) => writer.id(),
+        }
+    }
+}
+
 /// A handle to the underlying onion router allowing the construction of new tunnels.
 ///
 /// Use [`OnionBuilder`] to configure and start a new onion router instance.
 /// This type implements [`Clone`], [`Send`] and [`Sync`], so it can be shared across threads.
+///
+/// The synchronous methods only use channels and locks, so they can be called from any thread,
+/// including threads without a tokio runtime. The asynchronous methods can be awaited on any
+/// runtime, the work they start always runs on the runtime the onion router was started on.
+/// Threads without a runtime can use the `_blocking` variants instead.
 #[derive(Clone)]
 pub struct OnionContext {
+    runtime: Handle,
     peer_provider: PeerProvider,
     n_hops: usize,
+    rotation_policy: RotationPolicy,
+    stall_threshold: Duration,
     events: broadcast::Sender<tunnel::Event>,
+    notify: broadcast::Sender<Event>,
+    capabilities: CapabilityCache,
+    known_peers: KnownPeers,
+    registry: TunnelRegistry,
+    relay_stats: Arc<RelayCounters>,
+    build_reports: bool,
+    padding_interval: Duration,
+    idle_timeout: Duration,
+    build_timeouts: BuildTimeouts,
+    diagnosis_budget: Duration,
+    suspects: SuspectedPeers,
+    guards: EntryGuards,
+    handshakes: HandshakeLimiter,
+    strict: bool,
+    observer: Observer,
+    shutdown: Arc<Shutdown>,
+    shutdown_timeout: Duration,
+    event_tally: Arc<EventTally>,
     cover_tunnel: TunnelWriter,
+    pending_builds: PendingBuilds,
+    /// creates the generators of the circuit ids of first hops
+    rng: RngFactory,
+    /// the connections shared by the first hops of the tunnels, if enabled
+    connections: Option<ConnectionCache>,
+    socket_timeouts: SocketTimeouts,
+    max_batch_size: usize,
+    /// `None` in client-only mode
+    local_addr: Option<SocketAddr>,
 }
 
 impl OnionContext {
     fn new(
+        runtime: Handle,
         events: broadcast::Sender<tunnel::Event>,
         peer_provider: PeerProvider,
-        n_hops: usize,
-        enable_cover: bool,
+        settings: ContextSettings,
+        state: &NodeState,
     ) -> Self {
+        let ContextSettings {
+            n_hops,
+            rotation_policy,
+            stall_threshold,
+            enable_cover,
+            build_reports,
+            padding_interval,
+            idle_timeout,
+            build_timeouts,
+            diagnosis_budget,
+            max_handshakes_per_peer,
+            strict,
+            observer,
+            rng,
+            first_hop_connection_idle_timeout,
+            socket_timeouts,
+            max_batch_size,
+            shutdown_timeout,
+            local_addr,
+            n_guards,
+            imported_guards,
+        } = settings;
         let (cover_tx, cover_rx) = mpsc::unbounded_channel();
+        let (notify, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+        let event_tally = Arc::new(EventTally::new(&notify, EVENT_BUFFER_SIZE));
+        let guards = EntryGuards::new(n_guards, imported_guards, notify.clone());
+        let connections = (first_hop_connection_idle_timeout > Duration::ZERO).then(|| {
+            ConnectionCache::new(first_hop_connection_idle_timeout, None)
+                .with_rng(rng.clone())
+                .with_socket_timeouts(socket_timeouts)
+        });
         let ctx = OnionContext {
-            peer_provider,
+            runtime,
+            peer_provider: peer_provider.isolate(),
             n_hops,
+            rotation_policy,
+            stall_threshold,
             events,
+            notify,
+            capabilities: Default::default(),
+            known_peers: Default::default(),
+            registry: TunnelRegistry {
+                rng: rng.shared(),
+                ..Default::default()
+            },
+            relay_stats: Default::default(),
+            build_reports,
+            padding_interval,
+            idle_timeout,
+            build_timeouts,
+            diagnosis_budget,
+            suspects: Default::default(),
+            guards,
+            handshakes: HandshakeLimiter::new(max_handshakes_per_peer),
+            strict,
+            observer,
+            shutdown: Default::default(),
+            shutdown_timeout,
+            event_tally,
             cover_tunnel: TunnelWriter {
                 tunnel_id: 0,
                 data_tx: cover_tx,
+                cell_size: CellSize::default(),
+                stats: Default::default(),
             },
+            pending_builds: Default::default(),
+            rng,
+            connections,
+            socket_timeouts,
+            max_batch_size,
+            local_addr,
         };
+        // before any tunnel can be built
+        state.restore(&ctx.suspects, &ctx.registry.retries);
+
+        task::spawn("task.provider_watch", {
+            let peer_provider = ctx.peer_provider.clone();
+            let notify = ctx.notify.clone();
+            let shutdown = ctx.shutdown.clone();
+            async move {
+                tokio::select! {
+                    _ = watch_peer_provider(peer_provider, notify) => {}
+                    _ = shutdown.finished() => {}
+                }
+            }
+        });
 
         if enable_cover {
             let mut cover_handler = CoverHandler {
@@ -213,7 +974,7 @@ impl OnionContext {
                 cover_tunnel: None,
             };
 
-            tokio::spawn(async move {
+            task::spawn("task.cover_handler", async move {
                 cover_handler.handle().await;
             });
         }
@@ -221,40 +982,489 @@ impl OnionContext {
         ctx
     }
 
+    /// Subscribes to [`Event`]s concerning this onion router and the tunnels built by it.
+    ///
+    /// Only events emitted after this call are received.
+    pub fn events(&self) -> OnionEvents {
+        observer::debug_assert_not_observing();
+        let events = self.notify.subscribe();
+        OnionEvents {
+            events,
+            subscriber: self.event_tally.subscribe(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// Adds a peer which is not necessarily provided by the [`PeerProvider`], e.g. a private
+    /// relay.
+    ///
+    /// Known peers are never chosen randomly, but can be required for specific hops using
+    /// [`TunnelOptions::set_hop`].
+    pub fn add_known_peer(&self, peer: Peer) {
+        observer::debug_assert_not_observing();
+        self.known_peers.insert(peer);
+    }
+
+    /// Replaces the stream of peers used for building tunnels, e.g. after the stream of the
+    /// previous [`PeerProvider`] ended.
+    ///
+    /// Builds which are waiting for a peer are not affected, later builds use the new stream.
+    pub fn replace_peer_provider(&self, peer_provider: PeerProvider) {
+        observer::debug_assert_not_observing();
+        self.peer_provider.replace(&peer_provider);
+    }
+
+    /// Returns the address on which this onion router accepts connections from other peers.
+    ///
+    /// Returns `None` if it was started with [`OnionBuilder::client_only`], in which case no
+    /// socket is bound at all.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Returns the timeouts applied while building tunnel paths, see
+    /// [`OnionBuilder::set_build_timeouts`].
+    pub fn build_timeouts(&self) -> BuildTimeouts {
+        self.build_timeouts
+    }
+
+    /// Returns a snapshot of the statistics about incoming connections.
+    ///
+    /// Fails with [`NotARelay`] in client-only mode.
+    pub fn relay_stats(&self) -> std::result::Result<RelayStats, NotARelay> {
+        observer::debug_assert_not_observing();
+        self.local_addr.ok_or(NotARelay)?;
+        Ok(self.relay_stats.snapshot())
+    }
+
+    /// Clears the [`RelayStats::forwarding_latency`] and lowers the high-water marks of the
+    /// [`RelayStats::relay_connection_queues`] to the current length of each queue, e.g. to
+    /// measure each interval of a capacity planning period on its own.
+    ///
+    /// Fails with [`NotARelay`] in client-only mode.
+    pub fn reset_relay_measurements(&self) -> std::result::Result<(), NotARelay> {
+        self.local_addr.ok_or(NotARelay)?;
+        self.relay_stats.reset_measurements();
+        Ok(())
+    }
+
+    /// Returns the peers with which circuit handshakes are in progress or waiting while building
+    /// tunnels, see [`OnionBuilder::set_max_handshakes_per_peer`].
+    pub fn outgoing_handshakes(&self) -> Vec<PeerHandshakes> {
+        observer::debug_assert_not_observing();
+        self.handshakes.snapshot()
+    }
+
+    /// Returns the current entry guards, oldest first, see [`OnionBuilder::set_entry_guards`].
+    ///
+    /// The guards can be restored after a restart with [`OnionBuilder::import_entry_guards`].
+    pub fn entry_guards(&self) -> Vec<Peer> {
+        observer::debug_assert_not_observing();
+        self.guards.peers()
+    }
+
+    /// Returns the state learned about other peers, which can be restored after a restart with
+    /// [`OnionBuilder::import_state`].
+    pub fn export_state(&self) -> NodeState {
+        observer::debug_assert_not_observing();
+        NodeState::capture(&self.suspects, &self.registry.retries)
+    }
+
+    /// Returns information about the current path of the incoming tunnel with the given id, see
+    /// [`OnionContext::outgoing_tunnel_info`] and [`OnionContext::path_info`] for outgoing
+    /// tunnels.
+    ///
+    /// Returns `None` if there is no such incoming tunnel.
+    pub fn tunnel_info(&self, tunnel_id: TunnelId) -> Option<IncomingTunnelInfo> {
+        observer::debug_assert_not_observing();
+        self.registry.incoming(tunnel_id)
+    }
+
+    /// Returns the hops of the current path of the outgoing tunnel with the given id in path
+    /// order, together with the parameters negotiated with each of them.
+    ///
+    /// Returns `None` if there is no such tunnel or it is not ready yet.
+    pub fn path_info(&self, tunnel_id: TunnelId) -> Option<Vec<HopInfo>> {
+        observer::debug_assert_not_observing();
+        self.registry.path(tunnel_id)
+    }
+
+    /// Returns the state, path length and statistics of the outgoing tunnel with the given id, see
+    /// [`OnionContext::list_tunnels`].
+    ///
+    /// Returns `None` if there is no such tunnel or it has been closed.
+    pub fn outgoing_tunnel_info(&self, tunnel_id: TunnelId) -> Option<OutgoingTunnelInfo> {
+        observer::debug_assert_not_observing();
+        self.registry.outgoing_info(tunnel_id)
+    }
+
+    /// Returns the outgoing tunnels of this onion router, ordered by id.
+    ///
+    /// Lists every tunnel from the start of its build until its handler finished closing it,
+    /// including the tunnel carrying cover traffic. Failed builds are not listed.
+    pub fn list_tunnels(&self) -> Vec<OutgoingTunnelInfo> {
+        observer::debug_assert_not_observing();
+        self.registry.list_outgoing()
+    }
+
+    /// Sends an echo to the last hop of the current path of the outgoing tunnel with the given id
+    /// and returns the time it took to be answered.
+    ///
+    /// The measured time is also reported as [`TunnelStats::round_trip_time`]. A tunnel which is
+    /// still being built is pinged once it is ready. If the tunnel moves to another path before
+    /// the echo was answered, the new path is pinged instead. Since a path which does not answer
+    /// is only given up after several keep-alives, see
+    /// [`OnionBuilder::set_keep_alive_interval`], callers should wrap this in a timeout.
+    ///
+    /// Returns an error if there is no such tunnel or it is closed before the echo was answered.
+    pub async fn ping_tunnel(&self, tunnel_id: TunnelId) -> Result<Duration> {
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.registry
+            .send_request(tunnel_id, tunnel::HandlerRequest::Ping(answer_tx))?;
+        answer_rx
+            .await
+            .map_err(|_| anyhow!("Tunnel {} closed before the echo was answered", tunnel_id))
+    }
+
+    /// Moves the outgoing tunnel with the given id to new circuits through the same peers, so it
+    /// continues with fresh session keys for every hop, and returns once it has been moved.
+    ///
+    /// A tunnel gets fresh keys whenever it rotates to a new path, so this only matters for
+    /// tunnels kept on a path for long, see [`OnionBuilder::set_min_tunnel_lifetime`]. Use
+    /// [`OnionBuilder::set_rekey_interval`] to do it regularly. The tunnel switches to the new
+    /// circuits like at a rotation, data is paused while they are built. A tunnel which is still
+    /// being built is rekeyed once it is ready.
+    ///
+    /// Returns an error if there is no such tunnel, it is closed before it was moved, or the new
+    /// circuits could not be built, in which case the tunnel stays on its current path.
+    pub async fn rekey_tunnel(&self, tunnel_id: TunnelId) -> Result<()> {
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.registry
+            .send_request(tunnel_id, tunnel::HandlerRequest::Rekey(answer_tx))?;
+        answer_rx
+            .await
+            .map_err(|_| anyhow!("Tunnel {} closed before it was rekeyed", tunnel_id))?
+    }
+
+    /// Returns why automatic rebuilds of the outgoing tunnel with the given id are delayed, see
+    /// [`RetryBackoff`].
+    ///
+    /// Returns `None` if there is no such tunnel or its destination is not backed off. Tunnels to
+    /// random destinations or terminated at a relay are never backed off.
+    pub fn retry_backoff(&self, tunnel_id: TunnelId) -> Option<RetryBackoff> {
+        observer::debug_assert_not_observing();
+        self.registry.retry_backoff(tunnel_id)
+    }
+
     /// Builds a new tunnel to `dest`.
-    pub async fn build_tunnel(&self, dest: Peer) -> Result<Tunnel> {
-        self.build_tunnel_internal(Target::Peer(dest)).await
+    ///
+    /// Without a `dest`, the tunnel is terminated at its last relay instead, e.g. to measure the
+    /// latency of a path without a cooperating destination. Such a tunnel consists of the
+    /// configured number of hops, which must be at least one. The last relay drops padding and
+    /// answers the probes of the tunnel, but there is no application to receive data, so data
+    /// written to the tunnel is discarded. Building fails with [`TunnelBroken`] if the chosen relay
+    /// refuses the role, see [`OnionBuilder::enable_relay_termination`].
+    pub async fn build_tunnel(&self, dest: impl Into<Option<Peer>>) -> Result<Tunnel> {
+        self.build_tunnel_with_options(dest, Default::default())
+            .await
+    }
+
+    /// Builds a new tunnel to `dest` using the given [`TunnelOptions`], see
+    /// [`OnionContext::build_tunnel`].
+    pub async fn build_tunnel_with_options(
+        &self,
+        dest: impl Into<Option<Peer>>,
+        options: TunnelOptions,
+    ) -> Result<Tunnel> {
+        self.spawn_build(dest.into(), None, options).await
+    }
+
+    /// Builds a new tunnel to `dest` through exactly the peers of `path` in the given order,
+    /// instead of choosing its hops, see [`OnionContext::build_tunnel_with_options`].
+    ///
+    /// The path replaces the configured number of hops, an empty path builds a direct tunnel to
+    /// `dest`. Without a `dest`, the last peer of `path` terminates the tunnel. Rotations reuse
+    /// the same path. No peer of the path is substituted: building fails with
+    /// [`HopSelectionError::Unreachable`] at the first hop which could not be added, whose
+    /// position is the length of `path` for the destination. Like hops constrained by
+    /// [`TunnelOptions::set_hop`], the peers of the path are checked against the `options`.
+    pub async fn build_tunnel_with_path(
+        &self,
+        dest: impl Into<Option<Peer>>,
+        path: Vec<Peer>,
+        options: TunnelOptions,
+    ) -> Result<Tunnel> {
+        self.spawn_build(dest.into(), Some(path), options).await
+    }
+
+    /// Builds a new tunnel through `path` like [`OnionContext::build_tunnel_with_path`], blocking
+    /// the current thread until it is ready.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within an asynchronous execution context.
+    pub fn build_tunnel_with_path_blocking(
+        &self,
+        dest: impl Into<Option<Peer>>,
+        path: Vec<Peer>,
+        options: TunnelOptions,
+    ) -> Result<Tunnel> {
+        self.runtime
+            .block_on(self.build_tunnel_with_path(dest, path, options))
+    }
+
+    async fn spawn_build(
+        &self,
+        dest: Option<Peer>,
+        path: Option<Vec<Peer>>,
+        options: TunnelOptions,
+    ) -> Result<Tunnel> {
+        observer::debug_assert_not_observing();
+        let dest = match dest {
+            Some(peer) => Target::Peer(peer),
+            None => Target::Relay,
+        };
+        // the tasks of the tunnel are spawned by the build, so it has to run on our runtime
+        let ctx = self.clone();
+        let build = task::spawn_on(Some(&self.runtime), "task.build_tunnel", async move {
+            ctx.build_tunnel_internal(dest, path, options).await
+        });
+        match task::abort_on_drop(build).await {
+            Ok(Some(res)) => res,
+            Ok(None) => Err(anyhow!("Building the tunnel panicked")),
+            Err(e) => Err(anyhow!("Building the tunnel was cancelled: {}", e)),
+        }
+    }
+
+    /// Builds a new tunnel to `dest` like [`OnionContext::build_tunnel`], blocking the current
+    /// thread until it is ready.
+    ///
+    /// Intended for threads without a tokio runtime, the build runs on the runtime the onion
+    /// router was started on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within an asynchronous execution context.
+    pub fn build_tunnel_blocking(&self, dest: impl Into<Option<Peer>>) -> Result<Tunnel> {
+        self.build_tunnel_with_options_blocking(dest, Default::default())
     }
 
-    async fn build_tunnel_internal(&self, dest: Target) -> Result<Tunnel> {
+    /// Builds a new tunnel to `dest` using the given [`TunnelOptions`] like
+    /// [`OnionContext::build_tunnel_with_options`], blocking the current thread until it is ready.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within an asynchronous execution context.
+    pub fn build_tunnel_with_options_blocking(
+        &self,
+        dest: impl Into<Option<Peer>>,
+        options: TunnelOptions,
+    ) -> Result<Tunnel> {
+        self.runtime
+            .block_on(self.build_tunnel_with_options(dest, options))
+    }
+
+    /// Builds a new tunnel to `dest` using the given [`TunnelOptions`] like
+    /// [`OnionContext::build_tunnel_with_options`], unless a build to the same destination with
+    /// equal options is already in flight. Then the tunnel of that build is shared once it is
+    /// ready, see [`CoalescedTunnel`].
+    ///
+    /// Only calls of this method are coalesced, [`OnionContext::build_tunnel`] always builds a
+    /// tunnel of its own. Options setting a peer provider or a hop filter can not be compared, so
+    /// such builds are never coalesced. If the build in flight fails, a tunnel is built for this
+    /// call instead.
+    ///
+    /// A shared tunnel keeps a single id, which all of its [`Event`]s refer to. It is closed once
+    /// the [`Tunnel`] of the first caller and all writers are dropped.
+    pub async fn build_tunnel_coalesced(
+        &self,
+        dest: Peer,
+        options: TunnelOptions,
+    ) -> Result<CoalescedTunnel> {
+        observer::debug_assert_not_observing();
+        let pending = match BuildKey::new(&dest, &options) {
+            Some(key) => self.pending_builds.enter(key),
+            None => {
+                let tunnel = self.build_tunnel_with_options(dest, options).await?;
+                return Ok(CoalescedTunnel::Built(tunnel));
+            }
+        };
+        let build = match pending {
+            Pending::First(build) => Some(build),
+            Pending::Waiting(shared) => match shared.await {
+                Ok(writer) => {
+                    debug!("Sharing tunnel {} with a concurrent build", writer.id());
+                    return Ok(CoalescedTunnel::Shared(writer));
+                }
+                // the build in flight failed or was cancelled
+                Err(_) => None,
+            },
+        };
+        let tunnel = self.build_tunnel_with_options(dest, options).await;
+        if let Some(build) = build {
+            build.finish(tunnel.as_ref().ok().map(Tunnel::writer));
+        }
+        Ok(CoalescedTunnel::Built(tunnel?))
+    }
+
+    /// Builds or shares a tunnel to `dest` like [`OnionContext::build_tunnel_coalesced`],
+    /// blocking the current thread until it is ready.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within an asynchronous execution context.
+    pub fn build_tunnel_coalesced_blocking(
+        &self,
+        dest: Peer,
+        options: TunnelOptions,
+    ) -> Result<CoalescedTunnel> {
+        self.runtime
+            .block_on(self.build_tunnel_coalesced(dest, options))
+    }
+
+    async fn build_tunnel_internal(
+        &self,
+        dest: Target,
+        path: Option<Vec<Peer>>,
+        options: TunnelOptions,
+    ) -> Result<Tunnel> {
+        observer::debug_assert_not_observing();
+        // subscribe before entering, so the handler can not miss the shutdown event
+        let events = self.events.subscribe();
+        let running = self.shutdown.enter()?;
         info!("Building tunnel to {:?}", dest);
-        let tunnel_id = tunnel::random_id();
+        let tunnel_id = self
+            .registry
+            .allocate_id()
+            .ok_or_else(|| anyhow!("No free tunnel id"))?;
+        let padding_interval = options.padding_interval.unwrap_or(self.padding_interval);
+        let idle_timeout = options.idle_timeout.unwrap_or(self.idle_timeout);
+        let strict = options.strict.unwrap_or(self.strict);
+        let options = options.set_strict(strict);
         let mut builder =
-            TunnelBuilder::new(tunnel_id, dest, self.n_hops, self.peer_provider.clone());
+            TunnelBuilder::new(tunnel_id, dest, self.n_hops, self.peer_provider.clone())
+                .with_options(options, self.capabilities.clone(), self.known_peers.clone())
+                .with_build_reports(self.build_reports)
+                .with_observer(self.observer.clone())
+                .with_suspects(self.suspects.clone())
+                .with_guards(self.guards.clone())
+                .with_handshake_limiter(self.handshakes.clone())
+                .with_rng(self.rng.clone())
+                .with_connection_cache(self.connections.clone())
+                .with_socket_timeouts(self.socket_timeouts)
+                .with_timeouts(self.build_timeouts)
+                .with_retries(self.registry.retries.clone());
+        if let Some(path) = path {
+            builder = builder.with_explicit_path(path);
+        }
+        if strict && padding_interval > Duration::ZERO && !builder.dest_supports_padding() {
+            self.registry.release(tunnel_id);
+            return Err(StrictViolation {
+                which: Fallback::NoPadding,
+            }
+            .into());
+        }
+        let tunnel = match builder.build().await {
+            Ok(tunnel) => tunnel,
+            Err(e) => {
+                self.registry.release(tunnel_id);
+                return Err(e);
+            }
+        };
 
+        // the handler releases the id once it has finished
         let (ready_tx, ready_rx) = oneshot::channel();
-        let mut handler = TunnelHandler::new(
-            builder.build().await?,
+        let handler = TunnelHandler::new(
+            tunnel,
             builder,
-            self.events.subscribe(),
+            events,
             ready_tx,
-        );
+            self.rotation_policy,
+            self.stall_threshold,
+            self.notify.clone(),
+        )
+        .with_shutdown(running)
+        .with_padding(padding_interval)
+        .with_idle_timeout(idle_timeout)
+        .with_diagnosis(self.diagnosis_budget)
+        .with_max_batch_size(self.max_batch_size)
+        .with_registry(self.registry.clone());
+
+        handler.spawn();
+        // the handler drops the sender without an answer only if it ended unexpectedly
+        ready_rx.await.map_err(|_| TunnelBroken)?
+    }
+
+    /// Send cover data with a fake payload of the given size.
+    pub fn send_cover(&self, size: u16) -> Result<()> {
+        observer::debug_assert_not_observing();
+        self.shutdown.check()?;
+        self.cover_tunnel
+            .write(vec![0u8; size as usize].into())
+            .map_err(|_| anyhow!("Cover traffic is disabled"))
+    }
+
+    /// Shuts down the onion router.
+    ///
+    /// First, building tunnels and sending cover traffic fail with [`ShuttingDown`] from then on.
+    /// Then all tunnels built by this onion router are closed, which is reported by an
+    /// [`Event::Closed`] with [`CloseReason::Shutdown`] each. Tunnels which are not closed within
+    /// the timeout set by [`OnionBuilder::set_shutdown_timeout`] are given up, i.e. their handlers
+    /// are stopped. Only then the [`OnionEvents`] streams end and no more incoming connections are
+    /// accepted.
+    ///
+    /// Events are never waited for, so subscribers which stopped reading do not hold up the
+    /// shutdown. Instead, they miss the events which do not fit into the buffer of their stream,
+    /// which are counted in the returned [`ShutdownReport`].
+    ///
+    /// Calling this method again waits for the remaining steps of the shutdown.
+    pub async fn shutdown(&self) -> ShutdownReport {
+        observer::debug_assert_not_observing();
+        // the timeout needs a timer, which the runtime of the caller might lack
+        let ctx = self.clone();
+        let _ = task::spawn_on(Some(&self.runtime), "task.shutdown", async move {
+            ctx.shutdown_internal().await
+        })
+        .await;
+        ShutdownReport {
+            dropped_events: self.event_tally.dropped(),
+        }
+    }
 
-        tokio::spawn(async move {
-            handler.handle().await;
-        });
-        ready_rx.await?
+    /// Shuts down the onion router like [`OnionContext::shutdown`], blocking the current thread
+    /// until the shutdown is complete.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within an asynchronous execution context.
+    pub fn shutdown_blocking(&self) -> ShutdownReport {
+        self.runtime.block_on(self.shutdown())
     }
 
-    /// Send cover data with a fake payload of the given size.
-    pub fn send_cover(&self, size: u16) -> Result<()> {
-        let packet_count = (size as usize + protocol::MAX_DATA_SIZE - 1) / protocol::MAX_DATA_SIZE;
-        for _ in 0..packet_count {
-            self.cover_tunnel
-                .write(Bytes::new())
-                .map_err(|_| anyhow!("Cover traffic is disabled"))?;
+    async fn shutdown_internal(&self) {
+        info!("Shutting down onion router");
+        if !self.shutdown.is_closing() {
+            self.event_tally.close();
         }
-        Ok(())
+        self.shutdown.close();
+        let _ = self.events.send(tunnel::Event::Shutdown);
+        if time::timeout(self.shutdown_timeout, self.shutdown.wait_idle())
+            .await
+            .is_err()
+        {
+            warn!("Giving up on tunnels which did not close in time");
+        }
+        self.shutdown.finish();
+    }
+}
+
+/// Emits an [`Event::PeerProviderClosed`] whenever the stream of `peer_provider` ends.
+async fn watch_peer_provider(peer_provider: PeerProvider, notify: broadcast::Sender<Event>) {
+    loop {
+        let generation = peer_provider.closed().await;
+        warn!("Peer provider closed");
+        let _ = notify.send(Event::PeerProviderClosed);
+        peer_provider.replaced(generation).await;
     }
 }
 
@@ -274,13 +1484,14 @@ impl CoverHandler {
                         self.update_tunnel().await;
                     }
                 }
+                _ = self.ctx.shutdown.finished() => break,
                 Some(data) = self.cover_rx.recv() => {
                     // FIXME errors in case cover_tunnel is None or write fails are not propagated
                     // to send_cover.
                     // Potential fix: store Arc<Mutex<Option<OnionTunnel>>> in OnionContext which
                     // is updated by update_tunnel.
                     if let Some(tunnel) = &self.cover_tunnel {
-                        tunnel.write(data);
+                        let _ = tunnel.write(data);
                     }
                 }
                 else => break,
@@ -295,7 +1506,7 @@ impl CoverHandler {
         ) {
             (None, 0) => self
                 .ctx
-                .build_tunnel_internal(tunnel::Target::Random)
+                .build_tunnel_internal(tunnel::Target::Random, None, Default::default())
                 .await
                 .ok(),
             (None, _) => None,
@@ -316,27 +1527,106 @@ impl OnionIncoming {
     pub async fn next(&mut self) -> Option<Tunnel> {
         self.incoming.recv().await
     }
+
+    /// Returns the next incoming [`Tunnel`] like [`OnionIncoming::next`], blocking the current
+    /// thread until a new incoming connection is made.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within an asynchronous execution context.
+    pub fn next_blocking(&mut self) -> Option<Tunnel> {
+        self.incoming.blocking_recv()
+    }
 }
 
 #[derive(Clone)]
 struct OnionListener {
-    hostkey: Arc<RsaPrivateKey>,
+    hostkey: Arc<HostKey>,
     incoming: mpsc::Sender<Tunnel>,
-    tunnels: Arc<Mutex<HashMap<TunnelId, mpsc::Sender<Tunnel>>>>,
+    endpoints: Endpoints,
+    backlog: HandshakeBacklog,
+    cipher_suites: CipherSuites,
+    observer: Observer,
+    connections: Option<ConnectionCache>,
+    relay_termination: bool,
+    latency_histogram: bool,
+    extend_policy: Arc<ExtendPolicy>,
+    rng: RngFactory,
+    socket_timeouts: SocketTimeouts,
+    max_batch_size: usize,
+    #[cfg(feature = "research")]
+    inspector: Option<Arc<dyn CellInspector>>,
 }
 
 impl OnionListener {
-    fn new(hostkey: RsaPrivateKey, incoming: mpsc::Sender<Tunnel>) -> Self {
+    fn new(
+        hostkey: HostKey,
+        incoming: mpsc::Sender<Tunnel>,
+        registry: TunnelRegistry,
+        backlog: HandshakeBacklog,
+        cipher_suites: CipherSuites,
+    ) -> Self {
         OnionListener {
             hostkey: Arc::new(hostkey),
             incoming,
-            tunnels: Default::default(),
+            endpoints: Endpoints::new(registry),
+            backlog,
+            cipher_suites,
+            observer: Default::default(),
+            connections: None,
+            relay_termination: true,
+            latency_histogram: true,
+            extend_policy: Default::default(),
+            rng: Default::default(),
+            socket_timeouts: Default::default(),
+            max_batch_size: socket::MAX_BATCH_SIZE,
+            #[cfg(feature = "research")]
+            inspector: None,
         }
     }
 
-    async fn listen_addr(&mut self, addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(addr).await?;
-        self.listen(listener).await
+    fn with_observer(mut self, observer: Observer) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    fn with_relay_termination(mut self, enable: bool) -> Self {
+        self.relay_termination = enable;
+        self
+    }
+
+    fn with_latency_histogram(mut self, enable: bool) -> Self {
+        self.latency_histogram = enable;
+        self
+    }
+
+    fn with_extend_policy(mut self, policy: ExtendPolicy) -> Self {
+        self.extend_policy = Arc::new(policy);
+        self
+    }
+
+    fn with_connection_cache(mut self, connections: Option<ConnectionCache>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    fn with_rng(mut self, rng: RngFactory) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    fn with_socket_timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.socket_timeouts = timeouts;
+        self
+    }
+
+    fn with_max_batch_size(mut self, n: usize) -> Self {
+        self.max_batch_size = n;
+        self
+    }
+
+    async fn listen_std(&mut self, listener: std::net::TcpListener) -> Result<()> {
+        self.listen(TcpListener::from_std(listener)?).await
     }
 
     async fn listen(&mut self, listener: TcpListener) -> Result<()> {
@@ -347,30 +1637,120 @@ impl OnionListener {
 
         loop {
             let (stream, peer_addr) = listener.accept().await?;
+            if !self.backlog.try_admit() {
+                debug!("Rejected connection from {:?}", peer_addr);
+                continue;
+            }
             info!("Accepted connection from {:?}", peer_addr);
             let mut handler = self.clone();
-            tokio::spawn(async move {
-                handler.handle_connection(stream).await;
-            });
+            task::spawn_with(
+                "task.connection",
+                format!("connection from {}", peer_addr),
+                async move {
+                    handler.handle_connection(stream, peer_addr).await;
+                },
+                |_| (),
+            );
         }
     }
 
-    async fn handle_connection(&mut self, stream: TcpStream) {
-        let socket = OnionSocket::new(stream);
-        let (incoming_tx, mut incoming_rx) = mpsc::channel(1); // maybe convert to oneshot
-        let mut handler = match CircuitHandler::init(socket, &*self.hostkey, incoming_tx).await {
-            Ok(handler) => handler,
+    async fn handle_connection(&mut self, stream: TcpStream, peer_addr: SocketAddr) {
+        let accept = connection::accept(
+            stream,
+            peer_addr,
+            circuit::IDLE_TIMEOUT,
+            self.socket_timeouts,
+        );
+        let mut circuits = match accept.await {
+            Ok(Accepted::Dedicated(stream)) => {
+                return self.handle_circuit(stream.into(), peer_addr).await;
+            }
+            Ok(Accepted::Shared(circuits)) => circuits,
             Err(e) => {
-                warn!("{}", e);
+                self.backlog.finish();
+                warn!("Incoming handshake failed: {}", e);
                 return;
             }
         };
 
-        tokio::spawn(async move {
-            if let Err(e) = handler.handle().await {
+        // the connection has been admitted for its first circuit, further circuits are admitted
+        // on their own
+        let mut admitted = true;
+        while let Some(stream) = circuits.recv().await {
+            if !mem::take(&mut admitted) && !self.backlog.try_admit() {
+                debug!("Rejected circuit from {:?}", peer_addr);
+                self.reject_circuit(stream).await;
+                continue;
+            }
+            let mut handler = self.clone();
+            task::spawn("task.shared_circuit", async move {
+                handler
+                    .handle_circuit(CircuitStream::Shared(stream), peer_addr)
+                    .await
+            });
+        }
+        if admitted {
+            self.backlog.finish();
+        }
+    }
+
+    /// Tears down a circuit on a shared connection instead of performing its handshake.
+    async fn reject_circuit(&self, stream: SharedStream) {
+        let circuit_id = stream.circuit_id();
+        let mut socket = OnionSocket::from_stream(CircuitStream::Shared(stream))
+            .with_timeouts(self.socket_timeouts);
+        let _ = socket.teardown(circuit_id, EndReason::ResourceLimit).await;
+    }
+
+    /// Performs the handshake of an admitted circuit and spawns its handler.
+    async fn handle_circuit(&mut self, stream: CircuitStream, peer_addr: SocketAddr) {
+        let socket = OnionSocket::from_stream(stream).with_timeouts(self.socket_timeouts);
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(1); // maybe convert to oneshot
+        let init = CircuitHandler::init(socket, &self.hostkey, self.cipher_suites, incoming_tx);
+        let handler = time::timeout(HANDSHAKE_TIMEOUT, init).await;
+        self.backlog.finish();
+        let mut handler = match handler {
+            Ok(Ok(handler)) => handler,
+            Ok(Err(e)) => {
                 warn!("{}", e);
+                return;
             }
-        });
+            Err(_) => {
+                warn!("Incoming handshake timed out");
+                return;
+            }
+        };
+        self.observer.circuit_accepted(peer_addr);
+        let info = InboundCircuitInfo {
+            peer_addr,
+            connection_id: handler.connection().id,
+            params: handler.params(),
+        };
+        let inbound = InboundCircuit::new(info, self.backlog.counters.clone());
+        handler.set_connection_cache(self.connections.clone());
+        handler.set_rng_factory(self.rng.clone());
+        handler.set_relay_termination(self.relay_termination);
+        handler.set_max_batch_size(self.max_batch_size);
+        handler.set_latency_counters(
+            self.latency_histogram
+                .then(|| self.backlog.counters.clone()),
+        );
+        handler.set_extend_policy(self.extend_policy.clone(), self.backlog.counters.clone());
+        #[cfg(feature = "research")]
+        handler.set_inspector(self.inspector.clone());
+
+        let context = format!("circuit {}", handler.circuit_id());
+        task::spawn_with(
+            "task.circuit_handler",
+            context,
+            async move {
+                let _inbound = inbound;
+                if let Err(e) = handler.handle().await {
+                    warn!("{}", e);
+                }
+            },
+            |_| (),
+        );
 
         if let Some(tunnel) = incoming_rx.recv().await {
             self.handle_incoming(tunnel).await;
@@ -378,42 +1758,50 @@ impl OnionListener {
     }
 
     async fn handle_incoming(&mut self, tunnel: Tunnel) {
-        let tunnels = self.tunnels.clone();
-        let mut tunnels = tunnels.lock().await;
-
-        match tunnels.entry(tunnel.id()) {
-            hash_map::Entry::Occupied(mut e) => {
-                if let Err(t) = e.get_mut().send(tunnel).await {
-                    if let Ok(tunnel_tx) = self.handle_new_tunnel(t.0).await {
-                        e.insert(tunnel_tx);
-                    }
-                }
-            }
-            hash_map::Entry::Vacant(e) => {
-                if let Ok(tunnel_tx) = self.handle_new_tunnel(tunnel).await {
-                    e.insert(tunnel_tx);
-                }
-            }
+        if let Some((path, paths)) = self.endpoints.bind(tunnel) {
+            self.spawn_incoming_tunnel(path, paths);
         }
     }
 
-    async fn handle_new_tunnel(&mut self, tunnel: Tunnel) -> Result<mpsc::Sender<Tunnel>> {
-        let (tunnel_tx, tunnel_rx) = mpsc::channel(1);
-        let (e_tunnel, e_data_tx, e_data_rx) = Tunnel::new(tunnel.id(), true);
-        self.incoming.send(e_tunnel).await?;
-
-        tokio::spawn({
-            let tunnels = self.tunnels.clone();
+    /// Spawns the task serving a new incoming tunnel, which was begun on `path`.
+    fn spawn_incoming_tunnel(&self, path: Tunnel, mut paths: mpsc::UnboundedReceiver<Tunnel>) {
+        task::spawn("task.incoming_tunnel", {
+            let incoming = self.incoming.clone();
+            let endpoints = self.endpoints.clone();
+            let counters = self.backlog.counters.clone();
+            let notify = self.backlog.notify.clone();
             async move {
-                let tunnel_id = tunnel.id();
-                debug!("Handling incoming tunnel {}", tunnel_id);
-                let _ = tunnel.forward_data(tunnel_rx, e_data_tx, e_data_rx).await;
-                tunnels.lock().await.remove(&tunnel_id);
-                debug!("Finished handling incoming tunnel {}", tunnel_id);
+                let tunnel_id = path.id();
+                let mut next = Some(path);
+                while let Some(path) = next {
+                    debug!("Handling incoming tunnel {}", tunnel_id);
+                    let (mut e_tunnel, e_data_tx, e_data_rx) =
+                        Tunnel::new(tunnel_id, true, path.cell_size);
+                    e_tunnel.incoming_info = path.incoming_info.clone();
+                    let stats = e_tunnel.stats.clone();
+                    if incoming.send(e_tunnel).await.is_ok() {
+                        let forward = endpoint::forward(
+                            path,
+                            &mut paths,
+                            e_data_tx,
+                            e_data_rx,
+                            stats.clone(),
+                            counters.clone(),
+                        );
+                        if let Err(panic) = task::catch_panic(forward).await {
+                            error!(
+                                "Forwarding of incoming tunnel {} panicked: {}",
+                                tunnel_id, panic
+                            );
+                            tunnel::report_panic(tunnel_id, &stats, &notify, &panic);
+                        }
+                    }
+                    // a path begun after the tunnel was destroyed starts a new tunnel
+                    next = endpoints.unbind(tunnel_id, &mut paths);
+                    debug!("Finished handling incoming tunnel {}", tunnel_id);
+                }
             }
         });
-
-        Ok(tunnel_tx)
     }
 }
 
@@ -426,14 +1814,15 @@ impl OnionListener {
 struct RoundHandler {
     events: broadcast::Sender<tunnel::Event>,
     round_duration: Duration,
+    keep_alive_interval: Duration,
+    shutdown: Arc<Shutdown>,
 }
 
 impl RoundHandler {
     async fn handle(&mut self) {
         info!("Starting RoundHandler");
         let mut round_timer = time::interval(self.round_duration);
-        let keep_alive_interval = circuit::IDLE_TIMEOUT / 3 * 2;
-        let mut keep_alive_timer = time::interval(keep_alive_interval);
+        let mut keep_alive_timer = time::interval(self.keep_alive_interval);
         loop {
             tokio::select! {
                 _ = round_timer.tick() => {
@@ -443,6 +1832,7 @@ impl RoundHandler {
                 _ = keep_alive_timer.tick() => {
                     let _ = self.events.send(tunnel::Event::KeepAlive);
                 }
+                _ = self.shutdown.finished() => break,
             }
         }
     }
@@ -450,30 +1840,112 @@ impl RoundHandler {
 
 /// Used for configuring and starting new onion router instances.
 pub struct OnionBuilder {
-    listen_addr: SocketAddr,
-    hostkey: RsaPrivateKey,
+    /// `None` in client-only mode
+    listen: Option<(SocketAddr, HostKey)>,
     peer_provider: PeerProvider,
     enable_cover: bool,
     n_hops: usize,
     round_duration: Duration,
+    min_tunnel_lifetime: Duration,
+    stall_threshold: Duration,
+    max_pending_handshakes: usize,
+    max_handshakes_per_peer: usize,
+    relay_connection_idle_timeout: Duration,
+    first_hop_connection_idle_timeout: Duration,
+    socket_timeouts: SocketTimeouts,
+    max_batch_size: usize,
+    cipher_suites: CipherSuites,
+    build_reports: bool,
+    padding_interval: Duration,
+    tunnel_idle_timeout: Duration,
+    build_timeouts: BuildTimeouts,
+    diagnosis_budget: Duration,
+    keep_alive_interval: Duration,
+    strict: bool,
+    relay_termination: bool,
+    latency_histogram: bool,
+    extend_policy: ExtendPolicy,
+    relay_runtime: Option<Handle>,
+    observer: Observer,
+    rotation_strategy: RotationStrategy,
+    key_limits: KeyLimits,
+    rekey_interval: Duration,
+    shutdown_timeout: Duration,
+    state: NodeState,
+    entry_guards: usize,
+    imported_guards: Vec<Peer>,
+    rng: RngFactory,
+    #[cfg(feature = "research")]
+    inspector: Option<Arc<dyn CellInspector>>,
 }
 
 impl OnionBuilder {
     /// Initialized the construction of a new onion router instance.
     ///
+    /// Peers connect to `listen_addr`, which may be an IPv4 or IPv6 address. The unspecified IPv6
+    /// address, e.g. `[::]:4200`, accepts connections over both address families. The host key
+    /// may be a [`RsaPrivateKey`](crate::RsaPrivateKey), an
+    /// [`Ed25519PrivateKey`](crate::Ed25519PrivateKey) or a [`HostKey`] holding either.
+    ///
     /// Returns a builder which allows further configuration.
     pub fn new(
         listen_addr: SocketAddr,
-        hostkey: RsaPrivateKey,
+        hostkey: impl Into<HostKey>,
         peer_provider: PeerProvider,
     ) -> OnionBuilder {
+        let mut builder = OnionBuilder::client_only(peer_provider);
+        builder.listen = Some((listen_addr, hostkey.into()));
+        builder
+    }
+
+    /// Initializes the construction of an onion router which only builds tunnels, but does not
+    /// listen for connections from other peers.
+    ///
+    /// No socket is bound, so the onion router neither relays circuits of other peers nor accepts
+    /// incoming tunnels, and the [`OnionIncoming`] stream ends immediately. Building, using and
+    /// rotating tunnels as well as cover traffic work as usual, since every connection is opened
+    /// by this peer. Methods which only make sense for a relay fail with [`NotARelay`], and the
+    /// settings concerning relaying have no effect.
+    ///
+    /// Returns a builder which allows further configuration.
+    pub fn client_only(peer_provider: PeerProvider) -> OnionBuilder {
         OnionBuilder {
-            listen_addr,
-            hostkey,
+            listen: None,
             peer_provider,
             enable_cover: true,
             n_hops: DEFAULT_HOPS,
             round_duration: DEFAULT_ROUND_DURATION,
+            min_tunnel_lifetime: DEFAULT_MIN_TUNNEL_LIFETIME,
+            stall_threshold: DEFAULT_STALL_THRESHOLD,
+            max_pending_handshakes: DEFAULT_MAX_PENDING_HANDSHAKES,
+            max_handshakes_per_peer: handshakes::DEFAULT_MAX_HANDSHAKES_PER_PEER,
+            relay_connection_idle_timeout: DEFAULT_RELAY_CONNECTION_IDLE_TIMEOUT,
+            first_hop_connection_idle_timeout: Duration::ZERO,
+            socket_timeouts: Default::default(),
+            max_batch_size: socket::MAX_BATCH_SIZE,
+            cipher_suites: CipherSuites::all(),
+            build_reports: false,
+            padding_interval: Duration::ZERO,
+            tunnel_idle_timeout: Duration::ZERO,
+            build_timeouts: Default::default(),
+            diagnosis_budget: DEFAULT_DIAGNOSIS_BUDGET,
+            keep_alive_interval: DEFAULT_KEEP_ALIVE_INTERVAL,
+            strict: false,
+            relay_termination: true,
+            latency_histogram: true,
+            extend_policy: Default::default(),
+            relay_runtime: None,
+            observer: Default::default(),
+            rotation_strategy: Default::default(),
+            key_limits: Default::default(),
+            rekey_interval: Duration::ZERO,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            state: Default::default(),
+            entry_guards: 0,
+            imported_guards: Vec::new(),
+            rng: Default::default(),
+            #[cfg(feature = "research")]
+            inspector: None,
         }
     }
 
@@ -486,8 +1958,168 @@ impl OnionBuilder {
         self
     }
 
+    /// Sets whether a [`BuildReport`] is recorded for every build of a tunnel path.
+    ///
+    /// Reports contain a prefix of the fingerprint of every peer tried, so they are not recorded
+    /// by default.
+    pub fn enable_build_reports(mut self, enable: bool) -> Self {
+        self.build_reports = enable;
+        self
+    }
+
+    /// Sets the interval in which padding is sent on a tunnel while the application sends no
+    /// data.
+    ///
+    /// Padding messages are indistinguishable from data for the relays and dropped by the
+    /// destination. Unlike the cover tunnel of [`OnionBuilder::enable_cover_traffic`], they do not
+    /// reveal themselves by a tunnel of their own, and keep each tunnel carrying at least one cell
+    /// per interval. They are only sent to destinations known to support
+    /// [`Capabilities::PADDING`](crate::Capabilities::PADDING), use
+    /// [`TunnelOptions::set_padding_interval`] to choose the interval per tunnel.
+    ///
+    /// The default value is zero, which disables padding.
+    pub fn set_padding_interval(mut self, dur: Duration) -> Self {
+        self.padding_interval = dur;
+        self
+    }
+
+    /// Sets the time after which a tunnel is closed if neither the application nor the
+    /// destination sent data over it, with [`CloseReason::IdleTimeout`].
+    ///
+    /// This keeps tunnels the application forgot to close from holding their paths forever.
+    /// Keep-alives and padding do not count as data. Use [`TunnelOptions::set_idle_timeout`] to
+    /// choose the timeout per tunnel.
+    ///
+    /// The default value is zero, which keeps idle tunnels open.
+    pub fn set_tunnel_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.tunnel_idle_timeout = timeout;
+        self
+    }
+
+    /// Sets how long building a tunnel path may take, for each hop and in total, see
+    /// [`BuildTimeouts`].
+    ///
+    /// Without these, a single unresponsive peer could hold up a build for as long as the
+    /// operating system takes to give up on the connection. The hop timeout must be positive and
+    /// must not exceed the deadline. Rebuilds during rotation are subject to the same timeouts.
+    pub fn set_build_timeouts(mut self, timeouts: BuildTimeouts) -> Self {
+        self.build_timeouts = timeouts;
+        self
+    }
+
+    /// Sets how long the reads and writes on the connections to other peers may take, see
+    /// [`SocketTimeouts`].
+    ///
+    /// A peer which accepts the connection, but does not answer a handshake or stops reading,
+    /// fails the operation once the timeout elapsed, so the hop is replaced by another peer. Both
+    /// timeouts must be positive. Reading data from a tunnel waits without a timeout, see
+    /// [`OnionBuilder::set_tunnel_idle_timeout`] instead.
+    ///
+    /// Connections shared by several circuits apply the same timeouts, a write timing out closes
+    /// the connection along with all of its circuits.
+    pub fn set_socket_timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.socket_timeouts = timeouts;
+        self
+    }
+
+    /// Sets the maximum number of data messages which are coalesced into a single write.
+    ///
+    /// Data written to a tunnel faster than it can be sent is queued, and up to `n` of the queued
+    /// messages are encrypted into one buffer and written at once. A value of 1 writes every
+    /// message on its own. The value must be between 1 and 32, which is the default.
+    pub fn set_max_batch_size(mut self, n: usize) -> Self {
+        self.max_batch_size = n;
+        self
+    }
+
+    /// Sets the time for which a failed tunnel path may be probed to find the hop which caused
+    /// the failure, see [`Event::HopSuspected`].
+    ///
+    /// The hops are probed one after another before the path is replaced, so this delays the
+    /// recovery of the tunnel by at most the given time. Suspected peers are avoided as random
+    /// hops for the following 10 minutes, as long as other peers are available.
+    ///
+    /// The default value is 2 seconds. Zero disables the attribution of failures.
+    pub fn set_diagnosis_budget(mut self, budget: Duration) -> Self {
+        self.diagnosis_budget = budget;
+        self
+    }
+
+    /// Sets the interval in which keep-alives are sent on every tunnel.
+    ///
+    /// Keep-alives keep idle paths from being closed by the relays. On ready tunnels, they are
+    /// echoes answered by the last hop, which measure [`TunnelStats::round_trip_time`]. A path
+    /// whose last hop missed 3 echoes in a row is replaced as if its connection had been lost, or
+    /// closed with [`CloseReason::Unresponsive`] if no replacement can be built. The interval has
+    /// to be shorter than the two minutes after which relays close idle circuits.
+    ///
+    /// The default value is 80 seconds.
+    pub fn set_keep_alive_interval(mut self, dur: Duration) -> Self {
+        self.keep_alive_interval = dur;
+        self
+    }
+
+    /// Sets whether tunnels are built in strict mode, which never trades anonymity for
+    /// availability. Use [`TunnelOptions::set_strict`] to choose the mode per tunnel.
+    ///
+    /// In strict mode, building or rotating a tunnel fails with a [`StrictViolation`] instead of
+    /// using one of the [`Fallback`]s:
+    /// - a random hop is never a peer which is already part of the path, including the
+    ///   destination and hops set with [`TunnelOptions::set_hop`]. Peers are told apart by their
+    ///   address. Outside of strict mode, a random hop is still never the destination or the
+    ///   peer of the previous hop.
+    /// - a tunnel with a padding interval is not built to a destination which is not known to
+    ///   support [`Capabilities::PADDING`](crate::Capabilities::PADDING).
+    /// - with entry guards, the first hop is never a peer other than a guard, see
+    ///   [`OnionBuilder::set_entry_guards`].
+    ///
+    /// The default value is false.
+    pub fn enable_strict_mode(mut self, enable: bool) -> Self {
+        self.strict = enable;
+        self
+    }
+
+    /// Sets whether other peers may terminate their tunnels at this onion router, see
+    /// [`OnionContext::build_tunnel`]. Such tunnels carry no data, but keep this onion router
+    /// busy without a destination application taking part.
+    ///
+    /// The default value is true.
+    pub fn enable_relay_termination(mut self, enable: bool) -> Self {
+        self.relay_termination = enable;
+        self
+    }
+
+    /// Sets whether the time relayed cells spend in this onion router is recorded in
+    /// [`RelayStats::forwarding_latency`].
+    ///
+    /// Recording takes a clock reading and an atomic increment per relayed cell.
+    ///
+    /// The default value is true.
+    pub fn enable_latency_histogram(mut self, enable: bool) -> Self {
+        self.latency_histogram = enable;
+        self
+    }
+
+    /// Sets the addresses this onion router connects to when other peers extend their tunnels
+    /// through it. Refused requests are answered with an error, which the initiator records as
+    /// [`BuildOutcome::PolicyRefused`] before trying another peer, and counted in
+    /// [`RelayStats::refused_extends`].
+    ///
+    /// The policy does not apply to the tunnels built by this onion router.
+    ///
+    /// The default policy denies private addresses, see [`ExtendPolicy`].
+    pub fn set_extend_policy(mut self, policy: ExtendPolicy) -> Self {
+        self.extend_policy = policy;
+        self
+    }
+
     /// Sets the number of additional hops per tunnel, not counting the two endpoints.
     ///
+    /// With zero hops, tunnels are direct connections to their destination, which learns the
+    /// address of this onion router. Direct tunnels are meant for development and testing.
+    /// They are rotated like any other tunnel, by connecting to the destination again, and
+    /// [`TunnelOptions::set_hop`] constraints make building them fail.
+    ///
     /// The default value is 2.
     pub fn set_hops_per_tunnel(mut self, n_hops: usize) -> Self {
         self.n_hops = n_hops;
@@ -502,37 +2134,449 @@ impl OnionBuilder {
         self
     }
 
+    /// Sets the minimum amount of time a tunnel is used before it may be replaced.
+    ///
+    /// Switchovers scheduled before a tunnel reached this age are deferred until it does.
+    /// This protects against tunnels being rebuilt back-to-back, which costs handshakes and risks
+    /// data loss.
+    ///
+    /// The default value is 2 seconds.
+    pub fn set_min_tunnel_lifetime(mut self, dur: Duration) -> Self {
+        self.min_tunnel_lifetime = dur;
+        self
+    }
+
+    /// Sets how the paths of tunnels are replaced at the end of a round.
+    ///
+    /// The default is [`RotationStrategy::Rebuild`].
+    pub fn set_rotation_strategy(mut self, strategy: RotationStrategy) -> Self {
+        self.rotation_strategy = strategy;
+        self
+    }
+
+    /// Sets how many cells the session key of a hop may process in one direction before the
+    /// tunnel is moved to a path with fresh keys, and how many before the tunnel is closed.
+    ///
+    /// Once a key processed `rotate_after` cells, the tunnel is rotated right away instead of at
+    /// the end of the round; a spliced tunnel is moved to an entirely new path. If no new path can
+    /// be built, the tunnel is closed with [`CloseReason::KeysExhausted`] once a key processed
+    /// `ceiling` cells, so a key is used for at most one batch of cells beyond it. Both limits
+    /// are only reached by tunnels carrying a lot of data within a single round. The default
+    /// values are 2^24 and 2^28 cells.
+    pub fn set_key_usage_limits(mut self, rotate_after: u64, ceiling: u64) -> Self {
+        self.key_limits = KeyLimits {
+            rotate_after,
+            ceiling,
+        };
+        self
+    }
+
+    /// Sets the age after which the session keys of a tunnel are replaced, by moving it to new
+    /// circuits through the same peers like [`OnionContext::rekey_tunnel`] does.
+    ///
+    /// The age of a path is that of its oldest key, the first hops of a spliced path keep theirs.
+    /// Tunnels rotating to a new path every round get fresh keys anyway, so this only matters for
+    /// a minimum tunnel lifetime longer than the interval, see
+    /// [`OnionBuilder::set_min_tunnel_lifetime`]. If the new circuits can not be built, the path
+    /// is kept until the next rotation.
+    ///
+    /// The default value is zero, which disables rekeying.
+    pub fn set_rekey_interval(mut self, dur: Duration) -> Self {
+        self.rekey_interval = dur;
+        self
+    }
+
+    /// Sets the amount of time queued data may go unsent before an [`Event::Stalled`] is emitted.
+    ///
+    /// The default value is 10 seconds.
+    pub fn set_stall_threshold(mut self, dur: Duration) -> Self {
+        self.stall_threshold = dur;
+        self
+    }
+
+    /// Sets how long [`OnionContext::shutdown`] waits for the tunnels to close.
+    ///
+    /// The handlers of tunnels which are not closed by then are stopped, which reports the tunnels
+    /// as closed without tearing down their paths. The default value is 10 seconds.
+    pub fn set_shutdown_timeout(mut self, dur: Duration) -> Self {
+        self.shutdown_timeout = dur;
+        self
+    }
+
+    /// Sets the maximum number of incoming connections performing the circuit handshake at the
+    /// same time.
+    ///
+    /// Further connections are closed immediately, see [`Event::HandshakeBacklogFull`].
+    /// The default value is 128.
+    pub fn set_max_pending_handshakes(mut self, n: usize) -> Self {
+        self.max_pending_handshakes = n;
+        self
+    }
+
+    /// Sets the maximum number of circuit handshakes this onion router initiates with the same
+    /// peer at the same time while building tunnels.
+    ///
+    /// Further handshakes with the peer wait until one of those in progress finished, so a burst
+    /// of builds through a popular hop is not rate-limited by it. The counts are reported by
+    /// [`OnionContext::outgoing_handshakes`]. The default value is 4.
+    pub fn set_max_handshakes_per_peer(mut self, n: usize) -> Self {
+        self.max_handshakes_per_peer = n;
+        self
+    }
+
+    /// Sets the amount of time a connection to a next hop is kept open without carrying any
+    /// circuit.
+    ///
+    /// All circuits this onion router relays to the same peer share a single connection, which is
+    /// closed once it has been idle for this long. The timeout has to be shorter than the two
+    /// minutes after which peers close idle connections on their side. Zero disables sharing, so
+    /// every relayed circuit uses a connection of its own.
+    ///
+    /// The default value is 60 seconds.
+    pub fn set_relay_connection_idle_timeout(mut self, dur: Duration) -> Self {
+        self.relay_connection_idle_timeout = dur;
+        self
+    }
+
+    /// Enables sharing the connections to first hops, which are kept open for `dur` without
+    /// carrying any circuit.
+    ///
+    /// The first circuits of all tunnels built by this onion router through the same peer then
+    /// share a single connection, instead of opening one connection per tunnel, unless the tunnel
+    /// uses a non-default cell size. Every circuit only gets a share of the buffers of the
+    /// connection, so a tunnel whose application reads slower than data arrives loses its path
+    /// once its share overflowed, instead of slowing down the sender. The timeout has to be
+    /// shorter than the two minutes after which peers close idle connections on their side.
+    ///
+    /// The default value is zero, which disables sharing, so every tunnel uses a connection of
+    /// its own.
+    pub fn set_first_hop_connection_idle_timeout(mut self, dur: Duration) -> Self {
+        self.first_hop_connection_idle_timeout = dur;
+        self
+    }
+
+    /// Sets the cipher suites accepted from peers building circuits to this onion router.
+    ///
+    /// The strongest suite offered by the peer is selected, see
+    /// [`TunnelOptions::set_cipher_suites`] for the suites offered by this onion router.
+    /// By default all suites are accepted.
+    pub fn set_cipher_suites(mut self, cipher_suites: &[CipherSuite]) -> Self {
+        self.cipher_suites = CipherSuites::from_slice(cipher_suites);
+        self
+    }
+
+    /// Sets a [`StateObserver`] which is called on every state change of this onion router.
+    ///
+    /// By default state changes are only reported as [`Event`]s.
+    pub fn set_state_observer(mut self, observer: Arc<dyn StateObserver>) -> Self {
+        self.observer = Observer::new(observer);
+        self
+    }
+
+    /// Sets a [`CellInspector`] which receives the metadata of every cell handled by the circuits
+    /// accepted by this onion router.
+    ///
+    /// Only available with the `research` feature, which must not be enabled in production
+    /// deployments since the metadata weakens the anonymity of other users of the network.
+    #[cfg(feature = "research")]
+    pub fn set_cell_inspector(mut self, inspector: Arc<dyn CellInspector>) -> Self {
+        self.inspector = Some(inspector);
+        self
+    }
+
+    /// Restores the state exported by [`OnionContext::export_state`] before a restart, so peers
+    /// which failed recently are still avoided.
+    ///
+    /// The state is applied before the first tunnel is built. Entries which expired while the
+    /// onion router was down are skipped. Starting fails if the state has a newer format than
+    /// [`NodeState::VERSION`].
+    pub fn import_state(mut self, state: NodeState) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Sets the number of entry guards, the only peers used as first hop of the tunnels built by
+    /// this onion router.
+    ///
+    /// Choosing the first hop anew for every path eventually picks a malicious peer, which then
+    /// learns the address of this onion router. Instead, the guards are drawn from the
+    /// [`PeerProvider`] once and kept across rotations. A guard which fails 3 handshakes in a row
+    /// is replaced by a fresh peer. Every change of the guards is reported as
+    /// [`Event::GuardsChanged`].
+    ///
+    /// Hops set with [`TunnelOptions::set_first_hop`] and explicit paths are not affected. A
+    /// tunnel whose options accept none of the guards falls back to a random first hop, unless
+    /// it is built in strict mode.
+    ///
+    /// The default value is zero, which disables entry guards.
+    pub fn set_entry_guards(mut self, n: usize) -> Self {
+        self.entry_guards = n;
+        self
+    }
+
+    /// Restores the entry guards returned by [`OnionContext::entry_guards`] before a restart.
+    ///
+    /// Only the first guards up to the number set with [`OnionBuilder::set_entry_guards`] are
+    /// kept.
+    pub fn import_entry_guards(mut self, guards: Vec<Peer>) -> Self {
+        self.imported_guards = guards;
+        self
+    }
+
+    /// Sets the factory creating the generators the ids of tunnels and circuits are drawn from.
+    ///
+    /// The ids of all tunnels are drawn from a single generator, while a generator is created
+    /// for the circuits of every connection. Passing a factory which creates [`SeededRandom`]s
+    /// makes the ids reproducible in tests. Keys, nonces and padding are not affected.
+    ///
+    /// By default the ids are drawn from the [`SystemRandom`] generator.
+    pub fn set_rng_factory<F>(mut self, factory: F) -> Self
+    where
+        F: Fn() -> Box<dyn OnionRng> + Send + Sync + 'static,
+    {
+        self.rng = RngFactory::new(factory);
+        self
+    }
+
+    /// Sets the runtime on which incoming connections are handled.
+    ///
+    /// This isolates relaying circuits of other peers from the tunnels built by this onion router,
+    /// whose tasks keep running on the runtime calling [`OnionBuilder::start`].
+    /// Incoming tunnels, which arrive on incoming connections, are also handled on this runtime.
+    /// The onion router stops accepting connections once the runtime is shut down.
+    ///
+    /// By default incoming connections are handled on the runtime calling
+    /// [`OnionBuilder::start`].
+    pub fn set_relay_runtime(mut self, handle: Handle) -> Self {
+        self.relay_runtime = Some(handle);
+        self
+    }
+
     /// Starts the onion router.
     ///
     /// Returns a [`OnionContext`] handle used for building new tunnels and a stream of incoming
     /// connections [`OnionIncoming`].
-    pub fn start(self) -> (OnionContext, OnionIncoming) {
+    ///
+    /// The host key, the listen address and the configured settings are validated before
+    /// anything is started. If the onion router can not run, a [`StartError`] listing every
+    /// problem found is returned instead.
+    ///
+    /// Has to be called from within a tokio runtime. The background tasks of the onion router
+    /// run on this runtime, except for the relay tasks if [`OnionBuilder::set_relay_runtime`] is
+    /// used, so it has to outlive the onion router.
+    pub fn start(self) -> std::result::Result<(OnionContext, OnionIncoming), StartError> {
         let OnionBuilder {
-            listen_addr,
-            hostkey,
+            listen,
             peer_provider,
             enable_cover,
             n_hops,
             round_duration,
+            min_tunnel_lifetime,
+            stall_threshold,
+            max_pending_handshakes,
+            max_handshakes_per_peer,
+            relay_connection_idle_timeout,
+            first_hop_connection_idle_timeout,
+            socket_timeouts,
+            max_batch_size,
+            cipher_suites,
+            build_reports,
+            padding_interval,
+            tunnel_idle_timeout,
+            build_timeouts,
+            diagnosis_budget,
+            keep_alive_interval,
+            strict,
+            relay_termination,
+            latency_histogram,
+            extend_policy,
+            relay_runtime,
+            observer,
+            rotation_strategy,
+            key_limits,
+            rekey_interval,
+            shutdown_timeout,
+            state,
+            entry_guards,
+            imported_guards,
+            rng,
+            #[cfg(feature = "research")]
+            inspector,
         } = self;
 
+        let mut check = StartCheck::default();
+        let tcp_listener = match &listen {
+            Some((listen_addr, hostkey)) => {
+                check.hostkey(hostkey);
+                check.bind(*listen_addr)
+            }
+            None => None,
+        };
+        let runtime = check.runtime();
+        check.setting(
+            round_duration > Duration::ZERO,
+            "round duration",
+            "must be greater than zero",
+        );
+        check.setting(
+            stall_threshold > Duration::ZERO,
+            "stall threshold",
+            "must be greater than zero",
+        );
+        check.setting(
+            max_pending_handshakes > 0,
+            "maximum number of pending handshakes",
+            "must be at least 1, otherwise every incoming connection is rejected",
+        );
+        check.setting(
+            max_handshakes_per_peer > 0,
+            "maximum number of handshakes per peer",
+            "must be at least 1, otherwise no tunnel can be built",
+        );
+        check.setting(
+            relay_connection_idle_timeout < circuit::IDLE_TIMEOUT,
+            "relay connection idle timeout",
+            "must be shorter than the 120 seconds after which peers close idle connections",
+        );
+        check.setting(
+            first_hop_connection_idle_timeout < circuit::IDLE_TIMEOUT,
+            "first hop connection idle timeout",
+            "must be shorter than the 120 seconds after which peers close idle connections",
+        );
+        check.setting(
+            keep_alive_interval > Duration::ZERO && keep_alive_interval < circuit::IDLE_TIMEOUT,
+            "keep-alive interval",
+            "must be positive and shorter than the 120 seconds after which relays close idle \
+             circuits",
+        );
+        check.setting(
+            cipher_suites.strongest().is_some(),
+            "cipher suites",
+            "at least one suite has to be accepted, otherwise no circuit can be built to this peer",
+        );
+        check.setting(
+            n_hops > 0 || !matches!(rotation_strategy, RotationStrategy::Splice { .. }),
+            "rotation strategy",
+            "splicing requires at least one hop per tunnel",
+        );
+        check.setting(
+            key_limits.rotate_after > 0 && key_limits.rotate_after <= key_limits.ceiling,
+            "key usage limits",
+            "the rotation threshold must be at least 1 and must not exceed the ceiling",
+        );
+        check.setting(
+            build_timeouts.hop > Duration::ZERO && build_timeouts.hop <= build_timeouts.deadline,
+            "build timeouts",
+            "the hop timeout must be positive and must not exceed the deadline",
+        );
+        check.setting(
+            socket_timeouts.read > Duration::ZERO && socket_timeouts.write > Duration::ZERO,
+            "socket timeouts",
+            "the read and the write timeout must be positive",
+        );
+        check.setting(
+            (1..=socket::MAX_BATCH_SIZE).contains(&max_batch_size),
+            "maximum batch size",
+            "must be between 1 and 32",
+        );
+        check.setting(
+            state.version <= NodeState::VERSION,
+            "node state",
+            "the format is newer than this version of the crate",
+        );
+        check.finish()?;
+        let relay = listen.map(|(_, hostkey)| {
+            let bound = tcp_listener.expect("listener bound if the check passed");
+            (bound, hostkey)
+        });
+        let runtime = runtime.expect("runtime found if the check passed");
+
         // capacity = 2 so both initial switch-over and keep-alive are received
         let (events, _) = broadcast::channel(2);
         let (incoming_tx, incoming_rx) = mpsc::channel(INCOMING_BUFFER_SIZE);
 
-        // create task listening on p2p connections
-        tokio::spawn({
-            let mut listener = OnionListener::new(hostkey, incoming_tx);
-            async move { listener.listen_addr(listen_addr).await }
-        });
+        let rotation_policy = RotationPolicy {
+            min_lifetime: min_tunnel_lifetime,
+            strategy: rotation_strategy,
+            key_limits,
+            rekey_interval,
+            ..Default::default()
+        };
+        let settings = ContextSettings {
+            n_hops,
+            rotation_policy,
+            stall_threshold,
+            enable_cover,
+            build_reports,
+            padding_interval,
+            idle_timeout: tunnel_idle_timeout,
+            build_timeouts,
+            diagnosis_budget,
+            max_handshakes_per_peer,
+            strict,
+            observer: observer.clone(),
+            rng: rng.clone(),
+            first_hop_connection_idle_timeout,
+            socket_timeouts,
+            max_batch_size,
+            shutdown_timeout,
+            local_addr: relay.as_ref().map(|((_, local_addr), _)| *local_addr),
+            n_guards: entry_guards,
+            imported_guards,
+        };
+        let ctx = OnionContext::new(runtime, events.clone(), peer_provider, settings, &state);
 
-        let ctx = OnionContext::new(events.clone(), peer_provider, n_hops, enable_cover);
+        // create task listening on p2p connections, unless in client-only mode
+        if let Some(((tcp_listeners, _), hostkey)) = relay {
+            let backlog = HandshakeBacklog::new(
+                max_pending_handshakes,
+                ctx.relay_stats.clone(),
+                ctx.notify.clone(),
+            );
+            let listener = OnionListener::new(
+                hostkey,
+                incoming_tx,
+                ctx.registry.clone(),
+                backlog,
+                cipher_suites,
+            )
+            .with_observer(observer)
+            .with_relay_termination(relay_termination)
+            .with_latency_histogram(latency_histogram)
+            .with_extend_policy(extend_policy)
+            .with_connection_cache((relay_connection_idle_timeout > Duration::ZERO).then(|| {
+                ConnectionCache::new(relay_connection_idle_timeout, Some(ctx.relay_stats.clone()))
+                    .with_rng(rng.clone())
+                    .with_socket_timeouts(socket_timeouts)
+            }))
+            .with_rng(rng.clone())
+            .with_socket_timeouts(socket_timeouts)
+            .with_max_batch_size(max_batch_size);
+            #[cfg(feature = "research")]
+            let listener = OnionListener {
+                inspector,
+                ..listener
+            };
+            // a second socket only if the system does not accept IPv4 on an IPv6 socket
+            for tcp_listener in tcp_listeners {
+                let mut listener = listener.clone();
+                let shutdown = ctx.shutdown.clone();
+                task::spawn_on(relay_runtime.as_ref(), "task.listener", async move {
+                    tokio::select! {
+                        res = listener.listen_std(tcp_listener) => res,
+                        _ = shutdown.finished() => Ok(()),
+                    }
+                });
+            }
+        }
 
         // creates round handler task
-        tokio::spawn({
+        task::spawn("task.round_handler", {
             let mut round_handler = RoundHandler {
                 events,
                 round_duration,
+                keep_alive_interval,
+                shutdown: ctx.shutdown.clone(),
             };
             async move { round_handler.handle().await }
         });
@@ -540,6 +2584,6 @@ impl OnionBuilder {
         let incoming = OnionIncoming {
             incoming: incoming_rx,
         };
-        (ctx, incoming)
+        Ok((ctx, incoming))
     }
 }
@@ -0,0 +1,85 @@
+//! The protocol versions and optional features compiled into this binary, see [`build_info`].
+
+use crate::onion::crypto::{CipherSuite, HandshakeVersion};
+use crate::onion::CellSize;
+use std::fmt;
+
+/// The cargo features of this crate which change what a binary supports or how it behaves.
+const FEATURES: [(&str, bool); 6] = [
+    ("crypto_openssl", cfg!(feature = "crypto_openssl")),
+    ("crypto_ring", cfg!(feature = "crypto_ring")),
+    ("crypto_rustcrypto", cfg!(feature = "crypto_rustcrypto")),
+    ("serde", cfg!(feature = "serde")),
+    ("leak-check", cfg!(feature = "leak-check")),
+    ("research", cfg!(feature = "research")),
+];
+
+/// The connection types over which circuits are established.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub enum Transport {
+    /// A TCP connection, which circuits with standard cells may share.
+    Tcp,
+}
+
+/// What a binary built from this crate supports, returned by [`build_info`].
+///
+/// Peers do not exchange their build info: the cipher suites, cell sizes and handshake versions
+/// are negotiated with every hop during the circuit handshake. With the `serde` feature enabled,
+/// the build info implements `Serialize` and `Deserialize`, e.g. for inventories of a fleet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub struct BuildInfo {
+    /// The version of this crate.
+    pub version: String,
+    /// The versions of the circuit handshake, oldest first.
+    pub handshake_versions: Vec<HandshakeVersion>,
+    /// The cipher suites, weakest first.
+    pub cipher_suites: Vec<CipherSuite>,
+    pub cell_sizes: Vec<CellSize>,
+    pub transports: Vec<Transport>,
+    /// The enabled cargo features of this crate.
+    pub features: Vec<String>,
+}
+
+/// Returns the protocol versions and optional features compiled into this binary.
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        handshake_versions: HandshakeVersion::ALL.to_vec(),
+        cipher_suites: CipherSuite::ALL.to_vec(),
+        cell_sizes: CellSize::ALL.to_vec(),
+        transports: vec![Transport::Tcp],
+        features: FEATURES
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name.to_string())
+            .collect(),
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "allium {}, handshakes {:?}, cipher suites {:?}, cell sizes {:?}, transports {:?}, \
+             features {:?}",
+            self.version,
+            self.handshake_versions,
+            self.cipher_suites,
+            self.cell_sizes,
+            self.transports,
+            self.features
+        )
+    }
+}
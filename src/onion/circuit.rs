@@ -1,41 +1,106 @@
-use crate::onion::crypto::{self, EphemeralPublicKey, RsaPrivateKey, SessionKey};
+use crate::leak::Tracked;
+use crate::onion::connection::{self, CircuitStream, ConnectionCache, ConnectionInfo};
+use crate::onion::crypto::{
+    self, CipherSuite, CipherSuites, Direction, EphemeralPublicKey, HandshakeVersion, HostKey,
+    SessionKey,
+};
+use crate::onion::lanes::{Lane, Lanes, Outgoing};
 use crate::onion::protocol::{
-    CircuitOpaque, CircuitOpaqueBytes, SignKey, TryFromBytesExt, TunnelExtendedError,
-    TunnelProtocolError, TunnelRequest, TunnelTruncatedError, VerifyKey,
+    CellSize, CircuitOpaque, CircuitOpaqueBytes, EndReason, FeatureOffer, HopVerifier, SignKey,
+    TryFromBytesExt, TunnelExtendedError, TunnelProtocolError, TunnelRequest, TunnelTruncatedError,
+    UnsupportedFeatures, VerifyKey,
 };
-use crate::onion::socket::{OnionSocket, OnionSocketError, SocketResult};
+#[cfg(feature = "research")]
+use crate::onion::research::{CellDirection, CellInspector, CellTap};
+use crate::onion::rng::{OnionRng, RngFactory, SystemRandom};
+use crate::onion::socket::{self, OnionSocket, OnionSocketError, SocketErrorKind, SocketResult};
 use crate::onion::tunnel::TunnelId;
-use crate::onion::Tunnel;
+use crate::onion::{self, ExtendPolicy, IncomingTunnelInfo, RelayCounters, Tunnel, TunnelCounters};
+use crate::task;
 use crate::Result;
 use anyhow::anyhow;
 use anyhow::Context;
 use bytes::Bytes;
+use log::debug;
 use log::trace;
 use log::warn;
+use std::collections::HashSet;
 use std::fmt;
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::time;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 
 /// timeout applied if there is no traffic on a circuit
 pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
 /// timeout applied for a teardown operation
-const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// number of random circuit ids drawn before searching for a free one
+const MAX_ID_ATTEMPTS: usize = 16;
 
 pub(crate) type CircuitId = u16;
 
+/// The parameters negotiated during the handshake of a circuit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CircuitParams {
+    pub cell_size: CellSize,
+    pub cipher_suite: CipherSuite,
+    pub(crate) handshake: HandshakeVersion,
+}
+
+impl CircuitParams {
+    pub(crate) fn new(
+        cell_size: CellSize,
+        cipher_suite: CipherSuite,
+        handshake: HandshakeVersion,
+    ) -> Self {
+        CircuitParams {
+            cell_size,
+            cipher_suite,
+            handshake,
+        }
+    }
+}
+
+impl fmt::Display for CircuitParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cell size {:?}, cipher suite {:?}, handshake {:?}",
+            self.cell_size, self.cipher_suite, self.handshake
+        )
+    }
+}
+
 /// A Circuit is a direct connection between two peers.
 /// The struct stores its unique ID and a socket.
 pub(crate) struct Circuit {
     pub(crate) id: CircuitId,
-    pub(crate) socket: OnionSocket<TcpStream>,
+    pub(crate) socket: OnionSocket<CircuitStream>,
+    /// the negotiated parameters, set if this peer performed the key exchange of the circuit
+    pub(crate) params: Option<CircuitParams>,
+    _tracked: Tracked,
 }
 
 impl Circuit {
-    pub(crate) fn new(id: CircuitId, socket: OnionSocket<TcpStream>) -> Self {
-        Circuit { id, socket }
+    pub(crate) fn new(id: CircuitId, socket: OnionSocket<CircuitStream>) -> Self {
+        Circuit {
+            id,
+            socket,
+            params: None,
+            _tracked: Tracked::new("circuit"),
+        }
+    }
+
+    pub(crate) fn with_params(mut self, params: CircuitParams) -> Self {
+        self.params = Some(params);
+        self
     }
 
     pub(crate) async fn accept_opaque(
@@ -44,10 +109,10 @@ impl Circuit {
         self.socket.accept_opaque().await
     }
 
-    pub(crate) async fn teardown_with_timeout(&mut self) {
+    pub(crate) async fn teardown_with_timeout(&mut self, reason: EndReason) {
         match time::timeout(TEARDOWN_TIMEOUT, {
             // NOTE: Ignore any errors
-            self.socket.teardown(self.id)
+            self.socket.teardown(self.id, reason)
         })
         .await
         {
@@ -57,15 +122,94 @@ impl Circuit {
         };
     }
 
-    /// Generates a random circuit ID which is assumed to be unique.
-    pub(crate) fn random_id() -> CircuitId {
-        // FIXME an attacker may fill up all ids
+    /// Generates a random circuit ID. Uniqueness on a connection is ensured by [`CircuitIds`].
+    pub(crate) fn random_id(rng: &mut dyn OnionRng) -> CircuitId {
         let mut id_buf = [0u8; 2];
-        crypto::fill_random(&mut id_buf);
+        rng.fill_bytes(&mut id_buf);
         u16::from_le_bytes(id_buf)
     }
 }
 
+/// The ids of the circuits which are open on a single connection.
+///
+/// Circuit ids are chosen by the initiator of a circuit and only need to be unique per connection.
+pub(crate) struct CircuitIds {
+    ids: HashSet<CircuitId>,
+    /// the generator the ids are drawn from by [`allocate`](CircuitIds::allocate)
+    rng: Box<dyn OnionRng>,
+}
+
+impl CircuitIds {
+    /// Creates an empty set whose ids are drawn from `rng` instead of the system generator.
+    pub(crate) fn new(rng: Box<dyn OnionRng>) -> Self {
+        CircuitIds {
+            ids: HashSet::new(),
+            rng,
+        }
+    }
+
+    /// Allocates a random id which is not in use on this connection.
+    /// Returns `None` if all ids are in use.
+    pub(crate) fn allocate(&mut self) -> Option<CircuitId> {
+        let rng = &mut self.rng;
+        allocate_from(&mut self.ids, || Circuit::random_id(&mut **rng))
+    }
+
+    /// Allocates an id drawn from `random_id`, re-rolling on collision. If no free id has been
+    /// drawn after a few attempts, the lowest free id is allocated instead.
+    #[cfg(test)]
+    pub(crate) fn allocate_with<F>(&mut self, random_id: F) -> Option<CircuitId>
+    where
+        F: FnMut() -> CircuitId,
+    {
+        allocate_from(&mut self.ids, random_id)
+    }
+
+    /// Marks an id chosen by the peer as in use. Returns `false` if it already is.
+    pub(crate) fn insert(&mut self, id: CircuitId) -> bool {
+        self.ids.insert(id)
+    }
+
+    pub(crate) fn contains(&self, id: CircuitId) -> bool {
+        self.ids.contains(&id)
+    }
+
+    /// Frees `id` after its circuit has been torn down.
+    pub(crate) fn release(&mut self, id: CircuitId) {
+        self.ids.remove(&id);
+    }
+}
+
+impl Default for CircuitIds {
+    fn default() -> Self {
+        CircuitIds::new(Box::new(SystemRandom))
+    }
+}
+
+impl fmt::Debug for CircuitIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitIds")
+            .field("ids", &self.ids)
+            .finish()
+    }
+}
+
+fn allocate_from<F>(ids: &mut HashSet<CircuitId>, mut random_id: F) -> Option<CircuitId>
+where
+    F: FnMut() -> CircuitId,
+{
+    for _ in 0..MAX_ID_ATTEMPTS {
+        let id = random_id();
+        if ids.insert(id) {
+            return Some(id);
+        }
+    }
+    // FIXME an attacker may fill up all ids
+    let id = (0..=CircuitId::MAX).find(|id| !ids.contains(id))?;
+    ids.insert(id);
+    Some(id)
+}
+
 /// A CircuitHandler is created for each incoming circuit connection (in_circuit), after negotiating a session key.
 /// It implements the circuit layer logic.
 /// The events channel is used to communicate with the layer above.
@@ -78,60 +222,202 @@ pub(crate) struct CircuitHandler {
     session_key: [SessionKey; 1],
     incoming: mpsc::Sender<Tunnel>,
     state: State,
+    /// data received in the endpoint state which did not fit into the buffer of the tunnel
+    pending_data: Option<Bytes>,
+    /// messages waiting to be sent in the endpoint state
+    lanes: Lanes<Outgoing>,
+    /// maximum number of data messages sent in a single write
+    max_batch_size: usize,
+    /// set once the tunnel of the endpoint state has been closed by the application
+    app_closed: bool,
+    /// the reason sent when the circuits are torn down after an error, which passes on the reason
+    /// of a teardown received on either circuit
+    teardown_reason: EndReason,
+    /// connections shared by the out circuits of all relayed circuits, see [`connection`]
+    connections: Option<ConnectionCache>,
+    /// whether tunnels may be terminated at this hop, see [`TunnelRequest::Terminate`]
+    relay_termination: bool,
+    /// set if the forwarding latency of relayed cells is recorded, see
+    /// [`RelayStats::forwarding_latency`](crate::RelayStats::forwarding_latency)
+    latency_counters: Option<Arc<RelayCounters>>,
+    /// addresses this circuit may be extended to and the counters recording refusals, every
+    /// address is allowed if unset
+    extend_policy: Option<(Arc<ExtendPolicy>, Arc<RelayCounters>)>,
+    /// creates the generators of the ids of the out circuits on new connections
+    rng: RngFactory,
+    #[cfg(feature = "research")]
+    tap: CellTap,
 }
 
 pub(crate) enum State {
     Default,
     Router {
-        out_circuit: Circuit,
+        out_circuit: Box<Circuit>,
     },
     /// Stores the receiving end of a channel which is used by higher layers to control the tunnel.
     Endpoint {
         tunnel_id: TunnelId,
         data_rx: mpsc::UnboundedReceiver<Bytes>,
         data_tx: mpsc::Sender<Bytes>,
+        stats: Arc<TunnelCounters>,
+    },
+    /// This hop is the endpoint of a tunnel without a destination application.
+    Terminal {
+        tunnel_id: TunnelId,
     },
 }
 
 impl CircuitHandler {
     /// Performs the reacting part of a circuit handshake.
-    /// If successful a session key with the tunnel controller (tunnel-building peer) is agreed on.
+    /// If successful a session key with the tunnel controller (tunnel-building peer) is agreed on,
+    /// using the strongest offered cipher suite out of `cipher_suites`.
     pub(crate) async fn init(
-        mut socket: OnionSocket<TcpStream>,
-        host_key: &RsaPrivateKey,
+        mut socket: OnionSocket<CircuitStream>,
+        host_key: &Arc<HostKey>,
+        cipher_suites: CipherSuites,
         incoming: mpsc::Sender<Tunnel>,
     ) -> Result<Self> {
-        trace!("Accepting handshake from {:?}", socket.peer_addr());
-        let (circuit_id, peer_key) = socket
-            .accept_handshake()
+        trace!("Accepting handshake on {}", socket.connection());
+        let (circuit_id, peer_key, suites) = socket
+            .accept_handshake(cipher_suites)
             .await
             .context("Handshake with new connection failed")?;
+        // negotiate only selects known suites
+        let suite = CipherSuite::from_code(suites.selected).unwrap();
 
         let (private_key, key) = crypto::generate_ephemeral_keypair();
-        let key = SignKey::sign(&key, host_key);
+        // signing with a RSA host key would stall the other tasks of the worker thread
+        let host_key = host_key.clone();
+        let (key, salt) = task::spawn_blocking("task.sign_key", move || {
+            let key = SignKey::sign(&key, suites, &host_key);
+            (key.to_signed(), key.salt())
+        })
+        .await?;
 
         socket
             .finalize_handshake(circuit_id, key)
             .await
             .context("Could not finalize handshake")?;
 
-        if let Ok(secret) = SessionKey::from_key_exchange(private_key, &peer_key) {
-            let in_circuit = Circuit::new(circuit_id, socket);
+        let missing = suites.missing_features();
+        if missing != 0 {
+            // the signed reply tells the initiator which of the required features are missing
+            debug!(
+                "Rejecting circuit {} on {}: required features {:#06x} are not supported",
+                circuit_id,
+                socket.connection(),
+                missing
+            );
+            let teardown = socket.teardown(circuit_id, EndReason::ProtocolError);
+            let _ = time::timeout(TEARDOWN_TIMEOUT, teardown).await;
+            return Err(UnsupportedFeatures { missing }.into());
+        }
+
+        if let Ok(mut secret) = SessionKey::from_key_exchange(
+            private_key,
+            &peer_key,
+            suite,
+            salt.as_deref(),
+            suites.version(),
+        ) {
+            if suites.version() >= HandshakeVersion::SequencedNonces {
+                secret = secret.with_sequence_check();
+            }
+            let params = CircuitParams::new(socket.cell_size(), suite, suites.version());
+            debug!(
+                "Accepted circuit {} on {} ({})",
+                circuit_id,
+                socket.connection(),
+                params
+            );
+            let in_circuit = Circuit::new(circuit_id, socket).with_params(params);
             Ok(Self {
                 in_circuit,
                 session_key: [secret],
                 incoming,
                 state: State::Default,
+                pending_data: None,
+                lanes: Lanes::new(),
+                max_batch_size: socket::MAX_BATCH_SIZE,
+                app_closed: false,
+                teardown_reason: EndReason::ProtocolError,
+                connections: None,
+                relay_termination: true,
+                latency_counters: None,
+                extend_policy: None,
+                rng: Default::default(),
+                #[cfg(feature = "research")]
+                tap: CellTap::new(None),
             })
         } else {
             trace!("Incoming handshake failed post-handshake: unable to derive key");
-            let _ = time::timeout(TEARDOWN_TIMEOUT, socket.teardown(circuit_id)).await;
+            let teardown = socket.teardown(circuit_id, EndReason::ProtocolError);
+            let _ = time::timeout(TEARDOWN_TIMEOUT, teardown).await;
             Err(anyhow!(
                 "Incoming handshake failed post-handshake: unable to derive key"
             ))
         }
     }
 
+    /// Extends this circuit over the connections in `connections` instead of a new connection.
+    pub(crate) fn set_connection_cache(&mut self, connections: Option<ConnectionCache>) {
+        self.connections = connections;
+    }
+
+    /// Draws the ids of out circuits on new connections from the generators created by `rng`.
+    pub(crate) fn set_rng_factory(&mut self, rng: RngFactory) {
+        self.rng = rng;
+    }
+
+    /// Sets the maximum number of data messages sent in a single write in the endpoint state.
+    pub(crate) fn set_max_batch_size(&mut self, n: usize) {
+        self.max_batch_size = n;
+    }
+
+    /// Sets whether initiators may make this hop the endpoint of their tunnels. Otherwise the
+    /// circuit is torn down on a `TUNNEL TERMINATE` message.
+    pub(crate) fn set_relay_termination(&mut self, enable: bool) {
+        self.relay_termination = enable;
+    }
+
+    /// Records the forwarding latency of the cells relayed by this circuit in `counters`.
+    pub(crate) fn set_latency_counters(&mut self, counters: Option<Arc<RelayCounters>>) {
+        self.latency_counters = counters;
+    }
+
+    /// Refuses to extend this circuit to addresses denied by `policy`, counting the refusals in
+    /// `counters`.
+    pub(crate) fn set_extend_policy(
+        &mut self,
+        policy: Arc<ExtendPolicy>,
+        counters: Arc<RelayCounters>,
+    ) {
+        self.extend_policy = Some((policy, counters));
+    }
+
+    /// Reports the metadata of every cell handled by this circuit to `inspector`.
+    #[cfg(feature = "research")]
+    pub(crate) fn set_inspector(&mut self, inspector: Option<Arc<dyn CellInspector>>) {
+        self.tap = CellTap::new(inspector);
+    }
+
+    /// Returns the id of the circuit to the previous hop.
+    pub(crate) fn circuit_id(&self) -> CircuitId {
+        self.in_circuit.id
+    }
+
+    /// Returns the connection to the previous hop.
+    pub(crate) fn connection(&self) -> ConnectionInfo {
+        self.in_circuit.socket.connection()
+    }
+
+    /// Returns the parameters negotiated with the previous hop.
+    pub(crate) fn params(&self) -> CircuitParams {
+        self.in_circuit
+            .params
+            .expect("parameters of the accepted circuit are known")
+    }
+
     /// Handles messages and requests depending on the current state in a loop.
     pub(crate) async fn handle(&mut self) -> Result<()> {
         trace!("CircuitHandler started for circuit {:?}", self.in_circuit);
@@ -140,7 +426,7 @@ impl CircuitHandler {
             Ok(_) => Ok(()),
             Err(e) => {
                 // finally tear down the circuits
-                self.teardown_all().await;
+                self.teardown_all(self.teardown_reason).await;
                 Err(e)
             }
         }
@@ -152,7 +438,7 @@ impl CircuitHandler {
             tokio::pin!(delay);
 
             match &mut self.state {
-                State::Default => {
+                State::Default | State::Terminal { .. } => {
                     tokio::select! {
                         msg = self.in_circuit.accept_opaque() => self.handle_in_circuit(msg).await?,
                         _ = &mut delay => {
@@ -172,12 +458,26 @@ impl CircuitHandler {
                     }
                 }
                 State::Endpoint {
-                    tunnel_id, data_rx, ..
+                    tunnel_id,
+                    data_rx,
+                    data_tx,
+                    ..
                 } => {
                     let tunnel_id = *tunnel_id;
+                    let pending = self.pending_data.is_some();
+                    let outgoing = !self.lanes.is_empty();
                     tokio::select! {
-                        msg = self.in_circuit.accept_opaque() => self.handle_in_circuit(msg).await?,
-                        data = data_rx.recv() => self.handle_data(tunnel_id, data).await?,
+                        // stop reading until the tunnel has room for the pending data
+                        msg = self.in_circuit.accept_opaque(), if !pending => {
+                            self.handle_in_circuit(msg).await?
+                        }
+                        true = onion::has_room(data_tx), if pending => {
+                            if let Some(data) = self.pending_data.take() {
+                                self.deliver(data);
+                            }
+                        }
+                        data = data_rx.recv(), if !self.app_closed => self.queue_data(data),
+                        _ = async {}, if outgoing => self.send_next(tunnel_id).await?,
                         _ = &mut delay => {
                             self.handle_timeout().await;
                             break;
@@ -200,22 +500,39 @@ impl CircuitHandler {
         // match whether a message has been received or if an error occurred
         match msg {
             Ok(mut msg) => {
+                let read_at = Instant::now();
+                #[cfg(feature = "research")]
+                let arrived_at = SystemTime::now();
+                // every forward cell passes this hop, whether it is addressed to it or not
+                if !msg.is_in_sequence(&self.session_key[0], Direction::Forward) {
+                    return Err(anyhow!(
+                        "In Circuit on {} received a replayed or reordered cell",
+                        self.in_circuit.socket.connection()
+                    ));
+                }
                 // decrypt message
-                msg.decrypt(self.session_key.iter().rev())?;
+                msg.decrypt(Direction::Forward, self.session_key.iter().rev())?;
                 // test if this message is directed to us or is broken
-                let tunnel_msg = TunnelRequest::read_with_digest_from(&mut msg.payload.bytes);
+                let verifier = HopVerifier::new(&self.session_key[0], Direction::Forward);
+                let tunnel_msg =
+                    TunnelRequest::read_with_digest_from(&mut msg.payload.bytes, &verifier);
                 match tunnel_msg {
                     Ok(tunnel_msg) => {
                         // addressed to us
+                        #[cfg(feature = "research")]
+                        self.tap.addressed(&tunnel_msg, arrived_at);
                         self.handle_tunnel_message(tunnel_msg).await
                     }
                     Err(TunnelProtocolError::Digest) => {
                         // message not directed to us, forward to relay_socket
                         if let State::Router { out_circuit } = &mut self.state {
+                            #[cfg(feature = "research")]
+                            self.tap.relayed(CellDirection::Forward, arrived_at);
                             out_circuit
                                 .socket
                                 .forward_opaque(out_circuit.id, msg.payload)
                                 .await?;
+                            record_latency(&self.latency_counters, read_at);
                             Ok(())
                         } else {
                             // no relay_socket => proto breach teardown
@@ -233,20 +550,43 @@ impl CircuitHandler {
                         */
                         Err(anyhow!("Unsupported packet: {:?}", actual))
                     }
+                    Err(TunnelProtocolError::AddressType { actual }) => Err(anyhow!(
+                        "Unsupported address type in TUNNEL EXTEND: {}",
+                        actual
+                    )),
                     Err(TunnelProtocolError::Peer(())) => unreachable!(),
                 }
             }
-            Err(OnionSocketError::BrokenMessage) => Err(anyhow!(
-                "In Circuit breached protocol by sending unexpected message"
-            )),
-            Err(OnionSocketError::StreamTerminated(e)) => {
-                Err(anyhow!("In Stream terminated: {:?}", e))
-            }
-            Err(OnionSocketError::TeardownMessage) => Err(anyhow!("In Stream torn down")),
-            Err(e) => {
-                // Panicking stub
-                panic!("An unexpected error occurred during handling of the in_socket: {:?}", e);
-            }
+            Err(OnionSocketError { kind, connection }) => match kind {
+                SocketErrorKind::BrokenMessage => Err(anyhow!(
+                    "In Circuit on {} breached protocol by sending unexpected message",
+                    connection
+                )),
+                SocketErrorKind::StreamTerminated(e) => {
+                    self.teardown_reason = EndReason::Unspecified;
+                    Err(anyhow!("In Stream on {} terminated: {:?}", connection, e))
+                }
+                SocketErrorKind::TeardownMessage(reason) => {
+                    self.teardown_reason = reason;
+                    Err(anyhow!(
+                        "In Stream on {} torn down ({:?})",
+                        connection,
+                        reason
+                    ))
+                }
+                SocketErrorKind::UnknownCircuit(id) => Err(anyhow!(
+                    "In Circuit on {} breached protocol by sending a cell for unknown circuit {}",
+                    connection,
+                    id
+                )),
+                kind => {
+                    // Panicking stub
+                    panic!(
+                        "An unexpected error occurred during handling of the in_socket on {}: {:?}",
+                        connection, kind
+                    );
+                }
+            },
         }
     }
 
@@ -255,13 +595,16 @@ impl CircuitHandler {
         let mut state = State::Default;
         std::mem::swap(&mut self.state, &mut state);
         self.state = match (tunnel_msg, state) {
-            (TunnelRequest::Extend(dest, key), State::Default) => {
+            (TunnelRequest::Extend(dest, key, cipher_suites, features), State::Default) => {
                 /*
                    any error in here should never cause the entire loop to fail and we
                    should always respond with EXTENDED (same reason as before)
                    It may be preferable to capsulise this into another function
                 */
-                match self.handle_tunnel_message_extend(dest, key).await {
+                match self
+                    .handle_tunnel_message_extend(dest, key, cipher_suites, features)
+                    .await
+                {
                     Ok((out_circuit, peer_key)) => {
                         self.in_circuit
                             .socket
@@ -271,7 +614,9 @@ impl CircuitHandler {
                                 &self.session_key,
                             )
                             .await?;
-                        State::Router { out_circuit }
+                        State::Router {
+                            out_circuit: Box::new(out_circuit),
+                        }
                     }
                     Err(e) => {
                         self.in_circuit
@@ -282,7 +627,7 @@ impl CircuitHandler {
                     }
                 }
             }
-            (TunnelRequest::Extend(_, _), state) => {
+            (TunnelRequest::Extend(..), state) => {
                 /* reply to socket with EXTENDED
                    this is required to prevent any deadlocks and errors in the tunnel
                    since Alice in the tunnel waits for a EXTENDED packet
@@ -300,7 +645,7 @@ impl CircuitHandler {
             }
             (TunnelRequest::Truncate, State::Router { .. }) => {
                 // Teardown out circuit
-                self.teardown_out_circuit().await;
+                self.teardown_out_circuit(EndReason::Normal).await;
 
                 self.in_circuit
                     .socket
@@ -323,12 +668,21 @@ impl CircuitHandler {
             }
             (TunnelRequest::Begin(tunnel_id), State::Default) => {
                 // counted = false because these tunnels will be mapped to counted tunnels by the OnionListener
-                let (tunnel, tx, rx) = Tunnel::new(tunnel_id, false);
+                let cell_size = self.in_circuit.socket.cell_size();
+                let (mut tunnel, tx, rx) = Tunnel::new(tunnel_id, false, cell_size);
+                tunnel.incoming_info = Some(IncomingTunnelInfo {
+                    adjacent_peer: self.in_circuit.socket.peer_addr()?,
+                    cell_size,
+                    cipher_suite: self.params().cipher_suite,
+                    began_at: SystemTime::now(),
+                });
+                let stats = tunnel.stats.clone();
                 if self.incoming.try_send(tunnel).is_ok() {
                     State::Endpoint {
                         tunnel_id,
                         data_rx: rx,
                         data_tx: tx,
+                        stats,
                     }
                 } else {
                     State::Default
@@ -337,14 +691,38 @@ impl CircuitHandler {
             (TunnelRequest::Begin(_), _) => {
                 return Err(anyhow!("Begin request while not in Default state"));
             }
-            (TunnelRequest::End(req_tunnel_id), State::Endpoint { tunnel_id, .. }) => {
+            (TunnelRequest::Terminate(tunnel_id), State::Default) => {
+                if !self.relay_termination {
+                    return Err(anyhow!(
+                        "Refusing to terminate tunnel {} at this relay",
+                        tunnel_id
+                    ));
+                }
+                debug!("Terminating tunnel {} at this relay", tunnel_id);
+                State::Terminal { tunnel_id }
+            }
+            (TunnelRequest::Terminate(_), _) => {
+                return Err(anyhow!("Terminate request while not in Default state"));
+            }
+            (TunnelRequest::End(req_tunnel_id, _), State::Terminal { tunnel_id }) => {
+                if req_tunnel_id != tunnel_id {
+                    return Err(anyhow!("Unknown tunnel id in End message"));
+                }
+                State::Default
+            }
+            (TunnelRequest::End(req_tunnel_id, reason), State::Endpoint { tunnel_id, .. }) => {
                 if req_tunnel_id != tunnel_id {
                     return Err(anyhow!("Unknown tunnel id in Data message"));
                 }
+                debug!(
+                    "Tunnel {} was ended by its initiator ({:?})",
+                    tunnel_id, reason
+                );
 
+                self.leave_endpoint();
                 State::Default
             }
-            (TunnelRequest::End(_), _) => {
+            (TunnelRequest::End(..), _) => {
                 return Err(anyhow!("End request white not in Endpoint state"));
             }
             (
@@ -353,19 +731,24 @@ impl CircuitHandler {
                     tunnel_id,
                     data_tx,
                     data_rx,
+                    stats,
                 },
             ) => {
                 if req_tunnel_id != tunnel_id {
                     return Err(anyhow!("Unknown tunnel id in Data message"));
                 }
 
-                let _ = data_tx.send(data).await; // TODO handle closed
-
-                State::Endpoint {
+                self.state = State::Endpoint {
                     tunnel_id,
                     data_tx,
                     data_rx,
-                }
+                    stats,
+                };
+                self.deliver(data);
+                return Ok(());
+            }
+            (TunnelRequest::Data(_, _), State::Terminal { .. }) => {
+                return Err(anyhow!("Data request on a tunnel terminated at this relay"));
             }
             (TunnelRequest::Data(_, _), _) => {
                 return Err(anyhow!("Data request while not in Endpoint state"));
@@ -374,6 +757,16 @@ impl CircuitHandler {
              KeepAlive messages are always valid and only cause a reset of the loop
             */
             (TunnelRequest::KeepAlive, state) => state,
+            // padding is cover traffic of the initiator and dropped like a keep-alive
+            (TunnelRequest::Padding(_), state) => state,
+            // echoes are answered in any state, they show the initiator that the path still works
+            (TunnelRequest::Echo(nonce), state) => {
+                self.in_circuit
+                    .socket
+                    .reply_echo(self.in_circuit.id, nonce, &self.session_key)
+                    .await?;
+                state
+            }
         };
         Ok(())
     }
@@ -382,18 +775,62 @@ impl CircuitHandler {
         &mut self,
         dest: SocketAddr,
         key: EphemeralPublicKey,
+        cipher_suites: CipherSuites,
+        features: Option<FeatureOffer>,
     ) -> std::result::Result<(Circuit, VerifyKey), TunnelExtendedError> {
-        let stream = TcpStream::connect(dest)
-            .await
-            .map_err(|_| TunnelExtendedError::PeerUnreachable)?;
+        if let Some((policy, counters)) = &self.extend_policy {
+            if !policy.allows(dest) {
+                debug!(
+                    "Refusing to extend circuit {} on {} to {} by the extend policy",
+                    self.in_circuit.id,
+                    self.in_circuit.socket.connection(),
+                    dest
+                );
+                counters.refused_extends.fetch_add(1, Ordering::Relaxed);
+                return Err(TunnelExtendedError::PolicyRefused);
+            }
+        }
+        let cell_size = self.in_circuit.socket.cell_size();
+        // the out circuit is configured like the in circuit by this onion router
+        let timeouts = self.in_circuit.socket.timeouts();
+        let (circuit_id, relay_socket, peer_key) = match &self.connections {
+            Some(connections) if connection::is_shareable(cell_size) => {
+                let (circuit_id, stream) = connections
+                    .open(dest)
+                    .await
+                    .map_err(|_| TunnelExtendedError::PeerUnreachable)?;
+                let mut relay_socket =
+                    OnionSocket::from_stream(CircuitStream::Shared(stream)).with_timeouts(timeouts);
+                let res = relay_socket
+                    .initiate_handshake_with_id(circuit_id, key, cell_size, cipher_suites, features)
+                    .await;
+                match res {
+                    Ok(peer_key) => (circuit_id, relay_socket, peer_key),
+                    Err(_) => {
+                        // the peer may still answer, make it release the id before it is reused
+                        let teardown = relay_socket.teardown(circuit_id, EndReason::ProtocolError);
+                        let _ = time::timeout(TEARDOWN_TIMEOUT, teardown).await;
+                        return Err(TunnelExtendedError::PeerUnreachable);
+                    }
+                }
+            }
+            _ => {
+                let stream = TcpStream::connect(dest)
+                    .await
+                    .map_err(|_| TunnelExtendedError::PeerUnreachable)?;
 
-        let mut relay_socket = OnionSocket::new(stream);
-        let peer_key = relay_socket
-            .initiate_handshake(self.in_circuit.id, key)
-            .await
-            .map_err(|_| TunnelExtendedError::PeerUnreachable)?;
+                let mut relay_socket = OnionSocket::from_stream(CircuitStream::from(stream))
+                    .with_rng(self.rng.create())
+                    .with_timeouts(timeouts);
+                let (circuit_id, peer_key) = relay_socket
+                    .initiate_handshake(key, cell_size, cipher_suites, features)
+                    .await
+                    .map_err(|_| TunnelExtendedError::PeerUnreachable)?;
+                (circuit_id, relay_socket, peer_key)
+            }
+        };
 
-        let out_circuit = Circuit::new(Circuit::random_id(), relay_socket);
+        let out_circuit = Circuit::new(circuit_id, relay_socket);
 
         Ok((out_circuit, peer_key))
     }
@@ -408,58 +845,139 @@ impl CircuitHandler {
         // match whether a message has been received or if an error occured
         match msg {
             Ok(mut msg) => {
+                let read_at = Instant::now();
+                #[cfg(feature = "research")]
+                self.tap.relayed(CellDirection::Backward, SystemTime::now());
                 // encrypt message and try to send it to socket
-                msg.encrypt(self.session_key.iter())?;
+                msg.encrypt(Direction::Backward, self.session_key.iter())?;
                 self.in_circuit
                     .socket
                     .forward_opaque(self.in_circuit.id, msg.payload)
                     .await?;
+                record_latency(&self.latency_counters, read_at);
                 Ok(())
             }
-            Err(OnionSocketError::BrokenMessage) => {
-                // NOTE: error handling will just be propagated, robustness could be improved here
-                Err(anyhow!(
-                    "Out Circuit breached protocol by sending unexpected message"
-                ))
-            }
-            Err(OnionSocketError::StreamTerminated(_)) => {
-                // NOTE: error handling will just be propagated, robustness could be improved here
-                Err(anyhow!("Out Stream terminated"))
-            }
-            Err(OnionSocketError::TeardownMessage) => {
-                // NOTE: error handling will just be propagated, robustness could be improved here
-                Err(anyhow!("Out Stream torn down"))
-            }
-            Err(e) => {
-                panic!("An unexpected error occurred during handling of the in_socket: {:?}", e);
+            // NOTE: error handling will just be propagated, robustness could be improved here
+            Err(OnionSocketError { kind, connection }) => match kind {
+                SocketErrorKind::BrokenMessage => Err(anyhow!(
+                    "Out Circuit on {} breached protocol by sending unexpected message",
+                    connection
+                )),
+                SocketErrorKind::StreamTerminated(e) => {
+                    self.teardown_reason = EndReason::Unspecified;
+                    Err(anyhow!("Out Stream on {} terminated: {}", connection, e))
+                }
+                SocketErrorKind::TeardownMessage(reason) => {
+                    // passed on to the previous hop
+                    self.teardown_reason = reason;
+                    Err(anyhow!(
+                        "Out Stream on {} torn down ({:?})",
+                        connection,
+                        reason
+                    ))
+                }
+                SocketErrorKind::UnknownCircuit(id) => Err(anyhow!(
+                    "Out Circuit on {} breached protocol by sending a cell for unknown circuit {}",
+                    connection,
+                    id
+                )),
+                kind => {
+                    panic!(
+                        "An unexpected error occurred during handling of the in_socket on {}: {:?}",
+                        connection, kind
+                    );
+                }
+            },
+        }
+    }
+
+    /// Passes data received in the endpoint state on to the tunnel without waiting for room in its
+    /// buffer, so requests are still handled if the tunnel is not read.
+    fn deliver(&mut self, data: Bytes) {
+        if let State::Endpoint { data_tx, .. } = &self.state {
+            // TODO handle closed
+            if let Err(TrySendError::Full(data)) = data_tx.try_send(data) {
+                self.pending_data = Some(data);
             }
         }
     }
 
-    /// Handles a request to send data from a higher layer in the endpoint state.
-    /// If data is None, the tunnel is no longer needed and can be destroyed.
-    /// This function takes care of handling errors and tearing down the sockets if necessary
-    async fn handle_data(&mut self, tunnel_id: TunnelId, data: Option<Bytes>) -> Result<()> {
+    /// Queues data from a higher layer in the endpoint state.
+    /// If data is None, the tunnel is no longer needed and `TUNNEL END` is queued.
+    fn queue_data(&mut self, data: Option<Bytes>) {
         match data {
             Some(data) => {
-                let circuit_id = self.in_circuit.id;
-                self.in_circuit
-                    .socket
-                    .send_data(circuit_id, tunnel_id, data, &self.session_key)
-                    .await?;
+                self.lanes.push(Lane::Data, Outgoing::Data(data));
+                // take everything else written so far, so control messages can overtake it
+                if let State::Endpoint { data_rx, .. } = &mut self.state {
+                    while let Ok(data) = data_rx.try_recv() {
+                        self.lanes.push(Lane::Data, Outgoing::Data(data));
+                    }
+                }
             }
             None => {
-                let circuit_id = self.in_circuit.id;
-                self.in_circuit
-                    .socket
-                    .end(circuit_id, tunnel_id, &self.session_key)
-                    .await?;
-                self.state = State::Default;
+                self.app_closed = true;
+                self.lanes.push(Lane::Control, Outgoing::End);
+            }
+        }
+    }
+
+    /// Sends the next queued message, or batch of data messages, in the endpoint state.
+    /// This function takes care of handling errors and tearing down the sockets if necessary
+    async fn send_next(&mut self, tunnel_id: TunnelId) -> Result<()> {
+        let (other, data) = match self.lanes.pop_outgoing(self.max_batch_size) {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        let circuit_id = self.in_circuit.id;
+        for msg in other {
+            match msg {
+                Outgoing::End => {
+                    let discarded = self.lanes.clear(Lane::Data);
+                    if let State::Endpoint { stats, .. } = &self.state {
+                        stats.record_discarded(discarded);
+                    }
+                    self.in_circuit
+                        .socket
+                        .end(circuit_id, tunnel_id, EndReason::Normal, &self.session_key)
+                        .await?;
+                    self.leave_endpoint();
+                    return Ok(());
+                }
+                Outgoing::KeepAlive => {
+                    self.in_circuit
+                        .socket
+                        .send_keep_alive(circuit_id, &self.session_key)
+                        .await?
+                }
+                // only the initiator of a tunnel pads it
+                Outgoing::Padding => {}
+                Outgoing::Data(_) => unreachable!("data is taken out of the batch"),
             }
         }
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let n_cells = data.len();
+        self.in_circuit
+            .socket
+            .send_data(circuit_id, tunnel_id, data, &self.session_key)
+            .await?;
+        if let State::Endpoint { stats, .. } = &self.state {
+            stats.record_sent(n_cells);
+        }
         Ok(())
     }
 
+    /// Returns to the default state, dropping everything queued for the tunnel.
+    fn leave_endpoint(&mut self) {
+        self.state = State::Default;
+        self.pending_data = None;
+        self.lanes = Lanes::new();
+        self.app_closed = false;
+    }
+
     async fn handle_timeout(&mut self) {
         /* Depending on the implementation of next_message, the timeout may also be
           triggered if only a partial message has been collected so far from any of the
@@ -471,25 +989,32 @@ impl CircuitHandler {
           into multiple UDP packets. The tunnel would fail if any UDP packet gets lost.
         */
         warn!("Timeout triggered, terminating CircuitHandler");
-        self.teardown_all().await;
+        self.teardown_all(EndReason::Timeout).await;
     }
 
-    async fn teardown_all(&mut self) {
-        self.teardown_in_circuit().await;
-        self.teardown_out_circuit().await;
+    async fn teardown_all(&mut self, reason: EndReason) {
+        self.teardown_in_circuit(reason).await;
+        self.teardown_out_circuit(reason).await;
     }
 
-    async fn teardown_in_circuit(&mut self) {
-        self.in_circuit.teardown_with_timeout().await;
+    async fn teardown_in_circuit(&mut self, reason: EndReason) {
+        self.in_circuit.teardown_with_timeout(reason).await;
     }
 
-    async fn teardown_out_circuit(&mut self) {
+    async fn teardown_out_circuit(&mut self, reason: EndReason) {
         if let State::Router { out_circuit } = &mut self.state {
-            out_circuit.teardown_with_timeout().await;
+            out_circuit.teardown_with_timeout(reason).await;
         }
     }
 }
 
+/// Records the time since a relayed cell was read in `counters`, if set.
+fn record_latency(counters: &Option<Arc<RelayCounters>>, read_at: Instant) {
+    if let Some(counters) = counters {
+        counters.forwarding_latency.record(read_at.elapsed());
+    }
+}
+
 impl fmt::Debug for Circuit {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_tuple("Circuit").field(&self.id).finish()
@@ -517,6 +1042,10 @@ impl fmt::Debug for State {
                 .debug_struct("Endpoint")
                 .field("tunnel_id", tunnel_id)
                 .finish(),
+            State::Terminal { tunnel_id } => f
+                .debug_struct("Terminal")
+                .field("tunnel_id", tunnel_id)
+                .finish(),
         }
     }
 }
@@ -0,0 +1,123 @@
+//! Concurrent builds to the same destination which share a single tunnel, see
+//! [`OnionContext::build_tunnel_coalesced`](crate::OnionContext::build_tunnel_coalesced).
+
+use crate::onion::crypto::CipherSuites;
+use crate::onion::{CellSize, TunnelOptions, TunnelWriter};
+use crate::{Capabilities, Fingerprint, Peer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tokio::time::Duration;
+
+/// The destination and options of a build, which have to be equal for builds to share a tunnel.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct BuildKey {
+    dest: Fingerprint,
+    required_capabilities: Capabilities,
+    hops: BTreeMap<usize, Fingerprint>,
+    cell_size: CellSize,
+    cipher_suites: CipherSuites,
+    padding_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    strict: Option<bool>,
+    excluded_addrs: BTreeSet<SocketAddr>,
+    excluded_fingerprints: BTreeSet<Fingerprint>,
+    write_feedback: bool,
+}
+
+impl BuildKey {
+    /// Returns `None` if the options can not be compared with those of other builds, i.e. they
+    /// set a peer provider or a hop filter.
+    pub(crate) fn new(dest: &Peer, options: &TunnelOptions) -> Option<Self> {
+        if options.peer_provider.is_some() || options.hop_filter.is_some() {
+            return None;
+        }
+        Some(BuildKey {
+            dest: dest.fingerprint(),
+            required_capabilities: options.required_capabilities,
+            hops: options.hops.clone(),
+            cell_size: options.cell_size,
+            cipher_suites: options.cipher_suites,
+            padding_interval: options.padding_interval,
+            idle_timeout: options.idle_timeout,
+            strict: options.strict,
+            excluded_addrs: options.excluded_addrs.clone(),
+            excluded_fingerprints: options.excluded_fingerprints.clone(),
+            write_feedback: options.write_feedback,
+        })
+    }
+}
+
+/// the builds waiting for a build in flight
+type Waiting = Vec<oneshot::Sender<TunnelWriter>>;
+
+/// The builds in flight which other builds may wait for, together with the waiting builds.
+#[derive(Clone, Default)]
+pub(crate) struct PendingBuilds {
+    builds: Arc<Mutex<Vec<(BuildKey, Waiting)>>>,
+}
+
+/// The role of a build in [`PendingBuilds`].
+pub(crate) enum Pending {
+    /// No equal build is in flight, so this build has to be run. Waiting builds are answered by
+    /// [`PendingBuild::finish`].
+    First(PendingBuild),
+    /// An equal build is in flight, whose tunnel is sent unless the build fails.
+    Waiting(oneshot::Receiver<TunnelWriter>),
+}
+
+impl PendingBuilds {
+    /// Registers a build with `key`, waiting for an equal build if one is in flight.
+    pub(crate) fn enter(&self, key: BuildKey) -> Pending {
+        let mut builds = self.builds.lock().unwrap();
+        if let Some((_, waiting)) = builds.iter_mut().find(|(k, _)| *k == key) {
+            let (tx, rx) = oneshot::channel();
+            waiting.push(tx);
+            return Pending::Waiting(rx);
+        }
+        builds.push((key.clone(), Vec::new()));
+        Pending::First(PendingBuild {
+            builds: self.clone(),
+            key,
+            tunnel: None,
+        })
+    }
+
+    fn remove(&self, key: &BuildKey) -> Waiting {
+        let mut builds = self.builds.lock().unwrap();
+        match builds.iter().position(|(k, _)| k == key) {
+            Some(i) => builds.remove(i).1,
+            None => Vec::new(),
+        }
+    }
+}
+
+/// A build in flight, which is removed from the [`PendingBuilds`] once finished or dropped.
+///
+/// Builds waiting for a build which failed or was dropped are not answered, so they build a
+/// tunnel on their own.
+pub(crate) struct PendingBuild {
+    builds: PendingBuilds,
+    key: BuildKey,
+    tunnel: Option<TunnelWriter>,
+}
+
+impl PendingBuild {
+    /// Hands a write handle of `tunnel` to every build waiting for this one, if the build
+    /// succeeded.
+    pub(crate) fn finish(mut self, tunnel: Option<TunnelWriter>) {
+        self.tunnel = tunnel;
+    }
+}
+
+impl Drop for PendingBuild {
+    fn drop(&mut self) {
+        let waiting = self.builds.remove(&self.key);
+        if let Some(tunnel) = &self.tunnel {
+            for tx in waiting {
+                let _ = tx.send(tunnel.clone());
+            }
+        }
+    }
+}
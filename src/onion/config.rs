@@ -0,0 +1,317 @@
+//! Configuration of tunnels, see [`TunnelOptions`].
+//!
+//! The types are re-exported at the root of the crate.
+
+use crate::onion::crypto::CipherSuites;
+use crate::onion::observer::Observer;
+use crate::onion::rng::RngFactory;
+use crate::onion::tunnel::RotationPolicy;
+use crate::{Capabilities, Fingerprint, Peer, PeerProvider};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+pub use crate::onion::crypto::CipherSuite;
+pub use crate::onion::protocol::CellSize;
+
+/// time a single hop may take to connect and complete its handshake
+const DEFAULT_HOP_TIMEOUT: Duration = Duration::from_secs(5);
+/// time building the whole path of a tunnel may take
+const DEFAULT_BUILD_DEADLINE: Duration = Duration::from_secs(30);
+/// time a read on a socket may take while a reply is awaited
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// time a single write on a socket may take
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How the path of a tunnel is replaced at the end of a round.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RotationStrategy {
+    /// A new path is built in the background and the tunnel is switched over to it. The previous
+    /// path is torn down afterwards.
+    #[default]
+    Rebuild,
+    /// The current path is truncated to its first `keep_hops` hops, which are extended with fresh
+    /// hops and the destination. If this fails, a new path is built instead.
+    ///
+    /// This saves the handshakes with the kept hops, but data is paused until the path is
+    /// extended again. The destination only waits about a second for the new path, so splicing
+    /// is meant for paths with a low latency. At least one hop is kept and the destination is
+    /// always replaced.
+    Splice { keep_hops: usize },
+}
+
+/// Timeouts applied while building the path of a tunnel, see
+/// [`OnionBuilder::set_build_timeouts`](crate::OnionBuilder::set_build_timeouts).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BuildTimeouts {
+    /// The time a single hop may take to connect and complete its handshake, not counting the
+    /// wait for other handshakes with the same peer. A hop which times out counts as failed and
+    /// another peer is chosen. Defaults to 5 seconds.
+    pub hop: Duration,
+    /// The time building the whole path may take, including failed hops, after which building
+    /// fails with [`BuildTimedOut`](crate::BuildTimedOut). Defaults to 30 seconds.
+    pub deadline: Duration,
+}
+
+impl Default for BuildTimeouts {
+    fn default() -> Self {
+        BuildTimeouts {
+            hop: DEFAULT_HOP_TIMEOUT,
+            deadline: DEFAULT_BUILD_DEADLINE,
+        }
+    }
+}
+
+/// Timeouts applied to the reads and writes on the connections to other peers, see
+/// [`OnionBuilder::set_socket_timeouts`](crate::OnionBuilder::set_socket_timeouts).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SocketTimeouts {
+    /// The time waiting for the reply to a handshake, an extension or a truncation may take. A
+    /// peer which does not answer in time counts as failed. Defaults to 5 seconds.
+    pub read: Duration,
+    /// The time a single write may take. A peer which stopped reading counts as failed once its
+    /// buffers are full and a write made no progress for this long. Defaults to 2 seconds.
+    pub write: Duration,
+}
+
+impl Default for SocketTimeouts {
+    fn default() -> Self {
+        SocketTimeouts {
+            read: DEFAULT_READ_TIMEOUT,
+            write: DEFAULT_WRITE_TIMEOUT,
+        }
+    }
+}
+
+/// The settings of an onion router, which [`OnionBuilder::start`](crate::OnionBuilder::start)
+/// collects after validating them and passes to the new `OnionContext`.
+#[derive(Clone, Default)]
+pub(crate) struct ContextSettings {
+    pub(crate) n_hops: usize,
+    pub(crate) rotation_policy: RotationPolicy,
+    pub(crate) stall_threshold: Duration,
+    pub(crate) enable_cover: bool,
+    pub(crate) build_reports: bool,
+    pub(crate) padding_interval: Duration,
+    pub(crate) idle_timeout: Duration,
+    pub(crate) build_timeouts: BuildTimeouts,
+    pub(crate) diagnosis_budget: Duration,
+    pub(crate) max_handshakes_per_peer: usize,
+    pub(crate) strict: bool,
+    pub(crate) observer: Observer,
+    pub(crate) rng: RngFactory,
+    /// the connections to first hops are only shared if this is not zero
+    pub(crate) first_hop_connection_idle_timeout: Duration,
+    pub(crate) socket_timeouts: SocketTimeouts,
+    pub(crate) max_batch_size: usize,
+    pub(crate) shutdown_timeout: Duration,
+    /// `None` in client-only mode
+    pub(crate) local_addr: Option<SocketAddr>,
+    pub(crate) n_guards: usize,
+    pub(crate) imported_guards: Vec<Peer>,
+}
+
+/// Per-tunnel configuration used by
+/// [`OnionContext::build_tunnel_with_options`](crate::OnionContext::build_tunnel_with_options).
+#[derive(Clone, Debug, Default)]
+pub struct TunnelOptions {
+    pub(crate) required_capabilities: Capabilities,
+    pub(crate) hops: BTreeMap<usize, Fingerprint>,
+    pub(crate) cell_size: CellSize,
+    pub(crate) cipher_suites: CipherSuites,
+    pub(crate) peer_provider: Option<PeerProvider>,
+    pub(crate) padding_interval: Option<Duration>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) strict: Option<bool>,
+    pub(crate) hop_filter: Option<HopFilter>,
+    pub(crate) excluded_addrs: BTreeSet<SocketAddr>,
+    pub(crate) excluded_fingerprints: BTreeSet<Fingerprint>,
+    pub(crate) write_feedback: bool,
+}
+
+/// A filter vetoing peers as hops of a tunnel, see [`TunnelOptions::set_hop_filter`].
+#[derive(Clone)]
+pub(crate) struct HopFilter(Arc<dyn Fn(&Peer) -> bool + Send + Sync>);
+
+impl HopFilter {
+    pub(crate) fn accepts(&self, peer: &Peer) -> bool {
+        (self.0)(peer)
+    }
+}
+
+impl fmt::Debug for HopFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HopFilter")
+    }
+}
+
+impl TunnelOptions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the capabilities every hop of the tunnel must support.
+    ///
+    /// Peers which are known to lack any of these capabilities are skipped when choosing
+    /// intermediate hops. Building fails if the destination peer is known to lack them.
+    /// By default no capabilities are required.
+    pub fn require_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.required_capabilities = capabilities;
+        self
+    }
+
+    /// Requires the first hop of the tunnel to be the peer with the given fingerprint.
+    ///
+    /// See [`TunnelOptions::set_hop`].
+    pub fn set_first_hop(self, fingerprint: Fingerprint) -> Self {
+        self.set_hop(0, fingerprint)
+    }
+
+    /// Requires the hop at `position` to be the peer with the given fingerprint.
+    ///
+    /// The peer must have been registered with
+    /// [`OnionContext::add_known_peer`](crate::OnionContext::add_known_peer). Hops are counted from
+    /// zero, so the first hop has position 0. When building a tunnel to a given destination peer,
+    /// only the intermediate hops can be constrained. Building the tunnel fails with a
+    /// [`HopSelectionError`](crate::HopSelectionError) if the constraint can not be met.
+    pub fn set_hop(mut self, position: usize, fingerprint: Fingerprint) -> Self {
+        self.hops.insert(position, fingerprint);
+        self
+    }
+
+    /// Sets the cell size used on all circuits of the tunnel.
+    ///
+    /// The cell size is negotiated with every hop, so building fails on hops which do not
+    /// support it. Defaults to [`CellSize::Standard`].
+    pub fn set_cell_size(mut self, cell_size: CellSize) -> Self {
+        self.cell_size = cell_size;
+        self
+    }
+
+    /// Sets the cipher suites offered to every hop of the tunnel.
+    ///
+    /// Each hop selects the strongest offered suite it supports. Building fails on hops which
+    /// support none of them or select a weaker suite. By default all suites are offered.
+    pub fn set_cipher_suites(mut self, cipher_suites: &[CipherSuite]) -> Self {
+        self.cipher_suites = CipherSuites::from_slice(cipher_suites);
+        self
+    }
+
+    /// Draws the random hops of the tunnel from `peer_provider` instead of the provider of the
+    /// onion router, e.g. to restrict a tunnel to a pool of trusted relays.
+    ///
+    /// The override applies to every rotation of the tunnel, so all of its paths are drawn from
+    /// the same pool, while other tunnels keep using the provider of the onion router. Neither is
+    /// affected by the other being replaced with
+    /// [`OnionContext::replace_peer_provider`](crate::OnionContext::replace_peer_provider). Hops
+    /// constrained by [`TunnelOptions::set_hop`] are not drawn from any provider.
+    ///
+    /// Without an override, every rotation draws from the provider of the onion router at that
+    /// time, so the paths of a tunnel only come from different pools if the provider was
+    /// replaced in between.
+    pub fn set_peer_provider(mut self, peer_provider: PeerProvider) -> Self {
+        self.peer_provider = Some(peer_provider);
+        self
+    }
+
+    /// Sets the interval in which padding is sent on this tunnel while the application sends no
+    /// data, overriding
+    /// [`OnionBuilder::set_padding_interval`](crate::OnionBuilder::set_padding_interval) for this
+    /// tunnel.
+    ///
+    /// Zero disables padding for this tunnel.
+    pub fn set_padding_interval(mut self, dur: Duration) -> Self {
+        self.padding_interval = Some(dur);
+        self
+    }
+
+    /// Sets the time after which this tunnel is closed if no data was sent over it, overriding
+    /// [`OnionBuilder::set_tunnel_idle_timeout`](crate::OnionBuilder::set_tunnel_idle_timeout) for
+    /// this tunnel.
+    ///
+    /// Zero keeps this tunnel open however long it is idle.
+    pub fn set_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets whether building this tunnel fails with a [`StrictViolation`](crate::StrictViolation)
+    /// instead of weakening its path, overriding
+    /// [`OnionBuilder::enable_strict_mode`](crate::OnionBuilder::enable_strict_mode) for this
+    /// tunnel.
+    pub fn set_strict(mut self, strict: bool) -> Self {
+        self.strict = Some(strict);
+        self
+    }
+
+    /// Sets a filter which every hop of the tunnel must pass, e.g. to never route the tunnel
+    /// through the relays of a given operator.
+    ///
+    /// The filter is called for every peer considered as a hop, including hops constrained by
+    /// [`TunnelOptions::set_hop`], and applies to every rotation of the tunnel. A destination
+    /// given by the application is not filtered. Rejected peers are counted in
+    /// [`TunnelStats::rejected_hops`](crate::TunnelStats::rejected_hops). If the filter rejects
+    /// every peer drawn for a hop, building fails with
+    /// [`NoAcceptablePeers`](crate::NoAcceptablePeers), or with a
+    /// [`HopSelectionError`](crate::HopSelectionError) for a constrained hop.
+    ///
+    /// Like a [`StateObserver`](crate::StateObserver), the filter is called inline while building,
+    /// so it has to be cheap and must not block.
+    pub fn set_hop_filter(mut self, filter: Arc<dyn Fn(&Peer) -> bool + Send + Sync>) -> Self {
+        self.hop_filter = Some(HopFilter(filter));
+        self
+    }
+
+    /// Never routes the tunnel through the peer listening on `addr`, e.g. to avoid relays in the
+    /// same network as this onion router.
+    ///
+    /// Excluded peers are treated like peers rejected by the hop filter, see
+    /// [`TunnelOptions::set_hop_filter`], so the exclusion applies to every rotation of the
+    /// tunnel, but not to a destination given by the application.
+    pub fn exclude_address(mut self, addr: SocketAddr) -> Self {
+        self.excluded_addrs.insert(addr);
+        self
+    }
+
+    /// Never routes the tunnel through the peer with the given host key fingerprint, see
+    /// [`TunnelOptions::exclude_address`].
+    pub fn exclude_fingerprint(mut self, fingerprint: Fingerprint) -> Self {
+        self.excluded_fingerprints.insert(fingerprint);
+        self
+    }
+
+    /// Sets whether the tunnel reports when the messages written to it with
+    /// [`Tunnel::write_tracked`](crate::Tunnel::write_tracked) were actually sent, see
+    /// [`Tunnel::write_feedback`](crate::Tunnel::write_feedback).
+    ///
+    /// This allows pacing above the tunnel, e.g. a congestion control of the application. Without
+    /// it, which is the default, no messages are tracked.
+    pub fn enable_write_feedback(mut self, enable: bool) -> Self {
+        self.write_feedback = enable;
+        self
+    }
+
+    /// Returns whether `peer` is not excluded and the hop filter, if any, accepts it.
+    pub(crate) fn accepts_hop(&self, peer: &Peer) -> bool {
+        !self.is_excluded(peer)
+            && self
+                .hop_filter
+                .as_ref()
+                .is_none_or(|filter| filter.accepts(peer))
+    }
+
+    fn is_excluded(&self, peer: &Peer) -> bool {
+        self.excluded_addrs.contains(&peer.addr)
+            || (!self.excluded_fingerprints.is_empty()
+                && self.excluded_fingerprints.contains(&peer.fingerprint()))
+    }
+
+    pub(crate) fn is_strict(&self) -> bool {
+        self.strict == Some(true)
+    }
+}
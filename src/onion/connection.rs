@@ -0,0 +1,915 @@
+//! Connections carrying the circuits of several tunnels, see [`SharedConnection`].
+//!
+//! A relay would otherwise open a TCP connection for every circuit it extends, even if many
+//! circuits lead to the same next hop. Likewise, an onion router rotating its tunnels every round
+//! would open a connection for every path, even if all paths start at the same entry guard.
+//! Instead, the circuits to a peer share a connection from a [`ConnectionCache`] and are told
+//! apart by their circuit ids, which only need to be unique per connection.
+//!
+//! Every message on a shared connection is [`MESSAGE_SIZE`] bytes long, so the stream is split
+//! into messages which are routed to their circuits by the id in their header, without parsing
+//! the rest. Circuits with larger cells can not share a connection: the size of a `TEARDOWN` on
+//! such a circuit depends on whether its handshake completed, which the router does not know.
+//! They keep a connection of their own.
+//!
+//! A circuit whose consumer stalls must not stall the other circuits on its connection, so every
+//! circuit only gets a share of the connection's buffers:
+//! - Received messages are buffered per circuit. Once the buffer of a circuit is full, further
+//!   messages are kept in a backlog of the circuit instead of blocking the connection. A circuit
+//!   which overflows its backlog or does not read for [`STALL_TIMEOUT`] while messages are
+//!   waiting is torn down.
+//! - A circuit may only occupy [`CIRCUIT_WRITE_QUOTA`] slots of the write buffer of the
+//!   connection. Further writes wait for the circuit's own writes to complete, so they remain in
+//!   the queue of the circuit.
+
+use crate::onion::circuit::{CircuitId, CircuitIds};
+use crate::onion::config::SocketTimeouts;
+use crate::onion::protocol::{
+    CellSize, CircuitHeader, CircuitTeardown, EndReason, ToBytesExt, MESSAGE_SIZE,
+};
+use crate::onion::rng::{OnionRng, RngFactory};
+use crate::onion::stats::{ConnectionQueue, QueueDepth};
+use crate::onion::RelayCounters;
+use crate::task;
+
+use bytes::{Bytes, BytesMut};
+use log::{debug, warn};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::SendError, error::TrySendError, OwnedPermit};
+use tokio::sync::{AcquireError, Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Duration, Instant};
+
+/// number of received messages buffered for a single circuit
+const CIRCUIT_BUFFER_SIZE: usize = 64;
+/// number of received messages kept for a single circuit whose buffer is full, which bounds the
+/// memory taken by a circuit whose data is sent faster than it is read
+const CIRCUIT_BACKLOG_SIZE: usize = 1024;
+/// time a circuit may not read while messages are waiting in its backlog
+const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+/// number of writes buffered for a shared connection
+const WRITE_BUFFER_SIZE: usize = 64;
+/// number of writes a single circuit may have buffered for a shared connection
+const CIRCUIT_WRITE_QUOTA: usize = WRITE_BUFFER_SIZE / 4;
+/// interval in which the start of a new incoming connection is checked until its header arrived
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Returns whether circuits with cells of `cell_size` may share a connection.
+pub(crate) fn is_shareable(cell_size: CellSize) -> bool {
+    cell_size.bytes() == MESSAGE_SIZE
+}
+
+/// The stream underlying the socket of a circuit.
+#[derive(Debug)]
+pub(crate) enum CircuitStream {
+    /// A connection used by this circuit only.
+    Tcp(TcpStream),
+    /// This circuit's part of a [`SharedConnection`].
+    Shared(SharedStream),
+}
+
+impl CircuitStream {
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            CircuitStream::Tcp(stream) => stream.peer_addr(),
+            CircuitStream::Shared(stream) => Ok(stream.connection.peer_addr),
+        }
+    }
+
+    /// Returns the connection this stream is part of. A dedicated connection is assigned a new
+    /// id on every call, so this is only called once, when the socket is created.
+    pub(crate) fn connection(&self) -> ConnectionInfo {
+        match self {
+            CircuitStream::Tcp(stream) => ConnectionInfo::of(stream),
+            CircuitStream::Shared(stream) => stream.connection.info,
+        }
+    }
+}
+
+/// source of the ids of all connections of this process
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Identifies the connection underlying an `OnionSocket` in errors and logs.
+///
+/// All circuits on a shared connection report the same connection.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ConnectionInfo {
+    /// unique among the connections of this process, assigned in increasing order
+    pub(crate) id: u64,
+    pub(crate) local_addr: Option<SocketAddr>,
+    pub(crate) peer_addr: Option<SocketAddr>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(local_addr: Option<SocketAddr>, peer_addr: Option<SocketAddr>) -> Self {
+        ConnectionInfo {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            local_addr,
+            peer_addr,
+        }
+    }
+
+    pub(crate) fn of(stream: &TcpStream) -> Self {
+        ConnectionInfo::new(stream.local_addr().ok(), stream.peer_addr().ok())
+    }
+}
+
+impl fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn addr(addr: Option<SocketAddr>) -> String {
+            addr.map_or_else(|| "?".to_string(), |addr| addr.to_string())
+        }
+        write!(
+            f,
+            "connection {} ({} -> {})",
+            self.id,
+            addr(self.local_addr),
+            addr(self.peer_addr)
+        )
+    }
+}
+
+impl From<TcpStream> for CircuitStream {
+    fn from(stream: TcpStream) -> Self {
+        CircuitStream::Tcp(stream)
+    }
+}
+
+impl AsyncRead for CircuitStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            CircuitStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            CircuitStream::Shared(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for CircuitStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            CircuitStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            CircuitStream::Shared(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            CircuitStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            CircuitStream::Shared(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            CircuitStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            CircuitStream::Shared(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The part of a [`SharedConnection`] used by a single circuit.
+///
+/// Reading yields the messages routed to the circuit. Every write is passed on to the connection
+/// as a whole, so the messages of different circuits are never interleaved. Once the connection
+/// fails, reads and writes return the error which closed it. Once the circuit has been torn down
+/// for exceeding its share of the connection, reads return an error after the buffered messages
+/// and writes fail right away.
+///
+/// The circuit id is released when the stream is dropped.
+pub(crate) struct SharedStream {
+    circuit_id: CircuitId,
+    /// distinguishes this circuit from a later circuit reusing its id
+    route: u64,
+    messages: mpsc::Receiver<Bytes>,
+    /// the rest of the message read last
+    unread: Bytes,
+    writes: mpsc::Sender<Write>,
+    reserving: Option<Pin<Box<PermitFuture>>>,
+    /// the slots of the write buffer this circuit may occupy
+    quota: Arc<Semaphore>,
+    acquiring: Option<Pin<Box<QuotaFuture>>>,
+    /// the slot taken for the next write
+    slot: Option<OwnedSemaphorePermit>,
+    connection: Arc<Connection>,
+}
+
+type PermitFuture = dyn Future<Output = Result<OwnedPermit<Write>, SendError<()>>> + Send + 'static;
+type QuotaFuture = dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>> + Send + 'static;
+
+impl SharedStream {
+    pub(crate) fn circuit_id(&self) -> CircuitId {
+        self.circuit_id
+    }
+}
+
+impl AsyncRead for SharedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.unread.is_empty() {
+            match this.messages.poll_recv(cx) {
+                Poll::Ready(Some(msg)) => {
+                    this.unread = msg;
+                    this.connection.refill(this.circuit_id, this.route);
+                }
+                Poll::Ready(None) => {
+                    return Poll::Ready(this.connection.end_of_route(this.circuit_id, this.route))
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = cmp::min(buf.remaining(), this.unread.len());
+        buf.put_slice(&this.unread.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SharedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.connection.is_shed(this.circuit_id, this.route) {
+            return Poll::Ready(Err(shed_error(this.circuit_id)));
+        }
+        if this.slot.is_none() {
+            let quota = this.quota.clone();
+            let acquiring = this
+                .acquiring
+                .get_or_insert_with(|| Box::pin(quota.acquire_owned()));
+            let res = match acquiring.as_mut().poll(cx) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return Poll::Pending,
+            };
+            this.acquiring = None;
+            this.slot = Some(res.expect("quota of a circuit closed"));
+        }
+        let writes = this.writes.clone();
+        let reserving = this
+            .reserving
+            .get_or_insert_with(|| Box::pin(writes.reserve_owned()));
+        let res = match reserving.as_mut().poll(cx) {
+            Poll::Ready(res) => res,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.reserving = None;
+        match res {
+            Ok(permit) => {
+                permit.send(Write {
+                    buf: Bytes::copy_from_slice(buf),
+                    _slot: this.slot.take(),
+                });
+                this.connection.queued();
+                Poll::Ready(Ok(buf.len()))
+            }
+            Err(_) => Poll::Ready(Err(this.connection.error())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for SharedStream {
+    fn drop(&mut self) {
+        self.connection.release(self.circuit_id, self.route);
+    }
+}
+
+impl fmt::Debug for SharedStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedStream")
+            .field("circuit_id", &self.circuit_id)
+            .field("peer_addr", &self.connection.peer_addr)
+            .finish()
+    }
+}
+
+/// A connection to a peer carrying many circuits, see the [module documentation](self).
+///
+/// The connection is served by a task, which closes it once it fails or no circuit used it for
+/// the idle timeout.
+#[derive(Clone)]
+pub(crate) struct SharedConnection {
+    connection: Arc<Connection>,
+}
+
+impl SharedConnection {
+    /// Connects to the peer at `addr` for initiating circuits on the connection.
+    ///
+    /// If `counters` is set, the connection is counted in [`RelayStats`](crate::RelayStats) until
+    /// it is closed. The ids of the circuits opened on the connection are drawn from `rng`. A write
+    /// taking longer than the write timeout of `timeouts` closes the connection.
+    pub(crate) async fn connect(
+        addr: SocketAddr,
+        idle_timeout: Duration,
+        counters: Option<Arc<RelayCounters>>,
+        rng: Box<dyn OnionRng>,
+        timeouts: SocketTimeouts,
+    ) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr).await?;
+        if let Some(counters) = &counters {
+            counters.relay_connections.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(Connection::spawn(
+            stream,
+            addr,
+            idle_timeout,
+            None,
+            counters,
+            CircuitIds::new(rng),
+            timeouts,
+        ))
+    }
+
+    /// Opens a new circuit on this connection and allocates a free circuit id for it.
+    ///
+    /// Returns `None` if the connection has been closed or all circuit ids are in use.
+    pub(crate) fn open(&self) -> Option<(CircuitId, SharedStream)> {
+        let mut state = self.connection.state.lock().unwrap();
+        if state.closed.is_some() {
+            return None;
+        }
+        let circuit_id = state.ids.allocate()?;
+        let stream = self.connection.add_route(&mut state, circuit_id);
+        Some((circuit_id, stream))
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.connection.closed().is_some()
+    }
+}
+
+/// An incoming connection as classified by [`accept`].
+pub(crate) enum Accepted {
+    /// The connection carries a single circuit whose cells can not share a connection, or does
+    /// not start with a `CIRCUIT CREATE` message. It is handled like before connections were
+    /// shared.
+    Dedicated(TcpStream),
+    /// The connection may carry many circuits. The stream of every circuit created by the peer is
+    /// received in order, the channel closes along with the connection.
+    Shared(mpsc::UnboundedReceiver<SharedStream>),
+}
+
+/// Waits for the header of the first message on an incoming connection and classifies the
+/// connection.
+///
+/// A shared connection is closed once it carried no circuit for `idle_timeout`. This should
+/// exceed the idle timeout of the initiating peer, so the initiating peer is the one closing it.
+/// The header has to arrive within the read timeout of `timeouts`, like a reply read from an
+/// `OnionSocket`.
+pub(crate) async fn accept(
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    idle_timeout: Duration,
+    timeouts: SocketTimeouts,
+) -> io::Result<Accepted> {
+    let mut header = [0u8; CircuitHeader::SIZE];
+    let peek = async {
+        loop {
+            match stream.peek(&mut header).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n if n < CircuitHeader::SIZE => time::sleep(PEEK_INTERVAL).await,
+                _ => return io::Result::Ok(()),
+            }
+        }
+    };
+    time::timeout(timeouts.read, peek)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no message received"))??;
+    let header = CircuitHeader::peek(&header);
+    let shareable = header.is_create() && header.requested_cell_size().is_none_or(is_shareable);
+    if !shareable {
+        return Ok(Accepted::Dedicated(stream));
+    }
+
+    let (accepted_tx, accepted_rx) = mpsc::unbounded_channel();
+    Connection::spawn(
+        stream,
+        peer_addr,
+        idle_timeout,
+        Some(accepted_tx),
+        None,
+        Default::default(),
+        timeouts,
+    );
+    Ok(Accepted::Shared(accepted_rx))
+}
+
+/// Returns the error of a circuit torn down for exceeding its share of the connection.
+fn shed_error(circuit_id: CircuitId) -> io::Error {
+    io::Error::other(format!(
+        "circuit {} exceeded its share of the shared connection",
+        circuit_id
+    ))
+}
+
+/// A write passed on to the task serving a shared connection.
+struct Write {
+    buf: Bytes,
+    /// the slot of the circuit's write quota, released once written
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+/// The state of a shared connection, shared by the task serving it and the circuits using it.
+struct Connection {
+    peer_addr: SocketAddr,
+    info: ConnectionInfo,
+    state: Mutex<ConnectionState>,
+    writes: mpsc::Sender<Write>,
+    /// set if the connection is counted in `RelayStats`
+    counters: Option<Arc<RelayCounters>>,
+    /// the length of the write queue, set if the connection is counted in `RelayStats`
+    queue_depth: Option<Arc<QueueDepth>>,
+    timeouts: SocketTimeouts,
+}
+
+struct ConnectionState {
+    /// the channels of the circuits on this connection
+    routes: HashMap<CircuitId, Route>,
+    /// ids of the circuits initiated on this connection, ids chosen by the peer are only routed
+    ids: CircuitIds,
+    next_route: u64,
+    /// set while no circuit uses the connection
+    idle_since: Option<Instant>,
+    /// the error which closed the connection, set once
+    closed: Option<(io::ErrorKind, String)>,
+}
+
+struct Route {
+    id: u64,
+    /// `None` once the circuit has been torn down for exceeding its share
+    messages: Option<mpsc::Sender<Bytes>>,
+    /// messages received while the buffer of the circuit was full, oldest first
+    backlog: VecDeque<Bytes>,
+    /// set while messages are waiting in the backlog, since the circuit last read
+    stalled_since: Option<Instant>,
+}
+
+impl Route {
+    fn is_shed(&self) -> bool {
+        self.messages.is_none()
+    }
+
+    /// Passes `msg` on to the circuit without waiting for room in its buffer.
+    ///
+    /// Fails with the reason if the circuit persistently exceeds its share of the connection.
+    fn deliver(&mut self, msg: Bytes) -> Result<(), &'static str> {
+        let messages = match &self.messages {
+            Some(messages) => messages,
+            None => return Ok(()),
+        };
+        if self.backlog.is_empty() {
+            match messages.try_send(msg) {
+                // a closed channel belongs to a stream which is being dropped
+                Ok(()) | Err(TrySendError::Closed(_)) => return Ok(()),
+                Err(TrySendError::Full(msg)) => {
+                    self.backlog.push_back(msg);
+                    self.stalled_since = Some(Instant::now());
+                    return Ok(());
+                }
+            }
+        }
+        self.backlog.push_back(msg);
+        if self.backlog.len() > CIRCUIT_BACKLOG_SIZE {
+            Err("backlog overflowed")
+        } else if self
+            .stalled_since
+            .is_some_and(|since| since.elapsed() >= STALL_TIMEOUT)
+        {
+            Err("circuit stalled")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Moves messages from the backlog into the buffer of the circuit, after the circuit read.
+    fn refill(&mut self) {
+        let messages = match &self.messages {
+            Some(messages) if !self.backlog.is_empty() => messages,
+            _ => return,
+        };
+        while let Some(msg) = self.backlog.pop_front() {
+            if let Err(TrySendError::Full(msg)) = messages.try_send(msg) {
+                self.backlog.push_front(msg);
+                break;
+            }
+        }
+        self.stalled_since = match self.backlog.is_empty() {
+            true => None,
+            false => Some(Instant::now()),
+        };
+    }
+
+    /// Stops passing messages on to the circuit, whose stream fails once it read the buffered
+    /// messages.
+    fn shed(&mut self) {
+        self.messages = None;
+        self.backlog.clear();
+        self.stalled_since = None;
+    }
+}
+
+impl Connection {
+    /// Spawns the task serving `stream`. If `accepted` is set, the peer may create circuits on
+    /// the connection, which are passed on to `accepted`.
+    fn spawn(
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+        idle_timeout: Duration,
+        accepted: Option<mpsc::UnboundedSender<SharedStream>>,
+        counters: Option<Arc<RelayCounters>>,
+        ids: CircuitIds,
+        timeouts: SocketTimeouts,
+    ) -> SharedConnection {
+        let (writes_tx, writes_rx) = mpsc::channel(WRITE_BUFFER_SIZE);
+        let queue = counters
+            .as_ref()
+            .map(|counters| ConnectionQueue::new(peer_addr, counters.clone()));
+        let connection = Arc::new(Connection {
+            peer_addr,
+            info: ConnectionInfo::new(stream.local_addr().ok(), Some(peer_addr)),
+            state: Mutex::new(ConnectionState {
+                routes: HashMap::new(),
+                ids,
+                next_route: 0,
+                idle_since: Some(Instant::now()),
+                closed: None,
+            }),
+            writes: writes_tx,
+            counters,
+            queue_depth: queue.as_ref().map(ConnectionQueue::depth),
+            timeouts,
+        });
+        task::spawn("task.shared_connection", {
+            let connection = connection.clone();
+            async move {
+                // listed until the connection is closed
+                let _queue = queue;
+                connection
+                    .serve(stream, writes_rx, accepted, idle_timeout)
+                    .await
+            }
+        });
+        SharedConnection { connection }
+    }
+
+    async fn serve(
+        self: Arc<Self>,
+        stream: TcpStream,
+        writes: mpsc::Receiver<Write>,
+        accepted: Option<mpsc::UnboundedSender<SharedStream>>,
+        idle_timeout: Duration,
+    ) {
+        let (reader, writer) = stream.into_split();
+        let res = tokio::select! {
+            res = self.read_messages(reader, accepted) => res,
+            res = self.write_messages(writer, writes) => res,
+            _ = self.close_when_idle(idle_timeout) => Ok(()),
+        };
+        if let Err(e) = res {
+            warn!("Shared {} failed: {}", self.info, e);
+            self.close(e);
+        }
+        debug!("Closed shared {}", self.info);
+        if let Some(counters) = &self.counters {
+            counters.relay_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Reads messages and routes them to their circuits until the connection fails.
+    async fn read_messages(
+        self: &Arc<Self>,
+        mut reader: OwnedReadHalf,
+        accepted: Option<mpsc::UnboundedSender<SharedStream>>,
+    ) -> io::Result<()> {
+        loop {
+            let mut msg = BytesMut::new();
+            msg.resize(MESSAGE_SIZE, 0);
+            reader.read_exact(&mut msg).await?;
+            let created = self.route(msg.freeze(), accepted.is_some());
+            // outside of the lock, a stream dropped by a closed channel releases its route
+            if let (Some(stream), Some(accepted)) = (created, &accepted) {
+                let _ = accepted.send(stream);
+            }
+        }
+    }
+
+    /// Passes `msg` on to the circuit it belongs to.
+    ///
+    /// If the peer may create circuits and `msg` starts a new one, the stream of the new circuit
+    /// is returned.
+    fn route(self: &Arc<Self>, msg: Bytes, accepting: bool) -> Option<SharedStream> {
+        let header = CircuitHeader::peek(&msg);
+        let mut state = self.state.lock().unwrap();
+        let circuit_id = header.circuit_id;
+        match state.routes.get_mut(&circuit_id) {
+            // the peer reuses the id of a circuit torn down by this onion router
+            Some(route) if route.is_shed() && accepting && header.is_create() => {}
+            Some(route) => {
+                let shed = route.is_shed();
+                if let Err(reason) = route.deliver(msg) {
+                    warn!(
+                        "Tearing down circuit {} on the shared connection to {}: {}",
+                        circuit_id, self.peer_addr, reason
+                    );
+                    route.shed();
+                    self.send_teardown(circuit_id, EndReason::ResourceLimit);
+                }
+                if accepting && header.is_teardown() && !shed {
+                    // the peer may reuse the id right away, the circuit only reads the teardown
+                    state.routes.remove(&circuit_id);
+                    self.mark_idle(&mut state);
+                }
+                return None;
+            }
+            None => {}
+        }
+        if !accepting || !header.is_create() {
+            debug!(
+                "Dropped message for unknown circuit {} from {}",
+                circuit_id, self.peer_addr
+            );
+            return None;
+        }
+        if header
+            .requested_cell_size()
+            .is_some_and(|s| !is_shareable(s))
+        {
+            debug!(
+                "Rejecting circuit {} from {} with a cell size which can not be shared",
+                circuit_id, self.peer_addr
+            );
+            self.send_teardown(circuit_id, EndReason::ProtocolError);
+            return None;
+        }
+        let stream = self.add_route(&mut state, circuit_id);
+        if let Some(route) = state.routes.get_mut(&circuit_id) {
+            let _ = route.deliver(msg);
+        }
+        Some(stream)
+    }
+
+    /// Tears the circuit with `circuit_id` down at the peer for `reason`, unless the write buffer
+    /// is full.
+    fn send_teardown(&self, circuit_id: CircuitId, reason: EndReason) {
+        let mut teardown = BytesMut::with_capacity(MESSAGE_SIZE);
+        CircuitTeardown { circuit_id, reason }.write_padded_to(&mut teardown, MESSAGE_SIZE);
+        let sent = self.writes.try_send(Write {
+            buf: teardown.freeze(),
+            _slot: None,
+        });
+        if sent.is_ok() {
+            self.queued();
+        }
+    }
+
+    /// Counts a write passed on to the task serving the connection.
+    fn queued(&self) {
+        if let Some(depth) = &self.queue_depth {
+            depth.push();
+        }
+    }
+
+    fn add_route(
+        self: &Arc<Self>,
+        state: &mut ConnectionState,
+        circuit_id: CircuitId,
+    ) -> SharedStream {
+        let (messages_tx, messages_rx) = mpsc::channel(CIRCUIT_BUFFER_SIZE);
+        state.next_route += 1;
+        let route = state.next_route;
+        state.routes.insert(
+            circuit_id,
+            Route {
+                id: route,
+                messages: Some(messages_tx),
+                backlog: VecDeque::new(),
+                stalled_since: None,
+            },
+        );
+        state.idle_since = None;
+        SharedStream {
+            circuit_id,
+            route,
+            messages: messages_rx,
+            unread: Bytes::new(),
+            writes: self.writes.clone(),
+            reserving: None,
+            quota: Arc::new(Semaphore::new(CIRCUIT_WRITE_QUOTA)),
+            acquiring: None,
+            slot: None,
+            connection: self.clone(),
+        }
+    }
+
+    /// Removes the route of a dropped stream, unless the id has been reused in the meantime.
+    fn release(&self, circuit_id: CircuitId, route: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.routes.get(&circuit_id).is_some_and(|r| r.id == route) {
+            state.routes.remove(&circuit_id);
+            state.ids.release(circuit_id);
+            self.mark_idle(&mut state);
+        }
+    }
+
+    /// Moves messages from the backlog of a circuit into its buffer, after the circuit read.
+    fn refill(&self, circuit_id: CircuitId, route: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(route) = state.routes.get_mut(&circuit_id).filter(|r| r.id == route) {
+            route.refill();
+        }
+    }
+
+    fn is_shed(&self, circuit_id: CircuitId, route: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .routes
+            .get(&circuit_id)
+            .is_some_and(|r| r.id == route && r.is_shed())
+    }
+
+    /// Returns how reading a circuit ends once its channel has been closed.
+    fn end_of_route(&self, circuit_id: CircuitId, route: u64) -> io::Result<()> {
+        if self.is_shed(circuit_id, route) {
+            return Err(shed_error(circuit_id));
+        }
+        match self.closed() {
+            // the peer tore the circuit down and the id has been released
+            None => Ok(()),
+            Some(_) => Err(self.error()),
+        }
+    }
+
+    fn mark_idle(&self, state: &mut ConnectionState) {
+        if state.routes.is_empty() {
+            state.idle_since = Some(Instant::now());
+        }
+    }
+
+    /// Completes once the connection has been idle for `timeout` and marks it as closed, so no
+    /// further circuits are opened on it.
+    async fn close_when_idle(&self, timeout: Duration) {
+        loop {
+            let deadline = match self.state.lock().unwrap().idle_since {
+                Some(since) => since + timeout,
+                None => Instant::now() + timeout,
+            };
+            time::sleep_until(deadline).await;
+            let mut state = self.state.lock().unwrap();
+            if state
+                .idle_since
+                .is_some_and(|since| since.elapsed() >= timeout)
+            {
+                state.closed = Some((io::ErrorKind::TimedOut, "connection idle".to_string()));
+                return;
+            }
+        }
+    }
+
+    /// Marks the connection as failed with `error` and closes the channels of all circuits on it,
+    /// which then fail with the same error.
+    fn close(&self, error: io::Error) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed.is_none() {
+            let reason = format!("shared connection to {} failed: {}", self.peer_addr, error);
+            state.closed = Some((error.kind(), reason));
+        }
+        state.routes.clear();
+    }
+
+    fn closed(&self) -> Option<(io::ErrorKind, String)> {
+        self.state.lock().unwrap().closed.clone()
+    }
+
+    /// Returns the error reported to the circuits once the connection is closed.
+    fn error(&self) -> io::Error {
+        match self.closed() {
+            Some((kind, reason)) => io::Error::new(kind, reason),
+            None => io::ErrorKind::BrokenPipe.into(),
+        }
+    }
+
+    /// Writes everything passed on by the circuits until the connection fails.
+    async fn write_messages(
+        &self,
+        mut writer: OwnedWriteHalf,
+        mut writes: mpsc::Receiver<Write>,
+    ) -> io::Result<()> {
+        while let Some(write) = writes.recv().await {
+            time::timeout(self.timeouts.write, writer.write_all(&write.buf))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "write timed out"))??;
+            if let Some(depth) = &self.queue_depth {
+                depth.pop();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The shared connections to the next hops of the circuits relayed by this onion router, or to
+/// the first hops of the tunnels built by it.
+///
+/// Circuits opened to the same peer at the same time wait for a single connection attempt.
+#[derive(Clone)]
+pub(crate) struct ConnectionCache {
+    connections: Arc<Mutex<HashMap<SocketAddr, Arc<CacheSlot>>>>,
+    idle_timeout: Duration,
+    /// set if the connections are counted in `RelayStats`
+    counters: Option<Arc<RelayCounters>>,
+    /// creates the generators of the circuit ids on new connections
+    rng: RngFactory,
+    timeouts: SocketTimeouts,
+}
+
+/// The connection to a single peer, locked while connecting.
+type CacheSlot = AsyncMutex<Option<SharedConnection>>;
+
+impl ConnectionCache {
+    pub(crate) fn new(idle_timeout: Duration, counters: Option<Arc<RelayCounters>>) -> Self {
+        ConnectionCache {
+            connections: Default::default(),
+            idle_timeout,
+            counters,
+            rng: Default::default(),
+            timeouts: Default::default(),
+        }
+    }
+
+    /// Draws the circuit ids on new connections from the generators created by `rng`.
+    pub(crate) fn with_rng(mut self, rng: RngFactory) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Applies `timeouts` to the writes on new connections.
+    pub(crate) fn with_socket_timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Opens a circuit to the peer at `addr` on the cached connection, connecting first if there
+    /// is no open connection to the peer.
+    pub(crate) async fn open(&self, addr: SocketAddr) -> io::Result<(CircuitId, SharedStream)> {
+        let slot = self.slot(addr);
+        let mut cached = slot.lock().await;
+        if let Some(opened) = cached.as_ref().and_then(SharedConnection::open) {
+            return Ok(opened);
+        }
+
+        let connection = SharedConnection::connect(
+            addr,
+            self.idle_timeout,
+            self.counters.clone(),
+            self.rng.create(),
+            self.timeouts,
+        )
+        .await?;
+        let opened = connection.open().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "connection closed early")
+        })?;
+        *cached = Some(connection);
+        Ok(opened)
+    }
+
+    /// Returns the cache entry of the peer at `addr`.
+    fn slot(&self, addr: SocketAddr) -> Arc<CacheSlot> {
+        let mut connections = self.connections.lock().unwrap();
+        // forget the peers whose connection has been closed, unless a connection attempt is
+        // in progress
+        connections.retain(|_, slot| match slot.try_lock() {
+            Ok(cached) => cached.as_ref().is_some_and(|c| !c.is_closed()),
+            Err(_) => true,
+        });
+        connections.entry(addr).or_default().clone()
+    }
+}
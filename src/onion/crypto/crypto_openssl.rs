@@ -1,11 +1,17 @@
-use crate::Result;
+use super::{
+    CellSequence, CipherSuite, Direction, HandshakeVersion, SecretBytes, BACKWARD_KEY_LABEL,
+    BACKWARD_MAC_LABEL, ED25519_SIGNATURE_LEN, FORWARD_KEY_LABEL, FORWARD_MAC_LABEL,
+};
+use crate::{Fingerprint, Result};
 use anyhow::anyhow;
 use bytes::Bytes;
 use openssl::{derive, hash, pkey, rand, sha, sign, symm};
+#[cfg(test)]
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use zeroize::Zeroizing;
 
 const AES_128_CTR_KEY_LEN: usize = 16;
 const AES_128_CTR_IV_LEN: usize = 16;
@@ -15,8 +21,10 @@ pub(crate) const NONCE_LEN: usize = AES_128_CTR_IV_LEN;
 pub(crate) const KEY_LEN: usize = 44;
 
 // 4096 / 8
+#[cfg(test)]
 pub(crate) const SIGNATURE_LEN: usize = 512;
 
+/// OpenSSL wipes the private key when it is freed.
 pub(crate) struct EphemeralPrivateKey(pkey::PKey<pkey::Private>);
 pub(crate) struct EphemeralPublicKey(Bytes);
 
@@ -27,7 +35,24 @@ pub struct RsaPublicKey(Bytes);
 /// A RSA private key.
 pub struct RsaPrivateKey(pkey::PKey<pkey::Private>);
 
-pub(crate) struct SessionKey([u8; AES_128_CTR_KEY_LEN]);
+/// A Ed25519 public key.
+#[derive(Clone)]
+pub struct Ed25519PublicKey(Bytes);
+
+/// A Ed25519 private key, which OpenSSL wipes when it is freed.
+pub struct Ed25519PrivateKey(pkey::PKey<pkey::Private>);
+
+/// The keys shared with a single hop, along with the cipher suite negotiated with it.
+///
+/// The keys are wiped when dropped, the MAC keys by OpenSSL.
+pub(crate) struct SessionKey {
+    forward_key: SecretBytes<AES_128_CTR_KEY_LEN>,
+    backward_key: SecretBytes<AES_128_CTR_KEY_LEN>,
+    forward_mac_key: pkey::PKey<pkey::Private>,
+    backward_mac_key: pkey::PKey<pkey::Private>,
+    suite: CipherSuite,
+    sequence: CellSequence,
+}
 
 pub(crate) fn fill_random(buf: &mut [u8]) {
     rand::rand_bytes(buf).unwrap()
@@ -75,6 +100,9 @@ impl RsaPrivateKey {
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)?;
         let pkey = pkey::PKey::private_key_from_pem(&buf)?;
+        if pkey.id() != pkey::Id::RSA {
+            return Err(anyhow!("Not a RSA private key"));
+        }
         Ok(RsaPrivateKey(pkey))
     }
 
@@ -83,6 +111,11 @@ impl RsaPrivateKey {
         RsaPublicKey(self.0.public_key_to_der().unwrap().into())
     }
 
+    /// Returns the length of the modulus in bytes, which is also the length of its signatures.
+    pub(crate) fn size(&self) -> usize {
+        self.0.size()
+    }
+
     pub(crate) fn sign(&self, data: &[u8], signature: &mut [u8]) -> Result<()> {
         let mut signer = sign::Signer::new(hash::MessageDigest::sha256(), &self.0)?;
         signer.sign_oneshot(signature, data)?;
@@ -105,6 +138,13 @@ impl RsaPublicKey {
         Self(bytes.to_vec().into())
     }
 
+    /// Returns the SHA-256 digest of this key, which identifies the peer owning it.
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(digest(self.0.as_ref()).as_ref());
+        fingerprint
+    }
+
     pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> Result<()> {
         let pkey = pkey::PKey::public_key_from_der(self.0.as_ref())?;
         let mut verifier = sign::Verifier::new(hash::MessageDigest::sha256(), &pkey)?;
@@ -117,40 +157,218 @@ impl RsaPublicKey {
     }
 }
 
+impl Ed25519PrivateKey {
+    /// Reads a Ed25519 private key from the specified file.
+    ///
+    /// The key is expected to be in the PKCS #8 format and PEM encoded.
+    pub fn from_pem_file<P: AsRef<Path>>(path: P) -> Result<Ed25519PrivateKey> {
+        let mut file = File::open(path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let pkey = pkey::PKey::private_key_from_pem(&buf)?;
+        if pkey.id() != pkey::Id::ED25519 {
+            return Err(anyhow!("Not a Ed25519 private key"));
+        }
+        Ok(Ed25519PrivateKey(pkey))
+    }
+
+    /// Computes the corresponding public key.
+    pub fn public_key(&self) -> Ed25519PublicKey {
+        Ed25519PublicKey(self.0.public_key_to_der().unwrap().into())
+    }
+
+    pub(crate) fn sign(&self, data: &[u8], signature: &mut [u8]) -> Result<()> {
+        let mut signer = sign::Signer::new_without_digest(&self.0)?;
+        let len = signer.sign_oneshot(signature, data)?;
+        if len != ED25519_SIGNATURE_LEN {
+            return Err(anyhow!("Unexpected signature length {}", len));
+        }
+        Ok(())
+    }
+}
+
+impl Ed25519PublicKey {
+    /// Creates a Ed25519 public key from the given data.
+    ///
+    /// The data is expected to be a DER encoded key in the SubjectPublicKeyInfo format.
+    pub fn from_subject_info(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec().into())
+    }
+
+    /// Returns the SHA-256 digest of this key, which identifies the peer owning it.
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(digest(self.0.as_ref()).as_ref());
+        fingerprint
+    }
+
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> Result<()> {
+        let pkey = pkey::PKey::public_key_from_der(self.0.as_ref())?;
+        let mut verifier = sign::Verifier::new_without_digest(&pkey)?;
+
+        if verifier.verify_oneshot(signature, data)? {
+            Ok(())
+        } else {
+            Err(anyhow!("Could not verify signature"))
+        }
+    }
+}
+
 impl SessionKey {
     pub(crate) fn from_key_exchange(
         private_key: EphemeralPrivateKey,
         peer_key: &EphemeralPublicKey,
+        suite: CipherSuite,
+        salt: Option<&[u8]>,
+        version: HandshakeVersion,
     ) -> Result<SessionKey> {
         let pkey = pkey::PKey::public_key_from_der(peer_key.0.as_ref())?;
         let mut deriver = derive::Deriver::new(&private_key.0)?;
         deriver.set_peer(&pkey)?;
 
-        let mut key = [0u8; AES_128_CTR_KEY_LEN];
-        if deriver.derive(&mut key)? >= AES_128_CTR_KEY_LEN {
-            Ok(SessionKey(key))
-        } else {
-            Err(anyhow!("Insufficient keying material"))
+        // the X25519 shared secret is always 32 bytes, newer OpenSSL versions refuse to derive
+        // into a smaller buffer
+        let mut secret = Zeroizing::new([0u8; 32]);
+        if deriver.derive(&mut *secret)? < AES_128_CTR_KEY_LEN {
+            return Err(anyhow!("Insufficient keying material"));
         }
+        SessionKey::from_shared_secret(&*secret, suite, salt, version)
     }
 
+    /// Derives the keys of the handshake `version` from the `secret` shared by the key exchange.
+    pub(crate) fn from_shared_secret(
+        secret: &[u8],
+        suite: CipherSuite,
+        salt: Option<&[u8]>,
+        version: HandshakeVersion,
+    ) -> Result<SessionKey> {
+        if version >= HandshakeVersion::DirectionalKeys {
+            // HKDF-Extract with the nonce of the responder, and what it signed along with it,
+            // then HKDF-Expand a key for each direction and purpose
+            let prk = hmac_sha256(salt.unwrap_or(&[0u8; 32]), secret)?;
+            return Ok(SessionKey {
+                forward_key: SecretBytes::from_slice(&hkdf_expand(&prk, FORWARD_KEY_LABEL)?),
+                backward_key: SecretBytes::from_slice(&hkdf_expand(&prk, BACKWARD_KEY_LABEL)?),
+                forward_mac_key: pkey::PKey::hmac(&hkdf_expand(&prk, FORWARD_MAC_LABEL)?)?,
+                backward_mac_key: pkey::PKey::hmac(&hkdf_expand(&prk, BACKWARD_MAC_LABEL)?)?,
+                suite,
+                sequence: CellSequence::new(false),
+            });
+        }
+        match salt {
+            // HKDF-Extract with the nonce of the responder, and what it signed along with it
+            Some(salt) => SessionKey::from_secret(&hmac_sha256(salt, secret)?, suite),
+            None => SessionKey::from_secret(secret, suite),
+        }
+    }
+
+    /// Uses `secret` as the cipher key of both directions, as before
+    /// [`HandshakeVersion::DirectionalKeys`].
+    fn from_secret(secret: &[u8], suite: CipherSuite) -> Result<SessionKey> {
+        Ok(SessionKey {
+            forward_key: SecretBytes::from_slice(secret),
+            backward_key: SecretBytes::from_slice(secret),
+            forward_mac_key: derive_mac_key(secret, FORWARD_MAC_LABEL)?,
+            backward_mac_key: derive_mac_key(secret, BACKWARD_MAC_LABEL)?,
+            suite,
+            sequence: CellSequence::new(false),
+        })
+    }
+
+    #[cfg(test)]
     pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        Ok(SessionKey(bytes.try_into()?))
+        let _: [u8; AES_128_CTR_KEY_LEN] = bytes.try_into()?;
+        SessionKey::from_secret(bytes, CipherSuite::TruncatedDigest)
     }
 
-    pub(crate) fn encrypt(&self, nonce: [u8; NONCE_LEN], data: &mut [u8]) -> Result<()> {
-        let encrypted = symm::encrypt(symm::Cipher::aes_128_ctr(), &self.0, Some(&nonce), &data)?;
+    #[cfg(test)]
+    pub(crate) fn with_suite(mut self, suite: CipherSuite) -> Self {
+        self.suite = suite;
+        self
+    }
+
+    /// Checks the sequence numbers of the cells received from the hop sharing this key, which
+    /// negotiated [`HandshakeVersion::SequencedNonces`](super::HandshakeVersion::SequencedNonces).
+    pub(crate) fn with_sequence_check(mut self) -> Self {
+        self.sequence = CellSequence::new(true);
+        self
+    }
+
+    /// Returns the cipher suite negotiated with the hop sharing this key.
+    pub(crate) fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// Returns the sequence numbers of the cells exchanged with the hop sharing this key.
+    pub(crate) fn sequence(&self) -> &CellSequence {
+        &self.sequence
+    }
+
+    /// Computes the HMAC-SHA-256 of `data` using the key of the given direction.
+    pub(crate) fn mac(&self, direction: Direction, data: &[u8]) -> impl AsRef<[u8]> {
+        let key = match direction {
+            Direction::Forward => &self.forward_mac_key,
+            Direction::Backward => &self.backward_mac_key,
+        };
+        let mut signer = sign::Signer::new(hash::MessageDigest::sha256(), key).unwrap();
+        signer.update(data).unwrap();
+        signer.sign_to_vec().unwrap()
+    }
+
+    /// Returns the cipher key of cells travelling in `direction`.
+    fn key(&self, direction: Direction) -> &[u8] {
+        match direction {
+            Direction::Forward => self.forward_key.as_bytes(),
+            Direction::Backward => self.backward_key.as_bytes(),
+        }
+    }
+
+    pub(crate) fn encrypt(
+        &self,
+        direction: Direction,
+        nonce: [u8; NONCE_LEN],
+        data: &mut [u8],
+    ) -> Result<()> {
+        let key = self.key(direction);
+        let encrypted = symm::encrypt(symm::Cipher::aes_128_ctr(), key, Some(&nonce), data)?;
         data.copy_from_slice(&encrypted);
         Ok(())
     }
 
-    pub(crate) fn decrypt(&self, nonce: [u8; NONCE_LEN], data: &mut [u8]) -> Result<()> {
-        let decrypted = symm::decrypt(symm::Cipher::aes_128_ctr(), &self.0, Some(&nonce), &data)?;
+    pub(crate) fn decrypt(
+        &self,
+        direction: Direction,
+        nonce: [u8; NONCE_LEN],
+        data: &mut [u8],
+    ) -> Result<()> {
+        let key = self.key(direction);
+        let decrypted = symm::decrypt(symm::Cipher::aes_128_ctr(), key, Some(&nonce), data)?;
         data.copy_from_slice(&decrypted);
         Ok(())
     }
 }
 
+/// Derives a key for HMAC-SHA-256 from the shared `secret`, separated by `label`.
+fn derive_mac_key(secret: &[u8], label: &[u8]) -> Result<pkey::PKey<pkey::Private>> {
+    Ok(pkey::PKey::hmac(&hmac_sha256(secret, label)?)?)
+}
+
+/// HKDF-Expand of a single block, i.e. of at most 32 bytes, separated by `label`.
+fn hkdf_expand(prk: &[u8], label: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let key = pkey::PKey::hmac(prk)?;
+    let mut signer = sign::Signer::new(hash::MessageDigest::sha256(), &key)?;
+    signer.update(label)?;
+    signer.update(&[1])?;
+    Ok(Zeroizing::new(signer.sign_to_vec()?))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let key = pkey::PKey::hmac(key)?;
+    let mut signer = sign::Signer::new(hash::MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(Zeroizing::new(signer.sign_to_vec()?))
+}
+
 #[cfg(test)]
 mod tests {
     use super::RsaPrivateKey;
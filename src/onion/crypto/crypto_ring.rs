@@ -1,32 +1,66 @@
-use crate::Result;
+use super::{
+    CellSequence, CipherSuite, Direction, HandshakeVersion, BACKWARD_KEY_LABEL, BACKWARD_MAC_LABEL,
+    ED25519_SIGNATURE_LEN, FORWARD_KEY_LABEL, FORWARD_MAC_LABEL,
+};
+use crate::{Fingerprint, Result};
 use anyhow::anyhow;
 use bytes::Bytes;
 use once_cell::sync::Lazy;
-use ring::hkdf::KeyType;
 use ring::rand::SecureRandom;
 use ring::signature::KeyPair;
-use ring::{aead, agreement, digest, hkdf, rand, signature};
+use ring::{aead, agreement, digest, hkdf, hmac, rand, signature};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::ops::Deref;
 use std::path::Path;
 
-static GLOBAL_RNG: Lazy<rand::SystemRandom> = Lazy::new(|| rand::SystemRandom::new());
+static GLOBAL_RNG: Lazy<rand::SystemRandom> = Lazy::new(rand::SystemRandom::new);
 pub(crate) const NONCE_LEN: usize = aead::NONCE_LEN;
 /// Length of EphemeralPublicKey in bytes
 pub(crate) const KEY_LEN: usize = 32;
 
+/// The DER encoding of the AlgorithmIdentifier of RSA keys in a SubjectPublicKeyInfo.
+const RSA_ALGORITHM_ID: [u8; 15] = [
+    0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+];
+
+/// The DER encoding of a Ed25519 SubjectPublicKeyInfo up to the key itself.
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_BIT_STRING: u8 = 0x03;
+
 pub(crate) struct EphemeralPrivateKey(agreement::EphemeralPrivateKey);
 pub(crate) struct EphemeralPublicKey(agreement::UnparsedPublicKey<Bytes>);
 
 /// A RSA public key.
+///
+/// Like the other backends, the key is kept in the SubjectPublicKeyInfo format, whose digest is
+/// the fingerprint of the peer owning it.
 #[derive(Clone)]
-pub struct RsaPublicKey(signature::UnparsedPublicKey<Bytes>);
+pub struct RsaPublicKey(Bytes);
 
 /// A RSA private key.
 pub struct RsaPrivateKey(signature::RsaKeyPair);
 
-pub(crate) struct SessionKey(aead::LessSafeKey);
+/// A Ed25519 public key, kept in the SubjectPublicKeyInfo format.
+#[derive(Clone)]
+pub struct Ed25519PublicKey(Bytes);
+
+/// A Ed25519 private key.
+pub struct Ed25519PrivateKey(signature::Ed25519KeyPair);
+
+/// The keys shared with a single hop, along with the cipher suite negotiated with it.
+pub(crate) struct SessionKey {
+    forward_key: aead::LessSafeKey,
+    backward_key: aead::LessSafeKey,
+    forward_mac_key: hmac::Key,
+    backward_mac_key: hmac::Key,
+    suite: CipherSuite,
+    sequence: CellSequence,
+}
 // TODO consider storing generic B: AsRef<[u8]> instead of Bytes (-> avoid allocations)
 
 pub(crate) fn fill_random(buf: &mut [u8]) {
@@ -89,12 +123,12 @@ impl RsaPrivateKey {
 
     /// Computes the corresponding public key.
     pub fn public_key(&self) -> RsaPublicKey {
-        let public_key_bytes = self.0.public_key().as_ref().to_vec().into();
-        let public_key = signature::UnparsedPublicKey::new(
-            &signature::RSA_PKCS1_2048_8192_SHA256,
-            public_key_bytes,
-        );
-        RsaPublicKey(public_key)
+        RsaPublicKey::from_raw_bytes(self.0.public_key().as_ref())
+    }
+
+    /// Returns the length of the modulus in bytes, which is also the length of its signatures.
+    pub(crate) fn size(&self) -> usize {
+        self.0.public_modulus_len()
     }
 
     pub(crate) fn sign(&self, data: &[u8], signature: &mut [u8]) -> Result<()> {
@@ -113,22 +147,106 @@ impl RsaPublicKey {
     ///
     /// The data is expected to be a DER encoded key in the RSAPublicKey format.
     pub fn from_raw_bytes(bytes: &[u8]) -> Self {
+        let mut key = Vec::with_capacity(bytes.len() + 1);
+        // no unused bits
+        key.push(0);
+        key.extend_from_slice(bytes);
+        let mut info = RSA_ALGORITHM_ID.to_vec();
+        info.extend_from_slice(&der_element(DER_BIT_STRING, &key));
+        RsaPublicKey(der_element(DER_SEQUENCE, &info).into())
+    }
+
+    /// Creates a RSA public key from the given data.
+    ///
+    /// The data is expected to be a DER encoded key in the SubjectPublicKeyInfo format.
+    pub fn from_subject_info(bytes: &[u8]) -> Self {
+        RsaPublicKey(bytes.to_vec().into())
+    }
+
+    /// Returns the key in the RSAPublicKey format, which is wrapped by the SubjectPublicKeyInfo.
+    fn raw_bytes(&self) -> Result<&[u8]> {
+        let invalid = || anyhow!("Invalid RSA public key");
+        let (info, _) = der_split(DER_SEQUENCE, &self.0).ok_or_else(invalid)?;
+        let key = info
+            .strip_prefix(&RSA_ALGORITHM_ID[..])
+            .ok_or_else(invalid)?;
+        let (key, _) = der_split(DER_BIT_STRING, key).ok_or_else(invalid)?;
+        key.strip_prefix(&[0]).ok_or_else(invalid)
+    }
+
+    /// Returns the SHA-256 digest of this key, which identifies the peer owning it.
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(digest(self.0.as_ref()).as_ref());
+        fingerprint
+    }
+
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> Result<()> {
         let public_key = signature::UnparsedPublicKey::new(
             &signature::RSA_PKCS1_2048_8192_SHA256,
-            bytes.to_vec().into(),
+            self.raw_bytes()?,
         );
-        RsaPublicKey(public_key)
+        public_key.verify(data, signature)?;
+        Ok(())
     }
+}
 
-    /// Creates a RSA public key from the given data.
+impl Ed25519PrivateKey {
+    /// Reads a Ed25519 private key from the specified file.
+    ///
+    /// The key is expected to be in the PKCS #8 format and PEM encoded.
+    pub fn from_pem_file<P: AsRef<Path>>(path: P) -> Result<Ed25519PrivateKey> {
+        let file = BufReader::new(File::open(path)?);
+        let key = file
+            .lines()
+            .map(|line| line.unwrap())
+            .skip(1)
+            .take_while(|line| !line.starts_with('-'))
+            .collect::<String>();
+        let bytes = base64::decode(&key)?;
+        // OpenSSL writes version 1 documents, which lack the public key
+        let key_pair = signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(&bytes)?;
+        Ok(Ed25519PrivateKey(key_pair))
+    }
+
+    /// Computes the corresponding public key.
+    pub fn public_key(&self) -> Ed25519PublicKey {
+        let mut bytes = ED25519_SPKI_PREFIX.to_vec();
+        bytes.extend_from_slice(self.0.public_key().as_ref());
+        Ed25519PublicKey(bytes.into())
+    }
+
+    pub(crate) fn sign(&self, data: &[u8], signature: &mut [u8]) -> Result<()> {
+        if signature.len() != ED25519_SIGNATURE_LEN {
+            return Err(anyhow!("Unexpected signature length {}", signature.len()));
+        }
+        signature.copy_from_slice(self.0.sign(data).as_ref());
+        Ok(())
+    }
+}
+
+impl Ed25519PublicKey {
+    /// Creates a Ed25519 public key from the given data.
     ///
     /// The data is expected to be a DER encoded key in the SubjectPublicKeyInfo format.
     pub fn from_subject_info(bytes: &[u8]) -> Self {
-        Self::new(&bytes[24..])
+        Ed25519PublicKey(bytes.to_vec().into())
+    }
+
+    /// Returns the SHA-256 digest of this key, which identifies the peer owning it.
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(digest(self.0.as_ref()).as_ref());
+        fingerprint
     }
 
     pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> Result<()> {
-        self.0.verify(data, signature)?;
+        let public_key = self
+            .0
+            .strip_prefix(&ED25519_SPKI_PREFIX[..])
+            .ok_or_else(|| anyhow!("Invalid Ed25519 public key"))?;
+        signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(data, signature)?;
         Ok(())
     }
 }
@@ -137,44 +255,184 @@ impl SessionKey {
     pub(crate) fn from_key_exchange(
         private_key: EphemeralPrivateKey,
         peer_key: &EphemeralPublicKey,
+        suite: CipherSuite,
+        salt: Option<&[u8]>,
+        version: HandshakeVersion,
     ) -> Result<SessionKey> {
         agreement::agree_ephemeral(
             private_key.0,
             &peer_key.0,
             anyhow!("Key exchange failed"),
-            Self::from_bytes,
+            |secret| Self::from_shared_secret(secret, suite, salt, version),
         )
     }
 
+    /// Derives the keys of the handshake `version` from the `secret` shared by the key exchange.
+    pub(crate) fn from_shared_secret(
+        secret: &[u8],
+        suite: CipherSuite,
+        salt: Option<&[u8]>,
+        version: HandshakeVersion,
+    ) -> Result<Self> {
+        if version >= HandshakeVersion::DirectionalKeys {
+            // HKDF-Extract with the nonce of the responder, and what it signed along with it,
+            // then HKDF-Expand a key for each direction and purpose
+            let prk =
+                hkdf::Salt::new(hkdf::HKDF_SHA256, salt.unwrap_or(&[0u8; 32])).extract(secret);
+            let forward_key = prk.expand(&[FORWARD_KEY_LABEL], &aead::AES_128_GCM)?;
+            let backward_key = prk.expand(&[BACKWARD_KEY_LABEL], &aead::AES_128_GCM)?;
+            return Ok(SessionKey {
+                forward_key: aead::LessSafeKey::new(forward_key.into()),
+                backward_key: aead::LessSafeKey::new(backward_key.into()),
+                forward_mac_key: prk.expand(&[FORWARD_MAC_LABEL], hmac::HMAC_SHA256)?.into(),
+                backward_mac_key: prk.expand(&[BACKWARD_MAC_LABEL], hmac::HMAC_SHA256)?.into(),
+                suite,
+                sequence: CellSequence::new(false),
+            });
+        }
+        match salt {
+            // HKDF-Extract with the nonce of the responder, and what it signed along with it
+            Some(salt) => Self::from_secret(hmac_sha256(salt, secret).as_ref(), suite),
+            None => Self::from_secret(secret, suite),
+        }
+    }
+
+    /// Uses `secret` as the cipher key of both directions, as before
+    /// [`HandshakeVersion::DirectionalKeys`].
+    fn from_secret(secret: &[u8], suite: CipherSuite) -> Result<Self> {
+        let key = &secret[..aead::AES_128_GCM.key_len()];
+        Ok(SessionKey {
+            forward_key: aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, key)?),
+            backward_key: aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_128_GCM, key)?),
+            forward_mac_key: derive_mac_key(secret, FORWARD_MAC_LABEL),
+            backward_mac_key: derive_mac_key(secret, BACKWARD_MAC_LABEL),
+            suite,
+            sequence: CellSequence::new(false),
+        })
+    }
+
+    #[cfg(test)]
     pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
-        // HKDF_SHA256 salt len = 32
-        let salt = vec![0u8; hkdf::HKDF_SHA256.len()];
-        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(bytes);
-        // AES_128_GCM key len = 16
-        let unbound = prk.expand(&[], &aead::AES_128_GCM)?.into();
-        Ok(SessionKey(aead::LessSafeKey::new(unbound)))
+        if bytes.len() != aead::AES_128_GCM.key_len() {
+            return Err(anyhow!("Unexpected key length {}", bytes.len()));
+        }
+        Self::from_secret(bytes, CipherSuite::TruncatedDigest)
     }
 
-    pub(crate) fn encrypt(&self, nonce: [u8; NONCE_LEN], data: &mut [u8]) -> Result<()> {
-        let nonce = aead::Nonce::assume_unique_for_key(nonce);
-        let _tag = self
-            .0
-            .seal_in_place_separate_tag(nonce, aead::Aad::empty(), data)?;
-        Ok(())
+    #[cfg(test)]
+    pub(crate) fn with_suite(mut self, suite: CipherSuite) -> Self {
+        self.suite = suite;
+        self
+    }
+
+    /// Checks the sequence numbers of the cells received from the hop sharing this key, which
+    /// negotiated [`HandshakeVersion::SequencedNonces`](super::HandshakeVersion::SequencedNonces).
+    pub(crate) fn with_sequence_check(mut self) -> Self {
+        self.sequence = CellSequence::new(true);
+        self
     }
 
-    pub(crate) fn decrypt(&self, nonce: [u8; NONCE_LEN], data: &mut [u8]) -> Result<()> {
+    /// Returns the cipher suite negotiated with the hop sharing this key.
+    pub(crate) fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// Returns the sequence numbers of the cells exchanged with the hop sharing this key.
+    pub(crate) fn sequence(&self) -> &CellSequence {
+        &self.sequence
+    }
+
+    /// Computes the HMAC-SHA-256 of `data` using the key of the given direction.
+    pub(crate) fn mac(&self, direction: Direction, data: &[u8]) -> impl AsRef<[u8]> {
+        let key = match direction {
+            Direction::Forward => &self.forward_mac_key,
+            Direction::Backward => &self.backward_mac_key,
+        };
+        hmac::sign(key, data)
+    }
+
+    /// Returns the cipher key of cells travelling in `direction`.
+    fn key(&self, direction: Direction) -> &aead::LessSafeKey {
+        match direction {
+            Direction::Forward => &self.forward_key,
+            Direction::Backward => &self.backward_key,
+        }
+    }
+
+    pub(crate) fn encrypt(
+        &self,
+        direction: Direction,
+        nonce: [u8; NONCE_LEN],
+        data: &mut [u8],
+    ) -> Result<()> {
         let nonce = aead::Nonce::assume_unique_for_key(nonce);
-        self.0
-            .open_in_place_no_tag(nonce, aead::Aad::empty(), data)?;
+        let _tag =
+            self.key(direction)
+                .seal_in_place_separate_tag(nonce, aead::Aad::empty(), data)?;
         Ok(())
     }
+
+    pub(crate) fn decrypt(
+        &self,
+        direction: Direction,
+        nonce: [u8; NONCE_LEN],
+        data: &mut [u8],
+    ) -> Result<()> {
+        // the tag is not transmitted, so the cell is decrypted by applying the key stream of
+        // AES-GCM once more, which is its own inverse
+        self.encrypt(direction, nonce, data)
+    }
+}
+
+/// Encodes a DER element of type `tag` holding `content`.
+fn der_element(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    if content.len() < 0x80 {
+        element.push(content.len() as u8);
+    } else {
+        let len = content.len().to_be_bytes();
+        let skip = len.iter().take_while(|&&b| b == 0).count();
+        element.push(0x80 | (len.len() - skip) as u8);
+        element.extend_from_slice(&len[skip..]);
+    }
+    element.extend_from_slice(content);
+    element
+}
+
+/// Splits the DER element of type `tag` at the start of `bytes` off, returning its content and
+/// the bytes following it.
+fn der_split(tag: u8, bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&first, bytes) = bytes.split_first()?;
+    let (&len, mut bytes) = bytes.split_first()?;
+    if first != tag {
+        return None;
+    }
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        let n = (len & 0x7f) as usize;
+        if n > 4 || bytes.len() < n {
+            return None;
+        }
+        let (len, rest) = bytes.split_at(n);
+        bytes = rest;
+        len.iter().fold(0, |len, &b| len << 8 | b as usize)
+    };
+    (len <= bytes.len()).then(|| bytes.split_at(len))
+}
+
+/// Derives a key for HMAC-SHA-256 from the shared `secret`, separated by `label`.
+fn derive_mac_key(secret: &[u8], label: &[u8]) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, hmac_sha256(secret, label).as_ref())
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> hmac::Tag {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data)
 }
 
 #[cfg(test)]
 mod tests {
     use super::RsaPrivateKey;
-    use ring::hkdf::KeyType;
 
     #[test]
     fn test_read_hostkey() {
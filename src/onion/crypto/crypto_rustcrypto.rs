@@ -0,0 +1,480 @@
+use super::{
+    CellSequence, CipherSuite, Direction, HandshakeVersion, SecretBytes, BACKWARD_KEY_LABEL,
+    BACKWARD_MAC_LABEL, ED25519_SIGNATURE_LEN, FORWARD_KEY_LABEL, FORWARD_MAC_LABEL,
+};
+use crate::{Fingerprint, Result};
+use aes::cipher::{KeyIvInit, StreamCipher};
+use anyhow::anyhow;
+use bytes::Bytes;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::{self, Scalar};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::der::pem::PemLabel;
+use rsa::pkcs8::der::Decode;
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePublicKey, PrivateKeyInfo};
+use rsa::traits::PublicKeyParts;
+use rsa::Pkcs1v15Sign;
+use sha2::{Digest, Sha256, Sha512};
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+type HmacSha256 = Hmac<Sha256>;
+
+const AES_128_CTR_KEY_LEN: usize = 16;
+const AES_128_CTR_IV_LEN: usize = 16;
+const MAC_KEY_LEN: usize = 32;
+pub(crate) const NONCE_LEN: usize = AES_128_CTR_IV_LEN;
+
+/// Length of EphemeralPublicKey in bytes
+pub(crate) const KEY_LEN: usize = 44;
+
+/// The DER encoding of a X25519 SubjectPublicKeyInfo up to the key itself, which is how the
+/// OpenSSL backend encodes ephemeral public keys.
+const X25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x03, 0x21, 0x00,
+];
+
+/// The DER encoding of a Ed25519 SubjectPublicKeyInfo up to the key itself.
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// The object identifier of Ed25519 keys in PKCS #8 documents.
+const ED25519_OID: &str = "1.3.101.112";
+
+// 4096 / 8
+#[cfg(test)]
+pub(crate) const SIGNATURE_LEN: usize = 512;
+
+/// The secret is wiped on drop by `x25519_dalek`.
+pub(crate) struct EphemeralPrivateKey(x25519_dalek::EphemeralSecret);
+pub(crate) struct EphemeralPublicKey(Bytes);
+
+/// A RSA public key.
+#[derive(Clone)]
+pub struct RsaPublicKey(Bytes);
+
+/// A RSA private key.
+pub struct RsaPrivateKey(rsa::RsaPrivateKey);
+
+/// A Ed25519 public key.
+#[derive(Clone)]
+pub struct Ed25519PublicKey(Bytes);
+
+/// A Ed25519 private key.
+///
+/// Only the seed is kept, which is wiped on drop. The signing scalar is derived from it anew for
+/// every signature, as described in RFC 8032.
+pub struct Ed25519PrivateKey {
+    seed: SecretBytes<32>,
+    public_key: [u8; 32],
+}
+
+/// The keys shared with a single hop, along with the cipher suite negotiated with it.
+///
+/// The keys are wiped when dropped. The MAC keys are kept as bytes instead of keyed `Hmac`
+/// states, which can not be wiped.
+pub(crate) struct SessionKey {
+    forward_key: SecretBytes<AES_128_CTR_KEY_LEN>,
+    backward_key: SecretBytes<AES_128_CTR_KEY_LEN>,
+    forward_mac_key: SecretBytes<MAC_KEY_LEN>,
+    backward_mac_key: SecretBytes<MAC_KEY_LEN>,
+    suite: CipherSuite,
+    sequence: CellSequence,
+}
+
+pub(crate) fn fill_random(buf: &mut [u8]) {
+    OsRng.fill_bytes(buf)
+}
+
+pub(crate) fn digest(buf: &[u8]) -> impl AsRef<[u8]> {
+    Sha256::digest(buf)
+}
+
+impl EphemeralPrivateKey {
+    pub(crate) fn generate() -> Self {
+        Self(x25519_dalek::EphemeralSecret::random_from_rng(OsRng))
+    }
+
+    pub(crate) fn public_key(&self) -> EphemeralPublicKey {
+        let public_key = x25519_dalek::PublicKey::from(&self.0);
+        let mut bytes = Vec::with_capacity(KEY_LEN);
+        bytes.extend_from_slice(&X25519_SPKI_PREFIX);
+        bytes.extend_from_slice(public_key.as_bytes());
+        EphemeralPublicKey::new(bytes.into())
+    }
+}
+
+impl EphemeralPublicKey {
+    pub(crate) fn new(bytes: Bytes) -> EphemeralPublicKey {
+        Self(bytes)
+    }
+
+    pub(crate) fn bytes(&self) -> &Bytes {
+        &self.0
+    }
+
+    fn parse(&self) -> Result<x25519_dalek::PublicKey> {
+        let key = self
+            .0
+            .strip_prefix(&X25519_SPKI_PREFIX[..])
+            .ok_or_else(|| anyhow!("Invalid ephemeral public key"))?;
+        let key: [u8; 32] = key
+            .try_into()
+            .map_err(|_| anyhow!("Invalid ephemeral public key"))?;
+        Ok(key.into())
+    }
+}
+
+pub(crate) fn generate_ephemeral_keypair() -> (EphemeralPrivateKey, EphemeralPublicKey) {
+    let private_key = EphemeralPrivateKey::generate();
+    let public_key = private_key.public_key();
+    (private_key, public_key)
+}
+
+impl RsaPrivateKey {
+    /// Reads a RSA private key from the specified file.
+    ///
+    /// The key is expected to be in the DER format and PEM encoded.
+    pub fn from_pem_file<P: AsRef<Path>>(path: P) -> Result<RsaPrivateKey> {
+        let pem = fs::read_to_string(path)?;
+        let key = match rsa::RsaPrivateKey::from_pkcs1_pem(&pem) {
+            Ok(key) => key,
+            Err(_) => rsa::RsaPrivateKey::from_pkcs8_pem(&pem)?,
+        };
+        Ok(RsaPrivateKey(key))
+    }
+
+    /// Computes the corresponding public key.
+    pub fn public_key(&self) -> RsaPublicKey {
+        let der = self.0.to_public_key().to_public_key_der().unwrap();
+        RsaPublicKey(der.as_bytes().to_vec().into())
+    }
+
+    /// Returns the length of the modulus in bytes, which is also the length of its signatures.
+    pub(crate) fn size(&self) -> usize {
+        self.0.size()
+    }
+
+    pub(crate) fn sign(&self, data: &[u8], signature: &mut [u8]) -> Result<()> {
+        let signed = self
+            .0
+            .sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(data))?;
+        if signed.len() != signature.len() {
+            return Err(anyhow!("Unexpected signature length {}", signed.len()));
+        }
+        signature.copy_from_slice(&signed);
+        Ok(())
+    }
+}
+
+impl RsaPublicKey {
+    /// Creates a RSA public key from the given data.
+    ///
+    /// The data is expected to be a DER encoded key in the RSAPublicKey format.
+    pub fn from_raw_bytes(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec().into())
+    }
+
+    /// Creates a RSA public key from the given data.
+    ///
+    /// The data is expected to be a DER encoded key in the SubjectPublicKeyInfo format.
+    pub fn from_subject_info(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec().into())
+    }
+
+    /// Returns the SHA-256 digest of this key, which identifies the peer owning it.
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(digest(self.0.as_ref()).as_ref());
+        fingerprint
+    }
+
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> Result<()> {
+        let key = rsa::RsaPublicKey::from_public_key_der(self.0.as_ref())?;
+        key.verify(
+            Pkcs1v15Sign::new::<Sha256>(),
+            &Sha256::digest(data),
+            signature,
+        )
+        .map_err(|_| anyhow!("Could not verify signature"))
+    }
+}
+
+impl Ed25519PrivateKey {
+    /// Reads a Ed25519 private key from the specified file.
+    ///
+    /// The key is expected to be in the PKCS #8 format and PEM encoded.
+    pub fn from_pem_file<P: AsRef<Path>>(path: P) -> Result<Ed25519PrivateKey> {
+        let pem = Zeroizing::new(fs::read_to_string(path)?);
+        let (label, der) = rsa::pkcs8::SecretDocument::from_pem(&pem)?;
+        PrivateKeyInfo::validate_pem_label(label).map_err(|e| anyhow!("{}", e))?;
+        let info = PrivateKeyInfo::from_der(der.as_bytes())?;
+        if info.algorithm.oid.to_string() != ED25519_OID {
+            return Err(anyhow!("Not a Ed25519 private key"));
+        }
+        // the key is a DER encoded octet string itself
+        let seed = match info.private_key {
+            [0x04, 0x20, seed @ ..] if seed.len() == 32 => SecretBytes::from_slice(seed),
+            _ => return Err(anyhow!("Invalid Ed25519 private key")),
+        };
+        let expanded: Zeroizing<[u8; 64]> = Zeroizing::new(Sha512::digest(seed.as_bytes()).into());
+        let mut scalar_bytes = Zeroizing::new([0u8; 32]);
+        scalar_bytes.copy_from_slice(&expanded[..32]);
+        let public_key = EdwardsPoint::mul_base_clamped(*scalar_bytes).compress();
+        Ok(Ed25519PrivateKey {
+            seed,
+            public_key: public_key.to_bytes(),
+        })
+    }
+
+    /// Computes the corresponding public key.
+    pub fn public_key(&self) -> Ed25519PublicKey {
+        let mut bytes = Vec::with_capacity(ED25519_SPKI_PREFIX.len() + 32);
+        bytes.extend_from_slice(&ED25519_SPKI_PREFIX);
+        bytes.extend_from_slice(&self.public_key);
+        Ed25519PublicKey(bytes.into())
+    }
+
+    pub(crate) fn sign(&self, data: &[u8], signature: &mut [u8]) -> Result<()> {
+        if signature.len() != ED25519_SIGNATURE_LEN {
+            return Err(anyhow!("Unexpected signature length {}", signature.len()));
+        }
+        let expanded: Zeroizing<[u8; 64]> =
+            Zeroizing::new(Sha512::digest(self.seed.as_bytes()).into());
+        let mut scalar_bytes = Zeroizing::new([0u8; 32]);
+        scalar_bytes.copy_from_slice(&expanded[..32]);
+        let secret = Zeroizing::new(Scalar::from_bytes_mod_order(scalar::clamp_integer(
+            *scalar_bytes,
+        )));
+        let nonce = Sha512::new()
+            .chain_update(&expanded[32..])
+            .chain_update(data)
+            .finalize();
+        let nonce = Zeroizing::new(Scalar::from_bytes_mod_order_wide(&nonce.into()));
+        let commitment = EdwardsPoint::mul_base(&nonce).compress();
+        let challenge = ed25519_challenge(&commitment, &self.public_key, data);
+        let response = *nonce + challenge * *secret;
+        signature[..32].copy_from_slice(commitment.as_bytes());
+        signature[32..].copy_from_slice(response.as_bytes());
+        Ok(())
+    }
+}
+
+impl Ed25519PublicKey {
+    /// Creates a Ed25519 public key from the given data.
+    ///
+    /// The data is expected to be a DER encoded key in the SubjectPublicKeyInfo format.
+    pub fn from_subject_info(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec().into())
+    }
+
+    /// Returns the SHA-256 digest of this key, which identifies the peer owning it.
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(digest(self.0.as_ref()).as_ref());
+        fingerprint
+    }
+
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> Result<()> {
+        let public_key: [u8; 32] = self
+            .0
+            .strip_prefix(&ED25519_SPKI_PREFIX[..])
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| anyhow!("Invalid Ed25519 public key"))?;
+        let point = CompressedEdwardsY(public_key)
+            .decompress()
+            .ok_or_else(|| anyhow!("Invalid Ed25519 public key"))?;
+        if signature.len() != ED25519_SIGNATURE_LEN {
+            return Err(anyhow!("Could not verify signature"));
+        }
+        let commitment = CompressedEdwardsY::from_slice(&signature[..32])?;
+        let response: [u8; 32] = signature[32..].try_into()?;
+        // a response which is not reduced would make the signature malleable
+        let response = Option::<Scalar>::from(Scalar::from_canonical_bytes(response))
+            .ok_or_else(|| anyhow!("Could not verify signature"))?;
+        let challenge = ed25519_challenge(&commitment, &public_key, data);
+        let expected =
+            EdwardsPoint::vartime_double_scalar_mul_basepoint(&challenge, &-point, &response);
+        if expected.compress() == commitment {
+            Ok(())
+        } else {
+            Err(anyhow!("Could not verify signature"))
+        }
+    }
+}
+
+/// Computes the challenge of a Ed25519 signature, the digest of the commitment, the public key
+/// and the signed data.
+fn ed25519_challenge(
+    commitment: &CompressedEdwardsY,
+    public_key: &[u8; 32],
+    data: &[u8],
+) -> Scalar {
+    let digest = Sha512::new()
+        .chain_update(commitment.as_bytes())
+        .chain_update(public_key)
+        .chain_update(data)
+        .finalize();
+    Scalar::from_bytes_mod_order_wide(&digest.into())
+}
+
+impl SessionKey {
+    pub(crate) fn from_key_exchange(
+        private_key: EphemeralPrivateKey,
+        peer_key: &EphemeralPublicKey,
+        suite: CipherSuite,
+        salt: Option<&[u8]>,
+        version: HandshakeVersion,
+    ) -> Result<SessionKey> {
+        let secret = private_key.0.diffie_hellman(&peer_key.parse()?);
+        // like OpenSSL, refuse the all-zero secret resulting from a low order point
+        if !secret.was_contributory() {
+            return Err(anyhow!("Key exchange failed"));
+        }
+        SessionKey::from_shared_secret(secret.as_bytes(), suite, salt, version)
+    }
+
+    /// Derives the keys of the handshake `version` from the `secret` shared by the key exchange.
+    pub(crate) fn from_shared_secret(
+        secret: &[u8],
+        suite: CipherSuite,
+        salt: Option<&[u8]>,
+        version: HandshakeVersion,
+    ) -> Result<SessionKey> {
+        if version >= HandshakeVersion::DirectionalKeys {
+            // HKDF-Extract with the nonce of the responder, and what it signed along with it,
+            // then HKDF-Expand a key for each direction and purpose
+            let prk = hmac_sha256(salt.unwrap_or(&[0u8; 32]), secret);
+            return Ok(SessionKey {
+                forward_key: SecretBytes::from_slice(&hkdf_expand(&prk, FORWARD_KEY_LABEL)),
+                backward_key: SecretBytes::from_slice(&hkdf_expand(&prk, BACKWARD_KEY_LABEL)),
+                forward_mac_key: SecretBytes::from_slice(&hkdf_expand(&prk, FORWARD_MAC_LABEL)),
+                backward_mac_key: SecretBytes::from_slice(&hkdf_expand(&prk, BACKWARD_MAC_LABEL)),
+                suite,
+                sequence: CellSequence::new(false),
+            });
+        }
+        match salt {
+            // HKDF-Extract with the nonce of the responder, and what it signed along with it
+            Some(salt) => SessionKey::from_secret(&hmac_sha256(salt, secret), suite),
+            None => SessionKey::from_secret(secret, suite),
+        }
+    }
+
+    /// Uses `secret` as the cipher key of both directions, as before
+    /// [`HandshakeVersion::DirectionalKeys`].
+    fn from_secret(secret: &[u8], suite: CipherSuite) -> Result<SessionKey> {
+        Ok(SessionKey {
+            forward_key: SecretBytes::from_slice(secret),
+            backward_key: SecretBytes::from_slice(secret),
+            forward_mac_key: derive_mac_key(secret, FORWARD_MAC_LABEL),
+            backward_mac_key: derive_mac_key(secret, BACKWARD_MAC_LABEL),
+            suite,
+            sequence: CellSequence::new(false),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let _: [u8; AES_128_CTR_KEY_LEN] = bytes.try_into()?;
+        SessionKey::from_secret(bytes, CipherSuite::TruncatedDigest)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_suite(mut self, suite: CipherSuite) -> Self {
+        self.suite = suite;
+        self
+    }
+
+    /// Checks the sequence numbers of the cells received from the hop sharing this key, which
+    /// negotiated [`HandshakeVersion::SequencedNonces`](super::HandshakeVersion::SequencedNonces).
+    pub(crate) fn with_sequence_check(mut self) -> Self {
+        self.sequence = CellSequence::new(true);
+        self
+    }
+
+    /// Returns the cipher suite negotiated with the hop sharing this key.
+    pub(crate) fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// Returns the sequence numbers of the cells exchanged with the hop sharing this key.
+    pub(crate) fn sequence(&self) -> &CellSequence {
+        &self.sequence
+    }
+
+    /// Computes the HMAC-SHA-256 of `data` using the key of the given direction.
+    pub(crate) fn mac(&self, direction: Direction, data: &[u8]) -> impl AsRef<[u8]> {
+        let key = match direction {
+            Direction::Forward => &self.forward_mac_key,
+            Direction::Backward => &self.backward_mac_key,
+        };
+        let mac = HmacSha256::new_from_slice(key.as_bytes()).unwrap();
+        mac.chain_update(data).finalize().into_bytes()
+    }
+
+    pub(crate) fn encrypt(
+        &self,
+        direction: Direction,
+        nonce: [u8; NONCE_LEN],
+        data: &mut [u8],
+    ) -> Result<()> {
+        let key = match direction {
+            Direction::Forward => &self.forward_key,
+            Direction::Backward => &self.backward_key,
+        };
+        Aes128Ctr::new(key.as_bytes().into(), &nonce.into()).apply_keystream(data);
+        Ok(())
+    }
+
+    pub(crate) fn decrypt(
+        &self,
+        direction: Direction,
+        nonce: [u8; NONCE_LEN],
+        data: &mut [u8],
+    ) -> Result<()> {
+        // the key stream is its own inverse
+        self.encrypt(direction, nonce, data)
+    }
+}
+
+/// Derives a key for HMAC-SHA-256 from the shared `secret`, separated by `label`.
+fn derive_mac_key(secret: &[u8], label: &[u8]) -> SecretBytes<MAC_KEY_LEN> {
+    SecretBytes::from_slice(&hmac_sha256(secret, label))
+}
+
+/// HKDF-Expand of a single block, i.e. of at most 32 bytes, separated by `label`.
+fn hkdf_expand(prk: &[u8], label: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mac = HmacSha256::new_from_slice(prk).unwrap();
+    let block = mac.chain_update(label).chain_update([1]).finalize();
+    Zeroizing::new(block.into_bytes().to_vec())
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mac = HmacSha256::new_from_slice(key).unwrap();
+    Zeroizing::new(mac.chain_update(data).finalize().into_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_ephemeral_keypair, RsaPrivateKey, KEY_LEN, SIGNATURE_LEN};
+
+    #[test]
+    fn test_read_hostkey() {
+        let key = RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+        assert_eq!(key.size(), SIGNATURE_LEN);
+    }
+
+    #[test]
+    fn test_ephemeral_key_len() {
+        let (_, public_key) = generate_ephemeral_keypair();
+        assert_eq!(public_key.bytes().len(), KEY_LEN);
+        public_key.parse().unwrap();
+    }
+}
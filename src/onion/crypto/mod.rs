@@ -1,8 +1,651 @@
 #[cfg(feature = "crypto_ring")]
 #[path = "crypto_ring.rs"]
 pub(crate) mod inner;
-#[cfg(not(feature = "crypto_ring"))]
+#[cfg(all(feature = "crypto_rustcrypto", not(feature = "crypto_ring")))]
+#[path = "crypto_rustcrypto.rs"]
+pub(crate) mod inner;
+#[cfg(not(any(feature = "crypto_ring", feature = "crypto_rustcrypto")))]
 #[path = "crypto_openssl.rs"]
 pub(crate) mod inner;
 
+/// The backend not selected as `inner`, compiled for the tests checking that peers using the OpenSSL
+/// and the RustCrypto backend understand each other.
+#[cfg(all(test, not(any(feature = "crypto_ring", feature = "crypto_rustcrypto"))))]
+#[path = "crypto_rustcrypto.rs"]
+#[allow(dead_code)]
+mod other;
+#[cfg(all(
+    test,
+    feature = "crypto_openssl",
+    feature = "crypto_rustcrypto",
+    not(feature = "crypto_ring")
+))]
+#[path = "crypto_openssl.rs"]
+#[allow(dead_code)]
+mod other;
+
+#[cfg(not(any(
+    feature = "crypto_openssl",
+    feature = "crypto_ring",
+    feature = "crypto_rustcrypto"
+)))]
+compile_error!("one of the features crypto_openssl, crypto_ring or crypto_rustcrypto is required");
+
+use crate::{Fingerprint, Result};
 pub use inner::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use zeroize::Zeroize;
+
+/// Length in bytes of the longest integrity tag of any [`CipherSuite`].
+pub(crate) const MAX_TAG_LEN: usize = 32;
+
+/// Length in bytes of the nonce a responder adds to its handshake reply.
+pub(crate) const HANDSHAKE_NONCE_LEN: usize = 32;
+
+pub(crate) type HandshakeNonce = [u8; HANDSHAKE_NONCE_LEN];
+
+/// The HKDF info labels of the session keys from [`HandshakeVersion::DirectionalKeys`] on.
+pub(crate) const FORWARD_KEY_LABEL: &[u8] = b"allium forward cipher";
+pub(crate) const BACKWARD_KEY_LABEL: &[u8] = b"allium backward cipher";
+pub(crate) const FORWARD_MAC_LABEL: &[u8] = b"allium forward mac";
+pub(crate) const BACKWARD_MAC_LABEL: &[u8] = b"allium backward mac";
+
+/// Length in bytes of a Ed25519 signature.
+pub(crate) const ED25519_SIGNATURE_LEN: usize = 64;
+
+/// The bit of a [`CipherSuites`] byte which does not stand for a suite, but announces support for
+/// [`HandshakeVersion::ResponderNonce`]. Peers predating it treat it like any unknown suite, i.e.
+/// they neither select it nor strip it from the echoed offer.
+const RESPONDER_NONCE_BIT: u8 = 1 << 7;
+
+/// The bit of a [`CipherSuites`] byte which announces support for
+/// [`HandshakeVersion::SequencedNonces`], only together with [`RESPONDER_NONCE_BIT`].
+const SEQUENCED_NONCES_BIT: u8 = 1 << 6;
+
+/// The bit of a [`CipherSuites`] byte which announces support for
+/// [`HandshakeVersion::FeatureFlags`], only together with the two bits above. Later versions are
+/// announced by the version number of the feature offer instead of further bits.
+const FEATURE_FLAGS_BIT: u8 = 1 << 5;
+
+/// The bits of a [`CipherSuites`] byte which announce a [`HandshakeVersion`] instead of a suite.
+const VERSION_BITS: u8 = RESPONDER_NONCE_BIT | SEQUENCED_NONCES_BIT | FEATURE_FLAGS_BIT;
+
+/// The bit of a cell nonce which is set on backward cells. Before
+/// [`HandshakeVersion::DirectionalKeys`], both directions use the same key, so the nonces of
+/// forward and backward cells must never collide.
+const BACKWARD_NONCE_BIT: u64 = 1 << 63;
+
+/// The mechanism protecting the integrity of tunnel messages.
+///
+/// The suite is negotiated with every hop of a tunnel during the circuit handshake. Suites are
+/// ordered by strength, the strongest suite supported by both sides is used.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub enum CipherSuite {
+    /// An unkeyed SHA-256 digest truncated to 12 bytes, understood by all peers.
+    TruncatedDigest,
+    /// A HMAC-SHA-256 with per-direction keys, truncated to 8 bytes.
+    ShortHmac,
+    /// A HMAC-SHA-256 with per-direction keys and the full 32 byte tag.
+    Hmac,
+}
+
+impl CipherSuite {
+    pub(crate) const ALL: [CipherSuite; 3] = [
+        CipherSuite::TruncatedDigest,
+        CipherSuite::ShortHmac,
+        CipherSuite::Hmac,
+    ];
+
+    /// Returns the length in bytes of the integrity tag of each tunnel message.
+    pub fn tag_len(self) -> usize {
+        match self {
+            CipherSuite::TruncatedDigest => 12,
+            CipherSuite::ShortHmac => 8,
+            CipherSuite::Hmac => 32,
+        }
+    }
+
+    pub(crate) const fn code(self) -> u8 {
+        match self {
+            CipherSuite::TruncatedDigest => 0,
+            CipherSuite::ShortHmac => 1,
+            CipherSuite::Hmac => 2,
+        }
+    }
+
+    pub(crate) fn from_code(code: u8) -> Option<Self> {
+        CipherSuite::ALL.iter().copied().find(|s| s.code() == code)
+    }
+}
+
+/// The version of the circuit handshake, negotiated along with the cipher suite.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub enum HandshakeVersion {
+    /// The session key only depends on the ephemeral keys, i.e. on a value chosen by the initiator
+    /// and a value the responder may reuse.
+    ///
+    /// This is the key schedule of peers predating the negotiation, which are told apart by the
+    /// layout of their handshake messages: unlike peers negotiating this version, they neither
+    /// send nor sign any suites.
+    Unsalted,
+    /// The responder adds a random nonce to its signed reply, which is mixed into the session key.
+    /// Every tag computed with the session key, starting with that of the first message of the
+    /// initiator, thus confirms the nonce.
+    ResponderNonce,
+    /// In addition to the responder nonce, the nonce of every opaque cell is a sequence number
+    /// which increases in each direction. A cell which does not continue the sequence, e.g. a
+    /// replayed or reordered one, tears the circuit down.
+    SequencedNonces,
+    /// In addition to the sequenced nonces, the initiator offers the number of its latest version
+    /// and feature flags, some of which it may require. The responder signs its own along with
+    /// the offer, and both are mixed into the session key. Later versions and features are
+    /// negotiated by these fields instead of the bits of the cipher suites.
+    FeatureFlags,
+    /// In addition to the feature flags, each direction has its own cipher and MAC key. The keys
+    /// are expanded from the shared secret with HKDF, each under its own label.
+    DirectionalKeys,
+}
+
+impl HandshakeVersion {
+    pub(crate) const ALL: [HandshakeVersion; 5] = [
+        HandshakeVersion::Unsalted,
+        HandshakeVersion::ResponderNonce,
+        HandshakeVersion::SequencedNonces,
+        HandshakeVersion::FeatureFlags,
+        HandshakeVersion::DirectionalKeys,
+    ];
+
+    /// The latest version supported by this crate.
+    pub(crate) const LATEST: HandshakeVersion = HandshakeVersion::DirectionalKeys;
+
+    /// Returns the number of this version, as exchanged from [`HandshakeVersion::FeatureFlags`]
+    /// on.
+    pub(crate) fn code(self) -> u8 {
+        match self {
+            HandshakeVersion::Unsalted => 0,
+            HandshakeVersion::ResponderNonce => 1,
+            HandshakeVersion::SequencedNonces => 2,
+            HandshakeVersion::FeatureFlags => 3,
+            HandshakeVersion::DirectionalKeys => 4,
+        }
+    }
+
+    pub(crate) fn from_code(code: u8) -> Option<Self> {
+        HandshakeVersion::ALL
+            .iter()
+            .copied()
+            .find(|v| v.code() == code)
+    }
+}
+
+/// The algorithm of a host key, which a responder announces along with its signed handshake reply.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum KeyType {
+    Rsa,
+    Ed25519,
+}
+
+impl KeyType {
+    pub(crate) fn code(self) -> u8 {
+        match self {
+            KeyType::Rsa => 0,
+            KeyType::Ed25519 => 1,
+        }
+    }
+}
+
+/// The private key identifying an onion router, which signs its handshake replies.
+///
+/// RSA keys need to have 4096 bits. Ed25519 keys are much smaller and faster to sign and verify
+/// with, but only peers of this version on know how to verify their signatures.
+// a host key is loaded once and then shared, so boxing the larger RSA key would not save anything
+#[allow(clippy::large_enum_variant)]
+#[non_exhaustive]
+pub enum HostKey {
+    Rsa(RsaPrivateKey),
+    Ed25519(Ed25519PrivateKey),
+}
+
+impl HostKey {
+    /// Reads a RSA or Ed25519 private key from the specified file, whichever it contains.
+    ///
+    /// See [`RsaPrivateKey::from_pem_file`] and [`Ed25519PrivateKey::from_pem_file`] for the
+    /// expected formats.
+    pub fn from_pem_file<P: AsRef<Path>>(path: P) -> Result<HostKey> {
+        let path = path.as_ref();
+        match Ed25519PrivateKey::from_pem_file(path) {
+            Ok(key) => Ok(HostKey::Ed25519(key)),
+            Err(_) => Ok(HostKey::Rsa(RsaPrivateKey::from_pem_file(path)?)),
+        }
+    }
+
+    /// Computes the corresponding public key.
+    pub fn public_key(&self) -> HostPublicKey {
+        match self {
+            HostKey::Rsa(key) => HostPublicKey::Rsa(key.public_key()),
+            HostKey::Ed25519(key) => HostPublicKey::Ed25519(key.public_key()),
+        }
+    }
+
+    pub(crate) fn key_type(&self) -> KeyType {
+        match self {
+            HostKey::Rsa(_) => KeyType::Rsa,
+            HostKey::Ed25519(_) => KeyType::Ed25519,
+        }
+    }
+
+    /// Returns the length of the signatures made with this key in bytes.
+    pub(crate) fn signature_len(&self) -> usize {
+        match self {
+            HostKey::Rsa(key) => key.size(),
+            HostKey::Ed25519(_) => ED25519_SIGNATURE_LEN,
+        }
+    }
+
+    /// Signs `data`, `signature` has to be [`signature_len`](Self::signature_len) bytes long.
+    pub(crate) fn sign(&self, data: &[u8], signature: &mut [u8]) -> Result<()> {
+        match self {
+            HostKey::Rsa(key) => key.sign(data, signature),
+            HostKey::Ed25519(key) => key.sign(data, signature),
+        }
+    }
+}
+
+impl From<RsaPrivateKey> for HostKey {
+    fn from(key: RsaPrivateKey) -> Self {
+        HostKey::Rsa(key)
+    }
+}
+
+impl From<Ed25519PrivateKey> for HostKey {
+    fn from(key: Ed25519PrivateKey) -> Self {
+        HostKey::Ed25519(key)
+    }
+}
+
+/// The public key of a [`HostKey`], which verifies the identity of a peer.
+#[derive(Clone)]
+#[non_exhaustive]
+pub enum HostPublicKey {
+    Rsa(RsaPublicKey),
+    Ed25519(Ed25519PublicKey),
+}
+
+impl HostPublicKey {
+    pub(crate) fn key_type(&self) -> KeyType {
+        match self {
+            HostPublicKey::Rsa(_) => KeyType::Rsa,
+            HostPublicKey::Ed25519(_) => KeyType::Ed25519,
+        }
+    }
+
+    /// Returns the SHA-256 digest of this key, which identifies the peer owning it.
+    pub(crate) fn fingerprint(&self) -> Fingerprint {
+        match self {
+            HostPublicKey::Rsa(key) => key.fingerprint(),
+            HostPublicKey::Ed25519(key) => key.fingerprint(),
+        }
+    }
+
+    pub(crate) fn verify(&self, data: &[u8], signature: &[u8]) -> Result<()> {
+        match self {
+            HostPublicKey::Rsa(key) => key.verify(data, signature),
+            HostPublicKey::Ed25519(key) => key.verify(data, signature),
+        }
+    }
+}
+
+impl From<RsaPublicKey> for HostPublicKey {
+    fn from(key: RsaPublicKey) -> Self {
+        HostPublicKey::Rsa(key)
+    }
+}
+
+impl From<Ed25519PublicKey> for HostPublicKey {
+    fn from(key: Ed25519PublicKey) -> Self {
+        HostPublicKey::Ed25519(key)
+    }
+}
+
+/// Key material which is overwritten with zeros when dropped.
+///
+/// The bytes are kept on the heap, so moving the key which owns them, e.g. when the session keys of
+/// a tunnel are moved to a new path or their `Vec` grows, does not leave copies behind.
+// the keys of ring are opaque, so the ring backend has none to keep
+#[cfg_attr(feature = "crypto_ring", allow(dead_code))]
+pub(crate) struct SecretBytes<const N: usize>(Box<[u8; N]>);
+
+#[cfg_attr(feature = "crypto_ring", allow(dead_code))]
+impl<const N: usize> SecretBytes<N> {
+    /// Copies the first `N` bytes of `bytes`, which must not be shorter.
+    pub(crate) fn from_slice(bytes: &[u8]) -> Self {
+        let mut secret = SecretBytes(Box::new([0u8; N]));
+        secret.0.copy_from_slice(&bytes[..N]);
+        secret
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+}
+
+impl<const N: usize> Drop for SecretBytes<N> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Draws a fresh nonce for a handshake reply.
+pub(crate) fn generate_handshake_nonce() -> HandshakeNonce {
+    let mut nonce = [0u8; HANDSHAKE_NONCE_LEN];
+    fill_random(&mut nonce);
+    nonce
+}
+
+/// A set of cipher suites, each represented by the bit at the position of its code.
+///
+/// The three highest bits announce the latest [`HandshakeVersion`], which is set in all sets built
+/// from a slice of suites.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CipherSuites(u8);
+
+impl CipherSuites {
+    /// The suites of a peer predating their negotiation, which only knows
+    /// [`CipherSuite::TruncatedDigest`] and [`HandshakeVersion::Unsalted`]. Such a peer neither sends
+    /// nor expects the suites after the ephemeral key, so handshakes offering exactly this set use
+    /// its layout, see [`NEGOTIATION_MARKER`](super::protocol::NEGOTIATION_MARKER).
+    pub(crate) const BASELINE: CipherSuites =
+        CipherSuites(1 << CipherSuite::TruncatedDigest.code());
+
+    pub(crate) fn all() -> Self {
+        Self::from_slice(&CipherSuite::ALL)
+    }
+
+    pub(crate) fn from_slice(suites: &[CipherSuite]) -> Self {
+        CipherSuites(
+            suites
+                .iter()
+                .fold(VERSION_BITS, |bits, s| bits | 1 << s.code()),
+        )
+    }
+
+    pub(crate) fn from_bits(bits: u8) -> Self {
+        CipherSuites(bits)
+    }
+
+    pub(crate) fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub(crate) fn contains(self, suite: CipherSuite) -> bool {
+        self.0 & 1 << suite.code() != 0
+    }
+
+    pub(crate) fn intersection(self, other: CipherSuites) -> Self {
+        CipherSuites(self.0 & other.0)
+    }
+
+    /// Returns the latest handshake version announced by this set.
+    pub(crate) fn version(self) -> HandshakeVersion {
+        if self.0 & RESPONDER_NONCE_BIT == 0 {
+            HandshakeVersion::Unsalted
+        } else if self.0 & SEQUENCED_NONCES_BIT == 0 {
+            HandshakeVersion::ResponderNonce
+        } else if self.0 & FEATURE_FLAGS_BIT == 0 {
+            HandshakeVersion::SequencedNonces
+        } else {
+            HandshakeVersion::FeatureFlags
+        }
+    }
+
+    /// Returns this set announcing `version` instead of its own version.
+    pub(crate) fn with_version(self, version: HandshakeVersion) -> Self {
+        let bits = self.0 & !VERSION_BITS;
+        match version {
+            HandshakeVersion::Unsalted => CipherSuites(bits),
+            HandshakeVersion::ResponderNonce => CipherSuites(bits | RESPONDER_NONCE_BIT),
+            HandshakeVersion::SequencedNonces => {
+                CipherSuites(bits | RESPONDER_NONCE_BIT | SEQUENCED_NONCES_BIT)
+            }
+            // later versions are announced by the feature offer
+            HandshakeVersion::FeatureFlags | HandshakeVersion::DirectionalKeys => {
+                CipherSuites(bits | VERSION_BITS)
+            }
+        }
+    }
+
+    /// Returns the strongest suite in this set, ignoring unknown suites.
+    pub(crate) fn strongest(self) -> Option<CipherSuite> {
+        CipherSuite::ALL
+            .iter()
+            .copied()
+            .filter(|&s| self.contains(s))
+            .max()
+    }
+}
+
+impl Default for CipherSuites {
+    fn default() -> Self {
+        CipherSuites::all()
+    }
+}
+
+/// The direction of a tunnel message relative to the peer which built the tunnel.
+///
+/// Keyed cipher suites use a separate key for each direction, so a message cannot be reflected
+/// back to its sender.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    /// From the tunnel initiator towards a hop.
+    Forward,
+    /// From a hop back to the tunnel initiator.
+    Backward,
+}
+
+/// The sequence numbers of the opaque cells exchanged with a single hop.
+///
+/// The nonce of an opaque cell starts with a big-endian sequence number, the highest bit of which
+/// is set on backward cells. The initiator numbers all forward cells of a tunnel with the sequence
+/// of the first hop, which sees all of them, so the numbers keep increasing when the tunnel is
+/// truncated or extended. Each hop numbers the backward cells it sends with its own sequence.
+/// Sequences start at a random number, so a nonce does not tell how many cells were sent.
+///
+/// Received numbers are only checked with hops which negotiated
+/// [`HandshakeVersion::SequencedNonces`], older peers draw random nonces.
+pub(crate) struct CellSequence {
+    checked: bool,
+    next: AtomicU64,
+    /// the highest number received, zero before the first cell
+    received: AtomicU64,
+}
+
+impl CellSequence {
+    pub(crate) fn new(checked: bool) -> Self {
+        let mut start = [0u8; 8];
+        fill_random(&mut start);
+        // leaves room for 2^62 cells below the direction bit
+        let start = (u64::from_be_bytes(start) >> 2).max(1);
+        CellSequence {
+            checked,
+            next: AtomicU64::new(start),
+            received: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the nonce of the next cell sent in `direction`.
+    pub(crate) fn next_nonce(&self, direction: Direction) -> [u8; NONCE_LEN] {
+        let mut number = self.next.fetch_add(1, Ordering::Relaxed);
+        if direction == Direction::Backward {
+            number |= BACKWARD_NONCE_BIT;
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..8].copy_from_slice(&number.to_be_bytes());
+        nonce
+    }
+
+    /// Returns whether the nonce of a cell received in `direction` continues the sequence and
+    /// records its number if so. Numbers have to increase, but may skip cells which were sent to
+    /// other hops of the tunnel.
+    pub(crate) fn accept(&self, nonce: &[u8; NONCE_LEN], direction: Direction) -> bool {
+        if !self.checked {
+            return true;
+        }
+        let mut number = [0u8; 8];
+        number.copy_from_slice(&nonce[..8]);
+        let number = u64::from_be_bytes(number);
+        let backward = number & BACKWARD_NONCE_BIT != 0;
+        if backward != (direction == Direction::Backward) {
+            return false;
+        }
+        let number = number & !BACKWARD_NONCE_BIT;
+        self.received
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |received| {
+                if number > received {
+                    Some(number)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HostKey, KeyType, ED25519_SIGNATURE_LEN};
+
+    /// The signature of the empty message in test 1 of RFC 8032, section 7.1, whose key is stored
+    /// in `tests/ed25519key.pem`.
+    const RFC8032_SIGNATURE: [u8; ED25519_SIGNATURE_LEN] = [
+        0xe5, 0x56, 0x43, 0x00, 0xc3, 0x60, 0xac, 0x72, 0x90, 0x86, 0xe2, 0xcc, 0x80, 0x6e, 0x82,
+        0x8a, 0x84, 0x87, 0x7f, 0x1e, 0xb8, 0xe5, 0xd9, 0x74, 0xd8, 0x73, 0xe0, 0x65, 0x22, 0x49,
+        0x01, 0x55, 0x5f, 0xb8, 0x82, 0x15, 0x90, 0xa3, 0x3b, 0xac, 0xc6, 0x1e, 0x39, 0x70, 0x1c,
+        0xf9, 0xb4, 0x6b, 0xd2, 0x5b, 0xf5, 0xf0, 0x59, 0x5b, 0xbe, 0x24, 0x65, 0x51, 0x41, 0x43,
+        0x8e, 0x7a, 0x10, 0x0b,
+    ];
+
+    #[test]
+    fn test_read_host_keys() {
+        let key = HostKey::from_pem_file("testkey.pem").unwrap();
+        assert_eq!(key.key_type(), KeyType::Rsa);
+        let key = HostKey::from_pem_file("tests/ed25519key.pem").unwrap();
+        assert_eq!(key.key_type(), KeyType::Ed25519);
+        assert_eq!(key.public_key().key_type(), KeyType::Ed25519);
+    }
+
+    #[test]
+    fn test_ed25519_signature() {
+        let key = HostKey::from_pem_file("tests/ed25519key.pem").unwrap();
+        let mut signature = [0u8; ED25519_SIGNATURE_LEN];
+        key.sign(b"", &mut signature).unwrap();
+        assert_eq!(signature, RFC8032_SIGNATURE);
+
+        let public_key = key.public_key();
+        public_key.verify(b"", &signature).unwrap();
+        assert!(public_key.verify(b"x", &signature).is_err());
+        signature[0] ^= 1;
+        assert!(public_key.verify(b"", &signature).is_err());
+    }
+
+    /// Runs the cryptographic part of a handshake between the `inner` backend and the `other`
+    /// one, followed by a cell in each direction.
+    #[cfg(any(
+        not(any(feature = "crypto_ring", feature = "crypto_rustcrypto")),
+        all(
+            feature = "crypto_openssl",
+            feature = "crypto_rustcrypto",
+            not(feature = "crypto_ring")
+        )
+    ))]
+    #[test]
+    fn test_backend_interop() {
+        use super::{inner, other, CipherSuite, Direction, HandshakeVersion, HANDSHAKE_NONCE_LEN};
+
+        // the host keys sign for each other
+        let inner_key = inner::RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+        let other_key = other::RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+        assert_eq!(
+            inner_key.public_key().fingerprint(),
+            other_key.public_key().fingerprint()
+        );
+        let mut signature = vec![0u8; inner_key.size()];
+        inner_key.sign(b"key", &mut signature).unwrap();
+        other_key.public_key().verify(b"key", &signature).unwrap();
+        other_key.sign(b"key", &mut signature).unwrap();
+        inner_key.public_key().verify(b"key", &signature).unwrap();
+
+        let inner_key = inner::Ed25519PrivateKey::from_pem_file("tests/ed25519key.pem").unwrap();
+        let other_key = other::Ed25519PrivateKey::from_pem_file("tests/ed25519key.pem").unwrap();
+        let mut signature = [0u8; ED25519_SIGNATURE_LEN];
+        inner_key.sign(b"key", &mut signature).unwrap();
+        other_key.public_key().verify(b"key", &signature).unwrap();
+        other_key.sign(b"key", &mut signature).unwrap();
+        inner_key.public_key().verify(b"key", &signature).unwrap();
+
+        // both sides derive the same session key from the exchanged ephemeral keys
+        let nonce = [1u8; HANDSHAKE_NONCE_LEN];
+        for &version in &HandshakeVersion::ALL {
+            for &suite in &CipherSuite::ALL {
+                let salt = (version >= HandshakeVersion::ResponderNonce).then_some(&nonce[..]);
+                let (initiator_private, initiator_public) = inner::generate_ephemeral_keypair();
+                let (responder_private, responder_public) = other::generate_ephemeral_keypair();
+                let responder_public =
+                    inner::EphemeralPublicKey::new(responder_public.bytes().clone());
+                let initiator_public =
+                    other::EphemeralPublicKey::new(initiator_public.bytes().clone());
+                let initiator = inner::SessionKey::from_key_exchange(
+                    initiator_private,
+                    &responder_public,
+                    suite,
+                    salt,
+                    version,
+                )
+                .unwrap();
+                let responder = other::SessionKey::from_key_exchange(
+                    responder_private,
+                    &initiator_public,
+                    suite,
+                    salt,
+                    version,
+                )
+                .unwrap();
+
+                let cell_nonce = [2u8; inner::NONCE_LEN];
+                let mut cell = *b"onion routing cell";
+                initiator
+                    .encrypt(Direction::Forward, cell_nonce, &mut cell)
+                    .unwrap();
+                responder
+                    .decrypt(Direction::Forward, cell_nonce, &mut cell)
+                    .unwrap();
+                assert_eq!(&cell, b"onion routing cell", "{:?} {:?}", version, suite);
+                responder
+                    .encrypt(Direction::Backward, cell_nonce, &mut cell)
+                    .unwrap();
+                initiator
+                    .decrypt(Direction::Backward, cell_nonce, &mut cell)
+                    .unwrap();
+                assert_eq!(&cell, b"onion routing cell", "{:?} {:?}", version, suite);
+                for &direction in &[Direction::Forward, Direction::Backward] {
+                    assert_eq!(
+                        initiator.mac(direction, &cell).as_ref(),
+                        responder.mac(direction, &cell).as_ref()
+                    );
+                }
+            }
+        }
+    }
+}
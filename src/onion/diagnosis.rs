@@ -0,0 +1,57 @@
+//! Attribution of path failures to single hops, see [`Tunnel::diagnose`](super::tunnel::Tunnel::diagnose).
+//!
+//! When the path of a tunnel fails while its circuit may still exist, the initiator probes the hops
+//! in path order with `TUNNEL ECHO` messages. The first hop which does not answer is suspected to
+//! have caused the failure and is avoided for a while when random hops are chosen.
+
+use crate::{Fingerprint, Peer};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// time a single hop has to answer a probe
+pub(crate) const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// time for which a suspected peer is not chosen as a random hop
+const SUSPICION_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// Peers suspected to have caused the failure of a path, shared by all tunnels of an onion router.
+#[derive(Clone, Default)]
+pub(crate) struct SuspectedPeers {
+    inner: Arc<Mutex<HashMap<Fingerprint, Instant>>>,
+}
+
+impl SuspectedPeers {
+    /// Records that the peer with the given fingerprint is suspected to have caused a failure.
+    pub(crate) fn suspect(&self, fingerprint: Fingerprint) {
+        let mut suspects = self.inner.lock().unwrap();
+        let now = Instant::now();
+        suspects.retain(|_, until| *until > now);
+        suspects.insert(fingerprint, now + SUSPICION_PERIOD);
+    }
+
+    /// Returns whether `peer` caused a failure within the suspicion period.
+    pub(crate) fn is_suspected(&self, peer: &Peer) -> bool {
+        let suspects = self.inner.lock().unwrap();
+        suspects
+            .get(&peer.fingerprint())
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    /// Returns the suspected peers together with the end of their suspicion.
+    pub(crate) fn entries(&self) -> Vec<(Fingerprint, Instant)> {
+        let now = Instant::now();
+        let suspects = self.inner.lock().unwrap();
+        suspects
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(fingerprint, until)| (*fingerprint, *until))
+            .collect()
+    }
+
+    /// Suspects the peer with the given fingerprint until `until`, unless it has passed.
+    pub(crate) fn insert(&self, fingerprint: Fingerprint, until: Instant) {
+        if until > Instant::now() {
+            self.inner.lock().unwrap().insert(fingerprint, until);
+        }
+    }
+}
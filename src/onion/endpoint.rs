@@ -0,0 +1,182 @@
+//! Bookkeeping of the incoming tunnels this onion router is the endpoint of, see [`Endpoints`].
+//!
+//! The initiator of a tunnel replaces its path every round by building a new one and sending
+//! `TUNNEL BEGIN` with the id of the tunnel on it. The application at the endpoint keeps using the
+//! same [`Tunnel`], only the path carrying its data is replaced. The superseded path is still read
+//! for [`DRAIN_TIMEOUT`], so data sent shortly before the switchover is not lost, and dropped
+//! afterwards, which sends `TUNNEL END` on it unless the initiator ended it already.
+
+use crate::onion::tunnel::TunnelId;
+use crate::onion::{has_room, RelayCounters, Tunnel, TunnelCounters, TunnelRegistry};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{self, Duration, Instant};
+
+/// time an incoming tunnel waits for a new path after its path was closed
+const REPLACEMENT_TIMEOUT: Duration = Duration::from_secs(1);
+/// time for which a superseded path is still read
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Maps the id of every open incoming tunnel to the task forwarding its data, which is handed the
+/// paths replacing the current one.
+///
+/// The [`IncomingTunnelInfo`](crate::IncomingTunnelInfo) in the [`TunnelRegistry`] is updated
+/// along with the bindings, so it always describes the path bound last.
+#[derive(Clone)]
+pub(crate) struct Endpoints {
+    bindings: Arc<Mutex<HashMap<TunnelId, mpsc::UnboundedSender<Tunnel>>>>,
+    registry: TunnelRegistry,
+}
+
+impl Endpoints {
+    pub(crate) fn new(registry: TunnelRegistry) -> Self {
+        Endpoints {
+            bindings: Default::default(),
+            registry,
+        }
+    }
+
+    /// Binds `path`, on which `TUNNEL BEGIN` was just received, to the incoming tunnel with the
+    /// same id.
+    ///
+    /// If there is no such tunnel, e.g. because it has been destroyed already, the path starts a
+    /// new incoming tunnel. Then a binding is created and the path is returned along with the
+    /// receiver of the paths replacing it, which have to be forwarded by the caller.
+    pub(crate) fn bind(&self, path: Tunnel) -> Option<(Tunnel, mpsc::UnboundedReceiver<Tunnel>)> {
+        let mut bindings = self.bindings.lock().unwrap();
+        let tunnel_id = path.id();
+        if let Some(info) = &path.incoming_info {
+            self.registry.insert_incoming(tunnel_id, info.clone());
+        }
+        let path = match bindings.get(&tunnel_id) {
+            Some(paths) => match paths.send(path) {
+                Ok(()) => return None,
+                Err(e) => e.0,
+            },
+            None => path,
+        };
+        let (paths_tx, paths_rx) = mpsc::unbounded_channel();
+        bindings.insert(tunnel_id, paths_tx);
+        Some((path, paths_rx))
+    }
+
+    /// Removes the binding of an incoming tunnel which has been destroyed.
+    ///
+    /// If another path was bound to the tunnel in the meantime, the binding is kept and the path
+    /// is returned instead. It starts a new incoming tunnel with the same id.
+    pub(crate) fn unbind(
+        &self,
+        tunnel_id: TunnelId,
+        paths: &mut mpsc::UnboundedReceiver<Tunnel>,
+    ) -> Option<Tunnel> {
+        let mut bindings = self.bindings.lock().unwrap();
+        match paths.try_recv() {
+            Ok(path) => Some(path),
+            Err(_) => {
+                bindings.remove(&tunnel_id);
+                self.registry.remove_incoming(tunnel_id);
+                None
+            }
+        }
+    }
+}
+
+/// A superseded path, which is counted in [`RelayStats`](crate::RelayStats) while it is drained.
+struct Draining {
+    path: Tunnel,
+    counters: Arc<RelayCounters>,
+}
+
+impl Draining {
+    fn new(path: Tunnel, counters: Arc<RelayCounters>) -> Self {
+        counters.draining_circuits.fetch_add(1, Ordering::Relaxed);
+        Draining { path, counters }
+    }
+}
+
+impl Drop for Draining {
+    fn drop(&mut self) {
+        self.counters
+            .draining_circuits
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Forwards data between `path` and the application until either side closes the tunnel.
+///
+/// `path` is replaced by every path received from `paths`, the superseded path is drained.
+pub(crate) async fn forward(
+    mut path: Tunnel,
+    paths: &mut mpsc::UnboundedReceiver<Tunnel>,
+    data_tx: mpsc::Sender<Bytes>,
+    mut data_rx: mpsc::UnboundedReceiver<Bytes>,
+    stats: Arc<TunnelCounters>,
+    counters: Arc<RelayCounters>,
+) -> Option<()> {
+    // data which did not fit into the buffer of the application
+    let mut pending = None;
+    // the path replaced last, a path replaced before is given up
+    let mut draining = None;
+    let drain_deadline = time::sleep(Duration::ZERO);
+    tokio::pin!(drain_deadline);
+    loop {
+        tokio::select! {
+            p = paths.recv() => {
+                draining = Some(Draining::new(mem::replace(&mut path, p?), counters.clone()));
+                drain_deadline.as_mut().reset(Instant::now() + DRAIN_TIMEOUT);
+                stats.rotations.fetch_add(1, Ordering::Relaxed);
+            }
+            // stop reading until the application makes room for the pending data, but keep
+            // forwarding its writes and accepting new paths
+            d = path.read(), if pending.is_none() => match d {
+                Ok(d) => deliver(&data_tx, d, &mut pending)?,
+                // during a rotation the old path may be closed before the new one arrives
+                Err(_) => {
+                    path = time::timeout(REPLACEMENT_TIMEOUT, paths.recv()).await.ok()??;
+                    stats.rotations.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            d = read_draining(&mut draining), if draining.is_some() && pending.is_none() => {
+                match d {
+                    Some(d) => deliver(&data_tx, d, &mut pending)?,
+                    None => draining = None,
+                }
+            }
+            _ = &mut drain_deadline, if draining.is_some() => draining = None,
+            true = has_room(&data_tx), if pending.is_some() => {
+                deliver(&data_tx, pending.take()?, &mut pending)?
+            }
+            d = data_rx.recv() => {
+                let d = d?;
+                let len = d.len();
+                path.write(d).ok()?;
+                stats.record_sent(1);
+                stats.sent_bytes.fetch_add(len as u64, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+async fn read_draining(draining: &mut Option<Draining>) -> Option<Bytes> {
+    draining.as_mut()?.path.read().await.ok()
+}
+
+/// Passes `data` on to the application without waiting for room in its buffer. Data which does
+/// not fit is kept in `pending`.
+///
+/// Returns `None` if the application closed the tunnel.
+fn deliver(data_tx: &mpsc::Sender<Bytes>, data: Bytes, pending: &mut Option<Bytes>) -> Option<()> {
+    match data_tx.try_send(data) {
+        Ok(()) => Some(()),
+        Err(TrySendError::Full(data)) => {
+            *pending = Some(data);
+            Some(())
+        }
+        Err(TrySendError::Closed(_)) => None,
+    }
+}
@@ -0,0 +1,143 @@
+//! Errors returned by the onion router, in addition to the [`anyhow::Error`]s of most methods.
+//!
+//! Errors attached to an [`anyhow::Error`] can be retrieved using `downcast_ref`. The types are
+//! re-exported at the root of the crate.
+
+use crate::onion::stats::BuildOutcome;
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+
+pub use crate::onion::shutdown::ShuttingDown;
+pub use crate::onion::startup::{StartError, StartProblem};
+pub use crate::ProviderClosed;
+
+/// The reason why no peer could be chosen for a hop constrained by
+/// [`TunnelOptions::set_hop`](crate::TunnelOptions::set_hop) or given by an explicit path, see
+/// [`OnionContext::build_tunnel_with_path`](crate::OnionContext::build_tunnel_with_path).
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum HopSelectionError {
+    /// The position is not part of the tunnel.
+    #[error("hop {position} does not exist or can not be constrained")]
+    InvalidPosition { position: usize },
+    /// No peer with the required fingerprint has been added.
+    #[error("the peer required for hop {position} is unknown")]
+    UnknownPeer { position: usize },
+    /// The required peer lacks the capabilities required for the tunnel.
+    #[error("the peer required for hop {position} lacks the required capabilities")]
+    MissingCapabilities { position: usize },
+    /// The required peer was rejected by the hop filter of the tunnel.
+    #[error("the peer required for hop {position} is rejected by the hop filter")]
+    Rejected { position: usize },
+    /// The tunnel could not be extended to the peer given for the hop by an explicit path.
+    ///
+    /// The destination follows the explicit path, so its position is the length of the path.
+    #[error("the tunnel could not be extended to the peer given for hop {position}")]
+    Unreachable { position: usize },
+}
+
+/// Returned if the hop filter of a tunnel rejected every peer drawn for one of its hops, see
+/// [`TunnelOptions::set_hop_filter`](crate::TunnelOptions::set_hop_filter).
+///
+/// Peers skipped for other reasons, e.g. lacking required capabilities, are not counted in
+/// `rejected`.
+#[derive(Error, Debug, PartialEq)]
+#[error("no acceptable peer for hop {position}, the hop filter rejected {rejected} peers")]
+pub struct NoAcceptablePeers {
+    pub position: usize,
+    pub rejected: usize,
+}
+
+/// Returned by [`OnionContext::build_tunnel`](crate::OnionContext::build_tunnel) if the path of
+/// the tunnel was built, but the tunnel broke before it became ready.
+///
+/// This happens if the destination or the last relay of a tunnel terminated at a relay does not
+/// accept the tunnel, or if the handler of the tunnel ended unexpectedly, which is reported as
+/// [`Event::Error`](crate::Event::Error).
+#[derive(Error, Debug, PartialEq)]
+#[error("the tunnel broke before it became ready")]
+pub struct TunnelBroken;
+
+/// Returned if the destination of a tunnel could not be added to its path several times in a row,
+/// e.g. because it is down. Failures of intermediate hops are not counted, since the build
+/// replaces such hops by other peers.
+///
+/// Builds of replacement paths which fail this way are reported as
+/// [`Event::RotationFailed`](crate::Event::RotationFailed).
+#[derive(Error, Debug, PartialEq)]
+#[error("the destination could not be reached after {failures} attempts, last {last_outcome:?}")]
+pub struct DestinationUnreachable {
+    pub failures: usize,
+    /// The outcome of the last attempt, e.g. [`BuildOutcome::ConnectFailed`] if the destination
+    /// is down or [`BuildOutcome::DeriveFailed`] if it does not hold the expected hostkey.
+    pub last_outcome: BuildOutcome,
+}
+
+/// Returned if the path of a tunnel could not be built since adding its hops failed too often,
+/// e.g. because most peers returned by the peer provider are down.
+///
+/// Failed hops are replaced by other peers, so this is only returned after `failures` attempts
+/// to add a hop failed.
+#[derive(Error, Debug, PartialEq)]
+#[error("the path could not be built, adding a hop failed {failures} times")]
+pub struct PathUnreachable {
+    pub failures: usize,
+}
+
+/// Returned if building the path of a tunnel did not complete within the deadline set by
+/// [`OnionBuilder::set_build_timeouts`](crate::OnionBuilder::set_build_timeouts).
+///
+/// Hops which time out are replaced by other peers until the deadline is reached, so this
+/// usually means that many peers were unresponsive.
+#[derive(Error, Debug, PartialEq)]
+#[error("building the tunnel did not complete within {deadline:?}")]
+pub struct BuildTimedOut {
+    pub deadline: Duration,
+}
+
+/// Returned when parsing an [`AddressRange`](crate::AddressRange) which is not a valid IP
+/// address, optionally followed by a prefix length.
+#[derive(Error, Debug, PartialEq)]
+#[error("invalid address range: {0:?}")]
+pub struct InvalidAddressRange(pub String);
+
+/// Returned by methods which only make sense for a relay if the onion router was started with
+/// [`OnionBuilder::client_only`](crate::OnionBuilder::client_only).
+#[derive(Error, Debug, PartialEq)]
+#[error("the onion router runs in client-only mode and does not relay")]
+pub struct NotARelay;
+
+/// Returned if a tunnel in strict mode could only be built or rotated by weakening its anonymity,
+/// see [`OnionBuilder::enable_strict_mode`](crate::OnionBuilder::enable_strict_mode).
+#[derive(Error, Debug, PartialEq)]
+#[error("strict mode forbids {which}")]
+pub struct StrictViolation {
+    pub which: Fallback,
+}
+
+/// A fallback which trades the anonymity of a tunnel for its availability.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Fallback {
+    /// Using a peer for more than one position of the path, because the peer provider returned no
+    /// other peer.
+    HopReuse,
+    /// Sending no padding, because the destination is not known to support
+    /// [`Capabilities::PADDING`](crate::Capabilities::PADDING).
+    NoPadding,
+    /// Using a first hop which is not an entry guard, because the options of the tunnel accept
+    /// none of the guards, see
+    /// [`OnionBuilder::set_entry_guards`](crate::OnionBuilder::set_entry_guards).
+    NoGuard,
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fallback::HopReuse => f.write_str("reusing a peer on the path"),
+            Fallback::NoPadding => f.write_str("sending no padding"),
+            Fallback::NoGuard => f.write_str("using a first hop which is no entry guard"),
+        }
+    }
+}
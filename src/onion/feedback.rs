@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+/// The number of written messages a [`WriteFeedback`] holds before further reports are dropped.
+pub(crate) const FEEDBACK_CAPACITY: usize = 1024;
+
+/// Identifies a message written with [`Tunnel::write_tracked`](crate::Tunnel::write_tracked).
+///
+/// Handles are assigned in the order the messages are queued, starting at zero for each tunnel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageHandle(u64);
+
+impl MessageHandle {
+    /// Returns the position of the message among all messages written to the tunnel.
+    pub fn sequence(self) -> u64 {
+        self.0
+    }
+}
+
+/// Reports that all `TUNNEL DATA` cells of a message have been written to the socket of the first
+/// hop, see [`WriteFeedback`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct WrittenMessage {
+    /// The handle returned when the message was written.
+    pub handle: MessageHandle,
+    /// The point in time at which the message was queued by the application.
+    pub queued_at: Instant,
+    /// The point in time at which writing the last cell of the message to the socket completed.
+    pub written_at: Instant,
+}
+
+/// A stream of [`WrittenMessage`]s, reporting when the messages written to a tunnel actually hit
+/// the wire.
+///
+/// Enable it with [`TunnelOptions::enable_write_feedback`](crate::TunnelOptions::enable_write_feedback)
+/// and obtain it with [`Tunnel::write_feedback`](crate::Tunnel::write_feedback). Messages are
+/// reported in the order they were written. Messages which are discarded, e.g. because the
+/// tunnel was closed first, are not reported.
+///
+/// At most 1024 reports are held until they are read. Reports which do not fit are dropped and
+/// counted in [`TunnelStats::dropped_feedback`](crate::TunnelStats::dropped_feedback).
+#[derive(Debug)]
+pub struct WriteFeedback(mpsc::Receiver<WrittenMessage>);
+
+impl WriteFeedback {
+    /// Returns the next written message.
+    ///
+    /// Returns `None` once the tunnel, its writers and the tasks serving it were dropped.
+    pub async fn next(&mut self) -> Option<WrittenMessage> {
+        self.0.recv().await
+    }
+
+    /// Returns the next written message if one has already been reported.
+    pub fn try_next(&mut self) -> Option<WrittenMessage> {
+        self.0.try_recv().ok()
+    }
+}
+
+/// The cells of a tunnel which have been queued but not yet written, for reporting written
+/// messages to a [`WriteFeedback`].
+#[derive(Debug)]
+pub(crate) struct FeedbackQueue {
+    next_handle: u64,
+    /// one entry for each queued cell, the handle is only set on the last cell of each message
+    cells: VecDeque<(Option<MessageHandle>, Instant)>,
+    tx: mpsc::Sender<WrittenMessage>,
+    rx: Option<mpsc::Receiver<WrittenMessage>>,
+    /// reports dropped because the receiver was full
+    dropped: u64,
+}
+
+impl FeedbackQueue {
+    pub(crate) fn new() -> Self {
+        let (tx, rx) = mpsc::channel(FEEDBACK_CAPACITY);
+        FeedbackQueue {
+            next_handle: 0,
+            cells: VecDeque::new(),
+            tx,
+            rx: Some(rx),
+            dropped: 0,
+        }
+    }
+
+    pub(crate) fn next_handle(&mut self) -> MessageHandle {
+        let handle = MessageHandle(self.next_handle);
+        self.next_handle += 1;
+        handle
+    }
+
+    /// Records a queued cell, which is the last cell of the message `handle`, if set.
+    pub(crate) fn push(&mut self, handle: Option<MessageHandle>, queued_at: Instant) {
+        self.cells.push_back((handle, queued_at));
+    }
+
+    /// Reports the message `handle` written without sending any cells, as it is empty.
+    pub(crate) fn report_empty(&mut self, handle: MessageHandle, queued_at: Instant) {
+        self.report(handle, queued_at, queued_at);
+    }
+
+    /// Records the `n` oldest queued cells being written.
+    pub(crate) fn written(&mut self, n: usize) {
+        let written_at = Instant::now();
+        for _ in 0..n {
+            match self.cells.pop_front() {
+                Some((Some(handle), queued_at)) => self.report(handle, queued_at, written_at),
+                Some((None, _)) => {}
+                None => break,
+            }
+        }
+    }
+
+    /// Records the `n` oldest queued cells being dropped without being written.
+    pub(crate) fn discarded(&mut self, n: usize) {
+        let n = n.min(self.cells.len());
+        self.cells.drain(..n);
+    }
+
+    /// Hands out the receiving end of the feedback, only once.
+    pub(crate) fn take_receiver(&mut self) -> Option<WriteFeedback> {
+        self.rx.take().map(WriteFeedback)
+    }
+
+    /// Returns the number of reports dropped because the [`WriteFeedback`] was full.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn report(&mut self, handle: MessageHandle, queued_at: Instant, written_at: Instant) {
+        let written = WrittenMessage {
+            handle,
+            queued_at,
+            written_at,
+        };
+        // the application is free to ignore the feedback, but must not make it grow without limit
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(written) {
+            self.dropped += 1;
+        }
+    }
+}
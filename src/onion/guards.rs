@@ -0,0 +1,141 @@
+//! Entry guards, a small set of peers which are the only first hops of the tunnels built by an
+//! onion router, see [`OnionBuilder::set_entry_guards`](crate::OnionBuilder::set_entry_guards).
+//!
+//! Choosing the first hop anew for every path eventually picks a malicious peer, which then learns
+//! the address of the initiator. Guards limit this to the few peers chosen once.
+
+use crate::onion::crypto;
+use crate::onion::Event;
+use crate::Peer;
+use log::info;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// number of consecutive failed handshakes after which a guard is replaced
+pub(crate) const MAX_GUARD_FAILURES: u32 = 3;
+
+struct Guard {
+    peer: Peer,
+    /// consecutive failed attempts to use the guard as first hop
+    failures: u32,
+}
+
+#[derive(Default)]
+struct GuardSet {
+    size: usize,
+    guards: Vec<Guard>,
+}
+
+/// The entry guards shared by all tunnels of an onion router.
+///
+/// Guards are told apart by their address, like the hops of a path.
+#[derive(Clone, Default)]
+pub(crate) struct EntryGuards {
+    inner: Arc<Mutex<GuardSet>>,
+    /// receives [`Event::GuardsChanged`]
+    notify: Option<broadcast::Sender<Event>>,
+}
+
+impl EntryGuards {
+    /// Creates a set of up to `size` guards, starting with the first of `guards`. A size of zero
+    /// disables guards.
+    pub(crate) fn new(size: usize, guards: Vec<Peer>, notify: broadcast::Sender<Event>) -> Self {
+        let mut set = GuardSet {
+            size,
+            guards: Vec::new(),
+        };
+        for peer in guards {
+            if set.guards.len() < size && !set.contains(&peer) {
+                set.guards.push(Guard { peer, failures: 0 });
+            }
+        }
+        EntryGuards {
+            inner: Arc::new(Mutex::new(set)),
+            notify: Some(notify),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.inner.lock().unwrap().size > 0
+    }
+
+    /// Returns whether there are fewer guards than the configured number.
+    pub(crate) fn is_missing(&self) -> bool {
+        let set = self.inner.lock().unwrap();
+        set.guards.len() < set.size
+    }
+
+    /// Returns the current guards, oldest first.
+    pub(crate) fn peers(&self) -> Vec<Peer> {
+        let set = self.inner.lock().unwrap();
+        set.guards.iter().map(|guard| guard.peer.clone()).collect()
+    }
+
+    /// Returns a random guard accepted by `acceptable`, if any.
+    pub(crate) fn choose(&self, acceptable: impl Fn(&Peer) -> bool) -> Option<Peer> {
+        let set = self.inner.lock().unwrap();
+        let candidates: Vec<_> = set
+            .guards
+            .iter()
+            .map(|guard| &guard.peer)
+            .filter(|peer| acceptable(peer))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let mut index = [0u8; 4];
+        crypto::fill_random(&mut index);
+        let index = u32::from_le_bytes(index) as usize % candidates.len();
+        Some(candidates[index].clone())
+    }
+
+    /// Adds `peer` as a guard, unless it already is one or the set is complete. Returns whether
+    /// the peer was added.
+    pub(crate) fn add(&self, peer: Peer) -> bool {
+        let mut set = self.inner.lock().unwrap();
+        if set.guards.len() >= set.size || set.contains(&peer) {
+            return false;
+        }
+        info!("Choosing {:?} as entry guard", peer);
+        set.guards.push(Guard { peer, failures: 0 });
+        self.notify_changed(&set);
+        true
+    }
+
+    /// Records whether the first hop of a path could be added to `peer`. A guard which failed
+    /// [`MAX_GUARD_FAILURES`] times in a row is demoted, so another peer takes its place.
+    pub(crate) fn record(&self, peer: &Peer, success: bool) {
+        let mut set = self.inner.lock().unwrap();
+        let position = match set.guards.iter().position(|g| g.peer.addr == peer.addr) {
+            Some(position) => position,
+            None => return,
+        };
+        let guard = &mut set.guards[position];
+        if success {
+            guard.failures = 0;
+            return;
+        }
+        guard.failures += 1;
+        if guard.failures >= MAX_GUARD_FAILURES {
+            info!(
+                "Demoting entry guard {:?} after {} failures",
+                peer, guard.failures
+            );
+            set.guards.remove(position);
+            self.notify_changed(&set);
+        }
+    }
+
+    fn notify_changed(&self, set: &GuardSet) {
+        if let Some(notify) = &self.notify {
+            let guards = set.guards.iter().map(|g| g.peer.fingerprint()).collect();
+            let _ = notify.send(Event::GuardsChanged { guards });
+        }
+    }
+}
+
+impl GuardSet {
+    fn contains(&self, peer: &Peer) -> bool {
+        self.guards.iter().any(|guard| guard.peer.addr == peer.addr)
+    }
+}
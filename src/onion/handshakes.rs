@@ -0,0 +1,116 @@
+//! Limits the circuit handshakes an onion router initiates with the same peer at the same time,
+//! see [`OnionBuilder::set_max_handshakes_per_peer`](crate::OnionBuilder::set_max_handshakes_per_peer).
+//!
+//! A burst of builds through a popular first hop would otherwise start a handshake with it for
+//! every tunnel at once, which relays may rate-limit. Excess handshakes wait for a permit instead
+//! of failing.
+
+use crate::onion::stats::PeerHandshakes;
+use crate::Fingerprint;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub(crate) const DEFAULT_MAX_HANDSHAKES_PER_PEER: usize = 4;
+
+/// The handshakes initiated with each peer, shared by all tunnels of an onion router.
+#[derive(Clone, Debug)]
+pub(crate) struct HandshakeLimiter {
+    limit: usize,
+    /// only peers with handshakes in flight or waiting have an entry
+    peers: Arc<Mutex<HashMap<Fingerprint, PeerSlot>>>,
+}
+
+#[derive(Debug)]
+struct PeerSlot {
+    permits: Arc<Semaphore>,
+    waiting: usize,
+}
+
+impl HandshakeLimiter {
+    pub(crate) fn new(limit: usize) -> Self {
+        HandshakeLimiter {
+            limit,
+            peers: Default::default(),
+        }
+    }
+
+    /// Waits until a handshake with the peer with the given fingerprint may be started.
+    ///
+    /// The handshake counts as in flight until the returned permit is dropped. This function is
+    /// cancellation safe.
+    pub(crate) async fn acquire(&self, fingerprint: Fingerprint) -> HandshakePermit {
+        let permits = {
+            let mut peers = self.peers.lock().unwrap();
+            let slot = peers.entry(fingerprint).or_insert_with(|| PeerSlot {
+                permits: Arc::new(Semaphore::new(self.limit)),
+                waiting: 0,
+            });
+            slot.waiting += 1;
+            slot.permits.clone()
+        };
+        // counts as waiting until the permit is set, even if this future is dropped
+        let mut permit = HandshakePermit {
+            limiter: self.clone(),
+            fingerprint,
+            permit: None,
+        };
+        // the semaphore is never closed
+        let acquired = permits.acquire_owned().await.unwrap();
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(slot) = peers.get_mut(&fingerprint) {
+            slot.waiting -= 1;
+        }
+        permit.permit = Some(acquired);
+        permit
+    }
+
+    /// Returns the peers with handshakes in flight or waiting.
+    pub(crate) fn snapshot(&self) -> Vec<PeerHandshakes> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .iter()
+            .map(|(fingerprint, slot)| PeerHandshakes {
+                fingerprint: *fingerprint,
+                in_flight: self.limit - slot.permits.available_permits(),
+                waiting: slot.waiting,
+            })
+            .collect()
+    }
+
+    fn release(&self, fingerprint: Fingerprint, permit: Option<OwnedSemaphorePermit>) {
+        let mut peers = self.peers.lock().unwrap();
+        let slot = match peers.get_mut(&fingerprint) {
+            Some(slot) => slot,
+            None => return,
+        };
+        match permit {
+            // returned to the semaphore while the lock is held
+            Some(permit) => drop(permit),
+            None => slot.waiting -= 1,
+        }
+        if slot.waiting == 0 && slot.permits.available_permits() == self.limit {
+            peers.remove(&fingerprint);
+        }
+    }
+}
+
+impl Default for HandshakeLimiter {
+    fn default() -> Self {
+        HandshakeLimiter::new(DEFAULT_MAX_HANDSHAKES_PER_PEER)
+    }
+}
+
+/// Allows a single handshake with a peer, see [`HandshakeLimiter::acquire`].
+#[derive(Debug)]
+pub(crate) struct HandshakePermit {
+    limiter: HandshakeLimiter,
+    fingerprint: Fingerprint,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for HandshakePermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.fingerprint, self.permit.take());
+    }
+}
@@ -0,0 +1,139 @@
+//! Prioritization of the messages sent on a circuit, see [`Lanes`].
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+
+/// maximum number of control messages sent in a row while data is waiting
+const MAX_CONTROL_STREAK: usize = 16;
+
+/// The priority class of an outgoing message. Lanes are listed from highest to lowest priority.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Lane {
+    /// Messages managing the tunnel, e.g. `END` or keep-alives.
+    Control,
+    /// Data written by the application.
+    Data,
+    /// Cover traffic.
+    Cover,
+}
+
+impl Lane {
+    fn index(self) -> usize {
+        match self {
+            Lane::Control => 0,
+            Lane::Data => 1,
+            Lane::Cover => 2,
+        }
+    }
+}
+
+/// A message queued for sending on a circuit.
+#[derive(Debug)]
+pub(crate) enum Outgoing {
+    /// `TUNNEL END`, sent once the application closed the tunnel. Queued data is dropped.
+    End,
+    KeepAlive,
+    Data(Bytes),
+    /// `TUNNEL PADDING` filling a whole cell, which the destination drops.
+    Padding,
+}
+
+/// Queues of outgoing messages, one per [`Lane`].
+///
+/// Messages are taken strictly in the order of their lanes and in FIFO order within a lane, so
+/// control messages are not delayed by queued data. To bound the delay of data in the unexpected
+/// case of constant control traffic, a data message is taken after `MAX_CONTROL_STREAK` control
+/// messages in a row.
+#[derive(Debug)]
+pub(crate) struct Lanes<T> {
+    queues: [VecDeque<T>; 3],
+    /// number of control messages taken since the last message of another lane
+    control_streak: usize,
+}
+
+impl<T> Lanes<T> {
+    pub(crate) fn new() -> Self {
+        Lanes {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            control_streak: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, lane: Lane, msg: T) {
+        self.queues[lane.index()].push_back(msg);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.iter().all(VecDeque::is_empty)
+    }
+
+    /// Removes all messages of `lane` and returns their number.
+    pub(crate) fn clear(&mut self, lane: Lane) -> usize {
+        let queue = &mut self.queues[lane.index()];
+        let n = queue.len();
+        queue.clear();
+        n
+    }
+
+    /// Returns the lane the next message is taken from.
+    fn next_lane(&self) -> Option<Lane> {
+        let waiting = |lane: Lane| !self.queues[lane.index()].is_empty();
+        if waiting(Lane::Control)
+            && !(self.control_streak >= MAX_CONTROL_STREAK && waiting(Lane::Data))
+        {
+            Some(Lane::Control)
+        } else if waiting(Lane::Data) {
+            Some(Lane::Data)
+        } else if waiting(Lane::Cover) {
+            Some(Lane::Cover)
+        } else {
+            None
+        }
+    }
+
+    /// Takes the next message.
+    pub(crate) fn pop(&mut self) -> Option<(Lane, T)> {
+        let lane = self.next_lane()?;
+        self.control_streak = match lane {
+            Lane::Control => self.control_streak + 1,
+            _ => 0,
+        };
+        let msg = self.queues[lane.index()].pop_front()?;
+        Some((lane, msg))
+    }
+
+    /// Takes the next message and, unless it is a control message, up to `max - 1` further
+    /// messages of the same lane, which may be sent in a single write.
+    pub(crate) fn pop_batch(&mut self, max: usize) -> Option<(Lane, Vec<T>)> {
+        let (lane, msg) = self.pop()?;
+        let mut batch = vec![msg];
+        if lane != Lane::Control {
+            let queue = &mut self.queues[lane.index()];
+            while batch.len() < max {
+                match queue.pop_front() {
+                    Some(msg) => batch.push(msg),
+                    None => break,
+                }
+            }
+        }
+        Some((lane, batch))
+    }
+}
+
+impl Lanes<Outgoing> {
+    /// Takes the next batch of at most `max` messages, see [`pop_batch`](Lanes::pop_batch), and
+    /// separates the payloads of its data messages, which are sent in a single write, from the
+    /// other messages.
+    pub(crate) fn pop_outgoing(&mut self, max: usize) -> Option<(Vec<Outgoing>, Vec<Bytes>)> {
+        let (_, batch) = self.pop_batch(max)?;
+        let mut other = Vec::new();
+        let mut data = Vec::with_capacity(batch.len());
+        for msg in batch {
+            match msg {
+                Outgoing::Data(bytes) => data.push(bytes),
+                msg => other.push(msg),
+            }
+        }
+        Some((other, data))
+    }
+}
@@ -0,0 +1,139 @@
+//! Histograms of the time cells spend in a relay, see [`Histogram`].
+//!
+//! Durations are counted in buckets of fixed bounds, like in an HDR histogram: every power of two
+//! of microseconds is split into [`SUB_BUCKETS`] buckets of equal width, so the reported
+//! percentiles are at most 12.5 % above the true value. Recording is a single relaxed atomic
+//! increment, which keeps the cost per relayed cell negligible.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// number of bits of a duration kept below its highest bit
+const SUB_BUCKET_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// highest bit of the longest duration in microseconds told apart, about 67 seconds
+const MAX_EXPONENT: u32 = 26;
+const BUCKETS: usize = (MAX_EXPONENT - SUB_BUCKET_BITS + 2) as usize * SUB_BUCKETS;
+
+/// A histogram recorded by many tasks at once.
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    counts: [AtomicU64; BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Histogram {
+    pub(crate) fn record(&self, duration: Duration) {
+        self.counts[bucket(duration)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        for count in &self.counts {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> LatencyHistogram {
+        let mut counts = self
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        // snapshots of equal histograms compare equal, regardless of the number of buckets
+        while counts.last() == Some(&0) {
+            counts.pop();
+        }
+        let total = counts.iter().sum();
+        LatencyHistogram { counts, total }
+    }
+}
+
+/// Returns the index of the bucket counting `duration`.
+fn bucket(duration: Duration) -> usize {
+    let micros = duration.as_micros().min(u128::from(u64::MAX)) as u64;
+    if micros < SUB_BUCKETS as u64 {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros();
+    if exponent > MAX_EXPONENT {
+        return BUCKETS - 1;
+    }
+    let sub_bucket = (micros >> (exponent - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    (exponent - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub_bucket
+}
+
+/// Returns the longest duration counted by the bucket with the given index.
+fn upper_bound(index: usize) -> Duration {
+    let micros = if index < SUB_BUCKETS {
+        index as u64
+    } else {
+        let exponent = (index / SUB_BUCKETS) as u32 + SUB_BUCKET_BITS - 1;
+        let sub_bucket = (index % SUB_BUCKETS) as u64;
+        let width = 1u64 << (exponent - SUB_BUCKET_BITS);
+        (1u64 << exponent) + (sub_bucket + 1) * width - 1
+    };
+    Duration::from_micros(micros)
+}
+
+/// The distribution of the time cells spent in this onion router, see
+/// [`RelayStats::forwarding_latency`](crate::RelayStats::forwarding_latency).
+///
+/// Durations are counted in buckets whose width grows with the duration, so a percentile is
+/// reported as the longest duration of its bucket, which is at most 12.5 % above the true value.
+/// Durations of more than about 67 seconds are all counted in the last bucket.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl LatencyHistogram {
+    /// Returns the number of recorded durations.
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the duration below or at which the given percentage of the recorded durations
+    /// lie, or `None` if nothing has been recorded.
+    ///
+    /// # Panics
+    /// Panics if `percentage` is not between 0 and 100.
+    pub fn percentile(&self, percentage: f64) -> Option<Duration> {
+        assert!(
+            (0.0..=100.0).contains(&percentage),
+            "percentage {} is not between 0 and 100",
+            percentage
+        );
+        if self.total == 0 {
+            return None;
+        }
+        let rank = ((percentage / 100.0 * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.counts.iter().enumerate().find_map(|(index, &count)| {
+            seen += count;
+            (seen >= rank).then(|| upper_bound(index))
+        })
+    }
+
+    /// Returns the median of the recorded durations.
+    pub fn p50(&self) -> Option<Duration> {
+        self.percentile(50.0)
+    }
+
+    /// Returns the 95th percentile of the recorded durations.
+    pub fn p95(&self) -> Option<Duration> {
+        self.percentile(95.0)
+    }
+
+    /// Returns the 99th percentile of the recorded durations.
+    pub fn p99(&self) -> Option<Duration> {
+        self.percentile(99.0)
+    }
+}
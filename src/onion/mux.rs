@@ -0,0 +1,577 @@
+//! Independent byte streams multiplexed over a single tunnel, see [`StreamMux`].
+//!
+//! Both ends of the tunnel frame the data of every stream:
+//! ```text
+//! stream_id: u16
+//! kind: u8
+//! length: u16
+//! payload: [u8; length]
+//! ```
+//! The frames are carried in `TUNNEL DATA` cells like any other data of the tunnel, so relays can
+//! not tell multiplexed tunnels apart. Each end starts with a `HELLO` frame on stream 0 carrying
+//! its version, and holds back all other frames until it received the `HELLO` of the other end.
+//! Streams are opened with `OPEN`, the initiator of the tunnel uses odd stream ids and the
+//! destination even ones. `CLOSE` ends a stream in both directions. The sender of a stream may
+//! have [`STREAM_WINDOW`] bytes of `DATA` outstanding, `WINDOW` frames grant more once the
+//! application read them.
+
+use crate::onion::tunnel::TunnelId;
+use crate::onion::Tunnel;
+use crate::task;
+use crate::Result;
+use anyhow::anyhow;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::{debug, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 5;
+/// the first frame sent by each end
+const HELLO: [u8; HEADER_LEN + 1] = [0, 0, 0, 0, 1, VERSION];
+/// bytes of a stream which may be sent before the other end granted more
+const STREAM_WINDOW: u32 = 64 * 1024;
+/// maximum number of streams open at the same time, in each direction
+const MAX_STREAMS: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FrameKind {
+    Hello,
+    Open,
+    Data,
+    Close,
+    Window,
+}
+
+impl FrameKind {
+    fn code(self) -> u8 {
+        match self {
+            FrameKind::Hello => 0,
+            FrameKind::Open => 1,
+            FrameKind::Data => 2,
+            FrameKind::Close => 3,
+            FrameKind::Window => 4,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(FrameKind::Hello),
+            1 => Some(FrameKind::Open),
+            2 => Some(FrameKind::Data),
+            3 => Some(FrameKind::Close),
+            4 => Some(FrameKind::Window),
+            _ => None,
+        }
+    }
+}
+
+enum Command {
+    Open(oneshot::Sender<Result<MuxStream>>),
+    Write(u16, Bytes),
+    /// the application read the given number of bytes of the stream
+    Credit(u16, u32),
+    Close(u16),
+    /// the [`StreamMux`] was dropped
+    Detach,
+}
+
+/// Carries multiple independent byte streams over a single [`Tunnel`].
+///
+/// Building a tunnel for every connection to the same destination is expensive, so both ends may
+/// instead wrap the tunnel in a multiplexer. Either end opens streams with
+/// [`StreamMux::open_stream`], which the other end receives from [`StreamMux::accept`]. Closing
+/// a stream, by dropping it, does not affect the other streams of the tunnel.
+///
+/// The streams share the tunnel in turns, one cell worth of data per stream. Each stream may have
+/// 64 KiB in flight, which the other end has not read yet, so a stream which is not read does not
+/// hold up the others.
+///
+/// The other end must wrap the tunnel as well. Until it does, nothing but a greeting is sent and
+/// data written to the streams is only queued. If it sends anything else, the multiplexer is
+/// closed. Peers whose application accepts multiplexed tunnels may advertise
+/// [`Capabilities::STREAMS`](crate::Capabilities::STREAMS).
+///
+/// The tunnel is closed once the multiplexer and all of its streams are dropped, discarding data
+/// which has not been sent yet.
+#[derive(Debug)]
+pub struct StreamMux {
+    tunnel_id: TunnelId,
+    commands: mpsc::UnboundedSender<Command>,
+    incoming: mpsc::UnboundedReceiver<MuxStream>,
+}
+
+impl StreamMux {
+    /// Wraps `tunnel` in a multiplexer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime.
+    pub fn new(tunnel: Tunnel) -> Self {
+        let tunnel_id = tunnel.id();
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let max_payload = (tunnel.cell_size().max_data_size() - HEADER_LEN).min(u16::MAX as usize);
+        let driver = Driver {
+            // the destination of a tunnel does not build it
+            next_id: if tunnel.incoming_info().is_some() {
+                2
+            } else {
+                1
+            },
+            tunnel,
+            detached: false,
+            max_payload,
+            commands_tx: commands_tx.clone(),
+            commands: commands_rx,
+            incoming: incoming_tx,
+            streams: BTreeMap::new(),
+            peer_ready: false,
+            read_buf: BytesMut::new(),
+            out: BytesMut::new(),
+        };
+        task::spawn_with(
+            "task.stream_mux",
+            format!("tunnel {}", tunnel_id),
+            async move {
+                if let Err(e) = driver.run().await {
+                    warn!("Multiplexer of tunnel {} failed: {}", tunnel_id, e);
+                }
+            },
+            |_| (),
+        );
+        StreamMux {
+            tunnel_id,
+            commands: commands_tx,
+            incoming: incoming_rx,
+        }
+    }
+
+    /// Returns the id of the wrapped tunnel.
+    pub fn tunnel_id(&self) -> TunnelId {
+        self.tunnel_id
+    }
+
+    /// Opens a new stream to the other end of the tunnel.
+    ///
+    /// Returns an error if the tunnel was closed or too many streams are open.
+    pub async fn open_stream(&self) -> Result<MuxStream> {
+        let (stream_tx, stream_rx) = oneshot::channel();
+        self.commands
+            .send(Command::Open(stream_tx))
+            .map_err(|_| anyhow!("Connection closed."))?;
+        stream_rx.await.map_err(|_| anyhow!("Connection closed."))?
+    }
+
+    /// Returns the next stream opened by the other end of the tunnel, or `None` once the tunnel
+    /// was closed.
+    pub async fn accept(&mut self) -> Option<MuxStream> {
+        self.incoming.recv().await
+    }
+}
+
+impl Drop for StreamMux {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Detach);
+    }
+}
+
+/// A byte stream multiplexed over a tunnel by a [`StreamMux`].
+///
+/// Like [`OnionStream`](crate::OnionStream), writes are only queued and reads return EOF once the
+/// stream was closed by the other end or the tunnel was closed. Dropping the stream closes it,
+/// after the data written to it has been sent.
+#[derive(Debug)]
+pub struct MuxStream {
+    id: u16,
+    commands: mpsc::UnboundedSender<Command>,
+    data_rx: mpsc::UnboundedReceiver<Bytes>,
+    /// set once the other end closed the stream
+    closed: Arc<AtomicBool>,
+    /// the rest of the chunk which did not fit into the buffer of the last read
+    read_buf: Bytes,
+    /// bytes read since the last credit was granted to the other end
+    unacknowledged: u32,
+}
+
+impl MuxStream {
+    /// Returns the id of this stream, which is unique among the open streams of the tunnel.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Receive data from the other end, in chunks as it arrives.
+    ///
+    /// Returns an error if the stream was closed.
+    pub async fn read(&mut self) -> Result<Bytes> {
+        if !self.read_buf.is_empty() {
+            return Ok(std::mem::take(&mut self.read_buf));
+        }
+        let data = self.data_rx.recv().await.ok_or(anyhow!("Stream closed."))?;
+        self.acknowledge(data.len());
+        Ok(data)
+    }
+
+    /// Send data to the other end.
+    ///
+    /// The data is only queued, so this never blocks. Returns an error if the stream was closed.
+    pub fn write(&self, buf: Bytes) -> Result<()> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(anyhow!("Stream closed."));
+        }
+        self.commands
+            .send(Command::Write(self.id, buf))
+            .map_err(|_| anyhow!("Stream closed."))
+    }
+
+    /// Grants more credit to the other end once half of the window was read.
+    fn acknowledge(&mut self, len: usize) {
+        self.unacknowledged += len as u32;
+        if self.unacknowledged >= STREAM_WINDOW / 2 {
+            let _ = self
+                .commands
+                .send(Command::Credit(self.id, self.unacknowledged));
+            self.unacknowledged = 0;
+        }
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Close(self.id));
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read_buf.is_empty() {
+            match self.data_rx.poll_recv(cx) {
+                Poll::Ready(Some(data)) => {
+                    self.acknowledge(data.len());
+                    self.read_buf = data;
+                }
+                // EOF
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = self.read_buf.len().min(buf.remaining());
+        buf.put_slice(&self.read_buf[..len]);
+        self.read_buf.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = self
+            .write(Bytes::copy_from_slice(buf))
+            .map(|_| buf.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()));
+        Poll::Ready(written)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct StreamState {
+    /// delivers received data to the [`MuxStream`]
+    data_tx: mpsc::UnboundedSender<Bytes>,
+    closed: Arc<AtomicBool>,
+    /// data written, but not sent yet
+    pending: VecDeque<Bytes>,
+    /// bytes which may still be sent
+    send_window: u32,
+    /// bytes which the other end may still send
+    recv_window: u32,
+    /// set once the [`MuxStream`] was dropped, `CLOSE` is sent after the pending data
+    closing: bool,
+}
+
+impl StreamState {
+    fn is_sendable(&self) -> bool {
+        !self.pending.is_empty() && self.send_window > 0
+    }
+}
+
+impl Drop for StreamState {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Owns the tunnel of a [`StreamMux`], framing the data of its streams.
+struct Driver {
+    tunnel: Tunnel,
+    max_payload: usize,
+    /// the id of the next stream opened by this end, odd or even depending on the end
+    next_id: u16,
+    /// set once the [`StreamMux`] was dropped, the tunnel is closed with the last stream
+    detached: bool,
+    /// handed to the streams opened by the other end
+    commands_tx: mpsc::UnboundedSender<Command>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    incoming: mpsc::UnboundedSender<MuxStream>,
+    streams: BTreeMap<u16, StreamState>,
+    /// set once the `HELLO` of the other end was received
+    peer_ready: bool,
+    /// received data which does not form a complete frame yet
+    read_buf: BytesMut,
+    /// frames to be written to the tunnel
+    out: BytesMut,
+}
+
+impl Driver {
+    /// Runs until the tunnel is closed, the other end violates the protocol, or the multiplexer
+    /// and all of its streams are dropped.
+    async fn run(mut self) -> Result<()> {
+        self.out.extend_from_slice(&HELLO);
+        if self.tunnel.write(self.out.split().freeze()).is_err() {
+            return Ok(());
+        }
+        while !self.detached || !self.streams.is_empty() {
+            let sendable = self.peer_ready && self.streams.values().any(StreamState::is_sendable);
+            tokio::select! {
+                data = self.tunnel.read() => match data {
+                    Ok(data) => self.receive(data)?,
+                    Err(_) => return Ok(()),
+                },
+                // never closed, the driver holds a sender itself
+                Some(command) = self.commands.recv() => self.handle_command(command),
+                _ = async {}, if sendable => self.send_round(),
+            }
+            if self.peer_ready
+                && !self.out.is_empty()
+                && self.tunnel.write(self.out.split().freeze()).is_err()
+            {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    fn queue_frame(&mut self, id: u16, kind: FrameKind, payload: &[u8]) {
+        self.out.reserve(HEADER_LEN + payload.len());
+        self.out.put_u16(id);
+        self.out.put_u8(kind.code());
+        self.out.put_u16(payload.len() as u16);
+        self.out.put_slice(payload);
+    }
+
+    fn receive(&mut self, data: Bytes) -> Result<()> {
+        self.read_buf.extend_from_slice(&data);
+        if !self.peer_ready {
+            // data of an application which does not multiplex may never fill a frame
+            let len = self.read_buf.len().min(HELLO.len());
+            if self.read_buf[..len] != HELLO[..len] {
+                return Err(anyhow!("The other end does not multiplex streams"));
+            }
+        }
+        while self.read_buf.len() >= HEADER_LEN {
+            let len = u16::from_be_bytes([self.read_buf[3], self.read_buf[4]]) as usize;
+            if self.read_buf.len() < HEADER_LEN + len {
+                break;
+            }
+            let mut frame = self.read_buf.split_to(HEADER_LEN + len).freeze();
+            let id = frame.get_u16();
+            let kind = frame.get_u8();
+            frame.advance(2);
+            self.handle_frame(id, kind, frame)?;
+        }
+        Ok(())
+    }
+
+    fn handle_frame(&mut self, id: u16, kind: u8, payload: Bytes) -> Result<()> {
+        let kind = FrameKind::from_code(kind)
+            .ok_or_else(|| anyhow!("Unknown frame kind {} on stream {}", kind, id))?;
+        if !self.peer_ready {
+            // checked by `receive`
+            self.peer_ready = true;
+            return Ok(());
+        }
+        match kind {
+            FrameKind::Hello => return Err(anyhow!("Repeated HELLO")),
+            FrameKind::Open => self.accept_stream(id),
+            FrameKind::Data => {
+                let stream = match self.streams.get_mut(&id) {
+                    Some(stream) => stream,
+                    // closed by this end, but the other end did not know yet
+                    None => return Ok(()),
+                };
+                if payload.len() as u32 > stream.recv_window {
+                    debug!("Closing stream {}, the other end exceeded its window", id);
+                    self.close(id);
+                    return Ok(());
+                }
+                stream.recv_window -= payload.len() as u32;
+                let _ = stream.data_tx.send(payload);
+            }
+            FrameKind::Close => {
+                self.streams.remove(&id);
+            }
+            FrameKind::Window => {
+                if payload.len() < 4 {
+                    return Err(anyhow!("Truncated WINDOW on stream {}", id));
+                }
+                let credit = (&payload[..]).get_u32();
+                if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.send_window = stream.send_window.saturating_add(credit);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a stream opened by the other end, or refuses it with `CLOSE`.
+    fn accept_stream(&mut self, id: u16) {
+        if id == 0 || self.is_local(id) || self.streams.contains_key(&id) {
+            debug!("Refusing stream {} opened by the other end", id);
+            self.close(id);
+            return;
+        }
+        let remote = self.streams.keys().filter(|&&id| !self.is_local(id));
+        if remote.count() >= MAX_STREAMS {
+            debug!("Refusing stream {}, too many streams are open", id);
+            self.queue_frame(id, FrameKind::Close, &[]);
+            return;
+        }
+        let stream = self.add_stream(id);
+        if self.incoming.send(stream).is_err() {
+            // the multiplexer was dropped, the stream is closed like any dropped stream
+            debug!("Refusing stream {}, no longer accepting streams", id);
+        }
+    }
+
+    /// Returns whether streams with the given id are opened by this end.
+    fn is_local(&self, id: u16) -> bool {
+        id % 2 == self.next_id % 2
+    }
+
+    /// Returns the handle of a new stream with the given id.
+    fn add_stream(&mut self, id: u16) -> MuxStream {
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        self.streams.insert(
+            id,
+            StreamState {
+                data_tx,
+                closed: closed.clone(),
+                pending: VecDeque::new(),
+                send_window: STREAM_WINDOW,
+                recv_window: STREAM_WINDOW,
+                closing: false,
+            },
+        );
+        MuxStream {
+            id,
+            commands: self.commands_tx.clone(),
+            data_rx,
+            closed,
+            read_buf: Bytes::new(),
+            unacknowledged: 0,
+        }
+    }
+
+    /// Removes the stream with the given id and tells the other end.
+    fn close(&mut self, id: u16) {
+        self.streams.remove(&id);
+        self.queue_frame(id, FrameKind::Close, &[]);
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Open(stream_tx) => {
+                let _ = stream_tx.send(self.open_stream());
+            }
+            Command::Write(id, data) => {
+                if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.pending.push_back(data);
+                }
+            }
+            Command::Credit(id, credit) => {
+                if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.recv_window = stream.recv_window.saturating_add(credit);
+                    self.queue_frame(id, FrameKind::Window, &credit.to_be_bytes());
+                }
+            }
+            Command::Close(id) => match self.streams.get_mut(&id) {
+                Some(stream) if !stream.pending.is_empty() => stream.closing = true,
+                Some(_) => self.close(id),
+                None => {}
+            },
+            Command::Detach => self.detached = true,
+        }
+    }
+
+    fn open_stream(&mut self) -> Result<MuxStream> {
+        let local = self.streams.keys().filter(|&&id| self.is_local(id));
+        if local.count() >= MAX_STREAMS {
+            return Err(anyhow!("Too many open streams"));
+        }
+        let mut id = self.next_id;
+        while self.streams.contains_key(&id) {
+            id = next_stream_id(id);
+        }
+        self.next_id = next_stream_id(id);
+        self.queue_frame(id, FrameKind::Open, &[]);
+        Ok(self.add_stream(id))
+    }
+
+    /// Queues one frame of data for every stream which has data pending and may send it.
+    fn send_round(&mut self) {
+        let mut frames = Vec::new();
+        let mut closed = Vec::new();
+        for (&id, stream) in self.streams.iter_mut() {
+            if !stream.is_sendable() {
+                continue;
+            }
+            let chunk = stream.pending.front_mut().unwrap();
+            let len = chunk
+                .len()
+                .min(self.max_payload)
+                .min(stream.send_window as usize);
+            frames.push((id, chunk.split_to(len)));
+            if chunk.is_empty() {
+                stream.pending.pop_front();
+            }
+            stream.send_window -= len as u32;
+            if stream.closing && stream.pending.is_empty() {
+                closed.push(id);
+            }
+        }
+        for (id, data) in frames {
+            self.queue_frame(id, FrameKind::Data, &data);
+        }
+        for id in closed {
+            self.close(id);
+        }
+    }
+}
+
+/// Returns the id following `id` among the ids of the same end, skipping 0 on wrap-around.
+fn next_stream_id(id: u16) -> u16 {
+    match id.wrapping_add(2) {
+        0 => 2,
+        next => next,
+    }
+}
@@ -0,0 +1,115 @@
+//! Synchronous hooks into the state changes of an onion router, see [`StateObserver`].
+
+use crate::onion::tunnel::TunnelId;
+use crate::onion::BuildAttempt;
+use std::cell::Cell;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+thread_local! {
+    /// set while a callback of a [`StateObserver`] runs on this thread
+    static IN_CALLBACK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The state of a tunnel built by this onion router.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TunnelState {
+    /// The first path of the tunnel is being built.
+    Building,
+    /// The tunnel carries data.
+    Ready,
+    /// The tunnel has been dropped and is closed at the next switchover.
+    Destroying,
+    /// The tunnel is closed.
+    Destroyed,
+}
+
+/// Receives a callback for every state change of an onion router, e.g. to maintain metrics.
+///
+/// Other than [`Event`](crate::Event)s, which are delivered asynchronously and dropped if a
+/// subscriber lags behind, callbacks are never lost. They are called inline by the tasks handling
+/// tunnels and incoming connections, so they have to be cheap. All methods do nothing by default.
+///
+/// # Reentrancy
+///
+/// The task making a callback is blocked until it returns. A callback must not block, e.g. on a
+/// lock or channel, and must not use the [`OnionContext`](crate::OnionContext) of the onion
+/// router, which is asserted in debug builds. Work beyond updating counters should be handed off
+/// to another task.
+pub trait StateObserver: Send + Sync {
+    /// Called when the tunnel with id `tunnel_id` moves from state `old` to state `new`.
+    fn on_tunnel_state(&self, _tunnel_id: TunnelId, _old: TunnelState, _new: TunnelState) {}
+
+    /// Called after every attempt to add a hop while building a path of the tunnel with id
+    /// `tunnel_id`, including the builds of rotated paths.
+    fn on_build_attempt(&self, _tunnel_id: TunnelId, _attempt: &BuildAttempt) {}
+
+    /// Called when a circuit from the peer at `peer_addr` has been accepted, i.e. the circuit
+    /// handshake completed.
+    fn on_circuit_accepted(&self, _peer_addr: SocketAddr) {}
+}
+
+struct NoopObserver;
+
+impl StateObserver for NoopObserver {}
+
+/// The [`StateObserver`] of an onion router, which does nothing by default.
+#[derive(Clone)]
+pub(crate) struct Observer(Arc<dyn StateObserver>);
+
+impl Observer {
+    pub(crate) fn new(observer: Arc<dyn StateObserver>) -> Self {
+        Observer(observer)
+    }
+
+    pub(crate) fn tunnel_state(&self, tunnel_id: TunnelId, old: TunnelState, new: TunnelState) {
+        self.call(|o| o.on_tunnel_state(tunnel_id, old, new));
+    }
+
+    pub(crate) fn build_attempt(&self, tunnel_id: TunnelId, attempt: &BuildAttempt) {
+        self.call(|o| o.on_build_attempt(tunnel_id, attempt));
+    }
+
+    pub(crate) fn circuit_accepted(&self, peer_addr: SocketAddr) {
+        self.call(|o| o.on_circuit_accepted(peer_addr));
+    }
+
+    fn call<F: FnOnce(&dyn StateObserver)>(&self, f: F) {
+        let _guard = CallbackGuard::enter();
+        f(&*self.0);
+    }
+}
+
+impl Default for Observer {
+    fn default() -> Self {
+        Observer(Arc::new(NoopObserver))
+    }
+}
+
+/// Marks the current thread as running a callback until dropped, also if the callback panics.
+struct CallbackGuard {
+    outer: bool,
+}
+
+impl CallbackGuard {
+    fn enter() -> Self {
+        CallbackGuard {
+            outer: IN_CALLBACK.with(|c| c.replace(true)),
+        }
+    }
+}
+
+impl Drop for CallbackGuard {
+    fn drop(&mut self) {
+        IN_CALLBACK.with(|c| c.set(self.outer));
+    }
+}
+
+/// Asserts in debug builds that the onion router is not used from a [`StateObserver`] callback.
+pub(crate) fn debug_assert_not_observing() {
+    debug_assert!(
+        !IN_CALLBACK.with(Cell::get),
+        "OnionContext must not be used from a StateObserver callback"
+    );
+}
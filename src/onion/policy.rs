@@ -0,0 +1,202 @@
+//! The addresses a relay connects to when extending tunnels, see [`ExtendPolicy`].
+//!
+//! The types are re-exported at the root of the crate.
+
+use crate::onion::error::InvalidAddressRange;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::{Bound, RangeBounds, RangeInclusive};
+use std::str::FromStr;
+
+/// The addresses to which an onion router opens connections when other peers extend their
+/// tunnels through it, see
+/// [`OnionBuilder::set_extend_policy`](crate::OnionBuilder::set_extend_policy).
+///
+/// The rules are checked in the order they were added, the first rule matching the address and
+/// port decides. Addresses matching no rule are allowed. IPv4 addresses mapped into IPv6 are
+/// checked as IPv4 addresses.
+///
+/// The default policy denies the private IPv4 ranges of RFC 1918 and the IPv6 unique local
+/// addresses, so that the relay can not be used to reach hosts of its internal network:
+///
+/// ```
+/// # use allium::ExtendPolicy;
+/// let policy = ExtendPolicy::default();
+/// assert!(!policy.allows("10.0.0.1:4000".parse().unwrap()));
+/// assert!(policy.allows("203.0.113.7:4000".parse().unwrap()));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtendPolicy {
+    rules: Vec<ExtendRule>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ExtendRule {
+    allow: bool,
+    range: AddressRange,
+    ports: RangeInclusive<u16>,
+}
+
+impl ExtendPolicy {
+    /// Creates a policy without any rules, which allows every address.
+    pub fn allow_all() -> Self {
+        ExtendPolicy { rules: Vec::new() }
+    }
+
+    /// Adds a rule allowing connections to `ports` of the addresses in `range`.
+    ///
+    /// Use `..` to match every port.
+    pub fn allow(self, range: AddressRange, ports: impl RangeBounds<u16>) -> Self {
+        self.with_rule(true, range, ports)
+    }
+
+    /// Adds a rule denying connections to `ports` of the addresses in `range`.
+    ///
+    /// Use `..` to match every port.
+    pub fn deny(self, range: AddressRange, ports: impl RangeBounds<u16>) -> Self {
+        self.with_rule(false, range, ports)
+    }
+
+    fn with_rule(mut self, allow: bool, range: AddressRange, ports: impl RangeBounds<u16>) -> Self {
+        self.rules.push(ExtendRule {
+            allow,
+            range,
+            ports: port_range(ports),
+        });
+        self
+    }
+
+    /// Returns whether the policy allows connecting to `addr`.
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        let ip = canonical(addr.ip());
+        self.rules
+            .iter()
+            .find(|rule| rule.range.contains(ip) && rule.ports.contains(&addr.port()))
+            .is_none_or(|rule| rule.allow)
+    }
+}
+
+impl Default for ExtendPolicy {
+    fn default() -> Self {
+        let private = [
+            (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8),
+            (IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), 12),
+            (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16),
+            (IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), 7),
+        ];
+        private
+            .iter()
+            .fold(ExtendPolicy::allow_all(), |policy, &(addr, prefix_len)| {
+                policy.deny(AddressRange::new(addr, prefix_len).unwrap(), ..)
+            })
+    }
+}
+
+/// A block of IP addresses sharing a common prefix, written in CIDR notation like `10.0.0.0/8`,
+/// see [`ExtendPolicy`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AddressRange {
+    /// `None` matches every address of both families
+    network: Option<(IpAddr, u8)>,
+}
+
+impl AddressRange {
+    /// Returns the range of addresses whose first `prefix_len` bits equal those of `addr`, or
+    /// `None` if `prefix_len` exceeds the length of the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return None;
+        }
+        Some(AddressRange {
+            network: Some((addr, prefix_len)),
+        })
+    }
+
+    /// Returns the range of all IPv4 and IPv6 addresses.
+    pub fn any() -> Self {
+        AddressRange { network: None }
+    }
+
+    /// Returns whether `addr` is part of the range.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr) {
+            (None, _) => true,
+            (Some((IpAddr::V4(network), len)), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (Some((IpAddr::V6(network), len)), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for AddressRange {
+    type Err = InvalidAddressRange;
+
+    /// Parses a range like `10.0.0.0/8` or `fc00::/7`. A single address without a prefix length
+    /// is a range containing only this address, while `*` matches every address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidAddressRange(s.to_string());
+        if s == "*" {
+            return Ok(AddressRange::any());
+        }
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => {
+                let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+                (addr, len.parse().map_err(|_| invalid())?)
+            }
+            None => {
+                let addr: IpAddr = s.parse().map_err(|_| invalid())?;
+                (addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        };
+        AddressRange::new(addr, prefix_len).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for AddressRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.network {
+            Some((addr, prefix_len)) => write!(f, "{}/{}", addr, prefix_len),
+            None => write!(f, "*"),
+        }
+    }
+}
+
+/// Converts `ports` into an inclusive range, which is empty if `ports` is.
+fn port_range(ports: impl RangeBounds<u16>) -> RangeInclusive<u16> {
+    let start = match ports.start_bound() {
+        Bound::Included(&port) => port,
+        Bound::Excluded(&u16::MAX) => return empty_port_range(),
+        Bound::Excluded(&port) => port + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match ports.end_bound() {
+        Bound::Included(&port) => port,
+        Bound::Excluded(&0) => return empty_port_range(),
+        Bound::Excluded(&port) => port - 1,
+        Bound::Unbounded => u16::MAX,
+    };
+    start..=end
+}
+
+#[allow(clippy::reversed_empty_ranges)]
+fn empty_port_range() -> RangeInclusive<u16> {
+    1..=0
+}
+
+/// Returns the IPv4 address mapped into `addr`, if any, so it is matched by IPv4 ranges.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
@@ -1,12 +1,15 @@
 use crate::onion::circuit::CircuitId;
-use crate::onion::crypto::{self, EphemeralPublicKey, RsaPrivateKey, RsaPublicKey, SessionKey};
+use crate::onion::crypto::{
+    self, CipherSuite, CipherSuites, Direction, EphemeralPublicKey, HandshakeNonce,
+    HandshakeVersion, HostKey, HostPublicKey, KeyType, SessionKey, HANDSHAKE_NONCE_LEN,
+};
 use crate::onion::tunnel::TunnelId;
 use crate::utils::{self, FromBytes, ToBytes, TryFromBytes};
 use crate::Result;
 use anyhow::{anyhow, Context};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use std::fmt;
 use std::net::SocketAddr;
+use std::{cmp, fmt};
 use thiserror::Error;
 
 const CIRCUIT_CREATE: u8 = 0x0;
@@ -18,27 +21,148 @@ const TUNNEL_EXTEND: u8 = 0x10;
 const TUNNEL_TRUNCATE: u8 = 0x11;
 const TUNNEL_BEGIN: u8 = 0x12;
 const TUNNEL_END: u8 = 0x13;
+const TUNNEL_ECHO: u8 = 0x14;
+const TUNNEL_TERMINATE: u8 = 0x15;
 
 const TUNNEL_DATA: u8 = 0x30;
 const TUNNEL_KEEPALIVE: u8 = 0x40;
+const TUNNEL_PADDING: u8 = 0x41;
 
 const TUNNEL_EXTENDED: u8 = 0x20;
 const TUNNEL_TRUNCATED: u8 = 0x21;
+const TUNNEL_ECHOED: u8 = 0x22;
 const TUNNEL_ERROR: u8 = 0x2f;
 
-/// Length in bytes of the digest included in relay messages.
+/// Length in bytes of the truncated digest of `CipherSuite::TruncatedDigest`.
 /// Must not be greater than `digest::SHA256_OUTPUT_LEN` (= 32)
-pub(crate) const DIGEST_LEN: usize = 12;
-const SIGNATURE_LEN: usize = 512;
+const DIGEST_LEN: usize = 12;
+/// Length in bytes of the signature of a handshake reply, that of a 4096 bit RSA key. Shorter
+/// signatures are padded with zeros.
+pub(crate) const SIGNATURE_LEN: usize = 512;
 const KEY_LEN: usize = crypto::KEY_LEN;
 
+/// Size of the handshake messages, which are exchanged before a cell size has been negotiated.
 pub(crate) const MESSAGE_SIZE: usize = 1024;
-pub(crate) const MAX_DATA_SIZE: usize = MESSAGE_SIZE - 4 - crypto::NONCE_LEN - DIGEST_LEN - 8;
+
+/// Precedes the cipher suites which follow the ephemeral key of handshake requests and signed
+/// replies. Peers predating the negotiation fill the rest of these messages with random padding,
+/// so they are told apart by the missing marker, save for a chance of 2^-32 per message. Their
+/// suites are [`CipherSuites::BASELINE`], which are offered and answered without the marker.
+pub(crate) const NEGOTIATION_MARKER: [u8; 4] = *b"NEGO";
+
+/// The size of the cells exchanged on a circuit.
+///
+/// The cell size is negotiated during the circuit handshake and applies to all subsequent
+/// messages on the circuit. All circuits of a tunnel use the same cell size.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub enum CellSize {
+    /// 1 KiB cells, understood by all peers.
+    #[default]
+    Standard,
+    /// 4 KiB cells, which reduce the per-cell overhead for bulk transfers.
+    Large,
+}
+
+impl CellSize {
+    pub(crate) const ALL: [CellSize; 2] = [CellSize::Standard, CellSize::Large];
+
+    /// Returns the size of a cell in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            CellSize::Standard => 1024,
+            CellSize::Large => 4096,
+        }
+    }
+
+    /// Returns the maximum number of payload bytes carried by a single `TUNNEL DATA` cell.
+    ///
+    /// Room for the longest integrity tag is reserved, since the cipher suite may differ between
+    /// the tunnels a `Tunnel` switches between.
+    pub(crate) fn max_data_size(self) -> usize {
+        self.bytes() - 4 - crypto::NONCE_LEN - crypto::MAX_TAG_LEN - 8
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            CellSize::Standard => 0,
+            CellSize::Large => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(CellSize::Standard),
+            1 => Some(CellSize::Large),
+            _ => None,
+        }
+    }
+}
+
+/// The reason for ending a tunnel or tearing down a circuit, sent with `TUNNEL END` and
+/// `TEARDOWN` and reported in [`Event::Closed`](crate::Event::Closed).
+///
+/// Relays tearing down a path pass on the reason of the hop which tore it down.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub enum EndReason {
+    /// No reason was given. Sent by peers predating reason codes, and reported for codes which
+    /// are unknown to this version.
+    Unspecified,
+    /// The tunnel or path is no longer needed, e.g. because the application closed the tunnel or
+    /// the onion router was shut down.
+    Normal,
+    /// A peer breached the protocol, e.g. by sending a cell with a broken digest.
+    ProtocolError,
+    /// A peer stopped answering or the tunnel was idle for too long.
+    Timeout,
+    /// A peer ran out of resources, e.g. its buffers for a circuit overflowed.
+    ResourceLimit,
+    /// The path was replaced by a new one at a switchover.
+    Replaced,
+}
+
+impl EndReason {
+    pub(crate) fn code(self) -> u8 {
+        match self {
+            EndReason::Unspecified => 0,
+            EndReason::Normal => 1,
+            EndReason::ProtocolError => 2,
+            EndReason::Timeout => 3,
+            EndReason::ResourceLimit => 4,
+            EndReason::Replaced => 5,
+        }
+    }
+
+    /// Unknown codes, e.g. from newer peers, are mapped to [`EndReason::Unspecified`].
+    pub(crate) fn from_code(code: u8) -> Self {
+        match code {
+            1 => EndReason::Normal,
+            2 => EndReason::ProtocolError,
+            3 => EndReason::Timeout,
+            4 => EndReason::ResourceLimit,
+            5 => EndReason::Replaced,
+            _ => EndReason::Unspecified,
+        }
+    }
+}
 
 #[derive(Error, Debug)]
 pub(crate) enum CircuitProtocolError {
-    #[error("Teardown while expecting {expected}")]
-    Teardown { expected: u8 },
+    #[error("Teardown ({reason:?}) while expecting {expected}")]
+    Teardown { expected: u8, reason: EndReason },
+    #[error("Unsupported cell size code {code} on circuit {circuit_id}")]
+    CellSize { circuit_id: CircuitId, code: u8 },
     #[error("Unknown tunnel message id: expected {expected} got {actual}")]
     Unknown { expected: u8, actual: u8 },
 }
@@ -51,6 +175,8 @@ pub(crate) enum TunnelProtocolError<E: fmt::Debug> {
     Peer(E),
     #[error("Unknown tunnel message id: {actual}")]
     Unknown { actual: u8 },
+    #[error("Unknown address type: {actual}")]
+    AddressType { actual: u8 },
     #[error("Computed hash did not match the message hash")]
     Digest,
 }
@@ -59,16 +185,459 @@ pub(crate) type TunnelProtocolResult<T, E> = std::result::Result<T, TunnelProtoc
 
 pub(crate) type Key = EphemeralPublicKey;
 
+/// The responder states the type of its host key at the end of the signed key, see [`SignKey`].
+pub(crate) const FEATURE_KEY_TYPE: u16 = 1 << 0;
+
+/// The feature flags known to this crate. The flags leave room for later changes of the cell
+/// format which peers opt into.
+pub(crate) const KNOWN_FEATURES: u16 = FEATURE_KEY_TYPE;
+
+/// The ephemeral key of a responder, signed along with the cipher suite selection and, from
+/// [`HandshakeVersion::ResponderNonce`] on, a nonce chosen by the responder.
+///
+/// The signature field keeps the length of a RSA signature regardless of the type of the host key,
+/// so peers which do not know other types still find the key behind it. With
+/// [`FEATURE_KEY_TYPE`], the responder also signs the type of its key, which the initiator checks
+/// against the key it expects.
+///
+/// Format:
+/// ```text
+/// signature: [u8; SIGNATURE_LEN]
+/// key
+/// marker: [u8; 4] (only if the offer was not CipherSuites::BASELINE)
+/// suites: SuiteSelection (likewise)
+/// nonce: [u8; HANDSHAKE_NONCE_LEN] (only if the selection negotiates a nonce)
+/// key_type: u8 (only if the selection negotiates FEATURE_KEY_TYPE)
+/// ```
+///
+/// A reply without the [`NEGOTIATION_MARKER`] has the layout of peers predating the negotiation,
+/// whose signature only covers the key. Answering so is never signed by this crate unless the
+/// request lacked the marker as well, which means that a man in the middle stripping it can force
+/// the baseline suites on both sides. Initiators not offering [`CipherSuite::TruncatedDigest`]
+/// reject such replies.
 pub(crate) struct SignKey<'a> {
     key: &'a Key,
-    key_pair: &'a RsaPrivateKey,
+    suites: SuiteSelection,
+    nonce: Option<HandshakeNonce>,
+    key_pair: &'a HostKey,
 }
 
 pub(crate) struct VerifyKey {
     key: Key,
+    suites: SuiteSelection,
+    nonce: Option<HandshakeNonce>,
+    key_type: Option<u8>,
     signature: Bytes,
 }
 
+/// The contents of a [`VerifyKey`] whose signature has been verified.
+pub(crate) struct VerifiedKey {
+    pub(crate) key: Key,
+    pub(crate) suite: CipherSuite,
+    /// the salt to derive the session key with, see [`SignKey::salt`]
+    pub(crate) salt: Option<Vec<u8>>,
+    /// the handshake version negotiated with the responder
+    pub(crate) version: HandshakeVersion,
+    /// the latest handshake version supported by the responder
+    pub(crate) peer_version: HandshakeVersion,
+}
+
+/// The outcome of the cipher suite negotiation, which is signed by the responder along with its
+/// ephemeral key.
+///
+/// The offer of the initiator is echoed, so the initiator can detect a modified offer as well as a
+/// responder choosing a weaker suite than both sides support. The same holds for the handshake
+/// version announced along with the suites, see [`CipherSuites::version`], and for the feature
+/// selection which follows from [`HandshakeVersion::FeatureFlags`] on.
+///
+/// Format:
+/// ```text
+/// offered: u8
+/// supported: u8
+/// selected: u8
+/// features: FeatureSelection (only if the suites negotiate feature flags)
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct SuiteSelection {
+    pub(crate) offered: CipherSuites,
+    pub(crate) supported: CipherSuites,
+    pub(crate) selected: u8,
+    pub(crate) features: Option<FeatureSelection>,
+}
+
+impl SuiteSelection {
+    /// The selection implied by a reply without the [`NEGOTIATION_MARKER`].
+    fn baseline() -> Self {
+        SuiteSelection {
+            offered: CipherSuites::BASELINE,
+            supported: CipherSuites::BASELINE,
+            selected: CipherSuite::TruncatedDigest.code(),
+            features: None,
+        }
+    }
+
+    /// Returns whether this selection answers an offer of [`CipherSuites::BASELINE`], so it is
+    /// not part of the reply.
+    fn is_baseline(&self) -> bool {
+        self.offered == CipherSuites::BASELINE
+    }
+
+    /// Selects the strongest suite in both `offered` and `supported`, if there is any, for an
+    /// initiator offering the features of this crate.
+    #[cfg(test)]
+    pub(crate) fn negotiate(offered: CipherSuites, supported: CipherSuites) -> Option<Self> {
+        Self::negotiate_features(offered, FeatureOffer::default(), supported)
+    }
+
+    /// Like [`negotiate`](Self::negotiate), but answers the feature offer `features` if both
+    /// sides support [`HandshakeVersion::FeatureFlags`].
+    pub(crate) fn negotiate_features(
+        offered: CipherSuites,
+        features: FeatureOffer,
+        supported: CipherSuites,
+    ) -> Option<Self> {
+        let selected = offered.intersection(supported).strongest()?;
+        let version = cmp::min(offered.version(), supported.version());
+        Some(SuiteSelection {
+            offered,
+            supported,
+            selected: selected.code(),
+            features: if version >= HandshakeVersion::FeatureFlags {
+                Some(FeatureSelection::negotiate(features))
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Returns the handshake version supported by both sides, which determines the layout of the
+    /// signed key.
+    pub(crate) fn version(&self) -> HandshakeVersion {
+        match &self.features {
+            Some(features) => features.version(),
+            None => cmp::min(self.offered.version(), self.supported.version()),
+        }
+    }
+
+    /// Returns the latest handshake version supported by the responder, as far as it is known to
+    /// this crate.
+    pub(crate) fn peer_version(&self) -> HandshakeVersion {
+        match &self.features {
+            Some(features) => {
+                HandshakeVersion::from_code(features.version).unwrap_or(HandshakeVersion::LATEST)
+            }
+            None => self.supported.version(),
+        }
+    }
+
+    /// Returns whether both sides support the optional `feature`.
+    pub(crate) fn negotiates(&self, feature: u16) -> bool {
+        self.features
+            .is_some_and(|features| features.negotiated() & feature != 0)
+    }
+
+    /// Returns the required features the responder does not support.
+    pub(crate) fn missing_features(&self) -> u16 {
+        self.features.map_or(0, |features| features.missing())
+    }
+
+    /// Returns the selected suite, unless it is not the strongest suite in both `offered` and the
+    /// suites supported by the responder.
+    ///
+    /// The echoed feature offer has to match `features`, the offer sent along with `offered`. A
+    /// responder which does not support all required features is reported as
+    /// [`UnsupportedFeatures`].
+    ///
+    /// A reply without the [`NEGOTIATION_MARKER`] selects [`CipherSuite::TruncatedDigest`] if it
+    /// was offered, see [`SignKey`].
+    pub(crate) fn check(
+        &self,
+        offered: CipherSuites,
+        features: Option<FeatureOffer>,
+    ) -> Result<CipherSuite> {
+        if self.is_baseline() && offered != CipherSuites::BASELINE {
+            // the responder predates the negotiation, or the offer was stripped on the way
+            if !offered.contains(CipherSuite::TruncatedDigest) {
+                return Err(anyhow!(
+                    "Peer does not negotiate cipher suites, but {:?} does not offer {:?}",
+                    offered,
+                    CipherSuite::TruncatedDigest
+                ));
+            }
+            let required = features.map_or(0, |features| features.required);
+            if required != 0 {
+                return Err(UnsupportedFeatures { missing: required }.into());
+            }
+            return Ok(CipherSuite::TruncatedDigest);
+        }
+        if self.offered != offered {
+            return Err(anyhow!(
+                "Offered cipher suites {:?} were modified to {:?}",
+                offered,
+                self.offered
+            ));
+        }
+        if let Some(selection) = &self.features {
+            if Some(selection.offered) != features {
+                return Err(anyhow!(
+                    "Offered features {:?} were modified to {:?}",
+                    features,
+                    selection.offered
+                ));
+            }
+            if selection.missing() != 0 {
+                return Err(UnsupportedFeatures {
+                    missing: selection.missing(),
+                }
+                .into());
+            }
+        }
+        let expected = offered.intersection(self.supported).strongest();
+        match CipherSuite::from_code(self.selected) {
+            Some(selected) if Some(selected) == expected => Ok(selected),
+            selected => Err(anyhow!(
+                "Peer selected cipher suite {:?} ({}) instead of {:?}",
+                selected,
+                self.selected,
+                expected
+            )),
+        }
+    }
+
+    fn size(&self) -> usize {
+        3 + self.features.map_or(0, |_| FeatureSelection::SIZE)
+    }
+
+    fn write_to(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.offered.bits());
+        buf.put_u8(self.supported.bits());
+        buf.put_u8(self.selected);
+        if let Some(features) = &self.features {
+            features.write_to(buf);
+        }
+    }
+
+    /// Writes this selection preceded by the [`NEGOTIATION_MARKER`], unless it is not part of the
+    /// reply.
+    fn write_marked_to(&self, buf: &mut BytesMut) {
+        if !self.is_baseline() {
+            buf.put(NEGOTIATION_MARKER.as_ref());
+            self.write_to(buf);
+        }
+    }
+
+    fn read_from(buf: &mut BytesMut) -> Self {
+        let offered = CipherSuites::from_bits(buf.get_u8());
+        let supported = CipherSuites::from_bits(buf.get_u8());
+        let selected = buf.get_u8();
+        let version = cmp::min(offered.version(), supported.version());
+        SuiteSelection {
+            offered,
+            supported,
+            selected,
+            features: if version >= HandshakeVersion::FeatureFlags {
+                Some(FeatureSelection::read_from(buf))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// The versions and features offered by an initiator from [`HandshakeVersion::FeatureFlags`] on,
+/// which follow the cipher suites of `CIRCUIT CREATE` and `TUNNEL EXTEND` messages.
+///
+/// The initiator supports all versions up to `version`, which is how versions later than
+/// [`HandshakeVersion::FeatureFlags`] are announced. A responder which does not know all
+/// `required` features rejects the handshake.
+///
+/// Format:
+/// ```text
+/// version: u8
+/// features: u16
+/// required: u16
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct FeatureOffer {
+    pub(crate) version: u8,
+    pub(crate) features: u16,
+    pub(crate) required: u16,
+}
+
+impl FeatureOffer {
+    const SIZE: usize = 5;
+
+    /// Returns the offer this crate sends along with `suites`, if they announce a version which
+    /// has one.
+    pub(crate) fn for_suites(suites: CipherSuites) -> Option<Self> {
+        if suites.version() >= HandshakeVersion::FeatureFlags {
+            Some(FeatureOffer::default())
+        } else {
+            None
+        }
+    }
+
+    fn write_to(&self, buf: &mut BytesMut) {
+        buf.put_u8(self.version);
+        buf.put_u16(self.features);
+        buf.put_u16(self.required);
+    }
+
+    fn read_from(buf: &mut BytesMut) -> Self {
+        FeatureOffer {
+            version: buf.get_u8(),
+            features: buf.get_u16(),
+            required: buf.get_u16(),
+        }
+    }
+}
+
+impl Default for FeatureOffer {
+    fn default() -> Self {
+        FeatureOffer {
+            version: HandshakeVersion::LATEST.code(),
+            features: KNOWN_FEATURES,
+            required: 0,
+        }
+    }
+}
+
+/// The answer of a responder to a [`FeatureOffer`], which is signed as part of the
+/// [`SuiteSelection`]. Like the suites, the offer is echoed and the responder states what it
+/// supports, so both sides derive the same version and features from it.
+///
+/// Format:
+/// ```text
+/// offered: FeatureOffer
+/// version: u8
+/// supported: u16
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct FeatureSelection {
+    pub(crate) offered: FeatureOffer,
+    /// the latest version supported by the responder
+    pub(crate) version: u8,
+    pub(crate) supported: u16,
+}
+
+impl FeatureSelection {
+    const SIZE: usize = FeatureOffer::SIZE + 3;
+
+    pub(crate) fn negotiate(offered: FeatureOffer) -> Self {
+        FeatureSelection {
+            offered,
+            version: HandshakeVersion::LATEST.code(),
+            supported: KNOWN_FEATURES,
+        }
+    }
+
+    /// Returns the latest version supported by both sides. Announcing feature flags implies
+    /// supporting [`HandshakeVersion::FeatureFlags`], lower version numbers are ignored.
+    pub(crate) fn version(&self) -> HandshakeVersion {
+        let code = cmp::min(self.offered.version, self.version);
+        HandshakeVersion::from_code(code)
+            .unwrap_or(HandshakeVersion::LATEST)
+            .max(HandshakeVersion::FeatureFlags)
+    }
+
+    /// Returns the features supported by both sides.
+    pub(crate) fn negotiated(&self) -> u16 {
+        self.offered.features & self.supported
+    }
+
+    /// Returns the features required by the initiator which the responder does not support.
+    pub(crate) fn missing(&self) -> u16 {
+        self.offered.required & !self.supported
+    }
+
+    fn write_to(&self, buf: &mut BytesMut) {
+        self.offered.write_to(buf);
+        buf.put_u8(self.version);
+        buf.put_u16(self.supported);
+    }
+
+    fn read_from(buf: &mut BytesMut) -> Self {
+        FeatureSelection {
+            offered: FeatureOffer::read_from(buf),
+            version: buf.get_u8(),
+            supported: buf.get_u16(),
+        }
+    }
+}
+
+/// A handshake was rejected, since the responder does not support all features the initiator
+/// requires.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Peer does not support the required features {missing:#06x}")]
+pub(crate) struct UnsupportedFeatures {
+    pub(crate) missing: u16,
+}
+
+/// Computes and checks the integrity tag which precedes each tunnel message.
+pub(crate) trait Verifier {
+    /// Returns the length of the tag in bytes.
+    fn tag_len(&self) -> usize;
+
+    /// Writes the tag of `data` to `tag`, which is `tag_len` bytes long.
+    fn write_tag(&self, data: &[u8], tag: &mut [u8]);
+
+    /// Returns whether `tag` is the tag of `data`.
+    fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+        let mut expected = [0u8; crypto::MAX_TAG_LEN];
+        let expected = &mut expected[..self.tag_len()];
+        self.write_tag(data, expected);
+        // compare in constant time, a keyed tag must not be guessable byte by byte
+        tag.len() == expected.len()
+            && tag
+                .iter()
+                .zip(expected.iter())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// The unkeyed tag of `CipherSuite::TruncatedDigest`.
+pub(crate) struct TruncatedDigest;
+
+impl Verifier for TruncatedDigest {
+    fn tag_len(&self) -> usize {
+        DIGEST_LEN
+    }
+
+    fn write_tag(&self, data: &[u8], tag: &mut [u8]) {
+        tag.copy_from_slice(&crypto::digest(data).as_ref()[..DIGEST_LEN]);
+    }
+}
+
+/// The tag of messages exchanged with a single hop in one direction, as defined by the cipher
+/// suite negotiated with that hop.
+pub(crate) struct HopVerifier<'a> {
+    key: &'a SessionKey,
+    direction: Direction,
+}
+
+impl<'a> HopVerifier<'a> {
+    pub(crate) fn new(key: &'a SessionKey, direction: Direction) -> Self {
+        HopVerifier { key, direction }
+    }
+}
+
+impl Verifier for HopVerifier<'_> {
+    fn tag_len(&self) -> usize {
+        self.key.suite().tag_len()
+    }
+
+    fn write_tag(&self, data: &[u8], tag: &mut [u8]) {
+        match self.key.suite() {
+            CipherSuite::TruncatedDigest => TruncatedDigest.write_tag(data, tag),
+            CipherSuite::ShortHmac | CipherSuite::Hmac => {
+                let mac = self.key.mac(self.direction, data);
+                tag.copy_from_slice(&mac.as_ref()[..tag.len()]);
+            }
+        }
+    }
+}
+
 /// A message exchanged between onion peers.
 /// Initiates the creation of a new circuit to the recipient by performing a Diffie-Hellman key
 /// exchange. This message contains the sender's ephemeral public key.
@@ -76,28 +645,36 @@ pub(crate) struct VerifyKey {
 /// Header Format:
 /// ```text
 /// message_type: u8
-/// padding: u8
+/// cell_size: u8
 /// circuit_id: u16
 /// key
+/// offer: SuiteOffer
 /// ```
 pub(crate) struct CircuitCreate {
     pub(crate) circuit_id: CircuitId,
+    pub(crate) cell_size: CellSize,
     pub(crate) key: Key,
+    pub(crate) cipher_suites: CipherSuites,
+    pub(crate) features: Option<FeatureOffer>,
 }
 
 /// A message exchanged between onion peers.
 /// Confirms the creation of a new circuit, initiated by a `CreateRequest`. Contains the peer's
 /// ephemeral public key, which the initiator can use to generate a shared secret.
 ///
+/// The cell size requested in the `CIRCUIT CREATE` message is echoed back to confirm it. The
+/// selected cipher suite is signed together with the key, see [`SignKey`].
+///
 /// Header Format:
 /// ```text
 /// message_type: u8
-/// padding: u8
+/// cell_size: u8
 /// circuit_id: u16
 /// signed_key
 /// ```
 pub(crate) struct CircuitCreated<K> {
     pub(crate) circuit_id: CircuitId,
+    pub(crate) cell_size: CellSize,
     pub(crate) key: K,
 }
 
@@ -110,16 +687,24 @@ pub(crate) struct CircuitCreated<K> {
 /// message_type: u8
 /// padding: u8
 /// circuit_id: u16
+/// nonce
 /// payload
 /// ```
+///
+/// The nonce is shared by all layers of encryption and numbers the cell, see
+/// [`CellSequence`](crypto::CellSequence).
 pub(crate) struct CircuitOpaque<P> {
     pub(crate) circuit_id: CircuitId,
     pub(crate) payload: P,
 }
 
+/// The integrity tag of `msg` is computed using the cipher suite of the first of the
+/// `encrypt_keys`, which must not be empty.
 pub(crate) struct CircuitOpaquePayload<'a, M> {
     pub(crate) msg: &'a M,
     pub(crate) encrypt_keys: &'a [SessionKey],
+    pub(crate) cell_size: CellSize,
+    pub(crate) direction: Direction,
 }
 
 pub(crate) struct CircuitOpaqueBytes {
@@ -133,11 +718,28 @@ pub(crate) struct CircuitOpaqueBytes {
 /// Header Format:
 /// ```text
 /// message_type: u8
-/// padding: u8
+/// reason: u8
 /// circuit_id: u16
 /// ```
 pub(crate) struct CircuitTeardown {
     pub(crate) circuit_id: CircuitId,
+    pub(crate) reason: EndReason,
+}
+
+/// The fields at the start of every circuit message. They are read without consuming or
+/// validating the rest of the message, e.g. to route the message to its circuit.
+///
+/// Header Format:
+/// ```text
+/// message_type: u8
+/// cell_size or padding: u8
+/// circuit_id: u16
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct CircuitHeader {
+    message_type: u8,
+    code: u8,
+    pub(crate) circuit_id: CircuitId,
 }
 
 /// A fully decrypted relay message.
@@ -152,15 +754,44 @@ pub(crate) struct CircuitTeardown {
 pub(crate) enum TunnelRequest {
     /// Format:
     /// ```text
-    /// ipv6_flag: u8
+    /// ipv6_flag: u8 (0 for IPv4, 1 for IPv6)
     /// dest.addr(): [u8; 4] or [u8; 16] (depending on ipv6_flag)
     /// dest.port(): u16
     /// key
+    /// offer: SuiteOffer
     /// ```
-    Extend(/* dest */ SocketAddr, /* key */ Key),
+    Extend(
+        /* dest */ SocketAddr,
+        /* key */ Key,
+        /* cipher_suites */ CipherSuites,
+        /* features */ Option<FeatureOffer>,
+    ),
     Truncate,
     Begin(TunnelId),
-    End(TunnelId),
+    /// Format:
+    /// ```text
+    /// reason: u8
+    /// tunnel_id: u32
+    /// ```
+    End(TunnelId, EndReason),
+    /// Asks the addressed hop to answer with [`TunnelResponseEchoed`] carrying the same nonce,
+    /// which shows that the path up to this hop still works.
+    ///
+    /// Format:
+    /// ```text
+    /// nonce: u32
+    /// ```
+    Echo(/* nonce */ u32),
+    /// Makes the addressed hop the endpoint of the tunnel instead of a destination application,
+    /// like `BEGIN` without delivering the tunnel. The hop drops padding and answers echoes, but
+    /// tears the circuit down on `DATA`, or right away if it refuses the role.
+    ///
+    /// Format:
+    /// ```text
+    /// _padding: u8
+    /// tunnel_id: u32
+    /// ```
+    Terminate(TunnelId),
     /// Format:
     /// ```text
     /// _padding: u8
@@ -169,10 +800,19 @@ pub(crate) enum TunnelRequest {
     /// ```
     Data(TunnelId, /* data */ Bytes),
     KeepAlive,
+    /// Cover traffic within a tunnel, which the destination drops. Only sent to peers supporting
+    /// [`Capabilities::PADDING`](crate::Capabilities::PADDING).
+    ///
+    /// Format:
+    /// ```text
+    /// padding: [u8; len]
+    /// ```
+    Padding(/* len */ usize),
 }
 
 const ERR_BRANCHING: u8 = 0x01;
 const ERR_UNREACHABLE: u8 = 0x02;
+const ERR_POLICY_REFUSED: u8 = 0x03;
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -182,6 +822,9 @@ pub(crate) enum TunnelExtendedError {
     BranchingDetected = ERR_BRANCHING,
     /// The `EXTENDED` call was unsuccessful since the new peer was unreachable.
     PeerUnreachable = ERR_UNREACHABLE,
+    /// The `EXTEND` call was refused since the address policy of the targeted hop denies the
+    /// address of the new peer.
+    PolicyRefused = ERR_POLICY_REFUSED,
     Unknown,
 }
 
@@ -202,16 +845,27 @@ pub(crate) enum TunnelTruncatedError {
 
 pub(crate) struct TunnelResponseTruncated;
 
+/// The answer to a [`TunnelRequest::Echo`].
+///
+/// Format:
+/// ```text
+/// nonce: u32
+/// ```
+pub(crate) struct TunnelResponseEchoed(pub(crate) u32);
+
 pub(crate) trait TryFromBytesExt<E: fmt::Debug>:
     TryFromBytes<TunnelProtocolError<E>>
 {
-    fn read_with_digest_from(buf: &mut BytesMut) -> TunnelProtocolResult<Self, E>
+    fn read_with_digest_from<V: Verifier>(
+        buf: &mut BytesMut,
+        verifier: &V,
+    ) -> TunnelProtocolResult<Self, E>
     where
         Self: Sized,
     {
-        let digest = crypto::digest(&buf[DIGEST_LEN..]);
-        if digest.as_ref()[..DIGEST_LEN] == buf[..DIGEST_LEN] {
-            buf.advance(DIGEST_LEN);
+        let tag_len = verifier.tag_len();
+        if verifier.verify(&buf[tag_len..], &buf[..tag_len]) {
+            buf.advance(tag_len);
             Self::try_read_from(buf)
         } else {
             Err(TunnelProtocolError::Digest)
@@ -222,15 +876,14 @@ pub(crate) trait TryFromBytesExt<E: fmt::Debug>:
 impl<T, E: fmt::Debug> TryFromBytesExt<E> for T where T: TryFromBytes<TunnelProtocolError<E>> {}
 
 pub(crate) trait ToBytesExt: ToBytes {
-    fn write_with_digest_to(&self, buf: &mut BytesMut, pad_size: usize) {
+    fn write_with_digest_to<V: Verifier>(&self, buf: &mut BytesMut, pad_size: usize, verifier: &V) {
         let digest_start = buf.len();
-        let payload_start = digest_start + DIGEST_LEN;
+        let payload_start = digest_start + verifier.tag_len();
         buf.resize(payload_start, 0);
         let mut payload_buf = buf.split_off(payload_start);
-        self.write_padded_to(&mut payload_buf, pad_size - DIGEST_LEN);
+        self.write_padded_to(&mut payload_buf, pad_size - verifier.tag_len());
         // digest must include padding as size is unknown during verification
-        let digest = crypto::digest(&payload_buf[..]);
-        buf[digest_start..payload_start].copy_from_slice(&digest.as_ref()[..DIGEST_LEN]);
+        verifier.write_tag(&payload_buf[..], &mut buf[digest_start..payload_start]);
         buf.unsplit(payload_buf);
     }
 
@@ -257,14 +910,24 @@ impl FromBytes for CircuitProtocolResult<CircuitCreate> {
         let message_type = buf.get_u8();
         match message_type {
             CIRCUIT_CREATE => {
-                buf.get_u8();
+                let code = buf.get_u8();
                 let circuit_id = buf.get_u16();
+                let cell_size = CellSize::from_code(code)
+                    .ok_or(CircuitProtocolError::CellSize { circuit_id, code })?;
                 let key_bytes = buf.split_to(KEY_LEN).freeze();
                 let key = Key::new(key_bytes);
-                Ok(CircuitCreate { circuit_id, key })
+                let (cipher_suites, features) = read_suite_offer(buf);
+                Ok(CircuitCreate {
+                    circuit_id,
+                    cell_size,
+                    key,
+                    cipher_suites,
+                    features,
+                })
             }
             CIRCUIT_TEARDOWN => Err(CircuitProtocolError::Teardown {
                 expected: CIRCUIT_CREATE,
+                reason: EndReason::from_code(buf.get_u8()),
             }),
             _ => Err(CircuitProtocolError::Unknown {
                 expected: CIRCUIT_CREATE,
@@ -281,9 +944,68 @@ impl ToBytes for CircuitCreate {
 
     fn write_to(&self, buf: &mut BytesMut) {
         buf.put_u8(CIRCUIT_CREATE);
-        buf.put_u8(0);
+        buf.put_u8(self.cell_size.code());
         buf.put_u16(self.circuit_id);
         buf.put(self.key.bytes().as_ref());
+        write_suite_offer(buf, self.cipher_suites, self.features.as_ref());
+    }
+}
+
+/// Consumes the [`NEGOTIATION_MARKER`] at the start of `buf`, returning whether there was one.
+fn read_negotiation_marker(buf: &mut BytesMut) -> bool {
+    if buf.starts_with(&NEGOTIATION_MARKER) {
+        buf.advance(NEGOTIATION_MARKER.len());
+        true
+    } else {
+        false
+    }
+}
+
+/// Reads the offer following the key of a handshake request, which is
+/// [`CipherSuites::BASELINE`] if it lacks the [`NEGOTIATION_MARKER`].
+///
+/// Format of `SuiteOffer`:
+/// ```text
+/// marker: [u8; 4]
+/// cipher_suites: u8
+/// features: FeatureOffer (only if the cipher suites announce feature flags)
+/// ```
+fn read_suite_offer(buf: &mut BytesMut) -> (CipherSuites, Option<FeatureOffer>) {
+    if !read_negotiation_marker(buf) {
+        return (CipherSuites::BASELINE, None);
+    }
+    let cipher_suites = CipherSuites::from_bits(buf.get_u8());
+    let features = if cipher_suites.version() >= HandshakeVersion::FeatureFlags {
+        Some(FeatureOffer::read_from(buf))
+    } else {
+        None
+    };
+    (cipher_suites, features)
+}
+
+/// Writes the offer of `cipher_suites` and `features`, which is left out for
+/// [`CipherSuites::BASELINE`], so the request keeps the layout of peers predating the negotiation.
+fn write_suite_offer(
+    buf: &mut BytesMut,
+    cipher_suites: CipherSuites,
+    features: Option<&FeatureOffer>,
+) {
+    if cipher_suites == CipherSuites::BASELINE {
+        return;
+    }
+    buf.put(NEGOTIATION_MARKER.as_ref());
+    buf.put_u8(cipher_suites.bits());
+    if let Some(features) = features {
+        features.write_to(buf);
+    }
+}
+
+/// Returns the length of the offer written by [`write_suite_offer`].
+fn suite_offer_len(cipher_suites: CipherSuites, features: Option<&FeatureOffer>) -> usize {
+    if cipher_suites == CipherSuites::BASELINE {
+        0
+    } else {
+        NEGOTIATION_MARKER.len() + 1 + features.map_or(0, |_| FeatureOffer::SIZE)
     }
 }
 
@@ -294,13 +1016,20 @@ impl FromBytes for CircuitProtocolResult<CircuitCreated<VerifyKey>> {
         let message_type = buf.get_u8();
         match message_type {
             CIRCUIT_CREATED => {
-                buf.get_u8();
+                let code = buf.get_u8();
                 let circuit_id = buf.get_u16();
+                let cell_size = CellSize::from_code(code)
+                    .ok_or(CircuitProtocolError::CellSize { circuit_id, code })?;
                 let key = VerifyKey::read_from(buf);
-                Ok(CircuitCreated { circuit_id, key })
+                Ok(CircuitCreated {
+                    circuit_id,
+                    cell_size,
+                    key,
+                })
             }
             CIRCUIT_TEARDOWN => Err(CircuitProtocolError::Teardown {
                 expected: CIRCUIT_CREATED,
+                reason: EndReason::from_code(buf.get_u8()),
             }),
             _ => Err(CircuitProtocolError::Unknown {
                 expected: CIRCUIT_CREATED,
@@ -310,14 +1039,14 @@ impl FromBytes for CircuitProtocolResult<CircuitCreated<VerifyKey>> {
     }
 }
 
-impl ToBytes for CircuitCreated<SignKey<'_>> {
+impl<K: ToBytes> ToBytes for CircuitCreated<K> {
     fn size(&self) -> usize {
         MESSAGE_SIZE
     }
 
     fn write_to(&self, buf: &mut BytesMut) {
         buf.put_u8(CIRCUIT_CREATED);
-        buf.put_u8(0);
+        buf.put_u8(self.cell_size.code());
         buf.put_u16(self.circuit_id);
         self.key.write_to(buf);
     }
@@ -326,23 +1055,33 @@ impl ToBytes for CircuitCreated<SignKey<'_>> {
 /* == CircuitOpaque == */
 
 impl CircuitOpaque<CircuitOpaqueBytes> {
+    /// Returns whether the nonce of this message continues the sequence of the cells received in
+    /// `direction` from the hop sharing `key`, see [`CellSequence`](crypto::CellSequence).
+    pub(crate) fn is_in_sequence(&self, key: &SessionKey, direction: Direction) -> bool {
+        key.sequence().accept(&self.payload.nonce, direction)
+    }
+
+    /// Removes the layers of `decrypt_keys` from this message, which travels in `direction`.
     pub(crate) fn decrypt<'k>(
         &mut self,
+        direction: Direction,
         decrypt_keys: impl Iterator<Item = &'k SessionKey>,
     ) -> Result<()> {
         for key in decrypt_keys {
-            key.decrypt(self.payload.nonce, self.payload.bytes.as_mut())
+            key.decrypt(direction, self.payload.nonce, self.payload.bytes.as_mut())
                 .context("Failed to decrypt message")?;
         }
         Ok(())
     }
 
+    /// Adds the layers of `encrypt_keys` to this message, which travels in `direction`.
     pub(crate) fn encrypt<'k>(
         &mut self,
+        direction: Direction,
         encrypt_keys: impl Iterator<Item = &'k SessionKey>,
     ) -> Result<()> {
         for key in encrypt_keys {
-            key.encrypt(self.payload.nonce, self.payload.bytes.as_mut())
+            key.encrypt(direction, self.payload.nonce, self.payload.bytes.as_mut())
                 .context("Failed to encrypt message")?;
         }
         Ok(())
@@ -369,6 +1108,7 @@ impl FromBytes for CircuitProtocolResult<CircuitOpaque<CircuitOpaqueBytes>> {
             }
             CIRCUIT_TEARDOWN => Err(CircuitProtocolError::Teardown {
                 expected: CIRCUIT_OPAQUE,
+                reason: EndReason::from_code(buf.get_u8()),
             }),
             _ => Err(CircuitProtocolError::Unknown {
                 expected: CIRCUIT_OPAQUE,
@@ -380,7 +1120,7 @@ impl FromBytes for CircuitProtocolResult<CircuitOpaque<CircuitOpaqueBytes>> {
 
 impl ToBytes for CircuitOpaque<CircuitOpaqueBytes> {
     fn size(&self) -> usize {
-        MESSAGE_SIZE
+        4 + crypto::NONCE_LEN + self.payload.bytes.len()
     }
 
     fn write_to(&self, buf: &mut BytesMut) {
@@ -395,7 +1135,7 @@ impl ToBytes for CircuitOpaque<CircuitOpaqueBytes> {
 impl<'a, M: ToBytes> CircuitOpaque<CircuitOpaquePayload<'a, M>> {
     fn encrypt(&self, buf: &mut BytesMut, nonce: [u8; crypto::NONCE_LEN]) -> Result<()> {
         for key in self.payload.encrypt_keys.iter() {
-            key.encrypt(nonce, buf.as_mut())
+            key.encrypt(self.payload.direction, nonce, buf.as_mut())
                 .context("Failed to encrypt message")?;
         }
         Ok(())
@@ -404,20 +1144,30 @@ impl<'a, M: ToBytes> CircuitOpaque<CircuitOpaquePayload<'a, M>> {
 
 impl<'a, M: ToBytes> ToBytes for CircuitOpaque<CircuitOpaquePayload<'a, M>> {
     fn size(&self) -> usize {
-        MESSAGE_SIZE
+        self.payload.cell_size.bytes()
     }
 
     fn write_to(&self, buf: &mut BytesMut) {
         buf.put_u8(CIRCUIT_OPAQUE);
         buf.put_u8(0);
         buf.put_u16(self.circuit_id);
-        let mut nonce = [0u8; crypto::NONCE_LEN];
-        crypto::fill_random(&mut nonce); // TODO maybe use counter instead
+        // the key shared with the neighbouring hop, which every forward cell of the initiator passes
+        // and which a hop uses for the backward cells it sends itself
+        let nonce = self
+            .payload
+            .encrypt_keys
+            .last()
+            .unwrap()
+            .sequence()
+            .next_nonce(self.payload.direction);
         buf.extend_from_slice(&nonce);
         let mut payload_buf = buf.split_off(buf.len());
-        self.payload
-            .msg
-            .write_with_digest_to(&mut payload_buf, MESSAGE_SIZE - 4 - crypto::NONCE_LEN);
+        let verifier = HopVerifier::new(&self.payload.encrypt_keys[0], self.payload.direction);
+        self.payload.msg.write_with_digest_to(
+            &mut payload_buf,
+            self.size() - 4 - crypto::NONCE_LEN,
+            &verifier,
+        );
         self.encrypt(&mut payload_buf, nonce).unwrap();
         buf.unsplit(payload_buf);
     }
@@ -432,11 +1182,44 @@ impl ToBytes for CircuitTeardown {
 
     fn write_to(&self, buf: &mut BytesMut) {
         buf.put_u8(CIRCUIT_TEARDOWN);
-        buf.put_u8(0);
+        buf.put_u8(self.reason.code());
         buf.put_u16(self.circuit_id);
     }
 }
 
+/* == CircuitHeader == */
+
+impl CircuitHeader {
+    pub(crate) const SIZE: usize = 4;
+
+    /// Reads the header at the start of `buf`, which must hold at least [`Self::SIZE`] bytes.
+    pub(crate) fn peek(buf: &[u8]) -> Self {
+        CircuitHeader {
+            message_type: buf[0],
+            code: buf[1],
+            circuit_id: u16::from_be_bytes([buf[2], buf[3]]),
+        }
+    }
+
+    pub(crate) fn is_create(&self) -> bool {
+        self.message_type == CIRCUIT_CREATE
+    }
+
+    pub(crate) fn is_teardown(&self) -> bool {
+        self.message_type == CIRCUIT_TEARDOWN
+    }
+
+    /// Returns the cell size requested by a `CIRCUIT CREATE` message, or `None` for other
+    /// messages and unknown cell sizes.
+    pub(crate) fn requested_cell_size(&self) -> Option<CellSize> {
+        if self.is_create() {
+            CellSize::from_code(self.code)
+        } else {
+            None
+        }
+    }
+}
+
 /* == TunnelRequest == */
 
 impl FromBytes for TunnelProtocolResult<TunnelRequest, ()> {
@@ -446,12 +1229,16 @@ impl FromBytes for TunnelProtocolResult<TunnelRequest, ()> {
         match message_type {
             TUNNEL_EXTEND => {
                 let ipv6_flag = buf.get_u8();
+                if ipv6_flag > 1 {
+                    return Err(TunnelProtocolError::AddressType { actual: ipv6_flag });
+                }
                 let dest_ip = utils::get_ip_addr(buf, ipv6_flag == 1);
                 let dest_port = buf.get_u16();
                 let dest = SocketAddr::new(dest_ip, dest_port);
                 let key_bytes = buf.split_to(KEY_LEN).freeze();
                 let key = Key::new(key_bytes);
-                Ok(TunnelRequest::Extend(dest, key))
+                let (cipher_suites, features) = read_suite_offer(buf);
+                Ok(TunnelRequest::Extend(dest, key, cipher_suites, features))
             }
             TUNNEL_TRUNCATE => Ok(TunnelRequest::Truncate),
             TUNNEL_BEGIN => {
@@ -460,9 +1247,15 @@ impl FromBytes for TunnelProtocolResult<TunnelRequest, ()> {
                 Ok(TunnelRequest::Begin(tunnel_id))
             }
             TUNNEL_END => {
+                let reason = EndReason::from_code(buf.get_u8());
+                let tunnel_id = buf.get_u32();
+                Ok(TunnelRequest::End(tunnel_id, reason))
+            }
+            TUNNEL_ECHO => Ok(TunnelRequest::Echo(buf.get_u32())),
+            TUNNEL_TERMINATE => {
                 buf.get_u8();
                 let tunnel_id = buf.get_u32();
-                Ok(TunnelRequest::End(tunnel_id))
+                Ok(TunnelRequest::Terminate(tunnel_id))
             }
             TUNNEL_DATA => {
                 buf.get_u8();
@@ -471,6 +1264,11 @@ impl FromBytes for TunnelProtocolResult<TunnelRequest, ()> {
                 Ok(TunnelRequest::Data(tunnel_id, data))
             }
             TUNNEL_KEEPALIVE => Ok(TunnelRequest::KeepAlive),
+            TUNNEL_PADDING => {
+                let len = cmp::min(size.saturating_sub(3), buf.len());
+                buf.advance(len);
+                Ok(TunnelRequest::Padding(len))
+            }
             _ => Err(TunnelProtocolError::Unknown {
                 actual: message_type,
             }),
@@ -481,9 +1279,14 @@ impl FromBytes for TunnelProtocolResult<TunnelRequest, ()> {
 impl ToBytes for TunnelRequest {
     fn size(&self) -> usize {
         match self {
-            TunnelRequest::Extend(dest, key) => {
-                // size (2), type (1), ip flag (1), ip addr, dest port (2), secret
-                2 + 1 + 1 + dest.ip().size() + 2 + key.bytes().len()
+            TunnelRequest::Extend(dest, key, cipher_suites, features) => {
+                // size (2), type (1), ip flag (1), ip addr, dest port (2), secret, suite offer
+                2 + 1
+                    + 1
+                    + dest.ip().size()
+                    + 2
+                    + key.bytes().len()
+                    + suite_offer_len(*cipher_suites, features.as_ref())
             }
             TunnelRequest::Truncate => {
                 // size (2), type (1)
@@ -493,7 +1296,15 @@ impl ToBytes for TunnelRequest {
                 // size (2), type (1), padding (1), tunnel_id (4)
                 2 + 1 + 1 + 4
             }
-            TunnelRequest::End(_) => {
+            TunnelRequest::End(..) => {
+                // size (2), type (1), reason (1), tunnel_id (4)
+                2 + 1 + 1 + 4
+            }
+            TunnelRequest::Echo(_) => {
+                // size (2), type (1), nonce (4)
+                2 + 1 + 4
+            }
+            TunnelRequest::Terminate(_) => {
                 // size (2), type (1), padding (1), tunnel_id (4)
                 2 + 1 + 1 + 4
             }
@@ -505,18 +1316,23 @@ impl ToBytes for TunnelRequest {
                 // size (2), type (1)
                 2 + 1
             }
+            TunnelRequest::Padding(len) => {
+                // size (2), type (1), padding
+                2 + 1 + len
+            }
         }
     }
 
     fn write_to(&self, buf: &mut BytesMut) {
         match self {
-            TunnelRequest::Extend(dest, key) => {
+            TunnelRequest::Extend(dest, key, cipher_suites, features) => {
                 buf.put_u16(self.size() as u16);
                 buf.put_u8(TUNNEL_EXTEND);
                 buf.put_u8(if dest.is_ipv6() { 1 } else { 0 });
                 dest.ip().write_to(buf);
                 buf.put_u16(dest.port());
                 buf.put(key.bytes().as_ref());
+                write_suite_offer(buf, *cipher_suites, features.as_ref());
             }
             TunnelRequest::Truncate => {
                 buf.put_u16(self.size() as u16);
@@ -528,9 +1344,20 @@ impl ToBytes for TunnelRequest {
                 buf.put_u8(0);
                 buf.put_u32(*tunnel_id);
             }
-            TunnelRequest::End(tunnel_id) => {
+            TunnelRequest::End(tunnel_id, reason) => {
                 buf.put_u16(self.size() as u16);
                 buf.put_u8(TUNNEL_END);
+                buf.put_u8(reason.code());
+                buf.put_u32(*tunnel_id);
+            }
+            TunnelRequest::Echo(nonce) => {
+                buf.put_u16(self.size() as u16);
+                buf.put_u8(TUNNEL_ECHO);
+                buf.put_u32(*nonce);
+            }
+            TunnelRequest::Terminate(tunnel_id) => {
+                buf.put_u16(self.size() as u16);
+                buf.put_u8(TUNNEL_TERMINATE);
                 buf.put_u8(0);
                 buf.put_u32(*tunnel_id);
             }
@@ -545,6 +1372,11 @@ impl ToBytes for TunnelRequest {
                 buf.put_u16(self.size() as u16);
                 buf.put_u8(TUNNEL_KEEPALIVE);
             }
+            TunnelRequest::Padding(len) => {
+                buf.put_u16(self.size() as u16);
+                buf.put_u8(TUNNEL_PADDING);
+                buf.resize(buf.len() + len, 0);
+            }
         }
     }
 }
@@ -569,6 +1401,9 @@ impl FromBytes for TunnelProtocolResult<TunnelResponseExtended<VerifyKey>, Tunne
                     ERR_UNREACHABLE => Err(TunnelProtocolError::Peer(
                         TunnelExtendedError::PeerUnreachable,
                     )),
+                    ERR_POLICY_REFUSED => Err(TunnelProtocolError::Peer(
+                        TunnelExtendedError::PolicyRefused,
+                    )),
                     _ => Err(TunnelProtocolError::Peer(TunnelExtendedError::Unknown)),
                 }
             }
@@ -654,40 +1489,187 @@ impl ToBytes for TunnelTruncatedError {
     }
 }
 
+/* == TunnelResponseEchoed == */
+
+impl FromBytes for TunnelProtocolResult<TunnelResponseEchoed, ()> {
+    fn read_from(buf: &mut BytesMut) -> Self {
+        let _size = buf.get_u16() as usize;
+        let message_type = buf.get_u8();
+        match message_type {
+            TUNNEL_ECHOED => Ok(TunnelResponseEchoed(buf.get_u32())),
+            _ => Err(TunnelProtocolError::Unknown {
+                actual: message_type,
+            }),
+        }
+    }
+}
+
+impl TunnelResponseEchoed {
+    /// Returns whether the decrypted payload `buf`, still starting with the digest of `verifier`,
+    /// claims to be a `TUNNEL ECHOED` message. The digest is not checked.
+    pub(crate) fn is_echoed<V: Verifier>(buf: &[u8], verifier: &V) -> bool {
+        // digest, size (2), type (1)
+        buf.get(verifier.tag_len() + 2) == Some(&TUNNEL_ECHOED)
+    }
+}
+
+impl ToBytes for TunnelResponseEchoed {
+    fn size(&self) -> usize {
+        // size (2), type (1), nonce (4)
+        2 + 1 + 4
+    }
+
+    fn write_to(&self, buf: &mut BytesMut) {
+        buf.put_u16(self.size() as u16);
+        buf.put_u8(TUNNEL_ECHOED);
+        buf.put_u32(self.0);
+    }
+}
+
 /* == Keys == */
 
+/// Returns the length of the signed part of a key, which depends on the negotiated version.
+fn signed_key_len(suites: &SuiteSelection) -> usize {
+    if suites.is_baseline() {
+        return KEY_LEN;
+    }
+    let nonce_len = match suites.version() {
+        HandshakeVersion::Unsalted => 0,
+        _ => HANDSHAKE_NONCE_LEN,
+    };
+    let key_type_len = if suites.negotiates(FEATURE_KEY_TYPE) {
+        1
+    } else {
+        0
+    };
+    KEY_LEN + NEGOTIATION_MARKER.len() + suites.size() + nonce_len + key_type_len
+}
+
+/// Returns the length of the part of the signature field taken by a signature of `key_type`.
+fn signature_len(key_type: KeyType) -> usize {
+    match key_type {
+        KeyType::Rsa => SIGNATURE_LEN,
+        KeyType::Ed25519 => crypto::ED25519_SIGNATURE_LEN,
+    }
+}
+
+/// Returns the salt the session key is derived with: the nonce of the responder and, from
+/// [`HandshakeVersion::FeatureFlags`] on, the signed selection, so both sides derive different
+/// keys unless they agree on the negotiated version and features.
+fn key_salt(suites: &SuiteSelection, nonce: Option<&HandshakeNonce>) -> Option<Vec<u8>> {
+    let mut salt = BytesMut::from(nonce?.as_ref());
+    if suites.features.is_some() {
+        suites.write_to(&mut salt);
+    }
+    Some(salt.to_vec())
+}
+
 impl FromBytes for VerifyKey {
     fn read_from(buf: &mut BytesMut) -> Self {
         let signature = buf.split_to(SIGNATURE_LEN).freeze();
         let key_bytes = buf.split_to(KEY_LEN).freeze();
         let key = Key::new(key_bytes);
-        VerifyKey { key, signature }
+        if !read_negotiation_marker(buf) {
+            return VerifyKey {
+                key,
+                suites: SuiteSelection::baseline(),
+                nonce: None,
+                key_type: None,
+                signature,
+            };
+        }
+        let suites = SuiteSelection::read_from(buf);
+        let nonce = match suites.version() {
+            HandshakeVersion::Unsalted => None,
+            _ => {
+                let mut nonce = [0u8; HANDSHAKE_NONCE_LEN];
+                buf.copy_to_slice(&mut nonce);
+                Some(nonce)
+            }
+        };
+        let key_type = if suites.negotiates(FEATURE_KEY_TYPE) {
+            Some(buf.get_u8())
+        } else {
+            None
+        };
+        VerifyKey {
+            key,
+            suites,
+            nonce,
+            key_type,
+            signature,
+        }
     }
 }
 
 impl ToBytes for VerifyKey {
     fn size(&self) -> usize {
-        SIGNATURE_LEN + KEY_LEN
+        SIGNATURE_LEN + signed_key_len(&self.suites)
     }
 
     fn write_to(&self, buf: &mut BytesMut) {
         buf.put(self.signature.as_ref());
         buf.put(self.key.bytes().as_ref());
+        self.suites.write_marked_to(buf);
+        if let Some(nonce) = &self.nonce {
+            buf.put(nonce.as_ref());
+        }
+        if let Some(key_type) = self.key_type {
+            buf.put_u8(key_type);
+        }
     }
 }
 
 impl VerifyKey {
-    pub(crate) fn verify(self, public_key: &RsaPublicKey) -> Result<Key> {
-        match public_key.verify(self.key.bytes().as_ref(), self.signature.as_ref()) {
-            Ok(_) => Ok(self.key),
-            Err(_) => Err(anyhow!("Could not verify key signature")),
+    /// Verifies the signature of the key, the cipher suite selection and the nonce, and checks
+    /// that the selected suite is the strongest of the `offered` suites supported by the peer.
+    ///
+    /// As the offer is echoed, a version stripped from `offered` or a modified feature offer
+    /// `features` is detected as well.
+    pub(crate) fn verify(
+        self,
+        public_key: &HostPublicKey,
+        offered: CipherSuites,
+        features: Option<FeatureOffer>,
+    ) -> Result<VerifiedKey> {
+        let expected_type = public_key.key_type();
+        if let Some(key_type) = self.key_type {
+            if key_type != expected_type.code() {
+                return Err(anyhow!(
+                    "Peer signed with key type {} instead of {:?}",
+                    key_type,
+                    expected_type
+                ));
+            }
         }
+        let mut signed = BytesMut::with_capacity(signed_key_len(&self.suites));
+        signed.put(self.key.bytes().as_ref());
+        self.suites.write_marked_to(&mut signed);
+        if let Some(nonce) = &self.nonce {
+            signed.put(nonce.as_ref());
+        }
+        if let Some(key_type) = self.key_type {
+            signed.put_u8(key_type);
+        }
+        let (signature, padding) = self.signature.split_at(signature_len(expected_type));
+        if padding.iter().any(|&b| b != 0) || public_key.verify(signed.as_ref(), signature).is_err()
+        {
+            return Err(anyhow!("Could not verify key signature"));
+        }
+        let suite = self.suites.check(offered, features)?;
+        Ok(VerifiedKey {
+            key: self.key,
+            suite,
+            salt: key_salt(&self.suites, self.nonce.as_ref()),
+            version: self.suites.version(),
+            peer_version: self.suites.peer_version(),
+        })
     }
 }
 
 impl ToBytes for SignKey<'_> {
     fn size(&self) -> usize {
-        SIGNATURE_LEN + KEY_LEN
+        SIGNATURE_LEN + signed_key_len(&self.suites)
     }
 
     fn write_to(&self, buf: &mut BytesMut) {
@@ -695,15 +1677,58 @@ impl ToBytes for SignKey<'_> {
         let sig_end = sig_start + SIGNATURE_LEN;
         buf.resize(sig_end, 0);
         buf.put(self.key.bytes().as_ref());
-        self.key_pair
-            .sign(self.key.bytes().as_ref(), &mut buf[sig_start..sig_end])
-            .unwrap();
+        self.suites.write_marked_to(buf);
+        if let Some(nonce) = &self.nonce {
+            buf.put(nonce.as_ref());
+        }
+        if self.suites.negotiates(FEATURE_KEY_TYPE) {
+            buf.put_u8(self.key_pair.key_type().code());
+        }
+        let (signature, signed) = buf[sig_start..].split_at_mut(SIGNATURE_LEN);
+        let signature = &mut signature[..self.key_pair.signature_len()];
+        self.key_pair.sign(signed, signature).unwrap();
     }
 }
 
 impl<'a> SignKey<'a> {
-    pub(crate) fn sign(key: &'a Key, key_pair: &'a RsaPrivateKey) -> Self {
-        SignKey { key, key_pair }
+    /// Prepares the reply to a handshake, drawing a nonce if the negotiated version has one.
+    pub(crate) fn sign(key: &'a Key, suites: SuiteSelection, key_pair: &'a HostKey) -> Self {
+        let nonce = match suites.version() {
+            HandshakeVersion::Unsalted => None,
+            _ => Some(crypto::generate_handshake_nonce()),
+        };
+        SignKey {
+            key,
+            suites,
+            nonce,
+            key_pair,
+        }
+    }
+
+    /// Returns the salt the responder has to derive the session key with.
+    pub(crate) fn salt(&self) -> Option<Vec<u8>> {
+        key_salt(&self.suites, self.nonce.as_ref())
+    }
+
+    /// Signs the key, which takes milliseconds with a RSA host key.
+    pub(crate) fn to_signed(&self) -> SignedKey {
+        let mut buf = BytesMut::with_capacity(self.size());
+        self.write_to(&mut buf);
+        SignedKey(buf.freeze())
+    }
+}
+
+/// A [`SignKey`] which has been signed ahead of writing the reply, e.g. on the blocking thread
+/// pool.
+pub(crate) struct SignedKey(Bytes);
+
+impl ToBytes for SignedKey {
+    fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    fn write_to(&self, buf: &mut BytesMut) {
+        buf.put(self.0.as_ref());
     }
 }
 
@@ -711,7 +1736,7 @@ impl<'a> SignKey<'a> {
 mod tests {
     use super::*;
     use crate::onion::crypto::{self, EphemeralPrivateKey};
-    use crate::onion::tests::read_rsa_keypair;
+    use crate::onion::tests::{read_ed25519_keypair, read_rsa_keypair};
 
     #[test]
     fn test_circuit_create() -> Result<()> {
@@ -719,14 +1744,40 @@ mod tests {
         let key_bytes = key.bytes().clone();
 
         let circuit_id = 0;
-        let msg = CircuitCreate { circuit_id, key };
+        let features = FeatureOffer {
+            required: 0x8001,
+            ..FeatureOffer::default()
+        };
+        let msg = CircuitCreate {
+            circuit_id,
+            cell_size: CellSize::Large,
+            key,
+            cipher_suites: CipherSuites::all(),
+            features: Some(features),
+        };
         let mut buf = BytesMut::with_capacity(msg.size());
         msg.write_padded_to(&mut buf, MESSAGE_SIZE);
         let read_msg = CircuitCreate::try_read_from(&mut buf)?;
 
         assert_eq!(circuit_id, read_msg.circuit_id);
-        let key2_bytes: &[u8] = &read_msg.key.bytes().as_ref();
+        assert_eq!(CellSize::Large, read_msg.cell_size);
+        assert_eq!(CipherSuites::all(), read_msg.cipher_suites);
+        assert_eq!(read_msg.features, Some(features));
+        let key2_bytes: &[u8] = read_msg.key.bytes().as_ref();
         assert_eq!(&key_bytes.as_ref(), &key2_bytes);
+
+        // older versions have no feature offer, the padding is not mistaken for one
+        let old = CipherSuites::all().with_version(HandshakeVersion::SequencedNonces);
+        let msg = CircuitCreate {
+            cipher_suites: old,
+            features: None,
+            ..msg
+        };
+        let mut buf = BytesMut::with_capacity(msg.size());
+        msg.write_padded_to(&mut buf, MESSAGE_SIZE);
+        let read_msg = CircuitCreate::try_read_from(&mut buf)?;
+        assert_eq!(old, read_msg.cipher_suites);
+        assert_eq!(read_msg.features, None);
         Ok(())
     }
 
@@ -736,36 +1787,74 @@ mod tests {
         let key_bytes = key.bytes().clone();
 
         let (rsa_private, rsa_public) = read_rsa_keypair("testkey.pem")?;
-        let key = SignKey::sign(&key, &rsa_private);
+        let suites = SuiteSelection::negotiate(CipherSuites::all(), CipherSuites::all()).unwrap();
+        let key = SignKey::sign(&key, suites, &rsa_private);
 
         let circuit_id = 0;
-        let msg = CircuitCreated { circuit_id, key };
+        let msg = CircuitCreated {
+            circuit_id,
+            cell_size: CellSize::Large,
+            key,
+        };
         let mut buf = BytesMut::with_capacity(msg.size());
         msg.write_padded_to(&mut buf, MESSAGE_SIZE);
         let read_msg = CircuitCreated::try_read_from(&mut buf)?;
 
         assert_eq!(circuit_id, read_msg.circuit_id);
-        let key2 = read_msg.key.verify(&rsa_public)?;
-        let key2_bytes: &[u8] = &key2.bytes().as_ref();
+        assert_eq!(CellSize::Large, read_msg.cell_size);
+        let verified = read_msg.key.verify(
+            &rsa_public,
+            CipherSuites::all(),
+            FeatureOffer::for_suites(CipherSuites::all()),
+        )?;
+        assert_eq!(verified.suite, CipherSuite::Hmac);
+        assert!(verified.salt.is_some());
+        let key2_bytes: &[u8] = verified.key.bytes().as_ref();
         assert_eq!(&key_bytes.as_ref(), &key2_bytes);
         Ok(())
     }
 
     #[test]
     fn test_tunnel_extend() -> Result<()> {
+        check_tunnel_extend("127.0.0.1:4201".parse().unwrap())
+    }
+
+    #[test]
+    fn test_tunnel_extend_ipv6() -> Result<()> {
+        check_tunnel_extend("[2001:db8::1]:4201".parse().unwrap())
+    }
+
+    #[test]
+    fn test_tunnel_extend_address_type() {
+        let key = EphemeralPrivateKey::generate().public_key();
+        let dest = "127.0.0.1:4201".parse().unwrap();
+        let mut buf = BytesMut::new();
+        TunnelRequest::Extend(dest, key, CipherSuites::all(), None).write_to(&mut buf);
+        // size (2), type (1), ip flag (1)
+        buf[3] = 2;
+        let res = TunnelProtocolResult::<TunnelRequest, ()>::read_from(&mut buf);
+        assert!(matches!(
+            res,
+            Err(TunnelProtocolError::AddressType { actual: 2 })
+        ));
+    }
+
+    fn check_tunnel_extend(dest: SocketAddr) -> Result<()> {
         let key = EphemeralPrivateKey::generate().public_key();
         let key_bytes = key.bytes().clone();
 
         let aes_keys = generate_aes_keys()?;
 
-        let dest = "127.0.0.1:4201".parse().unwrap();
-        let tunnel_msg = TunnelRequest::Extend(dest, key);
+        let features = FeatureOffer::for_suites(CipherSuites::all());
+        let tunnel_msg = TunnelRequest::Extend(dest, key, CipherSuites::all(), features);
         let circuit_id = 0;
         let msg = CircuitOpaque {
             circuit_id,
             payload: CircuitOpaquePayload {
                 msg: &tunnel_msg,
                 encrypt_keys: &aes_keys,
+                cell_size: CellSize::Standard,
+                direction: Direction::Forward,
             },
         };
 
@@ -776,13 +1865,20 @@ mod tests {
         let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
 
         assert_eq!(circuit_id, read_msg.circuit_id);
-        read_msg.decrypt(aes_keys.iter().rev())?;
-        let read_tunnel_msg = TunnelRequest::read_with_digest_from(&mut read_msg.payload.bytes)?;
-        if let TunnelRequest::Extend(dest2, key2) = read_tunnel_msg {
+        read_msg.decrypt(Direction::Forward, aes_keys.iter().rev())?;
+        let read_tunnel_msg = TunnelRequest::read_with_digest_from(
+            &mut read_msg.payload.bytes,
+            &HopVerifier::new(&aes_keys[0], Direction::Forward),
+        )?;
+        if let TunnelRequest::Extend(dest2, key2, cipher_suites, features2) = read_tunnel_msg {
+            assert_eq!(cipher_suites, CipherSuites::all());
+            assert_eq!(features2, features);
             //assert_eq!(tunnel_id, tunnel_id2);
             assert_eq!(dest, dest2);
-            let key2_bytes: &[u8] = &key2.bytes().as_ref();
+            let key2_bytes: &[u8] = key2.bytes().as_ref();
             assert_eq!(&key_bytes.as_ref(), &key2_bytes);
+        } else {
+            panic!("expected TUNNEL EXTEND");
         }
         Ok(())
     }
@@ -793,7 +1889,8 @@ mod tests {
         let key_bytes = key.bytes().clone();
 
         let (rsa_private, rsa_public) = read_rsa_keypair("testkey.pem")?;
-        let key = SignKey::sign(&key, &rsa_private);
+        let suites = SuiteSelection::negotiate(CipherSuites::all(), CipherSuites::all()).unwrap();
+        let key = SignKey::sign(&key, suites, &rsa_private);
 
         let aes_keys = generate_aes_keys()?;
 
@@ -804,6 +1901,8 @@ mod tests {
             payload: CircuitOpaquePayload {
                 msg: &tunnel_msg,
                 encrypt_keys: &aes_keys,
+                cell_size: CellSize::Standard,
+                direction: Direction::Backward,
             },
         };
 
@@ -814,11 +1913,17 @@ mod tests {
         let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
 
         assert_eq!(circuit_id, read_msg.circuit_id);
-        read_msg.decrypt(aes_keys.iter().rev())?;
-        let read_tunnel_msg =
-            TunnelResponseExtended::read_with_digest_from(&mut read_msg.payload.bytes)?;
-        let key2 = read_tunnel_msg.peer_key.verify(&rsa_public)?;
-        let key2_bytes: &[u8] = &key2.bytes().as_ref();
+        read_msg.decrypt(Direction::Backward, aes_keys.iter().rev())?;
+        let read_tunnel_msg = TunnelResponseExtended::read_with_digest_from(
+            &mut read_msg.payload.bytes,
+            &HopVerifier::new(&aes_keys[0], Direction::Backward),
+        )?;
+        let verified = read_tunnel_msg.peer_key.verify(
+            &rsa_public,
+            CipherSuites::all(),
+            FeatureOffer::for_suites(CipherSuites::all()),
+        )?;
+        let key2_bytes: &[u8] = verified.key.bytes().as_ref();
         assert_eq!(&key_bytes.as_ref(), &key2_bytes);
         Ok(())
     }
@@ -834,6 +1939,8 @@ mod tests {
             payload: CircuitOpaquePayload {
                 msg: &tunnel_msg,
                 encrypt_keys: &aes_keys,
+                cell_size: CellSize::Standard,
+                direction: Direction::Backward,
             },
         };
 
@@ -844,9 +1951,11 @@ mod tests {
         let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
 
         assert_eq!(circuit_id, read_msg.circuit_id);
-        read_msg.decrypt(aes_keys.iter().rev())?;
-        let read_tunnel_msg =
-            TunnelResponseExtended::read_with_digest_from(&mut read_msg.payload.bytes);
+        read_msg.decrypt(Direction::Backward, aes_keys.iter().rev())?;
+        let read_tunnel_msg = TunnelResponseExtended::read_with_digest_from(
+            &mut read_msg.payload.bytes,
+            &HopVerifier::new(&aes_keys[0], Direction::Backward),
+        );
         assert!(matches!(
             read_tunnel_msg,
             Err(TunnelProtocolError::Peer(
@@ -867,6 +1976,8 @@ mod tests {
             payload: CircuitOpaquePayload {
                 msg: &tunnel_msg,
                 encrypt_keys: &aes_keys,
+                cell_size: CellSize::Standard,
+                direction: Direction::Backward,
             },
         };
 
@@ -877,8 +1988,11 @@ mod tests {
         let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
 
         assert_eq!(circuit_id, read_msg.circuit_id);
-        read_msg.decrypt(aes_keys.iter().rev())?;
-        TunnelResponseTruncated::read_with_digest_from(&mut read_msg.payload.bytes)?;
+        read_msg.decrypt(Direction::Backward, aes_keys.iter().rev())?;
+        TunnelResponseTruncated::read_with_digest_from(
+            &mut read_msg.payload.bytes,
+            &HopVerifier::new(&aes_keys[0], Direction::Backward),
+        )?;
         Ok(())
     }
 
@@ -893,6 +2007,8 @@ mod tests {
             payload: CircuitOpaquePayload {
                 msg: &tunnel_msg,
                 encrypt_keys: &aes_keys,
+                cell_size: CellSize::Standard,
+                direction: Direction::Backward,
             },
         };
 
@@ -903,9 +2019,11 @@ mod tests {
         let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
 
         assert_eq!(circuit_id, read_msg.circuit_id);
-        read_msg.decrypt(aes_keys.iter().rev())?;
-        let read_tunnel_msg =
-            TunnelResponseTruncated::read_with_digest_from(&mut read_msg.payload.bytes);
+        read_msg.decrypt(Direction::Backward, aes_keys.iter().rev())?;
+        let read_tunnel_msg = TunnelResponseTruncated::read_with_digest_from(
+            &mut read_msg.payload.bytes,
+            &HopVerifier::new(&aes_keys[0], Direction::Backward),
+        );
         assert!(matches!(
             read_tunnel_msg,
             Err(TunnelProtocolError::Peer(TunnelTruncatedError::NoNextHop))
@@ -913,14 +2031,539 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_tunnel_terminate() -> Result<()> {
+        let aes_keys = generate_aes_keys()?;
+        let tunnel_msg = TunnelRequest::Terminate(0xdead_beef);
+        let msg = CircuitOpaque {
+            circuit_id: 0,
+            payload: CircuitOpaquePayload {
+                msg: &tunnel_msg,
+                encrypt_keys: &aes_keys,
+                cell_size: CellSize::Standard,
+                direction: Direction::Forward,
+            },
+        };
+        let mut buf = BytesMut::with_capacity(MESSAGE_SIZE);
+        msg.write_to(&mut buf);
+        let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
+        read_msg.decrypt(Direction::Forward, aes_keys.iter().rev())?;
+        let read_tunnel_msg = TunnelRequest::read_with_digest_from(
+            &mut read_msg.payload.bytes,
+            &HopVerifier::new(&aes_keys[0], Direction::Forward),
+        )?;
+        assert!(matches!(
+            read_tunnel_msg,
+            TunnelRequest::Terminate(0xdead_beef)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tunnel_padding() -> Result<()> {
+        let aes_keys = generate_aes_keys()?;
+        let len = CellSize::Standard.max_data_size();
+        let tunnel_msg = TunnelRequest::Padding(len);
+        let msg = CircuitOpaque {
+            circuit_id: 0,
+            payload: CircuitOpaquePayload {
+                msg: &tunnel_msg,
+                encrypt_keys: &aes_keys,
+                cell_size: CellSize::Standard,
+                direction: Direction::Forward,
+            },
+        };
+        let mut buf = BytesMut::with_capacity(MESSAGE_SIZE);
+        msg.write_to(&mut buf);
+        assert_eq!(buf.len(), MESSAGE_SIZE);
+        let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
+        read_msg.decrypt(Direction::Forward, aes_keys.iter().rev())?;
+        let read_tunnel_msg = TunnelRequest::read_with_digest_from(
+            &mut read_msg.payload.bytes,
+            &HopVerifier::new(&aes_keys[0], Direction::Forward),
+        )?;
+        assert!(matches!(read_tunnel_msg, TunnelRequest::Padding(l) if l == len));
+        Ok(())
+    }
+
+    #[test]
+    fn test_end_reasons() -> Result<()> {
+        let aes_keys = generate_aes_keys()?;
+        let tunnel_msg = TunnelRequest::End(7, EndReason::Replaced);
+        let msg = CircuitOpaque {
+            circuit_id: 0,
+            payload: CircuitOpaquePayload {
+                msg: &tunnel_msg,
+                encrypt_keys: &aes_keys,
+                cell_size: CellSize::Standard,
+                direction: Direction::Backward,
+            },
+        };
+        let mut buf = BytesMut::with_capacity(MESSAGE_SIZE);
+        msg.write_to(&mut buf);
+        let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
+        read_msg.decrypt(Direction::Backward, aes_keys.iter().rev())?;
+        let read_tunnel_msg = TunnelRequest::read_with_digest_from(
+            &mut read_msg.payload.bytes,
+            &HopVerifier::new(&aes_keys[0], Direction::Backward),
+        )?;
+        assert!(matches!(
+            read_tunnel_msg,
+            TunnelRequest::End(7, EndReason::Replaced)
+        ));
+
+        let teardown = CircuitTeardown {
+            circuit_id: 3,
+            reason: EndReason::ResourceLimit,
+        };
+        let mut buf = BytesMut::with_capacity(MESSAGE_SIZE);
+        teardown.write_padded_to(&mut buf, MESSAGE_SIZE);
+        let read = CircuitOpaque::try_read_from(&mut buf);
+        assert!(matches!(
+            read,
+            Err(CircuitProtocolError::Teardown {
+                expected: CIRCUIT_OPAQUE,
+                reason: EndReason::ResourceLimit,
+            })
+        ));
+
+        // older peers send zero, newer ones may send codes unknown to this version
+        for &code in &[0, 0x7f] {
+            buf.clear();
+            buf.put_u8(CIRCUIT_TEARDOWN);
+            buf.put_u8(code);
+            buf.put_u16(3);
+            let read = CircuitOpaque::try_read_from(&mut buf);
+            assert!(matches!(
+                read,
+                Err(CircuitProtocolError::Teardown {
+                    reason: EndReason::Unspecified,
+                    ..
+                })
+            ));
+        }
+        for code in 0..=5 {
+            assert_eq!(EndReason::from_code(code).code(), code);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tunnel_echo() -> Result<()> {
+        let aes_keys = generate_aes_keys()?;
+        let tunnel_msg = TunnelRequest::Echo(0xdead_beef);
+        let msg = CircuitOpaque {
+            circuit_id: 0,
+            payload: CircuitOpaquePayload {
+                msg: &tunnel_msg,
+                encrypt_keys: &aes_keys,
+                cell_size: CellSize::Standard,
+                direction: Direction::Forward,
+            },
+        };
+        let mut buf = BytesMut::with_capacity(MESSAGE_SIZE);
+        msg.write_to(&mut buf);
+        let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
+        read_msg.decrypt(Direction::Forward, aes_keys.iter().rev())?;
+        let read_tunnel_msg = TunnelRequest::read_with_digest_from(
+            &mut read_msg.payload.bytes,
+            &HopVerifier::new(&aes_keys[0], Direction::Forward),
+        )?;
+        assert!(matches!(read_tunnel_msg, TunnelRequest::Echo(0xdead_beef)));
+
+        let tunnel_msg = TunnelResponseEchoed(0xdead_beef);
+        let msg = CircuitOpaque {
+            circuit_id: 0,
+            payload: CircuitOpaquePayload {
+                msg: &tunnel_msg,
+                encrypt_keys: &aes_keys,
+                cell_size: CellSize::Standard,
+                direction: Direction::Backward,
+            },
+        };
+        buf.clear();
+        msg.write_to(&mut buf);
+        assert_eq!(buf.len(), MESSAGE_SIZE);
+        let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
+        read_msg.decrypt(Direction::Backward, aes_keys.iter().rev())?;
+        let read_tunnel_msg = TunnelResponseEchoed::read_with_digest_from(
+            &mut read_msg.payload.bytes,
+            &HopVerifier::new(&aes_keys[0], Direction::Backward),
+        )?;
+        assert_eq!(read_tunnel_msg.0, 0xdead_beef);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cipher_suite_tags() -> Result<()> {
+        let suites = [
+            CipherSuite::TruncatedDigest,
+            CipherSuite::ShortHmac,
+            CipherSuite::Hmac,
+        ];
+        for &suite in suites.iter() {
+            let [key] = generate_aes_keys()?;
+            let aes_keys = [key.with_suite(suite)];
+            let tunnel_msg = TunnelRequest::KeepAlive;
+            let msg = CircuitOpaque {
+                circuit_id: 0,
+                payload: CircuitOpaquePayload {
+                    msg: &tunnel_msg,
+                    encrypt_keys: &aes_keys,
+                    cell_size: CellSize::Standard,
+                    direction: Direction::Forward,
+                },
+            };
+            let mut buf = BytesMut::with_capacity(MESSAGE_SIZE);
+            msg.write_to(&mut buf);
+            let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
+            read_msg.decrypt(Direction::Forward, aes_keys.iter().rev())?;
+
+            let forward = HopVerifier::new(&aes_keys[0], Direction::Forward);
+            let backward = HopVerifier::new(&aes_keys[0], Direction::Backward);
+            assert_eq!(forward.tag_len(), suite.tag_len());
+            let mut bytes = read_msg.payload.bytes.clone();
+            // keyed suites must not accept a message reflected in the other direction
+            if suite != CipherSuite::TruncatedDigest {
+                assert!(matches!(
+                    TunnelRequest::read_with_digest_from(&mut bytes, &backward),
+                    Err(TunnelProtocolError::Digest)
+                ));
+            }
+            let read_tunnel_msg = TunnelRequest::read_with_digest_from(&mut bytes, &forward)?;
+            assert!(matches!(read_tunnel_msg, TunnelRequest::KeepAlive));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_cipher_suite_downgrade() -> Result<()> {
+        let all = CipherSuites::all();
+        let features = FeatureOffer::for_suites(all);
+        let short =
+            CipherSuites::from_slice(&[CipherSuite::TruncatedDigest, CipherSuite::ShortHmac]);
+        let selection = SuiteSelection::negotiate(all, short).unwrap();
+        assert_eq!(selection.check(all, features)?, CipherSuite::ShortHmac);
+
+        // both support a stronger suite than the selected one
+        let weaker = SuiteSelection {
+            selected: CipherSuite::TruncatedDigest.code(),
+            ..selection
+        };
+        assert!(weaker.check(all, features).is_err());
+        // the offer was stripped on the way to the responder
+        let stripped = SuiteSelection::negotiate(short, short).unwrap();
+        assert!(stripped.check(all, features).is_err());
+        let unknown = SuiteSelection {
+            selected: 0xff,
+            ..selection
+        };
+        assert!(unknown.check(all, features).is_err());
+        assert!(
+            SuiteSelection::negotiate(CipherSuites::from_slice(&[CipherSuite::Hmac]), short)
+                .is_none()
+        );
+
+        // the selection is covered by the signature
+        let key = EphemeralPrivateKey::generate().public_key();
+        let (rsa_private, rsa_public) = read_rsa_keypair("testkey.pem")?;
+        let mut buf = BytesMut::new();
+        SignKey::sign(&key, selection, &rsa_private).write_to(&mut buf);
+        buf[SIGNATURE_LEN + KEY_LEN + 2] = CipherSuite::TruncatedDigest.code();
+        assert!(VerifyKey::read_from(&mut buf)
+            .verify(&rsa_public, all, features)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_handshake_versions() -> Result<()> {
+        use HandshakeVersion::{DirectionalKeys, FeatureFlags, SequencedNonces, Unsalted};
+        let key = EphemeralPrivateKey::generate().public_key();
+        let (rsa_private, rsa_public) = read_rsa_keypair("testkey.pem")?;
+        let new = CipherSuites::all();
+        let mid = new.with_version(SequencedNonces);
+        let old = new.with_version(Unsalted);
+
+        // the nonce is only added if both sides support it, and the feature selection only if
+        // both support feature flags. Older peers read the layout of their version
+        for &offered in &[new, mid, old] {
+            for &supported in &[new, mid, old] {
+                let suites = SuiteSelection::negotiate(offered, supported).unwrap();
+                let sign_key = SignKey::sign(&key, suites, &rsa_private);
+                let salt = sign_key.salt();
+                let mut buf = BytesMut::new();
+                sign_key.write_to(&mut buf);
+                assert_eq!(buf.len(), sign_key.size());
+                let read_key = VerifyKey::read_from(&mut buf);
+                assert!(buf.is_empty());
+                let features = FeatureOffer::for_suites(offered);
+                let verified = read_key.verify(&rsa_public, offered, features)?;
+                // versions from feature flags on are announced by the feature offer
+                let (version, peer_version) = match cmp::min(offered.version(), supported.version())
+                {
+                    FeatureFlags => (DirectionalKeys, DirectionalKeys),
+                    version => (version, supported.version()),
+                };
+                assert_eq!(verified.version, version);
+                assert_eq!(verified.peer_version, peer_version);
+                let salt_len = match version {
+                    Unsalted => None,
+                    SequencedNonces => Some(HANDSHAKE_NONCE_LEN),
+                    _ => Some(HANDSHAKE_NONCE_LEN + 3 + FeatureSelection::SIZE),
+                };
+                assert_eq!(verified.salt.as_ref().map(Vec::len), salt_len);
+                assert_eq!(verified.salt, salt);
+            }
+        }
+        assert_eq!(
+            SuiteSelection::negotiate(new, new).unwrap().version(),
+            DirectionalKeys
+        );
+
+        // the nonce is covered by the signature
+        let suites = SuiteSelection::negotiate(new, new).unwrap();
+        let features = FeatureOffer::for_suites(new);
+        let mut buf = BytesMut::new();
+        SignKey::sign(&key, suites, &rsa_private).write_to(&mut buf);
+        // the last byte of the nonce, followed by the key type
+        let last = buf.len() - 2;
+        buf[last] ^= 1;
+        assert!(VerifyKey::read_from(&mut buf)
+            .verify(&rsa_public, new, features)
+            .is_err());
+
+        // a version stripped from the offer on its way to the responder is detected
+        for &stripped in &[old, mid] {
+            let stripped = SuiteSelection::negotiate(stripped, new).unwrap();
+            let mut buf = BytesMut::new();
+            SignKey::sign(&key, stripped, &rsa_private).write_to(&mut buf);
+            assert!(VerifyKey::read_from(&mut buf)
+                .verify(&rsa_public, new, features)
+                .is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_baseline_layout() -> Result<()> {
+        let key = EphemeralPrivateKey::generate().public_key();
+        let (rsa_private, rsa_public) = read_rsa_keypair("testkey.pem")?;
+        let all = CipherSuites::all();
+        let features = FeatureOffer::for_suites(all);
+
+        // the baseline suites are requested in the layout of peers predating the negotiation,
+        // which is what their random padding is read as
+        let msg = CircuitCreate {
+            circuit_id: 0,
+            cell_size: CellSize::Standard,
+            key: Key::new(key.bytes().clone()),
+            cipher_suites: CipherSuites::BASELINE,
+            features: None,
+        };
+        let mut buf = BytesMut::new();
+        msg.write_to(&mut buf);
+        assert_eq!(buf.len(), 4 + KEY_LEN);
+        let mut buf = BytesMut::new();
+        msg.write_padded_to(&mut buf, MESSAGE_SIZE);
+        let read_msg = CircuitCreate::try_read_from(&mut buf)?;
+        assert_eq!(read_msg.cipher_suites, CipherSuites::BASELINE);
+        assert_eq!(read_msg.features, None);
+
+        // and answered with a signature over the key alone
+        let suites = SuiteSelection::negotiate(CipherSuites::BASELINE, all).unwrap();
+        let sign_key = SignKey::sign(&key, suites, &rsa_private);
+        assert_eq!(sign_key.salt(), None);
+        let mut buf = BytesMut::new();
+        sign_key.write_padded_to(&mut buf, MESSAGE_SIZE);
+        rsa_public.verify(key.bytes(), &buf[..rsa_private.signature_len()])?;
+        let verified = VerifyKey::read_from(&mut buf.clone()).verify(&rsa_public, all, features)?;
+        assert_eq!(verified.suite, CipherSuite::TruncatedDigest);
+        assert_eq!(verified.version, HandshakeVersion::Unsalted);
+        assert_eq!(verified.peer_version, HandshakeVersion::Unsalted);
+        assert_eq!(verified.salt, None);
+        // which initiators not offering the baseline suite refuse
+        let hmac = CipherSuites::from_slice(&[CipherSuite::Hmac]);
+        assert!(VerifyKey::read_from(&mut buf)
+            .verify(&rsa_public, hmac, FeatureOffer::for_suites(hmac))
+            .is_err());
+
+        // the marker is signed, so the selection can not be stripped from a reply
+        let suites = SuiteSelection::negotiate(all, all).unwrap();
+        let mut buf = BytesMut::new();
+        SignKey::sign(&key, suites, &rsa_private).write_to(&mut buf);
+        buf[SIGNATURE_LEN + KEY_LEN] ^= 1;
+        assert!(VerifyKey::read_from(&mut buf)
+            .verify(&rsa_public, all, features)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_feature_flags() -> Result<()> {
+        let key = EphemeralPrivateKey::generate().public_key();
+        let (rsa_private, rsa_public) = read_rsa_keypair("testkey.pem")?;
+        let suites = CipherSuites::all();
+        let offer = FeatureOffer::default();
+        let selection = SuiteSelection::negotiate(suites, suites).unwrap();
+
+        // the feature selection is covered by the signature
+        let mut buf = BytesMut::new();
+        SignKey::sign(&key, selection, &rsa_private).write_to(&mut buf);
+        // the version of the responder, after the suites and the echoed offer
+        buf[SIGNATURE_LEN + KEY_LEN + NEGOTIATION_MARKER.len() + 3 + FeatureOffer::SIZE] ^= 1;
+        assert!(VerifyKey::read_from(&mut buf)
+            .verify(&rsa_public, suites, Some(offer))
+            .is_err());
+
+        // a modified offer is detected like modified suites
+        let required = FeatureOffer {
+            required: 0x8000,
+            ..offer
+        };
+        let modified = SuiteSelection::negotiate_features(suites, required, suites).unwrap();
+        assert!(modified.check(suites, Some(offer)).is_err());
+
+        // an unknown required feature is reported as such, with the signed selection of the
+        // responder telling which features are missing
+        assert_eq!(modified.missing_features(), 0x8000);
+        let e = modified.check(suites, Some(required)).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<UnsupportedFeatures>(),
+            Some(&UnsupportedFeatures { missing: 0x8000 })
+        );
+        // unknown optional features are ignored
+        let optional = FeatureOffer {
+            features: 0x8000,
+            ..offer
+        };
+        let selection = SuiteSelection::negotiate_features(suites, optional, suites).unwrap();
+        assert_eq!(selection.check(suites, Some(optional))?, CipherSuite::Hmac);
+
+        // a later initiator announces its version by number, the latest common version is used
+        let later = FeatureOffer {
+            version: HandshakeVersion::LATEST.code() + 1,
+            ..offer
+        };
+        let selection = SuiteSelection::negotiate_features(suites, later, suites).unwrap();
+        assert_eq!(selection.version(), HandshakeVersion::LATEST);
+        assert_eq!(selection.peer_version(), HandshakeVersion::LATEST);
+
+        // an initiator predating directional keys derives the session key like before
+        let earlier = FeatureOffer {
+            version: HandshakeVersion::FeatureFlags.code(),
+            ..offer
+        };
+        let earlier = SuiteSelection::negotiate_features(suites, earlier, suites).unwrap();
+        assert_eq!(earlier.version(), HandshakeVersion::FeatureFlags);
+
+        // the selection is mixed into the session key, not only the nonce
+        let nonce = crypto::generate_handshake_nonce();
+        assert_ne!(
+            key_salt(&selection, Some(&nonce)),
+            key_salt(&modified, Some(&nonce))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_types() -> Result<()> {
+        use HandshakeVersion::SequencedNonces;
+        let key = EphemeralPrivateKey::generate().public_key();
+        let (rsa_private, rsa_public) = read_rsa_keypair("testkey.pem")?;
+        let (ed25519_private, ed25519_public) = read_ed25519_keypair("tests/ed25519key.pem")?;
+        let new = CipherSuites::all();
+        let old = new.with_version(SequencedNonces);
+
+        // either key type verifies, also with peers which do not announce the key type
+        for &(private, public) in &[
+            (&rsa_private, &rsa_public),
+            (&ed25519_private, &ed25519_public),
+        ] {
+            for &supported in &[new, old] {
+                let suites = SuiteSelection::negotiate(new, supported).unwrap();
+                let mut buf = BytesMut::new();
+                let sign_key = SignKey::sign(&key, suites, private);
+                sign_key.write_to(&mut buf);
+                assert_eq!(buf.len(), sign_key.size());
+                let features = FeatureOffer::for_suites(new);
+                VerifyKey::read_from(&mut buf).verify(public, new, features)?;
+            }
+        }
+
+        // the key type is covered by the signature and checked against the expected key
+        let suites = SuiteSelection::negotiate(new, new).unwrap();
+        let features = FeatureOffer::for_suites(new);
+        let mut buf = BytesMut::new();
+        SignKey::sign(&key, suites, &ed25519_private).write_to(&mut buf);
+        let last = buf.len() - 1;
+        assert_eq!(buf[last], KeyType::Ed25519.code());
+        buf[last] = KeyType::Rsa.code();
+        assert!(VerifyKey::read_from(&mut buf)
+            .verify(&ed25519_public, new, features)
+            .is_err());
+        let mut buf = BytesMut::new();
+        SignKey::sign(&key, suites, &ed25519_private).write_to(&mut buf);
+        assert!(VerifyKey::read_from(&mut buf)
+            .verify(&rsa_public, new, features)
+            .is_err());
+
+        // the padding of a short signature must be zero
+        let mut buf = BytesMut::new();
+        SignKey::sign(&key, suites, &ed25519_private).write_to(&mut buf);
+        buf[SIGNATURE_LEN - 1] = 1;
+        assert!(VerifyKey::read_from(&mut buf)
+            .verify(&ed25519_public, new, features)
+            .is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_tunnel_data() -> Result<()> {
+        let aes_keys = generate_aes_keys()?;
+        for &cell_size in CellSize::ALL.iter() {
+            let max_len = cell_size.max_data_size();
+            for &len in &[0, 1, 100, max_len] {
+                let data = Bytes::from(vec![0xab; len]);
+                let tunnel_msg = TunnelRequest::Data(0xdead_beef, data.clone());
+                let msg = CircuitOpaque {
+                    circuit_id: 0,
+                    payload: CircuitOpaquePayload {
+                        msg: &tunnel_msg,
+                        encrypt_keys: &aes_keys,
+                        cell_size,
+                        direction: Direction::Forward,
+                    },
+                };
+                let mut buf = BytesMut::with_capacity(cell_size.bytes());
+                msg.write_to(&mut buf);
+                // the length of the data is only known to the hop holding the session key
+                assert_eq!(buf.len(), cell_size.bytes());
+                let mut read_msg = CircuitOpaque::try_read_from(&mut buf)?;
+                read_msg.decrypt(Direction::Forward, aes_keys.iter().rev())?;
+                let read_tunnel_msg = TunnelRequest::read_with_digest_from(
+                    &mut read_msg.payload.bytes,
+                    &HopVerifier::new(&aes_keys[0], Direction::Forward),
+                )?;
+                assert!(matches!(
+                    read_tunnel_msg,
+                    TunnelRequest::Data(0xdead_beef, read) if read == data
+                ));
+            }
+        }
         Ok(())
     }
 
+    /// Returns the key of a single hop, with a different key for each direction, so a message
+    /// only decrypts with the direction it was encrypted for.
     fn generate_aes_keys() -> Result<[SessionKey; 1]> {
-        let mut aes_key_bytes = [0u8; 16];
-        crypto::fill_random(&mut aes_key_bytes);
-        Ok([SessionKey::from_bytes(&aes_key_bytes)?])
+        let mut secret = [0u8; 32];
+        crypto::fill_random(&mut secret);
+        Ok([SessionKey::from_shared_secret(
+            &secret,
+            CipherSuite::TruncatedDigest,
+            None,
+            HandshakeVersion::DirectionalKeys,
+        )?])
     }
 }
@@ -0,0 +1,107 @@
+//! Inspection of the cells passing through this peer for measurement studies, see
+//! [`CellInspector`].
+//!
+//! This module only exists with the `research` feature enabled. It reveals timing information
+//! about the traffic of other users of the network, which weakens their anonymity if it is
+//! recorded or leaked, so it must never be enabled in production deployments.
+
+use crate::onion::crypto;
+use crate::onion::protocol::TunnelRequest;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The direction in which a cell travels along its tunnel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CellDirection {
+    /// From the initiator towards the destination of the tunnel.
+    Forward,
+    /// From the destination back to the initiator of the tunnel.
+    Backward,
+}
+
+/// The kind of a cell, as far as it is known to this peer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CellKind {
+    /// The cell was relayed to the next or previous hop without being readable by this peer.
+    Relayed,
+    /// The cell was addressed to this peer and manages the tunnel, e.g. extends it or keeps it
+    /// alive.
+    Control,
+    /// The cell was addressed to this peer as the destination of the tunnel and carries data.
+    Data,
+    /// The cell was addressed to this peer as the destination of the tunnel and carries padding,
+    /// which is dropped.
+    Padding,
+}
+
+impl CellKind {
+    fn of(req: &TunnelRequest) -> Self {
+        match req {
+            TunnelRequest::Data(..) => CellKind::Data,
+            TunnelRequest::Padding(_) => CellKind::Padding,
+            _ => CellKind::Control,
+        }
+    }
+}
+
+/// Metadata about a single cell handled by this peer. The payload is never exposed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CellMeta {
+    /// A random id of the circuit the cell arrived on. It is drawn when the circuit is accepted
+    /// and can not be linked to peer addresses or circuit ids.
+    pub circuit_token: u64,
+    pub direction: CellDirection,
+    pub arrived_at: SystemTime,
+    pub cell_kind: CellKind,
+}
+
+/// Receives the metadata of every cell handled by the circuits accepted by this onion router.
+///
+/// Like a [`StateObserver`](crate::StateObserver), the inspector is called inline by the tasks
+/// handling circuits, so it has to be cheap and must not block.
+pub trait CellInspector: Send + Sync {
+    fn on_cell(&self, meta: &CellMeta);
+}
+
+impl<F: Fn(&CellMeta) + Send + Sync> CellInspector for F {
+    fn on_cell(&self, meta: &CellMeta) {
+        self(meta)
+    }
+}
+
+/// The [`CellInspector`] of a single circuit together with its token.
+pub(crate) struct CellTap {
+    inspector: Option<Arc<dyn CellInspector>>,
+    circuit_token: u64,
+}
+
+impl CellTap {
+    pub(crate) fn new(inspector: Option<Arc<dyn CellInspector>>) -> Self {
+        let mut token_buf = [0u8; 8];
+        crypto::fill_random(&mut token_buf);
+        CellTap {
+            inspector,
+            circuit_token: u64::from_le_bytes(token_buf),
+        }
+    }
+
+    pub(crate) fn relayed(&self, direction: CellDirection, arrived_at: SystemTime) {
+        self.record(direction, CellKind::Relayed, arrived_at);
+    }
+
+    pub(crate) fn addressed(&self, req: &TunnelRequest, arrived_at: SystemTime) {
+        self.record(CellDirection::Forward, CellKind::of(req), arrived_at);
+    }
+
+    fn record(&self, direction: CellDirection, cell_kind: CellKind, arrived_at: SystemTime) {
+        if let Some(inspector) = &self.inspector {
+            inspector.on_cell(&CellMeta {
+                circuit_token: self.circuit_token,
+                direction,
+                arrived_at,
+                cell_kind,
+            });
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Backoff of automatic rebuilds towards a failed destination, see [`DestinationRetries`].
+//!
+//! If a build gives up while adding the destination to the path, the destination itself is likely
+//! down. Rotations and replacements of failed paths then wait for an
+//! increasing, randomized interval before building towards it again, so a destination which is
+//! down is neither flooded with handshakes nor contacted in a recognizable rhythm. Builds requested
+//! by the application are never delayed.
+
+use crate::onion::crypto;
+use crate::onion::RetryBackoff;
+use crate::Fingerprint;
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// delay after the first failure at a destination, before jitter is applied
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+/// upper bound for the delay between two builds towards a destination
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(120);
+/// time after the end of a backoff after which the failures of a destination are forgotten
+const FORGET_AFTER: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// Failures of destinations, shared by all tunnels of an onion router.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DestinationRetries {
+    inner: Arc<Mutex<HashMap<Fingerprint, Backoff>>>,
+}
+
+impl DestinationRetries {
+    /// Records that a build failed at the destination with the given fingerprint and returns the
+    /// time for which automatic builds towards it are delayed.
+    ///
+    /// The delay doubles with every consecutive failure up to [`MAX_RETRY_BACKOFF`], and is
+    /// randomly chosen between half of it and all of it.
+    pub(crate) fn record_failure(&self, fingerprint: Fingerprint) -> Duration {
+        let mut destinations = self.inner.lock().unwrap();
+        let now = Instant::now();
+        destinations.retain(|_, backoff| backoff.retry_at + FORGET_AFTER > now);
+        let failures = destinations
+            .get(&fingerprint)
+            .map_or(1, |backoff| backoff.failures.saturating_add(1));
+        let delay = jitter(backoff_delay(failures));
+        destinations.insert(
+            fingerprint,
+            Backoff {
+                failures,
+                retry_at: now + delay,
+            },
+        );
+        delay
+    }
+
+    /// Records that a path reached the destination with the given fingerprint, which ends its
+    /// backoff.
+    pub(crate) fn record_success(&self, fingerprint: &Fingerprint) {
+        self.inner.lock().unwrap().remove(fingerprint);
+    }
+
+    /// Returns the remembered destinations with the number of their failures and the end of their
+    /// backoff.
+    pub(crate) fn entries(&self) -> Vec<(Fingerprint, u32, Instant)> {
+        let now = Instant::now();
+        let destinations = self.inner.lock().unwrap();
+        destinations
+            .iter()
+            .filter(|(_, backoff)| backoff.retry_at + FORGET_AFTER > now)
+            .map(|(fingerprint, backoff)| (*fingerprint, backoff.failures, backoff.retry_at))
+            .collect()
+    }
+
+    /// Remembers the failures of a destination, unless they would already be forgotten.
+    pub(crate) fn insert(&self, fingerprint: Fingerprint, failures: u32, retry_at: Instant) {
+        if retry_at + FORGET_AFTER > Instant::now() {
+            let backoff = Backoff { failures, retry_at };
+            self.inner.lock().unwrap().insert(fingerprint, backoff);
+        }
+    }
+
+    /// Returns the backoff of the destination with the given fingerprint, unless it has ended.
+    pub(crate) fn backoff(&self, fingerprint: &Fingerprint) -> Option<RetryBackoff> {
+        let destinations = self.inner.lock().unwrap();
+        let backoff = destinations.get(fingerprint)?;
+        let retry_in = backoff.retry_at.checked_duration_since(Instant::now())?;
+        Some(RetryBackoff {
+            failures: backoff.failures,
+            retry_in,
+        })
+    }
+}
+
+/// Returns the delay before jitter after `failures` consecutive failures.
+fn backoff_delay(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    cmp::min(RETRY_BACKOFF * 2u32.pow(doublings), MAX_RETRY_BACKOFF)
+}
+
+/// Returns a random delay between half of `delay` and `delay`.
+fn jitter(delay: Duration) -> Duration {
+    let mut buf = [0u8; 4];
+    crypto::fill_random(&mut buf);
+    let fraction = f64::from(u32::from_le_bytes(buf)) / f64::from(u32::MAX);
+    delay / 2 + (delay / 2).mul_f64(fraction)
+}
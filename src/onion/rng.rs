@@ -0,0 +1,117 @@
+//! The source of the random circuit and tunnel ids chosen by an onion router, see [`OnionRng`].
+
+use crate::onion::crypto;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A source of random bytes.
+///
+/// An onion router draws the ids of its tunnels and circuits from the generators created by the
+/// factory passed to [`OnionBuilder::set_rng_factory`](crate::OnionBuilder::set_rng_factory).
+/// By default every generator is a [`SystemRandom`]. Keys and nonces are always drawn from the
+/// system generator of the crypto backend.
+pub trait OnionRng: Send {
+    /// Fills `buf` with random bytes.
+    fn fill_bytes(&mut self, buf: &mut [u8]);
+
+    /// Returns a copy of this generator, which yields the same bytes as this one from now on.
+    fn box_clone(&self) -> Box<dyn OnionRng>;
+}
+
+impl Clone for Box<dyn OnionRng> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// The system generator of the crypto backend.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemRandom;
+
+impl OnionRng for SystemRandom {
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        crypto::fill_random(buf)
+    }
+
+    fn box_clone(&self) -> Box<dyn OnionRng> {
+        Box::new(*self)
+    }
+}
+
+/// A deterministic generator, which yields the same bytes for the same seed.
+///
+/// The bytes are the SHA-256 digests of the seed and a counter, incremented for every block of 32
+/// bytes. Every call of [`fill_bytes`](OnionRng::fill_bytes) starts a new block. Only meant for
+/// reproducing ids in tests, the ids of an onion router using it are predictable.
+#[derive(Clone, Debug)]
+pub struct SeededRandom {
+    seed: u64,
+    counter: u64,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        SeededRandom { seed, counter: 0 }
+    }
+}
+
+impl OnionRng for SeededRandom {
+    fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(32) {
+            let mut input = [0u8; 16];
+            input[..8].copy_from_slice(&self.seed.to_le_bytes());
+            input[8..].copy_from_slice(&self.counter.to_le_bytes());
+            self.counter += 1;
+            let block = crypto::digest(&input);
+            chunk.copy_from_slice(&block.as_ref()[..chunk.len()]);
+        }
+    }
+
+    fn box_clone(&self) -> Box<dyn OnionRng> {
+        Box::new(self.clone())
+    }
+}
+
+/// Creates the generators of an onion router, one for every connection and every path of a
+/// tunnel, which draw circuit ids. Creates [`SystemRandom`]s by default.
+#[derive(Clone)]
+pub(crate) struct RngFactory(Arc<dyn Fn() -> Box<dyn OnionRng> + Send + Sync>);
+
+impl RngFactory {
+    pub(crate) fn new<F>(factory: F) -> Self
+    where
+        F: Fn() -> Box<dyn OnionRng> + Send + Sync + 'static,
+    {
+        RngFactory(Arc::new(factory))
+    }
+
+    pub(crate) fn create(&self) -> Box<dyn OnionRng> {
+        (self.0)()
+    }
+
+    /// Creates a generator shared by all clones of the returned handle.
+    pub(crate) fn shared(&self) -> SharedRng {
+        SharedRng(Arc::new(Mutex::new(self.create())))
+    }
+}
+
+impl Default for RngFactory {
+    fn default() -> Self {
+        RngFactory::new(|| Box::new(SystemRandom))
+    }
+}
+
+/// A generator shared across threads, e.g. for drawing the ids of all tunnels of an onion router.
+#[derive(Clone)]
+pub(crate) struct SharedRng(Arc<Mutex<Box<dyn OnionRng>>>);
+
+impl SharedRng {
+    pub(crate) fn lock(&self) -> MutexGuard<'_, Box<dyn OnionRng>> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl Default for SharedRng {
+    fn default() -> Self {
+        RngFactory::default().shared()
+    }
+}
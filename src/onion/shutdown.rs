@@ -0,0 +1,229 @@
+//! Ordered shutdown of an onion router, see [`OnionContext::shutdown`](crate::OnionContext::shutdown).
+//!
+//! Shutdown happens in three steps: intake is closed, so no new tunnels are accepted, the running
+//! tunnel handlers are asked to destroy their tunnels and awaited, and only then the event streams
+//! and background tasks of the onion router end. Subscribers thereby receive the events reporting
+//! the closed tunnels before their stream ends.
+//!
+//! Sending an event never waits for the subscribers, so a subscriber which stopped reading can
+//! not hold up the shutdown. Instead, it misses the events which no longer fit into the buffer of
+//! its stream. These are counted by the [`EventTally`] and reported in the [`ShutdownReport`].
+
+use crate::onion::Event;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use thiserror::Error;
+use tokio::sync::{broadcast, Notify};
+
+/// Returned by an onion router after [`OnionContext::shutdown`](crate::OnionContext::shutdown)
+/// was called.
+#[derive(Error, Debug, PartialEq)]
+#[error("the onion router is shutting down")]
+pub struct ShuttingDown;
+
+/// Summary of the shutdown of an onion router, returned by
+/// [`OnionContext::shutdown`](crate::OnionContext::shutdown).
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct ShutdownReport {
+    /// The number of events missed by [`OnionEvents`](crate::OnionEvents) subscribers which did
+    /// not keep up during the shutdown, summed over all subscribers. If it is not zero, the final
+    /// view of a subscriber on its tunnels may be incomplete.
+    pub dropped_events: u64,
+}
+
+/// The shutdown state shared by all handles and tunnel handlers of an onion router.
+#[derive(Debug, Default)]
+pub(crate) struct Shutdown {
+    /// set once the shutdown started, new tunnels are rejected from then on
+    closing: AtomicBool,
+    /// set once all tunnel handlers finished or were given up
+    finished: AtomicBool,
+    /// number of live [`ShutdownGuard`]s
+    running: AtomicUsize,
+    changed: Notify,
+}
+
+impl Shutdown {
+    /// Registers a tunnel handler, which is awaited by the shutdown until the returned guard is
+    /// dropped.
+    ///
+    /// Fails if the shutdown already started. Everything the handler has to observe in order to
+    /// stop, e.g. a subscription to the tunnel events, has to be set up before this call.
+    pub(crate) fn enter(self: &Arc<Self>) -> Result<ShutdownGuard, ShuttingDown> {
+        self.running.fetch_add(1, Ordering::SeqCst);
+        let guard = ShutdownGuard(self.clone());
+        if self.is_closing() {
+            return Err(ShuttingDown);
+        }
+        Ok(guard)
+    }
+
+    pub(crate) fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Returns an error if the shutdown already started.
+    pub(crate) fn check(&self) -> Result<(), ShuttingDown> {
+        if self.is_closing() {
+            Err(ShuttingDown)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Closes intake, i.e. all later calls to [`Shutdown::enter`] fail.
+    pub(crate) fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits until all registered tunnel handlers finished.
+    pub(crate) async fn wait_idle(&self) {
+        loop {
+            // created before checking, so a notification in between is not missed
+            let changed = self.changed.notified();
+            if self.running.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Marks the shutdown as finished, which ends the event streams and background tasks.
+    pub(crate) fn finish(&self) {
+        self.finished.store(true, Ordering::SeqCst);
+        self.changed.notify_waiters();
+    }
+
+    /// Waits until the shutdown finished.
+    pub(crate) async fn finished(&self) {
+        loop {
+            let changed = self.changed.notified();
+            if self.finished.load(Ordering::SeqCst) {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+/// Keeps a tunnel handler registered with [`Shutdown`] until dropped.
+#[derive(Debug)]
+pub(crate) struct ShutdownGuard(Arc<Shutdown>);
+
+impl ShutdownGuard {
+    pub(crate) fn is_closing(&self) -> bool {
+        self.0.is_closing()
+    }
+
+    /// Returns the shutdown state this guard is registered with.
+    pub(crate) fn shutdown(&self) -> Arc<Shutdown> {
+        self.0.clone()
+    }
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        if self.0.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.changed.notify_waiters();
+        }
+    }
+}
+
+/// Counts the events missed by the subscribers of an onion router, see [`ShutdownReport`].
+#[derive(Debug)]
+pub(crate) struct EventTally {
+    /// a receiver which is never read but drained, so it counts all events sent
+    sent: Mutex<(broadcast::Receiver<Event>, u64)>,
+    subscribers: Mutex<Vec<Weak<Subscriber>>>,
+    /// number of events each subscriber can fall behind without missing any
+    capacity: u64,
+}
+
+/// The progress of a single subscriber, see [`EventTally`].
+#[derive(Debug, Default)]
+pub(crate) struct Subscriber {
+    /// events sent before the subscription
+    start: u64,
+    /// events received or skipped since the subscription
+    consumed: AtomicU64,
+    /// events skipped since the subscription
+    skipped: AtomicU64,
+    /// events skipped before the shutdown started
+    skipped_before_close: AtomicU64,
+}
+
+impl Subscriber {
+    /// Records that the subscriber received an event or skipped `skipped` events.
+    pub(crate) fn record(&self, skipped: u64) {
+        self.consumed.fetch_add(skipped.max(1), Ordering::Relaxed);
+        self.skipped.fetch_add(skipped, Ordering::Relaxed);
+    }
+}
+
+impl EventTally {
+    /// Creates a tally of the events sent by `notify`, whose streams buffer `capacity` events.
+    pub(crate) fn new(notify: &broadcast::Sender<Event>, capacity: usize) -> Self {
+        EventTally {
+            sent: Mutex::new((notify.subscribe(), 0)),
+            subscribers: Default::default(),
+            capacity: capacity as u64,
+        }
+    }
+
+    /// Returns the number of events sent so far.
+    fn sent(&self) -> u64 {
+        let mut sent = self.sent.lock().unwrap();
+        let (events, count) = &mut *sent;
+        loop {
+            match events.try_recv() {
+                Ok(_) => *count += 1,
+                Err(broadcast::error::TryRecvError::Lagged(n)) => *count += n,
+                Err(_) => return *count,
+            }
+        }
+    }
+
+    /// Registers a subscriber, which has to be subscribed to the events before this call.
+    pub(crate) fn subscribe(&self) -> Arc<Subscriber> {
+        let subscriber = Arc::new(Subscriber {
+            start: self.sent(),
+            ..Default::default()
+        });
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|s| s.strong_count() > 0);
+        subscribers.push(Arc::downgrade(&subscriber));
+        subscriber
+    }
+
+    fn live_subscribers(&self) -> Vec<Arc<Subscriber>> {
+        let subscribers = self.subscribers.lock().unwrap();
+        subscribers.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /// Marks the start of the shutdown, events skipped before are not counted as dropped.
+    pub(crate) fn close(&self) {
+        for subscriber in self.live_subscribers() {
+            let skipped = subscriber.skipped.load(Ordering::Relaxed);
+            subscriber
+                .skipped_before_close
+                .store(skipped, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of events the subscribers missed since [`EventTally::close`], including
+    /// those they will notice once they read again.
+    pub(crate) fn dropped(&self) -> u64 {
+        let sent = self.sent();
+        self.live_subscribers()
+            .iter()
+            .map(|subscriber| {
+                let consumed = subscriber.consumed.load(Ordering::Relaxed);
+                let unread = sent.saturating_sub(subscriber.start + consumed);
+                let skipped = subscriber.skipped.load(Ordering::Relaxed)
+                    - subscriber.skipped_before_close.load(Ordering::Relaxed);
+                skipped + unread.saturating_sub(self.capacity)
+            })
+            .sum()
+    }
+}
@@ -1,6 +1,10 @@
-use crate::onion::circuit::CircuitId;
-use crate::onion::crypto::SessionKey;
+use crate::leak::Tracked;
+use crate::onion::circuit::{CircuitId, CircuitIds};
+use crate::onion::config::SocketTimeouts;
+use crate::onion::connection::{CircuitStream, ConnectionInfo};
+use crate::onion::crypto::{CipherSuites, Direction, SessionKey};
 use crate::onion::protocol::*;
+use crate::onion::rng::OnionRng;
 use crate::onion::tunnel::TunnelId;
 use crate::utils::{ToBytes, TryFromBytes};
 use crate::Result;
@@ -9,38 +13,40 @@ use std::fmt;
 use std::net::SocketAddr;
 use thiserror::Error;
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::time::{error::Elapsed, timeout, Duration};
+use tokio::time::{error::Elapsed, timeout};
 
-/// timeout applied during a read on the socket
-const READ_TIMEOUT: Duration = Duration::from_secs(5);
-/// timeout applied during a write on the socket
-const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+/// maximum number of `TUNNEL DATA` messages coalesced into a single write
+pub(crate) const MAX_BATCH_SIZE: usize = 32;
 
+/// An error of an `OnionSocket`, along with the connection it occurred on.
 #[derive(Error, Debug)]
-pub(crate) enum OnionSocketError {
+#[error("{kind} on {connection}")]
+pub(crate) struct OnionSocketError {
+    #[source]
+    pub(crate) kind: SocketErrorKind,
+    pub(crate) connection: ConnectionInfo,
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum SocketErrorKind {
     /// The stream of this `OnionSocket` has been terminated and is unavailable for communication.
     /// The cause is underlying network layer stream of type  `S` threw an I/O error during interaction
     #[error("stream has been terminated")]
     StreamTerminated(#[from] io::Error),
-    /// Reading or writing on the stream of this `OnionSocket` timed out. Be aware of any possible
-    /// scenarios in which a partial message has been received and the buffer is partially filled.
-    /// If another operation on this `OnionSocket` is called, the buffer may not be filled with one
-    /// complete message as expected and `BrokenMessage` may be returned. Alternatively, clearing
-    /// the buffer may cause broken message fragments to remain in the underlying stream, which will
-    /// be ending up in the buffer on the next read call.
+    /// Reading or writing on the stream of this `OnionSocket` timed out. A partially received
+    /// message is kept and completed by the next read on this `OnionSocket`.
     #[error("stream operation has timed out")]
     StreamTimeout(#[from] Elapsed),
     /// The received message is of type `TEARDOWN` and the function throwing this error cannot deal
     /// with it. The `TEARDOWN` message is always allowed by protocol to indicate a closed circuit
-    /// by the connected peer.
-    #[error("received teardown message that cannot be handled")]
-    TeardownMessage,
+    /// by the connected peer, for the given reason.
+    #[error("received teardown message ({0:?}) that cannot be handled")]
+    TeardownMessage(EndReason),
     /// The received message does not comply with the protocol
     /// This may be caused by:
     /// - an undefined message type or tunnel message type
     /// - an irregular message type (like waiting for an Tunnel `TRUNCATED` message, yet receiving a
-    /// `TUNNEL EXTENDED` message)
+    ///   `TUNNEL EXTENDED` message)
     /// - a wrong circuit id
     // In the case that a command response is awaited, incoming Tunnel Data messages may or may
     // not be ignored. Either way would be conforming to the specification.
@@ -49,70 +55,183 @@ pub(crate) enum OnionSocketError {
     /// Indicates that the remote peer returned a tunnel request with an error code
     #[error("tunnel request returned error")]
     Peer,
+    /// The remote peer refused to extend the tunnel, since its address policy denies the address
+    /// of the new hop.
+    #[error("extension refused by the address policy of the peer")]
+    PolicyRefused,
+    /// The connected peer requested or confirmed a cell size which is not supported or does not
+    /// match the requested one. The circuit handshake has failed.
+    #[error("cell size could not be negotiated")]
+    UnsupportedCellSize,
+    /// None of the cipher suites offered by the connected peer is supported. The circuit has been
+    /// torn down.
+    #[error("no common cipher suite")]
+    NoCommonCipherSuite,
+    /// The received message references a circuit which is not open on this connection, or a
+    /// `CIRCUIT CREATE` message reuses the id of an open circuit. The message has been dropped.
+    #[error("received message for unknown circuit {0}")]
+    UnknownCircuit(CircuitId),
+    /// All circuit ids of this connection are in use, no further circuit can be created.
+    #[error("no free circuit id")]
+    CircuitIdsExhausted,
 }
 
 pub(crate) type SocketResult<T> = std::result::Result<T, OnionSocketError>;
 
-impl From<CircuitProtocolError> for OnionSocketError {
+impl OnionSocketError {
+    pub(crate) fn new(kind: impl Into<SocketErrorKind>, connection: ConnectionInfo) -> Self {
+        OnionSocketError {
+            kind: kind.into(),
+            connection,
+        }
+    }
+}
+
+impl From<CircuitProtocolError> for SocketErrorKind {
     fn from(e: CircuitProtocolError) -> Self {
         match e {
-            CircuitProtocolError::Teardown { .. } => OnionSocketError::TeardownMessage,
-            CircuitProtocolError::Unknown { .. } => OnionSocketError::BrokenMessage,
+            CircuitProtocolError::Teardown { reason, .. } => {
+                SocketErrorKind::TeardownMessage(reason)
+            }
+            CircuitProtocolError::Unknown { .. } => SocketErrorKind::BrokenMessage,
+            CircuitProtocolError::CellSize { .. } => SocketErrorKind::UnsupportedCellSize,
         }
     }
 }
 
-impl<E: fmt::Debug> From<TunnelProtocolError<E>> for OnionSocketError {
+impl<E: fmt::Debug> From<TunnelProtocolError<E>> for SocketErrorKind {
     fn from(e: TunnelProtocolError<E>) -> Self {
         match e {
-            TunnelProtocolError::Peer(_) => OnionSocketError::Peer,
-            _ => OnionSocketError::BrokenMessage,
+            TunnelProtocolError::Peer(_) => SocketErrorKind::Peer,
+            _ => SocketErrorKind::BrokenMessage,
         }
     }
 }
 
 /// Wraps an underlying network primitive (eg. TCP or TLS stream) and provides methods implementing the protocol.
-/// Utilizes an internal buffer for (de-)serialization.
+/// Utilizes internal buffers for serialization and deserialization.
 ///
 /// The socket layer serves as glue between the onion protocol and higher layers.
+///
+/// Reading is cancellation safe: if a read future is dropped before completion, e.g. because
+/// another branch of a `tokio::select!` won, the partially read message is kept in `read_buf` and
+/// completed by the next read. Writes use a separate buffer and do not disturb a pending read.
 pub(crate) struct OnionSocket<S> {
     stream: S,
     buf: BytesMut,
+    read_buf: BytesMut,
+    /// number of bytes of the message in `read_buf` which have been received so far
+    read_len: usize,
+    cell_size: CellSize,
+    /// direction of the tunnel messages written to this socket, `Backward` if the peer initiated
+    /// the circuit
+    direction: Direction,
+    /// circuits open on this connection, messages for other circuits are rejected
+    circuit_ids: CircuitIds,
+    /// the connection reported in errors of this socket
+    connection: ConnectionInfo,
+    timeouts: SocketTimeouts,
+    _tracked: Tracked,
 }
 
 impl<S> OnionSocket<S> {
+    /// Creates a socket on a stream of unknown addresses, see
+    /// [`from_stream`](OnionSocket::from_stream) for sockets on a `CircuitStream`.
+    #[cfg(test)]
     pub(crate) fn new(stream: S) -> Self {
+        Self::with_connection(stream, ConnectionInfo::new(None, None))
+    }
+
+    fn with_connection(stream: S, connection: ConnectionInfo) -> Self {
         OnionSocket {
             stream,
             buf: BytesMut::with_capacity(MESSAGE_SIZE),
+            read_buf: BytesMut::with_capacity(MESSAGE_SIZE),
+            read_len: 0,
+            cell_size: CellSize::default(),
+            direction: Direction::Forward,
+            circuit_ids: CircuitIds::default(),
+            connection,
+            timeouts: Default::default(),
+            _tracked: Tracked::new("socket_buffer"),
         }
     }
+
+    /// Draws the ids of the circuits initiated on this socket from `rng`.
+    pub(crate) fn with_rng(mut self, rng: Box<dyn OnionRng>) -> Self {
+        self.circuit_ids = CircuitIds::new(rng);
+        self
+    }
+
+    /// Applies `timeouts` to the reads awaiting a reply and to all writes on this socket.
+    pub(crate) fn with_timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Returns the timeouts applied to the operations on this socket.
+    pub(crate) fn timeouts(&self) -> SocketTimeouts {
+        self.timeouts
+    }
+
+    /// Returns the connection underlying this socket.
+    pub(crate) fn connection(&self) -> ConnectionInfo {
+        self.connection
+    }
+
+    fn error(&self, kind: impl Into<SocketErrorKind>) -> OnionSocketError {
+        OnionSocketError::new(kind, self.connection)
+    }
+
+    /// Returns the cell size negotiated during the circuit handshake on this socket.
+    pub(crate) fn cell_size(&self) -> CellSize {
+        self.cell_size
+    }
+
+    /// Marks the circuit with the id chosen by the peer as open on this connection.
+    /// Returns `false` if a circuit with this id is already open.
+    pub(crate) fn open_circuit(&mut self, circuit_id: CircuitId) -> bool {
+        self.circuit_ids.insert(circuit_id)
+    }
 }
 
 impl<S: AsyncRead + Unpin> OnionSocket<S> {
-    async fn read_buf_from_stream(&mut self) -> SocketResult<usize> {
-        Ok(timeout(READ_TIMEOUT, self.stream.read_exact(&mut self.buf)).await??)
+    /// Reads a message of `size` bytes into `read_buf`.
+    ///
+    /// This method is cancellation safe, a partially read message is completed by the next call.
+    async fn read_message(&mut self, size: usize) -> SocketResult<()> {
+        if self.read_len == 0 {
+            self.read_buf.clear();
+            self.read_buf.resize(size, 0);
+        }
+        debug_assert_eq!(self.read_buf.len(), size);
+        while self.read_len < size {
+            let n = self
+                .stream
+                .read(&mut self.read_buf[self.read_len..])
+                .await
+                .map_err(|e| self.error(e))?;
+            if n == 0 {
+                return Err(self.error(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+            self.read_len += n;
+        }
+        self.read_len = 0;
+        Ok(())
     }
 
-    /// Listends for incoming `CIRCUIT CREATE` messages and returns the circuit id and key in this
-    /// message.
-    ///
-    /// # Errors:
-    /// - `StreamTerminated` - The stream is broken
-    /// - `StreamTimeout` -  The stream operations timed out
-    /// - `TeardownMessage` - A `TEARDOWN` message has been received instead of `CIRCUIT CREATE`
-    /// - `BrokenMessage` - The received answer message could not be parsed
-    pub(crate) async fn accept_handshake(&mut self) -> SocketResult<(CircuitId, Key)> {
-        self.buf.resize(MESSAGE_SIZE, 0);
-        self.read_buf_from_stream().await?;
-        let msg = CircuitCreate::try_read_from(&mut self.buf)?;
-        Ok((msg.circuit_id, msg.key))
+    async fn read_buf_from_stream(&mut self, size: usize) -> SocketResult<()> {
+        timeout(self.timeouts.read, self.read_message(size))
+            .await
+            .map_err(|e| self.error(e))?
     }
 
     /// Tries to read an entire onion protocol message before returning. This function does not
     /// apply a timeout on stream listening, so expect this function to deadlock if the stream is
     /// idle, but kept alive.
     ///
+    /// This function is cancellation safe and may be used in `tokio::select!`.
+    ///
     /// Returns a `CIRCUIT OPAQUE` message if the received message could successfully be parsed. If
     /// not, an error will be returned.
     ///
@@ -120,21 +239,30 @@ impl<S: AsyncRead + Unpin> OnionSocket<S> {
     /// - `StreamTerminated` - The stream is broken
     /// - `TeardownMessage` - A `TEARDOWN` message has been received instead of `CIRCUIT OPAQUE`
     /// - `BrokenMessage` - The received answer message could not be parsed
+    /// - `UnknownCircuit` - The message references a circuit which is not open on this socket
     pub(crate) async fn accept_opaque(
         &mut self,
     ) -> SocketResult<CircuitOpaque<CircuitOpaqueBytes>> {
-        self.buf.resize(MESSAGE_SIZE, 0);
         // NOTE: no timeout applied here, parent is supposed to handle that
-        self.stream.read_exact(&mut self.buf).await?;
+        self.read_message(self.cell_size.bytes()).await?;
         //.context("Error while reading CircuitOpaque")?;
-        let msg = CircuitOpaque::try_read_from(&mut self.buf)?;
+        let msg = CircuitOpaque::try_read_from(&mut self.read_buf).map_err(|e| self.error(e))?;
+        if !self.circuit_ids.contains(msg.circuit_id) {
+            return Err(self.error(SocketErrorKind::UnknownCircuit(msg.circuit_id)));
+        }
         Ok(msg)
     }
 }
 
 impl<S: AsyncWrite + Unpin> OnionSocket<S> {
     async fn write_buf_to_stream(&mut self) -> SocketResult<()> {
-        Ok(timeout(WRITE_TIMEOUT, self.stream.write_all(self.buf.as_ref())).await??)
+        timeout(
+            self.timeouts.write,
+            self.stream.write_all(self.buf.as_ref()),
+        )
+        .await
+        .map_err(|e| self.error(e))?
+        .map_err(|e| self.error(e))
     }
 
     async fn encrypt_and_send_opaque<K: ToBytes>(
@@ -148,11 +276,13 @@ impl<S: AsyncWrite + Unpin> OnionSocket<S> {
             payload: CircuitOpaquePayload {
                 msg: &tunnel_res,
                 encrypt_keys: session_keys,
+                cell_size: self.cell_size,
+                direction: self.direction,
             },
         };
 
         req.write_to(&mut self.buf);
-        assert_eq!(self.buf.len(), MESSAGE_SIZE);
+        assert_eq!(self.buf.len(), self.cell_size.bytes());
         // TODO omit timeout here?
         self.write_buf_to_stream().await
     }
@@ -166,10 +296,14 @@ impl<S: AsyncWrite + Unpin> OnionSocket<S> {
     pub(crate) async fn finalize_handshake(
         &mut self,
         circuit_id: CircuitId,
-        key: SignKey<'_>,
+        key: SignedKey,
     ) -> SocketResult<()> {
         self.buf.clear();
-        let res = CircuitCreated { circuit_id, key };
+        let res = CircuitCreated {
+            circuit_id,
+            cell_size: self.cell_size,
+            key,
+        };
         res.write_padded_to(&mut self.buf, MESSAGE_SIZE);
         self.write_buf_to_stream().await?;
         Ok(())
@@ -262,22 +396,28 @@ impl<S: AsyncWrite + Unpin> OnionSocket<S> {
             payload,
         };
 
-        msg.write_padded_to(&mut self.buf, MESSAGE_SIZE);
+        msg.write_padded_to(&mut self.buf, self.cell_size.bytes());
         // FIXME Do we want to apply the timeout here? Generally: no, but what do we do instead?
         self.write_buf_to_stream().await?;
         //.context("Error while writing CircuitOpaque")?;
         Ok(())
     }
 
-    /// Sends a `TEARDOWN` message via the stream.
+    /// Sends a `TEARDOWN` message with the given `reason` via the stream.
     ///
     /// # Errors:
     /// - `StreamTerminated` - The stream is broken
     /// - `StreamTimeout` -  The stream operations timed out
-    pub(crate) async fn teardown(&mut self, circuit_id: CircuitId) -> SocketResult<()> {
+    pub(crate) async fn teardown(
+        &mut self,
+        circuit_id: CircuitId,
+        reason: EndReason,
+    ) -> SocketResult<()> {
+        // the circuit is closed even if the peer does not receive the teardown
+        self.circuit_ids.release(circuit_id);
         self.buf.clear();
-        let res = CircuitTeardown { circuit_id };
-        res.write_padded_to(&mut self.buf, MESSAGE_SIZE);
+        let res = CircuitTeardown { circuit_id, reason };
+        res.write_padded_to(&mut self.buf, self.cell_size.bytes());
         // NOTE: A timeout needs to be applied here
         self.write_buf_to_stream().await?;
         Ok(())
@@ -304,22 +444,53 @@ impl<S: AsyncWrite + Unpin> OnionSocket<S> {
             .await
     }
 
-    pub(crate) async fn send_data(
+    /// Sends a `TUNNEL TERMINATE` message via this stream with the given `tunnel_id`, which makes
+    /// the final hop on this socket the endpoint of the tunnel. This function does not block for
+    /// responses, a hop refusing the role tears the circuit down.
+    pub(crate) async fn terminate(
         &mut self,
         circuit_id: CircuitId,
         tunnel_id: TunnelId,
-        data: Bytes,
         session_keys: &[SessionKey],
     ) -> SocketResult<()> {
         self.buf.clear();
-        let tunnel_req = TunnelRequest::Data(tunnel_id, data);
+        let tunnel_req = TunnelRequest::Terminate(tunnel_id);
         self.encrypt_and_send_opaque(circuit_id, session_keys, tunnel_req)
             .await
     }
 
-    /// Sends a `TUNNEL END` message via this stream with the given `tunnel_id` to indicate a
-    /// conversation end to the final hop on this socket. This function does not block for
-    /// responses.
+    /// Sends a `TUNNEL DATA` message for each of the given `data` parts.
+    ///
+    /// The messages are coalesced into a single write on the stream, so at most `MAX_BATCH_SIZE`
+    /// parts should be passed at once.
+    pub(crate) async fn send_data<I: IntoIterator<Item = Bytes>>(
+        &mut self,
+        circuit_id: CircuitId,
+        tunnel_id: TunnelId,
+        data: I,
+        session_keys: &[SessionKey],
+    ) -> SocketResult<()> {
+        self.buf.clear();
+        for data in data {
+            let tunnel_req = TunnelRequest::Data(tunnel_id, data);
+            let req = CircuitOpaque {
+                circuit_id,
+                payload: CircuitOpaquePayload {
+                    msg: &tunnel_req,
+                    encrypt_keys: session_keys,
+                    cell_size: self.cell_size,
+                    direction: self.direction,
+                },
+            };
+            req.write_to(&mut self.buf);
+        }
+        debug_assert_eq!(self.buf.len() % self.cell_size.bytes(), 0);
+        self.write_buf_to_stream().await
+    }
+
+    /// Sends a `TUNNEL END` message via this stream with the given `tunnel_id` and `reason` to
+    /// indicate a conversation end to the final hop on this socket. This function does not block
+    /// for responses.
     ///
     /// If the targeted hop is not the final hop or forbids the connection, it may send a `TEARDOWN`
     /// message that will not be read here, but listening on the connection for `TUNNEL DATA`
@@ -328,10 +499,11 @@ impl<S: AsyncWrite + Unpin> OnionSocket<S> {
         &mut self,
         circuit_id: CircuitId,
         tunnel_id: TunnelId,
+        reason: EndReason,
         session_keys: &[SessionKey],
     ) -> SocketResult<()> {
         self.buf.clear();
-        let tunnel_res = TunnelRequest::End(tunnel_id);
+        let tunnel_res = TunnelRequest::End(tunnel_id, reason);
         self.encrypt_and_send_opaque(circuit_id, session_keys, tunnel_res)
             .await
     }
@@ -346,13 +518,115 @@ impl<S: AsyncWrite + Unpin> OnionSocket<S> {
         self.encrypt_and_send_opaque(circuit_id, session_keys, tunnel_req)
             .await
     }
+
+    /// Sends a `TUNNEL PADDING` message carrying `len` bytes of padding, which is indistinguishable
+    /// from a `TUNNEL DATA` message to every hop but the final one.
+    pub(crate) async fn send_padding(
+        &mut self,
+        circuit_id: CircuitId,
+        len: usize,
+        session_keys: &[SessionKey],
+    ) -> SocketResult<()> {
+        self.buf.clear();
+        let tunnel_req = TunnelRequest::Padding(len);
+        self.encrypt_and_send_opaque(circuit_id, session_keys, tunnel_req)
+            .await
+    }
+
+    /// Sends a `TUNNEL ECHO` message with the given `nonce` to the hop owning the last of the
+    /// `session_keys`. This function does not block for the `TUNNEL ECHOED` response, which has to
+    /// be read from the incoming messages.
+    pub(crate) async fn send_echo(
+        &mut self,
+        circuit_id: CircuitId,
+        nonce: u32,
+        session_keys: &[SessionKey],
+    ) -> SocketResult<()> {
+        self.buf.clear();
+        let tunnel_req = TunnelRequest::Echo(nonce);
+        self.encrypt_and_send_opaque(circuit_id, session_keys, tunnel_req)
+            .await
+    }
+
+    /// Replies on this `OnionSocket` with a `TUNNEL ECHOED` message to a `TUNNEL ECHO` call.
+    ///
+    /// # Errors:
+    /// - `StreamTerminated` - The stream is broken
+    /// - `StreamTimeout` -  The stream operations timed out
+    pub(crate) async fn reply_echo(
+        &mut self,
+        circuit_id: CircuitId,
+        nonce: u32,
+        session_keys: &[SessionKey],
+    ) -> SocketResult<()> {
+        self.buf.clear();
+        let tunnel_res = TunnelResponseEchoed(nonce);
+        self.encrypt_and_send_opaque(circuit_id, session_keys, tunnel_res)
+            .await
+    }
 }
 
 impl<S: AsyncWrite + AsyncRead + Unpin> OnionSocket<S> {
+    /// Listends for incoming `CIRCUIT CREATE` messages and returns the circuit id and key in this
+    /// message. The cell size requested by the peer is used for all subsequent messages.
+    ///
+    /// The strongest of the cipher suites offered by the peer which is contained in `supported`
+    /// is selected. If both sides support feature flags, the feature offer of the peer is answered
+    /// as well. A selection missing required features is returned like any other, the caller
+    /// answers it before rejecting the circuit, so the peer learns which features are missing.
+    ///
+    /// # Errors:
+    /// - `StreamTerminated` - The stream is broken
+    /// - `StreamTimeout` -  The stream operations timed out
+    /// - `TeardownMessage` - A `TEARDOWN` message has been received instead of `CIRCUIT CREATE`
+    /// - `BrokenMessage` - The received answer message could not be parsed
+    /// - `UnsupportedCellSize` - The requested cell size is unknown, the circuit has been torn down
+    /// - `NoCommonCipherSuite` - No offered suite is supported, the circuit has been torn down
+    /// - `UnknownCircuit` - The requested circuit id is already in use on this socket
+    pub(crate) async fn accept_handshake(
+        &mut self,
+        supported: CipherSuites,
+    ) -> SocketResult<(CircuitId, Key, SuiteSelection)> {
+        self.read_buf_from_stream(MESSAGE_SIZE).await?;
+        match CircuitCreate::try_read_from(&mut self.read_buf) {
+            Ok(msg) if self.circuit_ids.contains(msg.circuit_id) => {
+                // do not tear down, the peer would close the open circuit with this id
+                Err(self.error(SocketErrorKind::UnknownCircuit(msg.circuit_id)))
+            }
+            Ok(msg) => match SuiteSelection::negotiate_features(
+                msg.cipher_suites,
+                // only answered if the suites announce feature flags, which is when it was read
+                msg.features.unwrap_or_default(),
+                supported,
+            ) {
+                Some(suites) => {
+                    self.open_circuit(msg.circuit_id);
+                    self.cell_size = msg.cell_size;
+                    self.direction = Direction::Backward;
+                    Ok((msg.circuit_id, msg.key, suites))
+                }
+                None => {
+                    self.teardown(msg.circuit_id, EndReason::ProtocolError)
+                        .await?;
+                    Err(self.error(SocketErrorKind::NoCommonCipherSuite))
+                }
+            },
+            Err(CircuitProtocolError::CellSize { circuit_id, .. }) => {
+                // reject explicitly, so the initiator does not wait for a CIRCUIT CREATED
+                self.teardown(circuit_id, EndReason::ProtocolError).await?;
+                Err(self.error(SocketErrorKind::UnsupportedCellSize))
+            }
+            Err(e) => Err(self.error(e)),
+        }
+    }
+
     /// Performs a circuit handshake with the peer connected to this socket.
-    /// The `CIRCUIT CREATE` message is sent with the given `key` and sent to the peer. Then, this
-    /// method tries to receive a `CIRCUIT CREATED` message from the peer. If parsed correctly, the
-    /// received peer's key is returned.
+    /// A circuit id which is not in use on this socket is allocated for the new circuit.
+    /// The `CIRCUIT CREATE` message is sent with the given `key`, `cipher_suites` and `features`,
+    /// which have to be present iff the suites announce feature flags, and sent to the peer. Then,
+    /// this method tries to receive a `CIRCUIT CREATED` message from the peer. If parsed
+    /// correctly, the circuit id and the received peer's key are returned. The cipher suite
+    /// selected by the peer is checked when verifying the key.
     ///
     /// # Errors:
     /// - `StreamTerminated` - The stream is broken
@@ -360,23 +634,86 @@ impl<S: AsyncWrite + AsyncRead + Unpin> OnionSocket<S> {
     /// - `TeardownMessage` - A `TEARDOWN`message has been received instead of `CIRCUIT CREATED`
     /// - `BrokenMessage` - The received answer message could not be parsed or has an unexpected
     ///   circuit_id
+    /// - `UnsupportedCellSize` - The peer did not confirm the requested `cell_size`
+    /// - `CircuitIdsExhausted` - All circuit ids are in use on this socket
     pub(crate) async fn initiate_handshake(
+        &mut self,
+        key: Key,
+        cell_size: CellSize,
+        cipher_suites: CipherSuites,
+        features: Option<FeatureOffer>,
+    ) -> SocketResult<(CircuitId, VerifyKey)> {
+        let circuit_id = self
+            .circuit_ids
+            .allocate()
+            .ok_or_else(|| self.error(SocketErrorKind::CircuitIdsExhausted))?;
+        match self
+            .create_circuit(circuit_id, key, cell_size, cipher_suites, features)
+            .await
+        {
+            Ok(peer_key) => Ok((circuit_id, peer_key)),
+            Err(e) => {
+                self.circuit_ids.release(circuit_id);
+                Err(e)
+            }
+        }
+    }
+
+    /// Like [`initiate_handshake`](Self::initiate_handshake), but for a circuit whose id has been
+    /// allocated by the caller, e.g. on a connection shared with other circuits.
+    ///
+    /// # Errors:
+    /// Same as [`initiate_handshake`](Self::initiate_handshake), but `UnknownCircuit` instead of
+    /// `CircuitIdsExhausted` if `circuit_id` is already in use on this socket.
+    pub(crate) async fn initiate_handshake_with_id(
         &mut self,
         circuit_id: CircuitId,
         key: Key,
+        cell_size: CellSize,
+        cipher_suites: CipherSuites,
+        features: Option<FeatureOffer>,
+    ) -> SocketResult<VerifyKey> {
+        if !self.open_circuit(circuit_id) {
+            return Err(self.error(SocketErrorKind::UnknownCircuit(circuit_id)));
+        }
+        let res = self
+            .create_circuit(circuit_id, key, cell_size, cipher_suites, features)
+            .await;
+        if res.is_err() {
+            self.circuit_ids.release(circuit_id);
+        }
+        res
+    }
+
+    async fn create_circuit(
+        &mut self,
+        circuit_id: CircuitId,
+        key: Key,
+        cell_size: CellSize,
+        cipher_suites: CipherSuites,
+        features: Option<FeatureOffer>,
     ) -> SocketResult<VerifyKey> {
         self.buf.clear();
-        let req = CircuitCreate { circuit_id, key };
+        let req = CircuitCreate {
+            circuit_id,
+            cell_size,
+            key,
+            cipher_suites,
+            features,
+        };
 
         req.write_padded_to(&mut self.buf, MESSAGE_SIZE);
         self.write_buf_to_stream().await?;
 
-        self.read_buf_from_stream().await?;
-        let res = CircuitCreated::try_read_from(&mut self.buf)?;
-        if res.circuit_id == circuit_id {
-            Ok(res.key)
+        self.read_buf_from_stream(MESSAGE_SIZE).await?;
+        let res = CircuitCreated::try_read_from(&mut self.read_buf).map_err(|e| self.error(e))?;
+        if res.circuit_id != circuit_id {
+            Err(self.error(SocketErrorKind::BrokenMessage))
+        } else if res.cell_size != cell_size {
+            Err(self.error(SocketErrorKind::UnsupportedCellSize))
         } else {
-            Err(OnionSocketError::BrokenMessage)
+            self.cell_size = cell_size;
+            Ok(res.key)
         }
     }
 
@@ -393,43 +730,59 @@ impl<S: AsyncWrite + AsyncRead + Unpin> OnionSocket<S> {
     /// - `StreamTerminated` - The stream is broken
     /// - `StreamTimeout` -  The stream operations timed out
     /// - `TeardownMessage` - A `TEARDOWN`message has been received instead of `CIRCUIT OPAQUE`
-    /// - `BrokenMessage` - The received answer message could not be parsed
+    /// - `BrokenMessage` - The received answer message could not be parsed or is out of sequence
     pub(crate) async fn initiate_tunnel_handshake(
         &mut self,
         circuit_id: CircuitId,
         peer_addr: SocketAddr,
         key: Key,
+        cipher_suites: CipherSuites,
+        features: Option<FeatureOffer>,
         session_keys: &[SessionKey],
     ) -> SocketResult<VerifyKey> {
         self.buf.clear();
-        let tunnel_req = TunnelRequest::Extend(peer_addr, key);
+        let tunnel_req = TunnelRequest::Extend(peer_addr, key, cipher_suites, features);
         let req = CircuitOpaque {
             circuit_id,
             payload: CircuitOpaquePayload {
                 msg: &tunnel_req,
                 encrypt_keys: session_keys,
+                cell_size: self.cell_size,
+                direction: self.direction,
             },
         };
 
         req.write_to(&mut self.buf);
-        assert_eq!(self.buf.len(), MESSAGE_SIZE);
+        assert_eq!(self.buf.len(), self.cell_size.bytes());
         // TODO Fix timeout
         self.write_buf_to_stream().await?;
 
-        self.read_buf_from_stream().await?;
-        let mut res = CircuitOpaque::try_read_from(&mut self.buf)?;
+        self.read_buf_from_stream(self.cell_size.bytes()).await?;
+        let mut res =
+            CircuitOpaque::try_read_from(&mut self.read_buf).map_err(|e| self.error(e))?;
 
         if res.circuit_id != circuit_id {
-            return Err(OnionSocketError::BrokenMessage);
+            return Err(self.error(SocketErrorKind::BrokenMessage));
             //return Err(anyhow!(
             //    "Circuit ID in Opaque response does not match ID in request"
             //));
         }
 
-        res.decrypt(session_keys.iter().rev())
-            .map_err(|_| OnionSocketError::BrokenMessage)?;
-        let tunnel_res = TunnelResponseExtended::read_with_digest_from(&mut res.payload.bytes)?;
+        res.decrypt(Direction::Backward, session_keys.iter().rev())
+            .map_err(|_| self.error(SocketErrorKind::BrokenMessage))?;
+        let verifier = HopVerifier::new(&session_keys[0], Direction::Backward);
+        let tunnel_res =
+            TunnelResponseExtended::read_with_digest_from(&mut res.payload.bytes, &verifier)
+                .map_err(|e| match e {
+                    TunnelProtocolError::Peer(TunnelExtendedError::PolicyRefused) => {
+                        self.error(SocketErrorKind::PolicyRefused)
+                    }
+                    e => self.error(e),
+                })?;
         //.context("Invalid TunnelResponse message")?;
+        if !res.is_in_sequence(&session_keys[0], Direction::Backward) {
+            return Err(self.error(SocketErrorKind::BrokenMessage));
+        }
 
         Ok(tunnel_res.peer_key)
     }
@@ -445,7 +798,7 @@ impl<S: AsyncWrite + AsyncRead + Unpin> OnionSocket<S> {
     /// - `StreamTerminated` - The stream is broken
     /// - `StreamTimeout` -  The stream operations timed out
     /// - `TeardownMessage` - A `TEARDOWN`message has been received instead of `CIRCUIT OPAQUE`
-    /// - `BrokenMessage` - The received answer message could not be parsed
+    /// - `BrokenMessage` - The received answer message could not be parsed or is out of sequence
     pub(crate) async fn truncate_tunnel(
         &mut self,
         circuit_id: CircuitId,
@@ -458,34 +811,48 @@ impl<S: AsyncWrite + AsyncRead + Unpin> OnionSocket<S> {
             payload: CircuitOpaquePayload {
                 msg: &tunnel_req,
                 encrypt_keys: session_keys,
+                cell_size: self.cell_size,
+                direction: self.direction,
             },
         };
 
         req.write_to(&mut self.buf);
-        assert_eq!(self.buf.len(), MESSAGE_SIZE);
+        assert_eq!(self.buf.len(), self.cell_size.bytes());
         // TODO Fix timeout
         self.write_buf_to_stream().await?;
 
-        self.read_buf_from_stream().await?;
-        let mut res = CircuitOpaque::try_read_from(&mut self.buf)?;
+        self.read_buf_from_stream(self.cell_size.bytes()).await?;
+        let mut res =
+            CircuitOpaque::try_read_from(&mut self.read_buf).map_err(|e| self.error(e))?;
 
         if res.circuit_id != circuit_id {
-            return Err(OnionSocketError::BrokenMessage);
+            return Err(self.error(SocketErrorKind::BrokenMessage));
             //return Err(anyhow!(
             //    "Circuit ID in Opaque response does not match ID in request"
             //));
         }
 
-        res.decrypt(session_keys.iter().rev())
-            .map_err(|_| OnionSocketError::BrokenMessage)?;
-        let _tunnel_res = TunnelResponseTruncated::read_with_digest_from(&mut res.payload.bytes)?;
+        res.decrypt(Direction::Backward, session_keys.iter().rev())
+            .map_err(|_| self.error(SocketErrorKind::BrokenMessage))?;
+        let verifier = HopVerifier::new(&session_keys[0], Direction::Backward);
+        let _tunnel_res =
+            TunnelResponseTruncated::read_with_digest_from(&mut res.payload.bytes, &verifier)
+                .map_err(|e| self.error(e))?;
         //.context("Invalid TunnelResponse message")?;
+        if !res.is_in_sequence(&session_keys[0], Direction::Backward) {
+            return Err(self.error(SocketErrorKind::BrokenMessage));
+        }
 
         Ok(())
     }
 }
 
-impl OnionSocket<TcpStream> {
+impl OnionSocket<CircuitStream> {
+    pub(crate) fn from_stream(stream: CircuitStream) -> Self {
+        let connection = stream.connection();
+        Self::with_connection(stream, connection)
+    }
+
     pub(crate) fn peer_addr(&self) -> Result<SocketAddr> {
         Ok(self.stream.peer_addr()?)
     }
@@ -495,7 +862,9 @@ impl<S: fmt::Debug> fmt::Debug for OnionSocket<S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("OnionSocket")
             .field("stream", &self.stream)
+            .field("connection", &self.connection)
             .field("buf_len", &self.buf.len())
+            .field("read_len", &self.read_len)
             .finish()
     }
 }
@@ -0,0 +1,163 @@
+//! Validation of the configuration of an onion router before it is started, see
+//! [`OnionBuilder::start`](crate::OnionBuilder::start).
+//!
+//! Once started, the onion router runs in background tasks, which can only report problems by
+//! logging them. Everything which would make these tasks fail is therefore checked up front and
+//! reported at once, so a misconfigured peer can be fixed in a single attempt.
+
+use crate::onion::crypto::HostKey;
+use crate::onion::protocol::SIGNATURE_LEN;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use thiserror::Error;
+use tokio::runtime::Handle;
+
+const SELF_TEST_DATA: &[u8] = b"onion router host key self test";
+
+/// A single problem preventing an onion router from starting.
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum StartProblem {
+    #[error("the host key has {bits} bits, but a {required} bit RSA key is required")]
+    HostKeySize { bits: usize, required: usize },
+    #[error("the host key can not be used for signing: {0}")]
+    HostKeyUnusable(anyhow::Error),
+    #[error("the listen address {addr} is already in use by another process")]
+    AddressInUse { addr: SocketAddr },
+    #[error(
+        "no permission to listen on {addr}, ports below 1024 usually require elevated privileges"
+    )]
+    PermissionDenied { addr: SocketAddr },
+    #[error("could not listen on {addr}: {source}")]
+    Listen { addr: SocketAddr, source: io::Error },
+    #[error("no tokio runtime is running, the onion router has to be started from within one")]
+    NoRuntime,
+    #[error("invalid {setting}: {reason}")]
+    InvalidSetting {
+        setting: &'static str,
+        reason: &'static str,
+    },
+}
+
+/// Returned by [`OnionBuilder::start`](crate::OnionBuilder::start) if the onion router can not be
+/// started. Contains every problem found, not only the first one.
+#[derive(Error, Debug)]
+pub struct StartError {
+    problems: Vec<StartProblem>,
+}
+
+impl StartError {
+    /// Returns the problems preventing the onion router from starting.
+    pub fn problems(&self) -> &[StartProblem] {
+        &self.problems
+    }
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the onion router could not be started")?;
+        for (i, problem) in self.problems.iter().enumerate() {
+            let sep = if i == 0 { ": " } else { "; " };
+            write!(f, "{}{}", sep, problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the problems found while starting an onion router.
+#[derive(Debug, Default)]
+pub(crate) struct StartCheck {
+    problems: Vec<StartProblem>,
+}
+
+impl StartCheck {
+    /// Checks that a RSA host key has the size expected by the handshake and that the host key is
+    /// able to produce signatures which verify with its public key.
+    pub(crate) fn hostkey(&mut self, hostkey: &HostKey) {
+        if let HostKey::Rsa(key) = hostkey {
+            if key.size() != SIGNATURE_LEN {
+                self.problems.push(StartProblem::HostKeySize {
+                    bits: key.size() * 8,
+                    required: SIGNATURE_LEN * 8,
+                });
+                return;
+            }
+        }
+        let mut signature = vec![0u8; hostkey.signature_len()];
+        let res = hostkey
+            .sign(SELF_TEST_DATA, &mut signature)
+            .and_then(|_| hostkey.public_key().verify(SELF_TEST_DATA, &signature));
+        if let Err(e) = res {
+            self.problems.push(StartProblem::HostKeyUnusable(e));
+        }
+    }
+
+    /// Binds the listening sockets, so they are known to work before any task is spawned.
+    ///
+    /// The unspecified IPv6 address `::` is meant to accept IPv4 connections as well. Where the
+    /// system makes IPv6 sockets IPv6-only, e.g. on Windows, an IPv4 socket is bound to the same
+    /// port in addition.
+    pub(crate) fn bind(&mut self, addr: SocketAddr) -> Option<(Vec<TcpListener>, SocketAddr)> {
+        let res = bind_nonblocking(addr).and_then(|listener| {
+            // differs from `addr` if port 0 was requested
+            let local_addr = listener.local_addr()?;
+            let mut listeners = vec![listener];
+            if addr.ip().is_unspecified() && addr.is_ipv6() {
+                let ipv4_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, local_addr.port()));
+                match bind_nonblocking(ipv4_addr) {
+                    Ok(listener) => listeners.push(listener),
+                    // taken by the dual-stack socket itself
+                    Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok((listeners, local_addr))
+        });
+        match res {
+            Ok(bound) => Some(bound),
+            Err(e) => {
+                self.problems.push(match e.kind() {
+                    io::ErrorKind::AddrInUse => StartProblem::AddressInUse { addr },
+                    io::ErrorKind::PermissionDenied => StartProblem::PermissionDenied { addr },
+                    _ => StartProblem::Listen { addr, source: e },
+                });
+                None
+            }
+        }
+    }
+
+    /// Returns the runtime the background tasks of the onion router are spawned on.
+    pub(crate) fn runtime(&mut self) -> Option<Handle> {
+        let runtime = Handle::try_current().ok();
+        if runtime.is_none() {
+            self.problems.push(StartProblem::NoRuntime);
+        }
+        runtime
+    }
+
+    /// Records an invalid setting unless `valid` holds.
+    pub(crate) fn setting(&mut self, valid: bool, setting: &'static str, reason: &'static str) {
+        if !valid {
+            self.problems
+                .push(StartProblem::InvalidSetting { setting, reason });
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<(), StartError> {
+        if self.problems.is_empty() {
+            Ok(())
+        } else {
+            Err(StartError {
+                problems: self.problems,
+            })
+        }
+    }
+}
+
+fn bind_nonblocking(addr: SocketAddr) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr)?;
+    // required by `tokio::net::TcpListener::from_std`
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
@@ -0,0 +1,135 @@
+//! State of an onion router which is kept across restarts, see [`NodeState`].
+//!
+//! Suspected hops and the backoff of failed destinations only protect against peers which keep
+//! failing if they are not forgotten whenever the process restarts. Points in time are stored as
+//! wall-clock time, so the time the process was down counts towards their expiry.
+
+use crate::onion::diagnosis::SuspectedPeers;
+use crate::onion::retry::DestinationRetries;
+use crate::Fingerprint;
+use std::time::SystemTime;
+use tokio::time::Instant;
+
+/// The state an onion router learned about other peers, exported with
+/// [`OnionContext::export_state`](crate::OnionContext::export_state) and restored with
+/// [`OnionBuilder::import_state`](crate::OnionBuilder::import_state).
+///
+/// Keys and other secrets are never part of the state. With the `serde` feature enabled, the state
+/// implements `Serialize` and `Deserialize`. Unknown fields are ignored and missing fields are
+/// empty, so states written by other versions of this crate can be read. A state of a newer
+/// format [`version`](NodeState::version) is rejected when the onion router is started.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate", default)
+)]
+#[non_exhaustive]
+pub struct NodeState {
+    /// The version of the format, [`NodeState::VERSION`] for states exported by this version of
+    /// the crate.
+    pub version: u32,
+    /// Peers suspected to have caused a path failure, which are avoided as random hops.
+    pub suspected_peers: Vec<SuspectedPeer>,
+    /// Destinations which failed recently, towards which automatic rebuilds are delayed.
+    pub destination_backoffs: Vec<DestinationBackoff>,
+}
+
+/// A peer suspected to have caused a path failure, see [`NodeState`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub struct SuspectedPeer {
+    pub fingerprint: Fingerprint,
+    /// The end of the suspicion.
+    pub until: SystemTime,
+}
+
+/// The failures of a destination, see [`NodeState`] and [`RetryBackoff`](crate::RetryBackoff).
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde_crate::Serialize, serde_crate::Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[non_exhaustive]
+pub struct DestinationBackoff {
+    pub fingerprint: Fingerprint,
+    /// The number of consecutive failed builds.
+    pub failures: u32,
+    /// The end of the backoff, which may have passed already.
+    pub retry_at: SystemTime,
+}
+
+impl Default for NodeState {
+    fn default() -> Self {
+        NodeState {
+            version: NodeState::VERSION,
+            suspected_peers: vec![],
+            destination_backoffs: vec![],
+        }
+    }
+}
+
+impl NodeState {
+    /// The version of the format written by this version of the crate.
+    pub const VERSION: u32 = 1;
+
+    pub(crate) fn capture(suspects: &SuspectedPeers, retries: &DestinationRetries) -> Self {
+        NodeState {
+            version: NodeState::VERSION,
+            suspected_peers: suspects
+                .entries()
+                .into_iter()
+                .map(|(fingerprint, until)| SuspectedPeer {
+                    fingerprint,
+                    until: to_system_time(until),
+                })
+                .collect(),
+            destination_backoffs: retries
+                .entries()
+                .into_iter()
+                .map(|(fingerprint, failures, retry_at)| DestinationBackoff {
+                    fingerprint,
+                    failures,
+                    retry_at: to_system_time(retry_at),
+                })
+                .collect(),
+        }
+    }
+
+    /// Adds the state to `suspects` and `retries`, skipping entries which expired in the meantime.
+    pub(crate) fn restore(&self, suspects: &SuspectedPeers, retries: &DestinationRetries) {
+        for peer in &self.suspected_peers {
+            if let Some(until) = to_instant(peer.until) {
+                suspects.insert(peer.fingerprint, until);
+            }
+        }
+        for backoff in &self.destination_backoffs {
+            if let Some(retry_at) = to_instant(backoff.retry_at) {
+                retries.insert(backoff.fingerprint, backoff.failures, retry_at);
+            }
+        }
+    }
+}
+
+fn to_system_time(instant: Instant) -> SystemTime {
+    let now = Instant::now();
+    match instant.checked_duration_since(now) {
+        Some(ahead) => SystemTime::now() + ahead,
+        None => SystemTime::now() - now.duration_since(instant),
+    }
+}
+
+/// Returns `None` if `time` lies too far in the past to be represented.
+fn to_instant(time: SystemTime) -> Option<Instant> {
+    let now = Instant::now();
+    match time.duration_since(SystemTime::now()) {
+        Ok(ahead) => Some(now + ahead),
+        Err(e) => now.checked_sub(e.duration()),
+    }
+}
@@ -0,0 +1,619 @@
+//! Statistics and diagnostics of tunnels and relays.
+//!
+//! The types are re-exported at the root of the crate.
+
+use crate::onion::feedback::{FeedbackQueue, MessageHandle, WriteFeedback};
+use crate::onion::latency::Histogram;
+use crate::onion::tunnel::TunnelId;
+use crate::onion::TunnelState;
+use crate::onion::{CellSize, CipherSuite};
+use crate::{Fingerprint, Peer};
+use bytes::Bytes;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::time::{Duration, Instant};
+
+pub use crate::onion::build_info::{BuildInfo, Transport};
+pub use crate::onion::circuit::CircuitParams;
+pub use crate::onion::crypto::HandshakeVersion;
+pub use crate::onion::latency::LatencyHistogram;
+pub use crate::onion::shutdown::ShutdownReport;
+
+/// number of the most recent attempts kept in a [`BuildReport`]
+const BUILD_REPORT_SIZE: usize = 32;
+/// number of leading fingerprint bytes identifying a peer in a [`BuildReport`]
+const FINGERPRINT_PREFIX_LEN: usize = 4;
+
+/// Statistics collected over the lifetime of a [`Tunnel`](crate::Tunnel).
+///
+/// Use [`Tunnel::stats`](crate::Tunnel::stats) to obtain a snapshot.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct TunnelStats {
+    /// The number of switchovers to a freshly built tunnel.
+    pub rotations: u64,
+    /// The number of rotations which reused a part of the previous path, see
+    /// [`RotationStrategy::Splice`](crate::RotationStrategy::Splice).
+    pub spliced_rotations: u64,
+    /// The number of scheduled switchovers which had to be postponed because the current tunnel
+    /// had not yet reached its minimum lifetime.
+    pub deferred_rotations: u64,
+    /// The number of rotations performed ahead of the round because a session key of the path
+    /// reached its usage threshold, see
+    /// [`OnionBuilder::set_key_usage_limits`](crate::OnionBuilder::set_key_usage_limits).
+    pub key_limit_rotations: u64,
+    /// The number of rotations to new circuits through the same peers, which replaced the
+    /// session keys of the path, see
+    /// [`OnionContext::rekey_tunnel`](crate::OnionContext::rekey_tunnel).
+    pub rekeys: u64,
+    /// The number of failed attempts to build a replacement tunnel.
+    pub failed_rebuilds: u64,
+    /// The number of peers rejected by the hop filter while building paths for this tunnel, see
+    /// [`TunnelOptions::set_hop_filter`](crate::TunnelOptions::set_hop_filter).
+    pub rejected_hops: u64,
+    /// The number of data messages sent on this tunnel.
+    pub sent_cells: u64,
+    /// The number of bytes written by the application which have been sent on this tunnel.
+    pub sent_bytes: u64,
+    /// The number of bytes of padding sent on this tunnel, see
+    /// [`OnionBuilder::set_padding_interval`](crate::OnionBuilder::set_padding_interval). Not
+    /// included in `sent_bytes`.
+    pub padding_bytes: u64,
+    /// The number of data messages written to the tunnel which have not been sent yet.
+    pub queued_cells: usize,
+    /// The time since a data message was last sent, if any has been sent.
+    pub since_last_write: Option<Duration>,
+    /// The time for which queued data messages have been waiting without any message being sent,
+    /// or `None` if the queue is empty.
+    pub stalled_for: Option<Duration>,
+    /// The number of panics caught in tasks working on this tunnel.
+    pub panics: u64,
+    /// The round-trip time to the last hop of the current path, measured by the most recently
+    /// answered keep-alive or [`OnionContext::ping_tunnel`](crate::OnionContext::ping_tunnel).
+    /// `None` until an echo has been answered on the current path.
+    pub round_trip_time: Option<Duration>,
+    /// The number of written messages which were not reported because the
+    /// [`WriteFeedback`](crate::WriteFeedback) was full, i.e. the application did not read it.
+    pub dropped_feedback: u64,
+}
+
+/// Counters backing [`TunnelStats`], shared between a [`Tunnel`](crate::Tunnel) and its handler.
+#[derive(Debug, Default)]
+pub(crate) struct TunnelCounters {
+    pub(crate) rotations: AtomicU64,
+    pub(crate) spliced_rotations: AtomicU64,
+    pub(crate) deferred_rotations: AtomicU64,
+    pub(crate) key_limit_rotations: AtomicU64,
+    pub(crate) rekeys: AtomicU64,
+    pub(crate) failed_rebuilds: AtomicU64,
+    pub(crate) rejected_hops: AtomicU64,
+    pub(crate) sent_cells: AtomicU64,
+    pub(crate) sent_bytes: AtomicU64,
+    pub(crate) padding_bytes: AtomicU64,
+    pub(crate) panics: AtomicU64,
+    queue: Mutex<SendQueue>,
+    /// report of the most recent successful build of a path, if enabled
+    build_report: Mutex<Option<BuildReport>>,
+    /// most recent round-trip time to the last hop of the current path
+    round_trip_time: Mutex<Option<Duration>>,
+}
+
+/// Progress of the data messages written to a tunnel.
+#[derive(Debug, Default)]
+struct SendQueue {
+    len: usize,
+    last_write: Option<Instant>,
+    /// point in time since which the queue is non-empty without any message being sent
+    waiting_since: Option<Instant>,
+    /// the queued messages to report once written, if enabled
+    feedback: Option<FeedbackQueue>,
+}
+
+impl TunnelCounters {
+    /// Creates counters which report written messages, see [`TunnelCounters::write_feedback`].
+    pub(crate) fn with_write_feedback() -> Self {
+        let counters = TunnelCounters::default();
+        counters.queue.lock().unwrap().feedback = Some(FeedbackQueue::new());
+        counters
+    }
+
+    pub(crate) fn snapshot(&self) -> TunnelStats {
+        let queue = self.queue.lock().unwrap();
+        TunnelStats {
+            rotations: self.rotations.load(Ordering::Relaxed),
+            spliced_rotations: self.spliced_rotations.load(Ordering::Relaxed),
+            deferred_rotations: self.deferred_rotations.load(Ordering::Relaxed),
+            key_limit_rotations: self.key_limit_rotations.load(Ordering::Relaxed),
+            rekeys: self.rekeys.load(Ordering::Relaxed),
+            failed_rebuilds: self.failed_rebuilds.load(Ordering::Relaxed),
+            rejected_hops: self.rejected_hops.load(Ordering::Relaxed),
+            sent_cells: self.sent_cells.load(Ordering::Relaxed),
+            sent_bytes: self.sent_bytes.load(Ordering::Relaxed),
+            padding_bytes: self.padding_bytes.load(Ordering::Relaxed),
+            queued_cells: queue.len,
+            since_last_write: queue.last_write.map(|t| t.elapsed()),
+            stalled_for: queue.waiting_since.map(|t| t.elapsed()),
+            panics: self.panics.load(Ordering::Relaxed),
+            round_trip_time: *self.round_trip_time.lock().unwrap(),
+            dropped_feedback: queue.feedback.as_ref().map_or(0, FeedbackQueue::dropped),
+        }
+    }
+
+    /// Returns whether written messages are reported, see [`TunnelCounters::write_feedback`].
+    pub(crate) fn has_write_feedback(&self) -> bool {
+        self.queue.lock().unwrap().feedback.is_some()
+    }
+
+    /// Hands out the receiving end of the reports of written messages, only once.
+    pub(crate) fn write_feedback(&self) -> Option<WriteFeedback> {
+        let mut queue = self.queue.lock().unwrap();
+        queue.feedback.as_mut()?.take_receiver()
+    }
+
+    /// Splits `buf` into data messages of at most `part_size` bytes and records each of them being
+    /// queued for sending by `send`.
+    ///
+    /// The queue stays locked meanwhile, so the messages of concurrent writers are recorded in the
+    /// order they are sent. If `tracked` and written messages are reported, returns the handle of
+    /// the message.
+    pub(crate) fn queue_message<E>(
+        &self,
+        mut buf: Bytes,
+        part_size: usize,
+        tracked: bool,
+        mut send: impl FnMut(Bytes) -> Result<(), E>,
+    ) -> Result<Option<MessageHandle>, E> {
+        let mut queue = self.queue.lock().unwrap();
+        let now = Instant::now();
+        let queued_at = std::time::Instant::now();
+        let handle = match &mut queue.feedback {
+            Some(feedback) if tracked => Some(feedback.next_handle()),
+            _ => None,
+        };
+        if let (Some(feedback), Some(handle), true) = (&mut queue.feedback, handle, buf.is_empty())
+        {
+            feedback.report_empty(handle, queued_at);
+        }
+        while !buf.is_empty() {
+            let part = buf.split_to(cmp::min(part_size, buf.len()));
+            if queue.len == 0 {
+                queue.waiting_since = Some(now);
+            }
+            queue.len += 1;
+            if let Some(feedback) = &mut queue.feedback {
+                let last = buf.is_empty();
+                feedback.push(handle.filter(|_| last), queued_at);
+            }
+            send(part)?;
+        }
+        Ok(handle)
+    }
+
+    /// Records `n` queued data messages being sent.
+    pub(crate) fn record_sent(&self, n: usize) {
+        let mut queue = self.queue.lock().unwrap();
+        let now = Instant::now();
+        queue.len = queue.len.saturating_sub(n);
+        queue.last_write = Some(now);
+        queue.waiting_since = if queue.len > 0 { Some(now) } else { None };
+        if let Some(feedback) = &mut queue.feedback {
+            feedback.written(n);
+        }
+    }
+
+    /// Records `n` queued data messages being dropped without being sent.
+    pub(crate) fn record_discarded(&self, n: usize) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.len = queue.len.saturating_sub(n);
+        if queue.len == 0 {
+            queue.waiting_since = None;
+        }
+        if let Some(feedback) = &mut queue.feedback {
+            feedback.discarded(n);
+        }
+    }
+
+    /// Stores the report of the most recent successful build of a path for the tunnel.
+    pub(crate) fn set_build_report(&self, report: BuildReport) {
+        *self.build_report.lock().unwrap() = Some(report);
+    }
+
+    /// Returns the report of the most recent successful build of a path for the tunnel.
+    pub(crate) fn build_report(&self) -> Option<BuildReport> {
+        self.build_report.lock().unwrap().clone()
+    }
+
+    /// Records the round-trip time to the last hop of the current path, `None` once the tunnel
+    /// moved to a path which has not answered yet.
+    pub(crate) fn set_round_trip_time(&self, rtt: Option<Duration>) {
+        *self.round_trip_time.lock().unwrap() = rtt;
+    }
+
+    /// Returns the time for which queued data has been waiting to be sent.
+    pub(crate) fn stalled_for(&self) -> Option<Duration> {
+        let queue = self.queue.lock().unwrap();
+        queue.waiting_since.map(|t| t.elapsed())
+    }
+}
+
+/// Information about an incoming tunnel, as seen by its endpoint.
+///
+/// The initiator of the tunnel remains anonymous, only the adjacent relay is known.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct IncomingTunnelInfo {
+    /// The address of the peer which connected the last circuit of the tunnel to us.
+    pub adjacent_peer: SocketAddr,
+    /// The cell size negotiated with the adjacent peer.
+    pub cell_size: CellSize,
+    /// The cipher suite negotiated with the initiator of the tunnel.
+    pub cipher_suite: CipherSuite,
+    /// The time at which the `TUNNEL BEGIN` message arrived.
+    pub began_at: SystemTime,
+}
+
+/// The most recent attempts to add a hop during a build of a tunnel path.
+///
+/// Only recorded if enabled with
+/// [`OnionBuilder::enable_build_reports`](crate::OnionBuilder::enable_build_reports). The report of
+/// the last successful build is returned by
+/// [`Tunnel::last_build_report`](crate::Tunnel::last_build_report), while the report of a failed
+/// build is attached to the returned error and can be retrieved using
+/// `downcast_ref::<BuildReport>()`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BuildReport {
+    attempts: Vec<BuildAttempt>,
+}
+
+impl BuildReport {
+    /// Returns the recorded attempts, oldest first.
+    pub fn attempts(&self) -> &[BuildAttempt] {
+        &self.attempts
+    }
+
+    /// Records an attempt, dropping the oldest one if the report is full.
+    pub(crate) fn record(&mut self, attempt: BuildAttempt) {
+        if self.attempts.len() == BUILD_REPORT_SIZE {
+            self.attempts.remove(0);
+        }
+        self.attempts.push(attempt);
+    }
+}
+
+impl fmt::Display for BuildReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Build attempts:")?;
+        for attempt in &self.attempts {
+            write!(f, " [{}]", attempt)?;
+        }
+        Ok(())
+    }
+}
+
+/// An attempt to add a hop to a tunnel, see [`BuildReport`].
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct BuildAttempt {
+    /// The position of the hop, counted from zero.
+    pub hop: usize,
+    /// The leading bytes of the [`Fingerprint`] of the peer tried for the hop.
+    pub peer: [u8; FINGERPRINT_PREFIX_LEN],
+    /// The result of the attempt.
+    pub outcome: BuildOutcome,
+    /// The time taken by the attempt.
+    pub duration: Duration,
+}
+
+impl BuildAttempt {
+    pub(crate) fn new(hop: usize, peer: &Peer, outcome: BuildOutcome, duration: Duration) -> Self {
+        let mut prefix = [0u8; FINGERPRINT_PREFIX_LEN];
+        prefix.copy_from_slice(&peer.fingerprint()[..FINGERPRINT_PREFIX_LEN]);
+        BuildAttempt {
+            hop,
+            peer: prefix,
+            outcome,
+            duration,
+        }
+    }
+}
+
+impl fmt::Display for BuildAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "hop {} via ", self.hop)?;
+        for byte in &self.peer {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, ": {:?} after {:?}", self.outcome, self.duration)
+    }
+}
+
+/// The outcome of a [`BuildAttempt`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum BuildOutcome {
+    /// The hop was added to the tunnel.
+    Ok,
+    /// No connection to the peer could be established.
+    ConnectFailed,
+    /// The peer did not respond in time.
+    Timeout,
+    /// The circuit handshake failed or was rejected.
+    HandshakeFailed,
+    /// The peer could not prove its identity or no session key could be derived.
+    DeriveFailed,
+    /// The previous hop refused to connect to the peer, since its address policy denies the
+    /// address of the peer, see
+    /// [`OnionBuilder::set_extend_policy`](crate::OnionBuilder::set_extend_policy).
+    PolicyRefused,
+}
+
+/// Statistics about the incoming connections of an onion router.
+///
+/// Use [`OnionContext::relay_stats`](crate::OnionContext::relay_stats) to obtain a snapshot.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct RelayStats {
+    /// The number of incoming connections currently performing the circuit handshake.
+    pub pending_handshakes: usize,
+    /// The number of incoming connections closed because too many handshakes were pending.
+    pub rejected_handshakes: u64,
+    /// The number of requests to extend a tunnel which were refused, since the address policy
+    /// denies the address of the next hop, see
+    /// [`OnionBuilder::set_extend_policy`](crate::OnionBuilder::set_extend_policy).
+    pub refused_extends: u64,
+    /// The number of circuits which were replaced as the path of an incoming tunnel, but are
+    /// still read for data sent before the switchover.
+    pub draining_circuits: usize,
+    /// The number of open connections to next hops, each shared by all circuits this onion
+    /// router relays to the same peer.
+    pub relay_connections: usize,
+    /// The open circuits accepted by this onion router, oldest first.
+    pub inbound_circuits: Vec<InboundCircuitInfo>,
+    /// The time between reading a relayed cell from one circuit and handing it to the connection
+    /// of the other circuit, in both directions. Empty if disabled with
+    /// [`OnionBuilder::enable_latency_histogram`](crate::OnionBuilder::enable_latency_histogram).
+    ///
+    /// Use [`OnionContext::reset_relay_measurements`](crate::OnionContext::reset_relay_measurements) to start
+    /// a new measurement.
+    pub forwarding_latency: LatencyHistogram,
+    /// The write queues of the open connections to next hops, oldest first.
+    pub relay_connection_queues: Vec<ConnectionQueueInfo>,
+}
+
+/// The write queue of a connection to a next hop, see [`RelayStats::relay_connection_queues`].
+///
+/// Cells relayed to the next hop wait in the queue while the connection is slower than the
+/// circuits sharing it.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ConnectionQueueInfo {
+    /// The address of the next hop.
+    pub peer_addr: SocketAddr,
+    /// The number of messages currently waiting to be written.
+    pub queued: usize,
+    /// The highest number of messages which waited to be written at the same time since the
+    /// connection was opened or the measurements were last reset.
+    pub high_water_mark: usize,
+}
+
+/// The circuit handshakes this onion router initiates with a single peer while building tunnels,
+/// see [`OnionContext::outgoing_handshakes`](crate::OnionContext::outgoing_handshakes).
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct PeerHandshakes {
+    /// The fingerprint of the peer.
+    pub fingerprint: Fingerprint,
+    /// The number of handshakes with the peer currently in progress.
+    pub in_flight: usize,
+    /// The number of handshakes waiting for others to finish, see
+    /// [`OnionBuilder::set_max_handshakes_per_peer`](crate::OnionBuilder::set_max_handshakes_per_peer).
+    pub waiting: usize,
+}
+
+/// A circuit accepted by this onion router, see [`RelayStats::inbound_circuits`].
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct InboundCircuitInfo {
+    /// The address of the previous hop, or of the initiator if this onion router is the first hop.
+    pub peer_addr: SocketAddr,
+    /// Identifies the connection to the previous hop in the logs of this onion router. Circuits
+    /// sharing a connection have the same id.
+    pub connection_id: u64,
+    /// The parameters negotiated with the initiator of the tunnel.
+    pub params: CircuitParams,
+}
+
+/// Counters backing [`RelayStats`], shared between the [`OnionContext`](crate::OnionContext) and
+/// the listener.
+#[derive(Debug, Default)]
+pub(crate) struct RelayCounters {
+    pub(crate) pending_handshakes: AtomicUsize,
+    pub(crate) rejected_handshakes: AtomicU64,
+    pub(crate) refused_extends: AtomicU64,
+    pub(crate) draining_circuits: AtomicUsize,
+    pub(crate) relay_connections: AtomicUsize,
+    pub(crate) inbound_circuits: Mutex<BTreeMap<u64, InboundCircuitInfo>>,
+    pub(crate) next_inbound_circuit: AtomicU64,
+    pub(crate) forwarding_latency: Histogram,
+    pub(crate) connection_queues: Mutex<BTreeMap<u64, (SocketAddr, Arc<QueueDepth>)>>,
+    pub(crate) next_connection_queue: AtomicU64,
+}
+
+impl RelayCounters {
+    pub(crate) fn snapshot(&self) -> RelayStats {
+        RelayStats {
+            pending_handshakes: self.pending_handshakes.load(Ordering::Relaxed),
+            rejected_handshakes: self.rejected_handshakes.load(Ordering::Relaxed),
+            refused_extends: self.refused_extends.load(Ordering::Relaxed),
+            draining_circuits: self.draining_circuits.load(Ordering::Relaxed),
+            relay_connections: self.relay_connections.load(Ordering::Relaxed),
+            inbound_circuits: self
+                .inbound_circuits
+                .lock()
+                .unwrap()
+                .values()
+                .cloned()
+                .collect(),
+            forwarding_latency: self.forwarding_latency.snapshot(),
+            relay_connection_queues: self
+                .connection_queues
+                .lock()
+                .unwrap()
+                .values()
+                .map(|(peer_addr, depth)| ConnectionQueueInfo {
+                    peer_addr: *peer_addr,
+                    queued: depth.queued.load(Ordering::Relaxed),
+                    high_water_mark: depth.high_water_mark.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+
+    /// Clears the forwarding latency and lowers the high-water marks of the connection queues to
+    /// their current length.
+    pub(crate) fn reset_measurements(&self) {
+        self.forwarding_latency.reset();
+        for (_, depth) in self.connection_queues.lock().unwrap().values() {
+            let queued = depth.queued.load(Ordering::Relaxed);
+            depth.high_water_mark.store(queued, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The length of the write queue of a connection and its high-water mark.
+#[derive(Debug, Default)]
+pub(crate) struct QueueDepth {
+    queued: AtomicUsize,
+    high_water_mark: AtomicUsize,
+}
+
+impl QueueDepth {
+    pub(crate) fn push(&self) {
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water_mark.fetch_max(queued, Ordering::Relaxed);
+    }
+
+    pub(crate) fn pop(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The write queue of a connection to a next hop, which is listed in [`RelayStats`] while the
+/// connection is open.
+pub(crate) struct ConnectionQueue {
+    key: u64,
+    depth: Arc<QueueDepth>,
+    counters: Arc<RelayCounters>,
+}
+
+impl ConnectionQueue {
+    pub(crate) fn new(peer_addr: SocketAddr, counters: Arc<RelayCounters>) -> Self {
+        let key = counters
+            .next_connection_queue
+            .fetch_add(1, Ordering::Relaxed);
+        let depth = Arc::new(QueueDepth::default());
+        counters
+            .connection_queues
+            .lock()
+            .unwrap()
+            .insert(key, (peer_addr, depth.clone()));
+        ConnectionQueue {
+            key,
+            depth,
+            counters,
+        }
+    }
+
+    pub(crate) fn depth(&self) -> Arc<QueueDepth> {
+        self.depth.clone()
+    }
+}
+
+impl Drop for ConnectionQueue {
+    fn drop(&mut self) {
+        self.counters
+            .connection_queues
+            .lock()
+            .unwrap()
+            .remove(&self.key);
+    }
+}
+
+/// An accepted circuit, which is listed in [`RelayStats`] while it is open.
+pub(crate) struct InboundCircuit {
+    key: u64,
+    counters: Arc<RelayCounters>,
+}
+
+impl InboundCircuit {
+    pub(crate) fn new(info: InboundCircuitInfo, counters: Arc<RelayCounters>) -> Self {
+        let key = counters
+            .next_inbound_circuit
+            .fetch_add(1, Ordering::Relaxed);
+        counters.inbound_circuits.lock().unwrap().insert(key, info);
+        InboundCircuit { key, counters }
+    }
+}
+
+impl Drop for InboundCircuit {
+    fn drop(&mut self) {
+        self.counters
+            .inbound_circuits
+            .lock()
+            .unwrap()
+            .remove(&self.key);
+    }
+}
+
+/// A hop of the current path of an outgoing tunnel, see
+/// [`OnionContext::path_info`](crate::OnionContext::path_info).
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct HopInfo {
+    /// The address of the peer.
+    pub addr: SocketAddr,
+    /// The fingerprint of the host key of the peer.
+    pub fingerprint: Fingerprint,
+    /// The parameters negotiated with the peer. The cipher suite is negotiated with every hop on
+    /// its own, while the cell size is the same for all hops.
+    pub params: CircuitParams,
+}
+
+/// An outgoing tunnel built by this onion router, see
+/// [`OnionContext::list_tunnels`](crate::OnionContext::list_tunnels).
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct OutgoingTunnelInfo {
+    pub tunnel_id: TunnelId,
+    pub state: TunnelState,
+    /// The number of hops of the current path, including the destination. Zero until the first
+    /// path is ready.
+    pub hops: usize,
+    /// The fingerprint of the destination, `None` for tunnels to random destinations or
+    /// terminated at a relay.
+    pub destination: Option<Fingerprint>,
+    pub stats: TunnelStats,
+    /// The time since the tunnel was switched to its current path, either by becoming ready or by
+    /// the last rotation. `None` until the first path is ready.
+    pub since_switchover: Option<Duration>,
+}
+
+/// The delay of automatic rebuilds of an outgoing tunnel, because building a path failed at its
+/// destination, see [`OnionContext::retry_backoff`](crate::OnionContext::retry_backoff).
+///
+/// The delay grows with every failure and is randomized. Rotations and replacements of failed
+/// paths wait until the backoff ended, while tunnels built by the application are not delayed.
+/// A path reaching the destination ends the backoff.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct RetryBackoff {
+    /// The number of consecutive builds which failed at the destination.
+    pub failures: u32,
+    /// The time until the next automatic build towards the destination may start.
+    pub retry_in: Duration,
+}
@@ -0,0 +1,96 @@
+use crate::onion::Tunnel;
+use bytes::{Buf, Bytes};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// A byte stream over a [`Tunnel`], implementing [`AsyncRead`] and [`AsyncWrite`].
+///
+/// Like the tunnel itself, the stream persists over the rotation of its path, so reads and writes
+/// simply continue on the new path. Once the tunnel is closed, whether by `TUNNEL END` of the peer
+/// or due to a failure, reads return EOF and writes fail with [`io::ErrorKind::BrokenPipe`]. Use
+/// [`OnionContext::events`](crate::OnionContext::events) to learn why a tunnel was closed.
+///
+/// Writes are only queued, see [`Tunnel::write`], so they never return `Pending` and flushing does
+/// nothing. The tunnel has no notion of a half-closed connection, so shutting down the write side
+/// does nothing either. The tunnel is closed once the stream is dropped, discarding data which
+/// has not been sent yet.
+#[derive(Debug)]
+pub struct OnionStream {
+    tunnel: Tunnel,
+    /// the rest of the chunk which did not fit into the buffer of the last read
+    read_buf: Bytes,
+}
+
+impl OnionStream {
+    /// Wraps `tunnel` in a stream.
+    pub fn new(tunnel: Tunnel) -> Self {
+        Self {
+            tunnel,
+            read_buf: Bytes::new(),
+        }
+    }
+
+    /// Returns the wrapped tunnel.
+    pub fn get_ref(&self) -> &Tunnel {
+        &self.tunnel
+    }
+
+    /// Returns the wrapped tunnel.
+    ///
+    /// Data which has already been received, but not yet read from the stream, is lost.
+    pub fn into_inner(self) -> Tunnel {
+        self.tunnel
+    }
+}
+
+impl From<Tunnel> for OnionStream {
+    fn from(tunnel: Tunnel) -> Self {
+        OnionStream::new(tunnel)
+    }
+}
+
+impl AsyncRead for OnionStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read_buf.is_empty() {
+            match self.tunnel.data_rx.poll_recv(cx) {
+                Poll::Ready(Some(data)) => self.read_buf = data,
+                // EOF
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = self.read_buf.len().min(buf.remaining());
+        buf.put_slice(&self.read_buf[..len]);
+        self.read_buf.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for OnionStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = self
+            .tunnel
+            .write(Bytes::copy_from_slice(buf))
+            .map(|_| buf.len())
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()));
+        Poll::Ready(written)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,17 +1,54 @@
-use crate::onion::circuit::{self, CircuitHandler};
-use crate::onion::crypto::{RsaPrivateKey, RsaPublicKey};
-use crate::onion::socket::OnionSocket;
-use crate::onion::tunnel::{Event, Target, Tunnel, TunnelBuilder, TunnelError, TunnelHandler};
-use crate::onion::{OnionContext, OnionListener};
-use crate::{Peer, PeerProvider, Result};
+use crate::onion::circuit::{self, Circuit, CircuitHandler, CircuitIds};
+use crate::onion::config::ContextSettings;
+use crate::onion::connection::{self, ConnectionCache};
+use crate::onion::crypto::{
+    self, CipherSuite, CipherSuites, Direction, Ed25519PrivateKey, HandshakeVersion, HostKey,
+    HostPublicKey, RsaPrivateKey, SecretBytes, SessionKey,
+};
+use crate::onion::diagnosis::SuspectedPeers;
+use crate::onion::endpoint::Endpoints;
+use crate::onion::feedback::FEEDBACK_CAPACITY;
+use crate::onion::guards::EntryGuards;
+use crate::onion::handshakes::HandshakeLimiter;
+use crate::onion::lanes::{Lane, Lanes, Outgoing};
+use crate::onion::latency::Histogram;
+use crate::onion::observer::{self, Observer};
+use crate::onion::protocol::{
+    CellSize, CircuitCreate, CircuitCreated, EndReason, FeatureOffer, SignKey, SuiteSelection,
+    ToBytesExt, UnsupportedFeatures, MESSAGE_SIZE, SIGNATURE_LEN,
+};
+use crate::onion::retry::DestinationRetries;
+use crate::onion::rng::{OnionRng, RngFactory, SeededRandom, SystemRandom};
+use crate::onion::shutdown::{EventTally, Shutdown};
+use crate::onion::socket::{OnionSocket, OnionSocketError, SocketErrorKind};
+use crate::onion::state::{DestinationBackoff, NodeState, SuspectedPeer};
+use crate::onion::stats::RelayCounters;
+use crate::onion::tunnel::{
+    self, Event, KeyLimits, RotationPolicy, Target, Tunnel, TunnelBuilder, TunnelError,
+    TunnelHandler,
+};
+use crate::onion::{
+    self, AddressRange, BuildOutcome, BuildReport, BuildTimedOut, BuildTimeouts, CloseReason,
+    DestinationUnreachable, ExtendPolicy, Fallback, HandshakeBacklog, HopSelectionError,
+    IncomingTunnelInfo, OnionContext, OnionListener, PathUnreachable, ReadyCause, RelayStats,
+    SocketTimeouts, StrictViolation, TunnelOptions, TunnelRegistry,
+};
+use crate::utils::TryFromBytes;
+use crate::{Capabilities, CapabilityCache, KnownPeers, Peer, PeerProvider, Result};
 use anyhow::anyhow;
-use bytes::Bytes;
-use std::net::{IpAddr, Ipv4Addr};
+use bytes::{Bytes, BytesMut};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
-use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::TcpListener;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Handle;
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tokio::time;
 use tokio_stream as stream;
@@ -20,22 +57,29 @@ const TEST_IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
 static PORT_COUNTER: AtomicU16 = AtomicU16::new(42000);
 const ERROR_TIMEOUT: Duration = Duration::from_secs(4);
 const ROUND_DURATION: Duration = Duration::from_secs(5);
+const STALL_THRESHOLD: Duration = Duration::from_secs(10);
 
-pub(crate) fn read_rsa_keypair<P: AsRef<Path>>(path: P) -> Result<(RsaPrivateKey, RsaPublicKey)> {
-    let private_key = RsaPrivateKey::from_pem_file(path)?;
+pub(crate) fn read_rsa_keypair<P: AsRef<Path>>(path: P) -> Result<(HostKey, HostPublicKey)> {
+    let private_key = HostKey::from(RsaPrivateKey::from_pem_file(path)?);
     let public_key = private_key.public_key();
     Ok((private_key, public_key))
 }
 
-async fn listen(listener: TcpListener, host_key: &RsaPrivateKey) -> Result<()> {
+pub(crate) fn read_ed25519_keypair<P: AsRef<Path>>(path: P) -> Result<(HostKey, HostPublicKey)> {
+    let private_key = HostKey::from(Ed25519PrivateKey::from_pem_file(path)?);
+    let public_key = private_key.public_key();
+    Ok((private_key, public_key))
+}
+
+async fn listen(listener: TcpListener, host_key: &Arc<HostKey>) -> Result<()> {
     println!(
         "Listening for P2P connections on {}",
         listener.local_addr()?
     );
     let (stream, _) = listener.accept().await?;
-    let socket = OnionSocket::new(stream);
+    let socket = OnionSocket::new(stream.into());
     let (incoming, _) = mpsc::channel(1);
-    let mut handler = CircuitHandler::init(socket, host_key, incoming).await?;
+    let mut handler = CircuitHandler::init(socket, host_key, CipherSuites::all(), incoming).await?;
     handler.handle().await?;
     Ok(())
 }
@@ -57,9 +101,105 @@ async fn spawn_n_peers(n: usize) -> Vec<Peer> {
     peers
 }
 
+/// Like `spawn_n_peers`, but each peer keeps accepting circuits, so tunnels can be rebuilt.
+async fn spawn_n_relays(n: usize) -> Vec<Peer> {
+    spawn_n_relays_with_suites(n, CipherSuites::all()).await
+}
+
+async fn spawn_n_relays_with_suites(n: usize, cipher_suites: CipherSuites) -> Vec<Peer> {
+    let keypair = read_rsa_keypair("testkey.pem").unwrap();
+    spawn_n_relays_with_key(n, cipher_suites, keypair).await
+}
+
+/// Like `spawn_n_relays`, but the relays identify themselves with a Ed25519 key.
+async fn spawn_n_ed25519_relays(n: usize) -> Vec<Peer> {
+    let keypair = read_ed25519_keypair("tests/ed25519key.pem").unwrap();
+    spawn_n_relays_with_key(n, CipherSuites::all(), keypair).await
+}
+
+async fn spawn_n_relays_with_key(
+    n: usize,
+    cipher_suites: CipherSuites,
+    (host_key, peer_key): (HostKey, HostPublicKey),
+) -> Vec<Peer> {
+    let mut peers = Vec::new();
+    let host_key = Arc::new(host_key);
+    for _ in 0..n {
+        let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let peer_addr = (TEST_IP, peer_port).into();
+        let listener = TcpListener::bind(&peer_addr).await.unwrap();
+        let host_key = host_key.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let host_key = host_key.clone();
+                tokio::spawn(async move {
+                    let socket = OnionSocket::new(stream.into());
+                    let (incoming, _incoming_rx) = mpsc::channel(1);
+                    let mut handler =
+                        CircuitHandler::init(socket, &host_key, cipher_suites, incoming).await?;
+                    handler.handle().await
+                });
+            }
+        });
+        peers.push(Peer::new(peer_addr, peer_key.clone()));
+    }
+    peers
+}
+
+/// Spawns a relay refusing to extend circuits to the addresses denied by `policy`.
+async fn spawn_relay_with_policy(policy: ExtendPolicy, counters: Arc<RelayCounters>) -> Peer {
+    let (host_key, peer_key) = read_rsa_keypair("testkey.pem").unwrap();
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(&peer_addr).await.unwrap();
+    let host_key = Arc::new(host_key);
+    let policy = Arc::new(policy);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let host_key = host_key.clone();
+            let policy = policy.clone();
+            let counters = counters.clone();
+            tokio::spawn(async move {
+                let socket = OnionSocket::new(stream.into());
+                let (incoming, _incoming_rx) = mpsc::channel(1);
+                let mut handler =
+                    CircuitHandler::init(socket, &host_key, CipherSuites::all(), incoming).await?;
+                handler.set_extend_policy(policy, counters);
+                handler.handle().await
+            });
+        }
+    });
+    Peer::new(peer_addr, peer_key)
+}
+
+/// Spawns a peer which accepts a single circuit and after `delay` either tears it down for
+/// `teardown` or just closes the connection.
+async fn spawn_failing_peer(delay: Duration, teardown: Option<EndReason>) -> Peer {
+    let (host_key, peer_key) = read_rsa_keypair("testkey.pem").unwrap();
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(&peer_addr).await.unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = OnionSocket::new(stream);
+        let (circuit_id, _, suites) = socket.accept_handshake(CipherSuites::all()).await.unwrap();
+        let (_, key) = crypto::generate_ephemeral_keypair();
+        let key = SignKey::sign(&key, suites, &host_key);
+        socket
+            .finalize_handshake(circuit_id, key.to_signed())
+            .await
+            .unwrap();
+        time::sleep(delay).await;
+        if let Some(reason) = teardown {
+            socket.teardown(circuit_id, reason).await.unwrap();
+        }
+    });
+    Peer::new(peer_addr, peer_key)
+}
+
 async fn build_tunnel_n_peers(n: usize) -> Result<Tunnel> {
     let peers = spawn_n_peers(n).await;
-    let mut tunnel = Tunnel::init(0, &peers[0]).await?;
+    let mut tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
     for i in 1..n {
         tunnel.extend(&peers[i]).await?;
     }
@@ -88,167 +228,1349 @@ async fn test_handshake_three_peers() -> Result<()> {
 }
 
 #[tokio::test]
-async fn test_truncate_zero_peers() -> Result<()> {
-    let peers = spawn_n_peers(2).await;
-    let mut tunnel = Tunnel::init(0, &peers[0]).await?;
-    for i in 1..2 {
-        tunnel.extend(&peers[i]).await?;
-    }
-    match tunnel.truncate(0).await {
-        Err(TunnelError::Incomplete) => {
-            assert_eq!(tunnel.len(), 2);
-            Ok(())
+async fn test_handshake_mixed_cell_sizes() -> Result<()> {
+    let peers = spawn_n_relays(3).await;
+    let build = |cell_size| {
+        let peers = peers.clone();
+        async move {
+            let mut tunnel = Tunnel::init(0, &peers[0], cell_size, CipherSuites::all()).await?;
+            for peer in &peers[1..3] {
+                tunnel.extend(peer).await?;
+            }
+            Ok::<_, anyhow::Error>(tunnel)
         }
-        _ => Err(anyhow!(
-            "Expected truncate to fail since it tries to truncate a non-existing tail"
-        )),
-    }
+    };
+    let (large, standard) = tokio::try_join!(build(CellSize::Large), build(CellSize::Standard))?;
+    assert_eq!(large.len(), 3);
+    assert_eq!(large.cell_size(), CellSize::Large);
+    assert_eq!(standard.len(), 3);
+    assert_eq!(standard.cell_size(), CellSize::Standard);
+    Ok(())
+}
+
+/// Spawns a peer which answers a single `CIRCUIT CREATE` with the cell size and cipher suites
+/// chosen by `respond`, regardless of whether they match the request.
+async fn spawn_scripted_peer<F>(respond: F) -> Result<Peer>
+where
+    F: FnOnce(&CircuitCreate) -> (CellSize, SuiteSelection) + Send + 'static,
+{
+    let (host_key, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(&peer_addr).await?;
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = BytesMut::new();
+        buf.resize(MESSAGE_SIZE, 0);
+        stream.read_exact(&mut buf).await.unwrap();
+        let req = CircuitCreate::try_read_from(&mut buf).unwrap();
+        let (cell_size, suites) = respond(&req);
+        let (_, key) = crypto::generate_ephemeral_keypair();
+        let res = CircuitCreated {
+            circuit_id: req.circuit_id,
+            cell_size,
+            key: SignKey::sign(&key, suites, &host_key),
+        };
+        buf.clear();
+        res.write_padded_to(&mut buf, MESSAGE_SIZE);
+        stream.write_all(&buf).await.unwrap();
+    });
+    Ok(Peer::new(peer_addr, peer_key))
 }
 
 #[tokio::test]
-async fn test_truncate_one_peer() -> Result<()> {
-    let peers = spawn_n_peers(2).await;
-    let mut tunnel = Tunnel::init(0, &peers[0]).await?;
-    for i in 1..2 {
-        tunnel.extend(&peers[i]).await?;
+async fn test_handshake_cell_size_mismatch() -> Result<()> {
+    // a peer which only knows standard cells and ignores the requested cell size
+    let peer = spawn_scripted_peer(|req| {
+        let suites = SuiteSelection::negotiate(req.cipher_suites, CipherSuites::all()).unwrap();
+        (CellSize::Standard, suites)
+    })
+    .await?;
+    assert!(Tunnel::init(0, &peer, CellSize::Large, CipherSuites::all())
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_cipher_suite_downgrade() -> Result<()> {
+    // a peer which supports all suites, but selects the weakest one
+    let peer = spawn_scripted_peer(|req| {
+        let suites = SuiteSelection {
+            selected: CipherSuite::TruncatedDigest.code(),
+            ..SuiteSelection::negotiate(req.cipher_suites, CipherSuites::all()).unwrap()
+        };
+        (req.cell_size, suites)
+    })
+    .await?;
+    assert!(
+        Tunnel::init(0, &peer, CellSize::Standard, CipherSuites::all())
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_no_common_cipher_suite() -> Result<()> {
+    let peers = spawn_n_relays_with_suites(1, CipherSuites::from_slice(&[CipherSuite::Hmac])).await;
+    let offered = CipherSuites::from_slice(&[CipherSuite::TruncatedDigest]);
+    assert!(Tunnel::init(0, &peers[0], CellSize::Standard, offered)
+        .await
+        .is_err());
+    Ok(())
+}
+
+/// Spawns a peer which accepts connections, but neither reads nor writes on them.
+async fn spawn_silent_peer() -> Result<SocketAddr> {
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr: SocketAddr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(peer_addr).await?;
+    tokio::spawn(async move {
+        let mut streams = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+    Ok(peer_addr)
+}
+
+#[tokio::test]
+async fn test_handshake_read_timeout() -> Result<()> {
+    let peer_addr = spawn_silent_peer().await?;
+    let (_, hostkey) = read_rsa_keypair("testkey.pem")?;
+    let peer = Peer::new(peer_addr, hostkey);
+    let timeouts = SocketTimeouts {
+        read: Duration::from_millis(200),
+        ..Default::default()
+    };
+
+    // the handshake fails once the read timeout elapsed, not the hop timeout
+    let init = Tunnel::init_with(
+        0,
+        &peer,
+        CellSize::Standard,
+        CipherSuites::all(),
+        None,
+        Box::new(SystemRandom),
+        timeouts,
+    );
+    let e = time::timeout(Duration::from_secs(1), init)
+        .await?
+        .err()
+        .unwrap();
+    let e = e.downcast_ref::<OnionSocketError>().unwrap();
+    assert!(matches!(e.kind, SocketErrorKind::StreamTimeout(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_timeout() -> Result<()> {
+    let peer_addr = spawn_silent_peer().await?;
+    let stream = TcpStream::connect(peer_addr).await?;
+    let timeouts = SocketTimeouts {
+        write: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let mut socket = OnionSocket::new(stream).with_timeouts(timeouts);
+
+    // the writes succeed until the buffers of both ends are full
+    let e = loop {
+        if let Err(e) = socket.teardown(1, EndReason::Normal).await {
+            break e;
+        }
+    };
+    assert!(matches!(e.kind, SocketErrorKind::StreamTimeout(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mixed_cipher_suites() -> Result<()> {
+    let mut peers = spawn_n_relays_with_suites(1, CipherSuites::all()).await;
+    peers.extend(
+        spawn_n_relays_with_suites(1, CipherSuites::from_slice(&[CipherSuite::ShortHmac])).await,
+    );
+    peers.extend(
+        spawn_n_relays_with_suites(1, CipherSuites::from_slice(&[CipherSuite::TruncatedDigest]))
+            .await,
+    );
+
+    let mut tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+    for peer in &peers[1..] {
+        tunnel.extend(peer).await?;
     }
+    assert_eq!(tunnel.len(), 3);
+    // responses from the last hop are verified using its suite
+    tunnel.truncate(1).await?;
     tunnel.truncate(1).await?;
     assert_eq!(tunnel.len(), 1);
     Ok(())
 }
 
 #[tokio::test]
-async fn test_truncate_two_peers() -> Result<()> {
-    let peers = spawn_n_peers(3).await;
-    let mut tunnel = Tunnel::init(0, &peers[0]).await?;
-    for i in 1..3 {
-        tunnel.extend(&peers[i]).await?;
+async fn test_handshake_version_interop() -> Result<()> {
+    use HandshakeVersion::{DirectionalKeys, ResponderNonce, SequencedNonces, Unsalted};
+    let new = CipherSuites::all();
+    let mid = new.with_version(SequencedNonces);
+    // relays negotiating the oldest version, which still sign the suites unlike the peers predating
+    // the negotiation, see `test_handshake_baseline_layout`
+    let old = new.with_version(Unsalted);
+    let mut peers = spawn_n_relays_with_suites(2, new).await;
+    peers.extend(spawn_n_relays_with_suites(1, old).await);
+    peers.extend(spawn_n_relays_with_suites(2, new).await);
+    peers.extend(spawn_n_relays_with_suites(1, mid).await);
+    peers.extend(spawn_n_relays_with_suites(1, new).await);
+    peers.extend(spawn_n_relays_with_suites(1, new.with_version(ResponderNonce)).await);
+
+    // only the hop after an older relay falls back, as the relay would not forward the parts
+    // added by later versions. Each extend shows that the session key of the previous hop is
+    // shared by both sides.
+    let mut tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, new).await?;
+    for peer in &peers[1..] {
+        tunnel.extend(peer).await?;
     }
-    assert_eq!(tunnel.len(), 3);
+    assert_eq!(
+        tunnel.handshake_versions(),
+        [
+            DirectionalKeys,
+            DirectionalKeys,
+            Unsalted,
+            Unsalted,
+            DirectionalKeys,
+            SequencedNonces,
+            SequencedNonces,
+            ResponderNonce
+        ]
+    );
+    // the hops checking the sequence numbers keep accepting the cells after a truncate
     tunnel.truncate(2).await?;
-    assert_eq!(tunnel.len(), 1);
+    tunnel.extend(&peers[1]).await?;
+
+    // older initiators are answered in the layout of their version by new relays
+    let mut tunnel = Tunnel::init(1, &peers[0], CellSize::Standard, old).await?;
+    tunnel.extend(&peers[1]).await?;
+    tunnel.extend(&peers[3]).await?;
+    assert_eq!(tunnel.handshake_versions(), [Unsalted, Unsalted, Unsalted]);
+    let mut tunnel = Tunnel::init(2, &peers[0], CellSize::Standard, mid).await?;
+    tunnel.extend(&peers[1]).await?;
+    tunnel.extend(&peers[5]).await?;
+    assert_eq!(
+        tunnel.handshake_versions(),
+        [SequencedNonces, SequencedNonces, SequencedNonces]
+    );
     Ok(())
 }
 
-#[tokio::test]
-#[ignore = "broken"]
-async fn test_data_unidirectional() -> Result<()> {
-    let (host_key, peer_key) = read_rsa_keypair("testkey.pem").unwrap();
+/// Returns the signed key of a peer predating the cipher suite negotiation, whose signature only
+/// covers the key.
+fn baseline_signed_key(key: &crypto::EphemeralPublicKey, host_key: &HostKey) -> Vec<u8> {
+    let mut signed = vec![0u8; SIGNATURE_LEN];
+    host_key
+        .sign(key.bytes(), &mut signed[..host_key.signature_len()])
+        .unwrap();
+    signed.extend_from_slice(key.bytes());
+    signed
+}
+
+/// Writes a cell of a peer predating the cipher suite negotiation: the truncated digest, `msg` and
+/// random padding, encrypted with `session_key` under a random nonce.
+fn baseline_cell(circuit_id: &[u8], msg: &[u8], session_key: &SessionKey) -> Vec<u8> {
+    let mut nonce = [0u8; crypto::NONCE_LEN];
+    crypto::fill_random(&mut nonce);
+    let mut payload = vec![0u8; CellSize::Standard.bytes() - 4 - crypto::NONCE_LEN];
+    payload[12..12 + msg.len()].copy_from_slice(msg);
+    crypto::fill_random(&mut payload[12 + msg.len()..]);
+    let digest = crypto::digest(&payload[12..]);
+    payload[..12].copy_from_slice(&digest.as_ref()[..12]);
+    session_key
+        .encrypt(Direction::Backward, nonce, &mut payload)
+        .unwrap();
+    [&[0x3, 0], circuit_id, &nonce, &payload].concat()
+}
+
+/// Spawns a relay which speaks the layout of peers predating the cipher suite negotiation. It
+/// ignores everything following the ephemeral keys, fills its messages with random padding and
+/// only signs its key. The relay accepts a single circuit, extends it and then relays its cells.
+async fn spawn_baseline_relay() -> Result<Peer> {
+    use HandshakeVersion::Unsalted;
+    let (host_key, peer_key) = read_rsa_keypair("testkey.pem")?;
     let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
     let peer_addr = (TEST_IP, peer_port).into();
-    let peer = Peer::new(peer_addr, peer_key);
+    let listener = TcpListener::bind(&peer_addr).await?;
+    tokio::spawn(async move {
+        let (mut in_stream, _) = listener.accept().await?;
+        let mut buf = vec![0u8; MESSAGE_SIZE];
+        in_stream.read_exact(&mut buf).await?;
+        assert_eq!(buf[0], 0x0, "expected CIRCUIT CREATE");
+        let in_id = [buf[2], buf[3]];
+        let initiator_key = Bytes::copy_from_slice(&buf[4..4 + crypto::KEY_LEN]);
+        let (private_key, key) = crypto::generate_ephemeral_keypair();
+        let session_key = Arc::new(SessionKey::from_key_exchange(
+            private_key,
+            &crypto::EphemeralPublicKey::new(initiator_key),
+            CipherSuite::TruncatedDigest,
+            None,
+            Unsalted,
+        )?);
+        let mut created = [&[0x1, 0], &in_id[..], &baseline_signed_key(&key, &host_key)].concat();
+        let len = created.len();
+        created.resize(MESSAGE_SIZE, 0);
+        crypto::fill_random(&mut created[len..]);
+        in_stream.write_all(&created).await?;
 
-    let (incoming_tx, mut incoming_rx) = mpsc::channel(100);
-    tokio::spawn({
-        let mut listener = OnionListener::new(host_key, incoming_tx);
-        let tcp_listener = TcpListener::bind(peer_addr).await?;
-        async move { listener.listen(tcp_listener).await }
+        // TUNNEL EXTEND to an IPv4 address
+        in_stream.read_exact(&mut buf).await?;
+        assert_eq!(buf[0], 0x3, "expected CIRCUIT OPAQUE");
+        let nonce = buf[4..4 + crypto::NONCE_LEN].try_into()?;
+        let payload = &mut buf[4 + crypto::NONCE_LEN..];
+        session_key.decrypt(Direction::Forward, nonce, payload)?;
+        assert_eq!(
+            &crypto::digest(&payload[12..]).as_ref()[..12],
+            &payload[..12]
+        );
+        let msg = &payload[12..];
+        assert_eq!((msg[2], msg[3]), (0x10, 0), "expected TUNNEL EXTEND");
+        let dest_ip = Ipv4Addr::new(msg[4], msg[5], msg[6], msg[7]);
+        let dest_port = u16::from_be_bytes([msg[8], msg[9]]);
+        let mut create = [&[0x0, 0, 0, 1], &msg[10..10 + crypto::KEY_LEN]].concat();
+        let len = create.len();
+        create.resize(MESSAGE_SIZE, 0);
+        crypto::fill_random(&mut create[len..]);
+        let mut out_stream = TcpStream::connect((dest_ip, dest_port)).await?;
+        out_stream.write_all(&create).await?;
+        out_stream.read_exact(&mut buf).await?;
+        assert_eq!(buf[0], 0x1, "expected CIRCUIT CREATED");
+        let signed_key = &buf[4..4 + SIGNATURE_LEN + crypto::KEY_LEN];
+        let size = (3 + signed_key.len()) as u16;
+        let extended = [&size.to_be_bytes()[..], &[0x20], signed_key].concat();
+        in_stream
+            .write_all(&baseline_cell(&in_id, &extended, &session_key))
+            .await?;
+
+        // relay the cells of the later hops, adding or removing a layer
+        let (mut in_read, mut in_write) = in_stream.into_split();
+        let (mut out_read, mut out_write) = out_stream.into_split();
+        let backward_key = session_key.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; CellSize::Standard.bytes()];
+            while out_read.read_exact(&mut buf).await.is_ok() && buf[0] == 0x3 {
+                let nonce = buf[4..4 + crypto::NONCE_LEN].try_into()?;
+                let payload = &mut buf[4 + crypto::NONCE_LEN..];
+                backward_key.encrypt(Direction::Backward, nonce, payload)?;
+                buf[2..4].copy_from_slice(&in_id);
+                in_write.write_all(&buf).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        let mut buf = vec![0u8; CellSize::Standard.bytes()];
+        while in_read.read_exact(&mut buf).await.is_ok() && buf[0] == 0x3 {
+            let nonce = buf[4..4 + crypto::NONCE_LEN].try_into()?;
+            let payload = &mut buf[4 + crypto::NONCE_LEN..];
+            session_key.decrypt(Direction::Forward, nonce, payload)?;
+            buf[2..4].copy_from_slice(&[0, 1]);
+            out_write.write_all(&buf).await?;
+        }
+        Ok::<_, anyhow::Error>(())
     });
+    Ok(Peer::new(peer_addr, peer_key))
+}
 
-    let (evt_tx, _) = broadcast::channel(1);
-    let peer_provider = PeerProvider::from_stream(stream::empty());
-    let ctx = OnionContext::new(evt_tx.clone(), peer_provider, 0, false);
+#[tokio::test]
+async fn test_handshake_baseline_layout() -> Result<()> {
+    use HandshakeVersion::Unsalted;
+    let relay = spawn_baseline_relay().await?;
+    let peers = spawn_n_relays(2).await;
 
-    let send_tunnel = ctx.build_tunnel(peer).await.unwrap(); // FIXME task
-    evt_tx.send(Event::Switchover).unwrap();
-    let mut recv_tunnel = incoming_rx.recv().await.unwrap();
+    // the baseline relay answers without suites, then sends a CREATE without them to the next hop,
+    // which answers with a signature over its key alone. Extending through both shows that each
+    // shares its session key with the initiator.
+    let mut tunnel = Tunnel::init(0, &relay, CellSize::Standard, CipherSuites::all()).await?;
+    tunnel.extend(&peers[0]).await?;
+    tunnel.extend(&peers[1]).await?;
+    assert_eq!(tunnel.handshake_versions(), [Unsalted, Unsalted, Unsalted]);
 
-    let data = Bytes::from_static(b"test");
-    send_tunnel.write(data.clone()).unwrap();
-    assert_eq!(recv_tunnel.read().await.unwrap(), data);
+    // initiators which do not offer the suite of these peers refuse them
+    let relay = spawn_baseline_relay().await?;
+    let suites = CipherSuites::from_slice(&[CipherSuite::ShortHmac, CipherSuite::Hmac]);
+    assert!(Tunnel::init(1, &relay, CellSize::Standard, suites)
+        .await
+        .is_err());
     Ok(())
 }
 
 #[tokio::test]
-#[ignore = "broken"]
-async fn test_data_bidirectional() -> Result<()> {
-    let (host_key, peer_key) = read_rsa_keypair("testkey.pem").unwrap();
+async fn test_handshake_unsupported_features() -> Result<()> {
+    let (host_key, hostkey) = read_rsa_keypair("testkey.pem")?;
     let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
-    let peer_addr = (TEST_IP, peer_port).into();
-    let peer = Peer::new(peer_addr, peer_key);
+    let peer_addr: SocketAddr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(&peer_addr).await?;
+    let (rejected_tx, rejected_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let socket = OnionSocket::new(stream.into());
+        let (incoming, _incoming_rx) = mpsc::channel(1);
+        let host_key = Arc::new(host_key);
+        let res = CircuitHandler::init(socket, &host_key, CipherSuites::all(), incoming).await;
+        let _ = rejected_tx.send(res.err().map(|e| e.downcast::<UnsupportedFeatures>()));
+    });
 
-    let data_ping = Bytes::from_static(b"ping");
-    let data_pong = Bytes::from_static(b"pong");
+    // an initiator requiring a feature the relay does not know
+    let stream = TcpStream::connect(peer_addr).await?;
+    let mut socket = OnionSocket::from_stream(stream.into());
+    let (_, key) = crypto::generate_ephemeral_keypair();
+    let suites = CipherSuites::all();
+    let features = FeatureOffer {
+        required: 0x8000,
+        ..FeatureOffer::default()
+    };
+    let (_, peer_key) = socket
+        .initiate_handshake(key, CellSize::Standard, suites, Some(features))
+        .await?;
 
-    let (incoming_tx, mut incoming_rx) = mpsc::channel(100);
-    tokio::spawn({
-        let mut listener = OnionListener::new(host_key, incoming_tx);
-        let tcp_listener = TcpListener::bind(peer_addr).await?;
-        async move { listener.listen(tcp_listener).await }
-    });
+    // the signed reply tells which features are missing, then the relay tears the circuit down
+    let e = peer_key
+        .verify(&hostkey, suites, Some(features))
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.downcast_ref::<UnsupportedFeatures>(),
+        Some(&UnsupportedFeatures { missing: 0x8000 })
+    );
+    let e = socket.accept_opaque().await.err().unwrap();
+    assert!(matches!(
+        e.kind,
+        SocketErrorKind::TeardownMessage(EndReason::ProtocolError)
+    ));
+    let rejected = rejected_rx.await?.unwrap();
+    assert_eq!(rejected.ok(), Some(UnsupportedFeatures { missing: 0x8000 }));
+    Ok(())
+}
 
-    tokio::spawn({
-        let data_ping = data_ping.clone();
-        let data_pong = data_pong.clone();
+#[test]
+fn test_write_feedback_capacity() {
+    let counters = onion::TunnelCounters::with_write_feedback();
+    let mut feedback = counters.write_feedback().unwrap();
+    let write = || {
+        counters
+            .queue_message(Bytes::from_static(b"tracked"), 100, true, |_| {
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        counters.record_sent(1);
+    };
 
-        async move {
-            let mut tunnel = incoming_rx.recv().await.unwrap();
-            while let Ok(data) = tunnel.read().await {
-                println!("{:?}", &data);
-                assert_eq!(data, data_ping);
-                tunnel.write(data_pong.clone()).unwrap();
-            }
+    // the application does not read the feedback, so the reports which do not fit are dropped
+    let extra = 10;
+    for _ in 0..FEEDBACK_CAPACITY + extra {
+        write();
+    }
+    assert_eq!(counters.snapshot().dropped_feedback, extra as u64);
+    let mut reported = 0;
+    while feedback.try_next().is_some() {
+        reported += 1;
+    }
+    assert_eq!(reported, FEEDBACK_CAPACITY);
+
+    // once read, messages are reported again
+    write();
+    let written = feedback.try_next().unwrap();
+    assert_eq!(
+        written.handle.sequence(),
+        (FEEDBACK_CAPACITY + extra) as u64
+    );
+    assert_eq!(counters.snapshot().dropped_feedback, extra as u64);
+}
+
+#[test]
+fn test_cell_sequence() {
+    let sender = crypto::CellSequence::new(false);
+    let receiver = crypto::CellSequence::new(true);
+    let first = sender.next_nonce(Direction::Forward);
+    let second = sender.next_nonce(Direction::Forward);
+    assert!(receiver.accept(&first, Direction::Forward));
+    // numbers may be skipped, but not repeated or reordered
+    let third = sender.next_nonce(Direction::Forward);
+    assert!(receiver.accept(&third, Direction::Forward));
+    assert!(!receiver.accept(&second, Direction::Forward));
+    assert!(!receiver.accept(&third, Direction::Forward));
+    // a forward cell can not be passed off as a backward one
+    let fourth = sender.next_nonce(Direction::Forward);
+    assert!(!receiver.accept(&fourth, Direction::Backward));
+    assert!(receiver.accept(&fourth, Direction::Forward));
+
+    // peers which do not number their cells send random nonces
+    let unchecked = crypto::CellSequence::new(false);
+    assert!(unchecked.accept(&first, Direction::Backward));
+    assert!(unchecked.accept(&first, Direction::Backward));
+}
+
+/// Passes everything on to the system allocator, but copies the first bytes of the block at the
+/// watched address of the current thread before it is freed.
+struct WatchingAllocator;
+
+thread_local! {
+    static WATCHED: Cell<usize> = const { Cell::new(0) };
+    static FREED: Cell<Option<[u8; 16]>> = const { Cell::new(None) };
+}
+
+#[global_allocator]
+static ALLOCATOR: WatchingAllocator = WatchingAllocator;
+
+unsafe impl GlobalAlloc for WatchingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let watched = WATCHED.try_with(Cell::get).unwrap_or(0);
+        if watched == ptr as usize && layout.size() >= 16 {
+            let mut freed = [0u8; 16];
+            std::ptr::copy_nonoverlapping(ptr, freed.as_mut_ptr(), 16);
+            let _ = FREED.try_with(|f| f.set(Some(freed)));
         }
-    });
+        System.dealloc(ptr, layout)
+    }
 
-    let (evt_tx, _) = broadcast::channel(1);
-    let peer_provider = PeerProvider::from_stream(stream::empty());
-    let ctx = OnionContext::new(evt_tx.clone(), peer_provider, 0, false);
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        System.alloc_zeroed(layout)
+    }
 
-    let mut tunnel = ctx.build_tunnel(peer).await.unwrap(); // FIXME task
-    evt_tx.send(Event::Switchover).unwrap();
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        System.realloc(ptr, layout, new_size)
+    }
+}
 
-    tunnel.write(data_ping).unwrap();
-    assert_eq!(tunnel.read().await.unwrap(), data_pong);
-    Ok(())
+#[test]
+fn test_secret_bytes_wiped() {
+    let secret = SecretBytes::<16>::from_slice(&[0xab; 32]);
+    assert_eq!(secret.as_bytes(), &[0xab; 16]);
+    WATCHED.with(|w| w.set(secret.as_bytes().as_ptr() as usize));
+    drop(secret);
+    WATCHED.with(|w| w.set(0));
+    assert_eq!(FREED.with(Cell::take), Some([0; 16]));
+
+    // moving the owner of the bytes, e.g. when a `Vec` of keys grows, does not copy them
+    let secret = SecretBytes::<16>::from_slice(&[0xcd; 16]);
+    let ptr = secret.as_bytes().as_ptr();
+    let mut keys = vec![secret];
+    keys.reserve(keys.capacity() * 4);
+    assert_eq!(keys[0].as_bytes().as_ptr(), ptr);
+}
+
+/// Spawns a proxy for a single connection to `relay`, which passes all cells on and sends the
+/// cell with the index received on the returned channel to the relay once more.
+async fn spawn_replaying_proxy(relay: &Peer) -> Result<(Peer, mpsc::UnboundedSender<usize>)> {
+    let addr: SocketAddr = (TEST_IP, PORT_COUNTER.fetch_add(1, Ordering::Relaxed)).into();
+    let listener = TcpListener::bind(addr).await?;
+    let relay_addr = relay.addr;
+    let (replay_tx, mut replay_rx) = mpsc::unbounded_channel::<usize>();
+    tokio::spawn(async move {
+        let (client, _) = listener.accept().await?;
+        let (mut client_rd, mut client_wr) = client.into_split();
+        let (mut relay_rd, mut relay_wr) = TcpStream::connect(relay_addr).await?.into_split();
+        tokio::spawn(async move { tokio::io::copy(&mut relay_rd, &mut client_wr).await });
+        let (cells_tx, mut cells_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut cell = vec![0u8; MESSAGE_SIZE];
+            while client_rd.read_exact(&mut cell).await.is_ok()
+                && cells_tx.send(cell.clone()).is_ok()
+            {}
+        });
+        let mut cells: Vec<Vec<u8>> = Vec::new();
+        loop {
+            let cell = tokio::select! {
+                // a replay is sent before any later cell
+                biased;
+                Some(index) = replay_rx.recv() => cells[index].clone(),
+                Some(cell) = cells_rx.recv() => {
+                    cells.push(cell);
+                    cells[cells.len() - 1].clone()
+                }
+                else => break,
+            };
+            relay_wr.write_all(&cell).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+    Ok((Peer::new(addr, relay.hostkey.clone()), replay_tx))
 }
 
 #[tokio::test]
-async fn test_keep_alive() -> Result<()> {
-    let peers = spawn_n_peers(3).await;
-    let mut tunnel = Tunnel::init(0, &peers[0]).await?;
-    for i in 1..3 {
-        tunnel.extend(&peers[i]).await?;
+async fn test_replayed_cell() -> Result<()> {
+    let new = CipherSuites::all();
+    let old = new.with_version(HandshakeVersion::ResponderNonce);
+    for &(suites, replay_accepted) in &[(old, true), (new, false)] {
+        let relays = spawn_n_relays_with_suites(2, suites).await;
+        let (proxy, replay_tx) = spawn_replaying_proxy(&relays[0]).await?;
+        let mut tunnel = Tunnel::init(0, &proxy, CellSize::Standard, new).await?;
+        tunnel.extend(&relays[1]).await?;
+        // sends the cells 2 and 3, the latter an echo relayed by the first hop to the second
+        assert_eq!(tunnel.diagnose(ERROR_TIMEOUT).await, None);
+
+        replay_tx.send(3)?;
+        // drops the answer to the replayed echo, if there is one
+        assert_eq!(tunnel.diagnose(ERROR_TIMEOUT).await, None);
+        // the first hop tore the circuit down if it checks the sequence numbers
+        assert_eq!(tunnel.truncate(1).await.is_ok(), replay_accepted);
     }
-    assert_eq!(tunnel.len(), 3);
-    tunnel.keep_alive().await?;
-    assert_eq!(tunnel.len(), 3);
     Ok(())
 }
 
-#[tokio::test]
-#[ignore = "takes very long to complete"]
-async fn test_timeout() -> Result<()> {
-    let peers = spawn_n_peers(3).await;
-    let mut tunnel = Tunnel::init(0, &peers[0]).await?;
-    for i in 1..2 {
-        tunnel.extend(&peers[i]).await?;
+/// Pins the output of the backends. The OpenSSL and RustCrypto backends have to be compatible on
+/// the wire. The ring backend derives the same keys, but encrypts with the key stream of
+/// AES-128-GCM instead of AES-128-CTR, which starts at the third block of its 12 byte nonce.
+#[test]
+fn test_crypto_vectors() -> Result<()> {
+    let secret = (0u8..16).collect::<Vec<_>>();
+    let key = SessionKey::from_bytes(&secret)?;
+    let mut data = *b"allium onion routing";
+    key.encrypt(Direction::Forward, [7; crypto::NONCE_LEN], &mut data)?;
+    #[cfg(not(feature = "crypto_ring"))]
+    let expected = [
+        0x98, 0xe3, 0x9d, 0xd5, 0x7d, 0x33, 0xb8, 0x55, 0x06, 0xf7, 0x27, 0x35, 0xda, 0xbe, 0x12,
+        0x68, 0x31, 0x8b, 0xe0, 0x53,
+    ];
+    #[cfg(feature = "crypto_ring")]
+    let expected = [
+        0xf0, 0x20, 0x38, 0xb4, 0x14, 0xd6, 0xe0, 0x56, 0x99, 0x22, 0x4d, 0xad, 0x3f, 0xcb, 0x87,
+        0x0c, 0x42, 0xad, 0x24, 0x6d,
+    ];
+    assert_eq!(data, expected);
+    key.decrypt(Direction::Forward, [7; crypto::NONCE_LEN], &mut data)?;
+    assert_eq!(&data, b"allium onion routing");
+
+    assert_eq!(
+        key.mac(Direction::Forward, b"allium").as_ref(),
+        [
+            0x31, 0xa0, 0x72, 0xd8, 0xce, 0x42, 0xd7, 0x36, 0x33, 0x4d, 0xe4, 0x9b, 0x83, 0x45,
+            0x3e, 0x81, 0x55, 0xb5, 0xb9, 0x0a, 0x92, 0x36, 0x6b, 0x06, 0xe1, 0x6b, 0xab, 0xcb,
+            0x9a, 0xa0, 0x84, 0x26
+        ]
+    );
+    assert_eq!(
+        key.mac(Direction::Backward, b"allium").as_ref(),
+        [
+            0xa7, 0x9d, 0x21, 0xd3, 0xa8, 0xa2, 0xfd, 0xf2, 0x41, 0x02, 0x0e, 0x4c, 0x31, 0xa4,
+            0xa8, 0x79, 0x77, 0x14, 0xf0, 0xf2, 0x64, 0x6e, 0x9e, 0x76, 0xab, 0xce, 0x84, 0x19,
+            0x33, 0xd9, 0x38, 0x35
+        ]
+    );
+
+    // peers are identified by the digest of the DER encoding of their public key
+    let (_, public_key) = read_rsa_keypair("testkey.pem")?;
+    assert_eq!(
+        public_key.fingerprint(),
+        [
+            0x30, 0x3e, 0x3d, 0x74, 0x3e, 0x0b, 0x51, 0xc4, 0xd7, 0xa0, 0x41, 0x4c, 0xac, 0x08,
+            0xcf, 0x55, 0xfa, 0x93, 0x13, 0xf2, 0x87, 0x7b, 0x45, 0x1d, 0x88, 0x8e, 0xc9, 0x80,
+            0x8d, 0x65, 0x96, 0x66
+        ]
+    );
+    Ok(())
+}
+
+/// Pins the keys derived from [`HandshakeVersion::DirectionalKeys`] on, i.e. the HKDF-Expand
+/// outputs for each label, by the output of the cipher and the MAC of each direction.
+#[test]
+fn test_directional_key_vectors() -> Result<()> {
+    let secret = (0u8..32).collect::<Vec<_>>();
+    let salt = (32u8..64).collect::<Vec<_>>();
+    let key = SessionKey::from_shared_secret(
+        &secret,
+        CipherSuite::Hmac,
+        Some(&salt),
+        HandshakeVersion::DirectionalKeys,
+    )?;
+    #[cfg(not(feature = "crypto_ring"))]
+    let expected = [
+        [
+            0xec, 0xa1, 0x05, 0xad, 0x9c, 0x11, 0x6b, 0xc6, 0xc4, 0xda, 0x82, 0xdd, 0xfe, 0xef,
+            0x1d, 0x5f, 0x3c, 0x36, 0xeb, 0xaa,
+        ],
+        [
+            0x07, 0x5d, 0x78, 0x30, 0x56, 0x56, 0xc1, 0x95, 0xf9, 0x92, 0xa2, 0x89, 0x8c, 0x97,
+            0x90, 0xe9, 0xc8, 0x53, 0x53, 0x5d,
+        ],
+    ];
+    #[cfg(feature = "crypto_ring")]
+    let expected = [
+        [
+            0x7b, 0x78, 0xf6, 0x0b, 0x91, 0xaa, 0xdf, 0x44, 0xef, 0x57, 0x34, 0x19, 0x38, 0xf3,
+            0xf0, 0xba, 0xff, 0xc5, 0x3b, 0xde,
+        ],
+        [
+            0xd4, 0x0d, 0x8a, 0xd6, 0xfc, 0xbe, 0x08, 0x24, 0x40, 0x0c, 0xbb, 0xbe, 0x83, 0x01,
+            0x76, 0x47, 0x4a, 0xee, 0x0b, 0xd7,
+        ],
+    ];
+    let mut forward = *b"allium onion routing";
+    key.encrypt(Direction::Forward, [7; crypto::NONCE_LEN], &mut forward)?;
+    assert_eq!(forward, expected[0]);
+    let mut backward = *b"allium onion routing";
+    key.encrypt(Direction::Backward, [7; crypto::NONCE_LEN], &mut backward)?;
+    assert_eq!(backward, expected[1]);
+    // a cell only decrypts with the key of the direction it was encrypted for
+    key.decrypt(Direction::Backward, [7; crypto::NONCE_LEN], &mut forward)?;
+    assert_ne!(&forward, b"allium onion routing");
+    key.decrypt(Direction::Backward, [7; crypto::NONCE_LEN], &mut backward)?;
+    assert_eq!(&backward, b"allium onion routing");
+
+    assert_eq!(
+        key.mac(Direction::Forward, b"allium").as_ref(),
+        [
+            0x2d, 0xec, 0x58, 0xd0, 0xbe, 0x4b, 0x1d, 0x84, 0xda, 0x8c, 0x8b, 0xb5, 0x0c, 0x80,
+            0x36, 0xee, 0xba, 0xda, 0xfd, 0xba, 0x4d, 0x48, 0xd9, 0x2a, 0x7c, 0x48, 0x38, 0xf5,
+            0xe3, 0x88, 0x0b, 0x4e
+        ]
+    );
+    assert_eq!(
+        key.mac(Direction::Backward, b"allium").as_ref(),
+        [
+            0x21, 0xf5, 0x3b, 0x61, 0xea, 0x85, 0xd9, 0x2a, 0x54, 0x2e, 0xbd, 0xc4, 0xcb, 0xdd,
+            0xc3, 0x51, 0x5b, 0xc9, 0x1f, 0x6f, 0x99, 0xd8, 0xc2, 0x60, 0x9c, 0x76, 0x0f, 0xcb,
+            0x82, 0x82, 0x3e, 0x4c
+        ]
+    );
+
+    // earlier versions keep sharing the cipher key between both directions
+    let key = SessionKey::from_shared_secret(
+        &secret,
+        CipherSuite::Hmac,
+        Some(&salt),
+        HandshakeVersion::FeatureFlags,
+    )?;
+    let mut data = *b"allium onion routing";
+    key.encrypt(Direction::Forward, [7; crypto::NONCE_LEN], &mut data)?;
+    key.decrypt(Direction::Backward, [7; crypto::NONCE_LEN], &mut data)?;
+    assert_eq!(&data, b"allium onion routing");
+    Ok(())
+}
+
+#[test]
+fn test_latency_histogram() {
+    let histogram = Histogram::default();
+    assert_eq!(histogram.snapshot().p50(), None);
+    for micros in 1..=1000 {
+        histogram.record(Duration::from_micros(micros));
     }
-    assert_eq!(tunnel.len(), 2);
+    histogram.record(Duration::from_secs(3600));
+    let snapshot = histogram.snapshot();
+    assert_eq!(snapshot.count(), 1001);
+    // each percentile is reported as the bound of its bucket, which is at most 12.5 % higher
+    for &(percentage, expected) in &[(0.0, 1), (50.0, 501), (95.0, 951), (99.0, 991)] {
+        let reported = snapshot.percentile(percentage).unwrap().as_micros() as f64;
+        assert!(
+            reported >= expected as f64 && reported <= expected as f64 * 1.125,
+            "{} % reported as {} µs instead of {} µs",
+            percentage,
+            reported,
+            expected
+        );
+    }
+    // an outlier beyond the last bucket is still counted
+    assert!(snapshot.percentile(100.0).unwrap() > Duration::from_secs(60));
 
-    let tunnel_id = tunnel.id;
-    let peer_provider = PeerProvider::from_stream(stream::iter(vec![peers[2].clone()]));
-    let builder = TunnelBuilder::new(tunnel.id, Target::Peer(peers[1].clone()), 1, peer_provider);
+    histogram.reset();
+    assert_eq!(histogram.snapshot(), Default::default());
+}
 
-    let (events_tx, events_rx) = broadcast::channel(1);
-    let (ready_tx, ready_rx) = oneshot::channel();
-    let mut handler = TunnelHandler::new(tunnel, builder, events_rx, ready_tx);
+#[tokio::test]
+async fn test_handshake_limiter() {
+    let limiter = HandshakeLimiter::new(2);
+    let (busy, idle) = ([1; 32], [2; 32]);
+    let first = limiter.acquire(busy).await;
+    let second = limiter.acquire(busy).await;
+    let other = limiter.acquire(idle).await;
 
-    let handler_task = tokio::spawn({
-        async move {
-            handler.handle().await;
-        }
+    // a third handshake with the same peer waits, even when the attempt is given up
+    let cancelled = time::timeout(Duration::from_millis(50), limiter.acquire(busy)).await;
+    assert!(cancelled.is_err());
+    let waiting = tokio::spawn({
+        let limiter = limiter.clone();
+        async move { limiter.acquire(busy).await }
     });
+    time::sleep(Duration::from_millis(10)).await;
+    let mut snapshot = limiter.snapshot();
+    snapshot.sort_by_key(|peer| peer.fingerprint);
+    let counts = snapshot
+        .iter()
+        .map(|peer| (peer.fingerprint, peer.in_flight, peer.waiting))
+        .collect::<Vec<_>>();
+    assert_eq!(counts, [(busy, 2, 1), (idle, 1, 0)]);
 
-    events_tx.send(Event::Switchover).unwrap();
-    let ready = time::timeout(ERROR_TIMEOUT, ready_rx)
-        .await
-        .unwrap()
-        .unwrap()
-        .unwrap();
-    assert_eq!(ready.id(), tunnel_id);
+    drop(first);
+    let third = waiting.await.unwrap();
+    drop((second, third, other));
+    assert!(limiter.snapshot().is_empty());
+}
 
-    let delay = time::sleep(circuit::IDLE_TIMEOUT + ERROR_TIMEOUT);
+#[tokio::test]
+async fn test_accept_opaque_cancelled() -> Result<()> {
+    let keys = [SessionKey::from_bytes(&[0; 16])?];
+    let mut sent = Vec::new();
+    let mut sender = OnionSocket::new(&mut sent);
+    sender.send_keep_alive(1, &keys).await?;
+    sender.send_keep_alive(2, &keys).await?;
+    drop(sender);
+
+    let (mut tx, rx) = tokio::io::duplex(2 * CellSize::default().bytes());
+    let mut socket = OnionSocket::new(rx);
+    socket.open_circuit(1);
+    socket.open_circuit(2);
+    // drop a read while only part of the first message has arrived
+    tx.write_all(&sent[..100]).await?;
+    let pending = time::timeout(Duration::from_millis(50), socket.accept_opaque()).await;
+    assert!(pending.is_err());
+
+    tx.write_all(&sent[100..]).await?;
+    assert_eq!(socket.accept_opaque().await?.circuit_id, 1);
+    assert_eq!(socket.accept_opaque().await?.circuit_id, 2);
+    Ok(())
+}
+
+/// Returns a deterministic generator which only draws from `range` ids, forcing collisions.
+fn seeded_ids(seed: u32, range: u16) -> impl FnMut() -> circuit::CircuitId {
+    let mut state = seed;
+    move || {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u16 % range
+    }
+}
+
+#[test]
+fn test_circuit_id_collisions() {
+    let mut ids = CircuitIds::default();
+    let mut rng = seeded_ids(42, 4);
+    let mut allocated: Vec<_> = (0..4)
+        .map(|_| ids.allocate_with(&mut rng).unwrap())
+        .collect();
+    allocated.sort_unstable();
+    assert_eq!(allocated, vec![0, 1, 2, 3]);
+
+    // the generator only yields used ids, so the lowest free id is allocated
+    assert_eq!(ids.allocate_with(&mut rng), Some(4));
+    assert!(!ids.insert(2));
+
+    ids.release(2);
+    assert!(!ids.contains(2));
+    let mut rng = seeded_ids(7, 5);
+    assert_eq!(ids.allocate_with(&mut rng), Some(2));
+}
+
+#[test]
+fn test_tunnel_id_collisions() {
+    let registry = TunnelRegistry::default();
+    let mut ids = vec![7, 7, 7, 9].into_iter();
+    assert_eq!(registry.allocate_id_with(|| ids.next().unwrap()), Some(7));
+    // an id in use is drawn again instead of being shared by two tunnels
+    assert_eq!(registry.allocate_id_with(|| ids.next().unwrap()), Some(9));
+    assert_eq!(registry.allocate_id_with(|| 7), None);
+
+    registry.release(7);
+    assert_eq!(registry.allocate_id_with(|| 7), Some(7));
+}
+
+#[test]
+fn test_seeded_random() {
+    let (mut a, mut b, mut c) = (
+        SeededRandom::new(1),
+        SeededRandom::new(1),
+        SeededRandom::new(2),
+    );
+    let (mut buf_a, mut buf_b, mut buf_c) = ([0u8; 40], [0u8; 40], [0u8; 40]);
+    a.fill_bytes(&mut buf_a);
+    b.fill_bytes(&mut buf_b);
+    c.fill_bytes(&mut buf_c);
+    assert_eq!(buf_a, buf_b);
+    assert_ne!(buf_a, buf_c);
+    assert_ne!(buf_a[..8], buf_a[32..]);
+
+    // a clone continues where the original is
+    let mut clone = a.box_clone();
+    a.fill_bytes(&mut buf_a);
+    clone.fill_bytes(&mut buf_b);
+    assert_eq!(buf_a, buf_b);
+}
+
+#[test]
+fn test_reproducible_ids() {
+    let factory = RngFactory::new(|| Box::new(SeededRandom::new(42)));
+    let expected_tunnel_ids: Vec<_> = {
+        let mut rng = SeededRandom::new(42);
+        (0..3).map(|_| tunnel::random_id(&mut rng)).collect()
+    };
+    for _ in 0..2 {
+        let registry = TunnelRegistry {
+            rng: factory.shared(),
+            ..Default::default()
+        };
+        let ids: Vec<_> = (0..3).map(|_| registry.allocate_id().unwrap()).collect();
+        assert_eq!(ids, expected_tunnel_ids);
+    }
+
+    let expected_circuit_ids: Vec<_> = {
+        let mut rng = SeededRandom::new(42);
+        (0..3).map(|_| Circuit::random_id(&mut rng)).collect()
+    };
+    for _ in 0..2 {
+        let mut circuit_ids = CircuitIds::new(factory.create());
+        let ids: Vec<_> = (0..3).map(|_| circuit_ids.allocate().unwrap()).collect();
+        assert_eq!(ids, expected_circuit_ids);
+    }
+}
+
+#[tokio::test]
+async fn test_accept_opaque_unknown_circuit() -> Result<()> {
+    let keys = [SessionKey::from_bytes(&[0; 16])?];
+    let mut sent = Vec::new();
+    let mut sender = OnionSocket::new(&mut sent);
+    sender.send_keep_alive(2, &keys).await?;
+    sender.send_keep_alive(1, &keys).await?;
+    sender.send_keep_alive(1, &keys).await?;
+    drop(sender);
+
+    let (mut tx, rx) = tokio::io::duplex(4 * CellSize::default().bytes());
+    let mut socket = OnionSocket::new(rx);
+    socket.open_circuit(1);
+    tx.write_all(&sent).await?;
+    let e = match socket.accept_opaque().await {
+        Err(e) => e,
+        Ok(_) => panic!("message for unknown circuit accepted"),
+    };
+    assert!(matches!(e.kind, SocketErrorKind::UnknownCircuit(2)));
+    assert_eq!(e.connection, socket.connection());
+    assert_eq!(socket.accept_opaque().await?.circuit_id, 1);
+
+    // ids are freed on teardown
+    socket.teardown(1, EndReason::Normal).await?;
+    let e = match socket.accept_opaque().await {
+        Err(e) => e,
+        Ok(_) => panic!("message for closed circuit accepted"),
+    };
+    assert!(matches!(e.kind, SocketErrorKind::UnknownCircuit(1)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socket_error_connection() -> Result<()> {
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr: SocketAddr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(&peer_addr).await?;
+    tokio::spawn(async move {
+        // the peer hangs up instead of answering the handshake
+        while let Ok((stream, _)) = listener.accept().await {
+            drop(stream);
+        }
+    });
+
+    let mut connections = Vec::new();
+    for _ in 0..2 {
+        let stream = TcpStream::connect(peer_addr).await?;
+        let local_addr = stream.local_addr()?;
+        let mut socket = OnionSocket::from_stream(stream.into());
+        let (_, key) = crypto::generate_ephemeral_keypair();
+        let e = match socket
+            .initiate_handshake(
+                key,
+                CellSize::default(),
+                CipherSuites::all(),
+                FeatureOffer::for_suites(CipherSuites::all()),
+            )
+            .await
+        {
+            Err(e) => e,
+            Ok(_) => panic!("handshake with closed connection succeeded"),
+        };
+        assert!(matches!(e.kind, SocketErrorKind::StreamTerminated(_)));
+        assert_eq!(e.connection, socket.connection());
+        assert_eq!(e.connection.peer_addr, Some(peer_addr));
+        assert_eq!(e.connection.local_addr, Some(local_addr));
+        assert!(e.to_string().contains(&peer_addr.to_string()));
+        connections.push(e.connection.id);
+    }
+    assert!(connections[0] < connections[1]);
+    Ok(())
+}
+
+#[test]
+fn test_observer_reentrancy() {
+    struct Reentrant(std::sync::Mutex<Option<bool>>);
+
+    impl onion::StateObserver for Reentrant {
+        fn on_circuit_accepted(&self, _peer_addr: SocketAddr) {
+            let rejected = std::panic::catch_unwind(observer::debug_assert_not_observing).is_err();
+            *self.0.lock().unwrap() = Some(rejected);
+        }
+    }
+
+    let reentrant = Arc::new(Reentrant(Default::default()));
+    let observer = Observer::new(reentrant.clone());
+    observer.circuit_accepted((TEST_IP, 0).into());
+    assert_eq!(*reentrant.0.lock().unwrap(), Some(cfg!(debug_assertions)));
+    // the assertion only applies during the callback
+    observer::debug_assert_not_observing();
+}
+
+#[tokio::test]
+async fn test_direct_tunnel() -> Result<()> {
+    let peers = spawn_n_peers(1).await;
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[0].clone()), 0, peer_provider);
+    let mut tunnel = builder.build().await?;
+    assert_eq!(tunnel.len(), 1);
+
+    assert!(matches!(
+        tunnel.extend(&peers[0]).await,
+        Err(TunnelError::Direct)
+    ));
+    assert!(matches!(tunnel.truncate(0).await, Err(TunnelError::Direct)));
+    assert!(matches!(
+        tunnel.truncate_to_length(1).await,
+        Err(TunnelError::Direct)
+    ));
+    assert_eq!(tunnel.len(), 1);
+    tunnel.begin().await?;
+    tunnel.keep_alive().await?;
+
+    // intermediate hops can not be constrained
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let options = TunnelOptions::new().set_first_hop(peers[0].fingerprint());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[0].clone()), 0, peer_provider)
+        .with_options(options, Default::default(), Default::default());
+    let error = builder.build().await.unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<HopSelectionError>(),
+        Some(&HopSelectionError::InvalidPosition { position: 0 })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_truncate_zero_peers() -> Result<()> {
+    let peers = spawn_n_peers(2).await;
+    let mut tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+    for i in 1..2 {
+        tunnel.extend(&peers[i]).await?;
+    }
+    match tunnel.truncate(0).await {
+        Err(TunnelError::Incomplete) => {
+            assert_eq!(tunnel.len(), 2);
+            Ok(())
+        }
+        _ => Err(anyhow!(
+            "Expected truncate to fail since it tries to truncate a non-existing tail"
+        )),
+    }
+}
+
+#[tokio::test]
+async fn test_truncate_one_peer() -> Result<()> {
+    let peers = spawn_n_peers(2).await;
+    let mut tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+    for i in 1..2 {
+        tunnel.extend(&peers[i]).await?;
+    }
+    // the first hop is never truncated, so a tunnel is never empty
+    assert!(matches!(
+        tunnel.truncate(2).await,
+        Err(TunnelError::Incomplete)
+    ));
+    assert_eq!(tunnel.len(), 2);
+    tunnel.truncate(1).await?;
+    assert_eq!(tunnel.len(), 1);
+    assert!(matches!(
+        tunnel.truncate(1).await,
+        Err(TunnelError::Incomplete)
+    ));
+    assert_eq!(tunnel.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_truncate_to_length() -> Result<()> {
+    let mut tunnel = build_tunnel_n_peers(3).await?;
+    assert!(matches!(
+        tunnel.truncate_to_length(0).await,
+        Err(TunnelError::Incomplete)
+    ));
+    tunnel.truncate_to_length(3).await?;
+    assert_eq!(tunnel.len(), 3);
+    tunnel.truncate_to_length(1).await?;
+    assert_eq!(tunnel.len(), 1);
+    tunnel.keep_alive().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_truncate_two_peers() -> Result<()> {
+    let peers = spawn_n_peers(3).await;
+    let mut tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+    for i in 1..3 {
+        tunnel.extend(&peers[i]).await?;
+    }
+    assert_eq!(tunnel.len(), 3);
+    tunnel.truncate(2).await?;
+    assert_eq!(tunnel.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handshake_mixed_key_types() -> Result<()> {
+    let rsa = spawn_n_relays(2).await;
+    let ed25519 = spawn_n_ed25519_relays(2).await;
+    // a relay which does not announce the type of its key, like peers predating Ed25519 keys
+    let old = spawn_n_relays_with_suites(
+        1,
+        CipherSuites::all().with_version(HandshakeVersion::SequencedNonces),
+    )
+    .await;
+
+    let path = [&ed25519[0], &rsa[0], &old[0], &ed25519[1], &rsa[1]];
+    let mut tunnel = Tunnel::init(0, path[0], CellSize::Standard, CipherSuites::all()).await?;
+    for peer in &path[1..] {
+        tunnel.extend(peer).await?;
+    }
+    assert_eq!(tunnel.len(), path.len());
+    assert_eq!(tunnel.diagnose(ERROR_TIMEOUT).await, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_extend_key_type_mismatch() -> Result<()> {
+    let rsa = spawn_n_relays(1).await;
+    let ed25519 = spawn_n_ed25519_relays(1).await;
+    let mut tunnel = Tunnel::init(0, &rsa[0], CellSize::Standard, CipherSuites::all()).await?;
+
+    // a Ed25519 relay can not pass as the RSA relay we expect, nor the other way around
+    let (_, rsa_key) = read_rsa_keypair("testkey.pem")?;
+    let impostor = Peer::new(ed25519[0].addr, rsa_key);
+    assert!(matches!(
+        tunnel.extend(&impostor).await,
+        Err(TunnelError::KeyDerivation)
+    ));
+    let (_, ed25519_key) = read_ed25519_keypair("tests/ed25519key.pem")?;
+    let impostor = Peer::new(rsa[0].addr, ed25519_key);
+    assert!(matches!(
+        tunnel.extend(&impostor).await,
+        Err(TunnelError::KeyDerivation)
+    ));
+    assert_eq!(tunnel.len(), 1);
+
+    tunnel.extend(&ed25519[0]).await?;
+    assert_eq!(tunnel.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_extend_key_derivation_failure() -> Result<()> {
+    let peers = spawn_n_peers(3).await;
+    let mut tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+
+    // the peer can not prove the identity we expect, so the extension is truncated again
+    let other_key = RsaPrivateKey::from_pem_file("tests/smallkey.pem")?.public_key();
+    let impostor = Peer::new(peers[1].addr, other_key);
+    assert!(matches!(
+        tunnel.extend(&impostor).await,
+        Err(TunnelError::KeyDerivation)
+    ));
+    assert_eq!(tunnel.len(), 1);
+
+    tunnel.extend(&peers[2]).await?;
+    assert_eq!(tunnel.len(), 2);
+    assert_eq!(tunnel.diagnose(ERROR_TIMEOUT).await, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_diagnose_responsive_path() -> Result<()> {
+    let mut tunnel = build_tunnel_n_peers(3).await?;
+    assert_eq!(tunnel.diagnose(Duration::from_secs(2)).await, None);
+    // the tunnel is still usable after it has been probed
+    tunnel.truncate(1).await?;
+    assert_eq!(tunnel.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_diagnose_silent_hop() -> Result<()> {
+    let relays = spawn_n_relays(1).await;
+    // completes the handshake, but never answers on the circuit
+    let silent = spawn_failing_peer(Duration::from_secs(10), None).await;
+    let mut tunnel = Tunnel::init(0, &relays[0], CellSize::Standard, CipherSuites::all()).await?;
+    tunnel.extend(&silent).await?;
+
+    let started = time::Instant::now();
+    assert_eq!(tunnel.diagnose(Duration::from_secs(2)).await, Some(1));
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // an exhausted budget does not blame the hop
+    assert_eq!(tunnel.diagnose(Duration::from_millis(100)).await, None);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "broken"]
+async fn test_data_unidirectional() -> Result<()> {
+    let (host_key, peer_key) = read_rsa_keypair("testkey.pem").unwrap();
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr = (TEST_IP, peer_port).into();
+    let peer = Peer::new(peer_addr, peer_key);
+
+    let (incoming_tx, mut incoming_rx) = mpsc::channel(100);
+    tokio::spawn({
+        let mut listener = OnionListener::new(
+            host_key,
+            incoming_tx,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        let tcp_listener = TcpListener::bind(peer_addr).await?;
+        async move { listener.listen(tcp_listener).await }
+    });
+
+    let (evt_tx, _) = broadcast::channel(1);
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let ctx = OnionContext::new(
+        Handle::current(),
+        evt_tx.clone(),
+        peer_provider,
+        ContextSettings {
+            stall_threshold: STALL_THRESHOLD,
+            max_handshakes_per_peer: 4,
+            shutdown_timeout: Duration::from_secs(10),
+            ..Default::default()
+        },
+        &Default::default(),
+    );
+
+    let send_tunnel = ctx.build_tunnel(peer).await.unwrap(); // FIXME task
+    evt_tx.send(Event::Switchover).unwrap();
+    let mut recv_tunnel = incoming_rx.recv().await.unwrap();
+
+    let data = Bytes::from_static(b"test");
+    send_tunnel.write(data.clone()).unwrap();
+    assert_eq!(recv_tunnel.read().await.unwrap(), data);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "broken"]
+async fn test_data_bidirectional() -> Result<()> {
+    let (host_key, peer_key) = read_rsa_keypair("testkey.pem").unwrap();
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr = (TEST_IP, peer_port).into();
+    let peer = Peer::new(peer_addr, peer_key);
+
+    let data_ping = Bytes::from_static(b"ping");
+    let data_pong = Bytes::from_static(b"pong");
+
+    let (incoming_tx, mut incoming_rx) = mpsc::channel(100);
+    tokio::spawn({
+        let mut listener = OnionListener::new(
+            host_key,
+            incoming_tx,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        let tcp_listener = TcpListener::bind(peer_addr).await?;
+        async move { listener.listen(tcp_listener).await }
+    });
+
+    tokio::spawn({
+        let data_ping = data_ping.clone();
+        let data_pong = data_pong.clone();
+
+        async move {
+            let mut tunnel = incoming_rx.recv().await.unwrap();
+            while let Ok(data) = tunnel.read().await {
+                println!("{:?}", &data);
+                assert_eq!(data, data_ping);
+                tunnel.write(data_pong.clone()).unwrap();
+            }
+        }
+    });
+
+    let (evt_tx, _) = broadcast::channel(1);
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let ctx = OnionContext::new(
+        Handle::current(),
+        evt_tx.clone(),
+        peer_provider,
+        ContextSettings {
+            stall_threshold: STALL_THRESHOLD,
+            max_handshakes_per_peer: 4,
+            shutdown_timeout: Duration::from_secs(10),
+            ..Default::default()
+        },
+        &Default::default(),
+    );
+
+    let mut tunnel = ctx.build_tunnel(peer).await.unwrap(); // FIXME task
+    evt_tx.send(Event::Switchover).unwrap();
+
+    tunnel.write(data_ping).unwrap();
+    assert_eq!(tunnel.read().await.unwrap(), data_pong);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keep_alive() -> Result<()> {
+    let peers = spawn_n_peers(3).await;
+    let mut tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+    for i in 1..3 {
+        tunnel.extend(&peers[i]).await?;
+    }
+    assert_eq!(tunnel.len(), 3);
+    tunnel.keep_alive().await?;
+    assert_eq!(tunnel.len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "takes very long to complete"]
+async fn test_timeout() -> Result<()> {
+    let peers = spawn_n_peers(3).await;
+    let mut tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+    for i in 1..2 {
+        tunnel.extend(&peers[i]).await?;
+    }
+    assert_eq!(tunnel.len(), 2);
+
+    let tunnel_id = tunnel.id;
+    let peer_provider = PeerProvider::from_stream(stream::iter(vec![peers[2].clone()]));
+    let builder = TunnelBuilder::new(tunnel.id, Target::Peer(peers[1].clone()), 1, peer_provider);
+
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let policy = RotationPolicy::default();
+    let (notify, _) = broadcast::channel(1);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        policy,
+        STALL_THRESHOLD,
+        notify,
+    );
+
+    let handler_task = tokio::spawn({
+        async move {
+            handler.handle().await;
+        }
+    });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let ready = time::timeout(ERROR_TIMEOUT, ready_rx)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(ready.id(), tunnel_id);
+
+    let delay = time::sleep(circuit::IDLE_TIMEOUT + ERROR_TIMEOUT);
     tokio::pin!(delay);
     tokio::select! {
         _ = handler_task => Ok(()),
@@ -257,3 +1579,1819 @@ async fn test_timeout() -> Result<()> {
         },
     }
 }
+
+#[tokio::test]
+async fn test_rotation_deferred() -> Result<()> {
+    let peers = spawn_n_relays(1).await;
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[0].clone()), 0, peer_provider);
+    let tunnel = builder.build().await?;
+
+    let policy = RotationPolicy {
+        min_lifetime: ROUND_DURATION,
+        ..Default::default()
+    };
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        policy,
+        STALL_THRESHOLD,
+        notify,
+    );
+    tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+    assert_eq!(
+        notify_rx.recv().await?,
+        onion::Event::Ready {
+            tunnel_id: tunnel.id(),
+            cause: ReadyCause::Initial
+        }
+    );
+
+    // a second switchover right after the first one must not rotate the tunnel
+    time::sleep(Duration::from_secs(1)).await;
+    events_tx.send(Event::Switchover).unwrap();
+    time::sleep(Duration::from_millis(100)).await;
+    let stats = tunnel.stats();
+    assert_eq!(stats.deferred_rotations, 1);
+    assert_eq!(stats.rotations, 0);
+
+    // the deferred switchover is performed once the minimum lifetime has passed
+    time::sleep(ROUND_DURATION).await;
+    let stats = tunnel.stats();
+    assert_eq!(stats.deferred_rotations, 1);
+    assert_eq!(stats.rotations, 1);
+    match notify_rx.try_recv()? {
+        onion::Event::Rotated { old_path_age, .. } => assert!(old_path_age >= ROUND_DURATION),
+        evt => panic!("Expected a rotation event, got {:?}", evt),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handler_aborted() -> Result<()> {
+    let peers = spawn_n_relays(1).await;
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[0].clone()), 0, peer_provider);
+    let tunnel = builder.build().await?;
+
+    let registry = TunnelRegistry::default();
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        Default::default(),
+        STALL_THRESHOLD,
+        notify,
+    )
+    .with_registry(registry.clone());
+    let handle = tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+    assert!(matches!(
+        notify_rx.recv().await?,
+        onion::Event::Ready { .. }
+    ));
+    assert!(registry.path(tunnel.id()).is_some());
+
+    // the aborted handler is cleaned up as if it had closed the tunnel
+    handle.abort();
+    assert!(handle.await.unwrap_err().is_cancelled());
+    assert_eq!(
+        notify_rx.try_recv()?,
+        onion::Event::Closed {
+            tunnel_id: tunnel.id(),
+            reason: CloseReason::Internal,
+            end_reason: None,
+        }
+    );
+    assert!(registry.path(tunnel.id()).is_none());
+    assert!(tunnel.write(Bytes::from_static(b"data")).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown_gives_up_handler() -> Result<()> {
+    let peers = spawn_n_relays(1).await;
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[0].clone()), 0, peer_provider);
+    let tunnel = builder.build().await?;
+
+    let shutdown = Arc::new(Shutdown::default());
+    let registry = TunnelRegistry::default();
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        Default::default(),
+        STALL_THRESHOLD,
+        notify,
+    )
+    .with_shutdown(shutdown.enter()?)
+    .with_registry(registry.clone())
+    .spawn();
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+    assert!(matches!(
+        notify_rx.recv().await?,
+        onion::Event::Ready { .. }
+    ));
+
+    // the handler misses the shutdown event, as if it was stuck
+    shutdown.close();
+    let idle = time::timeout(Duration::from_millis(200), shutdown.wait_idle()).await;
+    assert!(idle.is_err());
+    shutdown.finish();
+    time::timeout(ERROR_TIMEOUT, shutdown.wait_idle()).await?;
+    assert_eq!(
+        notify_rx.try_recv()?,
+        onion::Event::Closed {
+            tunnel_id: tunnel.id(),
+            reason: CloseReason::Shutdown,
+            end_reason: None,
+        }
+    );
+    assert!(notify_rx.try_recv().is_err());
+    assert!(registry.path(tunnel.id()).is_none());
+    assert!(tunnel.write(Bytes::from_static(b"data")).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rebuild_backoff() -> Result<()> {
+    let peers = spawn_n_relays(1).await;
+    let tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+
+    // nobody listens on this port, so every rebuild fails
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let dead_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dead_peer = Peer::new((TEST_IP, dead_port).into(), peer_key);
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let builder = TunnelBuilder::new(0, Target::Peer(dead_peer), 0, peer_provider);
+
+    let policy = RotationPolicy {
+        rebuild_backoff: Duration::from_secs(2),
+        ..Default::default()
+    };
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, _) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        policy,
+        STALL_THRESHOLD,
+        notify,
+    );
+    tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+
+    time::sleep(Duration::from_millis(2500)).await;
+    let failed_rebuilds = tunnel.stats().failed_rebuilds;
+    assert!((1..=2).contains(&failed_rebuilds));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rotation_failed() -> Result<()> {
+    let peers = spawn_n_relays(1).await;
+    let tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let dead_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dead_peer = Peer::new((TEST_IP, dead_port).into(), peer_key);
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let builder = TunnelBuilder::new(0, Target::Peer(dead_peer), 0, peer_provider);
+
+    let policy = RotationPolicy {
+        rebuild_backoff: Duration::from_millis(10),
+        max_rebuild_attempts: 3,
+        ..Default::default()
+    };
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        policy,
+        STALL_THRESHOLD,
+        notify,
+    );
+    tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+    let tunnel_id = tunnel.id();
+
+    let mut failed = false;
+    while let Ok(evt) = time::timeout(ERROR_TIMEOUT, notify_rx.recv()).await? {
+        if evt == (onion::Event::RotationFailed { tunnel_id }) {
+            failed = true;
+            break;
+        }
+    }
+    assert!(failed);
+    assert_eq!(tunnel.stats().failed_rebuilds, 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unresponsive_path() -> Result<()> {
+    // completes the handshake, but never answers an echo
+    let silent = spawn_failing_peer(Duration::from_secs(30), None).await;
+    let tunnel = Tunnel::init(0, &silent, CellSize::Standard, CipherSuites::all()).await?;
+
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let dead_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dead_peer = Peer::new((TEST_IP, dead_port).into(), peer_key);
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let builder = TunnelBuilder::new(0, Target::Peer(dead_peer), 0, peer_provider);
+
+    let policy = RotationPolicy {
+        rebuild_backoff: Duration::from_millis(10),
+        max_rebuild_attempts: 1,
+        ..Default::default()
+    };
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        policy,
+        STALL_THRESHOLD,
+        notify,
+    );
+    tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+    let tunnel_id = tunnel.id();
+
+    // the first keep-alive sends an echo, the path is given up once the next three find it
+    // unanswered
+    for _ in 0..4 {
+        events_tx.send(Event::KeepAlive).unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    let closed = onion::Event::Closed {
+        tunnel_id,
+        reason: CloseReason::Unresponsive,
+        end_reason: None,
+    };
+    while time::timeout(ERROR_TIMEOUT, notify_rx.recv()).await?? != closed {}
+    assert_eq!(tunnel.stats().round_trip_time, None);
+    Ok(())
+}
+
+/// Writes `n` messages to `tunnel`, giving the handler time to send each of them as a single cell.
+async fn write_cells(tunnel: &onion::Tunnel, n: usize) {
+    for _ in 0..n {
+        let _ = tunnel.write(Bytes::from_static(b"test"));
+        time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_key_limit_rotation() -> Result<()> {
+    let peers = spawn_n_relays(1).await;
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[0].clone()), 0, peer_provider);
+    let tunnel = builder.build().await?;
+
+    let policy = RotationPolicy {
+        key_limits: KeyLimits {
+            rotate_after: 4,
+            ceiling: 100,
+        },
+        ..Default::default()
+    };
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        policy,
+        STALL_THRESHOLD,
+        notify,
+    );
+    tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+    notify_rx.recv().await?;
+
+    // the fifth cell is sent on a new path, without waiting for the round to end
+    write_cells(&tunnel, 4).await;
+    assert_eq!(tunnel.stats().rotations, 0);
+    write_cells(&tunnel, 1).await;
+    let stats = tunnel.stats();
+    assert_eq!(stats.key_limit_rotations, 1);
+    assert_eq!(stats.rotations, 1);
+    assert_eq!(stats.sent_cells, 5);
+    assert!(matches!(
+        notify_rx.try_recv()?,
+        onion::Event::Rotated { .. }
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rekey_interval() -> Result<()> {
+    let peers = spawn_n_relays(2).await;
+    let peer_provider = PeerProvider::from_stream(stream::iter(vec![peers[0].clone()]));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[1].clone()), 1, peer_provider);
+    let tunnel = builder.build().await?;
+
+    let policy = RotationPolicy {
+        rekey_interval: Duration::from_millis(200),
+        ..Default::default()
+    };
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        policy,
+        STALL_THRESHOLD,
+        notify,
+    );
+    tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+    notify_rx.recv().await?;
+
+    // the path is rebuilt through the same peers, the provider has no other relay to offer
+    assert!(matches!(
+        time::timeout(ERROR_TIMEOUT, notify_rx.recv()).await??,
+        onion::Event::Rotated { .. }
+    ));
+    write_cells(&tunnel, 1).await;
+    let stats = tunnel.stats();
+    assert!(stats.rekeys >= 1);
+    assert_eq!(stats.rotations, stats.rekeys);
+    assert_eq!(stats.sent_cells, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_key_limit_exhausted() -> Result<()> {
+    let peers = spawn_n_relays(1).await;
+    let tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+
+    // no path with fresh keys can be built
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let dead_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dead_peer = Peer::new((TEST_IP, dead_port).into(), peer_key);
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let builder = TunnelBuilder::new(0, Target::Peer(dead_peer), 0, peer_provider);
+
+    let policy = RotationPolicy {
+        max_rebuild_attempts: 1,
+        key_limits: KeyLimits {
+            rotate_after: 2,
+            ceiling: 4,
+        },
+        ..Default::default()
+    };
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        policy,
+        STALL_THRESHOLD,
+        notify,
+    );
+    tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+    let tunnel_id = tunnel.id();
+
+    // the path is kept after the failed replacement, until the ceiling is reached
+    let mut written = 0;
+    while tunnel.write(Bytes::from_static(b"test")).is_ok() {
+        written += 1;
+        assert!(written < 100, "tunnel not closed after {} cells", written);
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    let mut closed = None;
+    while let Ok(evt) = time::timeout(ERROR_TIMEOUT, notify_rx.recv()).await? {
+        if let onion::Event::Closed {
+            tunnel_id: id,
+            reason,
+            ..
+        } = evt
+        {
+            assert_eq!(id, tunnel_id);
+            closed = Some(reason);
+            break;
+        }
+    }
+    assert_eq!(closed, Some(CloseReason::KeysExhausted));
+    let stats = tunnel.stats();
+    assert_eq!(stats.key_limit_rotations, 0);
+    // the last batch may exceed the ceiling, no cell is sent once it is reached
+    assert!(stats.sent_cells >= 4 && stats.sent_cells < written);
+    Ok(())
+}
+
+#[test]
+fn test_destination_retries() {
+    let retries = DestinationRetries::default();
+    let fingerprint = [1; 32];
+    assert_eq!(retries.backoff(&fingerprint), None);
+
+    let first = retries.record_failure(fingerprint);
+    assert!(first >= Duration::from_millis(500) && first <= Duration::from_secs(1));
+    let second = retries.record_failure(fingerprint);
+    assert!(second >= Duration::from_secs(1) && second <= Duration::from_secs(2));
+    let backoff = retries.backoff(&fingerprint).unwrap();
+    assert_eq!(backoff.failures, 2);
+    assert!(backoff.retry_in <= second);
+
+    for _ in 0..20 {
+        assert!(retries.record_failure(fingerprint) <= Duration::from_secs(120));
+    }
+    retries.record_success(&fingerprint);
+    assert_eq!(retries.backoff(&fingerprint), None);
+}
+
+#[tokio::test]
+async fn test_destination_backoff() -> Result<()> {
+    let peers = spawn_n_relays(1).await;
+    let tunnel = Tunnel::init(0, &peers[0], CellSize::Standard, CipherSuites::all()).await?;
+
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let dead_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dead_peer = Peer::new((TEST_IP, dead_port).into(), peer_key);
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let registry = TunnelRegistry::default();
+    let builder = TunnelBuilder::new(0, Target::Peer(dead_peer), 0, peer_provider)
+        .with_retries(registry.retries.clone());
+
+    let policy = RotationPolicy {
+        rebuild_backoff: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, _) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder.clone(),
+        events_rx,
+        ready_tx,
+        policy,
+        STALL_THRESHOLD,
+        notify,
+    )
+    .with_registry(registry.clone());
+    tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+    time::sleep(Duration::from_millis(100)).await;
+
+    // the failed rebuild backs off instead of being retried after the rebuild backoff
+    let backoff = registry.retry_backoff(tunnel.id()).unwrap();
+    assert_eq!(backoff.failures, 1);
+    assert_eq!(tunnel.stats().failed_rebuilds, 1);
+
+    // builds requested by the application are not delayed
+    let mut builder = builder;
+    let started = time::Instant::now();
+    assert!(builder.build().await.is_err());
+    assert!(started.elapsed() < Duration::from_millis(400));
+    assert_eq!(registry.retry_backoff(tunnel.id()).unwrap().failures, 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_build_required_capabilities() -> Result<()> {
+    let required = Capabilities::from_bits(0b10);
+    let peers = spawn_n_relays(3).await;
+    let capable = peers[0]
+        .clone()
+        .with_capabilities(required | Capabilities::from_bits(0b1));
+    let incapable = peers[1]
+        .clone()
+        .with_capabilities(Capabilities::from_bits(0b1));
+    let dest = peers[2].clone().with_capabilities(required);
+    let options = TunnelOptions::new().require_capabilities(required);
+
+    // the incapable peer is skipped as an intermediate hop
+    let hops = vec![incapable.clone(), capable.clone()];
+    let peer_provider = PeerProvider::from_stream(stream::iter(hops.into_iter().cycle()));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest), 1, peer_provider).with_options(
+        options.clone(),
+        Default::default(),
+        Default::default(),
+    );
+    let tunnel = builder.build().await?;
+    assert_eq!(tunnel.len(), 2);
+
+    // no handshake is attempted with an incapable destination
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(incapable), 0, peer_provider)
+        .with_options(options, Default::default(), Default::default());
+    assert!(builder.build().await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_build_capability_mismatch() -> Result<()> {
+    // a peer advertising the required capabilities, which closes every connection
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr: SocketAddr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(peer_addr).await?;
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            accepted.fetch_add(1, Ordering::Relaxed);
+            drop(stream);
+        }
+    });
+    let (_, hostkey) = read_rsa_keypair("testkey.pem")?;
+    let required = Capabilities::from_bits(0b10);
+    let dest = Peer::new(peer_addr, hostkey).with_capabilities(required);
+    let options = TunnelOptions::new().require_capabilities(required);
+    let capabilities = CapabilityCache::default();
+
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 0, peer_provider)
+        .with_options(options.clone(), capabilities.clone(), Default::default());
+    assert!(builder.build().await.is_err());
+    let attempts = connections.load(Ordering::Relaxed);
+    assert!(attempts > 0);
+    assert!(!capabilities.may_support(&dest, required));
+
+    // the failed handshake overrides the stale advertisement, so no handshake is attempted
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 0, peer_provider)
+        .with_options(options, capabilities.clone(), Default::default());
+    assert!(builder.build().await.is_err());
+    assert_eq!(connections.load(Ordering::Relaxed), attempts);
+
+    // until the peer advertises different capabilities
+    let dest = dest.with_capabilities(required | Capabilities::from_bits(0b1));
+    assert!(capabilities.may_support(&dest, required));
+
+    // advertised capabilities are recorded even if none are required
+    let capabilities = CapabilityCache::default();
+    assert!(capabilities.may_support(&dest, Capabilities::empty()));
+    let mut unadvertised = dest;
+    unadvertised.capabilities = None;
+    assert!(capabilities.supports(&unadvertised, required));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_build_report() -> Result<()> {
+    let relays = spawn_n_relays(2).await;
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let dead_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dead_peer = Peer::new((TEST_IP, dead_port).into(), peer_key);
+
+    // the first hop is retried after the dead peer could not be reached
+    let hops = vec![dead_peer.clone(), relays[0].clone()];
+    let peer_provider = PeerProvider::from_stream(stream::iter(hops));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(relays[1].clone()), 1, peer_provider)
+        .with_build_reports(true);
+    builder.build().await?;
+    let report = builder.stats.build_report().unwrap();
+    let attempts: Vec<_> = report
+        .attempts()
+        .iter()
+        .map(|attempt| (attempt.hop, attempt.outcome))
+        .collect();
+    assert_eq!(
+        attempts,
+        vec![
+            (0, BuildOutcome::ConnectFailed),
+            (0, BuildOutcome::Ok),
+            (1, BuildOutcome::Ok)
+        ]
+    );
+    assert_eq!(report.attempts()[0].peer, dead_peer.fingerprint()[..4]);
+    assert_eq!(report.attempts()[2].peer, relays[1].fingerprint()[..4]);
+
+    // the report of a failed build is attached to the error
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dead_peer.clone()), 0, peer_provider)
+        .with_build_reports(true);
+    let error = builder.build().await.unwrap_err();
+    let report = error.downcast_ref::<BuildReport>().unwrap();
+    assert!(!report.attempts().is_empty());
+    assert!(report
+        .attempts()
+        .iter()
+        .all(|attempt| attempt.outcome == BuildOutcome::ConnectFailed));
+
+    // nothing is recorded by default
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dead_peer), 0, peer_provider);
+    let error = builder.build().await.unwrap_err();
+    assert!(error.downcast_ref::<BuildReport>().is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_no_consecutive_hops() -> Result<()> {
+    let relays = spawn_n_relays(3).await;
+    let dest = relays[2].clone();
+
+    // the same peer is never used for two consecutive hops
+    let peer_provider = PeerProvider::from_stream(stream::iter(iter::repeat(relays[0].clone())));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 2, peer_provider)
+        .with_build_reports(true);
+    let error = time::timeout(ERROR_TIMEOUT, builder.build())
+        .await?
+        .unwrap_err();
+    let report = error.downcast_ref::<BuildReport>().unwrap();
+    assert!(report.attempts().iter().all(|attempt| attempt.hop == 0));
+
+    // the destination is never an intermediate hop
+    let peer_provider = PeerProvider::from_stream(stream::iter(iter::repeat(dest.clone())));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 1, peer_provider)
+        .with_build_reports(true);
+    let error = time::timeout(ERROR_TIMEOUT, builder.build())
+        .await?
+        .unwrap_err();
+    let report = error.downcast_ref::<BuildReport>().unwrap();
+    assert!(report.attempts().is_empty());
+
+    // a peer may reappear on the path as long as it is not adjacent to itself
+    let hops = vec![relays[0].clone(), relays[0].clone(), relays[1].clone()];
+    let peer_provider = PeerProvider::from_stream(stream::iter(hops.into_iter().cycle()));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest), 2, peer_provider);
+    let tunnel = builder.build().await?;
+    let path: Vec<_> = (0..tunnel.len())
+        .map(|position| tunnel.hop(position).unwrap().addr)
+        .collect();
+    assert_eq!(path, vec![relays[0].addr, relays[1].addr, relays[2].addr]);
+    Ok(())
+}
+
+#[test]
+fn test_extend_policy_rules() {
+    let policy = ExtendPolicy::default();
+    assert!(!policy.allows("10.0.0.1:4000".parse().unwrap()));
+    assert!(!policy.allows("172.31.255.255:80".parse().unwrap()));
+    assert!(policy.allows("172.32.0.1:80".parse().unwrap()));
+    assert!(!policy.allows("[fd00::1]:4000".parse().unwrap()));
+    // mapped IPv4 addresses do not evade the IPv4 rules
+    assert!(!policy.allows("[::ffff:192.168.1.1]:4000".parse().unwrap()));
+    assert!(policy.allows("127.0.0.1:4000".parse().unwrap()));
+
+    // the first matching rule decides
+    let policy = ExtendPolicy::allow_all()
+        .allow("10.1.0.0/16".parse().unwrap(), 4000..=4001)
+        .deny("10.0.0.0/8".parse().unwrap(), ..)
+        .deny(AddressRange::any(), 25..26);
+    assert!(policy.allows("10.1.2.3:4001".parse().unwrap()));
+    assert!(!policy.allows("10.1.2.3:4002".parse().unwrap()));
+    assert!(!policy.allows("[2001:db8::1]:25".parse().unwrap()));
+    assert!(policy.allows("[2001:db8::1]:26".parse().unwrap()));
+
+    assert_eq!(
+        "192.168.0.0/16"
+            .parse::<AddressRange>()
+            .unwrap()
+            .to_string(),
+        "192.168.0.0/16"
+    );
+    assert_eq!(
+        "::1".parse::<AddressRange>().unwrap().to_string(),
+        "::1/128"
+    );
+    assert!("10.0.0.0/33".parse::<AddressRange>().is_err());
+    assert!("10.0.0/8".parse::<AddressRange>().is_err());
+}
+
+#[tokio::test]
+async fn test_extend_policy() -> Result<()> {
+    let counters = Arc::new(RelayCounters::default());
+    let relay = spawn_relay_with_policy(ExtendPolicy::default(), counters.clone()).await;
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let private_peer = Peer::new("10.0.0.1:4000".parse()?, peer_key);
+
+    // the relay refuses instead of connecting, the tunnel remains usable
+    let mut tunnel = Tunnel::init(0, &relay, CellSize::Standard, CipherSuites::all()).await?;
+    let extended = time::timeout(ERROR_TIMEOUT, tunnel.extend(&private_peer)).await?;
+    assert!(matches!(extended, Err(TunnelError::PolicyRefused)));
+    assert_eq!(tunnel.len(), 1);
+    tunnel.keep_alive().await?;
+    assert_eq!(counters.snapshot().refused_extends, 1);
+
+    // the refusal is attributed to the hop in the build report
+    let peer_provider = PeerProvider::from_stream(stream::iter(vec![relay]));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(private_peer), 1, peer_provider)
+        .with_build_reports(true);
+    let error = time::timeout(ERROR_TIMEOUT, builder.build())
+        .await?
+        .unwrap_err();
+    let report = error.downcast_ref::<BuildReport>().unwrap();
+    let refused: Vec<_> = report
+        .attempts()
+        .iter()
+        .filter(|attempt| attempt.hop == 1)
+        .map(|attempt| attempt.outcome)
+        .collect();
+    assert!(!refused.is_empty());
+    assert!(refused
+        .iter()
+        .all(|outcome| *outcome == BuildOutcome::PolicyRefused));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_build_timeouts() -> Result<()> {
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let silent_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let silent_addr: SocketAddr = (TEST_IP, silent_port).into();
+    let listener = TcpListener::bind(&silent_addr).await?;
+    tokio::spawn(async move {
+        // accept connections but never answer a handshake
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+    let silent_peer = Peer::new(silent_addr, peer_key);
+
+    // every hop attempt is cut short by the hop timeout
+    let timeouts = BuildTimeouts {
+        hop: Duration::from_millis(50),
+        deadline: Duration::from_secs(10),
+    };
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(silent_peer.clone()), 0, peer_provider)
+        .with_build_reports(true)
+        .with_timeouts(timeouts);
+    let error = builder.build().await.unwrap_err();
+    let report = error.downcast_ref::<BuildReport>().unwrap();
+    assert!(!report.attempts().is_empty());
+    assert!(report
+        .attempts()
+        .iter()
+        .all(|attempt| attempt.outcome == BuildOutcome::Timeout));
+
+    // the deadline bounds the whole build, retries included
+    let timeouts = BuildTimeouts {
+        hop: Duration::from_millis(100),
+        deadline: Duration::from_millis(250),
+    };
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder =
+        TunnelBuilder::new(0, Target::Peer(silent_peer), 0, peer_provider).with_timeouts(timeouts);
+    let start = time::Instant::now();
+    let error = builder.build().await.unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    let timed_out = error.downcast_ref::<BuildTimedOut>().unwrap();
+    assert_eq!(timed_out.deadline, Duration::from_millis(250));
+    Ok(())
+}
+
+/// Runs a handler whose first path fails after a short time and whose replacement paths are built
+/// to `dest`. Returns the events emitted by the handler after the tunnel became ready.
+async fn run_failing_path(teardown: Option<EndReason>, dest: Peer) -> Result<Vec<onion::Event>> {
+    let first_hop = spawn_failing_peer(Duration::from_millis(500), teardown).await;
+    run_tunnel_handler(first_hop, dest).await
+}
+
+/// Runs the handler of a tunnel through `first_hop` towards `dest` and returns the events it
+/// reports until it is quiet for two seconds.
+async fn run_tunnel_handler(first_hop: Peer, dest: Peer) -> Result<Vec<onion::Event>> {
+    let tunnel = Tunnel::init(0, &first_hop, CellSize::Standard, CipherSuites::all()).await?;
+
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let builder = TunnelBuilder::new(0, Target::Peer(dest), 0, peer_provider);
+    let policy = RotationPolicy {
+        max_rebuild_attempts: 1,
+        ..Default::default()
+    };
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        policy,
+        STALL_THRESHOLD,
+        notify,
+    );
+    tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let _tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+
+    let mut events = Vec::new();
+    while let Ok(Ok(evt)) = time::timeout(Duration::from_secs(2), notify_rx.recv()).await {
+        events.push(evt);
+    }
+    Ok(events)
+}
+
+#[tokio::test]
+async fn test_stalled_during_rebuild() -> Result<()> {
+    // the replacement path hangs in the handshake, so the handler is busy rebuilding
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let silent_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let silent_peer = Peer::new((TEST_IP, silent_port).into(), peer_key);
+    let listener = TcpListener::bind(silent_peer.addr).await?;
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+
+    let first_hop = spawn_failing_peer(Duration::from_millis(500), Some(EndReason::Timeout)).await;
+    let tunnel = Tunnel::init(0, &first_hop, CellSize::Standard, CipherSuites::all()).await?;
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let builder = TunnelBuilder::new(0, Target::Peer(silent_peer), 0, peer_provider);
+    let (events_tx, events_rx) = broadcast::channel(1);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    let mut handler = TunnelHandler::new(
+        tunnel,
+        builder,
+        events_rx,
+        ready_tx,
+        Default::default(),
+        Duration::from_secs(1),
+        notify,
+    );
+    tokio::spawn(async move { handler.handle().await });
+
+    events_tx.send(Event::Switchover).unwrap();
+    let tunnel = time::timeout(ERROR_TIMEOUT, ready_rx).await???;
+    time::sleep(Duration::from_secs(1)).await;
+    tunnel.write(Bytes::from_static(b"stalled"))?;
+
+    let duration = loop {
+        match time::timeout(ERROR_TIMEOUT, notify_rx.recv()).await?? {
+            onion::Event::Stalled { duration, .. } => break duration,
+            _ => continue,
+        }
+    };
+    assert!(duration >= Duration::from_secs(1));
+    let stats = tunnel.stats();
+    assert_eq!(stats.queued_cells, 1);
+    assert!(stats.stalled_for >= Some(Duration::from_secs(1)));
+    assert!(stats.since_last_write.is_none());
+    Ok(())
+}
+
+/// Checks whether a path torn down by the first hop for `reason` is replaced, or the tunnel is
+/// closed although a replacement could be built.
+async fn check_first_hop_teardown(reason: EndReason, recoverable: bool) -> Result<()> {
+    let peers = spawn_n_relays(1).await;
+    let events = run_failing_path(Some(reason), peers[0].clone()).await?;
+    let rotated = events
+        .iter()
+        .any(|evt| matches!(evt, onion::Event::Rotated { .. }));
+    assert_eq!(rotated, recoverable);
+    let closed = onion::Event::Closed {
+        tunnel_id: 0,
+        reason: CloseReason::Rejected,
+        end_reason: Some(reason),
+    };
+    assert_eq!(events.contains(&closed), !recoverable);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_first_hop_teardown_rebuilds() -> Result<()> {
+    check_first_hop_teardown(EndReason::Timeout, true).await
+}
+
+#[tokio::test]
+async fn test_first_hop_teardown_resource_limit_rebuilds() -> Result<()> {
+    check_first_hop_teardown(EndReason::ResourceLimit, true).await
+}
+
+#[tokio::test]
+async fn test_first_hop_teardown_unspecified_closes() -> Result<()> {
+    check_first_hop_teardown(EndReason::Unspecified, false).await
+}
+
+#[tokio::test]
+async fn test_first_hop_teardown_normal_closes() -> Result<()> {
+    check_first_hop_teardown(EndReason::Normal, false).await
+}
+
+#[tokio::test]
+async fn test_first_hop_teardown_protocol_error_closes() -> Result<()> {
+    check_first_hop_teardown(EndReason::ProtocolError, false).await
+}
+
+#[tokio::test]
+async fn test_first_hop_teardown_replaced_closes() -> Result<()> {
+    check_first_hop_teardown(EndReason::Replaced, false).await
+}
+
+#[tokio::test]
+async fn test_first_hop_teardown_closes() -> Result<()> {
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let dead_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dead_peer = Peer::new((TEST_IP, dead_port).into(), peer_key);
+
+    let events = run_failing_path(Some(EndReason::Timeout), dead_peer.clone()).await?;
+    assert!(events.contains(&onion::Event::Closed {
+        tunnel_id: 0,
+        reason: CloseReason::TornDown,
+        end_reason: Some(EndReason::Timeout),
+    }));
+
+    let events = run_failing_path(None, dead_peer).await?;
+    assert!(events.contains(&onion::Event::Closed {
+        tunnel_id: 0,
+        reason: CloseReason::ConnectionLost,
+        end_reason: None,
+    }));
+    Ok(())
+}
+
+/// Spawns a peer which accepts a single circuit, sends a message which is not valid for the tunnel
+/// and reports whether the circuit is torn down in return, and whether the connection is closed
+/// afterwards.
+///
+/// The message is encrypted with a random key if `wrong_key` is set, so its digest is broken,
+/// otherwise it is a well-formed `TUNNEL DATA` message for another tunnel.
+async fn spawn_garbage_peer(wrong_key: bool) -> (Peer, oneshot::Receiver<(bool, bool)>) {
+    let (host_key, peer_key) = read_rsa_keypair("testkey.pem").unwrap();
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(&peer_addr).await.unwrap();
+    let (torn_down_tx, torn_down_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = OnionSocket::new(stream);
+        let (circuit_id, peer_key, suites) =
+            socket.accept_handshake(CipherSuites::all()).await.unwrap();
+        let suite = CipherSuite::from_code(suites.selected).unwrap();
+        let (private_key, key) = crypto::generate_ephemeral_keypair();
+        let key = SignKey::sign(&key, suites, &host_key);
+        let salt = key.salt();
+        socket
+            .finalize_handshake(circuit_id, key.to_signed())
+            .await
+            .unwrap();
+        let session_key = if wrong_key {
+            let mut random_key = [0u8; 16];
+            crypto::fill_random(&mut random_key);
+            SessionKey::from_bytes(&random_key).unwrap()
+        } else {
+            SessionKey::from_key_exchange(
+                private_key,
+                &peer_key,
+                suite,
+                salt.as_deref(),
+                suites.version(),
+            )
+            .unwrap()
+        };
+        let data = Bytes::from_static(b"not for you");
+        socket
+            .send_data(circuit_id, 1, vec![data], &[session_key])
+            .await
+            .unwrap();
+        // skip the messages the tunnel sent before it noticed
+        let torn_down = loop {
+            if let Err(e) = socket.accept_opaque().await {
+                break matches!(
+                    e.kind,
+                    SocketErrorKind::TeardownMessage(EndReason::ProtocolError)
+                );
+            }
+        };
+        let closed = time::timeout(ERROR_TIMEOUT, async {
+            loop {
+                if let Err(e) = socket.accept_opaque().await {
+                    break matches!(e.kind, SocketErrorKind::StreamTerminated(_));
+                }
+            }
+        })
+        .await
+        .unwrap_or(false);
+        let _ = torn_down_tx.send((torn_down, closed));
+    });
+    (Peer::new(peer_addr, peer_key), torn_down_rx)
+}
+
+#[tokio::test]
+async fn test_garbage_message_tears_down() -> Result<()> {
+    for &wrong_key in &[true, false] {
+        let (first_hop, torn_down) = spawn_garbage_peer(wrong_key).await;
+        let events = run_tunnel_handler(first_hop.clone(), first_hop).await?;
+        assert!(events.contains(&onion::Event::Closed {
+            tunnel_id: 0,
+            reason: CloseReason::Failed,
+            end_reason: None,
+        }));
+        let (torn_down, closed) = time::timeout(ERROR_TIMEOUT, torn_down).await??;
+        assert!(torn_down);
+        assert!(closed);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_event_serde_roundtrip() -> Result<()> {
+    let events = vec![
+        onion::Event::Ready {
+            tunnel_id: 42,
+            cause: ReadyCause::Initial,
+        },
+        onion::Event::Rotated {
+            tunnel_id: 42,
+            old_path_age: Duration::from_millis(1500),
+        },
+        onion::Event::RotationFailed { tunnel_id: 42 },
+        onion::Event::Closed {
+            tunnel_id: 42,
+            reason: CloseReason::TornDown,
+            end_reason: Some(EndReason::ResourceLimit),
+        },
+        onion::Event::HopSuspected {
+            tunnel_id: 42,
+            position: 1,
+            fingerprint: [7; 32],
+        },
+        onion::Event::GuardsChanged {
+            guards: vec![[7; 32]],
+        },
+    ];
+
+    for evt in events {
+        let json = serde_json::to_string(&evt)?;
+        assert_eq!(serde_json::from_str::<onion::Event>(&json)?, evt);
+        let bin = bincode::serialize(&evt)?;
+        assert_eq!(bincode::deserialize::<onion::Event>(&bin)?, evt);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_node_state_restore() {
+    let suspects = SuspectedPeers::default();
+    let retries = DestinationRetries::default();
+    suspects.suspect([1; 32]);
+    let delay = retries.record_failure([2; 32]);
+    retries.record_failure([3; 32]);
+    retries.record_success(&[3; 32]);
+
+    let mut state = NodeState::capture(&suspects, &retries);
+    assert_eq!(state.version, NodeState::VERSION);
+    assert_eq!(state.suspected_peers.len(), 1);
+    assert_eq!(state.destination_backoffs.len(), 1);
+    // entries which expired while the onion router was down are skipped
+    let past = SystemTime::now() - Duration::from_secs(3600);
+    state.suspected_peers.push(SuspectedPeer {
+        fingerprint: [4; 32],
+        until: past,
+    });
+    state.destination_backoffs.push(DestinationBackoff {
+        fingerprint: [5; 32],
+        failures: 3,
+        retry_at: past,
+    });
+
+    let (restored_suspects, restored_retries) = Default::default();
+    state.restore(&restored_suspects, &restored_retries);
+    let restored = NodeState::capture(&restored_suspects, &restored_retries);
+    assert_eq!(restored.suspected_peers.len(), 1);
+    assert_eq!(restored.suspected_peers[0].fingerprint, [1; 32]);
+    let backoff = restored_retries.backoff(&[2; 32]).unwrap();
+    assert_eq!(backoff.failures, 1);
+    assert!(backoff.retry_in <= delay);
+    assert!(restored_retries.backoff(&[5; 32]).is_none());
+    assert_eq!(restored.destination_backoffs.len(), 1);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_node_state_serde() -> Result<()> {
+    let suspects = SuspectedPeers::default();
+    let retries = DestinationRetries::default();
+    suspects.suspect([1; 32]);
+    retries.record_failure([2; 32]);
+    let state = NodeState::capture(&suspects, &retries);
+    let json = serde_json::to_string(&state)?;
+    assert_eq!(serde_json::from_str::<NodeState>(&json)?, state);
+
+    // fields of other versions are ignored or left empty
+    let json = r#"{"version": 1, "guards": [], "suspected_peers": []}"#;
+    let state = serde_json::from_str::<NodeState>(json)?;
+    assert_eq!(state, NodeState::default());
+    Ok(())
+}
+
+#[test]
+fn test_build_info() {
+    let info = onion::build_info();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.cipher_suites.last(), Some(&CipherSuite::Hmac));
+    assert_eq!(
+        info.handshake_versions.last(),
+        Some(&HandshakeVersion::LATEST)
+    );
+    assert_eq!(info.transports, vec![onion::Transport::Tcp]);
+    let crypto = info
+        .features
+        .iter()
+        .filter(|feature| feature.starts_with("crypto_"))
+        .count();
+    // the default backend stays enabled next to the one taking precedence over it
+    assert!(crypto >= 1);
+    assert_eq!(
+        info.features.iter().any(|feature| feature == "serde"),
+        cfg!(feature = "serde")
+    );
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_build_info_serde() -> Result<()> {
+    let info = onion::build_info();
+    let json = serde_json::to_string(&info)?;
+    assert_eq!(serde_json::from_str::<onion::BuildInfo>(&json)?, info);
+    Ok(())
+}
+
+#[test]
+fn test_serde_is_optional() {
+    // serde must only be pulled in by the `serde` feature, not by the default build
+    let output = std::process::Command::new(env!("CARGO"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["tree", "-p", "allium", "-e", "normal", "--prefix", "none"])
+        .args(["--format", "{p}"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let packages = String::from_utf8(output.stdout).unwrap();
+    assert!(packages.lines().any(|p| p.starts_with("tokio ")));
+    assert!(!packages.lines().any(|p| p.starts_with("serde ")));
+}
+
+#[tokio::test]
+async fn test_build_known_first_hop() -> Result<()> {
+    let peers = spawn_n_relays(2).await;
+    let known_peers = KnownPeers::default();
+    known_peers.insert(peers[0].clone());
+    let options = TunnelOptions::new().set_first_hop(peers[0].fingerprint());
+
+    // the first hop is not taken from the (empty) peer provider
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[1].clone()), 1, peer_provider)
+        .with_options(options.clone(), Default::default(), known_peers.clone());
+    assert_eq!(builder.build().await?.len(), 2);
+
+    let peer_provider = PeerProvider::from_stream(stream::iter(vec![peers[0].clone()]));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[1].clone()), 1, peer_provider)
+        .with_options(options.clone(), Default::default(), Default::default());
+    let err = builder.build().await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref(),
+        Some(&HopSelectionError::UnknownPeer { position: 0 })
+    );
+
+    // the hop filter applies to constrained hops
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let vetoed = options.clone().set_hop_filter(Arc::new(|_: &Peer| false));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[1].clone()), 1, peer_provider)
+        .with_options(vetoed, Default::default(), known_peers);
+    let err = builder.build().await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref(),
+        Some(&HopSelectionError::Rejected { position: 0 })
+    );
+    assert_eq!(builder.stats.snapshot().rejected_hops, 1);
+
+    // the destination can not be constrained
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(peers[1].clone()), 0, peer_provider)
+        .with_options(options, Default::default(), Default::default());
+    let err = builder.build().await.unwrap_err();
+    assert_eq!(
+        err.downcast_ref(),
+        Some(&HopSelectionError::InvalidPosition { position: 0 })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_build_explicit_path() -> Result<()> {
+    let relays = spawn_n_relays(3).await;
+    let dest = relays[2].clone();
+
+    // the hops are taken from the path in order, not from the peer provider
+    let path = vec![relays[1].clone(), relays[0].clone()];
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 1, peer_provider)
+        .with_explicit_path(path);
+    let tunnel = builder.build().await?;
+    let hops: Vec<_> = (0..tunnel.len())
+        .map(|position| tunnel.hop(position).unwrap().addr)
+        .collect();
+    assert_eq!(hops, vec![relays[1].addr, relays[0].addr, dest.addr]);
+    // and again when rebuilding
+    let tunnel = builder.build().await?;
+    assert_eq!(tunnel.hop(0).unwrap().addr, relays[1].addr);
+
+    // an unreachable hop is not substituted but fails the build with its position
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let closed_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let unreachable = Peer::new((TEST_IP, closed_port).into(), peer_key);
+    let path = vec![relays[0].clone(), unreachable.clone()];
+    let peer_provider = PeerProvider::from_stream(stream::iter(iter::repeat(relays[1].clone())));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 2, peer_provider)
+        .with_explicit_path(path)
+        .with_build_reports(true);
+    let error = time::timeout(ERROR_TIMEOUT, builder.build())
+        .await?
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&HopSelectionError::Unreachable { position: 1 })
+    );
+    let report = error.downcast_ref::<BuildReport>().unwrap();
+    assert_eq!(report.attempts().len(), 2);
+
+    // the destination follows the path
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(unreachable.clone()), 0, peer_provider)
+        .with_explicit_path(vec![relays[0].clone()]);
+    let error = builder.build().await.unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&HopSelectionError::Unreachable { position: 1 })
+    );
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest), 1, peer_provider)
+        .with_explicit_path(vec![unreachable]);
+    let error = builder.build().await.unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&HopSelectionError::Unreachable { position: 0 })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_destination_unreachable() -> Result<()> {
+    let relays = spawn_n_relays(2).await;
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let closed_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dest = Peer::new((TEST_IP, closed_port).into(), peer_key);
+
+    // the build gives up on the destination instead of trying more intermediate hops
+    let peer_provider = PeerProvider::from_stream(stream::iter(relays.into_iter().cycle()));
+    let mut builder =
+        TunnelBuilder::new(0, Target::Peer(dest), 1, peer_provider).with_build_reports(true);
+    let error = time::timeout(ERROR_TIMEOUT, builder.build())
+        .await?
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&DestinationUnreachable {
+            failures: 3,
+            last_outcome: BuildOutcome::ConnectFailed
+        })
+    );
+    let report = error.downcast_ref::<BuildReport>().unwrap();
+    let dest_attempts = report
+        .attempts()
+        .iter()
+        .filter(|attempt| attempt.hop == 1)
+        .count();
+    assert_eq!(dest_attempts, 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_destination_impostor() -> Result<()> {
+    let relays = spawn_n_relays(2).await;
+    // the destination is up, but can not prove the identity we expect
+    let other_key = RsaPrivateKey::from_pem_file("tests/smallkey.pem")?.public_key();
+    let dest = Peer::new(relays[1].addr, other_key);
+
+    let peer_provider =
+        PeerProvider::from_stream(stream::iter(vec![relays[0].clone()].into_iter().cycle()));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest), 1, peer_provider);
+    let error = time::timeout(ERROR_TIMEOUT, builder.build())
+        .await?
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&DestinationUnreachable {
+            failures: 3,
+            last_outcome: BuildOutcome::DeriveFailed
+        })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_path_unreachable() -> Result<()> {
+    let relays = spawn_n_relays(1).await;
+    let dest = relays[0].clone();
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let down = Peer::new(
+        (TEST_IP, PORT_COUNTER.fetch_add(1, Ordering::Relaxed)).into(),
+        peer_key,
+    );
+
+    // every first hop fails, so the destination is never attempted
+    let peer_provider = PeerProvider::from_stream(stream::iter(vec![down].into_iter().cycle()));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest), 1, peer_provider);
+    let error = time::timeout(ERROR_TIMEOUT, builder.build())
+        .await?
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&PathUnreachable { failures: 10 })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prefetched_peers() -> Result<()> {
+    let relays = spawn_n_relays(3).await;
+    let dest = relays[2].clone();
+
+    // the peer drawn while the first hop is added is checked against the path when it is used
+    let hops = vec![relays[0].clone(), relays[0].clone(), relays[1].clone()];
+    let peer_provider = PeerProvider::from_stream(stream::iter(hops.into_iter().cycle()));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 2, peer_provider);
+    for _ in 0..2 {
+        let tunnel = builder.build().await?;
+        let hops: Vec<_> = (0..tunnel.len())
+            .map(|position| tunnel.hop(position).unwrap().addr)
+            .collect();
+        assert_eq!(hops, vec![relays[0].addr, relays[1].addr, dest.addr]);
+    }
+
+    // a failed first hop does not leave its prefetched successor behind
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let closed_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let unreachable = Peer::new((TEST_IP, closed_port).into(), peer_key);
+    let hops = vec![unreachable, relays[0].clone(), relays[1].clone()];
+    let peer_provider = PeerProvider::from_stream(stream::iter(hops));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 2, peer_provider);
+    let tunnel = time::timeout(ERROR_TIMEOUT, builder.build()).await??;
+    let hops: Vec<_> = (0..tunnel.len())
+        .map(|position| tunnel.hop(position).unwrap().addr)
+        .collect();
+    assert_eq!(hops, vec![relays[0].addr, relays[1].addr, dest.addr]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_entry_guards() -> Result<()> {
+    let relays = spawn_n_relays(3).await;
+    let dest = relays[2].clone();
+    let (notify, mut events) = broadcast::channel(16);
+    let guards = EntryGuards::new(1, Vec::new(), notify.clone());
+
+    // the first hop is drawn once and kept, while the other hops keep changing
+    let hops = vec![relays[0].clone(), relays[1].clone()];
+    let peer_provider = PeerProvider::from_stream(stream::iter(hops.into_iter().cycle()));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 2, peer_provider)
+        .with_guards(guards.clone());
+    for _ in 0..3 {
+        let tunnel = builder.build().await?;
+        assert_eq!(tunnel.hop(0).unwrap().addr, relays[0].addr);
+    }
+    assert_eq!(guards.peers().len(), 1);
+    assert!(matches!(
+        events.try_recv(),
+        Ok(onion::Event::GuardsChanged { guards }) if guards.len() == 1
+    ));
+    assert!(events.try_recv().is_err());
+
+    // a guard failing repeatedly is replaced
+    let (_, peer_key) = read_rsa_keypair("testkey.pem")?;
+    let closed_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let unreachable = Peer::new((TEST_IP, closed_port).into(), peer_key);
+    let guards = EntryGuards::new(1, vec![unreachable], notify);
+    let peer_provider = PeerProvider::from_stream(stream::iter(iter::repeat(relays[1].clone())));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 1, peer_provider)
+        .with_guards(guards.clone());
+    let tunnel = time::timeout(ERROR_TIMEOUT, builder.build()).await??;
+    assert_eq!(tunnel.hop(0).unwrap().addr, relays[1].addr);
+    let addrs: Vec<_> = guards.peers().iter().map(Peer::address).collect();
+    assert_eq!(addrs, vec![relays[1].addr]);
+    // demoted, then replaced
+    assert!(matches!(
+        events.try_recv(),
+        Ok(onion::Event::GuardsChanged { guards }) if guards.is_empty()
+    ));
+    assert!(matches!(
+        events.try_recv(),
+        Ok(onion::Event::GuardsChanged { guards }) if guards.len() == 1
+    ));
+
+    // only a tunnel in strict mode refuses a first hop which is no guard
+    let guard = relays[1].addr;
+    let options =
+        TunnelOptions::new().set_hop_filter(Arc::new(move |peer: &Peer| peer.addr != guard));
+    let peer_provider = PeerProvider::from_stream(stream::iter(iter::repeat(relays[0].clone())));
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest.clone()), 1, peer_provider.clone())
+        .with_options(options.clone(), Default::default(), Default::default())
+        .with_guards(guards.clone());
+    let tunnel = builder.build().await?;
+    assert_eq!(tunnel.hop(0).unwrap().addr, relays[0].addr);
+    let mut builder = TunnelBuilder::new(0, Target::Peer(dest), 1, peer_provider)
+        .with_options(
+            options.set_strict(true),
+            Default::default(),
+            Default::default(),
+        )
+        .with_guards(guards);
+    let error = builder.build().await.unwrap_err();
+    assert_eq!(
+        error.downcast_ref(),
+        Some(&StrictViolation {
+            which: Fallback::NoGuard
+        })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_event_tally() {
+    let (notify, _) = broadcast::channel(4);
+    let tally = EventTally::new(&notify, 4);
+    let event = onion::Event::RotationFailed { tunnel_id: 1 };
+
+    let mut idle = notify.subscribe();
+    let idle_subscriber = tally.subscribe();
+    // skipped before the shutdown
+    for _ in 0..6 {
+        let _ = notify.send(event.clone());
+    }
+    assert!(matches!(
+        idle.recv().await,
+        Err(broadcast::error::RecvError::Lagged(2))
+    ));
+    idle_subscriber.record(2);
+    let mut reader = notify.subscribe();
+    let reader_subscriber = tally.subscribe();
+
+    tally.close();
+    for _ in 0..10 {
+        let _ = notify.send(event.clone());
+        while reader.try_recv().is_ok() {
+            reader_subscriber.record(0);
+        }
+    }
+    // the idle subscriber buffered the last 4 of the 14 events sent since it skipped
+    assert_eq!(tally.dropped(), 10);
+    drop(idle_subscriber);
+    assert_eq!(tally.dropped(), 0);
+}
+
+#[tokio::test]
+async fn test_handshake_backlog_limit() -> Result<()> {
+    let (host_key, _) = read_rsa_keypair("testkey.pem")?;
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr: SocketAddr = (TEST_IP, peer_port).into();
+    let counters = Arc::new(onion::RelayCounters::default());
+    let (notify, mut notify_rx) = broadcast::channel(10);
+    let backlog = HandshakeBacklog::new(1, counters.clone(), notify);
+    let (incoming_tx, _incoming_rx) = mpsc::channel(1);
+    let mut listener = OnionListener::new(
+        host_key,
+        incoming_tx,
+        Default::default(),
+        backlog,
+        Default::default(),
+    );
+    let tcp_listener = TcpListener::bind(peer_addr).await?;
+    tokio::spawn(async move { listener.listen(tcp_listener).await });
+
+    // a half-open handshake occupies the only slot
+    let _pending = TcpStream::connect(peer_addr).await?;
+    time::sleep(Duration::from_millis(100)).await;
+    let mut rejected = TcpStream::connect(peer_addr).await?;
+    let mut buf = [0u8; 1];
+    let n = time::timeout(ERROR_TIMEOUT, rejected.read(&mut buf)).await??;
+    assert_eq!(n, 0);
+    assert_eq!(
+        counters.snapshot(),
+        RelayStats {
+            pending_handshakes: 1,
+            rejected_handshakes: 1,
+            refused_extends: 0,
+            draining_circuits: 0,
+            relay_connections: 0,
+            inbound_circuits: vec![],
+            forwarding_latency: Default::default(),
+            relay_connection_queues: vec![],
+        }
+    );
+    assert_eq!(
+        notify_rx.try_recv()?,
+        onion::Event::HandshakeBacklogFull { rejected: 1 }
+    );
+
+    // the slot is freed once the handshake times out
+    time::sleep(Duration::from_secs(6)).await;
+    assert_eq!(counters.snapshot().pending_handshakes, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shared_connection_failure() -> Result<()> {
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr: SocketAddr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(peer_addr).await?;
+    let counters = Arc::new(onion::RelayCounters::default());
+    let idle_timeout = Duration::from_millis(200);
+    let cache = ConnectionCache::new(idle_timeout, Some(counters.clone()));
+
+    let mut circuits = vec![];
+    for _ in 0..3 {
+        circuits.push(cache.open(peer_addr).await?);
+    }
+    let (peer, _) = listener.accept().await?;
+    assert!(time::timeout(Duration::from_millis(100), listener.accept())
+        .await
+        .is_err());
+    assert_ne!(circuits[0].0, circuits[1].0);
+    assert_ne!(circuits[1].0, circuits[2].0);
+    assert_ne!(circuits[0].0, circuits[2].0);
+    assert_eq!(counters.snapshot().relay_connections, 1);
+
+    // all circuits fail with the error of the connection
+    drop(peer);
+    for (_, mut stream) in circuits {
+        let err = time::timeout(ERROR_TIMEOUT, stream.read_u8())
+            .await?
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("shared connection to"),
+            "{}",
+            err
+        );
+    }
+    assert_eq!(counters.snapshot().relay_connections, 0);
+
+    // the next circuit connects again, the new connection is closed once idle
+    let (_, stream) = cache.open(peer_addr).await?;
+    let _peer = time::timeout(ERROR_TIMEOUT, listener.accept()).await??;
+    assert_eq!(counters.snapshot().relay_connections, 1);
+    drop(stream);
+    time::sleep(idle_timeout * 2).await;
+    assert_eq!(counters.snapshot().relay_connections, 0);
+    Ok(())
+}
+
+/// Returns a message for the circuit with `circuit_id` on a shared connection.
+fn shared_message(circuit_id: u16) -> Vec<u8> {
+    let mut msg = vec![0u8; MESSAGE_SIZE];
+    msg[0] = 0x3;
+    msg[2..4].copy_from_slice(&circuit_id.to_be_bytes());
+    msg
+}
+
+#[tokio::test]
+async fn test_shared_connection_stalled_reader() -> Result<()> {
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr: SocketAddr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(peer_addr).await?;
+    let counters = Arc::new(onion::RelayCounters::default());
+    let cache = ConnectionCache::new(Duration::from_secs(5), Some(counters));
+    let (stalled_id, mut stalled) = cache.open(peer_addr).await?;
+    let (reading_id, mut reading) = cache.open(peer_addr).await?;
+    let (mut peer, _) = listener.accept().await?;
+
+    // more messages than the buffer and the backlog of a circuit hold
+    const MESSAGES: usize = 1200;
+    let reader = tokio::spawn(async move {
+        let mut buf = vec![0u8; MESSAGES * MESSAGE_SIZE];
+        reading.read_exact(&mut buf).await.map(|_| reading)
+    });
+    for _ in 0..MESSAGES {
+        peer.write_all(&shared_message(stalled_id)).await?;
+        peer.write_all(&shared_message(reading_id)).await?;
+    }
+    // the stalled circuit does not block the other circuit
+    let _reading = time::timeout(ERROR_TIMEOUT, reader).await???;
+
+    // the stalled circuit is torn down, after reading its buffer
+    let mut teardown = [0u8; MESSAGE_SIZE];
+    time::timeout(ERROR_TIMEOUT, peer.read_exact(&mut teardown)).await??;
+    assert_eq!(teardown[0], 0xff);
+    assert_eq!(u16::from_be_bytes([teardown[2], teardown[3]]), stalled_id);
+    let mut buf = vec![0u8; 64 * MESSAGE_SIZE];
+    stalled.read_exact(&mut buf).await?;
+    let err = stalled.read_u8().await.unwrap_err();
+    assert!(err.to_string().contains("exceeded its share"), "{}", err);
+    assert!(stalled
+        .write_all(&shared_message(stalled_id))
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shared_connection_write_quota() -> Result<()> {
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr: SocketAddr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(peer_addr).await?;
+    let counters = Arc::new(onion::RelayCounters::default());
+    let cache = ConnectionCache::new(Duration::from_secs(5), Some(counters));
+    let (flooding_id, mut flooding) = cache.open(peer_addr).await?;
+    let (other_id, mut other) = cache.open(peer_addr).await?;
+    // the peer does not read, so the writes of the flooding circuit pile up
+    let (_peer, _) = listener.accept().await?;
+
+    let msg = shared_message(flooding_id);
+    while time::timeout(Duration::from_millis(100), flooding.write_all(&msg))
+        .await
+        .is_ok()
+    {}
+    // the flooding circuit used up its quota, not the write buffer of the connection
+    time::timeout(
+        Duration::from_millis(100),
+        other.write_all(&shared_message(other_id)),
+    )
+    .await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shared_connection_timeouts() -> Result<()> {
+    let timeouts = SocketTimeouts {
+        read: Duration::from_millis(100),
+        write: Duration::from_millis(100),
+    };
+
+    // the writes are queued until the buffers of both ends are full and a write times out
+    let peer_addr = spawn_silent_peer().await?;
+    let cache = ConnectionCache::new(Duration::from_secs(5), None).with_socket_timeouts(timeouts);
+    let (circuit_id, mut stream) = cache.open(peer_addr).await?;
+    let msg = shared_message(circuit_id);
+    let e = time::timeout(ERROR_TIMEOUT, async {
+        loop {
+            if let Err(e) = stream.write_all(&msg).await {
+                break e;
+            }
+        }
+    })
+    .await?;
+    assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+
+    // an incoming connection has to send its first header within the read timeout
+    let peer_port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let peer_addr: SocketAddr = (TEST_IP, peer_port).into();
+    let listener = TcpListener::bind(peer_addr).await?;
+    let _silent = TcpStream::connect(peer_addr).await?;
+    let (stream, _) = listener.accept().await?;
+    let accept = connection::accept(stream, peer_addr, Duration::from_secs(5), timeouts);
+    let e = time::timeout(ERROR_TIMEOUT, accept).await?.err().unwrap();
+    assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+    Ok(())
+}
+
+#[test]
+fn test_lanes_priority() {
+    let mut lanes = Lanes::new();
+    lanes.push(Lane::Cover, 0);
+    lanes.push(Lane::Data, 1);
+    lanes.push(Lane::Data, 2);
+    lanes.push(Lane::Control, 3);
+    lanes.push(Lane::Data, 4);
+    lanes.push(Lane::Control, 5);
+
+    assert_eq!(lanes.pop(), Some((Lane::Control, 3)));
+    assert_eq!(lanes.pop(), Some((Lane::Control, 5)));
+    assert_eq!(lanes.pop_batch(2), Some((Lane::Data, vec![1, 2])));
+    lanes.push(Lane::Control, 6);
+    // control messages are never batched
+    lanes.push(Lane::Control, 7);
+    assert_eq!(lanes.pop_batch(2), Some((Lane::Control, vec![6])));
+    assert_eq!(lanes.pop_batch(2), Some((Lane::Control, vec![7])));
+    assert_eq!(lanes.pop_batch(2), Some((Lane::Data, vec![4])));
+    assert_eq!(lanes.pop_batch(2), Some((Lane::Cover, vec![0])));
+    assert_eq!(lanes.pop(), None);
+    assert!(lanes.is_empty());
+}
+
+#[test]
+fn test_lanes_pop_outgoing() {
+    let mut lanes = Lanes::new();
+    lanes.push(Lane::Data, Outgoing::Data(Bytes::from_static(b"a")));
+    lanes.push(Lane::Cover, Outgoing::Padding);
+    lanes.push(Lane::Control, Outgoing::KeepAlive);
+    lanes.push(Lane::Data, Outgoing::Data(Bytes::from_static(b"b")));
+    lanes.push(Lane::Data, Outgoing::Data(Bytes::from_static(b"c")));
+
+    let (other, data) = lanes.pop_outgoing(8).unwrap();
+    assert!(matches!(other[..], [Outgoing::KeepAlive]) && data.is_empty());
+    let (other, data) = lanes.pop_outgoing(2).unwrap();
+    assert!(other.is_empty());
+    assert_eq!(
+        data,
+        vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")]
+    );
+    let (other, data) = lanes.pop_outgoing(1).unwrap();
+    assert!(other.is_empty());
+    assert_eq!(data, vec![Bytes::from_static(b"c")]);
+    let (other, data) = lanes.pop_outgoing(8).unwrap();
+    assert!(matches!(other[..], [Outgoing::Padding]) && data.is_empty());
+    assert!(lanes.pop_outgoing(8).is_none());
+}
+
+#[test]
+fn test_lanes_control_streak() {
+    let mut lanes = Lanes::new();
+    lanes.push(Lane::Data, usize::MAX);
+    lanes.push(Lane::Cover, usize::MAX);
+    let mut control_in_row = 0;
+    for i in 0..100 {
+        // constant control traffic
+        lanes.push(Lane::Control, i);
+        match lanes.pop() {
+            Some((Lane::Control, _)) => control_in_row += 1,
+            Some((Lane::Data, _)) => break,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    // data is not starved, cover traffic is
+    assert!(control_in_row > 0 && control_in_row < 100);
+    assert_eq!(lanes.clear(Lane::Cover), 1);
+}
+
+#[test]
+fn test_endpoint_bindings() {
+    let registry = TunnelRegistry::default();
+    let endpoints = Endpoints::new(registry.clone());
+    let path = |port| {
+        let (mut path, _, _) = onion::Tunnel::new(7, false, CellSize::default());
+        path.incoming_info = Some(IncomingTunnelInfo {
+            adjacent_peer: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
+            cell_size: CellSize::default(),
+            cipher_suite: CipherSuite::Hmac,
+            began_at: std::time::SystemTime::now(),
+        });
+        path
+    };
+
+    let (_, mut paths) = endpoints.bind(path(1)).unwrap();
+    // a second begin replaces the path of the tunnel
+    assert!(endpoints.bind(path(2)).is_none());
+    assert_eq!(registry.incoming(7).unwrap().adjacent_peer.port(), 2);
+    // the tunnel was destroyed before taking the new path, which starts a new tunnel
+    let path2 = endpoints.unbind(7, &mut paths).unwrap();
+    assert_eq!(path2.incoming_info().unwrap().adjacent_peer.port(), 2);
+    assert!(endpoints.unbind(7, &mut paths).is_none());
+    assert!(registry.incoming(7).is_none());
+    // once the tunnel is destroyed, a begin with its id starts a new tunnel
+    assert!(endpoints.bind(path(3)).is_some());
+}
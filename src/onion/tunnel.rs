@@ -1,32 +1,101 @@
 use crate::onion;
-use crate::onion::circuit::Circuit;
-use crate::onion::crypto::{self, EphemeralPrivateKey, SessionKey};
+use crate::onion::circuit::{Circuit, CircuitParams, TEARDOWN_TIMEOUT};
+use crate::onion::config::SocketTimeouts;
+use crate::onion::connection::{self, CircuitStream, ConnectionCache};
+use crate::onion::crypto::{
+    self, CipherSuite, CipherSuites, Direction, EphemeralPrivateKey, HandshakeVersion, SessionKey,
+};
+use crate::onion::diagnosis::{self, SuspectedPeers};
+use crate::onion::guards::EntryGuards;
+use crate::onion::handshakes::HandshakeLimiter;
+use crate::onion::lanes::{Lane, Lanes, Outgoing};
+use crate::onion::observer::Observer;
 use crate::onion::protocol::{
-    CircuitOpaque, CircuitOpaqueBytes, TryFromBytesExt, TunnelRequest, VerifyKey,
+    CellSize, CircuitOpaque, CircuitOpaqueBytes, EndReason, FeatureOffer, HopVerifier,
+    TryFromBytesExt, TunnelRequest, TunnelResponseEchoed, VerifyKey,
+};
+use crate::onion::retry::DestinationRetries;
+#[cfg(test)]
+use crate::onion::rng::SystemRandom;
+use crate::onion::rng::{OnionRng, RngFactory};
+use crate::onion::shutdown::{ShutdownGuard, ShuttingDown};
+use crate::onion::socket::{self, OnionSocket, OnionSocketError, SocketErrorKind, SocketResult};
+use crate::onion::{
+    BuildAttempt, BuildOutcome, BuildReport, BuildTimedOut, BuildTimeouts, DestinationUnreachable,
+    Fallback, HopInfo, HopSelectionError, NoAcceptablePeers, PathUnreachable, RetryBackoff,
+    RotationStrategy, StrictViolation, TunnelOptions, TunnelRegistry, TunnelState,
 };
-use crate::onion::socket::{OnionSocket, OnionSocketError, SocketResult};
-use crate::{Peer, PeerProvider, Result};
+use crate::task;
+use crate::{Capabilities, CapabilityCache, Fingerprint, KnownPeers, Peer, PeerProvider, Result};
 use anyhow::{anyhow, Context};
 use bytes::Bytes;
-use log::{trace, warn};
-use std::fmt;
-use std::mem;
-use std::sync::Arc;
+use log::{debug, error, info, trace, warn};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::{cmp, fmt, mem};
 use thiserror::Error;
 use tokio::net::TcpStream;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use tokio::time::{self, Duration, Instant};
 
 const MAX_PEER_FAILURES: usize = 10;
+/// number of failed attempts to add the destination after which a build is given up
+const MAX_DEST_FAILURES: usize = 3;
+/// delay before the first retry of a failed replacement tunnel build
+const REBUILD_BACKOFF: Duration = Duration::from_secs(1);
+/// upper bound for the delay between retries of a failed replacement tunnel build
+const MAX_REBUILD_BACKOFF: Duration = Duration::from_secs(60);
+/// number of failed replacement tunnel builds after which a rotation is given up
+const MAX_REBUILD_ATTEMPTS: usize = 10;
+/// lower bound for the interval in which tunnels are checked for stalled data
+const MIN_STALL_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+/// time the last relay has to confirm that it terminates a tunnel
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(5);
+/// time the last hop has to answer the echo verifying a path after a failed extend
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(2);
+/// number of keep-alive echoes missed in a row after which the path is replaced
+const MAX_MISSED_ECHOES: u32 = 3;
+/// number of cells after which a session key is replaced by rotating the tunnel
+const DEFAULT_KEY_ROTATION_THRESHOLD: u64 = 1 << 24;
+/// number of cells after which a session key which could not be replaced is no longer used
+const DEFAULT_KEY_USAGE_CEILING: u64 = 1 << 28;
 
 /// The unique ID of a tunnel.
 pub type TunnelId = u32;
 
+/// Answered with the round-trip time of the next echo answered on the path of a tunnel, see
+/// [`OnionContext::ping_tunnel`](crate::OnionContext::ping_tunnel).
+pub(crate) type PingRequest = oneshot::Sender<Duration>;
+
+/// A request of the [`OnionContext`](crate::OnionContext) to the handler of an outgoing tunnel.
+pub(crate) enum HandlerRequest {
+    Ping(PingRequest),
+    /// answered once the tunnel has been moved to fresh session keys, see
+    /// [`OnionContext::rekey_tunnel`](crate::OnionContext::rekey_tunnel)
+    Rekey(oneshot::Sender<Result<()>>),
+}
+
 #[derive(Error, Debug)]
 pub(crate) enum TunnelError {
     /// The requested operation could not be run to completion, but the tunnel has a consistent
     /// state that can be expanded on
     #[error("Tunnel operation could not be completed")]
     Incomplete,
+    /// The new hop could not prove its identity or no session key could be derived. The hop has
+    /// been truncated again and the last hop answered an echo through the whole path, so the
+    /// tunnel has a consistent state that can be expanded on. Otherwise `Broken` is returned.
+    #[error("Key derivation with the new hop failed")]
+    KeyDerivation,
+    /// The last hop refused to extend the tunnel, since its address policy denies the address of
+    /// the new hop. The tunnel is left unchanged and can be extended to another peer.
+    #[error("Extension refused by the address policy of the last hop")]
+    PolicyRefused,
+    /// The operation would change the intermediate hops of a direct tunnel, which has none. The
+    /// tunnel is left unchanged.
+    #[error("Tunnel operation does not apply to a direct tunnel")]
+    Direct,
     /// The requested operation could not be completed and the tunnel is left in a broken state
     /// that needs to be cleaned up. This may be triggered by an undecryptable `OPAQUE` message,
     /// or a `TEARDOWN` message from the first hop.
@@ -36,9 +105,10 @@ pub(crate) enum TunnelError {
 
 impl From<OnionSocketError> for TunnelError {
     fn from(e: OnionSocketError) -> Self {
-        match e {
-            OnionSocketError::Peer => TunnelError::Incomplete,
-            e => TunnelError::Broken(Some(e)),
+        match e.kind {
+            SocketErrorKind::Peer => TunnelError::Incomplete,
+            SocketErrorKind::PolicyRefused => TunnelError::PolicyRefused,
+            _ => TunnelError::Broken(Some(e)),
         }
     }
 }
@@ -48,75 +118,235 @@ pub(crate) type TunnelResult<T> = std::result::Result<T, TunnelError>;
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub(crate) enum Event {
     Switchover,
-    Destroy,
     KeepAlive,
+    /// the onion router shuts down, all tunnels are destroyed immediately
+    Shutdown,
 }
 
 /// Represents the tunnel controller view of a tunnel.
 /// Manages the first circuit and stores all session keys in encryption order.
+///
+/// A direct tunnel has no intermediate hops, its first hop is the destination. It is built and
+/// rotated like any other tunnel, but can not be extended or truncated.
 pub(crate) struct Tunnel {
     pub(crate) id: TunnelId,
     out_circuit: Circuit,
     session_keys: Vec<SessionKey>,
+    /// the hops in path order, i.e. in reverse order of `session_keys`
+    path: Vec<Hop>,
+    /// cipher suites offered to each hop
+    cipher_suites: CipherSuites,
+    /// set if the first hop is the destination
+    direct: bool,
+    /// set if the last hop is the endpoint of the tunnel, see [`Target::Relay`]
+    relay_terminated: bool,
 }
 
 impl Tunnel {
     /// Performs a circuit handshake with the first hop (peer).
-    pub(crate) async fn init(id: TunnelId, peer: &Peer) -> Result<Self> {
+    #[cfg(test)]
+    pub(crate) async fn init(
+        id: TunnelId,
+        peer: &Peer,
+        cell_size: CellSize,
+        cipher_suites: CipherSuites,
+    ) -> Result<Self> {
+        let rng = Box::new(SystemRandom);
+        let timeouts = Default::default();
+        Tunnel::init_with(id, peer, cell_size, cipher_suites, None, rng, timeouts).await
+    }
+
+    /// Performs a circuit handshake with the first hop (peer).
+    ///
+    /// If `connections` is given and the cell size allows it, the circuit is opened on the
+    /// connection to the peer shared with the circuits of other tunnels. Otherwise the circuit gets
+    /// a connection of its own, on which its id is drawn from `rng`. The reads and writes on the
+    /// circuit are subject to `timeouts`.
+    pub(crate) async fn init_with(
+        id: TunnelId,
+        peer: &Peer,
+        cell_size: CellSize,
+        cipher_suites: CipherSuites,
+        connections: Option<&ConnectionCache>,
+        rng: Box<dyn OnionRng>,
+        timeouts: SocketTimeouts,
+    ) -> Result<Self> {
         trace!("Creating tunnel {} to peer {}", id, &peer.addr);
         let (private_key, key) = crypto::generate_ephemeral_keypair();
+        let features = FeatureOffer::for_suites(cipher_suites);
 
-        let circuit_id = Circuit::random_id();
-        let stream = TcpStream::connect(peer.addr)
-            .await
-            .context("Could not connect to peer")?;
-        let mut socket = OnionSocket::new(stream);
-        let peer_key = socket
-            .initiate_handshake(circuit_id, key)
-            .await
-            .context("Handshake failed while initializing new tunnel")?;
+        let (circuit_id, socket, peer_key) = match connections {
+            Some(connections) if connection::is_shareable(cell_size) => {
+                let (circuit_id, stream) = connections
+                    .open(peer.addr)
+                    .await
+                    .context("Could not connect to peer")?;
+                let mut socket =
+                    OnionSocket::from_stream(CircuitStream::Shared(stream)).with_timeouts(timeouts);
+                let res = socket
+                    .initiate_handshake_with_id(circuit_id, key, cell_size, cipher_suites, features)
+                    .await;
+                match res {
+                    Ok(peer_key) => (circuit_id, socket, peer_key),
+                    Err(e) => {
+                        // the peer may still answer, make it release the id before it is reused
+                        let teardown = socket.teardown(circuit_id, EndReason::ProtocolError);
+                        let _ = time::timeout(TEARDOWN_TIMEOUT, teardown).await;
+                        return Err(e).context("Handshake failed while initializing new tunnel");
+                    }
+                }
+            }
+            _ => {
+                let stream = TcpStream::connect(peer.addr)
+                    .await
+                    .context("Could not connect to peer")?;
+                let mut socket = OnionSocket::from_stream(stream.into())
+                    .with_rng(rng)
+                    .with_timeouts(timeouts);
+                let (circuit_id, peer_key) = socket
+                    .initiate_handshake(key, cell_size, cipher_suites, features)
+                    .await
+                    .context("Handshake failed while initializing new tunnel")?;
+                (circuit_id, socket, peer_key)
+            }
+        };
 
-        let secret = Tunnel::derive_secret(&peer, private_key, peer_key)
+        let (secret, hop) = Tunnel::derive_secret(peer, private_key, peer_key, cipher_suites)
+            .await
             .context("SessionKey derivation failed")?;
+        let params = CircuitParams::new(socket.cell_size(), hop.suite, hop.version);
+        debug!("Created tunnel {} to peer {} ({})", id, &peer.addr, params);
         Ok(Self {
             id,
-            out_circuit: Circuit::new(circuit_id, socket),
+            out_circuit: Circuit::new(circuit_id, socket).with_params(params),
             session_keys: vec![secret],
+            path: vec![Hop {
+                peer: peer.clone(),
+                params,
+                peer_version: hop.peer_version,
+                usage: Default::default(),
+                created: Instant::now(),
+            }],
+            cipher_suites,
+            direct: false,
+            relay_terminated: false,
         })
     }
 
-    fn derive_secret(
-        peer: &&Peer,
+    /// Verifies the signed key of `peer` and derives the session key with it.
+    ///
+    /// The signature is verified on the blocking thread pool, since verifying a RSA signature
+    /// would stall the other tasks of the worker thread.
+    async fn derive_secret(
+        peer: &Peer,
         private_key: EphemeralPrivateKey,
         peer_key: VerifyKey,
-    ) -> Result<SessionKey> {
-        let peer_key = peer_key
-            .verify(&peer.hostkey)
-            .context("Could not verify peer public key")?;
-        let secret = SessionKey::from_key_exchange(private_key, &peer_key)?;
-        Ok(secret)
+        cipher_suites: CipherSuites,
+    ) -> Result<(SessionKey, Handshake)> {
+        let hostkey = peer.hostkey.clone();
+        let verified = task::spawn_blocking("task.verify_key", move || {
+            peer_key.verify(
+                &hostkey,
+                cipher_suites,
+                FeatureOffer::for_suites(cipher_suites),
+            )
+        })
+        .await?
+        .context("Could not verify peer public key")?;
+        let mut secret = SessionKey::from_key_exchange(
+            private_key,
+            &verified.key,
+            verified.suite,
+            verified.salt.as_deref(),
+            verified.version,
+        )?;
+        let handshake = Handshake {
+            suite: verified.suite,
+            version: verified.version,
+            peer_version: verified.peer_version,
+        };
+        if handshake.version >= HandshakeVersion::SequencedNonces {
+            secret = secret.with_sequence_check();
+        }
+        Ok((secret, handshake))
+    }
+
+    /// Returns the suites offered to the next hop. The last hop relays the reply of the next hop,
+    /// so a version it does not know is not offered, as it would drop the parts added by that
+    /// version. A relay predating the negotiation drops the whole offer, so the next hop answers it
+    /// in the layout of such peers.
+    fn next_hop_suites(&self) -> CipherSuites {
+        let relay_version = self
+            .path
+            .last()
+            .map_or(HandshakeVersion::Unsalted, |hop| hop.peer_version);
+        self.cipher_suites
+            .with_version(cmp::min(self.cipher_suites.version(), relay_version))
+    }
+
+    /// Returns the handshake version negotiated with each hop, in path order.
+    #[cfg(test)]
+    pub(crate) fn handshake_versions(&self) -> Vec<HandshakeVersion> {
+        self.path.iter().map(|hop| hop.params.handshake).collect()
+    }
+
+    /// Returns the cell size negotiated with the first hop, which is used by all hops.
+    pub(crate) fn cell_size(&self) -> CellSize {
+        self.out_circuit.socket.cell_size()
     }
 
     /// Returns the length of a tunnel. The result of this function may be used with caution if the
     /// tunnel is in a broken state.
+    ///
+    /// A tunnel starts with one hop and [`truncate`](Tunnel::truncate) never removes the first
+    /// one, so the length is at least one. Callers still do not rely on this, an empty tunnel is
+    /// treated as broken.
     pub(crate) fn len(&self) -> usize {
         self.session_keys.len()
     }
 
     /// Performs a key exchange with the given peer and extends the tunnel with a new hop
+    ///
+    /// Returns `Direct` for a direct tunnel.
     pub(crate) async fn extend(&mut self, peer: &Peer) -> TunnelResult<()> {
+        if self.direct {
+            return Err(TunnelError::Direct);
+        }
         trace!("Extending tunnel {} to peer {}", self.id, &peer.addr);
         let (private_key, key) = crypto::generate_ephemeral_keypair();
+        let cipher_suites = self.next_hop_suites();
 
         let peer_key = self
             .out_circuit
             .socket
-            .initiate_tunnel_handshake(self.out_circuit.id, peer.addr, key, &self.session_keys)
+            .initiate_tunnel_handshake(
+                self.out_circuit.id,
+                peer.addr,
+                key,
+                cipher_suites,
+                FeatureOffer::for_suites(cipher_suites),
+                &self.session_keys,
+            )
             .await?;
 
         // Any failure because of any incorrect secret answer should not cause our tunnel to become corrupted
-        if let Ok(secret) = Tunnel::derive_secret(&peer, private_key, peer_key) {
+        if let Ok((secret, hop)) =
+            Tunnel::derive_secret(peer, private_key, peer_key, cipher_suites).await
+        {
+            let params = CircuitParams::new(self.cell_size(), hop.suite, hop.version);
+            debug!(
+                "Extended tunnel {} to peer {} ({})",
+                self.id, &peer.addr, params
+            );
             self.session_keys.insert(0, secret);
+            self.path.push(Hop {
+                peer: peer.clone(),
+                params,
+                peer_version: hop.peer_version,
+                usage: Default::default(),
+                created: Instant::now(),
+            });
+            self.debug_check_keys();
             Ok(())
         } else {
             // key derivation failed, the final hop needs to be truncated
@@ -124,15 +354,28 @@ impl Tunnel {
             self.truncate(0)
                 .await
                 .map_err(|_| TunnelError::Broken(None))?;
-            Err(TunnelError::Incomplete)
+            // if the relays disagree with us on the length of the path, every later message would
+            // be dropped by the hop it is addressed to
+            self.probe_last(VERIFY_TIMEOUT).await.map_err(|e| {
+                warn!(
+                    "Tunnel {} is inconsistent after a failed extend: {}",
+                    self.id, e
+                );
+                TunnelError::Broken(None)
+            })?;
+            Err(TunnelError::KeyDerivation)
         }
     }
 
     /// Truncates the tunnel by `n` hops with one `TUNNEL TRUNCATE` message. If message returns with
     /// an error code, `Incomplete` will be returned.
     ///
-    /// Returns `Incomplete` if the resulting hop count would be less than one.
+    /// Returns `Incomplete` if the resulting hop count would be less than one and `Direct` for a
+    /// direct tunnel.
     pub(crate) async fn truncate(&mut self, n: usize) -> TunnelResult<()> {
+        if self.direct {
+            return Err(TunnelError::Direct);
+        }
         if n >= self.session_keys.len() {
             return Err(TunnelError::Incomplete);
         }
@@ -145,6 +388,8 @@ impl Tunnel {
         for _ in 0..n {
             self.session_keys.remove(0);
         }
+        self.path.truncate(self.session_keys.len());
+        self.debug_check_keys();
         Ok(())
     }
 
@@ -163,20 +408,63 @@ impl Tunnel {
     /// before tearing down the old tunnel. Be aware that the other endpoint peer should not be
     /// allowed to use the old tunnel indefinitely despite receiving a `TUNNEL END` packet. Any old
     /// tunnel that has been replaced should only have finite lifetime.
+    ///
+    /// A tunnel terminated at its last relay sends `TUNNEL TERMINATE` instead and waits until the
+    /// relay answers an echo, which it only does if it accepted the role.
     pub(crate) async fn begin(&mut self) -> TunnelResult<()> {
+        if self.relay_terminated {
+            return self.terminate().await;
+        }
         self.out_circuit
             .socket
             .begin(self.out_circuit.id, self.id, &self.session_keys)
             .await?;
+        self.debug_check_keys();
         Ok(())
     }
 
-    /// Ends a data connection with the last hop in the tunnel
-    pub(crate) async fn end(&mut self) -> TunnelResult<()> {
+    /// Makes the last hop the endpoint of this tunnel with a `TUNNEL TERMINATE` message.
+    async fn terminate(&mut self) -> TunnelResult<()> {
+        self.out_circuit
+            .socket
+            .terminate(self.out_circuit.id, self.id, &self.session_keys)
+            .await?;
+        self.probe_last(TERMINATE_TIMEOUT).await
+    }
+
+    /// Sends an echo through the whole path and waits at most `timeout` for the answer of the last
+    /// hop, which shows that the relays agree with the session keys on the length of the path.
+    async fn probe_last(&mut self, timeout: Duration) -> TunnelResult<()> {
+        let last = match self.len().checked_sub(1) {
+            Some(last) => last,
+            None => return Err(TunnelError::Broken(None)),
+        };
+        match time::timeout(timeout, self.probe(last)).await {
+            Ok(res) => Ok(res?),
+            Err(_) => Err(TunnelError::Broken(None)),
+        }
+    }
+
+    /// Checks in debug builds that a session key is stored for every hop of the path, so every
+    /// socket operation uses as many keys as the path has hops.
+    pub(crate) fn debug_check_keys(&self) {
+        debug_assert_eq!(
+            self.session_keys.len(),
+            self.path.len(),
+            "tunnel {} has {} session keys for {} hops",
+            self.id,
+            self.session_keys.len(),
+            self.path.len()
+        );
+    }
+
+    /// Ends a data connection with the last hop in the tunnel for `reason`
+    pub(crate) async fn end(&mut self, reason: EndReason) -> TunnelResult<()> {
         self.out_circuit
             .socket
-            .end(self.out_circuit.id, self.id, &self.session_keys)
+            .end(self.out_circuit.id, self.id, reason, &self.session_keys)
             .await?;
+        self.debug_check_keys();
         Ok(())
     }
 
@@ -191,24 +479,155 @@ impl Tunnel {
             .socket
             .send_keep_alive(self.out_circuit.id, &self.session_keys)
             .await?;
+        self.debug_check_keys();
+        Ok(())
+    }
+
+    /// Sends a `TUNNEL ECHO` message with the given `nonce` to the final hop without waiting for
+    /// the answer, which is read along with the data of the tunnel.
+    pub(crate) async fn echo(&mut self, nonce: u32) -> TunnelResult<()> {
+        self.out_circuit
+            .socket
+            .send_echo(self.out_circuit.id, nonce, &self.session_keys)
+            .await?;
+        self.debug_check_keys();
+        Ok(())
+    }
+
+    /// Sends a `TUNNEL PADDING` message with `len` bytes of padding to the final hop, which drops
+    /// it. The relays can not tell it apart from data.
+    pub(crate) async fn pad(&mut self, len: usize) -> TunnelResult<()> {
+        self.out_circuit
+            .socket
+            .send_padding(self.out_circuit.id, len, &self.session_keys)
+            .await?;
+        self.debug_check_keys();
         Ok(())
     }
 
-    pub(crate) async fn _truncate_to_length(&mut self, n_hops: usize) -> TunnelResult<()> {
+    /// Probes the hops of the tunnel in path order with `TUNNEL ECHO` messages and returns the
+    /// position of the first hop which does not answer within [`diagnosis::PROBE_TIMEOUT`]. Every
+    /// hop before it relays, so this hop, or the connection to it, is the suspected cause of a
+    /// failure of the path.
+    ///
+    /// Returns `None` if every hop answers, the circuit has been torn down, or `budget` is used up
+    /// before a hop was found unresponsive. Other messages received in the meantime are dropped,
+    /// so the tunnel should only be diagnosed once its path is considered broken.
+    pub(crate) async fn diagnose(&mut self, budget: Duration) -> Option<usize> {
+        let deadline = Instant::now() + budget;
+        for position in 0..self.len() {
+            let probe_deadline = cmp::min(Instant::now() + diagnosis::PROBE_TIMEOUT, deadline);
+            match time::timeout_at(probe_deadline, self.probe(position)).await {
+                Ok(Ok(())) => {}
+                // the first hop is unreachable
+                Ok(Err(OnionSocketError {
+                    kind: SocketErrorKind::StreamTerminated(_) | SocketErrorKind::StreamTimeout(_),
+                    ..
+                })) => return Some(0),
+                Ok(Err(_)) => return None,
+                // the hop did not get the full probe timeout
+                Err(_) if probe_deadline == deadline => return None,
+                Err(_) => return Some(position),
+            }
+        }
+        None
+    }
+
+    /// Sends a `TUNNEL ECHO` message to the hop at `position` in path order and waits for its
+    /// answer.
+    async fn probe(&mut self, position: usize) -> SocketResult<()> {
+        let keys = &self.session_keys[self.session_keys.len() - 1 - position..];
+        let mut nonce = [0u8; 4];
+        crypto::fill_random(&mut nonce);
+        let nonce = u32::from_le_bytes(nonce);
+        self.out_circuit
+            .socket
+            .send_echo(self.out_circuit.id, nonce, keys)
+            .await?;
+
+        let verifier = HopVerifier::new(&keys[0], Direction::Backward);
+        loop {
+            let mut msg = self.out_circuit.accept_opaque().await?;
+            if msg.decrypt(Direction::Backward, keys.iter().rev()).is_err() {
+                continue;
+            }
+            match TunnelResponseEchoed::read_with_digest_from(&mut msg.payload.bytes, &verifier) {
+                Ok(TunnelResponseEchoed(echoed))
+                    if echoed == nonce && msg.is_in_sequence(&keys[0], Direction::Backward) =>
+                {
+                    self.debug_check_keys();
+                    return Ok(());
+                }
+                _ => trace!("Dropping message received while probing tunnel {}", self.id),
+            }
+        }
+    }
+
+    /// Returns the hop at `position` in path order.
+    pub(crate) fn hop(&self, position: usize) -> Option<&Peer> {
+        self.path.get(position).map(|hop| &hop.peer)
+    }
+
+    /// Returns the hops in path order together with the parameters negotiated with each of them.
+    pub(crate) fn hop_info(&self) -> Vec<HopInfo> {
+        self.path
+            .iter()
+            .map(|hop| HopInfo {
+                addr: hop.peer.addr,
+                fingerprint: hop.peer.fingerprint(),
+                params: hop.params,
+            })
+            .collect()
+    }
+
+    /// Records `n_cells` cells sent or received over the whole path, each of which is processed with
+    /// the session key of every hop.
+    pub(crate) fn record_cells(&mut self, direction: Direction, n_cells: usize) {
+        let n_bytes = (n_cells * self.cell_size().bytes()) as u64;
+        for hop in &mut self.path {
+            hop.usage.record(direction, n_cells as u64, n_bytes);
+        }
+    }
+
+    /// Returns the number of cells and bytes processed in one direction with the most used session
+    /// key of the path.
+    pub(crate) fn key_usage(&self) -> (u64, u64) {
+        self.path
+            .iter()
+            .map(|hop| hop.usage.most_used())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Returns when the oldest session key of the path was agreed on. The keys of the first hops
+    /// outlive a splice.
+    pub(crate) fn keys_created(&self) -> Option<Instant> {
+        self.path.iter().map(|hop| hop.created).min()
+    }
+
+    /// Truncates the tunnel hop by hop until it consists of its first `len` hops.
+    ///
+    /// Returns `Incomplete` if truncating fails repeatedly or `len` is zero, and `Direct` for a
+    /// direct tunnel.
+    pub(crate) async fn truncate_to_length(&mut self, len: usize) -> TunnelResult<()> {
+        if self.direct {
+            return Err(TunnelError::Direct);
+        }
+        if len == 0 {
+            return Err(TunnelError::Incomplete);
+        }
         let mut num_fails = 0;
 
-        while self.session_keys.len() > n_hops + 1 {
+        while self.session_keys.len() > len {
             match self.truncate(1).await {
-                Err(TunnelError::Broken(e)) => {
-                    // do not try to fix this error to prevent endless looping
-                    return Err(TunnelError::Broken(e));
-                }
-                Err(TunnelError::Incomplete) => {
+                Err(TunnelError::Incomplete) | Err(TunnelError::KeyDerivation) => {
                     num_fails += 1;
                     if num_fails >= MAX_PEER_FAILURES {
                         return Err(TunnelError::Incomplete);
                     }
                 }
+                // do not try to fix this error to prevent endless looping
+                Err(e) => return Err(e),
                 Ok(_) => {}
             }
         }
@@ -216,20 +635,68 @@ impl Tunnel {
         Ok(())
     }
 
-    async fn unbuild(&mut self) {
+    async fn unbuild(&mut self, reason: EndReason) {
         // TODO graceful deconstruction
-        self.teardown().await;
+        self.teardown(reason).await;
+    }
+
+    async fn teardown(&mut self, reason: EndReason) {
+        self.out_circuit.teardown_with_timeout(reason).await;
+    }
+}
+
+/// A hop of a [`Tunnel`].
+struct Hop {
+    peer: Peer,
+    params: CircuitParams,
+    /// the latest handshake version supported by this hop
+    peer_version: HandshakeVersion,
+    /// the cells processed with the session key of this hop
+    usage: KeyUsage,
+    /// when the session key of this hop was agreed on
+    created: Instant,
+}
+
+/// The cells processed with a session key, counted for each direction.
+#[derive(Copy, Clone, Debug, Default)]
+struct KeyUsage {
+    forward_cells: u64,
+    forward_bytes: u64,
+    backward_cells: u64,
+    backward_bytes: u64,
+}
+
+impl KeyUsage {
+    fn record(&mut self, direction: Direction, n_cells: u64, n_bytes: u64) {
+        let (cells, bytes) = match direction {
+            Direction::Forward => (&mut self.forward_cells, &mut self.forward_bytes),
+            Direction::Backward => (&mut self.backward_cells, &mut self.backward_bytes),
+        };
+        *cells += n_cells;
+        *bytes += n_bytes;
     }
 
-    async fn teardown(&mut self) {
-        self.out_circuit.teardown_with_timeout().await;
+    /// Returns the cells and bytes of the direction in which more cells were processed.
+    fn most_used(&self) -> (u64, u64) {
+        cmp::max(
+            (self.forward_cells, self.forward_bytes),
+            (self.backward_cells, self.backward_bytes),
+        )
     }
 }
 
-pub fn random_id() -> TunnelId {
-    // FIXME an attacker may fill up all ids
+/// The outcome of a handshake with a hop, see [`Tunnel::derive_secret`].
+struct Handshake {
+    suite: CipherSuite,
+    version: HandshakeVersion,
+    peer_version: HandshakeVersion,
+}
+
+/// Generates a random tunnel ID. Uniqueness among the outgoing tunnels of an onion router is
+/// ensured by [`TunnelRegistry::allocate_id`](crate::onion::TunnelRegistry::allocate_id).
+pub fn random_id(rng: &mut dyn OnionRng) -> TunnelId {
     let mut id_buf = [0u8; 4];
-    crypto::fill_random(&mut id_buf);
+    rng.fill_bytes(&mut id_buf);
     u32::from_le_bytes(id_buf)
 }
 
@@ -237,14 +704,97 @@ pub fn random_id() -> TunnelId {
 pub enum Target {
     Peer(Peer),
     Random,
+    /// The tunnel ends at its last relay, which drops padding and answers echoes, but rejects
+    /// data. There is no destination application.
+    Relay,
+}
+
+/// Where the intermediate hops of a tunnel come from.
+#[derive(Clone, Debug)]
+pub(crate) enum RouteSource {
+    /// `n_hops` hops, each either constrained by the [`TunnelOptions`] or drawn from the peer
+    /// provider.
+    Random { n_hops: usize },
+    /// Exactly the given peers in path order.
+    Explicit(Vec<Peer>),
+}
+
+/// A peer drawn from the peer provider while the previous hop is added, which saves waiting for
+/// the provider afterwards. The draw is aborted when the prefetch is dropped.
+///
+/// The peer is only checked when it is used, against the path at that time. Clones start without
+/// a prefetched peer, so no draw is used by two builders.
+#[derive(Default)]
+struct Prefetch(Option<task::AbortOnDrop<Option<Result<Peer>>>>);
+
+impl Clone for Prefetch {
+    fn clone(&self) -> Self {
+        Prefetch(None)
+    }
+}
+
+impl Prefetch {
+    /// Starts drawing a peer from `peer_provider`, unless a peer is already being drawn.
+    fn start(&mut self, peer_provider: &PeerProvider) {
+        if self.0.is_none() {
+            let mut peer_provider = peer_provider.clone();
+            let draw = task::spawn("task.prefetch_peer", async move {
+                peer_provider.random_peer().await
+            });
+            self.0 = Some(task::abort_on_drop(draw));
+        }
+    }
+
+    /// Waits for the peer being drawn, if any.
+    async fn take(&mut self) -> Option<Result<Peer>> {
+        match self.0.take()?.await {
+            Ok(Some(result)) => Some(result),
+            // the provider panicked, which is logged by the task
+            _ => None,
+        }
+    }
+}
+
+impl RouteSource {
+    fn n_hops(&self) -> usize {
+        match self {
+            RouteSource::Random { n_hops } => *n_hops,
+            RouteSource::Explicit(path) => path.len(),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub(crate) struct TunnelBuilder {
     tunnel_id: TunnelId,
     dest: Target,
-    n_hops: usize,
+    route: RouteSource,
     peer_provider: PeerProvider,
+    /// the random peer for the next hop, drawn while the current hop is added
+    prefetch: Prefetch,
+    options: TunnelOptions,
+    capabilities: CapabilityCache,
+    known_peers: KnownPeers,
+    /// peers which are only chosen as random hops if no other peer is available
+    suspects: SuspectedPeers,
+    /// the only peers chosen as random first hop, if enabled
+    guards: EntryGuards,
+    /// destinations which failed recently, towards which automatic rebuilds are delayed
+    retries: DestinationRetries,
+    /// the handshakes in progress with each peer
+    handshakes: HandshakeLimiter,
+    /// whether a build report is recorded
+    build_reports: bool,
+    timeouts: BuildTimeouts,
+    /// statistics of the tunnel, shared with its handler
+    pub(crate) stats: Arc<onion::TunnelCounters>,
+    pub(crate) observer: Observer,
+    /// creates the generator of the circuit id of every first hop
+    rng: RngFactory,
+    /// the connections shared by the first hops of the tunnels, if enabled
+    connections: Option<ConnectionCache>,
+    /// applied to the operations on the circuit to the first hop
+    socket_timeouts: SocketTimeouts,
 }
 
 impl TunnelBuilder {
@@ -257,8 +807,145 @@ impl TunnelBuilder {
         TunnelBuilder {
             tunnel_id,
             dest,
-            n_hops,
+            route: RouteSource::Random { n_hops },
             peer_provider,
+            prefetch: Default::default(),
+            options: Default::default(),
+            capabilities: Default::default(),
+            known_peers: Default::default(),
+            suspects: Default::default(),
+            guards: Default::default(),
+            retries: Default::default(),
+            handshakes: Default::default(),
+            build_reports: false,
+            timeouts: Default::default(),
+            stats: Default::default(),
+            observer: Default::default(),
+            rng: Default::default(),
+            connections: None,
+            socket_timeouts: Default::default(),
+        }
+    }
+
+    pub(crate) fn with_options(
+        mut self,
+        options: TunnelOptions,
+        capabilities: CapabilityCache,
+        known_peers: KnownPeers,
+    ) -> Self {
+        if let Some(peer_provider) = &options.peer_provider {
+            self.peer_provider = peer_provider.clone();
+        }
+        if options.write_feedback {
+            self.stats = Arc::new(onion::TunnelCounters::with_write_feedback());
+        }
+        self.options = options;
+        self.capabilities = capabilities;
+        self.known_peers = known_peers;
+        self
+    }
+
+    /// Extends the tunnel through exactly the peers of `path` instead of choosing its hops, see
+    /// [`select_hop`](TunnelBuilder::select_hop).
+    ///
+    /// Replaces the number of hops the builder was created with by the length of `path`.
+    pub(crate) fn with_explicit_path(mut self, path: Vec<Peer>) -> Self {
+        self.route = RouteSource::Explicit(path);
+        self
+    }
+
+    /// Returns a builder for a path through the same peers as `tunnel`, including its destination,
+    /// whose hops share fresh session keys with this peer.
+    pub(crate) fn for_same_path(&self, tunnel: &Tunnel) -> Self {
+        let mut peers: Vec<_> = tunnel.path.iter().map(|hop| hop.peer.clone()).collect();
+        let mut builder = self.clone();
+        if !matches!(builder.dest, Target::Relay) {
+            if let Some(dest) = peers.pop() {
+                builder.dest = Target::Peer(dest);
+            }
+        }
+        builder.with_explicit_path(peers)
+    }
+
+    /// Sets whether a [`BuildReport`] is recorded for each build.
+    ///
+    /// The report of a successful build is stored in the statistics of the tunnel, while the
+    /// report of a failed build is attached to the returned error.
+    pub(crate) fn with_build_reports(mut self, enable: bool) -> Self {
+        self.build_reports = enable;
+        self
+    }
+
+    pub(crate) fn with_observer(mut self, observer: Observer) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Avoids the `suspects` when choosing random hops.
+    pub(crate) fn with_suspects(mut self, suspects: SuspectedPeers) -> Self {
+        self.suspects = suspects;
+        self
+    }
+
+    /// Chooses the first hop among the `guards`, if they are enabled.
+    pub(crate) fn with_guards(mut self, guards: EntryGuards) -> Self {
+        self.guards = guards;
+        self
+    }
+
+    /// Records the failures of the destination in `retries`, which delay [`rebuild`].
+    ///
+    /// [`rebuild`]: TunnelBuilder::rebuild
+    pub(crate) fn with_retries(mut self, retries: DestinationRetries) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Gives up on hops and whole builds which take longer than the `timeouts`.
+    pub(crate) fn with_timeouts(mut self, timeouts: BuildTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Waits for a permit from `handshakes` before each handshake with a hop.
+    pub(crate) fn with_handshake_limiter(mut self, handshakes: HandshakeLimiter) -> Self {
+        self.handshakes = handshakes;
+        self
+    }
+
+    /// Draws the circuit id of every first hop from a generator created by `rng`.
+    pub(crate) fn with_rng(mut self, rng: RngFactory) -> Self {
+        self.rng = rng;
+        self
+    }
+
+    /// Opens the circuit to every first hop on the connections of `connections`, if set.
+    pub(crate) fn with_connection_cache(mut self, connections: Option<ConnectionCache>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Applies `timeouts` to the reads and writes on the circuit to every first hop.
+    pub(crate) fn with_socket_timeouts(mut self, timeouts: SocketTimeouts) -> Self {
+        self.socket_timeouts = timeouts;
+        self
+    }
+
+    /// Returns the fingerprint of the destination, if it is a given peer.
+    pub(crate) fn destination(&self) -> Option<Fingerprint> {
+        match &self.dest {
+            Target::Peer(peer) => Some(peer.fingerprint()),
+            Target::Random | Target::Relay => None,
+        }
+    }
+
+    /// Returns whether the destination is known to drop padding, see [`Capabilities::PADDING`].
+    pub(crate) fn dest_supports_padding(&self) -> bool {
+        match &self.dest {
+            Target::Peer(peer) => self.capabilities.supports(peer, Capabilities::PADDING),
+            Target::Random => false,
+            // dropping padding is part of the role of the last relay
+            Target::Relay => true,
         }
     }
 
@@ -268,76 +955,752 @@ impl TunnelBuilder {
     /// the final hop at index `n` will be `final_peer`.
     ///
     /// This function does not check whether the peers provided by `peer_provider` are particularity
-    /// secure. In order to preserve anonymity, there should never be two consecutive hops to the
-    /// same peer. Also, `peer_provider` should produce peers in a way that potentially malicious
-    /// peers with shared knowledge of circuits should be returned with a low probability (or with
-    /// equal probability to any other peer) to prevent the tunnel from becoming compromised.
+    /// secure. In order to preserve anonymity, there are never two consecutive hops to the same
+    /// peer: a random hop is neither the peer of the previous hop nor the destination, told apart
+    /// by their address, and is drawn again otherwise. Also, `peer_provider` should produce peers
+    /// in a way that potentially malicious peers with shared knowledge of circuits should be
+    /// returned with a low probability (or with equal probability to any other peer) to prevent
+    /// the tunnel from becoming compromised.
     ///
     /// Even if there is a high failure-rate among peers, the `peer_provider` should be able to
     /// generate a secure stream of peers.
+    ///
+    /// Peers known to lack the capabilities required by the [`TunnelOptions`] or rejected by its hop
+    /// filter are not used, peers suspected to have caused a path failure only if no other peer is
+    /// available.
+    /// Hops constrained by the [`TunnelOptions`] are never substituted by random peers. In strict
+    /// mode, no peer is used for two positions of the path.
+    ///
+    /// With `n_hops == 0` a direct tunnel is built, whose first hop is the destination.
+    ///
+    /// With an explicit path, see [`with_explicit_path`], no peer is substituted: the build fails
+    /// with [`HopSelectionError::Unreachable`] at the first hop which could not be added,
+    /// including the destination.
+    ///
+    /// Intermediate hops which could not be added are replaced by other peers, while the build
+    /// fails with [`DestinationUnreachable`] once the destination failed 3 times. Fails with
+    /// [`PathUnreachable`] if adding hops failed too often in total.
+    ///
+    /// Hops which do not complete within the hop timeout count as failed. Fails with
+    /// [`BuildTimedOut`] if the path is not complete by the build deadline.
+    ///
+    /// [`with_explicit_path`]: TunnelBuilder::with_explicit_path
     pub(crate) async fn build(&mut self) -> Result<Tunnel> {
+        let mut report = BuildReport::default();
+        let deadline = self.timeouts.deadline;
+        let result = match time::timeout(deadline, self.build_path(&mut report)).await {
+            Ok(result) => result,
+            Err(_) => Err(BuildTimedOut { deadline }.into()),
+        };
+        // a peer still drawn, e.g. for a hop after a failure, is not kept for later builds
+        self.prefetch = Default::default();
+        self.record_destination(&result, &report);
+        self.finish_report(result, report)
+    }
+
+    /// Builds a replacement path like [`build`], but only once the backoff of a destination which
+    /// failed recently has ended.
+    ///
+    /// [`build`]: TunnelBuilder::build
+    pub(crate) async fn rebuild(&mut self) -> Result<Tunnel> {
+        self.wait_for_destination().await;
+        self.build().await
+    }
+
+    /// Returns the backoff of the destination, if it failed recently, see [`DestinationRetries`].
+    pub(crate) fn destination_backoff(&self) -> Option<RetryBackoff> {
+        self.retries.backoff(&self.destination()?)
+    }
+
+    /// Waits until the backoff of the destination has ended.
+    async fn wait_for_destination(&self) {
+        if let Some(backoff) = self.destination_backoff() {
+            debug!(
+                "Delaying rebuild of tunnel {} by {:?} after {} failures at its destination",
+                self.tunnel_id, backoff.retry_in, backoff.failures
+            );
+            time::sleep(backoff.retry_in).await;
+        }
+    }
+
+    /// Extends `tunnel`, which has been truncated, to a complete path like [`build`] does.
+    ///
+    /// If an error is returned, the tunnel may be left in any state and should be torn down.
+    ///
+    /// [`build`]: TunnelBuilder::build
+    pub(crate) async fn extend_path(&mut self, tunnel: &mut Tunnel) -> Result<()> {
+        let mut report = BuildReport::default();
+        let deadline = self.timeouts.deadline;
+        let result = match time::timeout(deadline, self.extend_remaining(tunnel, &mut report)).await
+        {
+            Ok(result) => result,
+            Err(_) => Err(BuildTimedOut { deadline }.into()),
+        };
+        self.prefetch = Default::default();
+        self.record_destination(&result, &report);
+        self.finish_report(result, report)
+    }
+
+    async fn extend_remaining(
+        &mut self,
+        tunnel: &mut Tunnel,
+        report: &mut BuildReport,
+    ) -> Result<()> {
+        for _ in 0..MAX_PEER_FAILURES {
+            if self.extend_next(tunnel, report).await? {
+                return Ok(());
+            }
+            self.check_destination(report)?;
+        }
+        Err(Self::path_unreachable(report))
+    }
+
+    /// Ends the backoff of the destination if it was reached, or extends it if the failed build
+    /// gave up while adding the destination.
+    fn record_destination<T>(&self, result: &Result<T>, report: &BuildReport) {
+        let fingerprint = match self.destination() {
+            Some(fingerprint) => fingerprint,
+            None => return,
+        };
+        if result.is_ok() {
+            self.retries.record_success(&fingerprint);
+            return;
+        }
+        let failed_at_dest = report.attempts().last().is_some_and(|attempt| {
+            attempt.hop == self.n_hops() && attempt.outcome != BuildOutcome::Ok
+        });
+        if failed_at_dest {
+            let delay = self.retries.record_failure(fingerprint);
+            debug!(
+                "Building tunnel {} failed at its destination, backing off for {:?}",
+                self.tunnel_id, delay
+            );
+        }
+    }
+
+    /// Stores the report of a successful build or attaches it to the error of a failed one.
+    fn finish_report<T>(&self, result: Result<T>, report: BuildReport) -> Result<T> {
+        if !self.build_reports {
+            return result;
+        }
+        match result {
+            Ok(tunnel) => {
+                self.stats.set_build_report(report);
+                Ok(tunnel)
+            }
+            Err(e) => Err(e.context(report)),
+        }
+    }
+
+    /// Returns the number of intermediate hops, i.e. the position of a given destination.
+    fn n_hops(&self) -> usize {
+        self.route.n_hops()
+    }
+
+    /// Returns the number of hops of a complete path.
+    fn path_len(&self) -> usize {
+        match self.dest {
+            Target::Peer(_) | Target::Random => self.n_hops() + 1,
+            Target::Relay => self.n_hops(),
+        }
+    }
+
+    /// Fails with [`DestinationUnreachable`] once adding the given destination failed
+    /// [`MAX_DEST_FAILURES`] times during the build recorded in `report`.
+    fn check_destination(&self, report: &BuildReport) -> Result<()> {
+        if !matches!(self.dest, Target::Peer(_)) {
+            return Ok(());
+        }
+        let failed: Vec<_> = report
+            .attempts()
+            .iter()
+            .filter(|attempt| attempt.hop == self.n_hops() && attempt.outcome != BuildOutcome::Ok)
+            .collect();
+        match failed.last() {
+            Some(last) if failed.len() >= MAX_DEST_FAILURES => Err(DestinationUnreachable {
+                failures: failed.len(),
+                last_outcome: last.outcome,
+            }
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the error of a build which gave up after adding hops failed too often.
+    fn path_unreachable(report: &BuildReport) -> anyhow::Error {
+        let failures = report
+            .attempts()
+            .iter()
+            .filter(|attempt| attempt.outcome != BuildOutcome::Ok)
+            .count();
+        PathUnreachable { failures }.into()
+    }
+
+    /// Returns whether the hops are given by an explicit path, so failed hops are not retried.
+    fn is_explicit(&self) -> bool {
+        matches!(self.route, RouteSource::Explicit(_))
+    }
+
+    /// Returns the number of positions whose peers are chosen, i.e. all but a given destination,
+    /// which takes the last position.
+    fn n_positions(&self) -> usize {
+        match self.dest {
+            Target::Peer(_) => self.n_hops(),
+            Target::Random | Target::Relay => self.path_len(),
+        }
+    }
+
+    /// Returns whether the peer of the hop at `position` is drawn from the peer provider.
+    fn is_random_position(&self, position: usize) -> bool {
+        let is_guard = position == 0 && self.guards.is_enabled();
+        position < self.n_positions()
+            && !self.is_explicit()
+            && !self.options.hops.contains_key(&position)
+            && !is_guard
+    }
+
+    /// Starts drawing the peer of the hop at `position`, if it is a random peer, see [`Prefetch`].
+    fn prefetch(&mut self, position: usize) {
+        if self.is_random_position(position) {
+            self.prefetch.start(&self.peer_provider);
+        }
+    }
+
+    async fn build_path(&mut self, report: &mut BuildReport) -> Result<Tunnel> {
+        let n_positions = self.n_positions();
+        if self.path_len() == 0 {
+            return Err(anyhow!(
+                "A tunnel terminated at a relay needs at least one hop"
+            ));
+        }
+        if let Some(&position) = self.options.hops.keys().find(|&&p| p >= n_positions) {
+            return Err(HopSelectionError::InvalidPosition { position }.into());
+        }
+
+        if let Target::Peer(peer) = &self.dest {
+            let required = self.options.required_capabilities;
+            if !self.capabilities.may_support(peer, required) {
+                return Err(anyhow!(
+                    "Destination {:?} lacks required capabilities {:?}",
+                    peer,
+                    required
+                ));
+            }
+        }
+
         let mut tunnel = None;
         for _ in 0..MAX_PEER_FAILURES {
             tunnel = match (tunnel.take(), &self.dest) {
-                (None, Target::Peer(peer)) if self.n_hops == 0 => {
-                    Tunnel::init(self.tunnel_id, peer)
-                        .await
-                        .map_err(|e| warn!("Error while building tunnel: {:?}", e))
-                        .ok()
+                (None, Target::Peer(peer)) if self.n_hops() == 0 => {
+                    self.init_hop(peer, report).await
                 }
                 (None, _) => {
-                    let peer = self
-                        .peer_provider
-                        .random_peer()
-                        .await
-                        .context(anyhow!("Failed to get random peer"))?;
-                    Tunnel::init(self.tunnel_id, &peer)
-                        .await
-                        .map_err(|e| warn!("Error while building tunnel: {:?}", e))
-                        .ok()
+                    let peer = self.select_hop(0, &[]).await?;
+                    self.prefetch(1);
+                    self.init_hop(&peer, report).await
                 }
-                (Some(mut tunnel), Target::Peer(peer)) if tunnel.len() == self.n_hops => {
-                    match tunnel.extend(peer).await {
-                        Err(TunnelError::Broken(e)) => {
-                            warn!("Error while building tunnel: {:?}", e);
-                            tunnel.teardown().await;
-                            None
-                        }
-                        Err(TunnelError::Incomplete) => Some(tunnel),
-                        Ok(_) => Some(tunnel),
+                (Some(mut tunnel), _) => match self.extend_next(&mut tunnel, report).await {
+                    Ok(true) => {
+                        tunnel.direct = self.n_hops() == 0;
+                        tunnel.relay_terminated = matches!(self.dest, Target::Relay);
+                        return Ok(tunnel);
                     }
-                }
-                (Some(mut tunnel), _) if tunnel.len() <= self.n_hops => {
-                    let peer = self
-                        .peer_provider
-                        .random_peer()
-                        .await
-                        .context(anyhow!("Failed to get random peer"))?;
-
-                    match tunnel.extend(&peer).await {
-                        Err(TunnelError::Broken(e)) => {
+                    Ok(false) => Some(tunnel),
+                    Err(e) if e.is::<HopSelectionError>() => {
+                        tunnel.teardown(EndReason::Normal).await;
+                        return Err(e);
+                    }
+                    Err(e) => match e.downcast_ref::<TunnelError>() {
+                        Some(TunnelError::Broken(e)) => {
                             warn!("Error while building tunnel: {:?}", e);
-                            tunnel.teardown().await;
+                            tunnel.teardown(EndReason::ProtocolError).await;
                             None
                         }
-                        Err(TunnelError::Incomplete) => Some(tunnel),
-                        Ok(_) => Some(tunnel),
+                        _ => return Err(e),
+                    },
+                },
+            };
+            if tunnel.is_none() && self.is_explicit() {
+                return Err(HopSelectionError::Unreachable { position: 0 }.into());
+            }
+            if let Err(e) = self.check_destination(report) {
+                if let Some(mut tunnel) = tunnel {
+                    tunnel.teardown(EndReason::Normal).await;
+                }
+                return Err(e);
+            }
+        }
+        Err(Self::path_unreachable(report))
+    }
+
+    /// Extends `tunnel` by its next hop, which is the destination or a peer chosen by
+    /// [`select_hop`](TunnelBuilder::select_hop). Returns `true` if the path is already complete.
+    ///
+    /// While the hop is added, the random peer for the following hop is drawn, see [`Prefetch`].
+    ///
+    /// A hop which could not be added is tried again by the next call, unless the tunnel broke,
+    /// which is returned as [`TunnelError::Broken`]. A hop of an explicit path is never tried
+    /// again, its failure is returned as [`HopSelectionError::Unreachable`].
+    async fn extend_next(&mut self, tunnel: &mut Tunnel, report: &mut BuildReport) -> Result<bool> {
+        let position = tunnel.len();
+        if position == 0 {
+            // an empty tunnel has no hop to extend from, so the path is built anew
+            return Err(TunnelError::Broken(None).into());
+        }
+        let peer = match &self.dest {
+            Target::Peer(peer) if position == self.n_hops() => {
+                let reused = tunnel.path.iter().any(|hop| hop.peer.addr == peer.addr);
+                if self.options.is_strict() && reused {
+                    return Err(StrictViolation {
+                        which: Fallback::HopReuse,
                     }
+                    .into());
                 }
-                (Some(tunnel), _) => return Ok(tunnel),
+                peer.clone()
+            }
+            _ if position < self.path_len() => self.select_hop(position, &tunnel.path).await?,
+            _ => return Ok(true),
+        };
+        self.prefetch(position + 1);
+        match self.extend_hop(tunnel, &peer, report).await {
+            Ok(()) => Ok(false),
+            Err(e) if self.is_explicit() => {
+                Err(anyhow!(e).context(HopSelectionError::Unreachable { position }))
             }
+            Err(e @ TunnelError::Broken(_)) => Err(e.into()),
+            Err(_) => Ok(false),
         }
-        Err(anyhow!("failed to build tunnel"))
     }
-}
 
-/// Manages a tunnel after its creation.
-pub(crate) struct TunnelHandler {
-    tunnel: Tunnel,
-    next_tunnel: Arc<Mutex<Option<Tunnel>>>,
-    state: State,
-    events: broadcast::Receiver<Event>,
-    builder: TunnelBuilder,
+    /// Creates a tunnel to `peer` as its first hop, recording the attempt in `report`.
+    async fn init_hop(&self, peer: &Peer, report: &mut BuildReport) -> Option<Tunnel> {
+        let _permit = self.handshakes.acquire(peer.fingerprint()).await;
+        let started = Instant::now();
+        let init = Tunnel::init_with(
+            self.tunnel_id,
+            peer,
+            self.options.cell_size,
+            self.options.cipher_suites,
+            self.connections.as_ref(),
+            self.rng.create(),
+            self.socket_timeouts,
+        );
+        let result = match time::timeout(self.timeouts.hop, init).await {
+            Ok(result) => result,
+            Err(e) => Err(anyhow!(e).context("Timed out while initializing new tunnel")),
+        };
+        let outcome = match &result {
+            Ok(_) => BuildOutcome::Ok,
+            Err(e) => init_outcome(e),
+        };
+        self.guards.record(peer, outcome == BuildOutcome::Ok);
+        self.record_capabilities(peer, outcome);
+        self.record(
+            report,
+            BuildAttempt::new(0, peer, outcome, started.elapsed()),
+        );
+        result
+            .map_err(|e| warn!("Error while building tunnel: {:?}", e))
+            .ok()
+    }
+
+    /// Extends `tunnel` to `peer`, recording the attempt in `report`.
+    async fn extend_hop(
+        &self,
+        tunnel: &mut Tunnel,
+        peer: &Peer,
+        report: &mut BuildReport,
+    ) -> TunnelResult<()> {
+        let hop = tunnel.len();
+        let _permit = self.handshakes.acquire(peer.fingerprint()).await;
+        let started = Instant::now();
+        let (result, outcome) = match time::timeout(self.timeouts.hop, tunnel.extend(peer)).await {
+            Ok(result) => {
+                let outcome = match &result {
+                    Ok(_) => BuildOutcome::Ok,
+                    // the previous hop reports an unreachable peer
+                    Err(TunnelError::Incomplete) => BuildOutcome::ConnectFailed,
+                    Err(TunnelError::KeyDerivation) => BuildOutcome::DeriveFailed,
+                    Err(TunnelError::PolicyRefused) => BuildOutcome::PolicyRefused,
+                    Err(TunnelError::Broken(Some(OnionSocketError {
+                        kind: SocketErrorKind::StreamTimeout(_),
+                        ..
+                    }))) => BuildOutcome::Timeout,
+                    Err(_) => BuildOutcome::HandshakeFailed,
+                };
+                (result, outcome)
+            }
+            // the reply may still arrive, so the path can not be extended any further
+            Err(_) => (Err(TunnelError::Broken(None)), BuildOutcome::Timeout),
+        };
+        self.record_capabilities(peer, outcome);
+        self.record(
+            report,
+            BuildAttempt::new(hop, peer, outcome, started.elapsed()),
+        );
+        result
+    }
+
+    /// Treats a failed handshake with `peer`, which was chosen as it may support the required
+    /// capabilities, as a sign of stale cached capabilities, see
+    /// [`CapabilityCache::record_mismatch`].
+    fn record_capabilities(&self, peer: &Peer, outcome: BuildOutcome) {
+        let required = self.options.required_capabilities;
+        if outcome == BuildOutcome::HandshakeFailed && required != Capabilities::empty() {
+            debug!(
+                "Peer {:?} failed the handshake, no longer expecting capabilities {:?}",
+                peer, required
+            );
+            self.capabilities.record_mismatch(peer, required);
+        }
+    }
+
+    fn record(&self, report: &mut BuildReport, attempt: BuildAttempt) {
+        self.observer.build_attempt(self.tunnel_id, &attempt);
+        report.record(attempt);
+    }
+
+    /// Chooses the peer for the hop at `position` behind the hops on `path`, which is either the
+    /// peer at `position` of an explicit path, the known peer required by the [`TunnelOptions`]
+    /// or a random peer.
+    ///
+    /// The peers of an explicit path take precedence over the hops constrained by the
+    /// [`TunnelOptions`], but are checked the same way.
+    async fn select_hop(&mut self, position: usize, path: &[Hop]) -> Result<Peer> {
+        let peer = match (&self.route, self.options.hops.get(&position)) {
+            (RouteSource::Explicit(explicit), _) => explicit[position].clone(),
+            (RouteSource::Random { .. }, Some(fingerprint)) => self
+                .known_peers
+                .get(fingerprint)
+                .ok_or(HopSelectionError::UnknownPeer { position })?,
+            (RouteSource::Random { .. }, None) if position == 0 && self.guards.is_enabled() => {
+                return self.guard_peer(path).await;
+            }
+            (RouteSource::Random { .. }, None) => return self.random_peer(position, path).await,
+        };
+        if !self
+            .capabilities
+            .may_support(&peer, self.options.required_capabilities)
+        {
+            return Err(HopSelectionError::MissingCapabilities { position }.into());
+        }
+        if !self.options.accepts_hop(&peer) {
+            self.stats.rejected_hops.fetch_add(1, Ordering::Relaxed);
+            return Err(HopSelectionError::Rejected { position }.into());
+        }
+        if self.options.is_strict() && self.is_on_path(path, &peer) {
+            return Err(StrictViolation {
+                which: Fallback::HopReuse,
+            }
+            .into());
+        }
+        Ok(peer)
+    }
+
+    /// Returns a random entry guard for the first hop, after filling up the guards with random
+    /// peers. Without an acceptable guard, a random peer is returned outside of strict mode.
+    async fn guard_peer(&mut self, path: &[Hop]) -> Result<Peer> {
+        for _ in 0..MAX_PEER_FAILURES {
+            if !self.guards.is_missing() {
+                break;
+            }
+            let peer = self.random_peer(0, path).await?;
+            self.guards.add(peer);
+        }
+        let required = self.options.required_capabilities;
+        let guard = self.guards.choose(|peer| {
+            self.capabilities.may_support(peer, required)
+                && self.options.accepts_hop(peer)
+                && !self.is_adjacent(path, peer)
+        });
+        match guard {
+            Some(peer) => Ok(peer),
+            None if self.options.is_strict() => Err(StrictViolation {
+                which: Fallback::NoGuard,
+            }
+            .into()),
+            None => {
+                debug!("No entry guard acceptable for tunnel {}", self.tunnel_id);
+                self.random_peer(0, path).await
+            }
+        }
+    }
+
+    /// Returns whether `peer` is the last hop on `path` or the destination, which are the
+    /// neighbours of a random hop appended to `path`.
+    fn is_adjacent(&self, path: &[Hop], peer: &Peer) -> bool {
+        let dest = match &self.dest {
+            Target::Peer(dest) => Some(dest),
+            Target::Random | Target::Relay => None,
+        };
+        path.last()
+            .map(|hop| &hop.peer)
+            .into_iter()
+            .chain(dest)
+            .any(|hop| hop.addr == peer.addr)
+    }
+
+    /// Returns whether `peer` is already one of the hops on `path` or the destination.
+    fn is_on_path(&self, path: &[Hop], peer: &Peer) -> bool {
+        let dest = match &self.dest {
+            Target::Peer(dest) => Some(dest),
+            Target::Random | Target::Relay => None,
+        };
+        path.iter()
+            .map(|hop| &hop.peer)
+            .chain(dest)
+            .any(|hop| hop.addr == peer.addr)
+    }
+
+    /// Returns a random peer for the hop at `position` from the `peer_provider`, which is not known
+    /// to lack any required capabilities and is accepted by the hop filter.
+    ///
+    /// Suspected peers are skipped, unless the provider returns no other peer. In strict mode,
+    /// peers already on `path` are skipped as well.
+    async fn random_peer(&mut self, position: usize, path: &[Hop]) -> Result<Peer> {
+        let required = self.options.required_capabilities;
+        let mut suspect = None;
+        let mut adjacent = false;
+        let mut reused = false;
+        let mut rejected = 0;
+        for _ in 0..MAX_PEER_FAILURES {
+            let peer = match self.prefetch.take().await {
+                Some(peer) => peer,
+                None => self.peer_provider.random_peer().await,
+            }
+            .context(anyhow!("Failed to get random peer"))?;
+            if !self.capabilities.may_support(&peer, required) {
+                debug!(
+                    "Skipping peer {:?} lacking capabilities {:?}",
+                    peer, required
+                );
+            } else if !self.options.accepts_hop(&peer) {
+                debug!("Skipping peer {:?} rejected by the hop filter", peer);
+                self.stats.rejected_hops.fetch_add(1, Ordering::Relaxed);
+                rejected += 1;
+            } else if self.options.is_strict() && self.is_on_path(path, &peer) {
+                debug!("Skipping peer {:?} which is already on the path", peer);
+                reused = true;
+            } else if self.is_adjacent(path, &peer) {
+                debug!(
+                    "Skipping peer {:?} which would follow itself on the path",
+                    peer
+                );
+                adjacent = true;
+            } else if self.suspects.is_suspected(&peer) {
+                debug!("Skipping suspected peer {:?}", peer);
+                suspect = Some(peer);
+            } else {
+                return Ok(peer);
+            }
+        }
+        match suspect {
+            Some(peer) => Ok(peer),
+            None if rejected > 0 => Err(NoAcceptablePeers { position, rejected }.into()),
+            None if reused => Err(StrictViolation {
+                which: Fallback::HopReuse,
+            }
+            .into()),
+            None if adjacent => Err(anyhow!(
+                "No peer other than the neighbours of hop {} available",
+                position
+            )),
+            None => Err(anyhow!(
+                "No peer with capabilities {:?} available",
+                required
+            )),
+        }
+    }
+}
+
+/// Classifies an error returned by [`Tunnel::init_with`] by the step which failed.
+fn init_outcome(error: &anyhow::Error) -> BuildOutcome {
+    for cause in error.chain() {
+        if cause.is::<time::error::Elapsed>() {
+            return BuildOutcome::Timeout;
+        }
+        if let Some(e) = cause.downcast_ref::<OnionSocketError>() {
+            return match e.kind {
+                SocketErrorKind::StreamTimeout(_) => BuildOutcome::Timeout,
+                _ => BuildOutcome::HandshakeFailed,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return match e.kind() {
+                io::ErrorKind::TimedOut => BuildOutcome::Timeout,
+                _ => BuildOutcome::ConnectFailed,
+            };
+        }
+    }
+    BuildOutcome::DeriveFailed
+}
+
+/// Limits how often a tunnel may be replaced.
+///
+/// Scheduled switchovers are deferred until the current tunnel reached `min_lifetime`.
+/// Failed builds of the replacement tunnel are retried with an exponential backoff starting at
+/// `rebuild_backoff` and capped at `max_rebuild_backoff`, until `max_rebuild_attempts` builds
+/// failed.
+///
+/// With the `Splice` strategy, no replacement tunnel is built in advance. The path is spliced at
+/// the switchover and only rebuilt if that fails. Since splicing pauses data, the switchover is
+/// skipped while the destination is backed off.
+///
+/// Once the oldest session key of a path is `rekey_interval` old, the tunnel is moved to new
+/// circuits through the same peers. A zero interval disables this.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct RotationPolicy {
+    pub(crate) strategy: RotationStrategy,
+    pub(crate) min_lifetime: Duration,
+    pub(crate) rekey_interval: Duration,
+    pub(crate) rebuild_backoff: Duration,
+    pub(crate) max_rebuild_backoff: Duration,
+    pub(crate) max_rebuild_attempts: usize,
+    pub(crate) key_limits: KeyLimits,
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy {
+            strategy: RotationStrategy::Rebuild,
+            min_lifetime: Duration::from_secs(0),
+            rekey_interval: Duration::ZERO,
+            rebuild_backoff: REBUILD_BACKOFF,
+            max_rebuild_backoff: MAX_REBUILD_BACKOFF,
+            max_rebuild_attempts: MAX_REBUILD_ATTEMPTS,
+            key_limits: Default::default(),
+        }
+    }
+}
+
+/// Limits on the number of cells processed with each session key of a path in one direction, see
+/// [`OnionBuilder::set_key_usage_limits`](crate::OnionBuilder::set_key_usage_limits).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct KeyLimits {
+    /// the tunnel is moved to a path with fresh keys once a key processed this many cells
+    pub(crate) rotate_after: u64,
+    /// the tunnel is closed once a key processed this many cells
+    pub(crate) ceiling: u64,
+}
+
+impl Default for KeyLimits {
+    fn default() -> Self {
+        KeyLimits {
+            rotate_after: DEFAULT_KEY_ROTATION_THRESHOLD,
+            ceiling: DEFAULT_KEY_USAGE_CEILING,
+        }
+    }
+}
+
+/// Manages a tunnel after its creation.
+pub(crate) struct TunnelHandler {
+    tunnel: Tunnel,
+    next_tunnel: Arc<Mutex<Option<Tunnel>>>,
+    /// set while the next tunnel is being built, see [`TunnelHandler::spawn_next_tunnel_task`]
+    rebuilding: Arc<AtomicBool>,
+    state: State,
+    events: broadcast::Receiver<Event>,
+    builder: TunnelBuilder,
+    policy: RotationPolicy,
+    /// time after which unsent queued data is reported as stalled
+    stall_threshold: Duration,
+    /// point in time at which the current tunnel started carrying data
+    rotated_at: Instant,
+    /// set if a switchover was postponed because the current tunnel is too young
+    deferred_until: Option<Instant>,
+    /// set once the current path was to be replaced because of the usage or age of its session
+    /// keys
+    keys_replaced: bool,
+    stats: Arc<onion::TunnelCounters>,
+    notify: broadcast::Sender<onion::Event>,
+    /// set if the handler stops because the path of the tunnel failed
+    close_reason: Option<onion::CloseReason>,
+    /// the reason sent by the peer which ended the tunnel or tore down its current path
+    end_reason: Option<EndReason>,
+    observer: Observer,
+    /// state last reported to the observer
+    observed_state: TunnelState,
+    /// data received from the tunnel which did not fit into the buffer of the application
+    pending_data: Option<Bytes>,
+    /// keeps a shutdown of the onion router waiting until this handler finished
+    running: Option<ShutdownGuard>,
+    /// messages waiting to be sent on the current path
+    lanes: Lanes<Outgoing>,
+    /// maximum number of data messages sent in a single write
+    max_batch_size: usize,
+    /// lane of the data written by the application, which is only cover traffic for tunnels to
+    /// random destinations
+    data_lane: Lane,
+    /// set once the application closed the tunnel
+    app_closed: bool,
+    /// set once `TUNNEL END` has been sent on the current path
+    end_sent: bool,
+    /// interval in which padding is sent while no data is sent, `None` if the tunnel is not padded
+    padding_interval: Option<Duration>,
+    /// point in time at which padding is due next
+    next_padding: Instant,
+    /// set if data was sent since padding was last due
+    sent_since_padding: bool,
+    /// time without data in either direction after which the tunnel is closed, `None` if it is
+    /// kept however long it is idle
+    idle_timeout: Option<Duration>,
+    /// point in time at which data was last written by the application or received from the
+    /// tunnel
+    last_data: Instant,
+    /// time the current path may be probed after it failed, `None` if failures are not attributed
+    diagnosis_budget: Option<Duration>,
+    /// the keep-alive echoes sent on the current path
+    echoes: Echoes,
+    /// requests of the [`OnionContext`](crate::OnionContext) concerning this tunnel
+    request_rx: mpsc::UnboundedReceiver<HandlerRequest>,
+    /// lists the current path while the tunnel is ready
+    registry: TunnelRegistry,
+    /// cleans up after the handler if it ends without finishing `handle`
+    exit: HandlerExit,
+}
+
+/// The echoes sent to the last hop of the current path of a tunnel as keep-alives or pings.
+#[derive(Default)]
+struct Echoes {
+    /// nonce and send time of the echo which has not been answered yet
+    outstanding: Option<(u32, Instant)>,
+    /// number of keep-alives in a row at which an echo was still not answered
+    missed: u32,
+    /// pings waiting for the answer to the next echo
+    waiting: Vec<PingRequest>,
+}
+
+/// Removes the entries of a tunnel from the [`TunnelRegistry`] if its handler panicked or its task
+/// was aborted, and reports the ready tunnel as closed with
+/// [`CloseReason::Internal`](onion::CloseReason::Internal).
+///
+/// A handler which finishes [`TunnelHandler::handle`] cleans up on its own.
+struct HandlerExit {
+    tunnel_id: TunnelId,
+    registry: TunnelRegistry,
+    notify: broadcast::Sender<onion::Event>,
+    /// set once the tunnel has been reported as ready
+    ready: bool,
+    /// set once the tunnel has been reported as closed
+    closed: bool,
+    finished: bool,
+}
+
+impl Drop for HandlerExit {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        warn!("Handler of tunnel {} ended unexpectedly", self.tunnel_id);
+        self.registry.release(self.tunnel_id);
+        if self.ready && !self.closed {
+            let _ = self.notify.send(onion::Event::Closed {
+                tunnel_id: self.tunnel_id,
+                reason: onion::CloseReason::Internal,
+                end_reason: None,
+            });
+        }
+    }
 }
 
 pub(crate) enum State {
@@ -358,14 +1721,179 @@ impl TunnelHandler {
         tunnel_builder: TunnelBuilder,
         events: broadcast::Receiver<Event>,
         ready: oneshot::Sender<Result<onion::Tunnel>>,
+        policy: RotationPolicy,
+        stall_threshold: Duration,
+        notify: broadcast::Sender<onion::Event>,
     ) -> Self {
+        let stats = tunnel_builder.stats.clone();
+        let observer = tunnel_builder.observer.clone();
+        let data_lane = match tunnel_builder.dest {
+            Target::Peer(_) | Target::Relay => Lane::Data,
+            Target::Random => Lane::Cover,
+        };
+        let exit = HandlerExit {
+            tunnel_id: first_tunnel.id,
+            registry: Default::default(),
+            notify: notify.clone(),
+            ready: false,
+            closed: false,
+            finished: false,
+        };
         TunnelHandler {
             tunnel: first_tunnel,
             next_tunnel: Arc::new(Mutex::new(None)),
+            rebuilding: Default::default(),
             state: State::Building { ready },
             events,
             builder: tunnel_builder,
+            policy,
+            stall_threshold,
+            rotated_at: Instant::now(),
+            deferred_until: None,
+            keys_replaced: false,
+            stats,
+            notify,
+            close_reason: None,
+            end_reason: None,
+            observer,
+            observed_state: TunnelState::Building,
+            pending_data: None,
+            running: None,
+            lanes: Lanes::new(),
+            max_batch_size: socket::MAX_BATCH_SIZE,
+            data_lane,
+            app_closed: false,
+            end_sent: false,
+            padding_interval: None,
+            next_padding: Instant::now(),
+            sent_since_padding: false,
+            idle_timeout: None,
+            last_data: Instant::now(),
+            diagnosis_budget: None,
+            echoes: Default::default(),
+            // replaced by a channel listed in the registry, see `with_registry`
+            request_rx: mpsc::unbounded_channel().1,
+            registry: Default::default(),
+            exit,
+        }
+    }
+
+    pub(crate) fn with_shutdown(mut self, running: ShutdownGuard) -> Self {
+        self.running = Some(running);
+        self
+    }
+
+    /// Sends padding whenever no data was sent for `interval`, if the destination is known to
+    /// support it. A zero `interval` disables padding.
+    pub(crate) fn with_padding(mut self, interval: Duration) -> Self {
+        if interval == Duration::ZERO {
+            return self;
+        }
+        if self.builder.dest_supports_padding() {
+            self.padding_interval = Some(interval);
+        } else {
+            debug!(
+                "Not padding tunnel {}, the destination is not known to support padding",
+                self.builder.tunnel_id
+            );
+        }
+        self
+    }
+
+    /// Closes the tunnel once neither the application nor the destination sent data for
+    /// `timeout`. Keep-alives and padding do not count as data. A zero `timeout` disables it.
+    pub(crate) fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        if timeout > Duration::ZERO {
+            self.idle_timeout = Some(timeout);
+        }
+        self
+    }
+
+    /// Attributes failures of the path to a single hop, probing the path for at most `budget`.
+    /// The suspected hops are recorded in the suspects of the builder. A zero `budget` disables
+    /// the attribution.
+    pub(crate) fn with_diagnosis(mut self, budget: Duration) -> Self {
+        if budget > Duration::ZERO {
+            self.diagnosis_budget = Some(budget);
+        }
+        self
+    }
+
+    /// Sends at most `n` data messages in a single write.
+    pub(crate) fn with_max_batch_size(mut self, n: usize) -> Self {
+        self.max_batch_size = n;
+        self
+    }
+
+    pub(crate) fn with_registry(mut self, registry: TunnelRegistry) -> Self {
+        registry.set_stats(self.tunnel.id, self.stats.clone());
+        let (request_tx, request_rx) = mpsc::unbounded_channel();
+        registry.set_requests(self.tunnel.id, request_tx);
+        self.request_rx = request_rx;
+        if let Some(fingerprint) = self.builder.destination() {
+            registry.set_destination(self.tunnel.id, fingerprint);
         }
+        self.exit.registry = registry.clone();
+        self.registry = registry;
+        self
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.running.as_ref().is_some_and(ShutdownGuard::is_closing)
+    }
+
+    /// Spawns a task handling the tunnel until it is destroyed.
+    ///
+    /// A panic of the handler ends the tunnel and is reported as [`onion::Event::Error`], followed
+    /// by [`onion::Event::Closed`] if the tunnel was ready. If the shutdown of the onion router
+    /// finishes before the handler, the handler is given up.
+    pub(crate) fn spawn(mut self) {
+        let tunnel_id = self.tunnel.id;
+        let stats = self.stats.clone();
+        let notify = self.notify.clone();
+        let shutdown = self.running.as_ref().map(ShutdownGuard::shutdown);
+        task::spawn_with(
+            "task.tunnel_handler",
+            format!("tunnel {}", tunnel_id),
+            async move {
+                match shutdown {
+                    Some(shutdown) => tokio::select! {
+                        _ = self.handle() => {}
+                        _ = shutdown.finished() => self.give_up(),
+                    },
+                    None => self.handle().await,
+                }
+            },
+            move |panic| report_panic(tunnel_id, &stats, &notify, panic),
+        );
+    }
+
+    /// Cleans up after the shutdown of the onion router stopped this handler midway.
+    ///
+    /// The tunnel is reported as closed, but its paths are not torn down, they are closed along
+    /// with their connections once the handler is dropped.
+    fn give_up(&mut self) {
+        warn!(
+            "Giving up on tunnel {}, which did not close in time",
+            self.tunnel.id
+        );
+        if self.exit.ready && !self.exit.closed {
+            self.report_closed(onion::CloseReason::Shutdown);
+        }
+        self.state = State::Destroyed;
+        self.observe_state();
+        self.registry.release(self.tunnel.id);
+        self.exit.finished = true;
+    }
+
+    /// Emits [`onion::Event::Closed`] and remembers having done so, see [`HandlerExit`].
+    fn report_closed(&mut self, reason: onion::CloseReason) {
+        let _ = self.notify.send(onion::Event::Closed {
+            tunnel_id: self.tunnel.id,
+            reason,
+            end_reason: self.end_reason,
+        });
+        self.exit.closed = true;
     }
 
     pub(crate) async fn handle(&mut self) {
@@ -373,15 +1901,65 @@ impl TunnelHandler {
             "Starting TunnelHandler for tunnel {:?}",
             self.builder.tunnel_id
         );
-        if let Err(e) = self.try_handle().await {
-            warn!("Error in TunnelHandler: {}", e);
+        let failed = match self.try_handle().await {
+            Ok(()) => false,
+            Err(e) => {
+                if self.is_shutting_down() {
+                    // peers commonly vanish while everything is shut down
+                    debug!("Error in TunnelHandler during shutdown: {}", e);
+                } else {
+                    warn!("Error in TunnelHandler: {}", e);
+                }
+                if !matches!(self.state, State::Building { .. }) {
+                    self.report_closed(self.close_reason.unwrap_or(onion::CloseReason::Failed));
+                }
+                true
+            }
+        };
+        self.cleanup(failed).await;
+    }
+
+    /// Releases the paths of the tunnel once the handler stops, whichever way it stopped.
+    ///
+    /// A regularly destroyed tunnel has already been unbuilt, the current path of a `failed` one
+    /// is torn down here. A prebuilt next tunnel is unbuilt in either case.
+    async fn cleanup(&mut self, failed: bool) {
+        let mut reason = EndReason::Normal;
+        if failed {
             self.state = State::Destroyed;
-            self.tunnel.teardown().await;
+            self.observe_state();
+            reason = self
+                .close_reason
+                .unwrap_or(onion::CloseReason::Failed)
+                .end_reason();
+            self.tunnel.teardown(reason).await;
+        }
+        if let Some(mut next_tunnel) = self.next_tunnel.lock().await.take() {
+            next_tunnel.unbuild(reason).await;
+        }
+        self.registry.release(self.tunnel.id);
+        self.exit.finished = true;
+    }
+
+    /// Reports a change of the state since the last call to the observer.
+    fn observe_state(&mut self) {
+        let state = match self.state {
+            State::Building { .. } => TunnelState::Building,
+            State::Ready { .. } => TunnelState::Ready,
+            State::Destroying => TunnelState::Destroying,
+            State::Destroyed => TunnelState::Destroyed,
+        };
+        if state != self.observed_state {
+            self.observer
+                .tunnel_state(self.tunnel.id, self.observed_state, state);
+            self.registry.set_state(self.tunnel.id, state);
+            self.observed_state = state;
         }
     }
 
     async fn try_handle(&mut self) -> Result<()> {
         loop {
+            let rekey_at = self.rekey_deadline();
             match &mut self.state {
                 State::Building { .. } | State::Destroying => {
                     tokio::select! {
@@ -390,17 +1968,50 @@ impl TunnelHandler {
                         }
                     }
                 }
-                State::Ready { data_rx, .. } => {
+                State::Ready { data_tx, data_rx } => {
+                    let deferred_until = self.deferred_until;
+                    let pending = self.pending_data.is_some();
+                    let outgoing = !self.lanes.is_empty();
+                    let padding = self.padding_interval.is_some() && !self.app_closed;
+                    let next_padding = self.next_padding;
+                    let last_data = self.last_data;
+                    let idle_deadline = self.idle_timeout.map(|timeout| last_data + timeout);
                     tokio::select! {
-                        data = data_rx.recv() => {
-                            self.handle_data(data).await?;
+                        data = data_rx.recv(), if !self.app_closed => {
+                            self.queue_data(data);
                         }
-                        msg = self.tunnel.out_circuit.accept_opaque() => {
+                        _ = async {}, if outgoing => {
+                            self.send_next().await?;
+                        }
+                        // stop reading until the application makes room for the pending data
+                        msg = self.tunnel.out_circuit.accept_opaque(), if !pending => {
                             self.handle_tunnel_message(msg).await?;
+                            self.enforce_key_limits().await?;
+                        }
+                        true = onion::has_room(data_tx), if pending => {
+                            if let Some(data) = self.pending_data.take() {
+                                self.deliver(data);
+                            }
+                        }
+                        Some(request) = self.request_rx.recv() => {
+                            self.handle_request(request).await?;
                         }
                         Ok(evt) = self.events.recv() => {
                             self.handle_event(evt).await?;
                         }
+                        _ = time::sleep_until(deferred_until.unwrap_or_else(Instant::now)),
+                            if deferred_until.is_some() => {
+                            self.handle_event(Event::Switchover).await?;
+                        }
+                        _ = time::sleep_until(next_padding), if padding => self.queue_padding(),
+                        _ = time::sleep_until(rekey_at.unwrap_or_else(Instant::now)),
+                            if rekey_at.is_some() => {
+                            self.rekey_on_schedule().await?;
+                        }
+                        _ = time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)),
+                            if idle_deadline.is_some() && !self.app_closed => {
+                            self.close_idle().await?;
+                        }
                     }
                 }
                 State::Destroyed => return Ok(()),
@@ -412,24 +2023,45 @@ impl TunnelHandler {
         &mut self,
         msg: SocketResult<CircuitOpaque<CircuitOpaqueBytes>>,
     ) -> Result<()> {
-        // no event in case of error
-        let mut msg = msg?;
-        // no event in case of error
-        msg.decrypt(self.tunnel.session_keys.iter().rev())?;
-        let tunnel_msg = TunnelRequest::read_with_digest_from(&mut msg.payload.bytes);
+        let mut msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => return self.handle_path_failure(e).await,
+        };
+        self.tunnel.record_cells(Direction::Backward, 1);
+        // on any error, `handle` reports the tunnel closed and tears it down
+        if let Err(e) = msg.decrypt(Direction::Backward, self.tunnel.session_keys.iter().rev()) {
+            self.attribute_failure(onion::CloseReason::Failed).await;
+            return Err(e);
+        }
+        // all messages are expected from the last hop, so a cell out of its sequence is either
+        // replayed or forged
+        if !msg.is_in_sequence(&self.tunnel.session_keys[0], Direction::Backward) {
+            self.attribute_failure(onion::CloseReason::Failed).await;
+            return Err(anyhow!("Tunnel received a replayed or reordered cell"));
+        }
+        let verifier = HopVerifier::new(&self.tunnel.session_keys[0], Direction::Backward);
+        if TunnelResponseEchoed::is_echoed(&msg.payload.bytes, &verifier) {
+            let echoed =
+                TunnelResponseEchoed::read_with_digest_from(&mut msg.payload.bytes, &verifier);
+            if let Ok(TunnelResponseEchoed(nonce)) = echoed {
+                self.echoed(nonce);
+                return Ok(());
+            }
+        }
+        let tunnel_msg = TunnelRequest::read_with_digest_from(&mut msg.payload.bytes, &verifier);
         match tunnel_msg {
             Ok(TunnelRequest::Data(tunnel_id, data)) if tunnel_id == self.tunnel.id => {
-                if let State::Ready { data_tx, .. } = &mut self.state {
-                    let _ = data_tx.send(data).await; // TODO handle closed
-                }
+                self.last_data = Instant::now();
+                self.deliver(data);
                 Ok(())
             }
-            Ok(TunnelRequest::End(_tunnel_id)) => {
-                // maybe reconstruct tunnel
-                Err(anyhow!("Tunnel broke due to unexpected End"))
+            Ok(TunnelRequest::Padding(_)) => Ok(()),
+            Ok(TunnelRequest::End(tunnel_id, reason)) if tunnel_id == self.tunnel.id => {
+                self.end_by_destination(reason).await
             }
             _ => {
                 // invalid request or broken digest
+                self.attribute_failure(onion::CloseReason::Failed).await;
                 Err(anyhow!(
                     "Tunnel broke due to invalid request or broken digest"
                 ))
@@ -437,22 +2069,226 @@ impl TunnelHandler {
         }
     }
 
-    async fn handle_data(&mut self, data: Option<Bytes>) -> Result<()> {
+    /// Closes the tunnel after its destination sent `TUNNEL END`, without ending it in return.
+    ///
+    /// Data the application writes from then on is rejected, as the tunnel is destroyed.
+    async fn end_by_destination(&mut self, reason: EndReason) -> Result<()> {
+        debug!(
+            "Tunnel {} was ended by its destination ({:?})",
+            self.tunnel.id, reason
+        );
+        self.end_sent = true;
+        self.close_reason = Some(onion::CloseReason::Ended);
+        self.end_reason = Some(reason);
+        self.destroy().await?;
+        self.report_closed(onion::CloseReason::Ended);
+        self.state = State::Destroyed;
+        self.observe_state();
+        Ok(())
+    }
+
+    /// Closes the tunnel after no data was sent or received for the idle timeout. Like a tunnel
+    /// closed by the application, it is ended towards the destination.
+    async fn close_idle(&mut self) -> Result<()> {
+        debug!(
+            "Tunnel {} was idle for {:?}, closing it",
+            self.tunnel.id,
+            self.last_data.elapsed()
+        );
+        self.close_reason = Some(onion::CloseReason::IdleTimeout);
+        self.destroy().await?;
+        self.report_closed(onion::CloseReason::IdleTimeout);
+        self.state = State::Destroyed;
+        self.observe_state();
+        Ok(())
+    }
+
+    /// Passes data received from the tunnel on to the application without waiting for room in its
+    /// buffer, so events and requests are still handled if the application does not read.
+    fn deliver(&mut self, data: Bytes) {
+        if let State::Ready { data_tx, .. } = &self.state {
+            // TODO handle closed
+            if let Err(TrySendError::Full(data)) = data_tx.try_send(data) {
+                self.pending_data = Some(data);
+            }
+        }
+    }
+
+    /// Queues data written by the application, or `TUNNEL END` once it closed the tunnel.
+    fn queue_data(&mut self, data: Option<Bytes>) {
         // state is assumed to be Ready
         debug_assert!(matches!(&self.state, State::Ready { .. }));
 
         match data {
+            // the last relay would tear the tunnel down
+            Some(_) if matches!(self.builder.dest, Target::Relay) => {
+                debug!(
+                    "Discarding data written to tunnel {}, which is terminated at a relay",
+                    self.tunnel.id
+                );
+                self.stats.record_discarded(1);
+            }
             Some(data) => {
-                let circuit_id = self.tunnel.out_circuit.id;
-                let tunnel_id = self.tunnel.id;
-                self.tunnel
-                    .out_circuit
-                    .socket
-                    .send_data(circuit_id, tunnel_id, data, &self.tunnel.session_keys)
-                    .await?;
+                self.last_data = Instant::now();
+                self.lanes.push(self.data_lane, Outgoing::Data(data));
+                // take everything else written so far, so control messages can overtake it
+                if let State::Ready { data_rx, .. } = &mut self.state {
+                    while let Ok(data) = data_rx.try_recv() {
+                        self.lanes.push(self.data_lane, Outgoing::Data(data));
+                    }
+                }
+            }
+            None => {
+                self.app_closed = true;
+                self.lanes.push(Lane::Control, Outgoing::End);
+            }
+        }
+    }
+
+    /// Queues padding unless data was sent since padding was last due, so the path carries at
+    /// least one cell per padding interval.
+    fn queue_padding(&mut self) {
+        if let Some(interval) = self.padding_interval {
+            if !self.sent_since_padding && self.lanes.is_empty() {
+                self.lanes.push(Lane::Cover, Outgoing::Padding);
             }
-            None => self.state = State::Destroying,
+            self.sent_since_padding = false;
+            self.next_padding = Instant::now() + interval;
         }
+    }
+
+    /// Sends a keep-alive on the current path as an echo to the last hop. An echo which is still
+    /// not answered by then counts as missed, once the last hop missed [`MAX_MISSED_ECHOES`]
+    /// echoes in a row, the path is replaced like one whose connection was lost.
+    async fn keep_alive(&mut self) -> Result<()> {
+        if self.echoes.outstanding.is_some() {
+            self.echoes.missed += 1;
+        }
+        if self.echoes.missed >= MAX_MISSED_ECHOES {
+            warn!(
+                "Path of tunnel {} missed {} echoes in a row",
+                self.tunnel.id, self.echoes.missed
+            );
+            let error = anyhow!(
+                "The last hop of tunnel {} stopped answering",
+                self.tunnel.id
+            );
+            return self
+                .recover_path(onion::CloseReason::Unresponsive, error)
+                .await;
+        }
+        self.send_echo().await
+    }
+
+    async fn handle_request(&mut self, request: HandlerRequest) -> Result<()> {
+        match request {
+            HandlerRequest::Ping(ping) => self.ping(ping).await,
+            HandlerRequest::Rekey(answer) => {
+                // the current path is kept if no new one can be built
+                let built = self.builder.for_same_path(&self.tunnel).build().await;
+                let result = match built {
+                    Ok(new_tunnel) => Ok(self.rekey(new_tunnel).await?),
+                    Err(e) => Err(e),
+                };
+                let _ = answer.send(result);
+                Ok(())
+            }
+        }
+    }
+
+    /// Answers `ping` with the round-trip time of the next echo answered on the current path,
+    /// sending one unless an echo is already waiting for its answer.
+    async fn ping(&mut self, ping: PingRequest) -> Result<()> {
+        self.echoes.waiting.push(ping);
+        if self.echoes.outstanding.is_none() {
+            self.send_echo().await?;
+        }
+        Ok(())
+    }
+
+    async fn send_echo(&mut self) -> Result<()> {
+        let mut nonce = [0u8; 4];
+        crypto::fill_random(&mut nonce);
+        let nonce = u32::from_le_bytes(nonce);
+        self.tunnel.echo(nonce).await?;
+        self.echoes.outstanding = Some((nonce, Instant::now()));
+        Ok(())
+    }
+
+    /// Records the round-trip time of the outstanding echo once it is answered. Answers to echoes
+    /// which were given up are ignored.
+    fn echoed(&mut self, nonce: u32) {
+        let sent_at = match self.echoes.outstanding {
+            Some((outstanding, sent_at)) if outstanding == nonce => sent_at,
+            _ => {
+                trace!("Ignoring late echo on tunnel {}", self.tunnel.id);
+                return;
+            }
+        };
+        let rtt = sent_at.elapsed();
+        trace!("Tunnel {} answered an echo after {:?}", self.tunnel.id, rtt);
+        self.echoes.outstanding = None;
+        self.echoes.missed = 0;
+        self.stats.set_round_trip_time(Some(rtt));
+        for ping in self.echoes.waiting.drain(..) {
+            let _ = ping.send(rtt);
+        }
+    }
+
+    /// Sends the next message, or batch of data messages, on the current path.
+    async fn send_next(&mut self) -> Result<()> {
+        let (other, data) = match self.lanes.pop_outgoing(self.max_batch_size) {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        self.enforce_key_limits().await?;
+        self.tunnel
+            .record_cells(Direction::Forward, other.len() + data.len());
+        for msg in other {
+            match msg {
+                Outgoing::End => {
+                    let discarded = self.lanes.clear(self.data_lane);
+                    self.stats.record_discarded(discarded);
+                    self.tunnel.end(EndReason::Normal).await?;
+                    self.end_sent = true;
+                    // the path is torn down at the next switchover like any other path
+                    self.state = State::Destroying;
+                    self.observe_state();
+                    return Ok(());
+                }
+                Outgoing::KeepAlive => self.keep_alive().await?,
+                Outgoing::Padding => {
+                    let len = self.tunnel.cell_size().max_data_size();
+                    self.tunnel.pad(len).await?;
+                    self.stats
+                        .padding_bytes
+                        .fetch_add(len as u64, Ordering::Relaxed);
+                }
+                Outgoing::Data(_) => unreachable!("data is taken out of the batch"),
+            }
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let n_cells = data.len();
+        let n_bytes: usize = data.iter().map(Bytes::len).sum();
+        let circuit_id = self.tunnel.out_circuit.id;
+        let tunnel_id = self.tunnel.id;
+        self.tunnel
+            .out_circuit
+            .socket
+            .send_data(circuit_id, tunnel_id, data, &self.tunnel.session_keys)
+            .await?;
+        self.tunnel.debug_check_keys();
+        self.stats.record_sent(n_cells);
+        self.stats
+            .sent_cells
+            .fetch_add(n_cells as u64, Ordering::Relaxed);
+        self.stats
+            .sent_bytes
+            .fetch_add(n_bytes as u64, Ordering::Relaxed);
+        self.sent_since_padding = true;
         Ok(())
     }
 
@@ -466,41 +2302,106 @@ impl TunnelHandler {
         std::mem::swap(&mut self.state, &mut state);
         self.state = match (evt, state) {
             (Event::Switchover, State::Building { ready }) => {
-                self.tunnel.begin().await?;
-                let (tunnel, data_tx, data_rx) = onion::Tunnel::new(self.tunnel.id, true);
+                if let Err(e) = self.tunnel.begin().await {
+                    let error = anyhow::Error::new(onion::TunnelBroken)
+                        .context(format!("Failed to begin tunnel: {}", e));
+                    let _ = ready.send(Err(error));
+                    return Err(e.into());
+                }
+                let (mut tunnel, data_tx, data_rx) =
+                    onion::Tunnel::new(self.tunnel.id, true, self.tunnel.cell_size());
+                tunnel.stats = self.stats.clone();
+                // listed as ready before the application gets the tunnel
+                self.registry
+                    .set_path(self.tunnel.id, self.tunnel.hop_info());
+                self.registry.set_state(self.tunnel.id, TunnelState::Ready);
                 let _ = ready.send(Ok(tunnel)); // TODO handle closed
+                let _ = self.notify.send(onion::Event::Ready {
+                    tunnel_id: self.tunnel.id,
+                    cause: onion::ReadyCause::Initial,
+                });
+                self.exit.ready = true;
+                self.rotated_at = Instant::now();
+                self.last_data = Instant::now();
                 self.spawn_next_tunnel_task();
+                self.spawn_stall_watchdog();
+                State::Ready { data_tx, data_rx }
+            }
+            (Event::Switchover, State::Ready { data_tx, data_rx })
+                if self.rotated_at.elapsed() < self.policy.min_lifetime =>
+            {
+                let deferred_until = self.rotated_at + self.policy.min_lifetime;
+                debug!(
+                    "Deferring switchover of tunnel {} by {:?}",
+                    self.tunnel.id,
+                    deferred_until - Instant::now()
+                );
+                self.deferred_until = Some(deferred_until);
+                self.stats
+                    .deferred_rotations
+                    .fetch_add(1, Ordering::Relaxed);
                 State::Ready { data_tx, data_rx }
             }
             (Event::Switchover, State::Ready { data_tx, data_rx }) => {
-                let mut new_tunnel = self
-                    .next_tunnel
-                    .lock()
-                    .await
-                    .take()
-                    .ok_or_else(|| anyhow!("Switchover failed: no next tunnel"))?;
-
-                mem::swap(&mut self.tunnel, &mut new_tunnel);
-                let mut old_tunnel = new_tunnel;
-                self.tunnel.begin().await?;
-                old_tunnel.end().await?;
-
-                self.spawn_next_tunnel_task();
-                tokio::spawn(async move {
-                    old_tunnel.unbuild().await;
-                });
+                match self.policy.strategy {
+                    RotationStrategy::Rebuild => {
+                        let next_tunnel = self.next_tunnel.lock().await.take();
+                        match next_tunnel {
+                            Some(new_tunnel) => {
+                                let mut old_tunnel = self.rotate(new_tunnel).await?;
+                                old_tunnel.end(EndReason::Replaced).await?;
+                                task::spawn_with(
+                                    "task.unbuild",
+                                    format!("tunnel {}", self.tunnel.id),
+                                    async move { old_tunnel.unbuild(EndReason::Replaced).await },
+                                    |_| (),
+                                );
+                            }
+                            None => self.keep_path(),
+                        }
+                    }
+                    RotationStrategy::Splice { .. }
+                        if self.builder.destination_backoff().is_some() =>
+                    {
+                        // splicing pauses data, so the current path is kept until the backoff ended
+                        debug!(
+                            "Not splicing tunnel {}, its destination failed recently",
+                            self.tunnel.id
+                        );
+                    }
+                    RotationStrategy::Splice { keep_hops } => self.splice(keep_hops).await?,
+                }
                 State::Ready { data_tx, data_rx }
             }
             (Event::Switchover, State::Destroying) => {
-                self.tunnel.end().await?;
-                self.tunnel.unbuild().await;
-                if let Some(mut next_tunnel) = self.next_tunnel.lock().await.take() {
-                    next_tunnel.unbuild().await;
-                }
+                self.destroy().await?;
+                State::Destroyed
+            }
+            (Event::Shutdown, State::Building { ready }) => {
+                let _ = ready.send(Err(ShuttingDown.into()));
+                self.tunnel.unbuild(EndReason::Normal).await;
                 State::Destroyed
             }
-            (Event::Destroy, State::Ready { .. }) => State::Destroying,
+            (Event::Shutdown, State::Ready { .. }) => {
+                self.close_reason = Some(onion::CloseReason::Shutdown);
+                self.destroy().await?;
+                self.report_closed(onion::CloseReason::Shutdown);
+                State::Destroyed
+            }
+            (Event::Shutdown, State::Destroying) => {
+                self.destroy().await?;
+                State::Destroyed
+            }
+            (Event::Shutdown, State::Destroyed) => State::Destroyed,
             (Event::KeepAlive, State::Destroyed) => State::Destroyed, // ignore this event
+            (Event::KeepAlive, state @ State::Ready { .. }) => {
+                // must not wait for queued data
+                self.lanes.push(Lane::Control, Outgoing::KeepAlive);
+                if let Some(next_tunnel) = self.next_tunnel.lock().await.as_mut() {
+                    next_tunnel.keep_alive().await?;
+                }
+                state
+            }
             (Event::KeepAlive, state) => {
                 self.tunnel.keep_alive().await?;
                 if let Some(next_tunnel) = self.next_tunnel.lock().await.as_mut() {
@@ -510,24 +2411,466 @@ impl TunnelHandler {
             } // ignore this event
             _ => return Err(anyhow!("Illegal TunnelHandler state")),
         };
+        self.observe_state();
+        Ok(())
+    }
+
+    /// Ends the current tunnel and unbuilds it together with the prebuilt next tunnel.
+    async fn destroy(&mut self) -> Result<()> {
+        let reason = self
+            .close_reason
+            .map_or(EndReason::Normal, onion::CloseReason::end_reason);
+        if !self.end_sent {
+            self.tunnel.end(reason).await?;
+        }
+        self.tunnel.unbuild(reason).await;
+        if let Some(mut next_tunnel) = self.next_tunnel.lock().await.take() {
+            next_tunnel.unbuild(reason).await;
+        }
+        Ok(())
+    }
+
+    /// Makes `new_tunnel` carry the data of this tunnel and returns the replaced tunnel.
+    async fn rotate(&mut self, mut new_tunnel: Tunnel) -> Result<Tunnel> {
+        mem::swap(&mut self.tunnel, &mut new_tunnel);
+        self.tunnel.begin().await?;
+        self.record_rotation();
+        Ok(new_tunnel)
+    }
+
+    /// Moves this tunnel to a new path which shares its first `keep_hops` hops with the current
+    /// path. The destination is always replaced.
+    ///
+    /// Data is paused during the splice, since there is no other path to carry it. If the path
+    /// can not be spliced, a new path is built instead.
+    async fn splice(&mut self, keep_hops: usize) -> Result<()> {
+        let len = cmp::min(cmp::max(keep_hops, 1), self.tunnel.len().saturating_sub(1));
+        self.tunnel.end(EndReason::Replaced).await?;
+        let spliced = match self.tunnel.truncate_to_length(len).await {
+            Ok(()) => self.builder.extend_path(&mut self.tunnel).await,
+            Err(e) => Err(e.into()),
+        };
+
+        match spliced {
+            Ok(()) => {
+                self.tunnel.begin().await?;
+                self.stats.spliced_rotations.fetch_add(1, Ordering::Relaxed);
+                self.record_rotation();
+            }
+            Err(e) => {
+                warn!(
+                    "Splicing the path of tunnel {} failed, rebuilding it: {}",
+                    self.tunnel.id, e
+                );
+                let new_tunnel = self.builder.build().await?;
+                let mut old_tunnel = self.rotate(new_tunnel).await?;
+                task::spawn_with(
+                    "task.teardown",
+                    format!("tunnel {}", self.tunnel.id),
+                    async move { old_tunnel.teardown(EndReason::Replaced).await },
+                    |_| (),
+                );
+            }
+        }
         Ok(())
     }
 
+    /// Moves the tunnel to a new path once a session key of the current path processed the number
+    /// of cells after which it should be replaced, regardless of the round. A spliced tunnel is
+    /// moved to an entirely new path. Only one attempt is made per path, if it fails the path is
+    /// kept until the next switchover.
+    ///
+    /// Fails with [`onion::CloseReason::KeysExhausted`] once a key processed the ceiling of cells,
+    /// so it is not used for any further cell.
+    async fn enforce_key_limits(&mut self) -> Result<()> {
+        if !matches!(self.state, State::Ready { .. }) {
+            return Ok(());
+        }
+        let limits = self.policy.key_limits;
+        let (cells, bytes) = self.tunnel.key_usage();
+        if cells >= limits.rotate_after && !self.keys_replaced {
+            self.keys_replaced = true;
+            info!(
+                "Replacing the path of tunnel {}, a session key processed {} cells ({} bytes)",
+                self.tunnel.id, cells, bytes
+            );
+            let next_tunnel = self.next_tunnel.lock().await.take();
+            let new_tunnel = match next_tunnel {
+                Some(tunnel) => Ok(tunnel),
+                None => self.builder.rebuild().await,
+            };
+            match new_tunnel {
+                Ok(new_tunnel) => {
+                    let mut old_tunnel = self.rotate(new_tunnel).await?;
+                    self.stats
+                        .key_limit_rotations
+                        .fetch_add(1, Ordering::Relaxed);
+                    old_tunnel.end(EndReason::Replaced).await?;
+                    task::spawn_with(
+                        "task.unbuild",
+                        format!("tunnel {}", self.tunnel.id),
+                        async move { old_tunnel.unbuild(EndReason::Replaced).await },
+                        |_| (),
+                    );
+                    return Ok(());
+                }
+                Err(e) => warn!(
+                    "Replacing the session keys of tunnel {} failed: {}",
+                    self.tunnel.id, e
+                ),
+            }
+        }
+        if cells >= limits.ceiling {
+            self.close_reason = Some(onion::CloseReason::KeysExhausted);
+            return Err(anyhow!(
+                "A session key of tunnel {} reached its ceiling of {} cells",
+                self.tunnel.id,
+                limits.ceiling
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns when the tunnel is to be moved to fresh session keys, see
+    /// [`RotationPolicy::rekey_interval`]. `None` if disabled or an attempt on the current path
+    /// failed.
+    fn rekey_deadline(&self) -> Option<Instant> {
+        let interval = self.policy.rekey_interval;
+        if interval == Duration::ZERO || self.keys_replaced {
+            return None;
+        }
+        Some(self.tunnel.keys_created()? + interval)
+    }
+
+    /// Moves the tunnel to fresh session keys once the oldest key of the path reached the rekey
+    /// interval. Only one attempt is made per path, if it fails the path is kept until the next
+    /// switchover.
+    async fn rekey_on_schedule(&mut self) -> Result<()> {
+        self.keys_replaced = true;
+        info!(
+            "Replacing the session keys of tunnel {} after {:?}",
+            self.tunnel.id, self.policy.rekey_interval
+        );
+        match self.builder.for_same_path(&self.tunnel).build().await {
+            Ok(new_tunnel) => self.rekey(new_tunnel).await,
+            Err(e) => {
+                warn!(
+                    "Replacing the session keys of tunnel {} failed: {}",
+                    self.tunnel.id, e
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Makes `new_tunnel`, which has been built through the same peers as the current path, carry
+    /// the data of this tunnel.
+    ///
+    /// The tunnel prebuilt for the next switchover is replaced as after any rotation. The age of
+    /// the path is kept, so a rekeyed path is not kept past its minimum lifetime.
+    async fn rekey(&mut self, new_tunnel: Tunnel) -> Result<()> {
+        let rotated_at = self.rotated_at;
+        if let Some(mut next_tunnel) = self.next_tunnel.lock().await.take() {
+            task::spawn_with(
+                "task.unbuild",
+                format!("tunnel {}", self.tunnel.id),
+                async move { next_tunnel.unbuild(EndReason::Replaced).await },
+                |_| (),
+            );
+        }
+        let mut old_tunnel = self.rotate(new_tunnel).await?;
+        self.rotated_at = rotated_at;
+        self.stats.rekeys.fetch_add(1, Ordering::Relaxed);
+        old_tunnel.end(EndReason::Replaced).await?;
+        task::spawn_with(
+            "task.unbuild",
+            format!("tunnel {}", self.tunnel.id),
+            async move { old_tunnel.unbuild(EndReason::Replaced).await },
+            |_| (),
+        );
+        Ok(())
+    }
+
+    /// Keeps the current path at a switchover for which no replacement has been built. If building
+    /// one was given up, it is started over, so the tunnel is rotated again once peers are
+    /// available.
+    fn keep_path(&mut self) {
+        warn!(
+            "No replacement path for tunnel {} is ready, keeping the current path",
+            self.tunnel.id
+        );
+        if !self.rebuilding.load(Ordering::Relaxed) {
+            self.spawn_next_tunnel_task();
+        }
+    }
+
+    /// Records that the current path of this tunnel has just begun carrying its data.
+    fn record_rotation(&mut self) {
+        let _ = self.notify.send(onion::Event::Rotated {
+            tunnel_id: self.tunnel.id,
+            old_path_age: self.rotated_at.elapsed(),
+        });
+        self.rotated_at = Instant::now();
+        self.deferred_until = None;
+        self.keys_replaced = false;
+        self.registry
+            .set_path(self.tunnel.id, self.tunnel.hop_info());
+        self.stats.rotations.fetch_add(1, Ordering::Relaxed);
+        // echoes sent on the old path are no longer read, waiting pings are sent on the new one
+        self.echoes.outstanding = None;
+        self.echoes.missed = 0;
+        self.stats.set_round_trip_time(None);
+        if !self.echoes.waiting.is_empty() {
+            self.lanes.push(Lane::Control, Outgoing::KeepAlive);
+        }
+        self.spawn_next_tunnel_task();
+    }
+
+    /// Handles the failure of the current path, indicated by an error while reading from the
+    /// first hop.
+    ///
+    /// If the failure is recoverable, the tunnel is immediately moved to a new path, which is only
+    /// built once the backoff of a recently failed destination ended.
+    /// Otherwise an error is returned which stops the handler.
+    async fn handle_path_failure(&mut self, error: OnionSocketError) -> Result<()> {
+        let reason = match error.kind {
+            SocketErrorKind::TeardownMessage(end_reason) => {
+                // reported if the path can not be replaced
+                self.end_reason = Some(end_reason);
+                match end_reason {
+                    // a new path may avoid the stalled or overloaded hop
+                    EndReason::Timeout | EndReason::ResourceLimit => onion::CloseReason::TornDown,
+                    EndReason::Unspecified
+                    | EndReason::Normal
+                    | EndReason::ProtocolError
+                    | EndReason::Replaced => onion::CloseReason::Rejected,
+                }
+            }
+            SocketErrorKind::StreamTerminated(_) | SocketErrorKind::StreamTimeout(_) => {
+                onion::CloseReason::ConnectionLost
+            }
+            _ => onion::CloseReason::Failed,
+        };
+        warn!(
+            "Path of tunnel {} failed ({:?}): {}",
+            self.tunnel.id, reason, error
+        );
+        self.recover_path(reason, error.into()).await
+    }
+
+    /// Moves the tunnel to a new path after the current one failed for `reason`, if the failure is
+    /// recoverable. Otherwise `error` is returned, which stops the handler.
+    async fn recover_path(
+        &mut self,
+        reason: onion::CloseReason,
+        error: anyhow::Error,
+    ) -> Result<()> {
+        self.attribute_failure(reason).await;
+
+        if reason.is_recoverable() {
+            let next_tunnel = self.next_tunnel.lock().await.take();
+            let new_tunnel = match next_tunnel {
+                Some(tunnel) => Ok(tunnel),
+                None => self.builder.rebuild().await,
+            };
+            match new_tunnel {
+                Ok(new_tunnel) => {
+                    self.end_reason = None;
+                    let mut old_tunnel = self.rotate(new_tunnel).await?;
+                    task::spawn_with(
+                        "task.teardown",
+                        format!("tunnel {}", self.tunnel.id),
+                        async move { old_tunnel.teardown(reason.end_reason()).await },
+                        |_| (),
+                    );
+                    return Ok(());
+                }
+                Err(e) => warn!("Replacing path of tunnel {} failed: {}", self.tunnel.id, e),
+            }
+        }
+
+        self.close_reason = Some(reason);
+        Err(error)
+    }
+
+    /// Looks for the hop which caused the current path to fail for `reason`, reporting it as
+    /// [`onion::Event::HopSuspected`] and recording it in the suspects of the builder.
+    ///
+    /// A lost connection is attributed to the first hop. Otherwise the path is probed by
+    /// [`Tunnel::diagnose`] for at most the diagnosis budget, so a replacement path is built with
+    /// a bounded delay. A torn down path can not be attributed, since the circuit no longer exists.
+    async fn attribute_failure(&mut self, reason: onion::CloseReason) {
+        let budget = match self.diagnosis_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        let position = match reason {
+            onion::CloseReason::ConnectionLost => Some(0),
+            onion::CloseReason::Failed | onion::CloseReason::Unresponsive => {
+                self.tunnel.diagnose(budget).await
+            }
+            onion::CloseReason::TornDown
+            | onion::CloseReason::Rejected
+            | onion::CloseReason::Shutdown
+            | onion::CloseReason::Internal
+            | onion::CloseReason::Ended
+            | onion::CloseReason::KeysExhausted
+            | onion::CloseReason::IdleTimeout => None,
+        };
+        let hop = position.and_then(|position| Some((position, self.tunnel.hop(position)?)));
+        let (position, peer) = match hop {
+            Some(hop) => hop,
+            None => {
+                debug!(
+                    "Could not attribute the failure of tunnel {} to a hop",
+                    self.tunnel.id
+                );
+                return;
+            }
+        };
+        warn!(
+            "Hop {} ({}) is suspected to have caused the failure of tunnel {}",
+            position, peer.addr, self.tunnel.id
+        );
+        let fingerprint = peer.fingerprint();
+        self.builder.suspects.suspect(fingerprint);
+        let _ = self.notify.send(onion::Event::HopSuspected {
+            tunnel_id: self.tunnel.id,
+            position,
+            fingerprint,
+        });
+    }
+
+    /// Builds the tunnel which replaces the current one on the next switchover.
+    ///
+    /// Failed builds are retried with an exponential backoff until the handler is gone or the
+    /// maximum number of attempts is reached, in which case [`onion::Event::RotationFailed`] is
+    /// emitted. A panicking build is reported as [`onion::Event::Error`] and retried like a failed
+    /// one. Builds towards a recently failed destination additionally wait for its backoff, see
+    /// [`TunnelBuilder::rebuild`].
+    ///
+    /// Nothing is built in advance if paths are spliced.
     fn spawn_next_tunnel_task(&self) {
-        tokio::spawn({
-            let next_tunnel = self.next_tunnel.clone();
+        if let RotationStrategy::Splice { .. } = self.policy.strategy {
+            return;
+        }
+        self.rebuilding.store(true, Ordering::Relaxed);
+        task::spawn("task.next_tunnel", {
+            let tunnel_id = self.tunnel.id;
+            let next_tunnel = Arc::downgrade(&self.next_tunnel);
+            let rebuilding = self.rebuilding.clone();
             let mut builder = self.builder.clone();
-            async move {
-                match builder.build().await {
-                    Ok(new_tunnel) => {
-                        // TODO only replace if not destroyed
-                        next_tunnel.lock().await.replace(new_tunnel);
+            let policy = self.policy;
+            let stats = self.stats.clone();
+            let notify = self.notify.clone();
+            let rebuild = async move {
+                let mut backoff = policy.rebuild_backoff;
+                for attempt in 1..=policy.max_rebuild_attempts {
+                    match task::catch_panic(builder.rebuild()).await {
+                        Ok(Ok(new_tunnel)) => {
+                            TunnelHandler::store_next_tunnel(&next_tunnel, new_tunnel).await;
+                            return;
+                        }
+                        Ok(Err(e)) => {
+                            warn!("Rebuilding of a tunnel failed: {}", e);
+                            stats.failed_rebuilds.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(panic) => {
+                            error!("Rebuilding of tunnel {} panicked: {}", tunnel_id, panic);
+                            stats.failed_rebuilds.fetch_add(1, Ordering::Relaxed);
+                            report_panic(tunnel_id, &stats, &notify, &panic);
+                        }
+                    };
+                    if attempt == policy.max_rebuild_attempts {
+                        break;
                     }
-                    Err(e) => warn!("Rebuilding of a tunnel failed: {}", e),
-                };
+
+                    time::sleep(backoff).await;
+                    backoff = cmp::min(backoff * 2, policy.max_rebuild_backoff);
+                    if next_tunnel.strong_count() == 0 {
+                        return;
+                    }
+                }
+                warn!("Giving up rebuilding tunnel {}", tunnel_id);
+                let _ = notify.send(onion::Event::RotationFailed { tunnel_id });
+            };
+            async move {
+                rebuild.await;
+                rebuilding.store(false, Ordering::Relaxed);
             }
         });
     }
+
+    /// Emits an [`onion::Event::Stalled`] whenever queued data has not been sent for the stall
+    /// threshold, which may be caused by a slow first hop or a pending replacement of the path.
+    /// The check runs independently of the handler, which is blocked in both cases.
+    fn spawn_stall_watchdog(&self) {
+        let tunnel_id = self.tunnel.id;
+        let on_panic = {
+            let stats = self.stats.clone();
+            let notify = self.notify.clone();
+            move |panic: &task::Panic| report_panic(tunnel_id, &stats, &notify, panic)
+        };
+        let watchdog = {
+            // only used to detect that the handler is gone
+            let handler = Arc::downgrade(&self.next_tunnel);
+            let stats = self.stats.clone();
+            let notify = self.notify.clone();
+            let threshold = self.stall_threshold;
+            async move {
+                let mut interval =
+                    time::interval(cmp::max(threshold / 4, MIN_STALL_CHECK_INTERVAL));
+                let mut reported = false;
+                while handler.strong_count() > 0 {
+                    interval.tick().await;
+                    match stats.stalled_for() {
+                        Some(duration) if duration >= threshold => {
+                            if !reported {
+                                warn!("Tunnel {} stalled for {:?}", tunnel_id, duration);
+                                let _ = notify.send(onion::Event::Stalled {
+                                    tunnel_id,
+                                    duration,
+                                });
+                                reported = true;
+                            }
+                        }
+                        _ => reported = false,
+                    }
+                }
+            }
+        };
+        task::spawn_with(
+            "task.stall_watchdog",
+            format!("tunnel {}", tunnel_id),
+            watchdog,
+            on_panic,
+        );
+    }
+
+    async fn store_next_tunnel(next_tunnel: &Weak<Mutex<Option<Tunnel>>>, mut new_tunnel: Tunnel) {
+        match next_tunnel.upgrade() {
+            // TODO only replace if not destroyed
+            Some(next_tunnel) => {
+                next_tunnel.lock().await.replace(new_tunnel);
+            }
+            None => new_tunnel.unbuild(EndReason::Normal).await,
+        }
+    }
+}
+
+/// Records a panic of a task working on the tunnel with the given id and reports it as
+/// [`onion::Event::Error`].
+pub(crate) fn report_panic(
+    tunnel_id: TunnelId,
+    stats: &onion::TunnelCounters,
+    notify: &broadcast::Sender<onion::Event>,
+    panic: &task::Panic,
+) {
+    stats.panics.fetch_add(1, Ordering::Relaxed);
+    let _ = notify.send(onion::Event::Error {
+        tunnel_id,
+        message: panic.to_string(),
+    });
 }
 
 impl fmt::Debug for Tunnel {
@@ -536,6 +2879,7 @@ impl fmt::Debug for Tunnel {
             .field("id", &self.id)
             .field("out_circuit", &self.out_circuit)
             .field("len", &self.len())
+            .field("direct", &self.direct)
             .finish()
     }
 }
@@ -545,7 +2889,8 @@ impl fmt::Debug for TunnelBuilder {
         f.debug_struct("TunnelBuilder")
             .field("tunnel_id", &self.tunnel_id)
             .field("dest", &self.dest)
-            .field("n_hops", &self.n_hops)
+            .field("route", &self.route)
+            .field("options", &self.options)
             .finish()
     }
 }
@@ -556,6 +2901,7 @@ impl fmt::Debug for TunnelHandler {
             .field("tunnel", &self.tunnel)
             .field("state", &self.state)
             .field("builder", &self.builder)
+            .field("policy", &self.policy)
             .finish()
     }
 }
@@ -0,0 +1,215 @@
+//! Spawning of the background tasks of the crate.
+//!
+//! Every task is spawned using [`spawn`], [`spawn_on`] or [`spawn_with`], which count the task
+//! with a [`Tracked`] guard and catch panics. A bug affecting a single tunnel or circuit is thereby
+//! logged with its context instead of silently ending the task, and never takes down other tasks.
+//!
+//! Blocking work, e.g. signing with a RSA host key, runs on the blocking thread pool using
+//! [`spawn_blocking`].
+
+use crate::leak::Tracked;
+use anyhow::anyhow;
+use log::error;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::runtime::Handle;
+use tokio::task::{JoinError, JoinHandle};
+
+static CAUGHT_PANICS: AtomicU64 = AtomicU64::new(0);
+
+/// A panic caught by [`catch_panic`].
+#[derive(Debug)]
+pub(crate) struct Panic {
+    message: String,
+}
+
+impl Panic {
+    fn from_payload(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&'static str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "Box<dyn Any>".to_string(),
+            },
+        };
+        Panic { message }
+    }
+}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Future returned by [`catch_panic`].
+pub(crate) struct CatchPanic<F> {
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for CatchPanic<F> {
+    type Output = Result<F::Output, Panic>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let future = self.future.as_mut();
+        match panic::catch_unwind(AssertUnwindSafe(|| future.poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => {
+                CAUGHT_PANICS.fetch_add(1, Ordering::Relaxed);
+                Poll::Ready(Err(Panic::from_payload(payload)))
+            }
+        }
+    }
+}
+
+/// Runs `future` to completion, returning an error if it panics.
+///
+/// State shared with the future may be left inconsistent by the panic, so the work should only be
+/// retried if it does not depend on such state.
+pub(crate) fn catch_panic<F: Future>(future: F) -> CatchPanic<F> {
+    CatchPanic {
+        future: Box::pin(future),
+    }
+}
+
+/// Spawns a task which is counted under `label` while it is running.
+///
+/// The output is `None` if the task panicked.
+pub(crate) fn spawn<F>(label: &'static str, future: F) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_inner(None, label, None, future, |_| ())
+}
+
+/// Spawns a task like [`spawn`] onto the runtime of `handle`, or the current runtime if `None`.
+///
+/// Tasks spawned by the task itself also run on that runtime.
+pub(crate) fn spawn_on<F>(
+    handle: Option<&Handle>,
+    label: &'static str,
+    future: F,
+) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    spawn_inner(handle, label, None, future, |_| ())
+}
+
+/// Spawns a task like [`spawn`], which works on behalf of `context`, e.g. a tunnel.
+///
+/// If the task panics, the panic is logged together with `context` and passed to `on_panic`.
+pub(crate) fn spawn_with<F, P>(
+    label: &'static str,
+    context: String,
+    future: F,
+    on_panic: P,
+) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+    P: FnOnce(&Panic) + Send + 'static,
+{
+    spawn_inner(None, label, Some(context), future, on_panic)
+}
+
+fn spawn_inner<F, P>(
+    handle: Option<&Handle>,
+    label: &'static str,
+    context: Option<String>,
+    future: F,
+    on_panic: P,
+) -> JoinHandle<Option<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+    P: FnOnce(&Panic) + Send + 'static,
+{
+    let tracked = Tracked::new(label);
+    let task = async move {
+        let _tracked = tracked;
+        match catch_panic(future).await {
+            Ok(output) => Some(output),
+            Err(panic) => {
+                match &context {
+                    Some(context) => error!("Task {} of {} panicked: {}", label, context, panic),
+                    None => error!("Task {} panicked: {}", label, panic),
+                }
+                on_panic(&panic);
+                None
+            }
+        }
+    };
+    match handle {
+        Some(handle) => handle.spawn(task),
+        None => tokio::spawn(task),
+    }
+}
+
+/// Runs the blocking function `f`, e.g. a RSA signature, on the blocking thread pool of the
+/// current runtime, so it does not stall the other tasks of the worker thread. `f` is counted under
+/// `label` while it is running.
+///
+/// Fails if `f` panics or the runtime shuts down before `f` has run. If the returned future is
+/// dropped, `f` still runs to completion.
+pub(crate) async fn spawn_blocking<F, T>(label: &'static str, f: F) -> crate::Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let tracked = Tracked::new(label);
+    let res = tokio::task::spawn_blocking(move || {
+        let _tracked = tracked;
+        f()
+    })
+    .await;
+    res.map_err(|e| match e.try_into_panic() {
+        Ok(payload) => {
+            CAUGHT_PANICS.fetch_add(1, Ordering::Relaxed);
+            let panic = Panic::from_payload(payload);
+            error!("Task {} panicked: {}", label, panic);
+            anyhow!("Task {} panicked: {}", label, panic)
+        }
+        Err(e) => anyhow!("Task {} did not run: {}", label, e),
+    })
+}
+
+/// Future returned by [`abort_on_drop`].
+pub(crate) struct AbortOnDrop<T> {
+    handle: JoinHandle<T>,
+}
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.handle).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Waits for the task of `handle` like the handle itself, but aborts the task if the wait is
+/// given up, e.g. because the calling future is dropped.
+pub(crate) fn abort_on_drop<T>(handle: JoinHandle<T>) -> AbortOnDrop<T> {
+    AbortOnDrop { handle }
+}
+
+/// Returns the number of panics caught in tasks of the crate.
+///
+/// The count is shared by all onion router instances in the process.
+pub fn caught_panics() -> u64 {
+    CAUGHT_PANICS.load(Ordering::Relaxed)
+}
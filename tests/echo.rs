@@ -0,0 +1,70 @@
+//! Runs the `echo_server` and `echo_client` examples as two processes.
+//! Run with `cargo test --test echo -- --ignored`.
+
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+const RUSTCRYPTO: &[&str] = &["--features", "crypto_rustcrypto"];
+
+fn example(name: &str, features: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO"));
+    command
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(["run", "--quiet", "--example", name])
+        .args(features)
+        .arg("--");
+    command
+}
+
+/// Kills the server once the test is done, even if it failed.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Echoes messages through a tunnel of one hop, for which the server relays the circuit to itself.
+fn run_echo(
+    server_addr: &str,
+    client_addr: &str,
+    server_features: &[&str],
+    client_features: &[&str],
+) {
+    let mut server = Server(
+        example("echo_server", server_features)
+            .args(["--listen", server_addr, "--round", "1"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+    let mut line = String::new();
+    BufReader::new(server.0.stdout.as_mut().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    assert_eq!(line.trim(), format!("listening on {}", server_addr));
+
+    let status = example("echo_client", client_features)
+        .args(["--listen", client_addr, "--server", server_addr])
+        .args(["--relay", server_addr, "--hops", "1"])
+        .args(["--count", "50", "--rate", "100", "--round", "1"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+#[ignore]
+fn test_echo_examples() {
+    run_echo("127.0.0.1:43800", "127.0.0.1:43801", &[], &[]);
+}
+
+/// Peers using the OpenSSL and the RustCrypto backend understand each other.
+#[test]
+#[ignore]
+fn test_crypto_backend_interop() {
+    run_echo("127.0.0.1:43802", "127.0.0.1:43803", RUSTCRYPTO, &[]);
+    run_echo("127.0.0.1:43804", "127.0.0.1:43805", &[], RUSTCRYPTO);
+}
@@ -1,10 +1,21 @@
-use allium::{OnionBuilder, OnionContext, OnionIncoming, Peer, PeerProvider, RsaPrivateKey};
+use allium::{
+    BuildAttempt, BuildOutcome, Capabilities, CellSize, CipherSuite, CloseReason, CoalescedTunnel,
+    EndReason, Event, Fallback, HostKey, NoAcceptablePeers, NodeState, NotARelay, OnionBuilder,
+    OnionContext, OnionIncoming, OnionRng, OnionStream, Peer, PeerProvider, ProviderClosed,
+    RotationStrategy, RsaPrivateKey, SeededRandom, ShuttingDown, StartProblem, StateObserver,
+    StreamMux, StrictViolation, TunnelBroken, TunnelId, TunnelOptions, TunnelState,
+};
 use bytes::Bytes;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::atomic::{AtomicU16, Ordering};
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time;
 use tokio_stream as stream;
+use tokio_stream::StreamExt;
 
 static PORT_COUNTER: AtomicU16 = AtomicU16::new(42000);
 const ROUND_DURATION: Duration = Duration::from_secs(5);
@@ -20,10 +31,25 @@ struct TestPeer {
     incoming: OnionIncoming,
 }
 
-fn new_unique_peer() -> (Peer, RsaPrivateKey) {
+fn new_unique_peer() -> (Peer, HostKey) {
     let port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
     let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
-    let hostkey = RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+    new_peer_at(addr)
+}
+
+fn new_peer_at(addr: SocketAddr) -> (Peer, HostKey) {
+    new_peer_with_key_at(addr, "testkey.pem")
+}
+
+/// Returns a new peer on a unique port, identified by a Ed25519 instead of a RSA key.
+fn new_unique_ed25519_peer() -> (Peer, HostKey) {
+    let port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    new_peer_with_key_at(addr, "tests/ed25519key.pem")
+}
+
+fn new_peer_with_key_at(addr: SocketAddr, key_path: &str) -> (Peer, HostKey) {
+    let hostkey = HostKey::from_pem_file(key_path).unwrap();
     (Peer::new(addr, hostkey.public_key()), hostkey)
 }
 
@@ -37,13 +63,22 @@ fn new_unique_peers(n: usize) -> Vec<Peer> {
 }
 
 async fn spawn_peer(peers: Vec<Peer>, cover: bool, hops: usize) -> TestPeer {
-    let (peer, hostkey) = new_unique_peer();
+    spawn_peer_with_key(new_unique_peer(), peers, cover, hops).await
+}
+
+async fn spawn_peer_with_key(
+    (peer, hostkey): (Peer, HostKey),
+    peers: Vec<Peer>,
+    cover: bool,
+    hops: usize,
+) -> TestPeer {
     let peer_provider = PeerProvider::from_stream(stream::iter(peers));
     let (ctx, incoming) = OnionBuilder::new(peer.address(), hostkey, peer_provider)
         .enable_cover_traffic(cover)
         .set_hops_per_tunnel(hops)
         .set_round_duration(ROUND_DURATION)
-        .start();
+        .start()
+        .unwrap();
     TestPeer {
         peer,
         ctx,
@@ -94,6 +129,374 @@ async fn test_build_success() {
     assert_eq!(incoming_id, ready_id);
 }
 
+#[tokio::test]
+async fn test_seeded_tunnel_ids() {
+    let mut dest = spawn_simple_peer().await;
+    let mut expected = [0u8; 4];
+    SeededRandom::new(7).fill_bytes(&mut expected);
+
+    for _ in 0..2 {
+        let (peer, hostkey) = new_unique_peer();
+        let (ctx, _incoming) = OnionBuilder::new(
+            peer.address(),
+            hostkey,
+            PeerProvider::from_stream(stream::empty()),
+        )
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(0)
+        .set_rng_factory(|| Box::new(SeededRandom::new(7)))
+        .start()
+        .unwrap();
+        let tunnel = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tunnel.id(), TunnelId::from_le_bytes(expected));
+        let incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(incoming.id(), tunnel.id());
+    }
+}
+
+/// Returns a new peer listening on a unique port of `ip`.
+fn new_unique_peer_on(ip: IpAddr) -> (Peer, HostKey) {
+    let port = PORT_COUNTER.fetch_add(1, Ordering::Relaxed);
+    new_peer_at(SocketAddr::new(ip, port))
+}
+
+#[tokio::test]
+async fn test_build_over_ipv6() {
+    let ip = IpAddr::V6(Ipv6Addr::LOCALHOST);
+    let relay = spawn_peer_with_key(new_unique_peer_on(ip), vec![], false, 0).await;
+    let peer1 =
+        spawn_peer_with_key(new_unique_peer_on(ip), vec![relay.peer.clone()], false, 1).await;
+    let mut peer2 = spawn_peer_with_key(new_unique_peer_on(ip), vec![], false, 0).await;
+
+    let tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let path = peer1.ctx.path_info(tunnel.id()).unwrap();
+    let addrs: Vec<_> = path.iter().map(|hop| hop.addr).collect();
+    assert_eq!(addrs, vec![relay.peer.address(), peer2.peer.address()]);
+
+    tunnel.write(TEST_DATA).unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(incoming.read().await.unwrap(), TEST_DATA);
+    assert!(incoming.incoming_info().unwrap().adjacent_peer.is_ipv6());
+}
+
+#[tokio::test]
+async fn test_build_mixed_key_types() {
+    let ed25519_relay = spawn_peer_with_key(new_unique_ed25519_peer(), vec![], false, 0).await;
+    let rsa_relay = spawn_simple_peer().await;
+    let relays = vec![ed25519_relay.peer.clone(), rsa_relay.peer.clone()];
+    let peer1 = spawn_peer(relays, false, 2).await;
+    let mut peer2 = spawn_peer_with_key(new_unique_ed25519_peer(), vec![], false, 0).await;
+
+    let tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let path = peer1.ctx.path_info(tunnel.id()).unwrap();
+    assert_eq!(path.len(), 3);
+
+    tunnel.write(TEST_DATA).unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(incoming.read().await.unwrap(), TEST_DATA);
+}
+
+#[tokio::test]
+async fn test_dual_stack_listener() {
+    let (peer, hostkey) = new_unique_peer_on(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+    let port = peer.address().port();
+    let mut dest = spawn_peer_with_key((peer, hostkey), vec![], false, 0).await;
+    let peer1 = spawn_simple_peer().await;
+
+    // the peer listening on `::` is reached over both address families
+    for ip in [
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(Ipv6Addr::LOCALHOST),
+    ] {
+        let key = RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+        let target = Peer::new(SocketAddr::new(ip, port), key.public_key());
+        let tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(target))
+            .await
+            .unwrap()
+            .unwrap();
+        tunnel.write(TEST_DATA).unwrap();
+        let mut incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(incoming.read().await.unwrap(), TEST_DATA);
+    }
+}
+
+#[tokio::test]
+async fn test_incoming_tunnel_info() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let options = TunnelOptions::new().set_cell_size(CellSize::Large);
+    let ready_fut = peer1.ctx.build_tunnel_with_options(peer2.peer, options);
+    let tunnel = time::timeout(ROUND_TIMEOUT, ready_fut)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(tunnel.incoming_info().is_none());
+    let incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let info = incoming.incoming_info().unwrap();
+    assert!(info.adjacent_peer.ip().is_loopback());
+    assert_eq!(info.cell_size, CellSize::Large);
+    assert!(info.began_at <= SystemTime::now());
+    assert_eq!(peer2.ctx.tunnel_info(incoming.id()).as_ref(), Some(info));
+    assert!(peer1.ctx.tunnel_info(tunnel.id()).is_none());
+}
+
+#[tokio::test]
+async fn test_list_tunnels() {
+    let relay = spawn_simple_peer().await;
+    let peer1 = spawn_peer(vec![relay.peer.clone()], false, 1).await;
+    let peer2 = spawn_simple_peer().await;
+    assert!(peer1.ctx.list_tunnels().is_empty());
+
+    let tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let tunnels = peer1.ctx.list_tunnels();
+    assert_eq!(tunnels.len(), 1);
+    let info = &tunnels[0];
+    assert_eq!(info.tunnel_id, tunnel.id());
+    assert_eq!(info.state, TunnelState::Ready);
+    assert_eq!(info.hops, 2);
+    assert_eq!(info.destination, Some(peer2.peer.fingerprint()));
+    assert!(info.since_switchover.is_some());
+    let info = peer1.ctx.outgoing_tunnel_info(tunnel.id()).unwrap();
+    assert_eq!(info.tunnel_id, tunnel.id());
+    // incoming tunnels are not listed
+    assert!(peer2.ctx.list_tunnels().is_empty());
+
+    // a closed tunnel is removed once its handler finished
+    drop(tunnel);
+    time::timeout(ROUND_TIMEOUT, async {
+        while !peer1.ctx.list_tunnels().is_empty() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_ping_tunnel() {
+    let relay = spawn_simple_peer().await;
+    let peer1 = spawn_peer(vec![relay.peer.clone()], false, 1).await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let rtt = time::timeout(ERROR_TIMEOUT, peer1.ctx.ping_tunnel(tunnel.id()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(rtt < ERROR_TIMEOUT);
+    assert_eq!(tunnel.stats().round_trip_time, Some(rtt));
+
+    // the tunnel still carries data after its destination answered the echo
+    tunnel.write(TEST_DATA).unwrap();
+    let mut incoming = time::timeout(ROUND_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(incoming.read().await.unwrap(), TEST_DATA);
+
+    // only outgoing tunnels can be pinged
+    assert!(peer2.ctx.ping_tunnel(incoming.id()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_rekey_tunnel() {
+    let relay = spawn_simple_peer().await;
+    let peer1 = spawn_peer(vec![relay.peer.clone()], false, 1).await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let path = peer1.ctx.path_info(tunnel.id()).unwrap();
+    time::timeout(ROUND_TIMEOUT, peer1.ctx.rekey_tunnel(tunnel.id()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(peer1.ctx.path_info(tunnel.id()).unwrap(), path);
+    assert_eq!(tunnel.stats().rekeys, 1);
+
+    // the destination receives the data sent on the new circuits
+    tunnel.write(TEST_DATA).unwrap();
+    let mut incoming = time::timeout(ROUND_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(incoming.read().await.unwrap(), TEST_DATA);
+
+    // only outgoing tunnels can be rekeyed
+    assert!(peer2.ctx.rekey_tunnel(incoming.id()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_negotiated_params() {
+    let (relay_peer, hostkey) = new_unique_peer();
+    let (relay, _relay_incoming) = OnionBuilder::new(
+        relay_peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_cipher_suites(&[CipherSuite::TruncatedDigest, CipherSuite::ShortHmac])
+    .start()
+    .unwrap();
+    let peer1 = spawn_peer(vec![relay_peer.clone()], false, 1).await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // each hop negotiates its own cipher suite
+    let hops = peer1.ctx.path_info(tunnel.id()).unwrap();
+    assert_eq!(hops.len(), 2);
+    assert_eq!(hops[0].addr, relay_peer.address());
+    assert_eq!(hops[0].params.cipher_suite, CipherSuite::ShortHmac);
+    assert_eq!(hops[1].addr, peer2.peer.address());
+    assert_eq!(hops[1].params.cipher_suite, CipherSuite::Hmac);
+    assert!(hops
+        .iter()
+        .all(|hop| hop.params.cell_size == CellSize::Standard));
+    assert!(peer2.ctx.path_info(incoming.id()).is_none());
+
+    let info = peer2.ctx.tunnel_info(incoming.id()).unwrap();
+    assert_eq!(info.cipher_suite, CipherSuite::Hmac);
+    let circuits = relay.relay_stats().unwrap().inbound_circuits;
+    assert_eq!(circuits.len(), 1);
+    assert_eq!(circuits[0].params, hops[0].params);
+}
+
+#[tokio::test]
+async fn test_relay_latency() {
+    const N_MESSAGES: usize = 20;
+    let relay = spawn_relay(16).await;
+    let peer1 = spawn_peer(vec![relay.peer.clone()], false, 1).await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    for _ in 0..N_MESSAGES {
+        tunnel.write(TEST_DATA).unwrap();
+    }
+    for _ in 0..N_MESSAGES {
+        time::timeout(ERROR_TIMEOUT, incoming.read())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    // cells are measured in both directions, at least the data cells have been relayed
+    let stats = relay.ctx.relay_stats().unwrap();
+    let latency = stats.forwarding_latency;
+    assert!(latency.count() >= N_MESSAGES as u64);
+    assert!(latency.p50().unwrap() <= latency.p95().unwrap());
+    assert!(latency.p95().unwrap() <= latency.p99().unwrap());
+    let queues = stats.relay_connection_queues;
+    assert_eq!(queues.len(), 1);
+    assert_eq!(queues[0].peer_addr, peer2.peer.address());
+    assert!(queues[0].high_water_mark >= 1);
+
+    relay.ctx.reset_relay_measurements().unwrap();
+    let stats = relay.ctx.relay_stats().unwrap();
+    assert_eq!(stats.forwarding_latency.count(), 0);
+    assert_eq!(stats.forwarding_latency.p99(), None);
+    let queue = &stats.relay_connection_queues[0];
+    assert_eq!(queue.high_water_mark, queue.queued);
+}
+
+#[tokio::test]
+async fn test_client_only() {
+    const SHORT_ROUND: Duration = Duration::from_secs(2);
+    let relay = spawn_simple_peer().await;
+    let mut dest = spawn_simple_peer().await;
+    let pool = PeerProvider::from_stream(stream::iter(iter::repeat(relay.peer.clone())));
+    let (ctx, mut incoming) = OnionBuilder::client_only(pool)
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(1)
+        .set_round_duration(SHORT_ROUND)
+        .start()
+        .unwrap();
+
+    // no socket is bound and nothing is relayed
+    assert_eq!(ctx.local_addr(), None);
+    assert_eq!(relay.ctx.local_addr(), Some(relay.peer.address()));
+    assert_eq!(ctx.relay_stats().err(), Some(NotARelay));
+    assert_eq!(ctx.reset_relay_measurements(), Err(NotARelay));
+    let ended = time::timeout(ERROR_TIMEOUT, incoming.next()).await.unwrap();
+    assert!(ended.is_none());
+
+    let mut tunnel = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    time::timeout(3 * SHORT_ROUND, async {
+        while tunnel.stats().rotations < 1 {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    tunnel.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+    incoming.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, tunnel.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+}
+
 #[tokio::test]
 async fn test_build_error() {
     let peer1 = spawn_simple_peer().await;
@@ -198,6 +601,82 @@ async fn test_long_data() {
     }
 }
 
+#[tokio::test]
+async fn test_many_small_writes() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let ready_fut = peer1.ctx.build_tunnel(peer2.peer);
+    let ready = time::timeout(ROUND_TIMEOUT, ready_fut)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    for _ in 0..500 {
+        ready.write(TEST_DATA).unwrap();
+    }
+    for _ in 0..500 {
+        let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_data, TEST_DATA);
+    }
+    assert_eq!(ready.stats().sent_cells, 500);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_interleaved_send_receive() {
+    const N: u32 = 2000;
+    let hop = spawn_many_peers(1).await.remove(0);
+    let peer1 = spawn_peer(vec![hop; 4], false, 1).await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let ready_fut = peer1.ctx.build_tunnel(peer2.peer);
+    let mut ready = time::timeout(ROUND_TIMEOUT, ready_fut)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // both ends send while receiving, so reads are frequently interrupted by writes
+    let send = |writer: allium::TunnelWriter| async move {
+        for i in 0..N {
+            writer
+                .write(Bytes::from(i.to_be_bytes().repeat(64)))
+                .unwrap();
+            if i % 16 == 0 {
+                let _ = tokio::task::yield_now().await;
+            }
+        }
+    };
+    tokio::spawn(send(ready.writer()));
+    tokio::spawn(send(incoming.writer()));
+
+    tokio::join!(
+        read_sequence(&mut ready, N),
+        read_sequence(&mut incoming, N)
+    );
+}
+
+async fn read_sequence(tunnel: &mut allium::Tunnel, n: u32) {
+    for i in 0..n {
+        let read_data = time::timeout(ERROR_TIMEOUT, tunnel.read())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_data, i.to_be_bytes().repeat(64));
+    }
+}
+
 async fn spawn_many_peers(n: usize) -> Vec<Peer> {
     let mut peers = vec![];
     for _ in 0..n {
@@ -238,47 +717,1993 @@ async fn test_long_data_two_hops() {
 }
 
 #[tokio::test]
-async fn test_data_error_disconnected_destination() {
-    let peer1 = spawn_simple_peer().await;
+async fn test_splice_rotation() {
+    let hops = spawn_many_peers(2).await;
+    let (peer, hostkey) = new_unique_peer();
+    let peer_provider = PeerProvider::from_stream(stream::iter(iter::repeat(hops).flatten()));
+    let (ctx, _incoming) = OnionBuilder::new(peer.address(), hostkey, peer_provider)
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(2)
+        .set_round_duration(ROUND_DURATION)
+        .set_rotation_strategy(RotationStrategy::Splice { keep_hops: 1 })
+        .start()
+        .unwrap();
     let mut peer2 = spawn_simple_peer().await;
 
-    let ready_fut = peer1.ctx.build_tunnel(peer2.peer);
-    let ready = time::timeout(ROUND_TIMEOUT, ready_fut)
+    let mut ready = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(peer2.peer))
         .await
         .unwrap()
         .unwrap();
-
-    let incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
         .await
         .unwrap()
         .unwrap();
-    assert_eq!(incoming.id(), ready.id());
 
-    drop(incoming);
-    time::sleep(DELAY_TIMEOUT).await;
-    ready.write(TEST_DATA).unwrap_err();
+    time::timeout(2 * ROUND_TIMEOUT, async {
+        while ready.stats().rotations == 0 {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let stats = ready.stats();
+    assert_eq!(stats.spliced_rotations, stats.rotations);
+
+    // data is carried by the spliced path
+    ready.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+    incoming.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, ready.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
 }
 
 #[tokio::test]
-async fn test_data_error_disconnected_source() {
-    let peer1 = spawn_simple_peer().await;
+async fn test_stream_across_rotations() {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    const CHUNKS: usize = 64;
+    let hops = spawn_many_peers(1).await;
+    let (peer, hostkey) = new_unique_peer();
+    let peer_provider = PeerProvider::from_stream(stream::iter(iter::repeat(hops).flatten()));
+    let (ctx, _incoming) = OnionBuilder::new(peer.address(), hostkey, peer_provider)
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(1)
+        .set_round_duration(ROUND_DURATION)
+        .start()
+        .unwrap();
     let mut peer2 = spawn_simple_peer().await;
 
-    let ready_fut = peer1.ctx.build_tunnel(peer2.peer);
-    let ready = time::timeout(ROUND_TIMEOUT, ready_fut)
+    let ready = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(peer2.peer))
         .await
         .unwrap()
         .unwrap();
-
-    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+    let incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
         .await
         .unwrap()
         .unwrap();
-    assert_eq!(incoming.id(), ready.id());
+    let mut source = OnionStream::new(ready);
+    let mut destination = OnionStream::new(incoming);
 
-    drop(ready);
-    time::timeout(ROUND_TIMEOUT, incoming.read())
+    // a deterministic pattern, which does not repeat at the size of a cell
+    let data: Vec<u8> = (0..CHUNK_SIZE * CHUNKS)
+        .map(|i| (i % 251) as u8 ^ (i / 251) as u8)
+        .collect();
+    let expected = data.clone();
+    // spread the writes over more than a round, so the path is rotated in between
+    let writer = tokio::spawn(async move {
+        for chunk in data.chunks(CHUNK_SIZE) {
+            source.write_all(chunk).await.unwrap();
+            time::sleep(ROUND_DURATION * 3 / 2 / CHUNKS as u32).await;
+        }
+        source
+    });
+
+    let mut received = vec![0; expected.len()];
+    time::timeout(2 * ROUND_TIMEOUT, destination.read_exact(&mut received))
         .await
         .unwrap()
+        .unwrap();
+    assert!(received == expected);
+    let source = writer.await.unwrap();
+    assert!(source.get_ref().stats().rotations > 0);
+
+    // the end of the tunnel is the end of the stream
+    drop(source);
+    let mut rest = Vec::new();
+    time::timeout(ERROR_TIMEOUT, destination.read_to_end(&mut rest))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(rest.is_empty());
+    assert!(destination.write_all(&TEST_DATA).await.is_err());
+}
+
+#[tokio::test]
+async fn test_stream_mux() {
+    // more than a stream may have in flight
+    const UNREAD_SIZE: usize = 256 * 1024;
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let incoming = time::timeout(ROUND_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut source = StreamMux::new(tunnel);
+    let mut destination = StreamMux::new(incoming);
+
+    let unread = source.open_stream().await.unwrap();
+    let other = source.open_stream().await.unwrap();
+    let unread_data: Vec<u8> = (0..UNREAD_SIZE).map(|i| (i % 251) as u8).collect();
+    unread.write(Bytes::from(unread_data.clone())).unwrap();
+    other.write(TEST_DATA).unwrap();
+
+    let mut unread2 = time::timeout(ERROR_TIMEOUT, destination.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut other2 = time::timeout(ERROR_TIMEOUT, destination.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(unread2.id(), unread.id());
+    assert_eq!(other2.id(), other.id());
+    // the unread stream does not hold up its sibling
+    let data = time::timeout(ERROR_TIMEOUT, other2.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(data, TEST_DATA);
+
+    // closing a stream leaves its siblings open
+    drop(other);
+    assert!(time::timeout(ERROR_TIMEOUT, other2.read())
+        .await
+        .unwrap()
+        .is_err());
+    assert!(other2.write(TEST_DATA).is_err());
+    let mut received = vec![0; UNREAD_SIZE];
+    time::timeout(ERROR_TIMEOUT, unread2.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(received == unread_data);
+
+    // both ends open streams, with ids which do not collide
+    let mut reverse = destination.open_stream().await.unwrap();
+    reverse.write(TEST_DATA).unwrap();
+    let mut reverse2 = time::timeout(ERROR_TIMEOUT, source.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_ne!(reverse.id() % 2, unread.id() % 2);
+    assert_eq!(reverse2.read().await.unwrap(), TEST_DATA);
+    reverse2.write(TEST_DATA).unwrap();
+    assert_eq!(reverse.read().await.unwrap(), TEST_DATA);
+}
+
+#[tokio::test]
+async fn test_stream_mux_unsupported() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ROUND_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    let mux = StreamMux::new(tunnel);
+    let mut stream = mux.open_stream().await.unwrap();
+    stream.write(TEST_DATA).unwrap();
+
+    // only the greeting reaches a destination which does not multiplex the tunnel
+    let hello = time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&hello[..], &[0, 0, 0, 0, 1, 1]);
+    incoming.write(TEST_DATA).unwrap();
+    assert!(time::timeout(ERROR_TIMEOUT, stream.read())
+        .await
+        .unwrap()
+        .is_err());
+    assert!(time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_write_feedback() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let options = TunnelOptions::new().enable_write_feedback(true);
+    let tunnel = time::timeout(
+        ROUND_TIMEOUT,
+        peer1
+            .ctx
+            .build_tunnel_with_options(peer2.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut feedback = tunnel.write_feedback().unwrap();
+    assert!(tunnel.write_feedback().is_none());
+
+    // untracked messages in between are not reported
+    let first = tunnel.write_tracked(TEST_DATA).unwrap();
+    tunnel.write(TEST_DATA).unwrap();
+    let second = tunnel.writer().write_tracked(LONG_DATA).unwrap();
+    assert!(first < second);
+
+    let mut bytes_received = 0;
+    while bytes_received < 2 * TEST_DATA.len() + LONG_DATA.len() {
+        let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+            .await
+            .unwrap()
+            .unwrap();
+        bytes_received += read_data.len();
+    }
+    for handle in [first, second] {
+        let written = time::timeout(ERROR_TIMEOUT, feedback.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(written.handle, handle);
+        assert!(written.queued_at <= written.written_at);
+    }
+    assert!(feedback.try_next().is_none());
+
+    // without the option, messages can not be tracked
+    let untracked = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(untracked.write_feedback().is_none());
+    assert!(untracked.write_tracked(TEST_DATA).is_err());
+}
+
+#[tokio::test]
+async fn test_panicking_peer_provider() {
+    let relay = spawn_simple_peer().await;
+    let mut dest = spawn_simple_peer().await;
+    let (peer, hostkey) = new_unique_peer();
+    let mut polls = 0;
+    let peers = stream::iter(iter::repeat(relay.peer.clone())).map(move |peer| {
+        polls += 1;
+        if polls == 1 {
+            panic!("injected peer provider panic");
+        }
+        peer
+    });
+    let (ctx, _incoming) =
+        OnionBuilder::new(peer.address(), hostkey, PeerProvider::from_stream(peers))
+            .enable_cover_traffic(false)
+            .set_hops_per_tunnel(1)
+            .set_round_duration(ROUND_DURATION)
+            // keep the paths stable for the duration of the test
+            .set_min_tunnel_lifetime(Duration::from_secs(60))
+            .start()
+            .unwrap();
+    ctx.add_known_peer(relay.peer.clone());
+
+    // does not use the peer provider
+    let options = TunnelOptions::new().set_first_hop(relay.peer.fingerprint());
+    let pinned = time::timeout(
+        ROUND_TIMEOUT,
+        ctx.build_tunnel_with_options(dest.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let mut pinned_incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let panics = allium::caught_panics();
+    time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert!(allium::caught_panics() > panics);
+
+    // the peer provider and the relay keep working
+    let ready = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(incoming.id(), ready.id());
+    for (tunnel, incoming) in [(&ready, &mut incoming), (&pinned, &mut pinned_incoming)] {
+        tunnel.write(TEST_DATA).unwrap();
+        let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_data, TEST_DATA);
+    }
+    assert_eq!(pinned.stats().panics, 0);
+}
+
+#[test]
+fn test_relay_runtime() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let relay_runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let (relay, hostkey) = new_unique_peer();
+        let _relay = OnionBuilder::new(
+            relay.address(),
+            hostkey,
+            PeerProvider::from_stream(stream::empty()),
+        )
+        .enable_cover_traffic(false)
+        .set_relay_runtime(relay_runtime.handle().clone())
+        .start()
+        .unwrap();
+        let peer1 = spawn_peer(vec![relay], false, 1).await;
+        let mut peer2 = spawn_simple_peer().await;
+
+        let ready = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+            .await
+            .unwrap()
+            .unwrap();
+        ready.write(TEST_DATA).unwrap();
+        let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_data, TEST_DATA);
+    });
+    relay_runtime.shutdown_background();
+}
+
+#[tokio::test]
+async fn test_direct_tunnel_rotation() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let mut ready = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // the destination is connected again on the next round
+    time::timeout(2 * ROUND_TIMEOUT, async {
+        while ready.stats().rotations == 0 {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    ready.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+    incoming.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, ready.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+
+    drop(ready);
+    time::timeout(ROUND_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap_err();
+}
+
+#[derive(Default)]
+struct RecordingObserver {
+    states: Mutex<Vec<(TunnelId, TunnelState, TunnelState)>>,
+    attempts: Mutex<Vec<BuildAttempt>>,
+    accepted: AtomicUsize,
+}
+
+impl StateObserver for RecordingObserver {
+    fn on_tunnel_state(&self, tunnel_id: TunnelId, old: TunnelState, new: TunnelState) {
+        self.states.lock().unwrap().push((tunnel_id, old, new));
+    }
+
+    fn on_build_attempt(&self, _tunnel_id: TunnelId, attempt: &BuildAttempt) {
+        self.attempts.lock().unwrap().push(attempt.clone());
+    }
+
+    fn on_circuit_accepted(&self, _peer_addr: SocketAddr) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+async fn spawn_observed_peer(observer: Arc<RecordingObserver>) -> TestPeer {
+    let (peer, hostkey) = new_unique_peer();
+    let peer_provider = PeerProvider::from_stream(stream::empty());
+    let (ctx, incoming) = OnionBuilder::new(peer.address(), hostkey, peer_provider)
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(0)
+        .set_round_duration(ROUND_DURATION)
+        .set_state_observer(observer)
+        .start()
+        .unwrap();
+    TestPeer {
+        peer,
+        ctx,
+        incoming,
+    }
+}
+
+#[tokio::test]
+async fn test_state_observer() {
+    let observer1 = Arc::new(RecordingObserver::default());
+    let observer2 = Arc::new(RecordingObserver::default());
+    let peer1 = spawn_observed_peer(observer1.clone()).await;
+    let mut peer2 = spawn_observed_peer(observer2.clone()).await;
+
+    let ready = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer))
+        .await
+        .unwrap()
+        .unwrap();
+    time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    let tunnel_id = ready.id();
+    assert_eq!(
+        *observer1.states.lock().unwrap(),
+        vec![(tunnel_id, TunnelState::Building, TunnelState::Ready)]
+    );
+    // peer2 may not be listening yet and the path of the next round may already be built
+    let attempts = observer1.attempts.lock().unwrap().clone();
+    assert!(attempts.iter().all(|a| a.hop == 0));
+    assert!(attempts.iter().any(|a| a.outcome == BuildOutcome::Ok));
+    assert!(observer2.accepted.load(Ordering::Relaxed) >= 1);
+
+    // the tunnel is closed on the next round
+    drop(ready);
+    time::timeout(2 * ROUND_TIMEOUT, async {
+        while observer1.states.lock().unwrap().len() < 3 {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        observer1.states.lock().unwrap()[1..],
+        [
+            (tunnel_id, TunnelState::Ready, TunnelState::Destroying),
+            (tunnel_id, TunnelState::Destroying, TunnelState::Destroyed),
+        ]
+    );
+}
+
+/// number of cells exceeding the buffers between the onion router and the application
+const UNREAD_CELLS: usize = 300;
+
+#[tokio::test]
+async fn test_unread_data_destination() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let mut ready = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer))
+        .await
+        .unwrap()
+        .unwrap();
+    let incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // the destination never reads, but its writes are still forwarded
+    for _ in 0..UNREAD_CELLS {
+        ready.write(TEST_DATA).unwrap();
+    }
+    time::sleep(Duration::from_millis(500)).await;
+    incoming.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, ready.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+}
+
+#[tokio::test]
+async fn test_unread_data_source() {
+    let observer = Arc::new(RecordingObserver::default());
+    let peer1 = spawn_observed_peer(observer.clone()).await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let ready = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer))
+        .await
+        .unwrap()
+        .unwrap();
+    let incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // the source never reads, but its tunnel is still rotated and destroyed
+    for _ in 0..UNREAD_CELLS {
+        incoming.write(TEST_DATA).unwrap();
+    }
+    time::timeout(2 * ROUND_TIMEOUT, async {
+        while ready.stats().rotations == 0 {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    drop(ready);
+    time::timeout(ROUND_TIMEOUT, async {
+        while !observer
+            .states
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|&(_, _, new)| new == TunnelState::Destroyed)
+        {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_data_error_disconnected_destination() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let ready_fut = peer1.ctx.build_tunnel(peer2.peer);
+    let ready = time::timeout(ROUND_TIMEOUT, ready_fut)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(incoming.id(), ready.id());
+
+    drop(incoming);
+    time::sleep(DELAY_TIMEOUT).await;
+    ready.write(TEST_DATA).unwrap_err();
+}
+
+#[tokio::test]
+async fn test_data_error_disconnected_source() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let ready_fut = peer1.ctx.build_tunnel(peer2.peer);
+    let ready = time::timeout(ROUND_TIMEOUT, ready_fut)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(incoming.id(), ready.id());
+
+    drop(ready);
+    time::timeout(ROUND_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn test_shutdown_ready_tunnel() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+
+    let ready_fut = peer1.ctx.build_tunnel(peer2.peer.clone());
+    let mut ready = time::timeout(ROUND_TIMEOUT, ready_fut)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut events = peer1.ctx.events();
+    let report = time::timeout(ERROR_TIMEOUT, peer1.ctx.shutdown())
+        .await
+        .unwrap();
+    // the closing fits into the buffer of the stream
+    assert_eq!(report.dropped_events, 0);
+
+    // intake is closed
+    let error = peer1.ctx.build_tunnel(peer2.peer).await.unwrap_err();
+    assert_eq!(error.downcast_ref::<ShuttingDown>(), Some(&ShuttingDown));
+    let error = peer1.ctx.send_cover(1).unwrap_err();
+    assert_eq!(error.downcast_ref::<ShuttingDown>(), Some(&ShuttingDown));
+
+    // the tunnel is closed in both directions
+    ready.read().await.unwrap_err();
+    ready.write(TEST_DATA).unwrap_err();
+    time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap_err();
+
+    // the closing is reported before the event stream ends
+    assert_eq!(
+        events.next().await,
+        Some(Event::Closed {
+            tunnel_id: ready.id(),
+            reason: CloseReason::Shutdown,
+            end_reason: None,
+        })
+    );
+    assert_eq!(events.next().await, None);
+}
+
+#[tokio::test]
+async fn test_shutdown_building_tunnel() {
+    let peer1 = spawn_simple_peer().await;
+    let peer2 = spawn_simple_peer().await;
+    // the tunnel only becomes ready at the next round
+    time::sleep(Duration::from_secs(1)).await;
+
+    let build = tokio::spawn({
+        let ctx = peer1.ctx.clone();
+        async move { ctx.build_tunnel(peer2.peer).await }
+    });
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut events = peer1.ctx.events();
+    time::timeout(ERROR_TIMEOUT, peer1.ctx.shutdown())
+        .await
+        .unwrap();
+    let error = time::timeout(ERROR_TIMEOUT, build)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(error.downcast_ref::<ShuttingDown>(), Some(&ShuttingDown));
+    assert_eq!(events.next().await, None);
+}
+
+#[cfg(feature = "research")]
+#[tokio::test]
+async fn test_cell_inspector() {
+    use allium::{CellDirection, CellKind, CellMeta};
+
+    let cells = Arc::new(Mutex::new(Vec::<CellMeta>::new()));
+    let (relay, hostkey) = new_unique_peer();
+    let (_relay_ctx, _relay_incoming) = OnionBuilder::new(
+        relay.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_round_duration(ROUND_DURATION)
+    .set_cell_inspector(Arc::new({
+        let cells = cells.clone();
+        move |meta: &CellMeta| cells.lock().unwrap().push(*meta)
+    }))
+    .start()
+    .unwrap();
+
+    let peer1 = spawn_peer(vec![relay], false, 1).await;
+    let mut peer2 = spawn_simple_peer().await;
+    let ready = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    ready.write(TEST_DATA).unwrap();
+    time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap();
+    incoming.write(TEST_DATA).unwrap();
+    let mut ready = ready;
+    time::timeout(ERROR_TIMEOUT, ready.read())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let cells = cells.lock().unwrap();
+    // the extend request is addressed to the relay, data is only relayed
+    assert!(cells
+        .iter()
+        .any(|c| c.direction == CellDirection::Forward && c.cell_kind == CellKind::Control));
+    assert!(cells
+        .iter()
+        .any(|c| c.direction == CellDirection::Forward && c.cell_kind == CellKind::Relayed));
+    assert!(cells
+        .iter()
+        .any(|c| c.direction == CellDirection::Backward && c.cell_kind == CellKind::Relayed));
+    assert!(cells.iter().all(|c| c.cell_kind != CellKind::Data));
+    // the next path may already be built through the relay, but only the current one carries data
+    let mut relayed = cells.iter().filter(|c| c.cell_kind == CellKind::Relayed);
+    let token = relayed.next().unwrap().circuit_token;
+    assert!(relayed.all(|c| c.circuit_token == token));
+}
+
+#[tokio::test]
+async fn test_peer_provider_closed() {
+    let relay = spawn_simple_peer().await;
+    let mut dest = spawn_simple_peer().await;
+    let (peer, hostkey) = new_unique_peer();
+    let (peer_tx, peer_rx) = tokio::sync::mpsc::channel::<Peer>(1);
+    let peer_provider = PeerProvider::from_stream(stream::wrappers::ReceiverStream::new(peer_rx));
+    let (ctx, _incoming) = OnionBuilder::new(peer.address(), hostkey, peer_provider)
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(1)
+        .set_round_duration(ROUND_DURATION)
+        .start()
+        .unwrap();
+    let mut events = ctx.events();
+
+    // the build waits for a peer until the provider is dropped
+    let build = tokio::spawn({
+        let ctx = ctx.clone();
+        let dest = dest.peer.clone();
+        async move { ctx.build_tunnel(dest).await }
+    });
+    time::sleep(DELAY_TIMEOUT).await;
+    drop(peer_tx);
+    let error = time::timeout(ERROR_TIMEOUT, build)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ProviderClosed>(),
+        Some(&ProviderClosed)
+    );
+    assert_eq!(
+        time::timeout(ERROR_TIMEOUT, events.next()).await.unwrap(),
+        Some(Event::PeerProviderClosed)
+    );
+
+    // later builds fail immediately until the provider is replaced
+    let error = time::timeout(ERROR_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ProviderClosed>(),
+        Some(&ProviderClosed)
+    );
+
+    ctx.replace_peer_provider(PeerProvider::from_stream(stream::iter(iter::repeat(
+        relay.peer.clone(),
+    ))));
+    let ready = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(incoming.id(), ready.id());
+}
+
+#[tokio::test]
+async fn test_switchover_without_replacement() {
+    let relay = spawn_simple_peer().await;
+    let mut dest = spawn_simple_peer().await;
+    // only enough peers for the first path
+    let peer1 = spawn_peer(vec![relay.peer.clone()], false, 1).await;
+    let mut events = peer1.ctx.events();
+
+    let mut tunnel = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // the switchover keeps the current path
+    time::sleep(ROUND_TIMEOUT).await;
+    let stats = tunnel.stats();
+    assert_eq!(stats.rotations, 0);
+    assert!(stats.failed_rebuilds >= 1);
+    tunnel.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+    incoming.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, tunnel.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+    while let Ok(Some(evt)) = time::timeout(Duration::from_millis(100), events.next()).await {
+        assert!(!matches!(evt, Event::Closed { .. }), "{:?}", evt);
+    }
+}
+
+const BULK_DATA_SIZE: usize = 10 * 1024 * 1024;
+/// time in which a single write on a socket has to complete
+const WRITE_DEADLINE: Duration = Duration::from_secs(2);
+
+/// Reads from `tunnel` until it is closed and returns the number of bytes read.
+async fn read_until_closed(tunnel: &mut allium::Tunnel) -> usize {
+    let mut n = 0;
+    while let Ok(data) = tunnel.read().await {
+        n += data.len();
+    }
+    n
+}
+
+#[tokio::test]
+async fn test_end_overtakes_queued_data_source() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+    let ready = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    ready.write(vec![0u8; BULK_DATA_SIZE].into()).unwrap();
+    drop(ready);
+    // the destination waits a second for a replacement path before closing the tunnel
+    let received = time::timeout(
+        WRITE_DEADLINE + Duration::from_secs(1),
+        read_until_closed(&mut incoming),
+    )
+    .await
+    .unwrap();
+    assert!(received < BULK_DATA_SIZE);
+}
+
+#[tokio::test]
+async fn test_end_overtakes_queued_data_destination() {
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+    let mut ready = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer))
+        .await
+        .unwrap()
+        .unwrap();
+    let incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    incoming.write(vec![0u8; BULK_DATA_SIZE].into()).unwrap();
+    drop(incoming);
+    let received = time::timeout(WRITE_DEADLINE, read_until_closed(&mut ready))
+        .await
+        .unwrap();
+    assert!(received < BULK_DATA_SIZE);
+}
+
+#[tokio::test]
+async fn test_destination_ends_tunnel() {
+    let relay = spawn_simple_peer().await;
+    let peer1 = spawn_peer(vec![relay.peer.clone()], false, 1).await;
+    let mut peer2 = spawn_simple_peer().await;
+    let mut ready = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer))
+        .await
+        .unwrap()
+        .unwrap();
+    let incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // closing the incoming tunnel ends it at the initiator as well
+    let mut events = peer1.ctx.events();
+    drop(incoming);
+    assert_eq!(
+        time::timeout(ERROR_TIMEOUT, events.next()).await.unwrap(),
+        Some(Event::Closed {
+            tunnel_id: ready.id(),
+            reason: CloseReason::Ended,
+            end_reason: Some(EndReason::Normal),
+        })
+    );
+    ready.read().await.unwrap_err();
+    ready.write(TEST_DATA).unwrap_err();
+    assert!(peer1.ctx.path_info(ready.id()).is_none());
+}
+
+/// An application which only writes in response to what it reads must not stall the tunnel, even
+/// if the other side does not read for a while and nobody consumes the events.
+#[tokio::test]
+async fn test_callbacks_make_progress() {
+    const N: usize = 500;
+    let peer1 = spawn_simple_peer().await;
+    let mut peer2 = spawn_simple_peer().await;
+    let mut ready = time::timeout(ROUND_TIMEOUT, peer1.ctx.build_tunnel(peer2.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let _events1 = peer1.ctx.events();
+    let _events2 = peer2.ctx.events();
+    let echo = tokio::spawn(async move {
+        for _ in 0..N {
+            let data = incoming.read().await.unwrap();
+            incoming.write(data).unwrap();
+        }
+        incoming
+    });
+
+    // everything is written before the first echo is read, more than fits into the buffers
+    for i in 0..N as u32 {
+        ready
+            .write(Bytes::copy_from_slice(&i.to_be_bytes()))
+            .unwrap();
+    }
+    let mut echoed = 0;
+    while echoed < N * 4 {
+        let data = time::timeout(ERROR_TIMEOUT, ready.read())
+            .await
+            .unwrap()
+            .unwrap();
+        echoed += data.len();
+    }
+    let _incoming = time::timeout(ERROR_TIMEOUT, echo).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_start_reports_all_problems() {
+    let (peer, _) = new_unique_peer();
+    let _occupied = std::net::TcpListener::bind(peer.address()).unwrap();
+    let hostkey = RsaPrivateKey::from_pem_file("tests/smallkey.pem").unwrap();
+    let res = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .set_round_duration(Duration::ZERO)
+    .set_max_pending_handshakes(0)
+    .start();
+
+    let err = res.err().unwrap();
+    let problems = err.problems();
+    assert!(matches!(
+        problems[0],
+        StartProblem::HostKeySize { bits: 2048, .. }
+    ));
+    assert!(matches!(problems[1], StartProblem::AddressInUse { .. }));
+    assert!(matches!(
+        problems[2],
+        StartProblem::InvalidSetting {
+            setting: "round duration",
+            ..
+        }
+    ));
+    assert!(matches!(
+        problems[3],
+        StartProblem::InvalidSetting {
+            setting: "maximum number of pending handshakes",
+            ..
+        }
+    ));
+    assert_eq!(problems.len(), 4);
+}
+
+#[tokio::test]
+async fn test_endpoint_keeps_tunnel_across_rotations() {
+    const SHORT_ROUND: Duration = Duration::from_secs(2);
+    let (peer, hostkey) = new_unique_peer();
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(0)
+    .set_round_duration(SHORT_ROUND)
+    .set_min_tunnel_lifetime(Duration::ZERO)
+    .start()
+    .unwrap();
+    let mut peer2 = spawn_simple_peer().await;
+
+    let mut ready = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(peer2.peer))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, peer2.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    for rotation in 1..=3 {
+        time::timeout(2 * SHORT_ROUND, async {
+            while ready.stats().rotations < rotation {
+                time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // the data arrives at the same tunnel
+        ready.write(TEST_DATA).unwrap();
+        let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_data, TEST_DATA);
+        incoming.write(TEST_DATA).unwrap();
+        let read_data = time::timeout(ERROR_TIMEOUT, ready.read())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read_data, TEST_DATA);
+    }
+    assert!(incoming.stats().rotations >= 3);
+    assert!(
+        time::timeout(Duration::from_millis(100), peer2.incoming.next())
+            .await
+            .is_err()
+    );
+
+    // the replaced circuits are ended by the initiator right after the switchover
+    time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(peer2.ctx.relay_stats().unwrap().draining_circuits, 0);
+}
+
+async fn spawn_relay(max_pending_handshakes: usize) -> TestPeer {
+    let (peer, hostkey) = new_unique_peer();
+    let (ctx, incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_max_pending_handshakes(max_pending_handshakes)
+    .start()
+    .unwrap();
+    TestPeer {
+        peer,
+        ctx,
+        incoming,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_relays_share_connections() {
+    const N_TUNNELS: usize = 100;
+    const SLOW_ROUND: Duration = Duration::from_secs(10);
+    let relay1 = spawn_relay(4 * N_TUNNELS).await;
+    let relay2 = spawn_relay(4 * N_TUNNELS).await;
+    let mut dest = spawn_relay(4 * N_TUNNELS).await;
+    let (peer, hostkey) = new_unique_peer();
+    let relays = vec![relay1.peer.clone(), relay2.peer.clone()];
+    let peer_provider = PeerProvider::from_stream(stream::iter(relays.into_iter().cycle()));
+    // splicing does not build replacement paths right away
+    let (ctx, _incoming) = OnionBuilder::new(peer.address(), hostkey, peer_provider)
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(2)
+        .set_round_duration(SLOW_ROUND)
+        .set_rotation_strategy(RotationStrategy::Splice { keep_hops: 1 })
+        .start()
+        .unwrap();
+
+    let builds: Vec<_> = (0..N_TUNNELS)
+        .map(|_| {
+            let ctx = ctx.clone();
+            let dest = dest.peer.clone();
+            tokio::spawn(async move { ctx.build_tunnel(dest).await })
+        })
+        .collect();
+    let mut tunnels = vec![];
+    for build in builds {
+        let tunnel = time::timeout(3 * SLOW_ROUND, build)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        tunnels.push(tunnel);
+    }
+
+    // every relay keeps a single connection to each next hop
+    let relay_connections = relay1.ctx.relay_stats().unwrap().relay_connections
+        + relay2.ctx.relay_stats().unwrap().relay_connections;
+    assert!(
+        relay_connections > 0 && relay_connections <= 6,
+        "{} relay connections",
+        relay_connections
+    );
+
+    for (i, tunnel) in tunnels.iter().enumerate() {
+        tunnel
+            .write(Bytes::from((i as u32).to_be_bytes().to_vec()))
+            .unwrap();
+    }
+    for _ in 0..N_TUNNELS {
+        let mut incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+            .await
+            .unwrap()
+            .unwrap();
+        let i = u32::from_be_bytes([read_data[0], read_data[1], read_data[2], read_data[3]]);
+        assert_eq!(tunnels[i as usize].id(), incoming.id());
+    }
+}
+
+/// Builds two tunnels through a new relay and returns the connection ids of the circuits at the
+/// relay, after checking that both tunnels carry data. Replacement paths may have been built
+/// meanwhile.
+async fn build_through_relay(configure: impl FnOnce(OnionBuilder) -> OnionBuilder) -> Vec<u64> {
+    let relay = spawn_relay(16).await;
+    let mut dest = spawn_simple_peer().await;
+    let (peer, hostkey) = new_unique_peer();
+    let peer_provider = PeerProvider::from_stream(stream::iter(iter::repeat(relay.peer.clone())));
+    let builder = OnionBuilder::new(peer.address(), hostkey, peer_provider)
+        .enable_cover_traffic(false)
+        .set_hops_per_tunnel(1)
+        .set_round_duration(ROUND_DURATION);
+    let (ctx, _incoming) = configure(builder).start().unwrap();
+
+    let mut tunnels = vec![];
+    for _ in 0..2 {
+        let tunnel = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+        tunnel.write(TEST_DATA).unwrap();
+        let mut incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(incoming.read().await.unwrap(), TEST_DATA);
+        // the destination ends dropped tunnels
+        tunnels.push((tunnel, incoming));
+    }
+    let circuits = relay.ctx.relay_stats().unwrap().inbound_circuits;
+    circuits
+        .iter()
+        .map(|circuit| circuit.connection_id)
+        .collect()
+}
+
+#[tokio::test]
+async fn test_first_hops_share_connections() {
+    // the first circuits of both tunnels share one connection to the relay
+    let connections =
+        build_through_relay(|b| b.set_first_hop_connection_idle_timeout(Duration::from_secs(60)))
+            .await;
+    assert!(connections.len() >= 2);
+    assert!(connections.iter().all(|&id| id == connections[0]));
+
+    // by default, every tunnel connects on its own
+    let mut connections = build_through_relay(|b| b).await;
+    let n_circuits = connections.len();
+    connections.sort_unstable();
+    connections.dedup();
+    assert!(n_circuits >= 2);
+    assert_eq!(connections.len(), n_circuits);
+}
+
+#[tokio::test]
+async fn test_peer_provider_override() {
+    const SHORT_ROUND: Duration = Duration::from_secs(2);
+    let relay = spawn_simple_peer().await;
+    let mut dest = spawn_simple_peer().await;
+    let (peer, hostkey) = new_unique_peer();
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(1)
+    .set_round_duration(SHORT_ROUND)
+    .set_min_tunnel_lifetime(Duration::ZERO)
+    .start()
+    .unwrap();
+
+    // the provider of the onion router is closed
+    let error = time::timeout(ERROR_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ProviderClosed>(),
+        Some(&ProviderClosed)
+    );
+
+    let pool = PeerProvider::from_stream(stream::iter(iter::repeat(relay.peer.clone())));
+    let options = TunnelOptions::new().set_peer_provider(pool);
+    let mut ready = time::timeout(
+        ROUND_TIMEOUT,
+        ctx.build_tunnel_with_options(dest.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(incoming.id(), ready.id());
+
+    // rotations keep drawing from the override
+    time::timeout(3 * SHORT_ROUND, async {
+        while ready.stats().rotations < 2 {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(ready.stats().failed_rebuilds, 0);
+    ready.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+    incoming.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, ready.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+}
+
+#[test]
+fn test_start_without_runtime() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let peer_provider = runtime.block_on(async { PeerProvider::from_stream(stream::empty()) });
+    let (peer, hostkey) = new_unique_peer();
+    let res = OnionBuilder::new(peer.address(), hostkey, peer_provider).start();
+
+    let err = res.err().unwrap();
+    assert!(matches!(err.problems(), [StartProblem::NoRuntime]));
+}
+
+#[test]
+fn test_blocking_api_from_plain_thread() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (peer1, peer2) = runtime.block_on(async {
+        let peer1 = spawn_simple_peer().await;
+        let peer2 = spawn_simple_peer().await;
+        (peer1, peer2)
+    });
+
+    // neither the thread nor the test itself is running on a runtime
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let TestPeer { ctx, .. } = peer1;
+        let TestPeer {
+            peer, mut incoming, ..
+        } = peer2;
+        let mut ready = ctx.build_tunnel_blocking(peer.clone()).unwrap();
+        let mut incoming = incoming.next_blocking().unwrap();
+        assert_eq!(incoming.id(), ready.id());
+
+        let writer = ready.writer();
+        std::thread::spawn(move || writer.write(TEST_DATA).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(incoming.read_blocking().unwrap(), TEST_DATA);
+        incoming.write(TEST_DATA).unwrap();
+        assert_eq!(ready.read_blocking().unwrap(), TEST_DATA);
+
+        let _events = ctx.events();
+        assert_eq!(ctx.relay_stats().unwrap().pending_handshakes, 0);
+        ctx.shutdown_blocking();
+        let err = ctx.build_tunnel_blocking(peer).unwrap_err();
+        assert!(err.is::<ShuttingDown>());
+        done_tx.send(()).unwrap();
+    });
+    done_rx.recv_timeout(2 * ROUND_TIMEOUT).unwrap();
+}
+
+#[tokio::test]
+async fn test_padding() {
+    const PADDING_INTERVAL: Duration = Duration::from_millis(50);
+    let (peer, hostkey) = new_unique_peer();
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(0)
+    .set_round_duration(ROUND_DURATION)
+    .set_padding_interval(PADDING_INTERVAL)
+    .start()
+    .unwrap();
+    let mut padded = spawn_simple_peer().await;
+    let mut unpadded = spawn_simple_peer().await;
+
+    // padding is only sent to peers known to drop it, built first since all
+    // test peers share a host key and thereby their cached capabilities
+    let options = TunnelOptions::new().set_padding_interval(PADDING_INTERVAL);
+    let unpadded_ready = time::timeout(
+        ROUND_TIMEOUT,
+        ctx.build_tunnel_with_options(unpadded.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let _unpadded_incoming = time::timeout(ERROR_TIMEOUT, unpadded.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    let padded_peer = padded.peer.clone().with_capabilities(Capabilities::PADDING);
+    let ready = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(padded_peer))
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, padded.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    time::sleep(10 * PADDING_INTERVAL).await;
+    let stats = ready.stats();
+    assert!(stats.padding_bytes > 0);
+    assert_eq!(stats.sent_bytes, 0);
+    assert_eq!(unpadded_ready.stats().padding_bytes, 0);
+
+    // the destination drops the padding, only data is delivered
+    ready.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+    assert_eq!(ready.stats().sent_bytes, TEST_DATA.len() as u64);
+    assert!(time::timeout(2 * PADDING_INTERVAL, incoming.read())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_idle_timeout() {
+    const IDLE_TIMEOUT: Duration = Duration::from_millis(300);
+    let (peer, hostkey) = new_unique_peer();
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(0)
+    .set_round_duration(ROUND_DURATION)
+    .set_tunnel_idle_timeout(IDLE_TIMEOUT)
+    .start()
+    .unwrap();
+    let mut dest = spawn_simple_peer().await;
+
+    let options = TunnelOptions::new().set_idle_timeout(Duration::ZERO);
+    let kept = time::timeout(
+        ROUND_TIMEOUT,
+        ctx.build_tunnel_with_options(dest.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let mut ready = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let _kept_incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut events = ctx.events();
+
+    // data in either direction keeps the tunnel open
+    for i in 0..6 {
+        time::sleep(IDLE_TIMEOUT / 3).await;
+        if i % 2 == 0 {
+            ready.write(TEST_DATA).unwrap();
+            time::timeout(ERROR_TIMEOUT, incoming.read())
+                .await
+                .unwrap()
+                .unwrap();
+        } else {
+            incoming.write(TEST_DATA).unwrap();
+            time::timeout(ERROR_TIMEOUT, ready.read())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+    assert!(ctx.path_info(ready.id()).is_some());
+
+    assert_eq!(
+        time::timeout(ERROR_TIMEOUT, events.next()).await.unwrap(),
+        Some(Event::Closed {
+            tunnel_id: ready.id(),
+            reason: CloseReason::IdleTimeout,
+            end_reason: None,
+        })
+    );
+    ready.read().await.unwrap_err();
+    ready.write(TEST_DATA).unwrap_err();
+    // the destination sees the tunnel ended
+    time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap_err();
+
+    // the tunnel without an idle timeout stays open
+    assert!(time::timeout(2 * IDLE_TIMEOUT, events.next())
+        .await
+        .is_err());
+    kept.write(TEST_DATA).unwrap();
+}
+
+#[tokio::test]
+async fn test_restore_state() {
+    let (dead_dest, _) = new_unique_peer();
+    let (peer, hostkey) = new_unique_peer();
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(0)
+    .start()
+    .unwrap();
+    time::timeout(ERROR_TIMEOUT, ctx.build_tunnel(dead_dest.clone()))
+        .await
+        .unwrap()
+        .unwrap_err();
+    let state = ctx.export_state();
+    assert_eq!(state.destination_backoffs.len(), 1);
+    assert_eq!(
+        state.destination_backoffs[0].fingerprint,
+        dead_dest.fingerprint()
+    );
+    ctx.shutdown().await;
+
+    // the restarted onion router still backs off from the destination
+    let (peer, hostkey) = new_unique_peer();
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .import_state(state.clone())
+    .start()
+    .unwrap();
+    let restored = ctx.export_state();
+    assert_eq!(restored.destination_backoffs.len(), 1);
+    let (before, after) = (
+        &state.destination_backoffs[0],
+        &restored.destination_backoffs[0],
+    );
+    assert_eq!(after.fingerprint, before.fingerprint);
+    assert_eq!(after.failures, 1);
+    // converting between monotonic and wall-clock time is not exact
+    let drift = match after.retry_at.duration_since(before.retry_at) {
+        Ok(drift) => drift,
+        Err(e) => e.duration(),
+    };
+    assert!(drift < Duration::from_millis(10));
+
+    let mut newer = state;
+    newer.version = NodeState::VERSION + 1;
+    let (peer, hostkey) = new_unique_peer();
+    let error = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .import_state(newer)
+    .start()
+    .err()
+    .unwrap();
+    assert!(matches!(
+        error.problems(),
+        [StartProblem::InvalidSetting {
+            setting: "node state",
+            ..
+        }]
+    ));
+}
+
+#[tokio::test]
+async fn test_entry_guards() {
+    const SHORT_ROUND: Duration = Duration::from_secs(2);
+    let first = spawn_simple_peer().await;
+    let second = spawn_simple_peer().await;
+    let mut dest = spawn_simple_peer().await;
+    let (peer, hostkey) = new_unique_peer();
+    let pool = vec![first.peer.clone(), second.peer.clone()];
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::iter(pool.into_iter().cycle())),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(2)
+    .set_round_duration(SHORT_ROUND)
+    .set_min_tunnel_lifetime(Duration::ZERO)
+    .set_entry_guards(1)
+    .start()
+    .unwrap();
+    let mut events = ctx.events();
+
+    let ready = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let _incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    let guards = ctx.entry_guards();
+    assert_eq!(guards.len(), 1);
+    let guard = guards[0].address();
+    assert_eq!(ctx.path_info(ready.id()).unwrap()[0].addr, guard);
+    let event = time::timeout(ERROR_TIMEOUT, events.next()).await.unwrap();
+    assert!(matches!(event, Some(Event::GuardsChanged { guards }) if guards.len() == 1));
+
+    // the replacement paths start at the same guard
+    time::timeout(3 * SHORT_ROUND, async {
+        while ready.stats().rotations < 2 {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(ctx.path_info(ready.id()).unwrap()[0].addr, guard);
+    assert_eq!(ctx.entry_guards().len(), 1);
+
+    // imported guards are used right away
+    let (peer, hostkey) = new_unique_peer();
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::iter(iter::repeat(first.peer.clone()))),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(2)
+    .set_entry_guards(1)
+    .import_entry_guards(vec![second.peer.clone(), first.peer.clone()])
+    .start()
+    .unwrap();
+    let restored: Vec<_> = ctx.entry_guards().iter().map(Peer::address).collect();
+    assert_eq!(restored, vec![second.peer.address()]);
+    let ready = time::timeout(ROUND_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    let path = ctx.path_info(ready.id()).unwrap();
+    assert_eq!(path[0].addr, second.peer.address());
+}
+
+#[tokio::test]
+async fn test_explicit_path() {
+    const SHORT_ROUND: Duration = Duration::from_secs(2);
+    let first = spawn_simple_peer().await;
+    let second = spawn_simple_peer().await;
+    let other = spawn_simple_peer().await;
+    let mut dest = spawn_simple_peer().await;
+    let (peer, hostkey) = new_unique_peer();
+    // the configured hops would be drawn from `other`
+    let pool = iter::repeat(other.peer.clone());
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::iter(pool)),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(1)
+    .set_round_duration(SHORT_ROUND)
+    .set_min_tunnel_lifetime(Duration::ZERO)
+    .start()
+    .unwrap();
+
+    let path = vec![first.peer.clone(), second.peer.clone()];
+    let ready = time::timeout(
+        ROUND_TIMEOUT,
+        ctx.build_tunnel_with_path(dest.peer.clone(), path, TunnelOptions::new()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let _incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    let expected = vec![
+        first.peer.address(),
+        second.peer.address(),
+        dest.peer.address(),
+    ];
+    let addrs = |ctx: &OnionContext| -> Vec<_> {
+        let path = ctx.path_info(ready.id()).unwrap();
+        path.iter().map(|hop| hop.addr).collect()
+    };
+    assert_eq!(addrs(&ctx), expected);
+
+    // the replacement paths are the same
+    time::timeout(3 * SHORT_ROUND, async {
+        while ready.stats().rotations < 2 {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(ready.stats().failed_rebuilds, 0);
+    assert_eq!(addrs(&ctx), expected);
+}
+
+#[tokio::test]
+async fn test_excluded_peers() {
+    const SHORT_ROUND: Duration = Duration::from_secs(2);
+    let excluded = spawn_simple_peer().await;
+    let allowed = spawn_simple_peer().await;
+    let mut dest = spawn_simple_peer().await;
+    let (peer, hostkey) = new_unique_peer();
+    let pool = iter::repeat(vec![excluded.peer.clone(), allowed.peer.clone()]).flatten();
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::iter(pool)),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(1)
+    .set_round_duration(SHORT_ROUND)
+    .set_min_tunnel_lifetime(Duration::ZERO)
+    .start()
+    .unwrap();
+
+    let options = TunnelOptions::new().exclude_address(excluded.peer.address());
+    let ready = time::timeout(
+        ROUND_TIMEOUT,
+        ctx.build_tunnel_with_options(dest.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let _incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    let path = ctx.path_info(ready.id()).unwrap();
+    assert_eq!(path[0].addr, allowed.peer.address());
+
+    // the replacement paths avoid the excluded peer as well
+    time::timeout(3 * SHORT_ROUND, async {
+        while ready.stats().rotations < 2 {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(ready.stats().failed_rebuilds, 0);
+    let path = ctx.path_info(ready.id()).unwrap();
+    assert_eq!(path[0].addr, allowed.peer.address());
+
+    // the test peers share a host key, so excluding its fingerprint leaves no peer
+    let options = TunnelOptions::new().exclude_fingerprint(allowed.peer.fingerprint());
+    let error = time::timeout(
+        ERROR_TIMEOUT,
+        ctx.build_tunnel_with_options(dest.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<NoAcceptablePeers>(),
+        Some(&NoAcceptablePeers {
+            position: 0,
+            rejected: 10
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_hop_filter() {
+    const SHORT_ROUND: Duration = Duration::from_secs(2);
+    let banned = spawn_simple_peer().await;
+    let allowed = spawn_simple_peer().await;
+    let mut dest = spawn_simple_peer().await;
+    let (peer, hostkey) = new_unique_peer();
+    let pool = iter::repeat(vec![banned.peer.clone(), allowed.peer.clone()]).flatten();
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::iter(pool)),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(1)
+    .set_round_duration(SHORT_ROUND)
+    .set_min_tunnel_lifetime(Duration::ZERO)
+    .start()
+    .unwrap();
+
+    let banned_addr = banned.peer.address();
+    let options = TunnelOptions::new()
+        .set_hop_filter(Arc::new(move |peer: &Peer| peer.address() != banned_addr));
+    let ready = time::timeout(
+        ROUND_TIMEOUT,
+        ctx.build_tunnel_with_options(dest.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let _incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // rotations honor the filter as well
+    time::timeout(3 * SHORT_ROUND, async {
+        while ready.stats().rotations < 2 {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let stats = ready.stats();
+    assert_eq!(stats.failed_rebuilds, 0);
+    // every build drew the banned relay once
+    assert!(stats.rejected_hops >= 3);
+    let path = ctx.path_info(ready.id()).unwrap();
+    assert_eq!(path[0].addr, allowed.peer.address());
+
+    // rejections are not reported as a generic build failure
+    let options = TunnelOptions::new().set_hop_filter(Arc::new(|_: &Peer| false));
+    let error = time::timeout(
+        ERROR_TIMEOUT,
+        ctx.build_tunnel_with_options(dest.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<NoAcceptablePeers>(),
+        Some(&NoAcceptablePeers {
+            position: 0,
+            rejected: 10
+        })
+    );
+}
+
+fn assert_strict_violation(error: anyhow::Error, which: Fallback) {
+    assert_eq!(
+        error.downcast_ref::<StrictViolation>(),
+        Some(&StrictViolation { which })
+    );
+}
+
+#[tokio::test]
+async fn test_coalesced_builds() {
+    let peer = spawn_simple_peer().await;
+    let mut dest = spawn_simple_peer().await;
+
+    // the second build shares the tunnel of the first one
+    let (first, second) = time::timeout(ROUND_TIMEOUT, async {
+        tokio::join!(
+            peer.ctx
+                .build_tunnel_coalesced(dest.peer.clone(), TunnelOptions::new()),
+            peer.ctx
+                .build_tunnel_coalesced(dest.peer.clone(), TunnelOptions::new()),
+        )
+    })
+    .await
+    .unwrap();
+    let tunnel = match first.unwrap() {
+        CoalescedTunnel::Built(tunnel) => tunnel,
+        other => panic!(
+            "expected a tunnel built for the first call, got {:?}",
+            other
+        ),
+    };
+    let writer = match second.unwrap() {
+        CoalescedTunnel::Shared(writer) => writer,
+        other => panic!("expected a shared tunnel, got {:?}", other),
+    };
+    assert_eq!(writer.id(), tunnel.id());
+    let mut incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+    writer.write(TEST_DATA).unwrap();
+    let read_data = time::timeout(ERROR_TIMEOUT, incoming.read())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_data, TEST_DATA);
+
+    // builds with different options are not coalesced
+    let options = TunnelOptions::new().set_cell_size(CellSize::Large);
+    let (first, second) = time::timeout(ROUND_TIMEOUT, async {
+        tokio::join!(
+            peer.ctx
+                .build_tunnel_coalesced(dest.peer.clone(), TunnelOptions::new()),
+            peer.ctx.build_tunnel_coalesced(dest.peer.clone(), options),
+        )
+    })
+    .await
+    .unwrap();
+    assert!(matches!(first.unwrap(), CoalescedTunnel::Built(_)));
+    assert!(matches!(second.unwrap(), CoalescedTunnel::Built(_)));
+
+    // a build which finished is not shared
+    let later = time::timeout(
+        ROUND_TIMEOUT,
+        peer.ctx
+            .build_tunnel_coalesced(dest.peer.clone(), TunnelOptions::new()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(matches!(later, CoalescedTunnel::Built(ref t) if t.id() != tunnel.id()));
+}
+
+#[tokio::test]
+async fn test_strict_mode() {
+    let relays = spawn_many_peers(2).await;
+    let mut dest = spawn_simple_peer().await;
+    let (peer, hostkey) = new_unique_peer();
+    let (ctx, _incoming) = OnionBuilder::new(
+        peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::iter(iter::repeat(relays[0].clone()))),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(2)
+    .set_round_duration(ROUND_DURATION)
+    .enable_strict_mode(true)
+    .start()
+    .unwrap();
+
+    // two distinct peers are not enough for a path of three
+    let error = time::timeout(ERROR_TIMEOUT, ctx.build_tunnel(dest.peer.clone()))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert_strict_violation(error, Fallback::HopReuse);
+
+    // the destination is not known to drop padding
+    let pool = iter::repeat(relays.clone()).flatten();
+    let options = TunnelOptions::new()
+        .set_peer_provider(PeerProvider::from_stream(stream::iter(pool)))
+        .set_padding_interval(Duration::from_millis(50));
+    let error = time::timeout(
+        ERROR_TIMEOUT,
+        ctx.build_tunnel_with_options(dest.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap_err();
+    assert_strict_violation(error, Fallback::NoPadding);
+
+    // three distinct peers are
+    let pool = iter::repeat(relays.clone()).flatten();
+    let options =
+        TunnelOptions::new().set_peer_provider(PeerProvider::from_stream(stream::iter(pool)));
+    let _ready = time::timeout(
+        ROUND_TIMEOUT,
+        ctx.build_tunnel_with_options(dest.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    let _incoming = time::timeout(ERROR_TIMEOUT, dest.incoming.next())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // the tunnel may opt out of the strict mode of the onion router, reusing the destination as
+    // its first hop
+    ctx.add_known_peer(dest.peer.clone());
+    let options = TunnelOptions::new()
+        .set_strict(false)
+        .set_first_hop(dest.peer.fingerprint());
+    let _ready = time::timeout(
+        ROUND_TIMEOUT,
+        ctx.build_tunnel_with_options(dest.peer.clone(), options),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_relay_terminated_tunnel() {
+    let mut relay = spawn_simple_peer().await;
+    let (refusing_peer, hostkey) = new_unique_peer();
+    let (_refusing, _refusing_incoming) = OnionBuilder::new(
+        refusing_peer.address(),
+        hostkey,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .enable_relay_termination(false)
+    .start()
+    .unwrap();
+    let peer = spawn_peer(vec![relay.peer.clone()], false, 1).await;
+
+    let tunnel = time::timeout(ROUND_TIMEOUT, peer.ctx.build_tunnel(None))
+        .await
+        .unwrap()
+        .unwrap();
+    let hops = peer.ctx.path_info(tunnel.id()).unwrap();
+    assert_eq!(hops.len(), 1);
+    assert_eq!(hops[0].addr, relay.peer.address());
+
+    // the relay does not pass the tunnel on to its application, data is discarded
+    tunnel.write(TEST_DATA).unwrap();
+    assert!(time::timeout(DELAY_TIMEOUT, relay.incoming.next())
+        .await
+        .is_err());
+    assert!(peer.ctx.path_info(tunnel.id()).is_some());
+
+    // a relay may refuse the role, which breaks the tunnel after its path was built
+    let peer = spawn_peer(vec![refusing_peer], false, 1).await;
+    let error = time::timeout(ROUND_TIMEOUT, peer.ctx.build_tunnel(None))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(error.downcast_ref::<TunnelBroken>(), Some(&TunnelBroken));
+    // a direct tunnel has no relay to terminate at
+    spawn_simple_peer()
+        .await
+        .ctx
+        .build_tunnel(None)
+        .await
         .unwrap_err();
 }
@@ -0,0 +1,430 @@
+//! Snapshot of the public API of the crate.
+//!
+//! Every item is named by its module path and by its re-export at the root of the crate, and the
+//! signatures of the synchronous methods are pinned by coercing them to function pointers. A change
+//! breaking downstream code therefore fails to compile here. If a change is intended, update the
+//! snapshot in the same commit.
+
+use allium::{config, error, policy, stats};
+use allium::{
+    Capabilities, CoalescedTunnel, Ed25519PrivateKey, Ed25519PublicKey, EndReason, Event,
+    Fingerprint, HostKey, HostPublicKey, MessageHandle, MuxStream, OnionBuilder, OnionContext,
+    OnionEvents, OnionIncoming, OnionStream, Peer, PeerProvider, RsaPrivateKey, RsaPublicKey,
+    StreamMux, Tunnel, TunnelId, TunnelWriter, WriteFeedback, WrittenMessage,
+};
+use bytes::Bytes;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+type HopFilter = Arc<dyn Fn(&Peer) -> bool + Send + Sync>;
+
+/// Only compiles if both arguments name the same type.
+fn same<T>(_: PhantomData<T>, _: PhantomData<T>) {}
+
+macro_rules! reexported {
+    ($($module:ident :: $name:ident),* $(,)?) => {
+        $(same(PhantomData::<$module::$name>, PhantomData::<allium::$name>);)*
+    };
+}
+
+#[test]
+fn test_facade_reexports() {
+    reexported!(
+        config::BuildTimeouts,
+        config::CellSize,
+        config::CipherSuite,
+        config::RotationStrategy,
+        config::SocketTimeouts,
+        config::TunnelOptions,
+        policy::AddressRange,
+        policy::ExtendPolicy,
+        stats::BuildAttempt,
+        stats::BuildInfo,
+        stats::BuildOutcome,
+        stats::BuildReport,
+        stats::CircuitParams,
+        stats::ConnectionQueueInfo,
+        stats::HandshakeVersion,
+        stats::HopInfo,
+        stats::InboundCircuitInfo,
+        stats::IncomingTunnelInfo,
+        stats::LatencyHistogram,
+        stats::OutgoingTunnelInfo,
+        stats::PeerHandshakes,
+        stats::RelayStats,
+        stats::RetryBackoff,
+        stats::ShutdownReport,
+        stats::Transport,
+        stats::TunnelStats,
+        error::BuildTimedOut,
+        error::DestinationUnreachable,
+        error::Fallback,
+        error::HopSelectionError,
+        error::InvalidAddressRange,
+        error::NoAcceptablePeers,
+        error::NotARelay,
+        error::PathUnreachable,
+        error::ProviderClosed,
+        error::ShuttingDown,
+        error::StartError,
+        error::StartProblem,
+        error::StrictViolation,
+        error::TunnelBroken,
+    );
+}
+
+#[test]
+fn test_method_signatures() {
+    let _: fn(SocketAddr, RsaPublicKey) -> Peer = Peer::new;
+    let _: fn(SocketAddr, Ed25519PublicKey) -> Peer = Peer::new;
+    let _: fn(SocketAddr, HostPublicKey) -> Peer = Peer::new;
+    let _: fn(Peer, Capabilities) -> Peer = Peer::with_capabilities;
+    let _: fn(&Peer) -> SocketAddr = Peer::address;
+    let _: fn(&Peer) -> Fingerprint = Peer::fingerprint;
+    let _: fn(&Peer) -> Option<Capabilities> = Peer::capabilities;
+    let _: fn(u32) -> Capabilities = Capabilities::from_bits;
+    let _: fn(Capabilities) -> u32 = Capabilities::bits;
+    let _: fn(Capabilities, Capabilities) -> bool = Capabilities::contains;
+    let _: [Capabilities; 2] = [Capabilities::PADDING, Capabilities::STREAMS];
+    let _: fn(&RsaPrivateKey) -> RsaPublicKey = RsaPrivateKey::public_key;
+    let _: fn(&Ed25519PrivateKey) -> Ed25519PublicKey = Ed25519PrivateKey::public_key;
+    let _: fn(&HostKey) -> HostPublicKey = HostKey::public_key;
+    let _: fn(&'static str) -> allium::Result<HostKey> = HostKey::from_pem_file;
+    let _: fn() -> stats::BuildInfo = allium::build_info;
+
+    let _: fn(&mut Tunnel) -> allium::Result<Bytes> = Tunnel::read_blocking;
+    let _: fn(&Tunnel, Bytes) -> allium::Result<()> = Tunnel::write;
+    let _: fn(&Tunnel) -> TunnelId = Tunnel::id;
+    let _: fn(&Tunnel) -> config::CellSize = Tunnel::cell_size;
+    let _: fn(&Tunnel) -> Option<&stats::IncomingTunnelInfo> = Tunnel::incoming_info;
+    let _: fn(&Tunnel) -> stats::TunnelStats = Tunnel::stats;
+    let _: fn(&Tunnel) -> Option<stats::BuildReport> = Tunnel::last_build_report;
+    let _: fn(&Tunnel) -> TunnelWriter = Tunnel::writer;
+    let _: fn(&Tunnel, Bytes) -> allium::Result<MessageHandle> = Tunnel::write_tracked;
+    let _: fn(&Tunnel) -> Option<WriteFeedback> = Tunnel::write_feedback;
+    let _: fn(&TunnelWriter, Bytes) -> allium::Result<()> = TunnelWriter::write;
+    let _: fn(&TunnelWriter, Bytes) -> allium::Result<MessageHandle> = TunnelWriter::write_tracked;
+    let _: fn(&mut WriteFeedback) -> Option<WrittenMessage> = WriteFeedback::try_next;
+    let _: fn(MessageHandle) -> u64 = MessageHandle::sequence;
+    let _: fn(&TunnelWriter) -> TunnelId = TunnelWriter::id;
+    let _: fn(&mut OnionIncoming) -> Option<Tunnel> = OnionIncoming::next_blocking;
+    let _: fn(Tunnel) -> OnionStream = OnionStream::new;
+    let _: fn(&OnionStream) -> &Tunnel = OnionStream::get_ref;
+    let _: fn(OnionStream) -> Tunnel = OnionStream::into_inner;
+    let _: fn(Tunnel) -> StreamMux = StreamMux::new;
+    let _: fn(&StreamMux) -> TunnelId = StreamMux::tunnel_id;
+    let _: fn(&MuxStream) -> u16 = MuxStream::id;
+    let _: fn(&MuxStream, Bytes) -> allium::Result<()> = MuxStream::write;
+
+    let _: fn(&OnionContext) -> OnionEvents = OnionContext::events;
+    let _: fn(&OnionContext, Peer) = OnionContext::add_known_peer;
+    let _: fn(&OnionContext, PeerProvider) = OnionContext::replace_peer_provider;
+    let _: fn(&OnionContext) -> Option<SocketAddr> = OnionContext::local_addr;
+    let _: fn(&OnionContext) -> config::BuildTimeouts = OnionContext::build_timeouts;
+    let _: fn(&OnionContext) -> Result<stats::RelayStats, error::NotARelay> =
+        OnionContext::relay_stats;
+    let _: fn(&OnionContext) -> Result<(), error::NotARelay> =
+        OnionContext::reset_relay_measurements;
+    let _: fn(&OnionContext) -> Vec<stats::PeerHandshakes> = OnionContext::outgoing_handshakes;
+    let _: fn(&OnionContext) -> allium::NodeState = OnionContext::export_state;
+    let _: fn(&OnionContext) -> Vec<Peer> = OnionContext::entry_guards;
+    let _: fn(&OnionContext, TunnelId) -> Option<stats::IncomingTunnelInfo> =
+        OnionContext::tunnel_info;
+    let _: fn(&OnionContext, TunnelId) -> Option<Vec<stats::HopInfo>> = OnionContext::path_info;
+    let _: fn(&OnionContext, TunnelId) -> Option<stats::OutgoingTunnelInfo> =
+        OnionContext::outgoing_tunnel_info;
+    let _: fn(&OnionContext) -> Vec<stats::OutgoingTunnelInfo> = OnionContext::list_tunnels;
+    let _: fn(&OnionContext, TunnelId) -> Option<stats::RetryBackoff> = OnionContext::retry_backoff;
+    let _: fn(&OnionContext, Option<Peer>) -> allium::Result<Tunnel> =
+        |ctx, dest| ctx.build_tunnel_blocking(dest);
+    let _: fn(&OnionContext, Option<Peer>, config::TunnelOptions) -> allium::Result<Tunnel> =
+        |ctx, dest, options| ctx.build_tunnel_with_options_blocking(dest, options);
+    let _: fn(
+        &OnionContext,
+        Option<Peer>,
+        Vec<Peer>,
+        config::TunnelOptions,
+    ) -> allium::Result<Tunnel> =
+        |ctx, dest, path, options| ctx.build_tunnel_with_path_blocking(dest, path, options);
+    let _: fn(&OnionContext, Peer, config::TunnelOptions) -> allium::Result<CoalescedTunnel> =
+        OnionContext::build_tunnel_coalesced_blocking;
+    let _: fn(&CoalescedTunnel) -> TunnelId = CoalescedTunnel::id;
+    let _: fn(CoalescedTunnel) -> Option<Tunnel> = |c| match c {
+        CoalescedTunnel::Built(tunnel) => Some(tunnel),
+        CoalescedTunnel::Shared(_) => None,
+        _ => None,
+    };
+    let _: fn(&OnionContext, u16) -> allium::Result<()> = OnionContext::send_cover;
+    let _: fn(&OnionContext) -> stats::ShutdownReport = OnionContext::shutdown_blocking;
+
+    let _: fn(SocketAddr, RsaPrivateKey, PeerProvider) -> OnionBuilder = OnionBuilder::new;
+    let _: fn(SocketAddr, HostKey, PeerProvider) -> OnionBuilder = OnionBuilder::new;
+    let _: fn(PeerProvider) -> OnionBuilder = OnionBuilder::client_only;
+    let _: fn(OnionBuilder, bool) -> OnionBuilder = OnionBuilder::enable_cover_traffic;
+    let _: fn(OnionBuilder, bool) -> OnionBuilder = OnionBuilder::enable_build_reports;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder = OnionBuilder::set_padding_interval;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder = OnionBuilder::set_tunnel_idle_timeout;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder = OnionBuilder::set_diagnosis_budget;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder = OnionBuilder::set_keep_alive_interval;
+    let _: fn(OnionBuilder, bool) -> OnionBuilder = OnionBuilder::enable_strict_mode;
+    let _: fn(OnionBuilder, bool) -> OnionBuilder = OnionBuilder::enable_relay_termination;
+    let _: fn(OnionBuilder, bool) -> OnionBuilder = OnionBuilder::enable_latency_histogram;
+    let _: fn(OnionBuilder, policy::ExtendPolicy) -> OnionBuilder = OnionBuilder::set_extend_policy;
+    let _: fn(OnionBuilder, usize) -> OnionBuilder = OnionBuilder::set_hops_per_tunnel;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder = OnionBuilder::set_round_duration;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder = OnionBuilder::set_min_tunnel_lifetime;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder = OnionBuilder::set_rekey_interval;
+    let _: fn(OnionBuilder, config::RotationStrategy) -> OnionBuilder =
+        OnionBuilder::set_rotation_strategy;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder = OnionBuilder::set_stall_threshold;
+    let _: fn(OnionBuilder, u64, u64) -> OnionBuilder = OnionBuilder::set_key_usage_limits;
+    let _: fn(OnionBuilder, config::BuildTimeouts) -> OnionBuilder =
+        OnionBuilder::set_build_timeouts;
+    let _: fn(OnionBuilder, config::SocketTimeouts) -> OnionBuilder =
+        OnionBuilder::set_socket_timeouts;
+    let _: fn(OnionBuilder, usize) -> OnionBuilder = OnionBuilder::set_max_batch_size;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder = OnionBuilder::set_shutdown_timeout;
+    let _: fn(OnionBuilder, usize) -> OnionBuilder = OnionBuilder::set_max_pending_handshakes;
+    let _: fn(OnionBuilder, usize) -> OnionBuilder = OnionBuilder::set_max_handshakes_per_peer;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder =
+        OnionBuilder::set_relay_connection_idle_timeout;
+    let _: fn(OnionBuilder, Duration) -> OnionBuilder =
+        OnionBuilder::set_first_hop_connection_idle_timeout;
+    let _: fn(OnionBuilder, &[config::CipherSuite]) -> OnionBuilder =
+        OnionBuilder::set_cipher_suites;
+    let _: fn(OnionBuilder, Arc<dyn allium::StateObserver>) -> OnionBuilder =
+        OnionBuilder::set_state_observer;
+    let _: fn(OnionBuilder, allium::NodeState) -> OnionBuilder = OnionBuilder::import_state;
+    let _: fn(OnionBuilder, usize) -> OnionBuilder = OnionBuilder::set_entry_guards;
+    let _: fn(OnionBuilder, Vec<Peer>) -> OnionBuilder = OnionBuilder::import_entry_guards;
+    let _ = OnionBuilder::set_rng_factory::<fn() -> Box<dyn allium::OnionRng>>;
+    let _: fn(u64) -> allium::SeededRandom = allium::SeededRandom::new;
+    let _: Box<dyn allium::OnionRng> = Box::new(allium::SystemRandom);
+    let _: fn(OnionBuilder) -> Result<(OnionContext, OnionIncoming), error::StartError> =
+        OnionBuilder::start;
+
+    let _: fn() -> config::TunnelOptions = config::TunnelOptions::new;
+    let _: fn(config::TunnelOptions, Capabilities) -> config::TunnelOptions =
+        config::TunnelOptions::require_capabilities;
+    let _: fn(config::TunnelOptions, Fingerprint) -> config::TunnelOptions =
+        config::TunnelOptions::set_first_hop;
+    let _: fn(config::TunnelOptions, usize, Fingerprint) -> config::TunnelOptions =
+        config::TunnelOptions::set_hop;
+    let _: fn(config::TunnelOptions, config::CellSize) -> config::TunnelOptions =
+        config::TunnelOptions::set_cell_size;
+    let _: fn(config::TunnelOptions, &[config::CipherSuite]) -> config::TunnelOptions =
+        config::TunnelOptions::set_cipher_suites;
+    let _: fn(config::TunnelOptions, PeerProvider) -> config::TunnelOptions =
+        config::TunnelOptions::set_peer_provider;
+    let _: fn(config::TunnelOptions, Duration) -> config::TunnelOptions =
+        config::TunnelOptions::set_padding_interval;
+    let _: fn(config::TunnelOptions, Duration) -> config::TunnelOptions =
+        config::TunnelOptions::set_idle_timeout;
+    let _: fn(config::TunnelOptions, bool) -> config::TunnelOptions =
+        config::TunnelOptions::set_strict;
+    let _: fn(config::TunnelOptions, HopFilter) -> config::TunnelOptions =
+        config::TunnelOptions::set_hop_filter;
+    let _: fn(config::TunnelOptions, SocketAddr) -> config::TunnelOptions =
+        config::TunnelOptions::exclude_address;
+    let _: fn(config::TunnelOptions, Fingerprint) -> config::TunnelOptions =
+        config::TunnelOptions::exclude_fingerprint;
+    let _: fn(config::TunnelOptions, bool) -> config::TunnelOptions =
+        config::TunnelOptions::enable_write_feedback;
+
+    let _: fn(config::CellSize) -> usize = config::CellSize::bytes;
+    let _: fn(config::CipherSuite) -> usize = config::CipherSuite::tag_len;
+    let _: fn(&stats::BuildReport) -> &[stats::BuildAttempt] = stats::BuildReport::attempts;
+    let _: fn(&stats::LatencyHistogram) -> u64 = stats::LatencyHistogram::count;
+    let _: fn(&stats::LatencyHistogram, f64) -> Option<Duration> =
+        stats::LatencyHistogram::percentile;
+    let _: fn(&stats::LatencyHistogram) -> Option<Duration> = stats::LatencyHistogram::p50;
+    let _: fn(&stats::LatencyHistogram) -> Option<Duration> = stats::LatencyHistogram::p95;
+    let _: fn(&stats::LatencyHistogram) -> Option<Duration> = stats::LatencyHistogram::p99;
+    let _: fn(&error::StartError) -> &[error::StartProblem] = error::StartError::problems;
+    let _: fn() -> policy::ExtendPolicy = policy::ExtendPolicy::allow_all;
+    let _: fn(
+        policy::ExtendPolicy,
+        policy::AddressRange,
+        std::ops::RangeFull,
+    ) -> policy::ExtendPolicy = policy::ExtendPolicy::allow;
+    let _: fn(
+        policy::ExtendPolicy,
+        policy::AddressRange,
+        std::ops::RangeFull,
+    ) -> policy::ExtendPolicy = policy::ExtendPolicy::deny;
+    let _: fn(&policy::ExtendPolicy, SocketAddr) -> bool = policy::ExtendPolicy::allows;
+    let _: fn(std::net::IpAddr, u8) -> Option<policy::AddressRange> = policy::AddressRange::new;
+    let _: fn() -> policy::AddressRange = policy::AddressRange::any;
+    let _: fn(&policy::AddressRange, std::net::IpAddr) -> bool = policy::AddressRange::contains;
+    let _: fn() -> u64 = allium::caught_panics;
+}
+
+#[test]
+fn test_public_fields() {
+    fn tunnel_stats(s: stats::TunnelStats) {
+        let _: (u64, u64, u64, u64) = (
+            s.rotations,
+            s.spliced_rotations,
+            s.deferred_rotations,
+            s.failed_rebuilds,
+        );
+        let _: (u64, u64, u64) = (s.rejected_hops, s.key_limit_rotations, s.rekeys);
+        let _: (u64, u64, u64, usize) =
+            (s.sent_cells, s.sent_bytes, s.padding_bytes, s.queued_cells);
+        let _: (Option<Duration>, Option<Duration>, u64) =
+            (s.since_last_write, s.stalled_for, s.panics);
+        let _: (Option<Duration>, u64) = (s.round_trip_time, s.dropped_feedback);
+    }
+    fn relay_stats(s: stats::RelayStats) {
+        let _: (usize, u64, usize, usize) = (
+            s.pending_handshakes,
+            s.rejected_handshakes,
+            s.draining_circuits,
+            s.relay_connections,
+        );
+        let _: u64 = s.refused_extends;
+        let _: Vec<stats::InboundCircuitInfo> = s.inbound_circuits;
+        let _: stats::LatencyHistogram = s.forwarding_latency;
+        let _: Vec<stats::ConnectionQueueInfo> = s.relay_connection_queues;
+    }
+    fn connection_queue(q: stats::ConnectionQueueInfo) -> (SocketAddr, usize, usize) {
+        (q.peer_addr, q.queued, q.high_water_mark)
+    }
+    fn peer_handshakes(p: stats::PeerHandshakes) -> (Fingerprint, usize, usize) {
+        (p.fingerprint, p.in_flight, p.waiting)
+    }
+    fn inbound_circuit(c: stats::InboundCircuitInfo) -> (SocketAddr, u64, stats::CircuitParams) {
+        (c.peer_addr, c.connection_id, c.params)
+    }
+    fn circuit_params(p: stats::CircuitParams) -> (config::CellSize, config::CipherSuite) {
+        (p.cell_size, p.cipher_suite)
+    }
+    fn hop(h: stats::HopInfo) -> (SocketAddr, Fingerprint, stats::CircuitParams) {
+        (h.addr, h.fingerprint, h.params)
+    }
+    fn incoming(i: stats::IncomingTunnelInfo) {
+        let _: (SocketAddr, config::CellSize, config::CipherSuite) =
+            (i.adjacent_peer, i.cell_size, i.cipher_suite);
+        let _: std::time::SystemTime = i.began_at;
+    }
+    fn attempt(a: stats::BuildAttempt) {
+        let _: (usize, [u8; 4], stats::BuildOutcome, Duration) =
+            (a.hop, a.peer, a.outcome, a.duration);
+    }
+    fn shutdown(r: stats::ShutdownReport) -> u64 {
+        r.dropped_events
+    }
+    fn outgoing(i: stats::OutgoingTunnelInfo) {
+        let _: (TunnelId, allium::TunnelState, usize) = (i.tunnel_id, i.state, i.hops);
+        let _: (Option<Fingerprint>, stats::TunnelStats) = (i.destination, i.stats);
+        let _: Option<Duration> = i.since_switchover;
+    }
+    fn retry(b: stats::RetryBackoff) -> (u32, Duration) {
+        (b.failures, b.retry_in)
+    }
+    fn node_state(s: allium::NodeState) {
+        let _: u32 = s.version;
+        let _: Vec<allium::SuspectedPeer> = s.suspected_peers;
+        let _: Vec<allium::DestinationBackoff> = s.destination_backoffs;
+        let _: u32 = allium::NodeState::VERSION;
+    }
+    fn suspected(p: allium::SuspectedPeer) -> (Fingerprint, std::time::SystemTime) {
+        (p.fingerprint, p.until)
+    }
+    fn backoff(b: allium::DestinationBackoff) -> (Fingerprint, u32, std::time::SystemTime) {
+        (b.fingerprint, b.failures, b.retry_at)
+    }
+    fn strict(e: error::StrictViolation) -> error::Fallback {
+        e.which
+    }
+    fn no_acceptable(e: error::NoAcceptablePeers) -> (usize, usize) {
+        (e.position, e.rejected)
+    }
+    fn build_timed_out(e: error::BuildTimedOut) -> Duration {
+        e.deadline
+    }
+    fn dest_unreachable(e: error::DestinationUnreachable) -> (usize, stats::BuildOutcome) {
+        (e.failures, e.last_outcome)
+    }
+    fn path_unreachable(e: error::PathUnreachable) -> usize {
+        e.failures
+    }
+    fn build_timeouts(t: config::BuildTimeouts) -> (Duration, Duration) {
+        (t.hop, t.deadline)
+    }
+    fn socket_timeouts(t: config::SocketTimeouts) -> (Duration, Duration) {
+        (t.read, t.write)
+    }
+    fn invalid_range(e: error::InvalidAddressRange) -> String {
+        e.0
+    }
+    let _ = (
+        tunnel_stats,
+        relay_stats,
+        inbound_circuit,
+        circuit_params,
+        hop,
+        incoming,
+        outgoing,
+    );
+    let _ = (attempt, retry, strict, no_acceptable, connection_queue);
+    fn written(m: WrittenMessage) {
+        let _: (MessageHandle, std::time::Instant, std::time::Instant) =
+            (m.handle, m.queued_at, m.written_at);
+    }
+    let _ = (
+        node_state,
+        suspected,
+        backoff,
+        shutdown,
+        written,
+        peer_handshakes,
+        build_timed_out,
+        dest_unreachable,
+        path_unreachable,
+        build_timeouts,
+        socket_timeouts,
+        invalid_range,
+    );
+}
+
+#[test]
+fn test_event_variants() {
+    // `Event` is non-exhaustive, so downstream matches need a wildcard arm.
+    fn tunnel_of(event: &Event) -> Option<TunnelId> {
+        match event {
+            Event::Ready { tunnel_id, .. }
+            | Event::Rotated { tunnel_id, .. }
+            | Event::RotationFailed { tunnel_id }
+            | Event::Stalled { tunnel_id, .. }
+            | Event::Closed { tunnel_id, .. }
+            | Event::HopSuspected { tunnel_id, .. }
+            | Event::Error { tunnel_id, .. } => Some(*tunnel_id),
+            Event::HandshakeBacklogFull { .. }
+            | Event::PeerProviderClosed
+            | Event::GuardsChanged { .. } => None,
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+    assert_eq!(tunnel_of(&Event::PeerProviderClosed), None);
+
+    fn end_reason_of(event: &Event) -> Option<EndReason> {
+        match event {
+            Event::Closed { end_reason, .. } => *end_reason,
+            _ => None,
+        }
+    }
+    assert_eq!(end_reason_of(&Event::PeerProviderClosed), None);
+    // `EndReason` is non-exhaustive as well
+    let _ = [
+        EndReason::Unspecified,
+        EndReason::Normal,
+        EndReason::ProtocolError,
+        EndReason::Timeout,
+        EndReason::ResourceLimit,
+        EndReason::Replaced,
+    ];
+}
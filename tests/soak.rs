@@ -0,0 +1,75 @@
+//! Builds and destroys many tunnels and checks that no tasks or objects are leaked.
+//! Run with `cargo test --features leak-check --test soak`.
+#![cfg(feature = "leak-check")]
+
+use allium::{OnionBuilder, Peer, PeerProvider, RsaPrivateKey};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use time::Duration;
+use tokio::time;
+use tokio_stream as stream;
+
+const ROUND_DURATION: Duration = Duration::from_secs(1);
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
+const BATCHES: usize = 10;
+const BATCH_SIZE: usize = 100;
+
+fn new_peer(port: u16) -> (Peer, RsaPrivateKey) {
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    let hostkey = RsaPrivateKey::from_pem_file("testkey.pem").unwrap();
+    (Peer::new(addr, hostkey.public_key()), hostkey)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_soak_no_leaks() {
+    let (peer1, hostkey1) = new_peer(43200);
+    let (peer2, hostkey2) = new_peer(43201);
+    let (ctx, _incoming1) = OnionBuilder::new(
+        peer1.address(),
+        hostkey1,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_hops_per_tunnel(0)
+    .set_round_duration(ROUND_DURATION)
+    .start()
+    .unwrap();
+    let (_ctx2, mut incoming2) = OnionBuilder::new(
+        peer2.address(),
+        hostkey2,
+        PeerProvider::from_stream(stream::empty()),
+    )
+    .enable_cover_traffic(false)
+    .set_round_duration(ROUND_DURATION)
+    .start()
+    .unwrap();
+    time::sleep(ROUND_DURATION).await;
+    let baseline = allium::live_objects();
+
+    for _ in 0..BATCHES {
+        let builds: Vec<_> = (0..BATCH_SIZE)
+            .map(|_| {
+                let ctx = ctx.clone();
+                let peer2 = peer2.clone();
+                tokio::spawn(async move { ctx.build_tunnel(peer2).await })
+            })
+            .collect();
+        let mut tunnels = Vec::new();
+        for build in builds {
+            tunnels.push(build.await.unwrap().unwrap());
+            tunnels.push(incoming2.next().await.unwrap());
+        }
+    }
+
+    let settled = time::timeout(SETTLE_TIMEOUT, async {
+        while allium::live_objects() != baseline {
+            time::sleep(ROUND_DURATION).await;
+        }
+    })
+    .await;
+    assert!(
+        settled.is_ok(),
+        "leaked objects:\n{}\nbaseline:\n{:?}",
+        allium::debug_dump(),
+        baseline
+    );
+}